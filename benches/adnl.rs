@@ -0,0 +1,192 @@
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::Arc;
+use std::time::Instant;
+
+use anyhow::Result;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use everscale_network::{adnl, MessageSubscriber, SubscriberContext};
+use tokio::sync::{mpsc, Mutex};
+
+const MESSAGE_LEN: usize = 256;
+const QUERY_COUNTS: [usize; 3] = [1, 16, 128];
+const LOCAL_KEY_COUNT: usize = 12;
+
+fn channel_packet(c: &mut Criterion) {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let network = adnl::VirtualNetwork::new(0);
+
+    let mut group = c.benchmark_group("adnl_channel_packet");
+    group.throughput(Throughput::Elements(1));
+    for (name, pair) in [
+        ("virtual", rt.block_on(Pair::new(Some(&network)))),
+        ("loopback", rt.block_on(Pair::new(None))),
+    ] {
+        let data = vec![0xaa; MESSAGE_LEN];
+        group.bench_function(name, |b| {
+            b.to_async(&rt).iter_custom(|iters| {
+                let pair = pair.clone();
+                let data = data.clone();
+                async move {
+                    let mut received = pair.received.lock().await;
+                    let started_at = Instant::now();
+                    for _ in 0..iters {
+                        pair.left
+                            .send_custom_message(&pair.left_id, &pair.right_id, &data)
+                            .unwrap();
+                        received.recv().await.unwrap();
+                    }
+                    started_at.elapsed()
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+fn query_roundtrip(c: &mut Criterion) {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let network = adnl::VirtualNetwork::new(0);
+
+    let mut group = c.benchmark_group("adnl_query_roundtrip");
+    for (name, pair) in [
+        ("virtual", rt.block_on(Pair::new(Some(&network)))),
+        ("loopback", rt.block_on(Pair::new(None))),
+    ] {
+        for count in QUERY_COUNTS {
+            group.throughput(Throughput::Elements(count as u64));
+            group.bench_with_input(BenchmarkId::new(name, count), &count, |b, &count| {
+                b.to_async(&rt).iter(|| {
+                    let pair = pair.clone();
+                    async move {
+                        let queries = (0..count).map(|_| {
+                            pair.left
+                                .ping_peer(&pair.left_id, &pair.right_id, 32, Some(1000))
+                        });
+                        for answer in futures_util::future::join_all(queries).await {
+                            assert!(answer.unwrap().unwrap().intact);
+                        }
+                    }
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+fn handshake_key_selection(c: &mut Criterion) {
+    let keys = (0..LOCAL_KEY_COUNT)
+        .map(|_| Arc::new(adnl::Key::from_bytes(rand::random())))
+        .collect::<Vec<_>>();
+    let unknown = adnl::Key::from_bytes(rand::random());
+    let payload = vec![0xaa; MESSAGE_LEN];
+
+    let mut group = c.benchmark_group("adnl_handshake_key_selection");
+    group.throughput(Throughput::Elements(1));
+    for (name, key) in [
+        ("first", keys[0].as_ref()),
+        ("last", keys[LOCAL_KEY_COUNT - 1].as_ref()),
+        ("unknown", &unknown),
+    ] {
+        let packet = adnl::build_handshake_packet(key.full_id(), &payload, None);
+        group.bench_with_input(
+            BenchmarkId::new(format!("keys_{LOCAL_KEY_COUNT}"), name),
+            &packet,
+            |b, packet| {
+                b.iter(|| adnl::parse_handshake_packet(&keys, packet).unwrap());
+            },
+        );
+    }
+    group.finish();
+}
+
+/// Two started nodes with an established channel
+struct Pair {
+    left: Arc<adnl::Node>,
+    _right: Arc<adnl::Node>,
+    left_id: adnl::NodeIdShort,
+    right_id: adnl::NodeIdShort,
+    received: Mutex<mpsc::UnboundedReceiver<()>>,
+}
+
+impl Pair {
+    /// Uses UDP sockets on the loopback interface if `network` is not specified
+    async fn new(network: Option<&adnl::VirtualNetwork>) -> Arc<Self> {
+        let make_node = || {
+            let keystore = adnl::Keystore::builder()
+                .with_tagged_key(rand::random(), 0)
+                .unwrap()
+                .build();
+            match network {
+                Some(network) => network.add_node(keystore, Default::default(), None),
+                None => adnl::Node::new(
+                    SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0),
+                    keystore,
+                    Default::default(),
+                    None,
+                )
+                .unwrap(),
+            }
+        };
+
+        let left = make_node();
+        let right = make_node();
+
+        let (received_tx, received) = mpsc::unbounded_channel();
+        right
+            .add_message_subscriber(Arc::new(Receiver(received_tx)))
+            .unwrap();
+        right.add_echo_subscriber().unwrap();
+
+        let left_id = *left.key_by_tag(0).unwrap().id();
+        let right_id_full = *right.key_by_tag(0).unwrap().full_id();
+        let right_id = right_id_full.compute_short_id();
+        left.add_peer(
+            adnl::NewPeerContext::AdnlPacket,
+            &left_id,
+            &right_id,
+            right.socket_addr(),
+            right_id_full,
+        )
+        .unwrap();
+
+        left.start().unwrap();
+        right.start().unwrap();
+
+        // Establish channel
+        left.ping_peer(&left_id, &right_id, 0, Some(1000))
+            .await
+            .unwrap()
+            .unwrap();
+
+        Arc::new(Self {
+            left,
+            _right: right,
+            left_id,
+            right_id,
+            received: Mutex::new(received),
+        })
+    }
+}
+
+struct Receiver(mpsc::UnboundedSender<()>);
+
+#[async_trait::async_trait]
+impl MessageSubscriber for Receiver {
+    async fn try_consume_custom<'a>(
+        &self,
+        _: SubscriberContext<'a>,
+        _: u32,
+        _: &'a [u8],
+    ) -> Result<bool> {
+        self.0.send(()).ok();
+        Ok(true)
+    }
+}
+
+criterion_group!(
+    benches,
+    channel_packet,
+    query_roundtrip,
+    handshake_key_selection
+);
+criterion_main!(benches);
@@ -0,0 +1,236 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use everscale_network::{adnl, overlay};
+
+const NEIGHBOUR_COUNT: usize = 16;
+const DECODED_BROADCAST_LEN: usize = 1 << 20;
+
+fn broadcast_fan_out(c: &mut Criterion) {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let network = rt.block_on(Network::new());
+
+    let mut group = c.benchmark_group("overlay_broadcast_fan_out");
+    group.throughput(Throughput::Elements(NEIGHBOUR_COUNT as u64));
+
+    // Ordinary and FEC broadcasts
+    for len in [512, 16 << 10] {
+        let mut counter = 0u64;
+        group.bench_function(BenchmarkId::new("neighbours_16", len), |b| {
+            b.to_async(&rt).iter(|| {
+                // Each broadcast must have a unique id
+                counter += 1;
+                let mut data = vec![0xaa; len];
+                data[..8].copy_from_slice(&counter.to_le_bytes());
+
+                let network = network.clone();
+                async move {
+                    network.sender.broadcast(
+                        &network.sender_adnl,
+                        data,
+                        None,
+                        overlay::BroadcastTarget::RandomNeighbours,
+                    );
+                    futures_util::future::join_all(
+                        network
+                            .receivers
+                            .iter()
+                            .map(|overlay| overlay.wait_for_broadcast()),
+                    )
+                    .await;
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+fn dispatch_latency(c: &mut Criterion) {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let pair = rt.block_on(Pair::new());
+
+    let mut group = c.benchmark_group("overlay_dispatch_latency");
+    group.sample_size(10);
+
+    // Echo query to the receiver without any broadcasts in flight
+    group.bench_function("idle", |b| {
+        b.to_async(&rt).iter(|| {
+            let pair = pair.clone();
+            async move { pair.ping().await }
+        })
+    });
+
+    // Slowest echo query to the receiver while it decodes a 1MB FEC broadcast
+    group.bench_function("fec_decode_1mb", |b| {
+        b.to_async(&rt).iter_custom(|iters| {
+            let pair = pair.clone();
+            async move {
+                let mut total = Duration::ZERO;
+                for _ in 0..iters {
+                    let data = (0..DECODED_BROADCAST_LEN)
+                        .map(|_| rand::random())
+                        .collect::<Vec<u8>>();
+                    pair.sender.broadcast(
+                        &pair.sender_adnl,
+                        data,
+                        None,
+                        overlay::BroadcastTarget::RandomNeighbours,
+                    );
+
+                    let received = pair.receiver.wait_for_broadcast();
+                    futures_util::pin_mut!(received);
+
+                    let mut slowest = Duration::ZERO;
+                    loop {
+                        tokio::select! {
+                            _ = &mut received => break,
+                            rtt = pair.ping() => slowest = slowest.max(rtt),
+                        }
+                    }
+                    total += slowest;
+                }
+                total
+            }
+        })
+    });
+    group.finish();
+}
+
+/// One sender and [`NEIGHBOUR_COUNT`] receivers in the same overlay
+struct Network {
+    sender_adnl: Arc<adnl::Node>,
+    sender: Arc<overlay::Overlay>,
+    receivers: Vec<Arc<overlay::Overlay>>,
+    _network: adnl::VirtualNetwork,
+}
+
+impl Network {
+    async fn new() -> Arc<Self> {
+        let network = adnl::VirtualNetwork::new(0);
+        let overlay_id = overlay::IdFull::for_workchain_overlay(0, &[0; 32]).compute_short_id();
+
+        let make_node = |options: overlay::OverlayOptions| {
+            let keystore = adnl::Keystore::builder()
+                .with_tagged_key(rand::random(), 0)
+                .unwrap()
+                .build();
+            let adnl = network.add_node(keystore, Default::default(), None);
+            let node = overlay::Node::new(adnl.clone(), 0).unwrap();
+            let (overlay, _) = node.add_public_overlay(&overlay_id, options).unwrap();
+            adnl.start().unwrap();
+            (adnl, overlay)
+        };
+
+        let (sender_adnl, sender) = make_node(overlay::OverlayOptions {
+            tuning: overlay::OverlayTuning {
+                broadcast_target_count: NEIGHBOUR_COUNT as u32,
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+
+        // Receivers don't redistribute broadcasts to measure only the fan-out
+        let receivers = (0..NEIGHBOUR_COUNT)
+            .map(|_| {
+                let (adnl, overlay) = make_node(overlay::OverlayOptions {
+                    tuning: overlay::OverlayTuning {
+                        secondary_broadcast_target_count: 0,
+                        secondary_fec_broadcast_target_count: 0,
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                });
+                sender
+                    .add_public_peer(
+                        &sender_adnl,
+                        adnl.socket_addr(),
+                        overlay.sign_local_node().as_equivalent_ref(),
+                    )
+                    .unwrap()
+                    .unwrap();
+                overlay
+            })
+            .collect();
+
+        Arc::new(Self {
+            sender_adnl,
+            sender,
+            receivers,
+            _network: network,
+        })
+    }
+}
+
+/// FEC broadcasts sender and a receiver which answers echo queries
+struct Pair {
+    sender_adnl: Arc<adnl::Node>,
+    sender_id: adnl::NodeIdShort,
+    sender: Arc<overlay::Overlay>,
+    receiver_id: adnl::NodeIdShort,
+    receiver: Arc<overlay::Overlay>,
+    _network: adnl::VirtualNetwork,
+}
+
+impl Pair {
+    async fn new() -> Arc<Self> {
+        let network = adnl::VirtualNetwork::new(0);
+        let overlay_id = overlay::IdFull::for_workchain_overlay(0, &[0; 32]).compute_short_id();
+
+        let make_node = |options: overlay::OverlayOptions| {
+            let keystore = adnl::Keystore::builder()
+                .with_tagged_key(rand::random(), 0)
+                .unwrap()
+                .build();
+            let adnl = network.add_node(keystore, Default::default(), None);
+            adnl.add_echo_subscriber().unwrap();
+            let node = overlay::Node::new(adnl.clone(), 0).unwrap();
+            let (overlay, _) = node.add_public_overlay(&overlay_id, options).unwrap();
+            adnl.start().unwrap();
+            (adnl, overlay)
+        };
+
+        // Send the whole broadcast at once so that the decoding starts as soon as possible
+        let (sender_adnl, sender) = make_node(overlay::OverlayOptions {
+            tuning: overlay::OverlayTuning {
+                fec_broadcast_wave_len: 20,
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        let (receiver_adnl, receiver) = make_node(Default::default());
+        sender
+            .add_public_peer(
+                &sender_adnl,
+                receiver_adnl.socket_addr(),
+                receiver.sign_local_node().as_equivalent_ref(),
+            )
+            .unwrap()
+            .unwrap();
+
+        let pair = Arc::new(Self {
+            sender_id: *sender_adnl.key_by_tag(0).unwrap().id(),
+            sender_adnl,
+            sender,
+            receiver_id: *receiver_adnl.key_by_tag(0).unwrap().id(),
+            receiver,
+            _network: network,
+        });
+
+        // Establish channel
+        pair.ping().await;
+        pair
+    }
+
+    async fn ping(&self) -> Duration {
+        self.sender_adnl
+            .ping_peer(&self.sender_id, &self.receiver_id, 32, Some(5000))
+            .await
+            .unwrap()
+            .unwrap()
+            .rtt
+    }
+}
+
+criterion_group!(benches, broadcast_fan_out, dispatch_latency);
+criterion_main!(benches);
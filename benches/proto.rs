@@ -0,0 +1,96 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use everscale_network::proto;
+use tl_proto::{TlRead, TlWrite};
+
+fn adnl_packet(c: &mut Criterion) {
+    let public_key = [1; 32];
+    let signature = [2; 64];
+    let message = tl_proto::serialize(proto::adnl::Message::Custom { data: &[0xaa; 256] });
+
+    let packet = proto::adnl::OutgoingPacketContents {
+        rand1: &[3; 7],
+        from: Some(everscale_crypto::tl::PublicKey::Ed25519 { key: &public_key }),
+        messages: proto::adnl::OutgoingMessages::Single(&message),
+        address: proto::adnl::AddressList {
+            addresses: [proto::adnl::Address {
+                ip: 0x7f000001,
+                port: 30303,
+            }]
+            .into_iter()
+            .collect(),
+            version: 1,
+            reinit_date: 1,
+            priority: 0,
+            expire_at: 0,
+        },
+        seqno: 1,
+        confirm_seqno: 1,
+        reinit_dates: Some(proto::adnl::ReinitDates {
+            local: 1,
+            target: 1,
+        }),
+        signature: Some(&signature),
+        rand2: &[4; 3],
+    };
+
+    bench_shape(c, "adnl_packet_contents", packet, |data| {
+        black_box(proto::adnl::IncomingPacketContents::read_from(data, &mut 0).unwrap());
+    });
+}
+
+fn rldp_message_part(c: &mut Criterion) {
+    let data = vec![0xaa; 768];
+    let part = proto::rldp::MessagePart::MessagePart {
+        transfer_id: &[1; 32],
+        fec_type: proto::rldp::RaptorQFecType {
+            total_len: 1 << 20,
+            packet_len: 768,
+            packet_count: 1366,
+        },
+        part: 0,
+        total_size: 1 << 20,
+        seqno: 1,
+        data: &data,
+    };
+
+    bench_shape(c, "rldp_message_part", part, |data| {
+        black_box(proto::rldp::MessagePart::read_from(data, &mut 0).unwrap());
+    });
+}
+
+fn overlay_broadcast(c: &mut Criterion) {
+    let public_key = [1; 32];
+    let data = vec![0xaa; 512];
+    let broadcast = proto::overlay::Broadcast::Broadcast(proto::overlay::OverlayBroadcast {
+        src: everscale_crypto::tl::PublicKey::Ed25519 { key: &public_key },
+        certificate: proto::overlay::Certificate::EmptyCertificate,
+        flags: 0,
+        data: &data,
+        date: 1,
+        signature: &[2; 64],
+    });
+
+    bench_shape(c, "overlay_broadcast", broadcast, |data| {
+        black_box(proto::overlay::Broadcast::read_from(data, &mut 0).unwrap());
+    });
+}
+
+/// Benches serialization and deserialization of the same value
+fn bench_shape<W: TlWrite>(c: &mut Criterion, name: &str, value: W, read: fn(&[u8])) {
+    let mut group = c.benchmark_group(format!("proto_{name}"));
+    let serialized = tl_proto::serialize(&value);
+    group.throughput(Throughput::Bytes(serialized.len() as u64));
+
+    group.bench_function("serialize", |b| {
+        let mut buffer = Vec::with_capacity(serialized.len());
+        b.iter(|| {
+            buffer.clear();
+            value.write_to(&mut buffer);
+        })
+    });
+    group.bench_function("deserialize", |b| b.iter(|| read(&serialized)));
+    group.finish();
+}
+
+criterion_group!(benches, adnl_packet, rldp_message_part, overlay_broadcast);
+criterion_main!(benches);
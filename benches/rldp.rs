@@ -0,0 +1,47 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use everscale_network::rldp::RaptorQEncoder;
+
+const PAYLOAD_LENS: [usize; 3] = [64 << 10, 1 << 20, 16 << 20];
+
+/// Symbol ids are 24-bit integers
+const MAX_SEQNO: u32 = 1 << 23;
+
+fn raptorq_encoder(c: &mut Criterion) {
+    let mut group = c.benchmark_group("rldp_raptorq_encoder");
+    group.sample_size(10);
+
+    for len in PAYLOAD_LENS {
+        let data = vec![0xaa; len];
+
+        // Encoder construction, including the precomputation of the intermediate symbols
+        group.throughput(Throughput::Bytes(len as u64));
+        group.bench_with_input(BenchmarkId::new("with_data", len), &data, |b, data| {
+            b.iter(|| RaptorQEncoder::with_data(data))
+        });
+
+        // Repair symbols, generated after all source symbols were sent
+        let mut encoder = RaptorQEncoder::with_data(&data);
+        let packet_count = encoder.params().packet_count;
+        for mut seqno in 0..packet_count {
+            encoder.encode(&mut seqno).unwrap();
+        }
+
+        let mut seqno = packet_count;
+        group.throughput(Throughput::Elements(1));
+        group.bench_function(BenchmarkId::new("repair_symbol", len), |b| {
+            b.iter(|| {
+                if seqno >= MAX_SEQNO {
+                    seqno = packet_count;
+                }
+                let symbol = encoder.encode(&mut seqno).unwrap();
+                seqno += 1;
+                symbol
+            })
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, raptorq_encoder);
+criterion_main!(benches);
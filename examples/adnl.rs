@@ -78,7 +78,7 @@ async fn main() -> Result<()> {
         _ = tokio::time::sleep(Duration::from_secs(10)) => {},
     }
 
-    left_node.shutdown();
+    left_node.shutdown().await;
 
     let throughput = (tl_proto::serialize(example_request()).len()
         + tl_proto::serialize(example_response()).len())
@@ -21,6 +21,10 @@ async fn main() -> Result<()> {
 
     let global_config =
         serde_json::from_str::<util::global_config::GlobalConfig>(include_str!("mainnet.json"))?;
+    tracing::info!(
+        zero_state = %hex::encode(global_config.zero_state.file_hash),
+        "loaded global config"
+    );
 
     // Resolve public ip
     let my_ip = public_ip::addr_v4()
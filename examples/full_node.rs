@@ -0,0 +1,71 @@
+use anyhow::{Context, Result};
+
+use everscale_network::{adnl, overlay, NetworkBuilder};
+use rand::Rng;
+
+use self::util::global_config;
+
+mod util;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt::init();
+
+    const KEY_TAG: usize = 0;
+
+    let global_config =
+        serde_json::from_str::<util::global_config::GlobalConfig>(include_str!("mainnet.json"))?;
+
+    // Resolve public ip
+    let my_ip = public_ip::addr_v4()
+        .await
+        .context("failed to resolve public ip address")?;
+
+    // Find static peers of the masterchain overlay using a separate DHT node
+    let overlay_id =
+        overlay::IdFull::for_workchain_overlay(-1, &global_config.zero_state.file_hash)
+            .compute_short_id();
+
+    let dht_keystore = adnl::Keystore::builder()
+        .with_tagged_key(rand::thread_rng().gen(), KEY_TAG)?
+        .build();
+    let (dht_adnl, dht) = NetworkBuilder::with_adnl((my_ip, 0), dht_keystore, Default::default())
+        .with_dht(KEY_TAG, Default::default())
+        .build()?;
+    for global_config::DhtNode(peer) in global_config.dht_nodes {
+        dht.add_dht_peer(peer)?;
+    }
+    dht.find_more_dht_nodes().await?;
+
+    let static_peers = dht
+        .find_overlay_nodes(&overlay_id)
+        .await
+        .context("failed to find overlay nodes")?;
+    tracing::info!("found {} overlay nodes", static_peers.len());
+    dht_adnl.shutdown().await;
+
+    // Create full node network stack
+    let keystore = adnl::Keystore::builder()
+        .with_tagged_key(rand::thread_rng().gen(), KEY_TAG)?
+        .build();
+
+    let network = overlay::FullNodeBuilder::new(
+        (my_ip, 0),
+        keystore,
+        KEY_TAG,
+        global_config.zero_state.file_hash,
+    )
+    .with_static_peers(static_peers)
+    .build()?;
+
+    // Print incoming broadcasts
+    loop {
+        let broadcast = network.workchain_overlay.wait_for_broadcast().await;
+        tracing::info!(
+            from = %broadcast.from,
+            packets = broadcast.packets,
+            "received broadcast of {} bytes",
+            broadcast.data.len()
+        );
+    }
+}
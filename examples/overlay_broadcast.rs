@@ -48,7 +48,7 @@ async fn main() -> Result<()> {
     let mc_overlay_id =
         overlay::IdFull::for_workchain_overlay(-1, &global_config.zero_state.file_hash)
             .compute_short_id();
-    let (workchain_overlay, _) = overlay.add_public_overlay(&mc_overlay_id, Default::default());
+    let (workchain_overlay, _) = overlay.add_public_overlay(&mc_overlay_id, Default::default())?;
 
     // Populate overlay with nodes
     let overlay_nodes = dht
@@ -70,7 +70,10 @@ async fn main() -> Result<()> {
     );
 
     // NOTE: broadcast is just fire-and-forget, so wait a bit
-    tokio::time::sleep(Duration::from_secs(1)).await;
+    for _ in 0..5 {
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        tracing::info!("adnl metrics: {:?}", adnl.metrics());
+    }
 
     // Done
     Ok(())
@@ -26,7 +26,7 @@ async fn main() -> Result<()> {
     .with_overlay(KEY_TAG)
     .build()?;
 
-    let (shard, _) = overlay.add_public_overlay(&overlay_id, Default::default());
+    let (shard, _) = overlay.add_public_overlay(&overlay_id, Default::default())?;
 
     let subscriber = Arc::new(OverlaySubscriber);
     overlay.add_overlay_subscriber(overlay_id, subscriber);
@@ -50,7 +50,7 @@ async fn send_query(
     .with_overlay(KEY_TAG)
     .build()?;
 
-    let (shard, _) = overlay.add_public_overlay(&overlay_id, Default::default());
+    let (shard, _) = overlay.add_public_overlay(&overlay_id, Default::default())?;
     let peer_id = shard
         .add_public_peer(&adnl, addr, other.as_equivalent_ref())?
         .context("failed to add overlay peer")?;
@@ -101,7 +101,7 @@ impl QuerySubscriber for OverlaySubscriber {
                 capabilities: 1,
             })
         } else {
-            Ok(QueryConsumingResult::Consumed(None))
+            Ok(QueryConsumingResult::no_answer())
         }
     }
 }
@@ -78,6 +78,16 @@ async fn main() -> Result<()> {
         }));
     }
 
+    // Print the ADNL traffic of the querying side
+    let metrics_adnl = left_adnl.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            interval.tick().await;
+            println!("{:?}", metrics_adnl.metrics().traffic);
+        }
+    });
+
     tokio::select! {
         _ = futures_util::future::join_all(handles) => {},
         _ = tokio::time::sleep(Duration::from_secs(10)) => {},
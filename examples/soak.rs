@@ -0,0 +1,492 @@
+//! Long-running traffic generator which checks that internal maps don't leak.
+//!
+//! Runs several in-process nodes which exchange a random mix of ADNL queries,
+//! RLDP transfers and overlay broadcasts. Traffic is periodically paused to check
+//! that all caches are drained and the memory usage doesn't grow.
+//!
+//! Run with `cargo run --release --example soak -- --duration-secs 3600`,
+//! see [`Config`] for all options.
+
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use everscale_network::{adnl, overlay, proto, rldp};
+use rand::Rng;
+use tokio::sync::RwLock;
+
+const KEY_TAG: usize = 0;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt::init();
+
+    let config = Config::from_args(std::env::args().skip(1))?;
+    println!("{config:?}");
+
+    let nodes = Arc::new(make_nodes(&config)?);
+    let stats = Arc::new(Stats::default());
+
+    // Workers hold the read lock during each operation,
+    // so checks can wait for all in-flight traffic to finish
+    let traffic = Arc::new(RwLock::new(()));
+    for _ in 0..config.concurrency {
+        tokio::spawn(run_worker(
+            config.clone(),
+            nodes.clone(),
+            stats.clone(),
+            traffic.clone(),
+        ));
+    }
+
+    let started_at = Instant::now();
+    let deadline = started_at + Duration::from_secs(config.duration_secs);
+    let mut baseline_rss = None;
+    loop {
+        tokio::time::sleep(Duration::from_secs(config.check_interval_secs)).await;
+        let finished = Instant::now() >= deadline;
+
+        let _paused = traffic.write().await;
+        tokio::time::sleep(config.settle_time()).await;
+
+        let snapshot = Snapshot::collect(&nodes);
+        let rss = current_rss();
+        println!(
+            "[{}s] {stats}, rss: {}",
+            started_at.elapsed().as_secs(),
+            rss.map(|rss| format!("{} MB", rss >> 20))
+                .unwrap_or_else(|| "unknown".to_owned())
+        );
+        if let Some(node) = snapshot.nodes.first() {
+            println!("  {} traffic: {:?}", node.id, node.adnl.traffic);
+        }
+
+        let mut violations = snapshot.check(&config);
+        match (baseline_rss, rss) {
+            (None, rss) => baseline_rss = rss,
+            (Some(baseline), Some(rss)) => {
+                let growth = rss.saturating_sub(baseline);
+                if growth > config.max_rss_growth_mb << 20 {
+                    violations.push(format!("RSS grew by {} MB", growth >> 20));
+                }
+            }
+            _ => {}
+        }
+
+        if !violations.is_empty() {
+            for violation in &violations {
+                eprintln!("invariant violated: {violation}");
+            }
+            eprintln!("{snapshot:#?}");
+            anyhow::bail!("{} invariants violated", violations.len());
+        }
+
+        if finished {
+            break;
+        }
+    }
+
+    println!("Done: {stats}");
+    Ok(())
+}
+
+/// Soak test parameters
+#[derive(Debug, Clone)]
+struct Config {
+    /// `--nodes`
+    nodes: usize,
+    /// `--duration-secs`
+    duration_secs: u64,
+    /// `--check-interval-secs`
+    check_interval_secs: u64,
+    /// `--concurrency`, number of parallel operations
+    concurrency: usize,
+    /// `--mix`, relative weights of ADNL queries, RLDP transfers and broadcasts
+    mix: [u32; 3],
+    /// `--max-rldp-size`, max RLDP query payload in bytes
+    max_rldp_size: usize,
+    /// `--max-broadcast-size`, max broadcast payload in bytes
+    max_broadcast_size: usize,
+    /// `--max-idle-queries`, max queries cache len after traffic was paused
+    max_idle_queries: usize,
+    /// `--max-rss-growth-mb`, max RSS growth since the first check
+    max_rss_growth_mb: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            nodes: 2,
+            duration_secs: 600,
+            check_interval_secs: 60,
+            concurrency: 16,
+            mix: [60, 30, 10],
+            max_rldp_size: 1 << 20,
+            max_broadcast_size: 4096,
+            max_idle_queries: 16,
+            max_rss_growth_mb: 64,
+        }
+    }
+}
+
+impl Config {
+    fn from_args(mut args: impl Iterator<Item = String>) -> Result<Self> {
+        let mut config = Self::default();
+        while let Some(name) = args.next() {
+            let value = args
+                .next()
+                .with_context(|| format!("no value for {name}"))?;
+            let parse_error = || format!("invalid value for {name}: {value}");
+            match name.as_str() {
+                "--nodes" => config.nodes = value.parse().with_context(parse_error)?,
+                "--duration-secs" => {
+                    config.duration_secs = value.parse().with_context(parse_error)?
+                }
+                "--check-interval-secs" => {
+                    config.check_interval_secs = value.parse().with_context(parse_error)?
+                }
+                "--concurrency" => config.concurrency = value.parse().with_context(parse_error)?,
+                "--mix" => {
+                    let weights = value
+                        .split(',')
+                        .map(str::parse)
+                        .collect::<Result<Vec<u32>, _>>()
+                        .with_context(parse_error)?;
+                    config.mix = weights.try_into().ok().with_context(parse_error)?;
+                }
+                "--max-rldp-size" => {
+                    config.max_rldp_size = value.parse().with_context(parse_error)?
+                }
+                "--max-broadcast-size" => {
+                    config.max_broadcast_size = value.parse().with_context(parse_error)?
+                }
+                "--max-idle-queries" => {
+                    config.max_idle_queries = value.parse().with_context(parse_error)?
+                }
+                "--max-rss-growth-mb" => {
+                    config.max_rss_growth_mb = value.parse().with_context(parse_error)?
+                }
+                _ => anyhow::bail!("unknown option: {name}"),
+            }
+        }
+
+        anyhow::ensure!(config.nodes >= 2, "at least two nodes are required");
+        anyhow::ensure!(config.mix.iter().any(|&w| w > 0), "empty traffic mix");
+        Ok(config)
+    }
+
+    fn rldp_options(&self) -> rldp::NodeOptions {
+        rldp::NodeOptions {
+            query_max_timeout_ms: 2000,
+            ..Default::default()
+        }
+    }
+
+    fn overlay_options(&self) -> overlay::OverlayOptions {
+        overlay::OverlayOptions {
+            tuning: overlay::OverlayTuning {
+                broadcast_timeout_sec: 5,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    /// Time after which all finished transfers and broadcasts must be collected
+    fn settle_time(&self) -> Duration {
+        // NOTE: RLDP transfers are kept for twice the max query timeout
+        let rldp = Duration::from_millis(self.rldp_options().query_max_timeout_ms * 2);
+        let overlay = self.overlay_options().tuning;
+        let overlay = Duration::from_secs(overlay.broadcast_timeout_sec)
+            + Duration::from_millis(overlay.broadcast_gc_interval_ms);
+        std::cmp::max(rldp, overlay) + Duration::from_secs(1)
+    }
+}
+
+struct TestNode {
+    adnl: Arc<adnl::Node>,
+    rldp: Arc<rldp::Node>,
+    overlay_node: Arc<overlay::Node>,
+    overlay: Arc<overlay::Overlay>,
+}
+
+impl TestNode {
+    fn id(&self) -> adnl::NodeIdShort {
+        *self.overlay.overlay_key().id()
+    }
+}
+
+fn make_nodes(config: &Config) -> Result<Vec<TestNode>> {
+    let overlay_id = overlay::IdFull::for_workchain_overlay(0, &[0; 32]).compute_short_id();
+
+    let mut nodes = Vec::with_capacity(config.nodes);
+    for _ in 0..config.nodes {
+        let keystore = adnl::Keystore::builder()
+            .with_tagged_key(rand::thread_rng().gen(), KEY_TAG)?
+            .build();
+        let adnl = adnl::Node::new(
+            SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0),
+            keystore,
+            Default::default(),
+            None,
+        )?;
+        adnl.add_echo_subscriber()?;
+
+        let rldp = rldp::Node::new(
+            adnl.clone(),
+            vec![Arc::new(adnl::EchoSubscriber)],
+            config.rldp_options(),
+        )?;
+        let overlay_node = overlay::Node::new(adnl.clone(), KEY_TAG)?;
+        let (overlay, _) =
+            overlay_node.add_public_overlay(&overlay_id, config.overlay_options())?;
+
+        adnl.start()?;
+
+        // Drain incoming broadcasts
+        tokio::spawn({
+            let overlay = overlay.clone();
+            async move {
+                loop {
+                    overlay.wait_for_broadcast().await;
+                }
+            }
+        });
+
+        nodes.push(TestNode {
+            adnl,
+            rldp,
+            overlay_node,
+            overlay,
+        });
+    }
+
+    for node in &nodes {
+        for peer in &nodes {
+            if !Arc::ptr_eq(&node.adnl, &peer.adnl) {
+                node.overlay.add_public_peer(
+                    &node.adnl,
+                    peer.adnl.socket_addr(),
+                    peer.overlay.sign_local_node().as_equivalent_ref(),
+                )?;
+            }
+        }
+    }
+
+    Ok(nodes)
+}
+
+async fn run_worker(
+    config: Config,
+    nodes: Arc<Vec<TestNode>>,
+    stats: Arc<Stats>,
+    traffic: Arc<RwLock<()>>,
+) {
+    let total_weight: u32 = config.mix.iter().sum();
+    loop {
+        let _guard = traffic.read().await;
+
+        let (src, dst, op, size) = {
+            let mut rng = rand::thread_rng();
+            let src = rng.gen_range(0..nodes.len());
+            let dst = (src + rng.gen_range(1..nodes.len())) % nodes.len();
+
+            let mut weight = rng.gen_range(0..total_weight);
+            let op = config
+                .mix
+                .iter()
+                .position(|&w| {
+                    if weight < w {
+                        return true;
+                    }
+                    weight -= w;
+                    false
+                })
+                .unwrap_or_default();
+
+            let size = match op {
+                0 => rng.gen_range(0..=1024),
+                1 => rng.gen_range(0..=config.max_rldp_size),
+                _ => rng.gen_range(0..=config.max_broadcast_size),
+            };
+            (&nodes[src], &nodes[dst], op, size)
+        };
+
+        let result = match op {
+            0 => src
+                .adnl
+                .ping_peer(&src.id(), &dst.id(), size, None)
+                .await
+                .map(|stats| stats.is_some()),
+            1 => {
+                let query = tl_proto::serialize(proto::rpc::NetworkEcho {
+                    data: vec![0xaa; size],
+                });
+                src.rldp
+                    .query(&src.id(), &dst.id(), query, None)
+                    .await
+                    .map(|(answer, _)| answer.is_some())
+            }
+            _ => {
+                // NOTE: broadcasts with the same data are deduplicated
+                let data = std::iter::repeat_with(rand::random).take(size).collect();
+                src.overlay.broadcast(
+                    &src.adnl,
+                    data,
+                    None,
+                    overlay::BroadcastTarget::RandomNeighbours,
+                );
+                Ok(true)
+            }
+        };
+
+        let counter = match result {
+            Ok(true) => &stats.completed[op],
+            Ok(false) => &stats.timed_out,
+            Err(e) => {
+                tracing::warn!("operation failed: {e:?}");
+                &stats.failed
+            }
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Default)]
+struct Stats {
+    /// ADNL queries, RLDP queries and broadcasts
+    completed: [AtomicU64; 3],
+    timed_out: AtomicU64,
+    failed: AtomicU64,
+}
+
+impl std::fmt::Display for Stats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        write!(
+            f,
+            "adnl: {}, rldp: {}, broadcasts: {}, timed out: {}, failed: {}",
+            load(&self.completed[0]),
+            load(&self.completed[1]),
+            load(&self.completed[2]),
+            load(&self.timed_out),
+            load(&self.failed),
+        )
+    }
+}
+
+/// Metrics of all nodes after the traffic was paused
+#[derive(Debug)]
+struct Snapshot {
+    nodes: Vec<NodeSnapshot>,
+}
+
+#[derive(Debug)]
+struct NodeSnapshot {
+    id: String,
+    adnl: adnl::NodeMetrics,
+    rldp: rldp::NodeMetrics,
+    overlays: Vec<overlay::OverlayMetrics>,
+}
+
+impl Snapshot {
+    fn collect(nodes: &[TestNode]) -> Self {
+        Self {
+            nodes: nodes
+                .iter()
+                .map(|node| NodeSnapshot {
+                    id: node.id().to_string(),
+                    adnl: node.adnl.metrics(),
+                    rldp: node.rldp.metrics(),
+                    overlays: node
+                        .overlay_node
+                        .metrics()
+                        .map(|(_, metrics)| metrics)
+                        .collect(),
+                })
+                .collect(),
+        }
+    }
+
+    fn check(&self, config: &Config) -> Vec<String> {
+        let max_peers = config.nodes - 1;
+        let overlay_tuning = config.overlay_options().tuning;
+        let max_broadcast_log = overlay_tuning.max_broadcast_log as usize;
+
+        let mut violations = Vec::new();
+        let mut check = |node: &NodeSnapshot, name: &str, value: usize, limit: usize| {
+            if value > limit {
+                violations.push(format!("{}: {name} is {value} (limit {limit})", node.id));
+            }
+        };
+
+        for node in &self.nodes {
+            let adnl = &node.adnl;
+            check(
+                node,
+                "adnl queries",
+                adnl.query_count,
+                config.max_idle_queries,
+            );
+            check(
+                node,
+                "adnl incoming transfers",
+                adnl.incoming_transfers_len,
+                0,
+            );
+            check(node, "adnl peers", adnl.peer_count, max_peers);
+            check(node, "adnl channels", adnl.channels_by_peers_len, max_peers);
+            check(
+                node,
+                "adnl channels by id",
+                adnl.channels_by_id_len,
+                max_peers * 2,
+            );
+
+            check(node, "rldp transfers", node.rldp.transfers_cache_len, 0);
+            check(node, "rldp peers", node.rldp.peer_count, max_peers);
+
+            for overlay in &node.overlays {
+                let finished = overlay.finished_broadcasts_len as usize;
+                check(
+                    node,
+                    "overlay owned broadcasts",
+                    overlay.owned_broadcasts_len,
+                    max_broadcast_log,
+                );
+                check(
+                    node,
+                    "overlay finished broadcasts",
+                    finished,
+                    max_broadcast_log,
+                );
+                check(node, "overlay nodes", overlay.node_count, max_peers);
+                check(node, "overlay known peers", overlay.known_peers, max_peers);
+                check(
+                    node,
+                    "overlay received peers",
+                    overlay.received_peers_len,
+                    max_peers,
+                );
+                check(
+                    node,
+                    "overlay received broadcasts",
+                    overlay.received_broadcasts_data_len,
+                    0,
+                );
+            }
+        }
+
+        violations
+    }
+}
+
+/// Resident set size of the current process in bytes (Linux only)
+fn current_rss() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kb * 1024)
+}
@@ -0,0 +1,77 @@
+//! Prints the span tree of a loopback ADNL query and RLDP transfer.
+//!
+//! Run with `cargo run --example tracing-spans`
+
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::Arc;
+
+use anyhow::Result;
+use everscale_crypto::ed25519;
+use everscale_network::{adnl, proto, rldp};
+use tracing_subscriber::fmt::format::FmtSpan;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    // Print span fields on each event and span durations on close
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::TRACE)
+        .with_span_events(FmtSpan::CLOSE)
+        .init();
+
+    let build_node = || -> Result<(Arc<adnl::Node>, Arc<rldp::Node>)> {
+        let key = ed25519::SecretKey::generate(&mut rand::thread_rng());
+        let adnl = adnl::Node::new(
+            SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0),
+            adnl::Keystore::builder()
+                .with_tagged_key(key.to_bytes(), 0)?
+                .build(),
+            Default::default(),
+            None,
+        )?;
+        adnl.add_echo_subscriber()?;
+
+        let rldp = rldp::Node::new(
+            adnl.clone(),
+            vec![Arc::new(adnl::EchoSubscriber)],
+            Default::default(),
+        )?;
+
+        adnl.start()?;
+        Ok((adnl, rldp))
+    };
+
+    let (left_adnl, left_rldp) = build_node()?;
+    let (right_adnl, _right_rldp) = build_node()?;
+
+    let left_node_id = *left_adnl.key_by_tag(0)?.id();
+    let right_node_id_full = *right_adnl.key_by_tag(0)?.full_id();
+    let right_node_id = right_node_id_full.compute_short_id();
+
+    left_adnl.add_peer(
+        adnl::NewPeerContext::AdnlPacket,
+        &left_node_id,
+        &right_node_id,
+        right_adnl.socket_addr(),
+        right_node_id_full,
+    )?;
+
+    // ADNL query
+    let stats = left_adnl
+        .ping_peer(&left_node_id, &right_node_id, 32, Some(1000))
+        .await?;
+    println!("ADNL ping: {stats:?}");
+
+    // RLDP query
+    let query = tl_proto::serialize(proto::rpc::NetworkEcho {
+        data: vec![1; 4096],
+    });
+    let (answer, roundtrip) = left_rldp
+        .query(&left_node_id, &right_node_id, query, None)
+        .await?;
+    println!(
+        "RLDP answer: {:?} bytes, roundtrip: {roundtrip} ms",
+        answer.map(|answer| answer.len())
+    );
+
+    Ok(())
+}
@@ -0,0 +1,97 @@
+//! Two local nodes in the same public overlay. The receiver decodes broadcasts
+//! of the custom TL type with a typed handler, other broadcasts are received as bytes.
+
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use everscale_network::{adnl, overlay};
+use rand::Rng;
+use tl_proto::{BoxedConstructor, TlRead, TlWrite};
+
+const KEY_TAG: usize = 0;
+
+/// Custom broadcast payload
+#[derive(Debug, TlRead, TlWrite)]
+struct Greeting {
+    text: Vec<u8>,
+}
+
+impl BoxedConstructor for Greeting {
+    const TL_ID: u32 = tl_proto::id!(
+        "example.greeting",
+        scheme_inline = "example.greeting text:bytes = example.Greeting;"
+    );
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt::init();
+
+    let overlay_id = overlay::IdFull::for_workchain_overlay(0, &[0; 32]).compute_short_id();
+    let (left_adnl, left) = make_node(&overlay_id)?;
+    let (right_adnl, right) = make_node(&overlay_id)?;
+
+    left.add_public_peer(
+        &left_adnl,
+        right_adnl.socket_addr(),
+        right.sign_local_node().as_equivalent_ref(),
+    )?;
+    right.add_public_peer(
+        &right_adnl,
+        left_adnl.socket_addr(),
+        left.sign_local_node().as_equivalent_ref(),
+    )?;
+
+    // Register typed handler
+    let (greetings_tx, mut greetings_rx) = tokio::sync::mpsc::unbounded_channel();
+    let registered =
+        right.register_broadcast_handler::<Greeting>(Arc::new(move |from, greeting| {
+            greetings_tx.send((from, greeting)).ok();
+        }));
+    assert!(registered);
+
+    // Send typed and raw broadcasts
+    let greeting = Greeting {
+        text: b"hello".to_vec(),
+    };
+    left.broadcast(
+        &left_adnl,
+        tl_proto::serialize(greeting.as_boxed()),
+        None,
+        overlay::BroadcastTarget::RandomNeighbours,
+    );
+    left.broadcast(
+        &left_adnl,
+        vec![0; 10],
+        None,
+        overlay::BroadcastTarget::RandomNeighbours,
+    );
+
+    let (from, greeting) = tokio::time::timeout(Duration::from_secs(1), greetings_rx.recv())
+        .await?
+        .expect("handler dropped");
+    tracing::info!(%from, ?greeting, "typed broadcast received");
+
+    let raw = right.wait_for_broadcast().await;
+    tracing::info!(from = %raw.from, len = raw.data.len(), "raw broadcast received");
+
+    Ok(())
+}
+
+fn make_node(overlay_id: &overlay::IdShort) -> Result<(Arc<adnl::Node>, Arc<overlay::Overlay>)> {
+    let keystore = adnl::Keystore::builder()
+        .with_tagged_key(rand::thread_rng().gen(), KEY_TAG)?
+        .build();
+    let adnl = adnl::Node::new(
+        SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0),
+        keystore,
+        Default::default(),
+        None,
+    )?;
+    let overlay_node = overlay::Node::new(adnl.clone(), KEY_TAG)?;
+    let (overlay, _) = overlay_node.add_public_overlay(overlay_id, Default::default())?;
+    adnl.start()?;
+    Ok((adnl, overlay))
+}
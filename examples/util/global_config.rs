@@ -2,6 +2,7 @@ use std::net::SocketAddrV4;
 
 use base64::Engine as _;
 use everscale_network::proto;
+use everscale_network::util::AddressListBuilder;
 use serde::{de::Error, Deserialize, Deserializer};
 
 #[derive(Deserialize)]
@@ -61,15 +62,12 @@ impl<'de> Deserialize<'de> for DhtNode {
 
         let entry = Entry::deserialize(deserializer)?;
 
-        let addr_list = proto::adnl::AddressList {
-            address: entry
-                .addr_list
-                .address
-                .map(|addr| proto::adnl::Address::from(&addr)),
-            version: entry.addr_list.version,
-            reinit_date: entry.addr_list.reinit_date,
-            expire_at: entry.addr_list.expire_at,
-        };
+        let addr_list = AddressListBuilder::new()
+            .with_addresses(entry.addr_list.address)
+            .with_version(entry.addr_list.version)
+            .with_reinit_date(entry.addr_list.reinit_date)
+            .with_expire_at(entry.addr_list.expire_at)
+            .build();
 
         let node = proto::dht::NodeOwned {
             id: everscale_crypto::tl::PublicKeyOwned::Ed25519 {
@@ -0,0 +1,73 @@
+use aes::cipher::StreamCipher;
+use everscale_crypto::ed25519;
+use sha2::Digest;
+
+use super::encryption::*;
+use super::keystore::Key;
+use crate::proto;
+
+/// Encrypts the answer to the one-time key from [`proto::rpc::PrivateQueryWithAnswerKey`]
+pub fn encrypt_answer(local_key: &Key, answer_key: &ed25519::PublicKey, answer: &[u8]) -> Vec<u8> {
+    let shared_secret = SharedSecret::new(local_key.secret_key().compute_shared_secret(answer_key));
+    let checksum: [u8; 32] = sha2::Sha256::digest(answer).into();
+
+    let mut data = answer.to_vec();
+    build_packet_cipher(&shared_secret, &checksum).apply_keystream(&mut data);
+
+    tl_proto::serialize(proto::adnl::PrivateEncryptedAnswer {
+        checksum: &checksum,
+        data: &data,
+    })
+}
+
+/// Decrypts [`proto::adnl::PrivateEncryptedAnswer`] with the one-time key which was sent in the query
+pub fn decrypt_answer(
+    answer_key: &Key,
+    peer_key: &ed25519::PublicKey,
+    answer: &[u8],
+) -> Result<Vec<u8>, AnswerEncryptionError> {
+    let answer = tl_proto::deserialize::<proto::adnl::PrivateEncryptedAnswer>(answer)
+        .map_err(|_| AnswerEncryptionError::InvalidAnswer)?;
+    let shared_secret = SharedSecret::new(answer_key.secret_key().compute_shared_secret(peer_key));
+
+    let mut data = answer.data.to_vec();
+    build_packet_cipher(&shared_secret, answer.checksum).apply_keystream(&mut data);
+
+    let checksum: [u8; 32] = sha2::Sha256::digest(&data).into();
+    if !checksum_eq(&checksum, answer.checksum) {
+        return Err(AnswerEncryptionError::ChecksumMismatch);
+    }
+    Ok(data)
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum AnswerEncryptionError {
+    #[error("Invalid encrypted answer")]
+    InvalidAnswer,
+    #[error("Encrypted answer checksum mismatch")]
+    ChecksumMismatch,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tampered_answers_are_rejected() {
+        let server_key = Key::from_bytes(rand::random());
+        let answer_key = Key::from_bytes(rand::random());
+
+        let encrypted = encrypt_answer(&server_key, answer_key.public_key(), b"answer");
+        let decrypted = decrypt_answer(&answer_key, server_key.public_key(), &encrypted).unwrap();
+        assert_eq!(decrypted, b"answer");
+
+        // Only the owner of the answer key can decrypt it
+        let other_key = Key::from_bytes(rand::random());
+        assert!(decrypt_answer(&other_key, server_key.public_key(), &encrypted).is_err());
+
+        // Flip the first byte of the data (after the constructor, checksum and length)
+        let mut tampered = encrypted;
+        tampered[4 + 32 + 1] ^= 1;
+        assert!(decrypt_answer(&answer_key, server_key.public_key(), &tampered).is_err());
+    }
+}
@@ -1,4 +1,3 @@
-use std::convert::TryInto;
 use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 
 use aes::cipher::{StreamCipher, StreamCipherSeek};
@@ -38,13 +37,14 @@ impl Channel {
         peer_channel_date: u32,
         context: ChannelCreationContext,
     ) -> Self {
-        let shared_secret = channel_key.compute_shared_secret(&peer_channel_public_key);
-        let mut reversed_secret = shared_secret;
+        let shared_secret =
+            SharedSecret::new(channel_key.compute_shared_secret(&peer_channel_public_key));
+        let mut reversed_secret = shared_secret.clone();
         reversed_secret.reverse();
 
         let (in_secret, out_secret) = match local_id.cmp(&peer_id) {
             std::cmp::Ordering::Less => (shared_secret, reversed_secret),
-            std::cmp::Ordering::Equal => (shared_secret, shared_secret),
+            std::cmp::Ordering::Equal => (shared_secret.clone(), shared_secret),
             std::cmp::Ordering::Greater => (reversed_secret, shared_secret),
         };
 
@@ -77,10 +77,10 @@ impl Channel {
         self.ready.load(Ordering::Acquire)
     }
 
-    /// Sets channel ready
+    /// Sets channel ready. Returns `true` if it was not ready before
     #[inline(always)]
-    pub fn set_ready(&self) {
-        self.ready.store(true, Ordering::Release)
+    pub fn set_ready(&self) -> bool {
+        !self.ready.swap(true, Ordering::AcqRel)
     }
 
     /// Public key of the keypair from the peer's side
@@ -119,7 +119,7 @@ impl Channel {
         &self.peer_id
     }
 
-    /// Sets channel drop timestamp if it wasn't set before.
+    /// Sets channel drop timestamp (node uptime in seconds) if it wasn't set before.
     /// Returns whether channel should be dropped
     pub fn update_drop_timeout(&self, now: u32, timeout: u32) -> bool {
         let drop_timestamp = self
@@ -144,16 +144,16 @@ impl Channel {
     ) -> Result<Option<u16>, AdnlChannelError> {
         // Ordinary data ranges
         const DATA_START: usize = 64;
-        const CHECKSUM_RANGE: std::ops::Range<usize> = 32..DATA_START;
-        const DATA_RANGE: std::ops::RangeFrom<usize> = DATA_START..;
+        const CHECKSUM_OFFSET: usize = 32;
 
         // Data ranges for packets with ADNL version
         const EXT_DATA_START: usize = 68;
-        const EXT_CHECKSUM_RANGE: std::ops::Range<usize> = 36..EXT_DATA_START;
-        const EXT_DATA_RANGE: std::ops::RangeFrom<usize> = EXT_DATA_START..;
+        const EXT_CHECKSUM_OFFSET: usize = 36;
 
-        if buffer.len() < DATA_START {
-            return Err(AdnlChannelError::ChannelMessageIsTooShort(buffer.len()));
+        if buffer.remaining() < DATA_START {
+            return Err(AdnlChannelError::ChannelMessageIsTooShort(
+                buffer.remaining(),
+            ));
         }
 
         let shared_secret = if priority {
@@ -162,46 +162,41 @@ impl Channel {
             &self.channel_in.ordinary.secret
         };
 
-        if buffer.len() > EXT_DATA_START {
-            if let Some(version) =
-                decode_version::<EXT_DATA_START>((&buffer[..EXT_DATA_START]).try_into().unwrap())
-            {
-                // Build cipher
-                let mut cipher = build_packet_cipher(
-                    shared_secret,
-                    &buffer[EXT_CHECKSUM_RANGE].try_into().unwrap(),
-                );
+        if buffer.remaining() > EXT_DATA_START {
+            if let Some(version) = decode_version(buffer.get_array::<EXT_DATA_START>(0)?) {
+                let checksum = *buffer.get_array::<32>(EXT_CHECKSUM_OFFSET)?;
+                let (_, data) = buffer.split_at_mut(EXT_DATA_START)?;
 
                 // Decode data
-                cipher.apply_keystream(&mut buffer[EXT_DATA_RANGE]);
+                let mut cipher = build_packet_cipher(shared_secret, &checksum);
+                cipher.apply_keystream(data);
 
                 // If hash is ok
-                if compute_packet_data_hash(Some(version), &buffer[EXT_DATA_RANGE]).as_slice()
-                    == &buffer[EXT_CHECKSUM_RANGE]
-                {
+                if checksum_eq(&compute_packet_data_hash(Some(version), data), &checksum) {
                     // Leave only data in the buffer and return version
-                    buffer.remove_prefix(EXT_DATA_START);
+                    buffer.remove_prefix(EXT_DATA_START)?;
                     return Ok(Some(version));
                 }
 
                 // Otherwise restore data
                 cipher.seek(0);
-                cipher.apply_keystream(&mut buffer[EXT_DATA_RANGE]);
+                cipher.apply_keystream(data);
             }
         }
 
+        let checksum = *buffer.get_array::<32>(CHECKSUM_OFFSET)?;
+        let (_, data) = buffer.split_at_mut(DATA_START)?;
+
         // Decode data
-        build_packet_cipher(shared_secret, &buffer[CHECKSUM_RANGE].try_into().unwrap())
-            .apply_keystream(&mut buffer[DATA_RANGE]);
+        build_packet_cipher(shared_secret, &checksum).apply_keystream(data);
 
         // Check checksum
-        if compute_packet_data_hash(None, &buffer[DATA_RANGE]).as_slice() != &buffer[CHECKSUM_RANGE]
-        {
+        if !checksum_eq(&compute_packet_data_hash(None, data), &checksum) {
             return Err(AdnlChannelError::InvalidChannelMessageChecksum);
         }
 
         // Leave only data in the buffer
-        buffer.remove_prefix(DATA_START);
+        buffer.remove_prefix(DATA_START)?;
 
         Ok(None)
     }
@@ -276,8 +271,8 @@ struct ChannelSide {
 }
 
 impl ChannelSide {
-    fn from_secret(secret: [u8; 32]) -> Self {
-        let priority_secret = build_priority_secret(secret);
+    fn from_secret(secret: SharedSecret) -> Self {
+        let priority_secret = build_priority_secret(&secret);
         Self {
             ordinary: SubChannelSide {
                 id: compute_channel_id(&secret),
@@ -293,11 +288,11 @@ impl ChannelSide {
 
 struct SubChannelSide {
     id: AdnlChannelId,
-    secret: [u8; 32],
+    secret: SharedSecret,
 }
 
-fn build_priority_secret(ordinary_secret: [u8; 32]) -> [u8; 32] {
-    [
+fn build_priority_secret(ordinary_secret: &[u8; 32]) -> SharedSecret {
+    SharedSecret::new([
         ordinary_secret[1],
         ordinary_secret[0],
         ordinary_secret[3],
@@ -330,7 +325,7 @@ fn build_priority_secret(ordinary_secret: [u8; 32]) -> [u8; 32] {
         ordinary_secret[28],
         ordinary_secret[31],
         ordinary_secret[30],
-    ]
+    ])
 }
 
 pub type AdnlChannelId = [u8; 32];
@@ -348,6 +343,16 @@ pub enum AdnlChannelError {
     InvalidChannelMessageChecksum,
 }
 
+impl From<PacketViewError> for AdnlChannelError {
+    fn from(e: PacketViewError) -> Self {
+        match e {
+            PacketViewError::UnexpectedEnd { remaining, .. } => {
+                Self::ChannelMessageIsTooShort(remaining)
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -394,7 +399,7 @@ mod tests {
                 let parsed_version = channel21.decrypt(&mut received_packet, false).unwrap();
                 assert_eq!(parsed_version, version);
 
-                assert_eq!(received_packet.as_slice(), message);
+                assert_eq!(received_packet.as_bytes(), message);
             }
 
             // Send 2 to 1
@@ -406,7 +411,49 @@ mod tests {
                 let parsed_version = channel12.decrypt(&mut received_packet, true).unwrap();
                 assert_eq!(parsed_version, version);
 
-                assert_eq!(received_packet.as_slice(), message);
+                assert_eq!(received_packet.as_bytes(), message);
+            }
+        }
+    }
+
+    #[test]
+    fn arbitrary_packets_never_panic() {
+        use rand::Rng;
+
+        let peer1_channel_key = ed25519::KeyPair::generate(&mut rand::thread_rng());
+        let peer2_channel_key = ed25519::KeyPair::generate(&mut rand::thread_rng());
+        let channel = |key: &ed25519::KeyPair, other: &ed25519::KeyPair| {
+            Channel::new(
+                NodeIdShort::random(),
+                NodeIdShort::random(),
+                key,
+                other.public_key,
+                now(),
+                ChannelCreationContext::CreateChannel,
+            )
+        };
+        let channel12 = channel(&peer1_channel_key, &peer2_channel_key);
+        let channel21 = channel(&peer2_channel_key, &peer1_channel_key);
+
+        let mut valid_packet = vec![0xaa; 64];
+        channel12.encrypt(&mut valid_packet, false, Some(0));
+
+        let mut rng = rand::thread_rng();
+        for _ in 0..1000 {
+            let mut packet = if rng.gen() {
+                (0..rng.gen_range(0..200)).map(|_| rng.gen()).collect()
+            } else {
+                valid_packet[..rng.gen_range(0..=valid_packet.len())].to_vec()
+            };
+            if !packet.is_empty() && rng.gen() {
+                let i = rng.gen_range(0..packet.len());
+                packet[i] ^= rng.gen::<u8>() | 1;
+            }
+
+            let len = packet.len();
+            let mut view = PacketView::from(packet.as_mut_slice());
+            if channel21.decrypt(&mut view, rng.gen()).is_ok() {
+                assert!(view.remaining() + 64 <= len);
             }
         }
     }
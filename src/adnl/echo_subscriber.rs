@@ -0,0 +1,49 @@
+use std::borrow::Cow;
+use std::time::Duration;
+
+use anyhow::Result;
+
+use crate::proto;
+use crate::subscriber::{QueryConsumingResult, QuerySubscriber, SubscriberContext};
+
+/// Answers [`proto::rpc::NetworkEcho`] queries with the same data.
+///
+/// Used to check connectivity between nodes, see [`crate::adnl::Node::ping_peer`]
+pub struct EchoSubscriber;
+
+#[async_trait::async_trait]
+impl QuerySubscriber for EchoSubscriber {
+    async fn try_consume_query<'a>(
+        &self,
+        ctx: SubscriberContext<'a>,
+        constructor: u32,
+        query: Cow<'a, [u8]>,
+    ) -> Result<QueryConsumingResult<'a>> {
+        if constructor != proto::rpc::NetworkEcho::TL_ID {
+            return Ok(QueryConsumingResult::reject(query));
+        }
+
+        let received_at = ctx.adnl.clock().now_ms();
+
+        let proto::rpc::NetworkEcho { data } = tl_proto::deserialize(&query)?;
+        Ok(QueryConsumingResult::answer(proto::adnl::EchoAnswer {
+            data,
+            received_at,
+        }))
+    }
+
+    fn interested_constructors(&self) -> &[u32] {
+        &[proto::rpc::NetworkEcho::TL_ID]
+    }
+}
+
+/// Echo query result
+#[derive(Debug, Copy, Clone)]
+pub struct PingStats {
+    /// Query roundtrip
+    pub rtt: Duration,
+    /// Remote unix timestamp in milliseconds when the query was received
+    pub remote_received_at: u64,
+    /// Whether the answer contained the same payload
+    pub intact: bool,
+}
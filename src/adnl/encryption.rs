@@ -1,19 +1,63 @@
 use sha2::Digest;
+use subtle::ConstantTimeEq;
+use zeroize::{Zeroize, ZeroizeOnDrop, Zeroizing};
+
+/// 32-byte secret which is zeroized on drop
+#[derive(Clone)]
+pub struct SharedSecret([u8; 32]);
+
+impl SharedSecret {
+    #[inline(always)]
+    pub fn new(secret: [u8; 32]) -> Self {
+        Self(secret)
+    }
+}
+
+impl std::ops::Deref for SharedSecret {
+    type Target = [u8; 32];
+
+    #[inline(always)]
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl std::ops::DerefMut for SharedSecret {
+    #[inline(always)]
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+impl Drop for SharedSecret {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl ZeroizeOnDrop for SharedSecret {}
 
 pub fn build_packet_cipher(shared_secret: &[u8; 32], checksum: &[u8; 32]) -> Aes256Ctr {
     use aes::cipher::KeyIvInit;
 
-    let mut aes_key_bytes: [u8; 32] = *shared_secret;
+    let mut aes_key_bytes = Zeroizing::new(*shared_secret);
     aes_key_bytes[16..32].copy_from_slice(&checksum[16..32]);
-    let mut aes_ctr_bytes: [u8; 16] = checksum[0..16].try_into().unwrap();
+    let mut aes_ctr_bytes = Zeroizing::new([0u8; 16]);
+    aes_ctr_bytes[0..4].copy_from_slice(&checksum[0..4]);
     aes_ctr_bytes[4..16].copy_from_slice(&shared_secret[20..32]);
 
     Aes256Ctr::new(
-        &generic_array::GenericArray::from(aes_key_bytes),
-        &generic_array::GenericArray::from(aes_ctr_bytes),
+        generic_array::GenericArray::from_slice(aes_key_bytes.as_slice()),
+        generic_array::GenericArray::from_slice(aes_ctr_bytes.as_slice()),
     )
 }
 
+/// Compares packet checksums in constant time
+#[inline(always)]
+pub fn checksum_eq(checksum: &[u8; 32], other: &[u8]) -> bool {
+    checksum.as_slice().ct_eq(other).into()
+}
+
 pub fn compute_packet_data_hash(version: Option<u16>, data: &[u8]) -> [u8; 32] {
     match version {
         Some(version) => {
@@ -68,4 +112,25 @@ mod tests {
         cipher.apply_keystream(&mut encoded_data);
         assert_eq!(encoded_data, data);
     }
+
+    #[test]
+    fn shared_secret_zeroized_on_drop() {
+        let mut secret = std::mem::ManuallyDrop::new(SharedSecret::new([0xaa; 32]));
+        let ptr = secret.as_ptr();
+
+        // SAFETY: secret is not used after drop, memory is still owned by `ManuallyDrop`
+        let bytes = unsafe {
+            std::mem::ManuallyDrop::drop(&mut secret);
+            std::slice::from_raw_parts(ptr, 32)
+        };
+        assert_eq!(bytes, &[0; 32]);
+    }
+
+    #[test]
+    fn constant_time_checksum() {
+        let checksum = [1; 32];
+        assert!(checksum_eq(&checksum, &[1; 32]));
+        assert!(!checksum_eq(&checksum, &[2; 32]));
+        assert!(!checksum_eq(&checksum, &[1; 31]));
+    }
 }
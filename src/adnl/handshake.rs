@@ -1,4 +1,3 @@
-use std::convert::TryInto;
 use std::sync::Arc;
 
 use aes::cipher::{StreamCipher, StreamCipherSeek};
@@ -8,15 +7,59 @@ use super::encryption::*;
 use super::keystore::Key;
 use super::node_id::{NodeIdFull, NodeIdShort};
 use super::packet_view::*;
-use crate::util::FastHashMap;
 
 #[inline(always)]
 pub fn compute_handshake_prefix_len(version: Option<u16>) -> usize {
     96 + if version.is_some() { 4 } else { 0 }
 }
 
-/// Modifies `buffer` in-place to contain the handshake packet
+/// Builds an ADNL handshake packet for the specified peer.
+///
+/// `payload` is the serialized packet contents (see [`OutgoingPacketContents`]). Sender identity
+/// and signature are the part of the contents, so only the peer key is needed to encrypt them.
+/// A new ephemeral key is generated for each packet.
+///
+/// [`OutgoingPacketContents`]: crate::proto::adnl::OutgoingPacketContents
 pub fn build_handshake_packet(
+    peer_id_full: &NodeIdFull,
+    payload: &[u8],
+    version: Option<u16>,
+) -> Vec<u8> {
+    let mut buffer = Vec::with_capacity(compute_handshake_prefix_len(version) + payload.len());
+    buffer.extend_from_slice(payload);
+    build_handshake_packet_in_place(
+        &peer_id_full.compute_short_id(),
+        peer_id_full,
+        &mut buffer,
+        version,
+    );
+    buffer
+}
+
+/// Decrypts an ADNL handshake packet addressed to one of the specified keys.
+///
+/// Returns the local id of the matched key and the decrypted packet contents,
+/// or `None` if the packet is addressed to some other key.
+pub fn parse_handshake_packet(
+    keys: &[Arc<Key>],
+    packet: &[u8],
+) -> Result<Option<(NodeIdShort, Vec<u8>)>, HandshakeError> {
+    let mut buffer = packet.to_vec();
+    let mut view = PacketView::from(buffer.as_mut_slice());
+
+    let find_key = |id: &NodeIdShort| keys.iter().find(|key| key.id() == id).map(Arc::as_ref);
+    match parse_handshake_packet_in_place(find_key, &mut view)? {
+        Some((local_id, _)) => {
+            let data_start = packet.len() - view.remaining();
+            buffer.drain(..data_start);
+            Ok(Some((local_id, buffer)))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Modifies `buffer` in-place to contain the handshake packet
+pub(crate) fn build_handshake_packet_in_place(
     peer_id: &NodeIdShort,
     peer_id_full: &NodeIdFull,
     buffer: &mut Vec<u8>,
@@ -24,10 +67,22 @@ pub fn build_handshake_packet(
 ) {
     // Create temp local key
     let temp_private_key = ed25519::SecretKey::generate(&mut rand::thread_rng());
-    let temp_private_key = ed25519::ExpandedSecretKey::from(&temp_private_key);
+    build_handshake_packet_with_temp_key(peer_id, peer_id_full, buffer, version, &temp_private_key)
+}
+
+/// Same as [`build_handshake_packet_in_place`], but with the specified one-time key
+pub(crate) fn build_handshake_packet_with_temp_key(
+    peer_id: &NodeIdShort,
+    peer_id_full: &NodeIdFull,
+    buffer: &mut Vec<u8>,
+    version: Option<u16>,
+    temp_private_key: &ed25519::SecretKey,
+) {
+    let temp_private_key = ed25519::ExpandedSecretKey::from(temp_private_key);
     let temp_public_key = ed25519::PublicKey::from(&temp_private_key);
 
-    let shared_secret = temp_private_key.compute_shared_secret(peer_id_full.public_key());
+    let shared_secret =
+        SharedSecret::new(temp_private_key.compute_shared_secret(peer_id_full.public_key()));
 
     // Prepare packet
     let checksum: [u8; 32] = compute_packet_data_hash(version, buffer.as_slice());
@@ -82,90 +137,83 @@ pub fn build_handshake_packet(
 ///  - 100..... - encrypted data
 ///
 /// **NOTE: even on failure buffer can be modified**
-pub fn parse_handshake_packet(
-    keys: &FastHashMap<NodeIdShort, Arc<Key>>,
+pub(crate) fn parse_handshake_packet_in_place<'k, F>(
+    find_key: F,
     buffer: &mut PacketView<'_>,
-) -> Result<Option<(NodeIdShort, Option<u16>)>, HandshakeError> {
-    const PUBLIC_KEY_RANGE: std::ops::Range<usize> = 32..64;
-
+) -> Result<Option<(NodeIdShort, Option<u16>)>, HandshakeError>
+where
+    F: FnOnce(&NodeIdShort) -> Option<&'k Key>,
+{
     // Ordinary data ranges
     const DATA_START: usize = 96;
-    const CHECKSUM_RANGE: std::ops::Range<usize> = 64..DATA_START;
-    const DATA_RANGE: std::ops::RangeFrom<usize> = DATA_START..;
+    const CHECKSUM_OFFSET: usize = 64;
 
     // Data ranges for packets with ADNL version
     const EXT_DATA_START: usize = 100;
-    const EXT_CHECKSUM_RANGE: std::ops::Range<usize> = 68..EXT_DATA_START;
-    const EXT_DATA_RANGE: std::ops::RangeFrom<usize> = EXT_DATA_START..;
+    const EXT_CHECKSUM_OFFSET: usize = 68;
 
-    if buffer.len() < DATA_START {
+    if buffer.remaining() < DATA_START {
         return Err(HandshakeError::BadHandshakePacketLength);
     }
 
-    // SAFETY: NodeIdShort is 32 (<= 96) bytes and has the same layout as `[u8; 32]`
-    // due to `#[repr(transparent)]`
-    let local_id = unsafe { &*(buffer.as_ptr() as *const NodeIdShort) };
-
     // Get local id
-    let local_key = match keys.get(local_id) {
+    let local_id = NodeIdShort::new(*buffer.get_array::<32>(0)?);
+    let local_key = match find_key(&local_id) {
         Some(key) => key,
         // No local keys found
         None => return Ok(None),
     };
 
     // Compute shared secret
-    let shared_secret =
-        match ed25519::PublicKey::from_bytes(buffer[PUBLIC_KEY_RANGE].try_into().unwrap()) {
-            Some(other_public_key) => local_key
+    let shared_secret = match ed25519::PublicKey::from_bytes(*buffer.get_array::<32>(32)?) {
+        Some(other_public_key) => SharedSecret::new(
+            local_key
                 .secret_key()
                 .compute_shared_secret(&other_public_key),
-            None => return Err(HandshakeError::InvalidPublicKey),
-        };
-
-    if buffer.len() > EXT_DATA_START {
-        if let Some(version) =
-            decode_version::<EXT_DATA_START>((&buffer[..EXT_DATA_START]).try_into().unwrap())
-        {
-            // Build cipher
-            let mut cipher = build_packet_cipher(
-                &shared_secret,
-                &buffer[EXT_CHECKSUM_RANGE].try_into().unwrap(),
-            );
+        ),
+        None => return Err(HandshakeError::InvalidPublicKey),
+    };
+
+    if buffer.remaining() > EXT_DATA_START {
+        if let Some(version) = decode_version(buffer.get_array::<EXT_DATA_START>(0)?) {
+            let checksum = *buffer.get_array::<32>(EXT_CHECKSUM_OFFSET)?;
+            let (_, data) = buffer.split_at_mut(EXT_DATA_START)?;
 
             // Decode data
-            cipher.apply_keystream(&mut buffer[EXT_DATA_RANGE]);
+            let mut cipher = build_packet_cipher(&shared_secret, &checksum);
+            cipher.apply_keystream(data);
 
             // If hash is ok
-            if compute_packet_data_hash(Some(version), &buffer[EXT_DATA_RANGE]).as_slice()
-                == &buffer[EXT_CHECKSUM_RANGE]
-            {
+            if checksum_eq(&compute_packet_data_hash(Some(version), data), &checksum) {
                 // Leave only data in the buffer and return version
-                buffer.remove_prefix(EXT_DATA_START);
-                return Ok(Some((*local_id, Some(version))));
+                buffer.remove_prefix(EXT_DATA_START)?;
+                return Ok(Some((local_id, Some(version))));
             }
 
             // Otherwise restore data
             cipher.seek(0);
-            cipher.apply_keystream(&mut buffer[EXT_DATA_RANGE]);
+            cipher.apply_keystream(data);
         }
     }
 
+    let checksum = *buffer.get_array::<32>(CHECKSUM_OFFSET)?;
+    let (_, data) = buffer.split_at_mut(DATA_START)?;
+
     // Decode data
-    build_packet_cipher(&shared_secret, &buffer[CHECKSUM_RANGE].try_into().unwrap())
-        .apply_keystream(&mut buffer[DATA_RANGE]);
+    build_packet_cipher(&shared_secret, &checksum).apply_keystream(data);
 
     // Check checksum
-    if compute_packet_data_hash(None, &buffer[DATA_RANGE]).as_slice() != &buffer[CHECKSUM_RANGE] {
+    if !checksum_eq(&compute_packet_data_hash(None, data), &checksum) {
         return Err(HandshakeError::BadHandshakePacketChecksum);
     }
 
     // Leave only data in the buffer
-    buffer.remove_prefix(DATA_START);
+    buffer.remove_prefix(DATA_START)?;
 
-    Ok(Some((*local_id, None)))
+    Ok(Some((local_id, None)))
 }
 
-#[derive(thiserror::Error, Debug)]
+#[derive(thiserror::Error, Debug, Copy, Clone, Eq, PartialEq)]
 pub enum HandshakeError {
     #[error("Bad handshake packet length")]
     BadHandshakePacketLength,
@@ -174,3 +222,90 @@ pub enum HandshakeError {
     #[error("Invalid public key")]
     InvalidPublicKey,
 }
+
+impl From<PacketViewError> for HandshakeError {
+    fn from(_: PacketViewError) -> Self {
+        Self::BadHandshakePacketLength
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn handshake_roundtrip() {
+        let sender = Arc::new(Key::from_bytes([1; 32]));
+        let receiver = Arc::new(Key::from_bytes([2; 32]));
+        let payload = b"some packet contents".repeat(10);
+
+        for version in [None, Some(0)] {
+            let packet = build_handshake_packet(receiver.full_id(), &payload, version);
+            assert_eq!(
+                packet.len(),
+                compute_handshake_prefix_len(version) + payload.len()
+            );
+            assert_eq!(&packet[..32], receiver.id().as_slice());
+
+            // Packet is not addressed to the sender
+            assert_eq!(
+                parse_handshake_packet(std::slice::from_ref(&sender), &packet),
+                Ok(None)
+            );
+
+            let (local_id, data) =
+                parse_handshake_packet(&[sender.clone(), receiver.clone()], &packet)
+                    .unwrap()
+                    .unwrap();
+            assert_eq!(&local_id, receiver.id());
+            assert_eq!(data, payload);
+
+            // Corrupted data
+            let mut corrupted = packet.clone();
+            *corrupted.last_mut().unwrap() ^= 1;
+            assert_eq!(
+                parse_handshake_packet(std::slice::from_ref(&receiver), &corrupted),
+                Err(HandshakeError::BadHandshakePacketChecksum)
+            );
+
+            // Truncated packet
+            assert_eq!(
+                parse_handshake_packet(std::slice::from_ref(&receiver), &packet[..64]),
+                Err(HandshakeError::BadHandshakePacketLength)
+            );
+        }
+    }
+
+    #[test]
+    fn arbitrary_packets_never_panic() {
+        use rand::Rng;
+
+        let receiver = Arc::new(Key::from_bytes([2; 32]));
+        let keys = std::slice::from_ref(&receiver);
+        let valid_packet = build_handshake_packet(receiver.full_id(), &[0xaa; 64], Some(0));
+
+        let mut rng = rand::thread_rng();
+        for _ in 0..1000 {
+            let mut packet = match rng.gen_range(0..3) {
+                // Random bytes
+                0 => (0..rng.gen_range(0..200)).map(|_| rng.gen()).collect(),
+                // Random bytes addressed to the known key
+                1 => {
+                    let mut packet = receiver.id().as_slice().to_vec();
+                    packet.extend((0..rng.gen_range(0..200)).map(|_| rng.gen::<u8>()));
+                    packet
+                }
+                // Truncated valid packet
+                _ => valid_packet[..rng.gen_range(0..=valid_packet.len())].to_vec(),
+            };
+            if !packet.is_empty() && rng.gen() {
+                let i = rng.gen_range(0..packet.len());
+                packet[i] ^= rng.gen::<u8>() | 1;
+            }
+
+            if let Ok(Some((_, data))) = parse_handshake_packet(keys, &packet) {
+                assert!(data.len() + compute_handshake_prefix_len(None) <= packet.len());
+            }
+        }
+    }
+}
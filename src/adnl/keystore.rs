@@ -4,7 +4,7 @@ use std::sync::Arc;
 use anyhow::Result;
 use everscale_crypto::ed25519;
 
-use super::node_id::{ComputeNodeIds, NodeIdFull, NodeIdShort};
+use super::node_id::{ComputeNodeIds, NodeIdFull, NodeIdFullError, NodeIdShort};
 use crate::util::FastHashMap;
 
 /// Tagged keystore for ADNL keys
@@ -106,7 +106,8 @@ impl KeystoreBuilder {
 pub struct Key {
     short_id: NodeIdShort,
     full_id: NodeIdFull,
-    secret_key: ed25519::ExpandedSecretKey,
+    /// Secret key bytes, zeroized on drop
+    seed: zeroize::Zeroizing<[u8; 32]>,
 }
 
 impl Key {
@@ -115,6 +116,32 @@ impl Key {
         ed25519::SecretKey::from_bytes(secret_key).into()
     }
 
+    /// Constructs new key from the secret key
+    pub fn from_secret_key(secret_key: ed25519::SecretKey) -> Self {
+        secret_key.into()
+    }
+
+    /// Parses key from the PKCS#8 v1 DER document (RFC 8410)
+    #[cfg(feature = "pkcs8")]
+    pub fn from_pkcs8(der: &[u8]) -> Result<Self, KeystoreError> {
+        match der.strip_prefix(PKCS8_PREFIX.as_slice()) {
+            Some(seed) => match <[u8; 32]>::try_from(seed) {
+                Ok(seed) => Ok(Self::from_bytes(seed)),
+                Err(_) => Err(KeystoreError::InvalidPkcs8),
+            },
+            None => Err(KeystoreError::InvalidPkcs8),
+        }
+    }
+
+    /// Exports the secret key as PKCS#8 v1 DER document (RFC 8410)
+    #[cfg(feature = "pkcs8")]
+    pub fn to_pkcs8(&self) -> zeroize::Zeroizing<Vec<u8>> {
+        let mut der = zeroize::Zeroizing::new(Vec::with_capacity(PKCS8_PREFIX.len() + 32));
+        der.extend_from_slice(&PKCS8_PREFIX);
+        der.extend_from_slice(self.seed.as_slice());
+        der
+    }
+
     /// Returns short key id
     #[inline(always)]
     pub fn id(&self) -> &NodeIdShort {
@@ -127,26 +154,59 @@ impl Key {
         &self.full_id
     }
 
-    /// Returns inner secret key (as expanded)
+    /// Returns public key
+    #[inline(always)]
+    pub fn public_key(&self) -> &ed25519::PublicKey {
+        self.full_id.public_key()
+    }
+
+    /// Returns inner secret key (as expanded).
+    ///
+    /// NOTE: the expanded key is computed on each call and is not zeroized
     #[inline(always)]
-    pub fn secret_key(&self) -> &ed25519::ExpandedSecretKey {
-        &self.secret_key
+    pub fn secret_key(&self) -> ed25519::ExpandedSecretKey {
+        ed25519::SecretKey::from_bytes(*self.seed).expand()
     }
 
     /// Signs serializable boxed data
     #[inline(always)]
     pub fn sign<T: tl_proto::TlWrite<Repr = tl_proto::Boxed>>(&self, data: T) -> [u8; 64] {
-        self.secret_key.sign(data, self.full_id.public_key())
+        self.secret_key().sign(data, self.full_id.public_key())
+    }
+
+    /// Signs raw bytes (with the same expanded key which is used for handshakes)
+    #[inline(always)]
+    pub fn sign_raw(&self, data: &[u8]) -> [u8; 64] {
+        self.secret_key().sign_raw(data, self.full_id.public_key())
+    }
+
+    /// Verifies the signature of serializable boxed data
+    #[inline(always)]
+    pub fn verify<T: tl_proto::TlWrite<Repr = tl_proto::Boxed>>(
+        &self,
+        data: T,
+        signature: &[u8],
+    ) -> Result<(), NodeIdFullError> {
+        self.full_id.verify(data, signature)
+    }
+
+    /// Verifies the signature of raw bytes
+    #[inline(always)]
+    pub fn verify_raw(&self, data: &[u8], signature: &[u8]) -> Result<(), NodeIdFullError> {
+        self.full_id.verify_raw(data, signature)
     }
 }
 
+// The only secret field is zeroized on drop
+impl zeroize::ZeroizeOnDrop for Key {}
+
 impl From<ed25519::SecretKey> for Key {
     fn from(secret_key: ed25519::SecretKey) -> Self {
         let (full_id, short_id) = secret_key.compute_node_ids();
         Self {
             short_id,
             full_id,
-            secret_key: ed25519::ExpandedSecretKey::from(&secret_key),
+            seed: zeroize::Zeroizing::new(secret_key.to_bytes()),
         }
     }
 }
@@ -163,4 +223,75 @@ pub enum KeystoreError {
     KeyTagNotFound(usize),
     #[error("Unexpected key")]
     UnexpectedKey,
+    #[cfg(feature = "pkcs8")]
+    #[error("Invalid PKCS#8 key")]
+    InvalidPkcs8,
+}
+
+/// DER prefix of the `OneAsymmetricKey` with ED25519 algorithm and 32-byte private key
+#[cfg(feature = "pkcs8")]
+const PKCS8_PREFIX: [u8; 16] = [
+    0x30, 0x2e, 0x02, 0x01, 0x00, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x04, 0x22, 0x04, 0x20,
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn secret_key_expansion() {
+        let secret_key = ed25519::SecretKey::from_bytes([0xaa; 32]);
+        let key = Key::from_secret_key(secret_key);
+        assert_eq!(key.secret_key().nonce(), secret_key.expand().nonce());
+
+        let peer_key = ed25519::KeyPair::generate(&mut rand::thread_rng());
+        assert_eq!(
+            key.secret_key().compute_shared_secret(&peer_key.public_key),
+            peer_key.compute_shared_secret(key.public_key())
+        );
+    }
+
+    #[test]
+    fn raw_signatures() {
+        let secret_key = ed25519::SecretKey::from_bytes([0xaa; 32]);
+        let key = Key::from_secret_key(secret_key);
+        assert_eq!(key.id(), Key::from_bytes([0xaa; 32]).id());
+        assert_eq!(key.public_key(), &ed25519::PublicKey::from(&secret_key));
+
+        // Same signature as produced by the plain keypair
+        let signature = key.sign_raw(b"hello");
+        assert_eq!(
+            signature,
+            ed25519::KeyPair::from(&secret_key).sign_raw(b"hello")
+        );
+        assert!(key.public_key().verify_raw(b"hello", &signature));
+        key.verify_raw(b"hello", &signature).unwrap();
+        assert!(key.verify_raw(b"hell0", &signature).is_err());
+        assert!(key.verify_raw(b"hello", &signature[..63]).is_err());
+
+        // TL signatures are signatures of the serialized data
+        let data = crate::proto::adnl::Pong { value: 123 };
+        let signature = key.sign(data);
+        assert_eq!(signature, key.sign_raw(&tl_proto::serialize(data)));
+        key.verify(data, &signature).unwrap();
+        key.full_id().verify(data, &signature).unwrap();
+    }
+
+    #[cfg(feature = "pkcs8")]
+    #[test]
+    fn pkcs8_roundtrip() {
+        let key = Key::from_bytes(rand::random());
+        let der = key.to_pkcs8();
+        assert_eq!(der.len(), 48);
+
+        let parsed = Key::from_pkcs8(&der).unwrap();
+        assert_eq!(parsed.id(), key.id());
+        assert_eq!(parsed.sign_raw(b"test"), key.sign_raw(b"test"));
+
+        assert!(Key::from_pkcs8(&der[..47]).is_err());
+        assert!(Key::from_pkcs8(&[der.as_slice(), &[0]].concat()).is_err());
+        let mut invalid = der.to_vec();
+        invalid[11] = 0x71; // X448
+        assert!(Key::from_pkcs8(&invalid).is_err());
+    }
 }
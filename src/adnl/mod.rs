@@ -82,16 +82,45 @@ use anyhow::{Context, Result};
 use frunk_core::hlist::{HCons, HList, HNil, Selector};
 use frunk_core::indices::Here;
 
+pub use self::echo_subscriber::{EchoSubscriber, PingStats};
+pub use self::handshake::{build_handshake_packet, parse_handshake_packet, HandshakeError};
 pub use self::keystore::{Key, Keystore};
-pub use self::node::{Node, NodeMetrics, NodeOptions};
+pub use self::node::{
+    AddPeerOutcome, ChannelInfo, ChannelSetupTimeout, EnsureChannelError, HolePunchError,
+    LatencyHistogram, LatencyReport, Node, NodeMetrics, NodeOptions, NodeRates, PacketDropMetrics,
+    PacketDropReason, PeerCountByContext, PeerMetrics, QueryOptions, Rates, TrafficMetrics,
+    LATENCY_BUCKETS_MS, MAX_PROBED_DATAGRAM_SIZE, MIN_PROBED_DATAGRAM_SIZE,
+};
 pub use self::node_id::{ComputeNodeIds, NodeIdFull, NodeIdShort};
-pub use self::peer::{NewPeerContext, PeerFilter};
+pub use self::packet_view::{PacketView, PacketViewError};
+pub use self::peer::{NewPeerContext, PeerCapabilities, PeerCompat, PeerFilter};
+pub use self::peer_filter::{AllowAllPeers, CidrAndIdListFilter, Ipv4Cidr, ParseCidrError};
 pub use self::peers_set::PeersSet;
+pub use self::queries_cache::{QueryId, TraceId};
+pub use self::rendezvous_subscriber::RendezvousSubscriber;
+pub use self::reputation::{InMemoryReputation, PeerReputation, ReputationEvent};
+pub use self::send_queue::SendQueuePolicy;
+#[cfg(any(test, feature = "test-utils"))]
+pub use self::virtual_network::{LinkOptions, VirtualNetwork};
+
+#[cfg(test)]
+pub(crate) use self::channel::{Channel, ChannelCreationContext};
+#[cfg(test)]
+pub(crate) use self::handshake::build_handshake_packet_with_temp_key;
+pub(crate) use self::node::DeferredAnswerSlot;
+#[cfg(feature = "rldp")]
+pub(crate) use self::node::DependentLayer;
+#[cfg(feature = "overlay")]
+pub(crate) use self::reputation::SharedReputation;
+#[cfg(feature = "rldp")]
+pub(crate) use self::transfer::DisplayTransferId;
 
 use crate::subscriber::{MessageSubscriber, QuerySubscriber};
 use crate::util::{DeferredInitialization, NetworkBuilder};
 
+mod answer_encryption;
 mod channel;
+mod echo_subscriber;
 mod encryption;
 mod handshake;
 mod keystore;
@@ -99,11 +128,17 @@ mod node;
 mod node_id;
 mod packet_view;
 mod peer;
+mod peer_filter;
 mod peers_set;
 mod ping_subscriber;
 mod queries_cache;
+mod rendezvous_subscriber;
+mod reputation;
+mod send_queue;
 mod socket;
 mod transfer;
+#[cfg(any(test, feature = "test-utils"))]
+mod virtual_network;
 
 pub(crate) type Deferred = Result<Arc<Node>>;
 
@@ -242,7 +277,7 @@ where
     ///         _constructor: u32,
     ///         _query: Cow<'a, [u8]>,
     ///     ) -> Result<QueryConsumingResult<'a>> {
-    ///         Ok(QueryConsumingResult::Consumed(None))
+    ///         Ok(QueryConsumingResult::no_answer())
     ///     }
     /// }
     ///
@@ -0,0 +1,257 @@
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use dashmap::mapref::entry::Entry;
+use parking_lot::Mutex;
+use tokio::sync::Notify;
+
+use super::{Node, NodeOptions};
+use crate::adnl::node_id::NodeIdShort;
+use crate::proto;
+
+/// Channel was not confirmed by the peer during [`NodeOptions::channel_setup_timeout_ms`]
+/// although `CreateChannel` was resent several times
+#[derive(thiserror::Error, Debug, Copy, Clone)]
+#[error("Channel setup with {peer_id} timed out after {attempts} attempts")]
+pub struct ChannelSetupTimeout {
+    pub peer_id: NodeIdShort,
+    /// Number of sent `CreateChannel` messages
+    pub attempts: u32,
+}
+
+/// Retransmission schedule of the `CreateChannel` message which is shared
+/// by all waiters of the channel (pending queries and [`Node::ensure_channel`])
+pub(super) struct ChannelSetup {
+    /// Retransmitted messages must be identical, so that the peer doesn't recreate the channel
+    date: u32,
+    state: Mutex<SetupState>,
+    finished: Notify,
+}
+
+struct SetupState {
+    deadline: Instant,
+    next_retry_at: Instant,
+    delay: Duration,
+    attempts: u32,
+    status: SetupStatus,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq)]
+enum SetupStatus {
+    Pending,
+    Established,
+    TimedOut,
+}
+
+enum SetupStep {
+    Resend,
+    Wait(Instant),
+    Established,
+    TimedOut(u32),
+}
+
+impl ChannelSetup {
+    fn new(date: u32, now: Instant, options: &NodeOptions) -> Self {
+        let delay = Duration::from_millis(options.channel_setup_retry_base_ms);
+        Self {
+            date,
+            state: Mutex::new(SetupState {
+                deadline: now + Duration::from_millis(options.channel_setup_timeout_ms),
+                next_retry_at: now + delay,
+                delay,
+                attempts: 1,
+                status: SetupStatus::Pending,
+            }),
+            finished: Notify::new(),
+        }
+    }
+
+    fn step(&self, now: Instant, max_delay: Duration) -> SetupStep {
+        let mut state = self.state.lock();
+        match state.status {
+            SetupStatus::Established => return SetupStep::Established,
+            SetupStatus::TimedOut => return SetupStep::TimedOut(state.attempts),
+            SetupStatus::Pending => {}
+        }
+
+        if now >= state.deadline {
+            state.status = SetupStatus::TimedOut;
+            drop(state);
+            self.finished.notify_waiters();
+            return self.step(now, max_delay);
+        }
+
+        if now >= state.next_retry_at {
+            state.delay = std::cmp::min(state.delay * 2, max_delay);
+            state.next_retry_at = now + state.delay;
+            state.attempts += 1;
+            SetupStep::Resend
+        } else {
+            SetupStep::Wait(std::cmp::min(state.next_retry_at, state.deadline))
+        }
+    }
+
+    fn is_expired(&self, now: Instant) -> bool {
+        let state = self.state.lock();
+        state.status != SetupStatus::Pending || now >= state.deadline
+    }
+
+    fn finish(&self, status: SetupStatus) {
+        let mut state = self.state.lock();
+        if state.status == SetupStatus::Pending {
+            state.status = status;
+        }
+        drop(state);
+        self.finished.notify_waiters();
+    }
+}
+
+impl Node {
+    /// Starts the `CreateChannel` retransmission schedule for the peer
+    /// unless it is already running or disabled. Returns the channel date
+    pub(super) fn begin_channel_setup(&self, local_id: &NodeIdShort, peer_id: &NodeIdShort) -> u32 {
+        let date = self.clock.now();
+        let options = self.options.load();
+        if options.channel_setup_retry_base_ms == 0 {
+            return date;
+        }
+
+        let now = self.clock.instant();
+        let new_setup = || Arc::new(ChannelSetup::new(date, now, &options));
+        match self.channel_setups.entry((*local_id, *peer_id)) {
+            // Nobody waited for the previous setup to time out
+            Entry::Occupied(mut entry) if entry.get().is_expired(now) => {
+                entry.insert(new_setup());
+                date
+            }
+            Entry::Occupied(entry) => entry.get().date,
+            Entry::Vacant(entry) => {
+                entry.insert(new_setup());
+                date
+            }
+        }
+    }
+
+    /// Stops the retransmission after the channel was confirmed
+    pub(super) fn complete_channel_setup(&self, local_id: &NodeIdShort, peer_id: &NodeIdShort) {
+        if let Some((_, setup)) = self.channel_setups.remove(&(*local_id, *peer_id)) {
+            setup.finish(SetupStatus::Established);
+        }
+    }
+
+    pub(super) fn channel_setup(
+        &self,
+        local_id: &NodeIdShort,
+        peer_id: &NodeIdShort,
+    ) -> Option<Arc<ChannelSetup>> {
+        self.channel_setups
+            .get(&(*local_id, *peer_id))
+            .map(|setup| setup.value().clone())
+    }
+
+    /// Returns the setup which is still waiting for the channel confirmation
+    pub(super) fn pending_channel_setup(
+        &self,
+        local_id: &NodeIdShort,
+        peer_id: &NodeIdShort,
+    ) -> Option<Arc<ChannelSetup>> {
+        let now = self.clock.instant();
+        self.channel_setup(local_id, peer_id)
+            .filter(|setup| !setup.is_expired(now))
+    }
+
+    /// Resends `CreateChannel` (with an empty message) until the channel is confirmed.
+    /// Never completes after the channel was established
+    pub(super) async fn drive_channel_setup(
+        &self,
+        local_id: &NodeIdShort,
+        peer_id: &NodeIdShort,
+        setup: Option<&Arc<ChannelSetup>>,
+    ) -> ChannelSetupTimeout {
+        let setup = match setup {
+            Some(setup) => setup,
+            None => return std::future::pending().await,
+        };
+
+        loop {
+            let finished = setup.finished.notified();
+            tokio::pin!(finished);
+            finished.as_mut().enable();
+
+            let max_delay = Duration::from_millis(self.options.load().channel_setup_retry_max_ms);
+            match setup.step(self.clock.instant(), max_delay) {
+                SetupStep::Resend => {
+                    tracing::trace!(%local_id, %peer_id, "resending CreateChannel");
+                    self.channel_setup_retransmits
+                        .fetch_add(1, Ordering::Relaxed);
+                    // Channel creation messages are added to any message without ready channel
+                    self.send_message(local_id, peer_id, proto::adnl::Message::Nop, false)
+                        .ok();
+                }
+                SetupStep::Wait(until) => {
+                    tokio::select! {
+                        _ = self.clock.sleep_until(until) => {}
+                        _ = finished => {}
+                    }
+                }
+                SetupStep::Established => return std::future::pending().await,
+                SetupStep::TimedOut(attempts) => {
+                    if self
+                        .channel_setups
+                        .remove_if(&(*local_id, *peer_id), |_, item| Arc::ptr_eq(item, setup))
+                        .is_some()
+                    {
+                        tracing::debug!(%local_id, %peer_id, attempts, "channel setup timed out");
+                        self.channel_setup_timeouts.fetch_add(1, Ordering::Relaxed);
+                    }
+                    return ChannelSetupTimeout {
+                        peer_id: *peer_id,
+                        attempts,
+                    };
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exponential_backoff() {
+        let options = NodeOptions {
+            channel_setup_retry_base_ms: 100,
+            channel_setup_retry_max_ms: 300,
+            channel_setup_timeout_ms: 1000,
+            ..Default::default()
+        };
+        let max_delay = Duration::from_millis(options.channel_setup_retry_max_ms);
+        let start = Instant::now();
+        let at = |ms: u64| start + Duration::from_millis(ms);
+
+        let setup = ChannelSetup::new(0, start, &options);
+        assert!(matches!(setup.step(at(0), max_delay), SetupStep::Wait(t) if t == at(100)));
+
+        // Delays: 100, 200, 300, 300
+        let mut resent_at = Vec::new();
+        for ms in (0..1000).step_by(10) {
+            if let SetupStep::Resend = setup.step(at(ms), max_delay) {
+                resent_at.push(ms);
+            }
+        }
+        assert_eq!(resent_at, [100, 300, 600, 900]);
+        assert!(matches!(
+            setup.step(at(1000), max_delay),
+            SetupStep::TimedOut(5)
+        ));
+
+        // Confirmation after the timeout is ignored
+        setup.finish(SetupStatus::Established);
+        assert!(matches!(
+            setup.step(at(1000), max_delay),
+            SetupStep::TimedOut(5)
+        ));
+    }
+}
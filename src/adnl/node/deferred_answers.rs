@@ -0,0 +1,179 @@
+use std::cmp::Ordering as CmpOrdering;
+use std::collections::BinaryHeap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+use parking_lot::Mutex;
+
+use crate::adnl::Node;
+
+/// Sends the deferred answer the same way as the inline answer would have been sent
+pub(crate) type DeferredResponder = Box<dyn FnOnce(&Arc<Node>, Vec<u8>) + Send>;
+
+/// Answer state shared by all clones of the [`AnswerToken`]
+///
+/// [`AnswerToken`]: crate::subscriber::AnswerToken
+#[derive(Default)]
+pub(crate) struct DeferredAnswerSlot {
+    state: Mutex<SlotState>,
+}
+
+#[derive(Default)]
+enum SlotState {
+    /// Token was not returned by the subscriber yet
+    #[default]
+    Created,
+    /// Answer was sent before the subscriber returned the token
+    Answered(Vec<u8>),
+    /// Transport waits for the answer
+    Registered {
+        responder: DeferredResponder,
+        expires_at: Instant,
+    },
+    /// Answer was sent or expired
+    Done,
+}
+
+/// Answers to the queries which were consumed with [`QueryConsumingResult::ConsumedDeferred`]
+///
+/// [`QueryConsumingResult::ConsumedDeferred`]: crate::QueryConsumingResult::ConsumedDeferred
+#[derive(Default)]
+pub(super) struct DeferredAnswers {
+    /// Registered answers by the expiration time. Can contain the already sent answers
+    queue: Mutex<BinaryHeap<PendingAnswer>>,
+    pending: AtomicUsize,
+    expired: AtomicU64,
+}
+
+impl DeferredAnswers {
+    /// Number of answers which are awaited
+    pub fn len(&self, now: Instant) -> usize {
+        self.expire(now);
+        self.pending.load(Ordering::Acquire)
+    }
+
+    /// Total number of answers which were not sent before the expiration
+    pub fn expired(&self) -> u64 {
+        self.expired.load(Ordering::Relaxed)
+    }
+
+    /// Starts waiting for the answer after the subscriber returned the token
+    pub fn register(
+        &self,
+        node: &Arc<Node>,
+        slot: &Arc<DeferredAnswerSlot>,
+        responder: DeferredResponder,
+        now: Instant,
+        expires_at: Instant,
+    ) {
+        self.expire(now);
+
+        let mut state = slot.state.lock();
+        match std::mem::replace(&mut *state, SlotState::Done) {
+            SlotState::Created => {
+                *state = SlotState::Registered {
+                    responder,
+                    expires_at,
+                };
+                drop(state);
+
+                self.pending.fetch_add(1, Ordering::AcqRel);
+                self.queue.lock().push(PendingAnswer {
+                    expires_at,
+                    slot: slot.clone(),
+                });
+            }
+            SlotState::Answered(answer) => {
+                drop(state);
+                responder(node, answer);
+            }
+            // Token was returned twice
+            other => *state = other,
+        }
+    }
+
+    /// Sends the answer through the registered transport.
+    /// Returns `false` if the token is expired or was already answered
+    pub fn send(
+        &self,
+        node: &Arc<Node>,
+        slot: &DeferredAnswerSlot,
+        answer: Vec<u8>,
+        now: Instant,
+    ) -> bool {
+        self.expire(now);
+
+        let mut state = slot.state.lock();
+        match std::mem::replace(&mut *state, SlotState::Done) {
+            SlotState::Created => {
+                *state = SlotState::Answered(answer);
+                true
+            }
+            SlotState::Registered {
+                responder,
+                expires_at,
+            } => {
+                drop(state);
+                self.pending.fetch_sub(1, Ordering::AcqRel);
+                if expires_at > now {
+                    responder(node, answer);
+                    true
+                } else {
+                    self.expired.fetch_add(1, Ordering::Relaxed);
+                    false
+                }
+            }
+            other => {
+                *state = other;
+                false
+            }
+        }
+    }
+
+    /// Drops the responders of the expired answers
+    fn expire(&self, now: Instant) {
+        let mut expired = Vec::new();
+        {
+            let mut queue = self.queue.lock();
+            while matches!(queue.peek(), Some(item) if item.expires_at <= now) {
+                expired.extend(queue.pop());
+            }
+        }
+
+        for item in expired {
+            let mut state = item.slot.state.lock();
+            if matches!(&*state, SlotState::Registered { .. }) {
+                *state = SlotState::Done;
+                self.pending.fetch_sub(1, Ordering::AcqRel);
+                self.expired.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+struct PendingAnswer {
+    expires_at: Instant,
+    slot: Arc<DeferredAnswerSlot>,
+}
+
+impl Eq for PendingAnswer {}
+
+impl PartialEq for PendingAnswer {
+    fn eq(&self, other: &Self) -> bool {
+        self.expires_at == other.expires_at
+    }
+}
+
+impl Ord for PendingAnswer {
+    fn cmp(&self, other: &Self) -> CmpOrdering {
+        // NOTE: the earliest answer is at the top of the max-heap
+        other.expires_at.cmp(&self.expires_at)
+    }
+}
+
+impl PartialOrd for PendingAnswer {
+    fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
@@ -0,0 +1,72 @@
+use std::collections::VecDeque;
+
+use parking_lot::Mutex;
+
+use crate::util::FastHashSet;
+
+/// Fingerprints of the latest successfully decrypted handshake packets
+#[derive(Default)]
+pub(super) struct HandshakeReplays {
+    state: Mutex<HandshakeReplaysState>,
+}
+
+impl HandshakeReplays {
+    /// Whether the packet with this fingerprint was already received
+    pub fn contains(&self, fingerprint: &[u8; 32]) -> bool {
+        self.state.lock().fingerprints.contains(fingerprint)
+    }
+
+    /// Remembers the fingerprint, evicting the oldest ones to keep at most `capacity` items.
+    ///
+    /// Returns `false` if the fingerprint was already remembered
+    pub fn insert(&self, fingerprint: [u8; 32], capacity: usize) -> bool {
+        let mut state = self.state.lock();
+        if !state.fingerprints.insert(fingerprint) {
+            return false;
+        }
+        state.order.push_back(fingerprint);
+
+        while state.order.len() > capacity {
+            if let Some(oldest) = state.order.pop_front() {
+                state.fingerprints.remove(&oldest);
+            }
+        }
+        true
+    }
+}
+
+#[derive(Default)]
+struct HandshakeReplaysState {
+    fingerprints: FastHashSet<[u8; 32]>,
+    /// Fingerprints in insertion order
+    order: VecDeque<[u8; 32]>,
+}
+
+/// Bytes which follow the local id and the sender ephemeral key. They contain
+/// the packet checksum (or the XOR'ed version and the beginning of the checksum)
+pub(super) fn handshake_fingerprint(packet: &[u8]) -> Option<&[u8; 32]> {
+    packet.get(64..96)?.try_into().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bounded_window() {
+        let replays = HandshakeReplays::default();
+        assert!(replays.insert([1; 32], 2));
+        assert!(!replays.insert([1; 32], 2));
+        assert!(replays.insert([2; 32], 2));
+        assert!(replays.insert([3; 32], 2));
+
+        // The oldest fingerprint is evicted
+        assert!(!replays.contains(&[1; 32]));
+        assert!(replays.contains(&[2; 32]) && replays.contains(&[3; 32]));
+
+        assert!(handshake_fingerprint(&[0; 95]).is_none());
+        let mut packet = [0; 96];
+        packet[64..].fill(7);
+        assert_eq!(handshake_fingerprint(&packet), Some(&[7; 32]));
+    }
+}
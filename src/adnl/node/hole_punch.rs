@@ -0,0 +1,427 @@
+use std::net::SocketAddrV4;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+
+use super::{ChannelInfo, Node};
+use crate::adnl::node_id::{NodeIdFull, NodeIdShort};
+use crate::adnl::peer::{NewPeerContext, Peer};
+use crate::proto;
+
+/// Failed stage of [`Node::connect_via`]
+#[derive(thiserror::Error, Debug)]
+pub enum HolePunchError {
+    #[error("Rendezvous query failed")]
+    RendezvousFailed(#[source] anyhow::Error),
+    #[error("Rendezvous peer didn't answer in time")]
+    RendezvousTimedOut,
+    #[error("Target is unknown to the rendezvous peer")]
+    TargetUnknown,
+    #[error("Rendezvous peer returned another target")]
+    InvalidAnswer,
+    #[error("Target was rejected by the peer filter")]
+    TargetRejected,
+    #[error("Channel with the target was not established in time")]
+    NotConnected,
+}
+
+impl Node {
+    /// Establishes the channel with the peer behind NAT through the rendezvous peer
+    /// which both sides can reach.
+    ///
+    /// Asks the rendezvous peer for the target address it observes. The rendezvous peer
+    /// notifies the target, and then both sides send handshake packets to each other
+    /// every [`NodeOptions::hole_punch_interval_ms`], so that each NAT lets in the packets
+    /// of the other side. Returns immediately if the channel is already ready.
+    ///
+    /// The rendezvous peer and the target must have [`RendezvousSubscriber`] registered,
+    /// which trusts the local node and the rendezvous peer respectively.
+    /// Fails with [`HolePunchError`] if the channel was not established during the `timeout`
+    /// (or [`NodeOptions::hole_punch_timeout_ms`])
+    ///
+    /// [`NodeOptions::hole_punch_interval_ms`]: super::NodeOptions::hole_punch_interval_ms
+    /// [`NodeOptions::hole_punch_timeout_ms`]: super::NodeOptions::hole_punch_timeout_ms
+    /// [`RendezvousSubscriber`]: crate::adnl::RendezvousSubscriber
+    pub async fn connect_via(
+        &self,
+        local_id: &NodeIdShort,
+        rendezvous: &NodeIdShort,
+        target: &NodeIdShort,
+        timeout: Option<u64>,
+    ) -> Result<ChannelInfo> {
+        if let Some(channel) = self.ready_channel(local_id, target, false) {
+            return Ok(channel);
+        }
+
+        let timeout = timeout.unwrap_or(self.options.load().hole_punch_timeout_ms);
+        let started_at = self.clock.instant();
+        let _pending = PendingRendezvous::new(self, rendezvous);
+
+        let query = proto::rpc::NetworkRendezvous {
+            target: target.as_slice(),
+        };
+        let answer = self
+            .query::<_, proto::adnl::RendezvousPeer>(local_id, rendezvous, query, Some(timeout))
+            .await
+            .map_err(HolePunchError::RendezvousFailed)?;
+        let (full_id, addr) = match answer {
+            Some(proto::adnl::RendezvousPeer::Found { id, address }) => {
+                match NodeIdFull::try_from(id.as_equivalent_ref()) {
+                    Ok(full_id) if full_id.compute_short_id() == *target => {
+                        (full_id, SocketAddrV4::from(address))
+                    }
+                    _ => return Err(HolePunchError::InvalidAnswer.into()),
+                }
+            }
+            Some(proto::adnl::RendezvousPeer::NotFound) => {
+                return Err(HolePunchError::TargetUnknown.into())
+            }
+            None => return Err(HolePunchError::RendezvousTimedOut.into()),
+        };
+
+        if !self.add_peer(NewPeerContext::AdnlPacket, local_id, target, addr, full_id)? {
+            return Err(HolePunchError::TargetRejected.into());
+        }
+
+        let elapsed = self.clock.instant().saturating_duration_since(started_at);
+        let remaining = Duration::from_millis(timeout).saturating_sub(elapsed);
+        match self.punch_hole(local_id, target, remaining).await {
+            Some(channel) => Ok(channel),
+            None => Err(HolePunchError::NotConnected.into()),
+        }
+    }
+
+    /// Whether [`Node::connect_via`] through this rendezvous peer is in progress
+    pub(crate) fn is_connecting_via(&self, rendezvous: &NodeIdShort) -> bool {
+        self.pending_rendezvous.contains_key(rendezvous)
+    }
+
+    /// Sends the observed address of the requester to the target.
+    /// Returns the full id and the observed address of the target, or `None` if it is unknown
+    pub(crate) fn introduce_peers(
+        &self,
+        local_id: &NodeIdShort,
+        requester: &NodeIdShort,
+        target: &NodeIdShort,
+    ) -> Result<Option<(NodeIdFull, SocketAddrV4)>> {
+        fn observed(peer: &Peer) -> (NodeIdFull, SocketAddrV4) {
+            (
+                *peer.id(),
+                peer.observed_addr().unwrap_or_else(|| peer.addr()),
+            )
+        }
+
+        let peers = self.get_peers(local_id)?;
+        let (target_id, target_addr) = match peers.get(target) {
+            Some(peer) => observed(&peer),
+            None => return Ok(None),
+        };
+        let (requester_id, requester_addr) = match peers.get(requester) {
+            Some(peer) => observed(&peer),
+            None => return Ok(None),
+        };
+
+        tracing::debug!(%local_id, %requester, %target, "introducing peers");
+        let message = tl_proto::serialize(proto::adnl::HolePunch {
+            id: requester_id.as_tl(),
+            address: (&requester_addr).into(),
+        });
+        self.send_custom_message(local_id, target, &message)?;
+
+        Ok(Some((target_id, target_addr)))
+    }
+
+    /// Starts the simultaneous open with the peer introduced by the rendezvous peer.
+    /// Does nothing if it is already running for this peer.
+    ///
+    /// NOTE: the address of the already known peer is not changed
+    pub(crate) fn start_hole_punch(
+        self: &Arc<Self>,
+        local_id: &NodeIdShort,
+        peer_id_full: NodeIdFull,
+        addr: SocketAddrV4,
+    ) -> Result<()> {
+        let peer_id = peer_id_full.compute_short_id();
+        let known = self.get_peers(local_id)?.contains_key(&peer_id);
+        if !known
+            && !self.add_peer(
+                NewPeerContext::AdnlPacket,
+                local_id,
+                &peer_id,
+                addr,
+                peer_id_full,
+            )?
+        {
+            return Ok(());
+        }
+        if !self.hole_punches.insert(peer_id) {
+            return Ok(());
+        }
+
+        let node = self.clone();
+        let local_id = *local_id;
+        let timeout = Duration::from_millis(self.options.load().hole_punch_timeout_ms);
+        self.tasks
+            .spawn(&self.runtime, "adnl_hole_punch", async move {
+                let connected = node
+                    .punch_hole(&local_id, &peer_id, timeout)
+                    .await
+                    .is_some();
+                tracing::debug!(%local_id, %peer_id, connected, "hole punching finished");
+                node.hole_punches.remove(&peer_id);
+            });
+        Ok(())
+    }
+
+    /// Sends handshake packets to the peer until the channel is established
+    async fn punch_hole(
+        &self,
+        local_id: &NodeIdShort,
+        peer_id: &NodeIdShort,
+        timeout: Duration,
+    ) -> Option<ChannelInfo> {
+        let interval = Duration::from_millis(self.options.load().hole_punch_interval_ms.max(1));
+        let deadline = self.clock.sleep(timeout);
+        tokio::pin!(deadline);
+
+        loop {
+            let notified = self.channel_established.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+
+            if let Some(channel) = self.ready_channel(local_id, peer_id, true) {
+                return Some(channel);
+            }
+
+            // Channel creation messages are added to any message without ready channel
+            self.send_message(local_id, peer_id, proto::adnl::Message::Nop, false)
+                .ok()?;
+
+            tokio::select! {
+                biased;
+                _ = notified => {}
+                _ = &mut deadline => return None,
+                _ = self.clock.sleep(interval) => {}
+            }
+        }
+    }
+
+    fn ready_channel(
+        &self,
+        local_id: &NodeIdShort,
+        peer_id: &NodeIdShort,
+        created: bool,
+    ) -> Option<ChannelInfo> {
+        let channel = self.channels_by_peers.get(&(*local_id, *peer_id))?;
+        channel.ready().then(|| ChannelInfo {
+            local_id: *local_id,
+            peer_id: *peer_id,
+            peer_channel_date: channel.peer_channel_date(),
+            created,
+        })
+    }
+}
+
+/// Marks the rendezvous peer as used by [`Node::connect_via`] until dropped
+struct PendingRendezvous<'a> {
+    node: &'a Node,
+    rendezvous: NodeIdShort,
+}
+
+impl<'a> PendingRendezvous<'a> {
+    fn new(node: &'a Node, rendezvous: &NodeIdShort) -> Self {
+        *node.pending_rendezvous.entry(*rendezvous).or_insert(0) += 1;
+        Self {
+            node,
+            rendezvous: *rendezvous,
+        }
+    }
+}
+
+impl Drop for PendingRendezvous<'_> {
+    fn drop(&mut self) {
+        self.node
+            .pending_rendezvous
+            .remove_if_mut(&self.rendezvous, |_, count| {
+                *count -= 1;
+                *count == 0
+            });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::adnl::VirtualNetwork;
+    use crate::util::fixtures::add_virtual_node;
+    use crate::util::AddressListBuilder;
+
+    #[tokio::test]
+    async fn connects_through_rendezvous() {
+        let network = VirtualNetwork::new(0);
+        let make_node = |behind_nat: bool| {
+            let node = add_virtual_node(&network, Default::default());
+            if behind_nat {
+                let external = network.add_nat(node.socket_addr());
+                node.set_address_list(AddressListBuilder::new().with_address(external))
+                    .unwrap();
+            }
+            node.add_echo_subscriber().unwrap();
+            node
+        };
+        let id = |node: &Node| *node.key_by_tag(0).unwrap().id();
+
+        let rendezvous = make_node(false);
+        let (left, right) = (make_node(true), make_node(true));
+        let (rendezvous_id, left_id, right_id) = (id(&rendezvous), id(&left), id(&right));
+
+        // Rendezvous peer only introduces the left node, and the right node
+        // doesn't accept the hole punch requests from anyone else
+        rendezvous.add_rendezvous_subscriber([left_id]).unwrap();
+        left.add_rendezvous_subscriber([]).unwrap();
+        right.add_rendezvous_subscriber([rendezvous_id]).unwrap();
+        for node in [&rendezvous, &left, &right] {
+            node.start().unwrap();
+        }
+
+        // Both nodes can reach the rendezvous peer
+        let rendezvous_key = rendezvous.key_by_tag(0).unwrap();
+        for (node, local_id) in [(&left, &left_id), (&right, &right_id)] {
+            node.add_peer(
+                NewPeerContext::AdnlPacket,
+                local_id,
+                &rendezvous_id,
+                rendezvous.socket_addr(),
+                *rendezvous_key.full_id(),
+            )
+            .unwrap();
+            node.ensure_channel(local_id, &rendezvous_id, Some(1000))
+                .await
+                .unwrap();
+        }
+
+        // But not each other
+        let right_addr = rendezvous
+            .get_peer_address(&rendezvous_id, &right_id)
+            .unwrap();
+        assert_ne!(right_addr, right.socket_addr());
+        left.add_peer(
+            NewPeerContext::AdnlPacket,
+            &left_id,
+            &right_id,
+            right_addr,
+            *right.key_by_tag(0).unwrap().full_id(),
+        )
+        .unwrap();
+        assert!(left
+            .ensure_channel(&left_id, &right_id, Some(300))
+            .await
+            .is_err());
+
+        let unknown_id = NodeIdShort::new([1; 32]);
+        let err = left
+            .connect_via(&left_id, &rendezvous_id, &unknown_id, Some(1000))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<HolePunchError>(),
+            Some(HolePunchError::TargetUnknown)
+        ));
+
+        // Untrusted requester gets nothing
+        let err = right
+            .connect_via(&right_id, &rendezvous_id, &left_id, Some(1000))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<HolePunchError>(),
+            Some(HolePunchError::TargetUnknown)
+        ));
+
+        let channel = left
+            .connect_via(&left_id, &rendezvous_id, &right_id, Some(2000))
+            .await
+            .unwrap();
+        assert!(channel.created);
+        assert!(!left.is_connecting_via(&rendezvous_id));
+
+        // Both NAT mappings are open now
+        assert!(left
+            .ping_peer(&left_id, &right_id, 16, Some(1000))
+            .await
+            .unwrap()
+            .is_some());
+        assert!(right
+            .ping_peer(&right_id, &left_id, 16, Some(1000))
+            .await
+            .unwrap()
+            .is_some());
+    }
+    #[tokio::test]
+    async fn untrusted_hole_punches_are_ignored() {
+        let network = VirtualNetwork::new(0);
+        let target = add_virtual_node(&network, Default::default());
+        let sender = add_virtual_node(&network, Default::default());
+        target.add_rendezvous_subscriber([]).unwrap();
+        target.start().unwrap();
+        sender.start().unwrap();
+
+        let target_key = target.key_by_tag(0).unwrap();
+        let sender_key = sender.key_by_tag(0).unwrap();
+        sender
+            .add_peer(
+                NewPeerContext::AdnlPacket,
+                sender_key.id(),
+                target_key.id(),
+                target.socket_addr(),
+                *target_key.full_id(),
+            )
+            .unwrap();
+
+        // Known peer which the sender wants to redirect
+        let victim_key = crate::adnl::Key::from_bytes(rand::random());
+        let victim_addr = SocketAddrV4::new(std::net::Ipv4Addr::new(1, 2, 3, 4), 1);
+        target
+            .add_peer(
+                NewPeerContext::AdnlPacket,
+                target_key.id(),
+                victim_key.id(),
+                victim_addr,
+                *victim_key.full_id(),
+            )
+            .unwrap();
+
+        let message = tl_proto::serialize(proto::adnl::HolePunch {
+            id: victim_key.full_id().as_tl(),
+            address: (&SocketAddrV4::new(std::net::Ipv4Addr::new(5, 6, 7, 8), 1)).into(),
+        });
+        sender
+            .send_custom_message(sender_key.id(), target_key.id(), &message)
+            .unwrap();
+        // Message is sent with the channel creation
+        sender
+            .ensure_channel(sender_key.id(), target_key.id(), Some(1000))
+            .await
+            .unwrap();
+
+        assert!(!target.hole_punches.contains(victim_key.id()));
+        assert_eq!(
+            target.get_peer_address(target_key.id(), victim_key.id()),
+            Some(victim_addr)
+        );
+
+        // Even the trusted rendezvous peer can't change the known address
+        target
+            .start_hole_punch(
+                target_key.id(),
+                *victim_key.full_id(),
+                SocketAddrV4::new(std::net::Ipv4Addr::new(5, 6, 7, 8), 1),
+            )
+            .unwrap();
+        assert_eq!(
+            target.get_peer_address(target_key.id(), victim_key.id()),
+            Some(victim_addr)
+        );
+    }
+}
@@ -0,0 +1,293 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+use crate::adnl::node_id::NodeIdShort;
+use crate::adnl::queries_cache::QueryId;
+use crate::util::FastHashMap;
+
+type IncomingQueryKey = (NodeIdShort, QueryId);
+
+/// Latest incoming queries which are remembered to recognize retransmissions
+#[derive(Default)]
+pub(super) struct IncomingQueries {
+    state: Mutex<IncomingQueriesState>,
+}
+
+impl IncomingQueries {
+    /// Starts processing of the query, evicting the expired and the oldest entries
+    /// to fit into the `limits`. Zero capacity disables the check.
+    pub fn begin(
+        &self,
+        peer_id: &NodeIdShort,
+        query_id: &QueryId,
+        limits: &IncomingQueryLimits,
+        now: Instant,
+    ) -> IncomingQueryState {
+        if limits.capacity == 0 {
+            return IncomingQueryState::New;
+        }
+
+        let key = (*peer_id, *query_id);
+        let mut state = self.state.lock();
+        state.evict(limits, now);
+        if let Some(entry) = state.queries.get(&key) {
+            return match &entry.answer {
+                None => IncomingQueryState::InProgress,
+                Some(answer) => IncomingQueryState::Answered(answer.clone()),
+            };
+        }
+
+        state.next_seqno += 1;
+        let seqno = state.next_seqno;
+        state.queries.insert(
+            key,
+            IncomingQuery {
+                seqno,
+                received_at: now,
+                answer: None,
+            },
+        );
+        state.order.push_back((seqno, key));
+        state.evict(limits, now);
+        state.compact();
+        IncomingQueryState::New
+    }
+
+    /// Remembers the answer to resend it to the retransmitted query
+    pub fn finish(
+        &self,
+        peer_id: &NodeIdShort,
+        query_id: &QueryId,
+        answer: Option<&[u8]>,
+        limits: &IncomingQueryLimits,
+        now: Instant,
+    ) {
+        let mut state = self.state.lock();
+        let len = match state.queries.get_mut(&(*peer_id, *query_id)) {
+            Some(entry) if entry.answer.is_none() => {
+                entry.answer = Some(answer.map(<[u8]>::to_vec));
+                answer.map(<[u8]>::len).unwrap_or_default()
+            }
+            _ => return,
+        };
+        state.total_size += len;
+        state.evict(limits, now);
+    }
+
+    /// Forgets the query which was not processed so that it could be retried.
+    ///
+    /// NOTE: its place in the eviction order is skipped later
+    pub fn forget(&self, peer_id: &NodeIdShort, query_id: &QueryId) {
+        let mut state = self.state.lock();
+        if let Some(entry) = state.queries.remove(&(*peer_id, *query_id)) {
+            state.total_size -= entry.len();
+        }
+    }
+}
+
+/// Retransmissions window, see [`NodeOptions::query_retransmit_window`]
+///
+/// [`NodeOptions::query_retransmit_window`]: super::NodeOptions::query_retransmit_window
+#[derive(Copy, Clone)]
+pub(super) struct IncomingQueryLimits {
+    /// Max number of the remembered queries
+    pub capacity: usize,
+    /// Max total size of the remembered answers
+    pub max_size: usize,
+    /// How long the queries are remembered
+    pub ttl: Duration,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(super) enum IncomingQueryState {
+    /// Query must be processed
+    New,
+    /// The same query is still being processed
+    InProgress,
+    /// The same query was already processed (with an optional answer)
+    Answered(Option<Vec<u8>>),
+}
+
+#[derive(Default)]
+struct IncomingQueriesState {
+    queries: FastHashMap<IncomingQueryKey, IncomingQuery>,
+    /// Keys in insertion order. Can contain entries of the forgotten queries
+    order: VecDeque<(u64, IncomingQueryKey)>,
+    /// Total size of the remembered answers
+    total_size: usize,
+    next_seqno: u64,
+}
+
+impl IncomingQueriesState {
+    /// Removes the oldest queries until the rest fit into the limits
+    fn evict(&mut self, limits: &IncomingQueryLimits, now: Instant) {
+        while let Some((seqno, key)) = self.order.front() {
+            let entry = match self.queries.get(key) {
+                Some(entry) if entry.seqno == *seqno => entry,
+                // Forgotten query
+                _ => {
+                    self.order.pop_front();
+                    continue;
+                }
+            };
+
+            let expired = now.saturating_duration_since(entry.received_at) >= limits.ttl;
+            if !expired
+                && self.queries.len() <= limits.capacity
+                && self.total_size <= limits.max_size
+            {
+                break;
+            }
+
+            if let Some(entry) = self.queries.remove(key) {
+                self.total_size -= entry.len();
+            }
+            self.order.pop_front();
+        }
+    }
+
+    /// Drops the entries of the forgotten queries if there are too many of them
+    fn compact(&mut self) {
+        if self.order.len() <= self.queries.len() * 2 + MIN_COMPACTED_LEN {
+            return;
+        }
+
+        let queries = &self.queries;
+        self.order.retain(
+            |(seqno, key)| matches!(queries.get(key), Some(entry) if entry.seqno == *seqno),
+        );
+    }
+}
+
+struct IncomingQuery {
+    seqno: u64,
+    received_at: Instant,
+    /// `None` while the query is processed, then an optional answer
+    answer: Option<Option<Vec<u8>>>,
+}
+
+impl IncomingQuery {
+    fn len(&self) -> usize {
+        match &self.answer {
+            Some(Some(answer)) => answer.len(),
+            _ => 0,
+        }
+    }
+}
+
+const MIN_COMPACTED_LEN: usize = 64;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retransmitted_queries() {
+        let queries = IncomingQueries::default();
+        let peer_id = NodeIdShort::new([1; 32]);
+        let (first, second) = (QueryId([1; 32]), QueryId([2; 32]));
+        let limits = IncomingQueryLimits {
+            capacity: 2,
+            max_size: 1024,
+            ttl: Duration::from_secs(10),
+        };
+        let now = Instant::now();
+
+        assert_eq!(
+            queries.begin(&peer_id, &first, &limits, now),
+            IncomingQueryState::New
+        );
+        assert_eq!(
+            queries.begin(&peer_id, &first, &limits, now),
+            IncomingQueryState::InProgress
+        );
+        queries.finish(&peer_id, &first, Some(b"answer"), &limits, now);
+        assert_eq!(
+            queries.begin(&peer_id, &first, &limits, now),
+            IncomingQueryState::Answered(Some(b"answer".to_vec()))
+        );
+
+        // Same query id from another peer is a different query
+        let other_peer_id = NodeIdShort::new([2; 32]);
+        assert_eq!(
+            queries.begin(&other_peer_id, &first, &limits, now),
+            IncomingQueryState::New
+        );
+
+        // Not processed queries can be retried
+        queries.forget(&other_peer_id, &first);
+        assert_eq!(
+            queries.begin(&other_peer_id, &first, &limits, now),
+            IncomingQueryState::New
+        );
+
+        // The oldest query is evicted
+        assert_eq!(
+            queries.begin(&peer_id, &second, &limits, now),
+            IncomingQueryState::New
+        );
+        assert_eq!(
+            queries.begin(&peer_id, &first, &limits, now),
+            IncomingQueryState::New
+        );
+
+        let disabled = IncomingQueryLimits {
+            capacity: 0,
+            ..limits
+        };
+        assert_eq!(
+            queries.begin(&peer_id, &first, &disabled, now),
+            IncomingQueryState::New
+        );
+    }
+
+    #[test]
+    fn answers_size_and_ttl() {
+        let queries = IncomingQueries::default();
+        let peer_id = NodeIdShort::new([1; 32]);
+        let query_id = |i: u8| QueryId([i; 32]);
+        let limits = IncomingQueryLimits {
+            capacity: 100,
+            max_size: 1000,
+            ttl: Duration::from_secs(10),
+        };
+        let now = Instant::now();
+
+        // Answers which don't fit into the size limit evict the oldest ones
+        for i in 0..3 {
+            queries.begin(&peer_id, &query_id(i), &limits, now);
+            queries.finish(&peer_id, &query_id(i), Some(&[0; 400]), &limits, now);
+        }
+        assert_eq!(queries.state.lock().total_size, 800);
+        assert_eq!(
+            queries.begin(&peer_id, &query_id(0), &limits, now),
+            IncomingQueryState::New
+        );
+        assert!(matches!(
+            queries.begin(&peer_id, &query_id(2), &limits, now),
+            IncomingQueryState::Answered(Some(_))
+        ));
+
+        // Expired queries are evicted
+        let later = now + Duration::from_secs(10);
+        assert_eq!(
+            queries.begin(&peer_id, &query_id(2), &limits, later),
+            IncomingQueryState::New
+        );
+        let state = queries.state.lock();
+        assert_eq!(state.queries.len(), 1);
+        assert_eq!(state.total_size, 0);
+        drop(state);
+
+        // Entries of the forgotten queries don't pile up
+        for i in 0..=255 {
+            queries.begin(&peer_id, &query_id(i), &limits, later);
+            queries.forget(&peer_id, &query_id(i));
+        }
+        let state = queries.state.lock();
+        assert!(state.queries.is_empty());
+        assert!(state.order.len() <= MIN_COMPACTED_LEN + 1);
+    }
+}
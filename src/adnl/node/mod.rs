@@ -1,33 +1,83 @@
+use std::borrow::Cow;
 use std::net::SocketAddrV4;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use anyhow::{Context, Result};
-use bytes::Bytes;
+use arc_swap::ArcSwap;
+use everscale_crypto::ed25519;
+use once_cell::sync::OnceCell;
 use parking_lot::Mutex;
 use serde::{Deserialize, Serialize};
 use tl_proto::{TlRead, TlWrite};
-use tokio::sync::mpsc;
-use tokio_util::sync::CancellationToken;
+use tokio::runtime::Handle;
+use tokio::sync::{mpsc, Notify};
+use tracing::Instrument;
 
+pub use self::channel_setup::ChannelSetupTimeout;
+pub use self::mtu_probe::{MAX_PROBED_DATAGRAM_SIZE, MIN_PROBED_DATAGRAM_SIZE};
+pub use self::packet_drops::{PacketDropMetrics, PacketDropReason};
+pub use self::query_latency::{LatencyHistogram, LatencyReport, LATENCY_BUCKETS_MS};
+pub use self::rates::{NodeRates, Rates};
+pub use self::traffic::TrafficMetrics;
+
+use self::channel_setup::ChannelSetup;
+use self::deferred_answers::DeferredAnswers;
+pub(crate) use self::deferred_answers::{DeferredAnswerSlot, DeferredResponder};
+use self::handshake_replays::HandshakeReplays;
+pub use self::hole_punch::HolePunchError;
+use self::incoming_queries::IncomingQueries;
+use self::mtu_probe::{MtuProbeRx, MtuProbeTx};
+use self::packet_drops::PacketDrops;
+use self::peer_events::{PeerEvent, PeerEvents};
+use self::peer_eviction::{PeerEviction, PinnedPeers};
+use self::query_latency::QueryLatencies;
+use self::rates::RatesSampler;
 use self::receiver::*;
 use self::sender::*;
+pub(crate) use self::shutdown::DependentLayer;
+use self::traffic::Traffic;
+use super::answer_encryption::decrypt_answer;
 use super::channel::{AdnlChannelId, Channel};
+use super::echo_subscriber::{EchoSubscriber, PingStats};
 use super::keystore::{Key, Keystore, KeystoreError};
 use super::node_id::{NodeIdFull, NodeIdShort};
-use super::peer::{NewPeerContext, Peer, PeerFilter, Peers};
+use super::peer::{NewPeerContext, Peer, PeerCapabilities, PeerCompat, PeerFilter, Peers};
+use super::peer_filter::Ipv4Cidr;
 use super::ping_subscriber::PingSubscriber;
-use super::queries_cache::{QueriesCache, QueryId};
-use super::socket::make_udp_socket;
+use super::queries_cache::{PendingAdnlQuery, QueriesCache, QueryId, TraceId};
+use super::rendezvous_subscriber::RendezvousSubscriber;
+use super::reputation::{InMemoryReputation, PeerReputation, ReputationEvent, SharedReputation};
+use super::send_queue::SendQueuePolicy;
+use super::socket::{make_udp_socket, wrap_udp_socket, NodeSocket};
 use super::transfer::*;
+use crate::events::EventsSender;
 use crate::proto;
 use crate::subscriber::*;
 use crate::util::*;
+use crate::NetworkEvent;
 
+mod channel_setup;
+mod deferred_answers;
+mod handshake_replays;
+mod hole_punch;
+mod incoming_queries;
+mod mtu_probe;
+mod packet_drops;
+mod peer_events;
+mod peer_eviction;
+mod query_latency;
+mod rates;
 mod receiver;
 mod sender;
+mod shutdown;
+mod traffic;
 
-/// ADNL node configuration
+/// ADNL node configuration.
+///
+/// All fields except `packet_history_enabled`, `event_queue_capacity` and `version`
+/// can be changed at runtime with [`Node::update_options`].
 #[derive(Debug, Copy, Clone, Serialize, Deserialize)]
 #[serde(default)]
 pub struct NodeOptions {
@@ -45,6 +95,19 @@ pub struct NodeOptions {
     /// See [`Node::query`], [`Node::query_with_prefix`], [`Node::query_raw`]
     pub query_default_timeout_ms: u64,
 
+    /// Estimated time in which the remote peer waits for the answer to the incoming ADNL query.
+    /// Answers which were prepared later than this will not be sent.
+    ///
+    /// Default: `5000` ms
+    pub incoming_query_timeout_ms: u64,
+
+    /// Whether to answer with [`proto::adnl::QueryRejected`] to the queries which
+    /// were explicitly rejected, were not consumed by any subscriber or whose answers
+    /// were too large for the transport (including [`QueryConsumingResult::ConsumedLarge`]).
+    ///
+    /// Default: `false`
+    pub send_query_rejections: bool,
+
     /// ADNL multipart transfer timeout. It will drop the transfer if it is not completed
     /// within this timeout.
     ///
@@ -61,35 +124,224 @@ pub struct NodeOptions {
     /// Default: `30` seconds
     pub channel_reset_timeout_sec: u32,
 
+    /// Initial interval between the `CreateChannel` retransmissions to the peer
+    /// which didn't confirm the channel yet. It is doubled after each attempt.
+    /// Zero disables the retransmissions and [`NodeOptions::channel_setup_timeout_ms`].
+    ///
+    /// Default: `1000` ms
+    pub channel_setup_retry_base_ms: u64,
+
+    /// Max interval between the `CreateChannel` retransmissions.
+    ///
+    /// Default: `2000` ms
+    pub channel_setup_retry_max_ms: u64,
+
+    /// Pending queries and [`Node::ensure_channel`] waiters fail with [`ChannelSetupTimeout`]
+    /// if the channel was not confirmed within this time.
+    ///
+    /// Default: `4000` ms
+    pub channel_setup_timeout_ms: u64,
+
     /// How much time address lists from packets should be valid.
     ///
     /// Default: `1000` seconds
     pub address_list_timeout_sec: u32,
 
-    /// Whether to add additional duplicated packets check.
+    /// Whether to add additional duplicated packets check. Construction-only.
     ///
     /// Default: `false`
     pub packet_history_enabled: bool,
 
+    /// Number of the latest handshake packets which are remembered to drop their exact
+    /// replays before decryption. Zero disables the check.
+    ///
+    /// Default: `16384`
+    pub handshake_replay_window: usize,
+
+    /// Number of the latest incoming queries which are remembered to answer their
+    /// retransmissions without processing them again. Zero disables the check.
+    ///
+    /// Queries are remembered for at most [`NodeOptions::incoming_query_timeout_ms`].
+    ///
+    /// Default: `1024`
+    pub query_retransmit_window: usize,
+
+    /// Max total size of the answers remembered for [`NodeOptions::query_retransmit_window`].
+    /// The oldest queries are forgotten when the answers don't fit.
+    ///
+    /// Default: `4` MB
+    pub query_retransmit_max_size: usize,
+
+    /// Number of the latest received seqnos which are tracked for each channel
+    /// (rounded up to a multiple of 64). Packets with older seqnos are dropped.
+    ///
+    /// Default: `512`
+    pub seqno_window: usize,
+
     /// Whether handshake packets signature is mandatory.
     ///
     /// Default: `true`
     pub packet_signature_required: bool,
 
+    /// Max number of messages in one incoming packet. Packets with more messages are dropped.
+    ///
+    /// Default: `64`
+    pub max_messages_per_packet: u32,
+
+    /// Whether to drop packets with unparsed data after the packet contents.
+    ///
+    /// Default: `false`
+    pub reject_trailing_data: bool,
+
     /// Whether to use priority channels for queries.
     ///
     /// Default: `true`
     pub force_use_priority_channels: bool,
 
+    /// Whether to send queries to the peers without a ready channel in handshake packets
+    /// without creating a channel. Queries which came this way are answered the same way,
+    /// to the source address of the packet, unless the answer is more than three times
+    /// larger than the query packet (such answers are sent to the known peer address).
+    /// Useful for the first contact and for the peers which never confirm channels.
+    ///
+    /// Default: `false`
+    pub allow_channelless_queries: bool,
+
     /// Whether to use loopback ip to communicate with nodes on the same ip
     ///
     /// Default: `false`
     pub use_loopback_for_neighbours: bool,
 
-    /// ADNL protocol version.
+    /// Max number of events which are kept for the slowest receiver of [`Node::events`].
+    /// The oldest events are dropped when this limit is reached. Construction-only.
+    ///
+    /// Default: `1024`
+    pub event_queue_capacity: usize,
+
+    /// Max number of outgoing packets which are waiting to be sent to a single peer.
+    /// Zero means that the queue is unbounded.
+    ///
+    /// Default: `1024`
+    pub peer_send_queue_capacity: usize,
+
+    /// What to do with the outgoing packets when the peer send queue is full.
+    ///
+    /// Default: `drop_newest`
+    pub peer_send_queue_policy: SendQueuePolicy,
+
+    /// Max length of the [`QueryConsumingResult::ConsumedLarge`] answer to the plain ADNL query.
+    /// Larger answers are only sent over RLDP.
+    ///
+    /// Default: `980` bytes (answer fits into a single ADNL message)
+    pub max_adnl_answer_len: usize,
+
+    /// Max length of the answer to the plain ADNL query which doesn't fit into
+    /// a single ADNL message (see [`NodeOptions::max_adnl_answer_len`]) and is split
+    /// into `adnl.message.part` messages. Longer answers are not sent, the subscriber
+    /// is notified with [`QuerySubscriber::on_answer_failed`]. Zero disables splitting.
+    ///
+    /// Default: `8192` bytes
+    pub max_split_adnl_answer_len: usize,
+
+    /// How long the large answers are kept to serve the same query repeated over RLDP.
+    /// Zero disables the cache.
+    ///
+    /// Default: `10000` ms
+    pub large_answer_cache_ttl_ms: u64,
+
+    /// Max total size of the large answers cache.
+    ///
+    /// Default: `16` MB
+    pub large_answer_cache_max_size: usize,
+
+    /// How long the answer to the query consumed with
+    /// [`QueryConsumingResult::ConsumedDeferred`] is awaited.
+    /// Nothing is sent if the answer is not ready in time.
+    ///
+    /// Default: `10000` ms
+    pub deferred_answer_ttl_ms: u64,
+
+    /// Max number of identical per-packet log messages during [`log_sampling_interval_ms`].
+    /// The rest are suppressed and summarized. Zero disables sampling.
+    ///
+    /// [`log_sampling_interval_ms`]: NodeOptions::log_sampling_interval_ms
+    ///
+    /// Default: `10`
+    pub log_sampling_limit: u32,
+
+    /// Interval of the log sampling.
+    ///
+    /// Default: `1000` ms
+    pub log_sampling_interval_ms: u64,
+
+    /// Max number of peers with separate query latency histograms.
+    /// Latencies of other peers are accumulated together, see [`Node::latency_report`].
+    ///
+    /// Default: `1024`
+    pub latency_tracked_peers: usize,
+
+    /// Max number of query constructors with separate latency histograms.
+    ///
+    /// Default: `64`
+    pub latency_tracked_constructors: usize,
+
+    /// Max size of the outgoing UDP datagram. Random padding and less preferred
+    /// addresses are removed from packets which don't fit, large messages are split
+    /// into smaller parts. Overridden by the probed size, see [`Node::probe_max_datagram_size`].
+    ///
+    /// Default: `1472` bytes
+    pub max_datagram_size: usize,
+
+    /// Whether to probe the max datagram size of each peer after the channel is established.
+    ///
+    /// Default: `false`
+    pub mtu_probe_enabled: bool,
+
+    /// Whether to ignore peers from public overlays and DHT ([`NewPeerContext::PublicOverlay`],
+    /// [`NewPeerContext::Dht`]) with private (RFC 1918), loopback, link-local, multicast,
+    /// `0.0.0.0/8`, `240.0.0.0/4` or broadcast addresses, except the networks from
+    /// [`Node::set_allowed_reserved_networks`].
+    /// Address updates of such peers (e.g. from the handshake packets) are checked too.
+    /// Explicitly added peers (e.g. of private overlays) are not checked.
+    ///
+    /// Default: `false`
+    pub reject_reserved_public_addresses: bool,
+
+    /// How long [`Node::shutdown`] waits for the background tasks
+    /// of the layers on top of the node and then for its own tasks.
+    ///
+    /// Default: `1000` ms
+    pub shutdown_timeout_ms: u64,
+
+    /// Interval between the handshake packets sent to the peer behind NAT
+    /// during the simultaneous open, see [`Node::connect_via`].
+    ///
+    /// Default: `100` ms
+    pub hole_punch_interval_ms: u64,
+
+    /// Default timeout of [`Node::connect_via`]. Also limits the simultaneous open
+    /// requested by the rendezvous peer.
+    ///
+    /// Default: `5000` ms
+    pub hole_punch_timeout_ms: u64,
+
+    /// Max number of unpinned peers of each local key which were added in the same
+    /// [`NewPeerContext`]. The least recently active peer is evicted when the new one
+    /// doesn't fit, see [`Node::pin_peer`].
+    ///
+    /// Default: None
+    pub max_peers_per_context: Option<usize>,
+
+    /// ADNL protocol version. Construction-only.
     ///
     /// Default: None
     pub version: Option<u16>,
+
+    /// Interval of the counters sampling for [`Node::rates`].
+    /// No sampling task is spawned if it is `None`. Construction-only.
+    ///
+    /// Default: None
+    pub rates_sample_interval_sec: Option<u32>,
 }
 
 impl Default for NodeOptions {
@@ -97,15 +349,47 @@ impl Default for NodeOptions {
         Self {
             query_min_timeout_ms: 500,
             query_default_timeout_ms: 5000,
+            incoming_query_timeout_ms: 5000,
+            send_query_rejections: false,
             transfer_timeout_sec: 3,
             clock_tolerance_sec: 60,
             channel_reset_timeout_sec: 30,
+            channel_setup_retry_base_ms: 1000,
+            channel_setup_retry_max_ms: 2000,
+            channel_setup_timeout_ms: 4000,
             address_list_timeout_sec: 1000,
             packet_history_enabled: false,
+            handshake_replay_window: 16384,
+            query_retransmit_window: 1024,
+            query_retransmit_max_size: 4 << 20,
+            seqno_window: DEFAULT_SEQNO_WINDOW,
             packet_signature_required: true,
+            max_messages_per_packet: proto::adnl::DEFAULT_MAX_PACKET_MESSAGES as u32,
+            reject_trailing_data: false,
             force_use_priority_channels: true,
+            allow_channelless_queries: false,
             use_loopback_for_neighbours: false,
+            event_queue_capacity: 1024,
+            peer_send_queue_capacity: 1024,
+            peer_send_queue_policy: SendQueuePolicy::DropNewest,
+            max_adnl_answer_len: 980,
+            max_split_adnl_answer_len: 8192,
+            large_answer_cache_ttl_ms: 10000,
+            large_answer_cache_max_size: 16 << 20,
+            deferred_answer_ttl_ms: 10000,
+            log_sampling_limit: 10,
+            log_sampling_interval_ms: 1000,
+            latency_tracked_peers: 1024,
+            latency_tracked_constructors: 64,
+            max_datagram_size: 1472,
+            mtu_probe_enabled: false,
+            reject_reserved_public_addresses: false,
+            shutdown_timeout_ms: 1000,
+            hole_punch_interval_ms: 100,
+            hole_punch_timeout_ms: 5000,
+            max_peers_per_context: None,
             version: None,
+            rates_sample_interval_sec: None,
         }
     }
 }
@@ -117,7 +401,7 @@ pub struct Node {
     /// Immutable keystore
     keystore: Keystore,
     /// Configuration
-    options: NodeOptions,
+    options: ArcSwap<NodeOptions>,
 
     /// If specified, peers are only accepted if they match the filter
     peer_filter: Option<Arc<dyn PeerFilter>>,
@@ -127,37 +411,229 @@ pub struct Node {
 
     /// Channels table used to fast search on incoming packets
     channels_by_id: FastDashMap<AdnlChannelId, ChannelReceiver>,
-    /// Channels table used to fast search when sending messages
-    channels_by_peers: FastDashMap<NodeIdShort, Arc<Channel>>,
+    /// Channels table used to fast search when sending messages (by local and peer ids)
+    channels_by_peers: FastDashMap<(NodeIdShort, NodeIdShort), Arc<Channel>>,
 
     /// Pending transfers of large messages that were split
     incoming_transfers: Arc<FastDashMap<TransferId, Arc<Transfer>>>,
 
     /// Pending queries
     queries: Arc<QueriesCache>,
+    /// Answers which are too large for the plain ADNL queries
+    large_answers: Mutex<AnswerCache>,
+    /// Answers which will be sent with [`Node::send_deferred_answer`]
+    deferred_answers: DeferredAnswers,
+    /// Number of answers which were not sent due to deadline
+    answers_expired: AtomicU64,
+    /// Number of answers which were too large for the transport by query constructor
+    answers_too_large: Mutex<FastHashMap<u32, u64>>,
+    /// Number of answers from the peers to which the query was not sent
+    answers_spoofed: AtomicU64,
+    /// Number of answers which were rejected by the query verifiers
+    answers_unverified: AtomicU64,
+    /// Number of new peers which were rejected by the peer filter
+    peers_rejected: AtomicU64,
+    /// Reserved networks which are accepted from public overlays
+    allowed_reserved_networks: ArcSwap<Vec<Ipv4Cidr>>,
+    /// Number of public peers which were rejected due to reserved addresses
+    reserved_addresses_rejected: AtomicU64,
+    /// Peers which are never evicted
+    pinned_peers: PinnedPeers,
+    /// Least recently active peers of each context
+    peer_eviction: PeerEviction,
+    /// Number of peers which were evicted to make room for the new ones
+    peers_evicted: AtomicU64,
+    /// Number of outgoing packets which were dropped due to full peer send queues
+    packets_send_dropped: AtomicU64,
+    /// Number of outgoing packets which were sent without channel
+    handshake_packets_sent: AtomicU64,
+    /// Number of retransmitted outgoing queries
+    query_retransmits: AtomicU64,
+    /// Number of retransmitted incoming queries which were not processed again
+    queries_deduplicated: AtomicU64,
+    /// Peers which must be probed after the channel establishment
+    mtu_probe_tx: MtuProbeTx,
+    /// Wakes up [`Node::ensure_channel`] waiters
+    channel_established: Notify,
+    /// Peers to which the hole is being punched on request of the rendezvous peer
+    hole_punches: FastDashSet<NodeIdShort>,
+    /// Number of [`Node::connect_via`] calls in progress by the rendezvous peers
+    pending_rendezvous: FastDashMap<NodeIdShort, usize>,
+    /// `CreateChannel` retransmission schedules of the peers without confirmed channels
+    channel_setups: FastDashMap<(NodeIdShort, NodeIdShort), Arc<ChannelSetup>>,
+    /// Number of retransmitted `CreateChannel` messages
+    channel_setup_retransmits: AtomicU64,
+    /// Number of channels which were not confirmed in time
+    channel_setup_timeouts: AtomicU64,
+    /// Dropped incoming packets counters
+    packet_drops: PacketDrops,
+    /// Processed packets, queries and messages counters
+    traffic: Arc<Traffic>,
+    /// Recently received handshake packets
+    handshake_replays: HandshakeReplays,
+    /// Recently received queries
+    incoming_queries: IncomingQueries,
+    /// Rate limiter of the per-packet log messages
+    log_sampler: LogSampler,
+    /// Round-trip durations of the answered queries
+    query_latencies: QueryLatencies,
+    /// Opt-in network events stream
+    events: Arc<EventsSender>,
+    /// Opt-in rolling rates of the counters
+    rates: Option<RatesSampler>,
+    /// Feature bits announced to the peers, see [`PeerCapabilities`]
+    local_features: AtomicU64,
 
-    /// Outgoing packets queue
+    /// Peer send queues which are ready to be processed by the sender loop
     sender_queue_tx: SenderQueueTx,
+    /// Reusable buffers for the outgoing packets
+    packet_buffers: Arc<BufferPool>,
     /// Stated used during initialization
     init_state: Mutex<Option<InitializationState>>,
+    /// Message subscribers after the node was started
+    message_subscribers: OnceCell<Vec<Arc<dyn MessageSubscriber>>>,
+    /// Peer events which are waiting for the subscriber hooks
+    peer_events: PeerEvents,
+
+    /// Advertised address list which overrides the socket address
+    address_list: OnceCell<AddressListBuilder>,
 
     /// Node start timestamp. Used as reinit date for connections
     start_time: u32,
 
-    /// Token, used to cancel all spawned tasks
-    cancellation_token: CancellationToken,
+    /// Monotonic node start time. Used for internal timeouts
+    started_at: Instant,
+
+    /// Source of wall and monotonic time
+    clock: Arc<dyn Clock>,
+
+    /// Peer scores shared with the upper layers
+    reputation: SharedReputation,
+
+    /// Runtime of the background tasks
+    runtime: Handle,
+
+    /// Background tasks, cancelled on shutdown
+    tasks: TaskGroup,
+    /// Protocols on top of the node, see [`Node::shutdown`]
+    layers: Mutex<Vec<Arc<DependentLayer>>>,
 }
 
 impl Node {
-    /// Create new ADNL node on the specified address
+    /// Create new ADNL node on the specified address.
+    ///
+    /// Background tasks are spawned on the current runtime
     pub fn new(
+        socket_addr: SocketAddrV4,
+        keystore: Keystore,
+        options: NodeOptions,
+        peer_filter: Option<Arc<dyn PeerFilter>>,
+    ) -> Result<Arc<Self>> {
+        Self::with_runtime(
+            socket_addr,
+            keystore,
+            options,
+            peer_filter,
+            Handle::current(),
+        )
+    }
+
+    /// Create new ADNL node on the specified address with a custom clock
+    pub fn with_clock(
+        socket_addr: SocketAddrV4,
+        keystore: Keystore,
+        options: NodeOptions,
+        peer_filter: Option<Arc<dyn PeerFilter>>,
+        clock: Arc<dyn Clock>,
+    ) -> Result<Arc<Self>> {
+        Self::bind(
+            socket_addr,
+            keystore,
+            options,
+            peer_filter,
+            clock,
+            Handle::current(),
+        )
+    }
+
+    /// Create new ADNL node on the specified address. The socket and all background
+    /// tasks (including tasks of the RLDP, DHT and overlay nodes on top of it)
+    /// use the specified runtime.
+    pub fn with_runtime(
+        socket_addr: SocketAddrV4,
+        keystore: Keystore,
+        options: NodeOptions,
+        peer_filter: Option<Arc<dyn PeerFilter>>,
+        runtime: Handle,
+    ) -> Result<Arc<Self>> {
+        Self::bind(
+            socket_addr,
+            keystore,
+            options,
+            peer_filter,
+            Arc::new(SystemClock),
+            runtime,
+        )
+    }
+
+    /// Create new ADNL node over the pre-bound non-blocking UDP socket
+    /// (e.g. bound before dropping privileges). Tokio sockets can be passed
+    /// after [`tokio::net::UdpSocket::into_std`].
+    ///
+    /// The local address of the socket is advertised unless `advertised_addr` is specified
+    /// (a zero port in it is replaced with the local one). Sockets bound to an unspecified
+    /// address (`0.0.0.0`) require an explicit `advertised_addr` with a specified IP.
+    /// Background tasks are spawned on the current runtime.
+    pub fn with_udp_socket(
+        udp_socket: std::net::UdpSocket,
+        advertised_addr: Option<SocketAddrV4>,
+        keystore: Keystore,
+        options: NodeOptions,
+        peer_filter: Option<Arc<dyn PeerFilter>>,
+    ) -> Result<Arc<Self>> {
+        let runtime = Handle::current();
+        let (socket, local_addr) = {
+            let _guard = runtime.enter();
+            wrap_udp_socket(udp_socket)?
+        };
+
+        let socket_addr = match advertised_addr {
+            Some(mut addr) => {
+                if addr.port() == 0 {
+                    addr.set_port(local_addr.port());
+                }
+                addr
+            }
+            None => local_addr,
+        };
+        if socket_addr.ip().is_unspecified() {
+            return Err(NodeError::UnspecifiedAdvertisedAddress.into());
+        }
+
+        Ok(Self::with_socket(
+            socket_addr,
+            NodeSocket::Udp(socket),
+            keystore,
+            options,
+            peer_filter,
+            Arc::new(SystemClock),
+            runtime,
+        ))
+    }
+
+    fn bind(
         mut socket_addr: SocketAddrV4,
         keystore: Keystore,
         options: NodeOptions,
         peer_filter: Option<Arc<dyn PeerFilter>>,
+        clock: Arc<dyn Clock>,
+        runtime: Handle,
     ) -> Result<Arc<Self>> {
-        // Bind node socket
-        let socket = make_udp_socket(socket_addr.port())?;
+        // Bind node socket (registers it in the runtime IO driver)
+        let socket = {
+            let _guard = runtime.enter();
+            make_udp_socket(socket_addr.port())?
+        };
 
         // Update socket addr with auto assigned port (in case of 0)
         if socket_addr.port() == 0 {
@@ -165,7 +641,29 @@ impl Node {
             socket_addr.set_port(local_addr.port());
         }
 
+        Ok(Self::with_socket(
+            socket_addr,
+            NodeSocket::Udp(socket),
+            keystore,
+            options,
+            peer_filter,
+            clock,
+            runtime,
+        ))
+    }
+
+    /// Creates new ADNL node on top of the already bound socket
+    pub(crate) fn with_socket(
+        socket_addr: SocketAddrV4,
+        socket: NodeSocket,
+        keystore: Keystore,
+        options: NodeOptions,
+        peer_filter: Option<Arc<dyn PeerFilter>>,
+        clock: Arc<dyn Clock>,
+        runtime: Handle,
+    ) -> Arc<Self> {
         let (sender_queue_tx, sender_queue_rx) = mpsc::unbounded_channel();
+        let (mtu_probe_tx, mtu_probe_rx) = mpsc::unbounded_channel();
 
         // Add empty peers map for each local peer
         let mut peers =
@@ -174,45 +672,298 @@ impl Node {
             peers.insert(*key, Peers::default());
         }
 
-        Ok(Arc::new(Self {
+        Arc::new(Self {
             socket_addr,
             keystore,
-            options,
+            options: ArcSwap::from_pointee(options),
             peer_filter,
             peers,
             channels_by_id: Default::default(),
             channels_by_peers: Default::default(),
             incoming_transfers: Default::default(),
             queries: Default::default(),
+            large_answers: Default::default(),
+            deferred_answers: Default::default(),
+            answers_expired: Default::default(),
+            answers_too_large: Default::default(),
+            answers_spoofed: Default::default(),
+            answers_unverified: Default::default(),
+            peers_rejected: Default::default(),
+            allowed_reserved_networks: Default::default(),
+            reserved_addresses_rejected: Default::default(),
+            pinned_peers: Default::default(),
+            peer_eviction: Default::default(),
+            peers_evicted: Default::default(),
+            packets_send_dropped: Default::default(),
+            handshake_packets_sent: Default::default(),
+            query_retransmits: Default::default(),
+            queries_deduplicated: Default::default(),
+            mtu_probe_tx,
+            channel_established: Default::default(),
+            hole_punches: Default::default(),
+            pending_rendezvous: Default::default(),
+            channel_setups: Default::default(),
+            channel_setup_retransmits: Default::default(),
+            channel_setup_timeouts: Default::default(),
+            packet_drops: Default::default(),
+            traffic: Default::default(),
+            handshake_replays: Default::default(),
+            incoming_queries: Default::default(),
+            log_sampler: Default::default(),
+            query_latencies: Default::default(),
+            events: Arc::new(EventsSender::new(options.event_queue_capacity)),
+            reputation: SharedReputation::new(Arc::new(InMemoryReputation::new(
+                clock.clone(),
+                InMemoryReputation::DEFAULT_HALF_LIFE,
+            ))),
+            rates: options.rates_sample_interval_sec.map(RatesSampler::new),
+            local_features: Default::default(),
             sender_queue_tx,
+            packet_buffers: Default::default(),
             init_state: Mutex::new(Some(InitializationState {
                 socket,
                 sender_queue_rx,
+                mtu_probe_rx,
                 message_subscribers: Default::default(),
                 query_subscribers: Default::default(),
             })),
-            start_time: now(),
-            cancellation_token: Default::default(),
-        }))
+            message_subscribers: Default::default(),
+            peer_events: Default::default(),
+            address_list: Default::default(),
+            start_time: clock.now(),
+            started_at: clock.instant(),
+            clock,
+            runtime,
+            tasks: Default::default(),
+            layers: Default::default(),
+        })
+    }
+
+    /// Runtime of the background tasks
+    pub fn runtime(&self) -> &Handle {
+        &self.runtime
     }
 
     /// ADNL node options
-    #[inline(always)]
-    pub fn options(&self) -> &NodeOptions {
-        &self.options
+    pub fn options(&self) -> Arc<NodeOptions> {
+        self.options.load_full()
+    }
+
+    /// Changes configuration at runtime. New values are used for all subsequent
+    /// queries and packets.
+    ///
+    /// Returns an error and leaves the configuration unchanged
+    /// if a construction-only field was modified.
+    ///
+    /// NOTE: `f` is applied again if the configuration was changed concurrently
+    pub fn update_options<F>(&self, mut f: F) -> Result<()>
+    where
+        F: FnMut(&mut NodeOptions),
+    {
+        let mut result = Ok(());
+        self.options.rcu(|current| {
+            let mut options = **current;
+            f(&mut options);
+
+            result = check_construction_only_options(current, &options);
+            match result {
+                Ok(()) => Arc::new(options),
+                Err(_) => current.clone(),
+            }
+        });
+        result.map_err(|name| NodeError::ConstructionOnlyOption(name).into())
     }
 
     /// Instant metrics
     pub fn metrics(&self) -> NodeMetrics {
+        let (large_answers_len, large_answers_size) = {
+            let large_answers = self.large_answers.lock();
+            (large_answers.len(), large_answers.total_size())
+        };
+
+        let mut peers_by_context = PeerCountByContext::default();
+        for peer in self.peers.values().flat_map(|peers| peers.iter()) {
+            *match peer.value().context() {
+                NewPeerContext::AdnlPacket => &mut peers_by_context.adnl_packet,
+                NewPeerContext::Dht => &mut peers_by_context.dht,
+                NewPeerContext::PublicOverlay => &mut peers_by_context.public_overlay,
+            } += 1;
+        }
+
         NodeMetrics {
             peer_count: self.peers.values().map(|peers| peers.len()).sum(),
+            peers_by_context,
             channels_by_id_len: self.channels_by_id.len(),
             channels_by_peers_len: self.channels_by_peers.len(),
             incoming_transfers_len: self.incoming_transfers.len(),
             query_count: self.queries.len(),
+            answers_expired: self.answers_expired.load(Ordering::Relaxed),
+            answers_too_large: self.answers_too_large.lock().values().sum(),
+            answers_spoofed: self.answers_spoofed.load(Ordering::Relaxed),
+            answers_unverified: self.answers_unverified.load(Ordering::Relaxed),
+            peers_rejected: self.peers_rejected.load(Ordering::Relaxed),
+            reserved_addresses_rejected: self.reserved_addresses_rejected.load(Ordering::Relaxed),
+            peers_evicted: self.peers_evicted.load(Ordering::Relaxed),
+            answers_dropped: self.queries.answers_dropped(),
+            packets_dropped: self.packet_drops.metrics(),
+            traffic: self.traffic.metrics(),
+            packets_send_dropped: self.packets_send_dropped.load(Ordering::Relaxed),
+            handshake_packets_sent: self.handshake_packets_sent.load(Ordering::Relaxed),
+            query_retransmits: self.query_retransmits.load(Ordering::Relaxed),
+            queries_deduplicated: self.queries_deduplicated.load(Ordering::Relaxed),
+            channel_setup_retransmits: self.channel_setup_retransmits.load(Ordering::Relaxed),
+            channel_setup_timeouts: self.channel_setup_timeouts.load(Ordering::Relaxed),
+            events_dropped: self.events.dropped(),
+            large_answers_len,
+            large_answers_size,
+            deferred_answers_len: self.deferred_answers.len(self.clock.instant()),
+            deferred_answers_expired: self.deferred_answers.expired(),
+            log_messages_suppressed: self.log_sampler.suppressed(),
+        }
+    }
+
+    /// Round-trip durations of the answered queries by peer and by query constructor
+    pub fn latency_report(&self) -> LatencyReport {
+        self.query_latencies.report()
+    }
+
+    /// Subscribes to the network events of this node and all protocols on top of it.
+    ///
+    /// Events are only produced while there is at least one receiver. Sending never blocks,
+    /// lagging receivers lose the oldest events (see [`NodeMetrics::events_dropped`] and
+    /// [`tokio::sync::broadcast::error::RecvError::Lagged`]).
+    pub fn events(&self) -> tokio::sync::broadcast::Receiver<NetworkEvent> {
+        self.events.subscribe()
+    }
+
+    /// Events channel for the layers which emit events without the node
+    #[cfg(feature = "overlay")]
+    pub(crate) fn events_sender(&self) -> &Arc<EventsSender> {
+        &self.events
+    }
+
+    pub(crate) fn emit_event<F>(&self, f: F)
+    where
+        F: FnOnce(u64) -> NetworkEvent,
+    {
+        self.events.emit(|| f(self.clock.now_ms()));
+    }
+
+    /// Whether the per-packet log message with this key must be logged
+    /// (see [`NodeOptions::log_sampling_limit`])
+    pub(crate) fn should_log(&self, key: &'static str) -> bool {
+        let options = self.options.load();
+        self.log_sampler.should_log(
+            key,
+            self.clock.now_ms(),
+            options.log_sampling_limit,
+            options.log_sampling_interval_ms,
+        )
+    }
+
+    pub(crate) fn add_expired_answer(&self) {
+        self.answers_expired.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Total number of answers which were too large for the plain ADNL queries,
+    /// by query constructor (see [`NodeOptions::max_split_adnl_answer_len`])
+    pub fn answers_too_large(&self) -> Vec<(u32, u64)> {
+        let mut result = self
+            .answers_too_large
+            .lock()
+            .iter()
+            .map(|(constructor, count)| (*constructor, *count))
+            .collect::<Vec<_>>();
+        result.sort_unstable();
+        result
+    }
+
+    pub(crate) fn add_answer_too_large(&self, constructor: u32) {
+        *self
+            .answers_too_large
+            .lock()
+            .entry(constructor)
+            .or_default() += 1;
+    }
+
+    /// Max answer length for the plain ADNL queries
+    pub(crate) fn adnl_answer_len_limit(&self) -> usize {
+        let options = self.options.load();
+        std::cmp::max(
+            options.max_adnl_answer_len,
+            options.max_split_adnl_answer_len,
+        )
+    }
+
+    /// Stores the answer which is too large for the plain ADNL query
+    pub(crate) fn cache_large_answer(
+        &self,
+        local_id: &NodeIdShort,
+        peer_id: &NodeIdShort,
+        query: &[u8],
+        answer: Vec<u8>,
+    ) {
+        let options = self.options.load();
+        let ttl = Duration::from_millis(options.large_answer_cache_ttl_ms);
+        let max_size = options.large_answer_cache_max_size;
+
+        let now = self.clock.instant();
+        let mut large_answers = self.large_answers.lock();
+        large_answers.shrink(now, max_size);
+        if max_size > 0 && !ttl.is_zero() {
+            let key = compute_large_answer_key(local_id, peer_id, query);
+            large_answers.insert(key, answer, now + ttl, max_size);
+        }
+    }
+
+    /// Returns the answer for the query which was previously answered
+    /// with [`RejectReason::AnswerTooLarge`]
+    pub(crate) fn find_large_answer(
+        &self,
+        local_id: &NodeIdShort,
+        peer_id: &NodeIdShort,
+        query: &[u8],
+    ) -> Option<Vec<u8>> {
+        let mut large_answers = self.large_answers.lock();
+        if large_answers.len() == 0 {
+            return None;
+        }
+        let key = compute_large_answer_key(local_id, peer_id, query);
+        large_answers.get(&key, self.clock.instant())
+    }
+
+    /// Sends the answer to the query which was consumed with
+    /// [`QueryConsumingResult::ConsumedDeferred`].
+    ///
+    /// The answer is delivered the same way as the inline answer would have been.
+    /// Returns an error if the token has expired or was already answered
+    /// (see [`NodeOptions::deferred_answer_ttl_ms`])
+    pub fn send_deferred_answer(
+        self: &Arc<Self>,
+        token: AnswerToken,
+        answer: Vec<u8>,
+    ) -> Result<()> {
+        let now = self.clock.instant();
+        if self.deferred_answers.send(self, token.slot(), answer, now) {
+            Ok(())
+        } else {
+            Err(NodeError::UnknownAnswerToken.into())
         }
     }
 
+    /// Starts waiting for the answer from [`Node::send_deferred_answer`]
+    /// after the token was returned by the subscriber
+    pub(crate) fn register_deferred_answer(
+        self: &Arc<Self>,
+        token: &AnswerToken,
+        responder: DeferredResponder,
+    ) {
+        let ttl = Duration::from_millis(self.options.load().deferred_answer_ttl_ms);
+        let now = self.clock.instant();
+        self.deferred_answers
+            .register(self, token.slot(), responder, now, now + ttl);
+    }
+
     /// Adds a new message subscriber brefore the node was started
     pub fn add_message_subscriber(
         &self,
@@ -240,6 +991,22 @@ impl Node {
         }
     }
 
+    /// Adds [`EchoSubscriber`] before the node was started
+    pub fn add_echo_subscriber(&self) -> Result<()> {
+        self.add_query_subscriber(Arc::new(EchoSubscriber))
+    }
+
+    /// Adds [`RendezvousSubscriber`] with the specified trusted peers
+    /// before the node was started
+    pub fn add_rendezvous_subscriber<I>(&self, trusted_peers: I) -> Result<()>
+    where
+        I: IntoIterator<Item = NodeIdShort>,
+    {
+        let subscriber = Arc::new(RendezvousSubscriber::new(trusted_peers));
+        self.add_query_subscriber(subscriber.clone())?;
+        self.add_message_subscriber(subscriber)
+    }
+
     /// Starts listening for incoming packets
     pub fn start(self: &Arc<Self>) -> Result<()> {
         // Consume receiver
@@ -250,27 +1017,26 @@ impl Node {
 
         init.query_subscribers.push(Arc::new(PingSubscriber));
 
+        let message_subscribers = self
+            .message_subscribers
+            .get_or_init(|| init.message_subscribers)
+            .clone();
+
         // Start background logic
         self.start_sender(init.socket.clone(), init.sender_queue_rx);
-        self.start_receiver(
-            init.socket,
-            init.message_subscribers,
-            init.query_subscribers,
-        );
+        self.start_mtu_prober(init.mtu_probe_rx);
+        self.start_rates_sampler();
+        self.start_receiver(init.socket, message_subscribers, init.query_subscribers);
 
         // Done
         Ok(())
     }
 
-    /// Stops all spawned listeners
-    pub fn shutdown(&self) {
-        self.cancellation_token.cancel();
-    }
-
     /// Computes ADNL query timeout, based on the roundtrip and the configured options
     pub fn compute_query_timeout(&self, roundtrip: Option<u64>) -> u64 {
-        let timeout = roundtrip.unwrap_or(self.options.query_default_timeout_ms);
-        std::cmp::max(self.options.query_min_timeout_ms, timeout)
+        let options = self.options.load();
+        let timeout = roundtrip.unwrap_or(options.query_default_timeout_ms);
+        std::cmp::max(options.query_min_timeout_ms, timeout)
     }
 
     /// Socket address of the node
@@ -285,13 +1051,109 @@ impl Node {
         self.start_time
     }
 
+    /// Clock which is used for all timestamps and timeouts
+    #[inline(always)]
+    pub fn clock(&self) -> &Arc<dyn Clock> {
+        &self.clock
+    }
+
+    /// Peer reputation storage used by all layers
+    pub fn reputation(&self) -> Arc<dyn PeerReputation> {
+        self.reputation.get()
+    }
+
+    /// Replaces the peer reputation storage (e.g. with the one which persists
+    /// scores between restarts). Upper layers start using it immediately.
+    ///
+    /// Default: [`InMemoryReputation`] with [`InMemoryReputation::DEFAULT_HALF_LIFE`]
+    ///
+    /// [`InMemoryReputation`]: crate::adnl::InMemoryReputation
+    pub fn set_reputation(&self, reputation: Arc<dyn PeerReputation>) {
+        self.reputation.set(reputation);
+    }
+
+    /// Replaces reserved networks which are accepted from public overlays
+    /// (e.g. for lab setups), see [`NodeOptions::reject_reserved_public_addresses`].
+    ///
+    /// Default: empty
+    pub fn set_allowed_reserved_networks(&self, networks: Vec<Ipv4Cidr>) {
+        self.allowed_reserved_networks.store(Arc::new(networks));
+    }
+
+    /// Whether the peer address must be ignored in this context
+    fn is_reserved_peer_address(&self, ctx: NewPeerContext, addr: &SocketAddrV4) -> bool {
+        let ip = addr.ip();
+        matches!(ctx, NewPeerContext::PublicOverlay | NewPeerContext::Dht)
+            && self.options.load().reject_reserved_public_addresses
+            && is_reserved_address(ip)
+            && !self
+                .allowed_reserved_networks
+                .load()
+                .iter()
+                .any(|net| net.contains(ip))
+    }
+
+    fn reject_reserved_peer_address(
+        &self,
+        local_id: &NodeIdShort,
+        peer_id: &NodeIdShort,
+        addr: SocketAddrV4,
+    ) {
+        tracing::debug!(%local_id, %peer_id, %addr, "ignored public peer with reserved address");
+        self.reserved_addresses_rejected
+            .fetch_add(1, Ordering::Relaxed);
+        self.emit_event(|timestamp_ms| NetworkEvent::PeerRejected {
+            local_id: *local_id,
+            peer_id: *peer_id,
+            addr,
+            timestamp_ms,
+        });
+    }
+
+    /// Records the peer behaviour in the reputation storage
+    pub(crate) fn report_peer(&self, peer_id: &NodeIdShort, event: ReputationEvent) {
+        self.reputation.report(peer_id, event);
+    }
+
+    #[cfg(feature = "overlay")]
+    pub(crate) fn shared_reputation(&self) -> &SharedReputation {
+        &self.reputation
+    }
+
+    /// Sets the address list which will be advertised instead of the socket address
+    /// (e.g. for nodes behind NAT or with port forwarding).
+    ///
+    /// NOTE: must be called before the node was started
+    pub fn set_address_list(&self, address_list: AddressListBuilder) -> Result<()> {
+        if self.init_state.lock().is_none() {
+            return Err(NodeError::AlreadyRunning.into());
+        }
+        if address_list.is_empty() {
+            return Err(NodeError::EmptyAddressList.into());
+        }
+        self.address_list
+            .set(address_list)
+            .map_err(|_| NodeError::AddressListAlreadySet.into())
+    }
+
     /// Builds a new address list for the current ADNL node with no expiration date
+    /// (unless it was specified in [`Node::set_address_list`])
     pub fn build_address_list(&self) -> proto::adnl::AddressList {
-        proto::adnl::AddressList {
-            address: Some(proto::adnl::Address::from(&self.socket_addr)),
-            version: now(),
-            reinit_date: self.start_time,
-            expire_at: 0,
+        self.build_address_list_with_expiration(0)
+    }
+
+    /// Builds a new address list for the current ADNL node,
+    /// using the specified expiration date if it was not set explicitly
+    pub(crate) fn build_address_list_with_expiration(
+        &self,
+        expire_at: u32,
+    ) -> proto::adnl::AddressList {
+        let now = self.clock.now();
+        match self.address_list.get() {
+            Some(address_list) => address_list.build_with_defaults(now, self.start_time, expire_at),
+            None => AddressListBuilder::new()
+                .with_address(self.socket_addr)
+                .build_with_defaults(now, self.start_time, expire_at),
         }
     }
 
@@ -311,7 +1173,7 @@ impl Node {
 
     /// Adds new remote peer. Returns whether the peer was added
     ///
-    /// See [`Node::remove_peer`]
+    /// See [`Node::remove_peer`], [`Node::add_peer_with_outcome`]
     pub fn add_peer(
         &self,
         ctx: NewPeerContext,
@@ -320,32 +1182,91 @@ impl Node {
         addr: SocketAddrV4,
         peer_id_full: NodeIdFull,
     ) -> Result<bool> {
+        let outcome = self.add_peer_with_outcome(ctx, local_id, peer_id, addr, peer_id_full)?;
+        Ok(outcome != AddPeerOutcome::Ignored)
+    }
+
+    /// Adds new remote peer. Also returns the peer which was evicted
+    /// to make room for it (see [`NodeOptions::max_peers_per_context`])
+    pub fn add_peer_with_outcome(
+        &self,
+        ctx: NewPeerContext,
+        local_id: &NodeIdShort,
+        peer_id: &NodeIdShort,
+        addr: SocketAddrV4,
+        peer_id_full: NodeIdFull,
+    ) -> Result<AddPeerOutcome> {
         use dashmap::mapref::entry::Entry;
 
         // Ignore ourself
         if peer_id == local_id || addr == self.socket_addr {
-            return Ok(false);
+            return Ok(AddPeerOutcome::Ignored);
         }
 
         // Check peer with peer filter (if specified)
         if let Some(filter) = &self.peer_filter {
             if !filter.check(ctx, addr, peer_id) {
-                return Ok(false);
+                self.peers_rejected.fetch_add(1, Ordering::Relaxed);
+                self.emit_event(|timestamp_ms| NetworkEvent::PeerRejected {
+                    local_id: *local_id,
+                    peer_id: *peer_id,
+                    addr,
+                    timestamp_ms,
+                });
+                return Ok(AddPeerOutcome::Ignored);
             }
         }
 
+        // Ignore internal addresses advertised to the public network
+        if self.is_reserved_peer_address(ctx, &addr) {
+            self.reject_reserved_peer_address(local_id, peer_id, addr);
+            return Ok(AddPeerOutcome::Ignored);
+        }
+
         // Search remove peer in known peers
-        match self.get_peers(local_id)?.entry(*peer_id) {
+        let peers = self.get_peers(local_id)?;
+        let added = match peers.entry(*peer_id) {
             // Update ip if peer is already known
-            Entry::Occupied(entry) => entry.get().set_addr(addr),
+            Entry::Occupied(entry) => {
+                // The new address is checked in the context in which the peer was added
+                // (e.g. the address list of a handshake packet from a public overlay peer)
+                if self.is_reserved_peer_address(entry.get().context(), &addr) {
+                    drop(entry);
+                    self.reject_reserved_peer_address(local_id, peer_id, addr);
+                    return Ok(AddPeerOutcome::Ignored);
+                }
+                entry.get().set_addr(addr);
+                None
+            }
             // Create new peer state otherwise
             Entry::Vacant(entry) => {
-                entry.insert(Peer::new(self.start_time, addr, peer_id_full));
+                let now_ms = self.clock.now_ms();
+                // NOTE: the event is pushed before the peer becomes visible
+                // so that it is always delivered before any other events
+                self.peer_events.push(local_id, peer_id, PeerEvent::Added);
+                entry.insert(Peer::new(self.start_time, addr, peer_id_full, ctx, now_ms));
                 tracing::trace!(%local_id, %peer_id, %addr, "added ADNL peer");
+                Some(now_ms)
             }
         };
 
-        Ok(true)
+        let now_ms = match added {
+            Some(now_ms) => now_ms,
+            None => return Ok(AddPeerOutcome::Updated),
+        };
+        self.peer_eviction
+            .insert(local_id, ctx, peer_id, now_ms, peers);
+
+        self.deliver_peer_events(local_id, peer_id);
+        self.emit_event(|timestamp_ms| NetworkEvent::PeerAdded {
+            local_id: *local_id,
+            peer_id: *peer_id,
+            addr,
+            timestamp_ms,
+        });
+
+        let evicted = self.evict_peer(ctx, local_id, peer_id)?;
+        Ok(AddPeerOutcome::Added { evicted })
     }
 
     /// Removes remote peer.
@@ -355,16 +1276,42 @@ impl Node {
     ///
     /// See [`Node::add_peer`]
     pub fn remove_peer(&self, local_id: &NodeIdShort, peer_id: &NodeIdShort) -> Result<bool> {
-        let peers = self.get_peers(local_id)?;
+        self.remove_peer_impl(local_id, peer_id, PeerLostReason::Removed)
+    }
 
-        self.channels_by_peers
-            .remove(peer_id)
-            .and_then(|(_, removed)| {
-                self.channels_by_id.remove(removed.ordinary_channel_in_id());
-                self.channels_by_id.remove(removed.priority_channel_in_id())
-            });
+    /// Exempts the peer from the eviction (see [`NodeOptions::max_peers_per_context`]).
+    /// Returns whether the peer was not pinned before.
+    ///
+    /// Pins are counted, so the peer can be evicted again only after the same number
+    /// of [`Node::unpin_peer`] calls.
+    ///
+    /// NOTE: Members of the private overlays are pinned automatically.
+    pub fn pin_peer(&self, peer_id: &NodeIdShort) -> bool {
+        let mut count = self.pinned_peers.entry(*peer_id).or_insert(0);
+        *count += 1;
+        *count == 1
+    }
 
-        Ok(peers.remove(peer_id).is_some())
+    /// Releases one pin of the peer. Returns whether the peer was pinned
+    pub fn unpin_peer(&self, peer_id: &NodeIdShort) -> bool {
+        use dashmap::mapref::entry::Entry;
+
+        match self.pinned_peers.entry(*peer_id) {
+            Entry::Occupied(mut entry) if *entry.get() > 1 => {
+                *entry.get_mut() -= 1;
+                true
+            }
+            Entry::Occupied(entry) => {
+                entry.remove();
+                true
+            }
+            Entry::Vacant(_) => false,
+        }
+    }
+
+    /// Whether the peer was pinned with [`Node::pin_peer`]
+    pub fn is_peer_pinned(&self, peer_id: &NodeIdShort) -> bool {
+        self.pinned_peers.contains_key(peer_id)
     }
 
     /// Searches for remote peer socket address in the known peers
@@ -378,6 +1325,229 @@ impl Node {
         Some(peer.addr())
     }
 
+    /// Adds the peer which is known for `from_local_id` for another local id,
+    /// in the same context and with the same address.
+    /// Returns `false` if the peer is unknown or was ignored
+    #[cfg(feature = "overlay")]
+    pub(crate) fn copy_peer(
+        &self,
+        from_local_id: &NodeIdShort,
+        to_local_id: &NodeIdShort,
+        peer_id: &NodeIdShort,
+    ) -> Result<bool> {
+        let (ctx, addr, peer_id_full) = match self
+            .get_peers(from_local_id)
+            .ok()
+            .and_then(|peers| peers.get(peer_id))
+        {
+            Some(peer) => (peer.context(), peer.addr(), *peer.id()),
+            None => return Ok(false),
+        };
+        self.add_peer(ctx, to_local_id, peer_id, addr, peer_id_full)
+    }
+
+    /// Returns capabilities announced by the remote peer.
+    ///
+    /// NOTE: Unknown peers and peers which didn't announce anything
+    /// have all-zero capabilities
+    pub fn peer_capabilities(
+        &self,
+        local_id: &NodeIdShort,
+        peer_id: &NodeIdShort,
+    ) -> PeerCapabilities {
+        match self
+            .get_peers(local_id)
+            .ok()
+            .and_then(|peers| peers.get(peer_id))
+        {
+            Some(peer) => peer.capabilities(),
+            None => Default::default(),
+        }
+    }
+
+    /// Rolling rates of the traffic, query and broadcast counters.
+    /// Returns `None` if [`NodeOptions::rates_sample_interval_sec`] is not set
+    pub fn rates(&self) -> Option<NodeRates> {
+        self.rates.as_ref().map(RatesSampler::rates)
+    }
+
+    /// Counts outgoing or incoming RLDP query for [`Node::rates`]
+    #[cfg(feature = "rldp")]
+    pub(crate) fn record_rldp_query(&self) {
+        if let Some(rates) = &self.rates {
+            rates.add_rldp_query();
+        }
+    }
+
+    /// Counts incoming overlay broadcast message for [`Node::rates`]
+    #[cfg(feature = "overlay")]
+    pub(crate) fn record_broadcast(&self) {
+        if let Some(rates) = &self.rates {
+            rates.add_broadcast();
+        }
+    }
+
+    /// Sets the set of optional packet fields emitted to the peer.
+    /// Returns `false` if the peer is unknown
+    pub fn set_peer_compat(
+        &self,
+        local_id: &NodeIdShort,
+        peer_id: &NodeIdShort,
+        compat: PeerCompat,
+    ) -> bool {
+        match self
+            .get_peers(local_id)
+            .ok()
+            .and_then(|peers| peers.get(peer_id))
+        {
+            Some(peer) => {
+                peer.set_compat(compat);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Current compatibility profile of the peer, see [`PeerCompat`].
+    /// Returns `None` for unknown peers
+    pub fn peer_compat(&self, local_id: &NodeIdShort, peer_id: &NodeIdShort) -> Option<PeerCompat> {
+        let peers = self.get_peers(local_id).ok()?;
+        let peer = peers.get(peer_id)?;
+        Some(peer.compat())
+    }
+
+    /// Instant metrics of the remote peer. Returns `None` for unknown peers
+    pub fn peer_metrics(
+        &self,
+        local_id: &NodeIdShort,
+        peer_id: &NodeIdShort,
+    ) -> Option<PeerMetrics> {
+        let peers = self.get_peers(local_id).ok()?;
+        let peer = peers.get(peer_id)?;
+        let queue = peer.send_queue();
+        let (received, lost) = [false, true]
+            .into_iter()
+            .map(|priority| peer.receiver_state().history(priority).stats())
+            .fold((0, 0), |(received, lost), stats| {
+                (received + stats.received, lost + stats.lost)
+            });
+        Some(PeerMetrics {
+            send_queue_len: queue.len(),
+            send_queue_dropped: queue.dropped(),
+            probed_datagram_size: peer.probed_datagram_size(),
+            incoming_packets_lost: lost,
+            incoming_loss: match received + lost {
+                0 => 0.0,
+                total => lost as f64 / total as f64,
+            },
+        })
+    }
+
+    /// Waits until the send queue of the remote peer has free space
+    /// (see [`NodeOptions::peer_send_queue_capacity`]).
+    ///
+    /// Returns immediately for unknown peers and unbounded queues
+    pub async fn wait_for_send_queue_space(&self, local_id: &NodeIdShort, peer_id: &NodeIdShort) {
+        let queue = match self
+            .get_peers(local_id)
+            .ok()
+            .and_then(|peers| peers.get(peer_id))
+        {
+            Some(peer) => peer.send_queue().clone(),
+            None => return,
+        };
+        queue
+            .wait_for_space(self.options.load().peer_send_queue_capacity)
+            .await;
+    }
+
+    /// Establishes the channel with the remote peer if there is no ready channel yet.
+    ///
+    /// Sends `CreateChannel` (or `ConfirmChannel` for the channel created by the peer)
+    /// and waits until the channel is confirmed. Returns immediately if the channel
+    /// is already ready. Concurrent calls for the same peer wait for the same channel
+    /// and share its pending setup instead of sending their own `CreateChannel`
+    /// (unless [`NodeOptions::channel_setup_retry_base_ms`] disables the setup tracking).
+    ///
+    /// Fails with [`EnsureChannelError`] if the channel creation was not sent
+    /// or was not confirmed during the `timeout` (or [`NodeOptions::query_default_timeout_ms`])
+    pub async fn ensure_channel(
+        &self,
+        local_id: &NodeIdShort,
+        peer_id: &NodeIdShort,
+        timeout: Option<u64>,
+    ) -> Result<ChannelInfo> {
+        let timeout = timeout.unwrap_or(self.options.load().query_default_timeout_ms);
+        let deadline = self.clock.sleep(Duration::from_millis(timeout));
+        tokio::pin!(deadline);
+
+        let mut sent = false;
+        let mut setup = None;
+        loop {
+            let notified = self.channel_established.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+
+            if let Some(channel) = self.channels_by_peers.get(&(*local_id, *peer_id)) {
+                if channel.ready() {
+                    return Ok(ChannelInfo {
+                        local_id: *local_id,
+                        peer_id: *peer_id,
+                        peer_channel_date: channel.peer_channel_date(),
+                        created: sent,
+                    });
+                }
+            }
+
+            if !sent {
+                setup = self.pending_channel_setup(local_id, peer_id);
+                if setup.is_none() {
+                    // Channel creation messages are added to any message without ready channel
+                    self.send_message(local_id, peer_id, proto::adnl::Message::Nop, false)
+                        .map_err(EnsureChannelError::NotSent)?;
+                    setup = self.channel_setup(local_id, peer_id);
+                }
+                sent = true;
+            }
+
+            tokio::select! {
+                biased;
+                _ = notified => {}
+                _ = &mut deadline => return Err(EnsureChannelError::NotConfirmed.into()),
+                e = self.drive_channel_setup(local_id, peer_id, setup.as_ref()) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// Waits for the send queue space if queries must not be dropped.
+    /// Returns `false` if the query timed out while waiting
+    async fn wait_for_query_send_queue_space(
+        &self,
+        local_id: &NodeIdShort,
+        peer_id: &NodeIdShort,
+        timeout: Option<u64>,
+    ) -> bool {
+        let options = self.options.load_full();
+        if options.peer_send_queue_policy != SendQueuePolicy::BlockQueries {
+            return true;
+        }
+
+        let timeout = timeout.unwrap_or(options.query_default_timeout_ms);
+        tokio::select! {
+            _ = self.wait_for_send_queue_space(local_id, peer_id) => true,
+            _ = self.clock.sleep(Duration::from_millis(timeout)) => {
+                tracing::trace!(timeout, "query timed out in the send queue");
+                false
+            }
+        }
+    }
+
+    /// Adds feature bits which will be announced to the peers after channel establishment
+    #[cfg(feature = "rldp")]
+    pub(crate) fn add_local_features(&self, features: u64) {
+        self.local_features.fetch_or(features, Ordering::Relaxed);
+    }
+
     /// Matches entries with peer id by socket address
     ///
     /// NOTE: It is a quite expensive method that iterates over all peers
@@ -404,6 +1574,7 @@ impl Node {
     }
 
     /// ADNL query without prefix to the remote peer.
+    /// Answer with an unexpected constructor results in [`UnexpectedAnswerError`].
     ///
     /// NOTE: In case of timeout returns `Ok(None)`
     pub async fn query<Q, A>(
@@ -418,14 +1589,86 @@ impl Node {
         for<'a> A: TlRead<'a, Repr = tl_proto::Boxed> + 'static,
     {
         match self
-            .query_raw(local_id, peer_id, make_query(None, query), timeout)
+            .query_impl(
+                local_id,
+                peer_id,
+                QueryId(gen_fast_bytes()),
+                None,
+                query,
+                timeout,
+                Default::default(),
+            )
+            .await?
+        {
+            Some(answer) => deserialize_answer(&answer).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// ADNL query without prefix to the remote peer which is sent again with
+    /// the same query id while there is no answer (see [`QueryOptions`]).
+    /// The answer to any of the transmissions completes the query.
+    ///
+    /// NOTE: In case of timeout returns `Ok(None)`
+    pub async fn query_with_options<Q, A>(
+        &self,
+        local_id: &NodeIdShort,
+        peer_id: &NodeIdShort,
+        query: Q,
+        timeout: Option<u64>,
+        options: QueryOptions,
+    ) -> Result<Option<A>>
+    where
+        Q: TlWrite,
+        for<'a> A: TlRead<'a, Repr = tl_proto::Boxed> + 'static,
+    {
+        match self
+            .query_impl(
+                local_id,
+                peer_id,
+                QueryId(gen_fast_bytes()),
+                None,
+                query,
+                timeout,
+                options,
+            )
             .await?
         {
-            Some(answer) => Ok(Some(tl_proto::deserialize(&answer)?)),
+            Some(answer) => deserialize_answer(&answer).map(Some),
             None => Ok(None),
         }
     }
 
+    /// Sends echo query with random payload of the specified length.
+    /// Remote peer must have [`EchoSubscriber`] registered.
+    ///
+    /// NOTE: In case of timeout returns `Ok(None)`
+    pub async fn ping_peer(
+        &self,
+        local_id: &NodeIdShort,
+        peer_id: &NodeIdShort,
+        payload_len: usize,
+        timeout: Option<u64>,
+    ) -> Result<Option<PingStats>> {
+        let data: Vec<u8> = (0..payload_len).map(|_| rand::random()).collect();
+
+        let started_at = Instant::now();
+        let answer = self
+            .query::<_, proto::adnl::EchoAnswer>(
+                local_id,
+                peer_id,
+                proto::rpc::NetworkEcho { data: data.clone() },
+                timeout,
+            )
+            .await?;
+
+        Ok(answer.map(|answer| PingStats {
+            rtt: started_at.elapsed(),
+            remote_received_at: answer.received_at,
+            intact: answer.data == data,
+        }))
+    }
+
     /// ADNL query with prefix to the remote peer
     ///
     /// NOTE: In case of timeout returns `Ok(None)`
@@ -442,58 +1685,359 @@ impl Node {
         for<'a> A: TlRead<'a, Repr = tl_proto::Boxed> + 'static,
     {
         match self
-            .query_raw(local_id, peer_id, make_query(Some(prefix), query), timeout)
+            .query_impl(
+                local_id,
+                peer_id,
+                QueryId(gen_fast_bytes()),
+                Some(prefix),
+                query,
+                timeout,
+                Default::default(),
+            )
             .await?
         {
-            Some(answer) => Ok(Some(tl_proto::deserialize(&answer)?)),
+            Some(answer) => deserialize_answer(&answer).map(Some),
             None => Ok(None),
         }
     }
 
-    /// ADNL query to the remote peer
+    /// ADNL query to the remote peer which also returns the query id
+    /// (e.g. to correlate logs with the remote side, see [`QueryContext::query_id`]).
+    ///
+    /// NOTE: In case of timeout returns `Ok((query_id, None))`
+    pub async fn query_traced<Q, A>(
+        &self,
+        local_id: &NodeIdShort,
+        peer_id: &NodeIdShort,
+        query: Q,
+        timeout: Option<u64>,
+    ) -> Result<(QueryId, Option<A>)>
+    where
+        Q: TlWrite,
+        for<'a> A: TlRead<'a, Repr = tl_proto::Boxed> + 'static,
+    {
+        self.query_traced_impl(local_id, peer_id, None, query, timeout)
+            .await
+    }
+
+    /// ADNL query with prefix to the remote peer which also returns the query id
+    ///
+    /// NOTE: In case of timeout returns `Ok((query_id, None))`
+    pub async fn query_with_prefix_traced<Q, A>(
+        &self,
+        local_id: &NodeIdShort,
+        peer_id: &NodeIdShort,
+        prefix: &[u8],
+        query: Q,
+        timeout: Option<u64>,
+    ) -> Result<(QueryId, Option<A>)>
+    where
+        Q: TlWrite,
+        for<'a> A: TlRead<'a, Repr = tl_proto::Boxed> + 'static,
+    {
+        self.query_traced_impl(local_id, peer_id, Some(prefix), query, timeout)
+            .await
+    }
+
+    async fn query_traced_impl<Q, A>(
+        &self,
+        local_id: &NodeIdShort,
+        peer_id: &NodeIdShort,
+        prefix: Option<&[u8]>,
+        query: Q,
+        timeout: Option<u64>,
+    ) -> Result<(QueryId, Option<A>)>
+    where
+        Q: TlWrite,
+        for<'a> A: TlRead<'a, Repr = tl_proto::Boxed> + 'static,
+    {
+        let query_id = QueryId(gen_fast_bytes());
+        match self
+            .query_impl(
+                local_id,
+                peer_id,
+                query_id,
+                prefix,
+                query,
+                timeout,
+                Default::default(),
+            )
+            .await?
+        {
+            Some(answer) => Ok((query_id, Some(deserialize_answer(&answer)?))),
+            None => Ok((query_id, None)),
+        }
+    }
+
+    /// ADNL query with already serialized data (e.g. received from an external client)
+    /// to the remote peer. The answer is returned as is.
     ///
     /// NOTE: In case of timeout returns `Ok(None)`
     pub async fn query_raw(
         &self,
         local_id: &NodeIdShort,
         peer_id: &NodeIdShort,
-        query: Bytes,
+        query: &[u8],
         timeout: Option<u64>,
     ) -> Result<Option<Vec<u8>>> {
-        let query_id: QueryId = gen_fast_bytes();
+        self.query_impl(
+            local_id,
+            peer_id,
+            QueryId(gen_fast_bytes()),
+            None,
+            tl_proto::RawBytes::<tl_proto::Boxed>::new(query),
+            timeout,
+            Default::default(),
+        )
+        .await
+    }
 
-        let pending_query = self.queries.add_query(query_id);
-        self.send_message(
+    /// Serializes query (with an optional prefix) into the reusable buffer
+    /// and sends it to the remote peer
+    async fn query_impl<Q>(
+        &self,
+        local_id: &NodeIdShort,
+        peer_id: &NodeIdShort,
+        query_id: QueryId,
+        prefix: Option<&[u8]>,
+        query: Q,
+        timeout: Option<u64>,
+        options: QueryOptions,
+    ) -> Result<Option<Vec<u8>>>
+    where
+        Q: TlWrite,
+    {
+        let span = query_span(peer_id, &query_id);
+
+        if !self
+            .wait_for_query_send_queue_space(local_id, peer_id, timeout)
+            .instrument(span.clone())
+            .await
+        {
+            return Ok(None);
+        }
+
+        // One-time key and the peer key to decrypt the answer
+        let answer_keys = match options.ephemeral_answer_key {
+            true => {
+                let peers = self.get_peers(local_id)?;
+                let peer = peers.get(peer_id).ok_or(NodeError::UnknownPeer)?;
+                let answer_key = Key::from(ed25519::SecretKey::generate(&mut rand::thread_rng()));
+                Some((answer_key, *peer.id().public_key()))
+            }
+            false => None,
+        };
+
+        let mut constructor = None;
+        let mut retransmitted_query = None;
+        let pending_query = self.queries.add_query(local_id, peer_id, query_id);
+        span.in_scope(|| {
+            with_serialize_buffer(|buffer| {
+                let prefix_len = prefix.map(<[u8]>::len).unwrap_or_default();
+                buffer.reserve(prefix_len + query.max_size_hint());
+                if let Some(prefix) = prefix {
+                    buffer.extend_from_slice(prefix);
+                }
+                query.write_to(buffer);
+                constructor = query_constructor(&buffer[prefix_len..]);
+                span.record(
+                    "constructor",
+                    tracing::field::display(DisplayConstructor(&buffer[prefix_len..])),
+                );
+
+                let query = match &answer_keys {
+                    Some((answer_key, _)) => {
+                        Cow::Owned(tl_proto::serialize(proto::rpc::PrivateQueryWithAnswerKey {
+                            answer_key: answer_key.public_key().as_bytes(),
+                            query: buffer,
+                        }))
+                    }
+                    None => Cow::Borrowed(buffer.as_slice()),
+                };
+                if options.retries > 0 {
+                    retransmitted_query = Some(query.to_vec());
+                }
+
+                self.send_query_message(local_id, peer_id, &query_id, &query)
+            })
+        })?;
+        drop(query);
+        self.traffic.queries_sent.fetch_add(1, Ordering::Relaxed);
+
+        let retransmit = retransmitted_query.as_deref().map(|query| QueryRetransmit {
+            query_id: &query_id,
+            query,
+            options: &options,
+        });
+        let answer = self
+            .wait_for_answer(
+                local_id,
+                peer_id,
+                pending_query,
+                constructor,
+                timeout,
+                retransmit,
+            )
+            .instrument(span)
+            .await?;
+
+        let answer = match (answer, answer_keys) {
+            (Some(answer), Some((answer_key, peer_key))) => {
+                decrypt_answer(&answer_key, &peer_key, &answer)?
+            }
+            (Some(answer), None) => answer,
+            (None, _) => return Ok(None),
+        };
+
+        if let Some(verify) = &options.verify {
+            self.verify_answer(peer_id, &answer, verify)?;
+        }
+        Ok(Some(answer))
+    }
+
+    /// Penalizes the peer if the answer was rejected by the verifier.
+    /// Query rejections are not verified
+    pub(crate) fn verify_answer(
+        &self,
+        peer_id: &NodeIdShort,
+        answer: &[u8],
+        verify: &AnswerVerifier,
+    ) -> Result<(), AnswerVerificationFailed> {
+        if verify(answer) || tl_proto::deserialize::<proto::adnl::QueryRejected>(answer).is_ok() {
+            return Ok(());
+        }
+
+        tracing::debug!(%peer_id, len = answer.len(), "answer failed verification");
+        self.answers_unverified.fetch_add(1, Ordering::Relaxed);
+        self.report_peer(peer_id, ReputationEvent::MalformedAnswer);
+        Err(AnswerVerificationFailed { peer_id: *peer_id })
+    }
+
+    fn send_query_message(
+        &self,
+        local_id: &NodeIdShort,
+        peer_id: &NodeIdShort,
+        query_id: &QueryId,
+        query: &[u8],
+    ) -> Result<()> {
+        let result = self.send_message(
             local_id,
             peer_id,
             proto::adnl::Message::Query {
-                query_id: &query_id,
-                query: &query,
+                query_id: query_id.as_slice(),
+                query,
             },
-            self.options.force_use_priority_channels,
-        )?;
-        drop(query);
+            self.options.load().force_use_priority_channels,
+        );
+        match &result {
+            Ok(()) => tracing::trace!(len = query.len(), "query sent"),
+            Err(e) => tracing::trace!("failed to send query: {e:?}"),
+        }
+        result
+    }
 
+    async fn wait_for_answer(
+        &self,
+        local_id: &NodeIdShort,
+        peer_id: &NodeIdShort,
+        pending_query: PendingAdnlQuery,
+        constructor: Option<u32>,
+        timeout: Option<u64>,
+        retransmit: Option<QueryRetransmit<'_>>,
+    ) -> Result<Option<Vec<u8>>> {
         let channel = self
             .channels_by_peers
-            .get(peer_id)
+            .get(&(*local_id, *peer_id))
             .map(|entry| entry.value().clone());
 
-        let timeout = timeout.unwrap_or(self.options.query_default_timeout_ms);
-        let answer = tokio::time::timeout(Duration::from_millis(timeout), pending_query.wait())
-            .await
-            .ok()
-            .flatten();
+        let started_at = self.clock.instant();
+        let timeout = timeout.unwrap_or(self.options.load().query_default_timeout_ms);
+        let mut deadline = self.clock.sleep(Duration::from_millis(timeout));
+        let mut retries_left = retransmit
+            .as_ref()
+            .map(|retransmit| retransmit.options.retries)
+            .unwrap_or_default();
+        let setup = self.channel_setup(local_id, peer_id);
+        let mut setup_timeout = None;
+        let answer = loop {
+            let next_retry = match &retransmit {
+                Some(retransmit) if retries_left > 0 => {
+                    self.clock.sleep(retransmit.options.retry_interval)
+                }
+                _ => Box::pin(std::future::pending()),
+            };
+
+            tokio::select! {
+                answer = pending_query.wait() => break answer,
+                _ = &mut deadline => break None,
+                e = self.drive_channel_setup(local_id, peer_id, setup.as_ref()) => {
+                    setup_timeout = Some(e);
+                    break None;
+                }
+                _ = next_retry => {
+                    if let Some(retransmit) = &retransmit {
+                        retries_left -= 1;
+                        self.query_retransmits.fetch_add(1, Ordering::Relaxed);
+                        self.send_query_message(
+                            local_id,
+                            peer_id,
+                            retransmit.query_id,
+                            retransmit.query,
+                        )
+                        .ok();
+                    }
+                }
+            }
+        };
+
+        let elapsed = self.clock.instant().saturating_duration_since(started_at);
+        let span = tracing::Span::current();
+        span.record("elapsed_ms", elapsed.as_millis() as u64);
+
+        let counter = match &answer {
+            Some(_) => &self.traffic.queries_answered,
+            None => &self.traffic.queries_timed_out,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
 
-        if answer.is_none() {
-            if let Some(channel) = channel {
-                if channel.update_drop_timeout(now(), self.options.channel_reset_timeout_sec) {
-                    self.reset_peer(local_id, peer_id)?;
+        match &answer {
+            Some(answer) => {
+                let options = self.options.load();
+                self.query_latencies.record(
+                    peer_id,
+                    constructor,
+                    elapsed,
+                    options.latency_tracked_peers,
+                    options.latency_tracked_constructors,
+                );
+                tracing::trace!(len = answer.len(), "query answered")
+            }
+            None => {
+                tracing::trace!(timeout, "query timed out");
+                if let Some(channel) = channel {
+                    if channel.update_drop_timeout(
+                        self.uptime_sec(),
+                        self.options.load().channel_reset_timeout_sec,
+                    ) {
+                        tracing::trace!("channel reset");
+                        self.reset_peer(local_id, peer_id)?;
+                    }
                 }
             }
         }
 
-        Ok(answer)
+        match setup_timeout {
+            Some(e) => Err(e.into()),
+            None => Ok(answer),
+        }
+    }
+
+    /// Seconds elapsed since the node creation (monotonic)
+    fn uptime_sec(&self) -> u32 {
+        self.clock
+            .instant()
+            .saturating_duration_since(self.started_at)
+            .as_secs() as u32
     }
 
     /// Sends a one-way ADNL message
@@ -507,7 +2051,7 @@ impl Node {
             local_id,
             peer_id,
             proto::adnl::Message::Custom { data },
-            self.options.force_use_priority_channels,
+            self.options.load().force_use_priority_channels,
         )
     }
 
@@ -519,71 +2063,442 @@ impl Node {
         }
     }
 
-    fn reset_peer(&self, local_id: &NodeIdShort, peer_id: &NodeIdShort) -> Result<()> {
+    fn remove_peer_impl(
+        &self,
+        local_id: &NodeIdShort,
+        peer_id: &NodeIdShort,
+        reason: PeerLostReason,
+    ) -> Result<bool> {
+        use dashmap::mapref::entry::Entry;
+
         let peers = self.get_peers(local_id)?;
-        let mut peer = peers.get_mut(peer_id).ok_or(NodeError::UnknownPeer)?;
+
+        self.channels_by_peers
+            .remove(&(*local_id, *peer_id))
+            .and_then(|(_, removed)| {
+                self.channels_by_id.remove(removed.ordinary_channel_in_id());
+                self.channels_by_id.remove(removed.priority_channel_in_id())
+            });
+
+        let removed = match peers.entry(*peer_id) {
+            Entry::Occupied(entry) => {
+                self.peer_events
+                    .push(local_id, peer_id, PeerEvent::Lost(reason));
+                let peer = entry.remove();
+                self.peer_eviction.remove(local_id, peer.context());
+                true
+            }
+            Entry::Vacant(_) => false,
+        };
+        if removed {
+            self.deliver_peer_events(local_id, peer_id);
+            self.emit_peer_lost(local_id, peer_id, reason);
+        }
+
+        Ok(removed)
+    }
+
+    /// Removes the least recently active unpinned peer of the same context
+    /// if there are more of them than [`NodeOptions::max_peers_per_context`]
+    fn evict_peer(
+        &self,
+        ctx: NewPeerContext,
+        local_id: &NodeIdShort,
+        new_peer_id: &NodeIdShort,
+    ) -> Result<Option<NodeIdShort>> {
+        let max_peers = match self.options.load().max_peers_per_context {
+            Some(max_peers) => max_peers,
+            None => return Ok(None),
+        };
+
+        let peer_id = match self.peer_eviction.least_active(
+            local_id,
+            ctx,
+            new_peer_id,
+            max_peers,
+            self.get_peers(local_id)?,
+            &self.pinned_peers,
+        ) {
+            Some(peer_id) => peer_id,
+            None => return Ok(None),
+        };
+
+        tracing::trace!(%local_id, %peer_id, "evicted ADNL peer");
+        self.peers_evicted.fetch_add(1, Ordering::Relaxed);
+        self.remove_peer_impl(local_id, &peer_id, PeerLostReason::Evicted)?;
+        Ok(Some(peer_id))
+    }
+
+    fn reset_peer(&self, local_id: &NodeIdShort, peer_id: &NodeIdShort) -> Result<()> {
+        let peers = self.get_peers(local_id)?;
+        let mut peer = peers.get_mut(peer_id).ok_or(NodeError::UnknownPeer)?;
 
         tracing::trace!(%local_id, %peer_id, "resetting peer pair");
 
         self.channels_by_peers
-            .remove(peer_id)
+            .remove(&(*local_id, *peer_id))
             .and_then(|(_, removed)| {
                 self.channels_by_id.remove(removed.ordinary_channel_in_id());
                 self.channels_by_id.remove(removed.priority_channel_in_id())
             });
 
         peer.reset();
+        self.peer_events
+            .push(local_id, peer_id, PeerEvent::Lost(PeerLostReason::Reset));
+        drop(peer);
+
+        self.deliver_peer_events(local_id, peer_id);
+        self.emit_peer_lost(local_id, peer_id, PeerLostReason::Reset);
 
         Ok(())
     }
+
+    fn emit_peer_lost(
+        &self,
+        local_id: &NodeIdShort,
+        peer_id: &NodeIdShort,
+        reason: PeerLostReason,
+    ) {
+        self.emit_event(|timestamp_ms| NetworkEvent::PeerLost {
+            local_id: *local_id,
+            peer_id: *peer_id,
+            reason,
+            timestamp_ms,
+        });
+    }
+
+    /// Notifies subscribers and announces local capabilities to the peer
+    fn on_channel_established(&self, local_id: &NodeIdShort, peer_id: &NodeIdShort) {
+        self.complete_channel_setup(local_id, peer_id);
+        self.channel_established.notify_waiters();
+        self.deliver_peer_events(local_id, peer_id);
+        self.emit_event(|timestamp_ms| NetworkEvent::ChannelEstablished {
+            local_id: *local_id,
+            peer_id: *peer_id,
+            timestamp_ms,
+        });
+
+        if self.options.load().mtu_probe_enabled {
+            self.mtu_probe_tx.send((*local_id, *peer_id)).ok();
+        }
+
+        let capabilities = proto::adnl::Capabilities {
+            version: CAPABILITIES_VERSION,
+            features: self.local_features.load(Ordering::Relaxed),
+        };
+        if let Err(e) =
+            self.send_custom_message(local_id, peer_id, &tl_proto::serialize(capabilities))
+        {
+            tracing::debug!(%local_id, %peer_id, "failed to send capabilities: {e:?}");
+        }
+    }
+
+    /// Calls peer hooks of all message subscribers for the events
+    /// which were pushed to [`PeerEvents`]
+    fn deliver_peer_events(&self, local_id: &NodeIdShort, peer_id: &NodeIdShort) {
+        self.peer_events.deliver(local_id, peer_id, |event| {
+            let f = |subscriber: &Arc<dyn MessageSubscriber>| match event {
+                PeerEvent::Added => subscriber.on_peer_added(local_id, peer_id),
+                PeerEvent::ChannelEstablished => {
+                    subscriber.on_channel_established(local_id, peer_id)
+                }
+                PeerEvent::Lost(reason) => subscriber.on_peer_lost(local_id, peer_id, reason),
+            };
+            match self.message_subscribers.get() {
+                Some(subscribers) => subscribers.iter().for_each(f),
+                None => {
+                    // Clone subscribers to allow registering new ones from hooks
+                    let subscribers = match &*self.init_state.lock() {
+                        Some(init) => init.message_subscribers.clone(),
+                        None => return,
+                    };
+                    subscribers.iter().for_each(f);
+                }
+            }
+        });
+    }
 }
 
-impl Drop for Node {
-    fn drop(&mut self) {
-        // Cancel all tasks on drop
-        self.shutdown()
+/// Returns the name of the changed option which can't be changed at runtime
+fn check_construction_only_options(
+    current: &NodeOptions,
+    options: &NodeOptions,
+) -> Result<(), &'static str> {
+    if options.packet_history_enabled != current.packet_history_enabled {
+        return Err("packet_history_enabled");
+    }
+    if options.event_queue_capacity != current.event_queue_capacity {
+        return Err("event_queue_capacity");
     }
+    if options.version != current.version {
+        return Err("version");
+    }
+    if options.rates_sample_interval_sec != current.rates_sample_interval_sec {
+        return Err("rates_sample_interval_sec");
+    }
+    Ok(())
 }
 
+/// Version of the announced capabilities, see [`PeerCapabilities`]
+const CAPABILITIES_VERSION: u32 = 1;
+
 /// Instant ADNL node metrics
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, Serialize)]
 pub struct NodeMetrics {
     /// Total remote peer count for all local keys
     pub peer_count: usize,
+    /// Remote peer count by the context in which each peer was first added
+    pub peers_by_context: PeerCountByContext,
     /// Total unique channel count (including priority/remote duplicates)
     pub channels_by_id_len: usize,
     /// Total channel count for each remote peer
     pub channels_by_peers_len: usize,
     /// Current multipart transfer count
     pub incoming_transfers_len: usize,
-    /// Current queries cache len
+    /// Current queries cache len (including recently cancelled queries)
     pub query_count: usize,
+    /// Total number of answers which were not sent due to deadline
+    #[serde(rename = "answers_expired_total")]
+    pub answers_expired: u64,
+    /// Total number of answers which were too large for the plain ADNL queries,
+    /// see [`Node::answers_too_large`]
+    #[serde(rename = "answers_too_large_total")]
+    pub answers_too_large: u64,
+    /// Total number of answers from the peers to which the query was not sent
+    #[serde(rename = "answers_spoofed_total")]
+    pub answers_spoofed: u64,
+    /// Total number of answers which were rejected by the query verifiers,
+    /// see [`QueryOptions::verify`]
+    #[serde(rename = "answers_unverified_total")]
+    pub answers_unverified: u64,
+    /// Total number of new peers which were rejected by the [`PeerFilter`]
+    #[serde(rename = "peers_rejected_total")]
+    pub peers_rejected: u64,
+    /// Total number of public peers which were ignored due to reserved addresses,
+    /// see [`NodeOptions::reject_reserved_public_addresses`]
+    #[serde(rename = "reserved_addresses_rejected_total")]
+    pub reserved_addresses_rejected: u64,
+    /// Total number of peers which were evicted to make room for the new ones
+    #[serde(rename = "peers_evicted_total")]
+    pub peers_evicted: u64,
+    /// Total number of received answers for the queries which were no longer awaited
+    #[serde(rename = "answers_dropped_total")]
+    pub answers_dropped: u64,
+    /// Total number of dropped incoming packets by reason
+    pub packets_dropped: PacketDropMetrics,
+    /// Total number of processed packets, queries and messages
+    pub traffic: TrafficMetrics,
+    /// Total number of outgoing packets which were dropped due to full peer send queues
+    #[serde(rename = "packets_send_dropped_total")]
+    pub packets_send_dropped: u64,
+    /// Total number of outgoing packets which were sent without channel (as handshake packets)
+    #[serde(rename = "handshake_packets_sent_total")]
+    pub handshake_packets_sent: u64,
+    /// Total number of retransmitted outgoing queries, see [`QueryOptions::retries`]
+    #[serde(rename = "query_retransmits_total")]
+    pub query_retransmits: u64,
+    /// Total number of retransmitted incoming queries which were not processed again
+    #[serde(rename = "queries_deduplicated_total")]
+    pub queries_deduplicated: u64,
+    /// Total number of retransmitted `CreateChannel` messages,
+    /// see [`NodeOptions::channel_setup_retry_base_ms`]
+    #[serde(rename = "channel_setup_retransmits_total")]
+    pub channel_setup_retransmits: u64,
+    /// Total number of channels which were not confirmed in time
+    #[serde(rename = "channel_setup_timeouts_total")]
+    pub channel_setup_timeouts: u64,
+    /// Total number of events which were dropped before all receivers got them
+    #[serde(rename = "events_dropped_total")]
+    pub events_dropped: u64,
+    /// Number of cached answers which were too large for the plain ADNL queries
+    pub large_answers_len: usize,
+    /// Total size of cached large answers in bytes
+    #[serde(rename = "large_answers_size_bytes")]
+    pub large_answers_size: usize,
+    /// Number of answers which are awaited from [`Node::send_deferred_answer`]
+    pub deferred_answers_len: usize,
+    /// Total number of deferred answers which were not sent in time,
+    /// see [`NodeOptions::deferred_answer_ttl_ms`]
+    #[serde(rename = "deferred_answers_expired_total")]
+    pub deferred_answers_expired: u64,
+    /// Total number of per-packet log messages which were suppressed by sampling
+    #[serde(rename = "log_messages_suppressed_total")]
+    pub log_messages_suppressed: u64,
+}
+
+/// Number of remote peers for each [`NewPeerContext`]
+#[derive(Debug, Default, Copy, Clone, Serialize)]
+pub struct PeerCountByContext {
+    pub adnl_packet: usize,
+    pub dht: usize,
+    pub public_overlay: usize,
+}
+
+/// See [`Node::add_peer_with_outcome`]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum AddPeerOutcome {
+    /// Peer was ignored or rejected by the [`PeerFilter`]
+    Ignored,
+    /// Peer was already known, only its address was updated
+    Updated,
+    /// New peer was added
+    Added {
+        /// Peer which was evicted to make room for the new one
+        evicted: Option<NodeIdShort>,
+    },
+}
+
+/// Ready channel with the remote peer, see [`Node::ensure_channel`]
+#[derive(Debug, Copy, Clone)]
+pub struct ChannelInfo {
+    pub local_id: NodeIdShort,
+    pub peer_id: NodeIdShort,
+    /// Channel creation time from the peer's side
+    pub peer_channel_date: u32,
+    /// Whether the channel was established during this call
+    pub created: bool,
+}
+
+/// Channel establishment failure stage
+#[derive(thiserror::Error, Debug)]
+pub enum EnsureChannelError {
+    #[error("Failed to send channel creation message")]
+    NotSent(#[source] anyhow::Error),
+    #[error("Channel was not confirmed by the peer in time")]
+    NotConfirmed,
+}
+
+/// Instant remote peer metrics
+#[derive(Debug, Copy, Clone)]
+pub struct PeerMetrics {
+    /// Number of outgoing packets which are waiting to be sent
+    pub send_queue_len: usize,
+    /// Total number of outgoing packets which were dropped due to the full send queue
+    pub send_queue_dropped: u64,
+    /// Max datagram size found by the MTU probe, see [`Node::probe_max_datagram_size`]
+    pub probed_datagram_size: Option<usize>,
+    /// Number of the incoming channel packets which were not received,
+    /// estimated from the gaps in their seqnos
+    pub incoming_packets_lost: u64,
+    /// Share of the lost incoming channel packets (`0.0..=1.0`)
+    pub incoming_loss: f64,
+}
+
+/// Retransmission and verification of the outgoing query, see [`Node::query_with_options`]
+#[derive(Clone)]
+pub struct QueryOptions {
+    /// Max number of times the query is sent again with the same query id
+    /// while there is no answer.
+    ///
+    /// Default: `0`
+    pub retries: u8,
+
+    /// Interval between the query retransmissions.
+    ///
+    /// Default: `500` ms
+    pub retry_interval: Duration,
+
+    /// Whether to send a new one-time key with the query, so that the answer
+    /// is encrypted to it instead of relying only on the channel keys.
+    ///
+    /// NOTE: the query wrapper is crate specific, so this works only
+    /// if the peer also uses this crate
+    ///
+    /// Default: `false`
+    pub ephemeral_answer_key: bool,
+
+    /// Checks the raw answer before the query resolves. Rejected answers result in
+    /// [`AnswerVerificationFailed`] and penalize the peer.
+    ///
+    /// Default: None
+    pub verify: Option<AnswerVerifier>,
+}
+
+impl std::fmt::Debug for QueryOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("QueryOptions")
+            .field("retries", &self.retries)
+            .field("retry_interval", &self.retry_interval)
+            .field("ephemeral_answer_key", &self.ephemeral_answer_key)
+            .field("verify", &self.verify.is_some())
+            .finish()
+    }
+}
+
+impl Default for QueryOptions {
+    fn default() -> Self {
+        Self {
+            retries: 0,
+            retry_interval: Duration::from_millis(500),
+            ephemeral_answer_key: false,
+            verify: None,
+        }
+    }
+}
+
+/// Serialized query which is sent again while there is no answer
+struct QueryRetransmit<'a> {
+    query_id: &'a QueryId,
+    query: &'a [u8],
+    options: &'a QueryOptions,
+}
+
+/// Creates a span for the outgoing query.
+///
+/// `constructor` and `elapsed_ms` fields are recorded later
+fn query_span(peer_id: &NodeIdShort, query_id: &QueryId) -> tracing::Span {
+    tracing::debug_span!(
+        "adnl_query",
+        %peer_id,
+        %query_id,
+        trace_id = %TraceId::from(query_id),
+        constructor = tracing::field::Empty,
+        elapsed_ms = tracing::field::Empty,
+    )
+}
+
+/// Displays TL constructor of the serialized query as hex
+/// Large answers are only served to the same peer which made the query
+fn compute_large_answer_key(
+    local_id: &NodeIdShort,
+    peer_id: &NodeIdShort,
+    query: &[u8],
+) -> AnswerCacheKey {
+    use sha2::Digest;
+
+    let mut hasher = sha2::Sha256::new();
+    hasher.update(local_id.as_slice());
+    hasher.update(peer_id.as_slice());
+    hasher.update(query);
+    hasher.finalize().into()
+}
+
+struct DisplayConstructor<'a>(&'a [u8]);
+
+fn query_constructor(query: &[u8]) -> Option<u32> {
+    let id = query.get(..4)?;
+    Some(u32::from_le_bytes(id.try_into().unwrap()))
+}
+
+impl std::fmt::Display for DisplayConstructor<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match query_constructor(self.0) {
+            Some(id) => write!(f, "0x{id:08x}"),
+            None => f.write_str("none"),
+        }
+    }
 }
 
 struct InitializationState {
-    socket: Arc<tokio::net::UdpSocket>,
+    socket: NodeSocket,
     /// Receiver end of the outgoing packets queue
     sender_queue_rx: SenderQueueRx,
+    /// Receiver end of the MTU probe requests
+    mtu_probe_rx: MtuProbeRx,
     message_subscribers: Vec<Arc<dyn MessageSubscriber>>,
     query_subscribers: Vec<Arc<dyn QuerySubscriber>>,
 }
 
-fn make_query<T>(prefix: Option<&[u8]>, query: T) -> Bytes
-where
-    T: TlWrite,
-{
-    let prefix_len = match prefix {
-        Some(prefix) => prefix.len(),
-        None => 0,
-    };
-    let mut data = Vec::with_capacity(prefix_len + query.max_size_hint());
-    if let Some(prefix) = prefix {
-        data.extend_from_slice(prefix);
-    }
-    query.write_to(&mut data);
-    data.into()
-}
-
 #[derive(thiserror::Error, Debug)]
 enum NodeError {
     #[error("ADNL node is already running")]
@@ -592,4 +2507,1906 @@ enum NodeError {
     PeersNotFound,
     #[error("Unknown peer")]
     UnknownPeer,
+    #[error("Address list is already set")]
+    AddressListAlreadySet,
+    #[error("Address list is empty")]
+    EmptyAddressList,
+    #[error("Option `{0}` can't be changed at runtime")]
+    ConstructionOnlyOption(&'static str),
+    #[error("Deferred answer token is unknown or expired")]
+    UnknownAnswerToken,
+    #[error("Advertised address must be specified for the socket bound to 0.0.0.0")]
+    UnspecifiedAdvertisedAddress,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+    use crate::adnl::{CidrAndIdListFilter, Ipv4Cidr, LinkOptions, VirtualNetwork};
+    use crate::util::fixtures::{
+        add_virtual_node, make_adnl_node, make_adnl_node_with_clock, make_keystore,
+    };
+
+    #[derive(Debug, Eq, PartialEq)]
+    enum PeerEvent {
+        Added,
+        ChannelEstablished,
+        Lost(PeerLostReason),
+    }
+
+    #[derive(Default)]
+    struct PeerEventsRecorder {
+        events: Mutex<Vec<(NodeIdShort, PeerEvent)>>,
+    }
+
+    impl MessageSubscriber for PeerEventsRecorder {
+        fn on_peer_added(&self, _: &NodeIdShort, peer_id: &NodeIdShort) {
+            self.events.lock().push((*peer_id, PeerEvent::Added));
+        }
+
+        fn on_channel_established(&self, _: &NodeIdShort, peer_id: &NodeIdShort) {
+            self.events
+                .lock()
+                .push((*peer_id, PeerEvent::ChannelEstablished));
+        }
+
+        fn on_peer_lost(&self, _: &NodeIdShort, peer_id: &NodeIdShort, reason: PeerLostReason) {
+            self.events.lock().push((*peer_id, PeerEvent::Lost(reason)));
+        }
+    }
+
+    #[tokio::test]
+    async fn peer_events_sequence() {
+        let left = make_adnl_node(Default::default());
+        let right = make_adnl_node(Default::default());
+
+        let recorder = Arc::new(PeerEventsRecorder::default());
+        left.add_message_subscriber(recorder.clone()).unwrap();
+        right.add_echo_subscriber().unwrap();
+
+        left.start().unwrap();
+        right.start().unwrap();
+
+        let left_id = *left.key_by_tag(0).unwrap().id();
+        let right_key = right.key_by_tag(0).unwrap().clone();
+        let right_id = *right_key.id();
+
+        left.add_peer(
+            NewPeerContext::AdnlPacket,
+            &left_id,
+            &right_id,
+            right.socket_addr(),
+            *right_key.full_id(),
+        )
+        .unwrap();
+
+        let stats = left
+            .ping_peer(&left_id, &right_id, 16, Some(1000))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(stats.intact);
+
+        assert!(left.remove_peer(&left_id, &right_id).unwrap());
+
+        let events = std::mem::take(&mut *recorder.events.lock());
+        assert_eq!(
+            events,
+            [
+                (right_id, PeerEvent::Added),
+                (right_id, PeerEvent::ChannelEstablished),
+                (right_id, PeerEvent::Lost(PeerLostReason::Removed)),
+            ]
+        );
+    }
+
+    /// Removes peers from their own hooks and waits for the hook of the other peer
+    #[derive(Default)]
+    struct ReentrantPeerHooks {
+        node: OnceCell<std::sync::Weak<Node>>,
+        blocked_peer: OnceCell<NodeIdShort>,
+        entered: Mutex<Option<std::sync::mpsc::Sender<()>>>,
+        unblock: Mutex<Option<std::sync::mpsc::Receiver<()>>>,
+        unblock_tx: Mutex<Option<std::sync::mpsc::Sender<()>>>,
+        recorder: PeerEventsRecorder,
+    }
+
+    impl MessageSubscriber for ReentrantPeerHooks {
+        fn on_peer_added(&self, local_id: &NodeIdShort, peer_id: &NodeIdShort) {
+            self.recorder.on_peer_added(local_id, peer_id);
+            if self.blocked_peer.get() == Some(peer_id) {
+                if let Some(entered) = self.entered.lock().take() {
+                    entered.send(()).unwrap();
+                }
+                let unblock = self.unblock.lock().take().unwrap();
+                unblock
+                    .recv_timeout(Duration::from_secs(10))
+                    .expect("hooks of other peers must not wait");
+            } else if let Some(unblock) = self.unblock_tx.lock().take() {
+                unblock.send(()).unwrap();
+            }
+
+            let node = self.node.get().and_then(std::sync::Weak::upgrade).unwrap();
+            assert!(node.remove_peer(local_id, peer_id).unwrap());
+        }
+
+        fn on_peer_lost(
+            &self,
+            local_id: &NodeIdShort,
+            peer_id: &NodeIdShort,
+            reason: PeerLostReason,
+        ) {
+            self.recorder.on_peer_lost(local_id, peer_id, reason);
+        }
+    }
+
+    #[tokio::test]
+    async fn peer_events_of_different_peers() {
+        let node = make_adnl_node(Default::default());
+        let local_id = *node.key_by_tag(0).unwrap().id();
+        let make_peer = |i: u8| {
+            let peer_key = everscale_crypto::ed25519::SecretKey::generate(&mut rand::thread_rng());
+            let (peer_id_full, peer_id) = crate::adnl::ComputeNodeIds::compute_node_ids(&peer_key);
+            let addr = SocketAddrV4::new(Ipv4Addr::new(1, 2, 3, i), 30303);
+            (peer_id, addr, peer_id_full)
+        };
+        let (first, second) = (make_peer(1), make_peer(2));
+
+        let hooks = Arc::new(ReentrantPeerHooks::default());
+        let (entered_tx, entered_rx) = std::sync::mpsc::channel();
+        let (unblock_tx, unblock_rx) = std::sync::mpsc::channel();
+        hooks.node.set(Arc::downgrade(&node)).unwrap();
+        hooks.blocked_peer.set(first.0).unwrap();
+        *hooks.entered.lock() = Some(entered_tx);
+        *hooks.unblock.lock() = Some(unblock_rx);
+        *hooks.unblock_tx.lock() = Some(unblock_tx);
+        node.add_message_subscriber(hooks.clone()).unwrap();
+
+        let add_peer = |node: &Node, (peer_id, addr, peer_id_full)| {
+            assert!(node
+                .add_peer(
+                    NewPeerContext::AdnlPacket,
+                    &local_id,
+                    &peer_id,
+                    addr,
+                    peer_id_full
+                )
+                .unwrap());
+        };
+
+        std::thread::scope(|scope| {
+            // Hook of the first peer waits for the hook of the second one
+            let blocked = scope.spawn(|| add_peer(&node, first));
+            entered_rx.recv_timeout(Duration::from_secs(10)).unwrap();
+            add_peer(&node, second);
+            blocked.join().unwrap();
+        });
+
+        // Events caused by hooks are delivered after them
+        let mut events = std::mem::take(&mut *hooks.recorder.events.lock());
+        events.sort_by_key(|(peer_id, _)| *peer_id != first.0);
+        assert_eq!(
+            events,
+            [
+                (first.0, PeerEvent::Added),
+                (first.0, PeerEvent::Lost(PeerLostReason::Removed)),
+                (second.0, PeerEvent::Added),
+                (second.0, PeerEvent::Lost(PeerLostReason::Removed)),
+            ]
+        );
+        assert_eq!(node.peer_events.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn reserved_public_addresses() {
+        let node = make_adnl_node(NodeOptions {
+            reject_reserved_public_addresses: true,
+            ..Default::default()
+        });
+        let local_id = *node.key_by_tag(0).unwrap().id();
+        let add_peer = |ctx: NewPeerContext, ip: [u8; 4]| {
+            let peer_key = everscale_crypto::ed25519::SecretKey::generate(&mut rand::thread_rng());
+            let (peer_id_full, peer_id) = crate::adnl::ComputeNodeIds::compute_node_ids(&peer_key);
+            let addr = SocketAddrV4::new(Ipv4Addr::from(ip), 30303);
+            node.add_peer(ctx, &local_id, &peer_id, addr, peer_id_full)
+                .unwrap()
+        };
+
+        assert!(add_peer(NewPeerContext::PublicOverlay, [1, 2, 3, 4]));
+        assert!(!add_peer(NewPeerContext::PublicOverlay, [10, 0, 0, 1]));
+        assert!(!add_peer(NewPeerContext::PublicOverlay, [127, 0, 0, 1]));
+        assert_eq!(node.metrics().reserved_addresses_rejected, 2);
+
+        // Explicitly added peers are not checked
+        assert!(add_peer(NewPeerContext::AdnlPacket, [10, 0, 0, 1]));
+
+        // DHT peers are public too
+        assert!(!add_peer(NewPeerContext::Dht, [10, 0, 0, 1]));
+        assert_eq!(node.metrics().reserved_addresses_rejected, 3);
+
+        // Public peer can't re-announce a private address in another context
+        // (e.g. with the address list of a handshake packet)
+        let peer_key = everscale_crypto::ed25519::SecretKey::generate(&mut rand::thread_rng());
+        let (peer_id_full, peer_id) = crate::adnl::ComputeNodeIds::compute_node_ids(&peer_key);
+        let public_addr = SocketAddrV4::new(Ipv4Addr::new(1, 2, 3, 5), 30303);
+        let private_addr = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 3), 30303);
+        assert!(node
+            .add_peer(
+                NewPeerContext::PublicOverlay,
+                &local_id,
+                &peer_id,
+                public_addr,
+                peer_id_full
+            )
+            .unwrap());
+        assert!(!node
+            .add_peer(
+                NewPeerContext::AdnlPacket,
+                &local_id,
+                &peer_id,
+                private_addr,
+                peer_id_full
+            )
+            .unwrap());
+        assert_eq!(
+            node.get_peer_address(&local_id, &peer_id),
+            Some(public_addr)
+        );
+        assert_eq!(node.metrics().reserved_addresses_rejected, 4);
+
+        node.set_allowed_reserved_networks(vec!["10.0.0.0/8".parse().unwrap()]);
+        assert!(add_peer(NewPeerContext::PublicOverlay, [10, 0, 0, 2]));
+        assert!(!add_peer(NewPeerContext::PublicOverlay, [192, 168, 0, 1]));
+        assert_eq!(node.metrics().reserved_addresses_rejected, 5);
+        assert_eq!(node.metrics().peers_rejected, 0);
+    }
+
+    #[tokio::test]
+    async fn peers_eviction() {
+        const MAX_PEERS: usize = 50;
+
+        let clock = ManualClock::default();
+        let node = make_adnl_node_with_clock(
+            NodeOptions {
+                max_peers_per_context: Some(MAX_PEERS),
+                ..Default::default()
+            },
+            Arc::new(clock.clone()),
+        );
+        let active = make_adnl_node_with_clock(Default::default(), Arc::new(clock.clone()));
+        node.add_echo_subscriber().unwrap();
+        let recorder = Arc::new(PeerEventsRecorder::default());
+        node.add_message_subscriber(recorder.clone()).unwrap();
+        node.start().unwrap();
+        active.start().unwrap();
+
+        let local_id = *node.key_by_tag(0).unwrap().id();
+        let active_key = active.key_by_tag(0).unwrap().clone();
+        let add_fake_peer = |ctx: NewPeerContext, port: u16| {
+            let peer_key = everscale_crypto::ed25519::SecretKey::generate(&mut rand::thread_rng());
+            let (peer_id_full, peer_id) = crate::adnl::ComputeNodeIds::compute_node_ids(&peer_key);
+            let addr = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), port);
+            let outcome = node
+                .add_peer_with_outcome(ctx, &local_id, &peer_id, addr, peer_id_full)
+                .unwrap();
+            (peer_id, outcome)
+        };
+
+        // Pinned peers and peers of other contexts are not counted
+        let pinned = (0..2)
+            .map(|port| {
+                let (peer_id, _) = add_fake_peer(NewPeerContext::PublicOverlay, port);
+                assert!(node.pin_peer(&peer_id));
+                peer_id
+            })
+            .collect::<Vec<_>>();
+        let (dht_peer, _) = add_fake_peer(NewPeerContext::Dht, 2);
+
+        node.add_peer(
+            NewPeerContext::PublicOverlay,
+            &local_id,
+            active_key.id(),
+            active.socket_addr(),
+            *active_key.full_id(),
+        )
+        .unwrap();
+        active
+            .add_peer(
+                NewPeerContext::AdnlPacket,
+                active_key.id(),
+                &local_id,
+                node.socket_addr(),
+                *node.key_by_tag(0).unwrap().full_id(),
+            )
+            .unwrap();
+
+        let mut fake_peers = Vec::new();
+        let mut evicted = Vec::new();
+        for i in 0..MAX_PEERS + 100 {
+            clock.advance(Duration::from_millis(1));
+            let (peer_id, outcome) = add_fake_peer(NewPeerContext::PublicOverlay, 100 + i as u16);
+            match outcome {
+                AddPeerOutcome::Added {
+                    evicted: Some(peer_id),
+                } => evicted.push(peer_id),
+                AddPeerOutcome::Added { evicted: None } => {}
+                outcome => panic!("unexpected outcome: {outcome:?}"),
+            }
+            fake_peers.push(peer_id);
+
+            // Incoming packets keep the peer from being evicted
+            if i % 10 == 0 {
+                active
+                    .ping_peer(active_key.id(), &local_id, 1, Some(1000))
+                    .await
+                    .unwrap()
+                    .unwrap();
+            }
+        }
+
+        // The oldest fake peers were evicted in the order of addition
+        let survivors = MAX_PEERS - 1;
+        assert_eq!(evicted, fake_peers[..fake_peers.len() - survivors]);
+        for peer_id in evicted.iter().take(10) {
+            assert!(node.get_peer_address(&local_id, peer_id).is_none());
+        }
+        for peer_id in pinned
+            .iter()
+            .chain([&dht_peer, active_key.id()])
+            .chain(&fake_peers[fake_peers.len() - survivors..])
+        {
+            assert!(node.get_peer_address(&local_id, peer_id).is_some());
+        }
+
+        let metrics = node.metrics();
+        assert_eq!(metrics.peer_count, MAX_PEERS + pinned.len() + 1);
+        assert_eq!(metrics.peers_evicted, evicted.len() as u64);
+        let evicted_events = recorder
+            .events
+            .lock()
+            .iter()
+            .filter(|(_, event)| *event == PeerEvent::Lost(PeerLostReason::Evicted))
+            .count();
+        assert_eq!(evicted_events, evicted.len());
+    }
+
+    #[tokio::test]
+    async fn network_events() {
+        let left = make_adnl_node(NodeOptions {
+            event_queue_capacity: 2,
+            ..Default::default()
+        });
+        let right = make_adnl_node(Default::default());
+        right.add_echo_subscriber().unwrap();
+
+        left.start().unwrap();
+        right.start().unwrap();
+
+        let left_id = *left.key_by_tag(0).unwrap().id();
+        let right_key = right.key_by_tag(0).unwrap().clone();
+        let right_id = *right_key.id();
+        let add_right = || {
+            left.add_peer(
+                NewPeerContext::AdnlPacket,
+                &left_id,
+                &right_id,
+                right.socket_addr(),
+                *right_key.full_id(),
+            )
+            .unwrap()
+        };
+
+        // No events without receivers
+        add_right();
+        assert!(left.remove_peer(&left_id, &right_id).unwrap());
+
+        let mut events = left.events();
+        add_right();
+        left.ping_peer(&left_id, &right_id, 16, Some(1000))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches!(
+            events.try_recv().unwrap(),
+            NetworkEvent::PeerAdded { peer_id, .. } if peer_id == right_id
+        ));
+        assert!(matches!(
+            events.try_recv().unwrap(),
+            NetworkEvent::ChannelEstablished { peer_id, .. } if peer_id == right_id
+        ));
+        assert!(events.try_recv().is_err());
+        assert_eq!(left.metrics().events_dropped, 0);
+
+        // Lagging receiver loses the oldest events
+        for _ in 0..3 {
+            assert!(left.remove_peer(&left_id, &right_id).unwrap());
+            add_right();
+        }
+        assert_eq!(left.metrics().events_dropped, 4);
+        assert!(matches!(
+            events.try_recv(),
+            Err(tokio::sync::broadcast::error::TryRecvError::Lagged(4))
+        ));
+        assert!(matches!(
+            events.try_recv().unwrap(),
+            NetworkEvent::PeerLost {
+                reason: PeerLostReason::Removed,
+                ..
+            }
+        ));
+    }
+
+    #[tokio::test]
+    async fn capabilities_exchange() {
+        let left = make_adnl_node(Default::default());
+        let right = make_adnl_node(Default::default());
+        right.add_echo_subscriber().unwrap();
+
+        left.start().unwrap();
+        right.start().unwrap();
+
+        let left_id = *left.key_by_tag(0).unwrap().id();
+        let right_key = right.key_by_tag(0).unwrap().clone();
+        let right_id = *right_key.id();
+        left.add_peer(
+            NewPeerContext::AdnlPacket,
+            &left_id,
+            &right_id,
+            right.socket_addr(),
+            *right_key.full_id(),
+        )
+        .unwrap();
+
+        let send_capabilities = |features| {
+            let data = tl_proto::serialize(proto::adnl::Capabilities {
+                version: 100,
+                features,
+            });
+            left.send_custom_message(&left_id, &right_id, &data)
+                .unwrap();
+        };
+
+        // Ignored without established channel
+        send_capabilities(u64::MAX);
+        left.ping_peer(&left_id, &right_id, 16, Some(1000))
+            .await
+            .unwrap()
+            .unwrap();
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while left.peer_capabilities(&left_id, &right_id).version == 0
+                || right.peer_capabilities(&right_id, &left_id).version == 0
+            {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        let expected = PeerCapabilities {
+            version: CAPABILITIES_VERSION,
+            features: 0,
+        };
+        assert_eq!(left.peer_capabilities(&left_id, &right_id), expected);
+        assert_eq!(right.peer_capabilities(&right_id, &left_id), expected);
+
+        // Too frequent updates are ignored
+        send_capabilities(u64::MAX);
+        left.ping_peer(&left_id, &right_id, 16, Some(1000))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(right.peer_capabilities(&right_id, &left_id), expected);
+
+        // Unknown peers have default capabilities
+        assert_eq!(
+            left.peer_capabilities(&left_id, &left_id),
+            PeerCapabilities::default()
+        );
+    }
+
+    #[tokio::test]
+    async fn dropped_packets_metrics() {
+        let node = make_adnl_node(Default::default());
+        node.start().unwrap();
+        let local_id = *node.key_by_tag(0).unwrap().id();
+
+        let socket = tokio::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+            .await
+            .unwrap();
+        let send = |data: Vec<u8>| {
+            let socket = &socket;
+            let addr = node.socket_addr();
+            async move { socket.send_to(&data, addr).await.unwrap() }
+        };
+
+        // Too short
+        send(vec![1; 10]).await;
+        // Unknown key or channel id
+        send(vec![1; 200]).await;
+        // Handshake packet with garbage data
+        let mut packet = local_id.as_slice().to_vec();
+        packet.extend_from_slice(Key::from_bytes([1; 32]).full_id().public_key().as_bytes());
+        packet.extend_from_slice(&[1; 100]);
+        send(packet).await;
+
+        let mut metrics = node.metrics().packets_dropped;
+        for _ in 0..100 {
+            if metrics.bad_length + metrics.unknown_channel + metrics.checksum_mismatch >= 3 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            metrics = node.metrics().packets_dropped;
+        }
+
+        assert_eq!(metrics.bad_length, 1);
+        assert_eq!(metrics.unknown_channel, 1);
+        assert_eq!(metrics.checksum_mismatch, 1);
+        assert_eq!(metrics.parse_error, 0);
+    }
+
+    #[tokio::test]
+    async fn peer_filter_both_directions() {
+        let network = VirtualNetwork::new(0);
+        let make_node = |filter: Option<Arc<dyn PeerFilter>>| {
+            let node = network.add_node(make_keystore(), Default::default(), filter);
+            node.add_echo_subscriber().unwrap();
+            node
+        };
+        let add_peer = |node: &Node, peer: &Node| {
+            let local_id = node.key_by_tag(0).unwrap().id();
+            let peer_key = peer.key_by_tag(0).unwrap();
+            node.add_peer(
+                NewPeerContext::AdnlPacket,
+                local_id,
+                peer_key.id(),
+                peer.socket_addr(),
+                *peer_key.full_id(),
+            )
+            .unwrap()
+        };
+        let ping = |node: &Arc<Node>, peer: &Node| {
+            let local_id = *node.key_by_tag(0).unwrap().id();
+            let peer_id = *peer.key_by_tag(0).unwrap().id();
+            let node = node.clone();
+            async move {
+                node.ping_peer(&local_id, &peer_id, 1, Some(200))
+                    .await
+                    .unwrap()
+                    .is_some()
+            }
+        };
+
+        let allowed = make_node(None);
+        let denied_addr = make_node(None);
+        let denied_id = make_node(None);
+
+        let filter = CidrAndIdListFilter {
+            denied_networks: vec![Ipv4Cidr::new(*denied_addr.socket_addr().ip(), 32).unwrap()],
+            denied_peers: [*denied_id.key_by_tag(0).unwrap().id()]
+                .into_iter()
+                .collect(),
+            ..Default::default()
+        };
+        let node = make_node(Some(Arc::new(filter)));
+        for node in [&node, &allowed, &denied_addr, &denied_id] {
+            node.start().unwrap();
+        }
+
+        // Outgoing
+        assert!(add_peer(&node, &allowed));
+        assert!(!add_peer(&node, &denied_addr));
+        assert!(!add_peer(&node, &denied_id));
+        assert_eq!(node.metrics().peers_rejected, 2);
+        assert!(ping(&node, &allowed).await);
+
+        // Incoming
+        for peer in [&allowed, &denied_addr, &denied_id] {
+            assert!(add_peer(peer, &node));
+        }
+        assert!(ping(&allowed, &node).await);
+        assert!(!ping(&denied_addr, &node).await);
+        assert!(!ping(&denied_id, &node).await);
+
+        let metrics = node.metrics();
+        assert_eq!(metrics.packets_dropped.denied_address, 1);
+        assert_eq!(metrics.packets_dropped.denied_peer, 1);
+        assert_eq!(metrics.peer_count, 1);
+    }
+
+    #[tokio::test]
+    async fn send_queue_drop_policies() {
+        const CAPACITY: usize = 8;
+        const MESSAGE_COUNT: u32 = 64;
+        const TAG: u32 = 0xc0ffee00;
+
+        #[derive(Default)]
+        struct Recorder(Mutex<Vec<u32>>);
+
+        #[async_trait::async_trait]
+        impl MessageSubscriber for Recorder {
+            async fn try_consume_custom<'a>(
+                &self,
+                _: SubscriberContext<'a>,
+                constructor: u32,
+                data: &'a [u8],
+            ) -> Result<bool> {
+                if constructor != TAG {
+                    return Ok(false);
+                }
+                self.0.lock().push(u32::read_from(data, &mut 4)?);
+                Ok(true)
+            }
+        }
+
+        async fn run(policy: SendQueuePolicy) -> (Vec<u32>, bool) {
+            let clock = ManualClock::default();
+            let network = VirtualNetwork::with_clock(0, Arc::new(clock.clone()));
+            let options = NodeOptions {
+                peer_send_queue_capacity: CAPACITY,
+                peer_send_queue_policy: policy,
+                ..Default::default()
+            };
+            let sender = add_virtual_node(&network, options);
+            let receiver = add_virtual_node(&network, Default::default());
+            let recorder = Arc::new(Recorder::default());
+            receiver.add_message_subscriber(recorder.clone()).unwrap();
+            receiver.add_echo_subscriber().unwrap();
+            sender.start().unwrap();
+            receiver.start().unwrap();
+
+            // Each packet takes 2 ms to leave the sender
+            network.set_uplink_delay(sender.socket_addr(), Duration::from_millis(2));
+
+            let local_id = *sender.key_by_tag(0).unwrap().id();
+            let peer_key = receiver.key_by_tag(0).unwrap();
+            sender
+                .add_peer(
+                    NewPeerContext::AdnlPacket,
+                    &local_id,
+                    peer_key.id(),
+                    receiver.socket_addr(),
+                    *peer_key.full_id(),
+                )
+                .unwrap();
+
+            for i in 0..MESSAGE_COUNT {
+                let data = tl_proto::serialize((TAG, i));
+                sender
+                    .send_custom_message(&local_id, peer_key.id(), &data)
+                    .unwrap();
+
+                let metrics = sender.peer_metrics(&local_id, peer_key.id()).unwrap();
+                assert!(metrics.send_queue_len <= CAPACITY);
+            }
+
+            let metrics = sender.peer_metrics(&local_id, peer_key.id()).unwrap();
+            assert_eq!(metrics.send_queue_len, CAPACITY);
+            assert_eq!(
+                metrics.send_queue_dropped,
+                (MESSAGE_COUNT as usize - CAPACITY) as u64
+            );
+            assert_eq!(
+                sender.metrics().packets_send_dropped,
+                metrics.send_queue_dropped
+            );
+
+            // Query is sent while the queue is still full,
+            // then the uplink is driven by the packet delay
+            let drive_uplink = async {
+                loop {
+                    clock.advance(Duration::from_millis(1));
+                    for _ in 0..32 {
+                        tokio::task::yield_now().await;
+                    }
+                }
+            };
+            let answered = tokio::select! {
+                biased;
+                answer = sender.ping_peer(&local_id, peer_key.id(), 1, Some(300)) => {
+                    answer.unwrap().is_some()
+                }
+                _ = drive_uplink => unreachable!(),
+            };
+
+            let received = std::mem::take(&mut *recorder.0.lock());
+            (received, answered)
+        }
+
+        let (received, answered) = run(SendQueuePolicy::DropNewest).await;
+        assert_eq!(received, (0..CAPACITY as u32).collect::<Vec<_>>());
+        assert!(!answered);
+
+        // The query displaces one more message
+        let (received, answered) = run(SendQueuePolicy::DropOldest).await;
+        assert_eq!(
+            received,
+            (MESSAGE_COUNT - CAPACITY as u32 + 1..MESSAGE_COUNT).collect::<Vec<_>>()
+        );
+        assert!(answered);
+
+        let (received, answered) = run(SendQueuePolicy::BlockQueries).await;
+        assert_eq!(received, (0..CAPACITY as u32).collect::<Vec<_>>());
+        assert!(answered);
+    }
+
+    #[tokio::test]
+    async fn strict_packet_parsing() {
+        use tl_proto::TlPacket;
+
+        // Packet contents with `count` nop messages and some trailing data
+        fn make_contents(count: u32, trailing: &[u8]) -> Vec<u8> {
+            let mut packet = Vec::new();
+            packet.write_u32(tl_proto::id!("adnl.packetContents", scheme = "scheme.tl"));
+            packet.write_u32(0); // rand1
+            packet.write_u32(0b1 << 3);
+            packet.write_u32(count);
+            for _ in 0..count {
+                proto::adnl::Message::Nop.write_to(&mut packet);
+            }
+            packet.write_u32(0); // rand2
+            packet.extend_from_slice(trailing);
+            packet
+        }
+
+        let socket = tokio::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+            .await
+            .unwrap();
+
+        for reject_trailing_data in [false, true] {
+            let node = make_adnl_node(NodeOptions {
+                max_messages_per_packet: 4,
+                reject_trailing_data,
+                ..Default::default()
+            });
+            node.start().unwrap();
+            let full_id = *node.key_by_tag(0).unwrap().full_id();
+
+            for contents in [make_contents(1, &[0xff; 8]), make_contents(5, &[])] {
+                let packet = crate::adnl::build_handshake_packet(&full_id, &contents, None);
+                socket.send_to(&packet, node.socket_addr()).await.unwrap();
+            }
+
+            let expected = 1 + reject_trailing_data as u64;
+            let mut metrics = node.metrics().packets_dropped;
+            for _ in 0..100 {
+                if metrics.parse_error >= expected {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                metrics = node.metrics().packets_dropped;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            assert_eq!(node.metrics().packets_dropped.parse_error, expected);
+            node.shutdown().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn handshake_replays_are_dropped() {
+        const TAG: u32 = 0xc0ffee01;
+
+        #[derive(Default)]
+        struct Counter(AtomicU64);
+
+        #[async_trait::async_trait]
+        impl MessageSubscriber for Counter {
+            async fn try_consume_custom<'a>(
+                &self,
+                _: SubscriberContext<'a>,
+                constructor: u32,
+                _: &'a [u8],
+            ) -> Result<bool> {
+                self.0.fetch_add(1, Ordering::Relaxed);
+                Ok(constructor == TAG)
+            }
+        }
+
+        let node = make_adnl_node(NodeOptions {
+            packet_signature_required: false,
+            ..Default::default()
+        });
+        let counter = Arc::new(Counter::default());
+        node.add_message_subscriber(counter.clone()).unwrap();
+        node.start().unwrap();
+
+        let socket = tokio::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+            .await
+            .unwrap();
+        let socket_addr = match socket.local_addr().unwrap() {
+            std::net::SocketAddr::V4(addr) => addr,
+            _ => unreachable!(),
+        };
+
+        // Captured handshake packet with a custom message
+        let peer_key = everscale_crypto::ed25519::SecretKey::generate(&mut rand::thread_rng());
+        let (peer_id_full, _) = crate::adnl::ComputeNodeIds::compute_node_ids(&peer_key);
+        let message = tl_proto::serialize(proto::adnl::Message::Custom {
+            data: &TAG.to_le_bytes(),
+        });
+        let now = node.clock().now();
+        let make_contents = |seqno| {
+            tl_proto::serialize(proto::adnl::OutgoingPacketContents {
+                rand1: &[0; 7],
+                from: Some(peer_id_full.as_tl()),
+                messages: proto::adnl::OutgoingMessages::Single(&message),
+                address: AddressListBuilder::new()
+                    .with_address(socket_addr)
+                    .build_with_defaults(now, now, 0),
+                seqno,
+                confirm_seqno: 0,
+                reinit_dates: None,
+                signature: None,
+                rand2: &[0; 3],
+            })
+        };
+        let full_id = *node.key_by_tag(0).unwrap().full_id();
+        let packet = crate::adnl::build_handshake_packet(&full_id, &make_contents(1), None);
+
+        async fn wait_until(f: impl Fn() -> bool) {
+            for _ in 0..100 {
+                if f() {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }
+        let received = || counter.0.load(Ordering::Relaxed);
+
+        socket.send_to(&packet, node.socket_addr()).await.unwrap();
+        wait_until(|| received() > 0).await;
+        assert_eq!(received(), 1);
+
+        // Exact copy is dropped before decryption
+        socket.send_to(&packet, node.socket_addr()).await.unwrap();
+        wait_until(|| node.metrics().packets_dropped.replayed > 0).await;
+        assert_eq!(node.metrics().packets_dropped.replayed, 1);
+        assert_eq!(received(), 1);
+
+        // Re-encrypted copy has the same checksum
+        let packet = crate::adnl::build_handshake_packet(&full_id, &make_contents(1), None);
+        socket.send_to(&packet, node.socket_addr()).await.unwrap();
+        wait_until(|| node.metrics().packets_dropped.replayed > 1).await;
+        assert_eq!(node.metrics().packets_dropped.replayed, 2);
+
+        // Next packet is accepted
+        let packet = crate::adnl::build_handshake_packet(&full_id, &make_contents(2), None);
+        socket.send_to(&packet, node.socket_addr()).await.unwrap();
+        wait_until(|| received() > 1).await;
+        assert_eq!(received(), 2);
+        node.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn channelless_answers_size() {
+        const TAG: u32 = 0xc0ffee00;
+
+        /// Answers with the data of the requested size
+        struct SizedAnswer;
+
+        #[async_trait::async_trait]
+        impl QuerySubscriber for SizedAnswer {
+            async fn try_consume_query<'a>(
+                &self,
+                _: SubscriberContext<'a>,
+                constructor: u32,
+                query: std::borrow::Cow<'a, [u8]>,
+            ) -> Result<QueryConsumingResult<'a>> {
+                if constructor != TAG {
+                    return Ok(QueryConsumingResult::Rejected(query));
+                }
+                let size = u32::read_from(&query, &mut 4)?;
+                Ok(QueryConsumingResult::Consumed(Some(vec![0; size as usize])))
+            }
+        }
+
+        let network = VirtualNetwork::new(0);
+        let options = NodeOptions {
+            allow_channelless_queries: true,
+            ..Default::default()
+        };
+        let client = add_virtual_node(&network, options);
+        let server = add_virtual_node(&network, options);
+        server.add_query_subscriber(Arc::new(SizedAnswer)).unwrap();
+        client.start().unwrap();
+        server.start().unwrap();
+
+        let local_id = *client.key_by_tag(0).unwrap().id();
+        let server_key = server.key_by_tag(0).unwrap();
+        client
+            .add_peer(
+                NewPeerContext::AdnlPacket,
+                &local_id,
+                server_key.id(),
+                server.socket_addr(),
+                *server_key.full_id(),
+            )
+            .unwrap();
+
+        let query = |size: u32| {
+            let client = client.clone();
+            let peer_id = *server_key.id();
+            async move {
+                let query = tl_proto::serialize((TAG, size));
+                client
+                    .query_raw(&local_id, &peer_id, &query, Some(1000))
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .len()
+            }
+        };
+
+        // Small answer is sent back without a channel
+        assert_eq!(query(64).await, 64);
+        assert_eq!(server.metrics().channels_by_peers_len, 0);
+
+        // Large answer is not sent to the unauthenticated source
+        assert_eq!(query(8192).await, 8192);
+        assert_eq!(server.metrics().channels_by_peers_len, 1);
+    }
+
+    #[tokio::test]
+    async fn query_over_injected_socket() {
+        let make_node = |socket: std::net::UdpSocket| {
+            Node::with_udp_socket(socket, None, make_keystore(), Default::default(), None)
+        };
+
+        // Blocking sockets are rejected
+        let socket = std::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        assert!(make_node(socket).is_err());
+
+        // Unspecified address is never advertised
+        let bind_unspecified = || {
+            let socket = std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).unwrap();
+            socket.set_nonblocking(true).unwrap();
+            socket
+        };
+        assert!(make_node(bind_unspecified()).is_err());
+        let advertised = |addr: SocketAddrV4| {
+            Node::with_udp_socket(
+                bind_unspecified(),
+                Some(addr),
+                make_keystore(),
+                Default::default(),
+                None,
+            )
+        };
+        assert!(advertised(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)).is_err());
+        let node = advertised(SocketAddrV4::new(Ipv4Addr::new(1, 2, 3, 4), 0)).unwrap();
+        assert_eq!(*node.socket_addr().ip(), Ipv4Addr::new(1, 2, 3, 4));
+        assert_ne!(node.socket_addr().port(), 0);
+
+        let mut nodes = Vec::new();
+        for _ in 0..2 {
+            let socket = std::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+            socket.set_nonblocking(true).unwrap();
+            let local_addr = socket.local_addr().unwrap();
+
+            let node = make_node(socket).unwrap();
+            assert_eq!(std::net::SocketAddr::V4(node.socket_addr()), local_addr);
+            node.add_echo_subscriber().unwrap();
+            node.start().unwrap();
+            nodes.push(node);
+        }
+
+        let (left, right) = (&nodes[0], &nodes[1]);
+        let left_id = *left.key_by_tag(0).unwrap().id();
+        let right_key = right.key_by_tag(0).unwrap();
+        left.add_peer(
+            NewPeerContext::AdnlPacket,
+            &left_id,
+            right_key.id(),
+            right.socket_addr(),
+            *right_key.full_id(),
+        )
+        .unwrap();
+
+        let pong = left
+            .query::<_, proto::adnl::Pong>(
+                &left_id,
+                right_key.id(),
+                proto::rpc::AdnlPing { value: 123 },
+                None,
+            )
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(pong.value, 123);
+    }
+
+    #[tokio::test]
+    async fn query_timeout_with_manual_clock() {
+        let clock = ManualClock::default();
+        // Channel setup timeout is checked separately
+        let options = NodeOptions {
+            channel_setup_retry_base_ms: 0,
+            ..Default::default()
+        };
+        let node = make_adnl_node_with_clock(options, Arc::new(clock.clone()));
+        node.start().unwrap();
+        let local_id = *node.key_by_tag(0).unwrap().id();
+
+        // Peer which never answers
+        let peer_key = everscale_crypto::ed25519::SecretKey::generate(&mut rand::thread_rng());
+        let (peer_id_full, peer_id) = crate::adnl::ComputeNodeIds::compute_node_ids(&peer_key);
+        node.add_peer(
+            NewPeerContext::AdnlPacket,
+            &local_id,
+            &peer_id,
+            SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1),
+            peer_id_full,
+        )
+        .unwrap();
+
+        let query = tokio::spawn({
+            let node = node.clone();
+            async move {
+                node.query::<_, proto::adnl::Pong>(
+                    &local_id,
+                    &peer_id,
+                    proto::rpc::AdnlPing { value: 0 },
+                    Some(60_000),
+                )
+                .await
+            }
+        });
+
+        // Real time is much less than the query timeout
+        let advanced = tokio::time::timeout(Duration::from_secs(5), async {
+            let mut advanced = Duration::ZERO;
+            while !query.is_finished() {
+                clock.advance(Duration::from_secs(1));
+                advanced += Duration::from_secs(1);
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+            advanced
+        })
+        .await
+        .unwrap();
+
+        assert!(advanced >= Duration::from_secs(60));
+        assert!(query.await.unwrap().unwrap().is_none());
+
+        let traffic = node.metrics().traffic;
+        assert_eq!(traffic.queries_sent, 1);
+        assert_eq!(traffic.queries_answered, 0);
+        assert_eq!(traffic.queries_timed_out, 1);
+        assert!(traffic.packets_sent > 0 && traffic.bytes_sent > 0);
+    }
+
+    #[tokio::test]
+    async fn rates_of_known_traffic() {
+        let clock = ManualClock::default();
+        let left = make_adnl_node_with_clock(
+            NodeOptions {
+                rates_sample_interval_sec: Some(1),
+                ..Default::default()
+            },
+            Arc::new(clock.clone()),
+        );
+        let right = make_adnl_node(Default::default());
+        assert!(right.rates().is_none());
+        left.start().unwrap();
+        right.start().unwrap();
+
+        let left_id = *left.key_by_tag(0).unwrap().id();
+        let right_key = right.key_by_tag(0).unwrap();
+        left.add_peer(
+            NewPeerContext::AdnlPacket,
+            &left_id,
+            right_key.id(),
+            right.socket_addr(),
+            *right_key.full_id(),
+        )
+        .unwrap();
+
+        // 10 messages per second during 30 seconds
+        for _ in 0..30 {
+            for _ in 0..10 {
+                left.send_custom_message(&left_id, right_key.id(), &[0; 4])
+                    .unwrap();
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+            clock.advance(Duration::from_secs(1));
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+
+        let rates = left.rates().unwrap();
+        for rates in [rates.last_1m, rates.last_5m] {
+            assert_eq!(rates.window_sec, 30.0);
+            assert!((rates.packets_sent - 10.0).abs() < 1.0, "{rates:?}");
+            assert!(rates.bytes_sent > rates.packets_sent * 4.0);
+            assert_eq!(rates.rldp_queries, 0.0);
+        }
+
+        // Sampling interval is construction-only
+        assert!(left
+            .update_options(|options| options.rates_sample_interval_sec = None)
+            .is_err());
+        left.shutdown().await;
+        right.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn query_timeout_update_at_runtime() {
+        let node = make_adnl_node(Default::default());
+        node.start().unwrap();
+        let local_id = *node.key_by_tag(0).unwrap().id();
+
+        // Peer which never answers
+        let peer_key = everscale_crypto::ed25519::SecretKey::generate(&mut rand::thread_rng());
+        let (peer_id_full, peer_id) = crate::adnl::ComputeNodeIds::compute_node_ids(&peer_key);
+        node.add_peer(
+            NewPeerContext::AdnlPacket,
+            &local_id,
+            &peer_id,
+            SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1),
+            peer_id_full,
+        )
+        .unwrap();
+        assert_eq!(node.compute_query_timeout(None), 5000);
+
+        node.update_options(|options| {
+            options.query_default_timeout_ms = 100;
+            options.query_min_timeout_ms = 50;
+        })
+        .unwrap();
+        assert_eq!(node.options().query_default_timeout_ms, 100);
+        assert_eq!(node.compute_query_timeout(None), 100);
+
+        // Subsequent queries use the new default timeout
+        let started_at = Instant::now();
+        let answer = node
+            .query::<_, proto::adnl::Pong>(
+                &local_id,
+                &peer_id,
+                proto::rpc::AdnlPing { value: 0 },
+                None,
+            )
+            .await
+            .unwrap();
+        assert!(answer.is_none());
+        assert!(started_at.elapsed() < Duration::from_secs(2));
+
+        // Construction-only options are left unchanged
+        assert!(node
+            .update_options(|options| {
+                options.query_default_timeout_ms = 200;
+                options.packet_history_enabled = true;
+            })
+            .is_err());
+        assert_eq!(node.options().query_default_timeout_ms, 100);
+        assert!(!node.options().packet_history_enabled);
+
+        // Concurrent updates are not lost
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for _ in 0..1000 {
+                        node.update_options(|options| options.query_default_timeout_ms += 1)
+                            .unwrap();
+                    }
+                });
+            }
+        });
+        assert_eq!(node.options().query_default_timeout_ms, 4100);
+    }
+
+    #[tokio::test]
+    async fn answer_from_unexpected_peer() {
+        let nodes = [(); 3].map(|_| make_adnl_node(Default::default()));
+        for node in &nodes {
+            node.start().unwrap();
+        }
+        let [local, attacker, remote] = &nodes;
+
+        let keys = nodes
+            .each_ref()
+            .map(|node| node.key_by_tag(0).unwrap().clone());
+        let [local_key, attacker_key, remote_key] = &keys;
+
+        for (node, key, peer, peer_key) in [
+            (local, local_key, remote, remote_key),
+            (attacker, attacker_key, local, local_key),
+            (remote, remote_key, local, local_key),
+        ] {
+            node.add_peer(
+                NewPeerContext::AdnlPacket,
+                key.id(),
+                peer_key.id(),
+                peer.socket_addr(),
+                *peer_key.full_id(),
+            )
+            .unwrap();
+        }
+
+        let query_id = QueryId(rand::random());
+        let pending_query = local
+            .queries
+            .add_query(local_key.id(), remote_key.id(), query_id);
+
+        let send_answer = |node: &Node, key: &Key, answer: &[u8]| {
+            node.send_message(
+                key.id(),
+                local_key.id(),
+                proto::adnl::Message::Answer {
+                    query_id: query_id.as_slice(),
+                    answer,
+                },
+                false,
+            )
+            .unwrap();
+        };
+
+        // Answer from the peer to which the query was not sent is ignored
+        send_answer(attacker, attacker_key, b"spoofed");
+        for _ in 0..100 {
+            if local.metrics().answers_spoofed > 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(local.metrics().answers_spoofed, 1);
+        assert_eq!(
+            local.reputation().score(attacker_key.id()),
+            InMemoryReputation::MIN_SCORE
+        );
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), pending_query.wait())
+                .await
+                .is_err()
+        );
+
+        // Answer from the expected peer wakes the waiter
+        send_answer(remote, remote_key, b"genuine");
+        let answer = tokio::time::timeout(Duration::from_secs(1), pending_query.wait())
+            .await
+            .unwrap();
+        assert_eq!(answer.as_deref(), Some(b"genuine".as_slice()));
+        assert_eq!(local.metrics().answers_spoofed, 1);
+    }
+
+    #[tokio::test]
+    async fn warm_up_channels() {
+        const PEERS: usize = 50;
+
+        // Concurrent handshakes are slow in debug builds, retransmissions
+        // would only add more work (see `channel_setup_over_lossy_link`)
+        let options = NodeOptions {
+            channel_setup_retry_base_ms: 0,
+            ..Default::default()
+        };
+        let network = VirtualNetwork::new(0);
+        let make_node = || {
+            let node = add_virtual_node(&network, options);
+            node.add_echo_subscriber().unwrap();
+            node.start().unwrap();
+            node
+        };
+
+        let node = make_node();
+        let local_id = *node.key_by_tag(0).unwrap().id();
+
+        let mut peer_ids = Vec::with_capacity(PEERS);
+        for _ in 0..PEERS {
+            let peer = make_node();
+            let peer_key = peer.key_by_tag(0).unwrap();
+            node.add_peer(
+                NewPeerContext::AdnlPacket,
+                &local_id,
+                peer_key.id(),
+                peer.socket_addr(),
+                *peer_key.full_id(),
+            )
+            .unwrap();
+            peer_ids.push(*peer_key.id());
+        }
+
+        // Concurrent calls for the same peer wait for the same channel
+        let channels = futures_util::future::join_all(
+            peer_ids
+                .iter()
+                .chain(&peer_ids[..5])
+                .map(|peer_id| node.ensure_channel(&local_id, peer_id, Some(10000))),
+        )
+        .await;
+        for channel in channels {
+            assert_eq!(channel.unwrap().local_id, local_id);
+        }
+
+        // Ready channels are returned immediately
+        let handshake_packets_sent = node.metrics().handshake_packets_sent;
+        let channel = node
+            .ensure_channel(&local_id, &peer_ids[0], Some(1000))
+            .await
+            .unwrap();
+        assert_eq!(channel.peer_id, peer_ids[0]);
+        assert!(!channel.created);
+
+        for peer_id in &peer_ids {
+            let stats = node.ping_peer(&local_id, peer_id, 1, Some(1000)).await;
+            assert!(stats.unwrap().is_some());
+        }
+        assert_eq!(
+            node.metrics().handshake_packets_sent,
+            handshake_packets_sent
+        );
+
+        // Unknown peers are not warmed up
+        let err = node
+            .ensure_channel(&local_id, &NodeIdShort::random(), Some(100))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(EnsureChannelError::NotSent(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn concurrent_channel_setups_are_shared() {
+        let network = VirtualNetwork::new(0);
+        let node = add_virtual_node(&network, Default::default());
+        let peer = add_virtual_node(&network, Default::default());
+        node.start().unwrap();
+        peer.start().unwrap();
+
+        let local_id = *node.key_by_tag(0).unwrap().id();
+        let peer_key = peer.key_by_tag(0).unwrap();
+        node.add_peer(
+            NewPeerContext::AdnlPacket,
+            &local_id,
+            peer_key.id(),
+            peer.socket_addr(),
+            *peer_key.full_id(),
+        )
+        .unwrap();
+
+        let channels = futures_util::future::join_all(
+            (0..5).map(|_| node.ensure_channel(&local_id, peer_key.id(), Some(1000))),
+        )
+        .await;
+        for channel in channels {
+            assert!(channel.unwrap().created);
+        }
+
+        // Only the first call sent `CreateChannel`
+        assert_eq!(node.metrics().handshake_packets_sent, 1);
+    }
+
+    #[tokio::test]
+    async fn datagram_size_limit() {
+        const LINK_MTU: usize = 700;
+
+        let network = VirtualNetwork::new(0);
+        let make_node = |options: NodeOptions, addresses: usize| {
+            let node = add_virtual_node(&network, options);
+            if addresses > 0 {
+                // The first address is the preferred one
+                let extra =
+                    (1..addresses as u16).map(|i| SocketAddrV4::new(Ipv4Addr::LOCALHOST, i));
+                node.set_address_list(
+                    AddressListBuilder::new()
+                        .with_address(node.socket_addr())
+                        .with_addresses(extra),
+                )
+                .unwrap();
+            }
+            node.add_echo_subscriber().unwrap();
+            node.start().unwrap();
+            node
+        };
+
+        let connect = |left: &Arc<Node>, right: &Arc<Node>| {
+            let right_key = right.key_by_tag(0).unwrap();
+            left.add_peer(
+                NewPeerContext::AdnlPacket,
+                left.key_by_tag(0).unwrap().id(),
+                right_key.id(),
+                right.socket_addr(),
+                *right_key.full_id(),
+            )
+            .unwrap();
+            // Only outgoing packets of the left node are limited
+            network.set_link(
+                left.socket_addr(),
+                right.socket_addr(),
+                LinkOptions {
+                    max_datagram_size: Some(LINK_MTU),
+                    ..Default::default()
+                },
+            );
+            (*left.key_by_tag(0).unwrap().id(), *right_key.id())
+        };
+
+        // Handshake with a long address list and multipart query are trimmed and split
+        let options = NodeOptions {
+            max_datagram_size: LINK_MTU,
+            ..Default::default()
+        };
+        let (left, right) = (make_node(options, 30), make_node(Default::default(), 0));
+        let (local_id, peer_id) = connect(&left, &right);
+        let stats = left.ping_peer(&local_id, &peer_id, 900, Some(1000)).await;
+        assert!(stats.unwrap().unwrap().intact);
+
+        let err = left
+            .send_padded_query(&local_id, &peer_id, &QueryId([0; 32]), &[0; 4], 100)
+            .unwrap_err();
+        assert!(err.to_string().contains("too small"));
+
+        // Default datagram size doesn't fit into the link
+        let options = NodeOptions {
+            query_min_timeout_ms: 50,
+            ..Default::default()
+        };
+        let (left, right) = (make_node(options, 0), make_node(Default::default(), 0));
+        let (local_id, peer_id) = connect(&left, &right);
+        left.ensure_channel(&local_id, &peer_id, Some(1000))
+            .await
+            .unwrap();
+        let stats = left.ping_peer(&local_id, &peer_id, 900, Some(200)).await;
+        assert!(stats.unwrap().is_none());
+
+        // Probed size overrides the global one
+        let size = left
+            .probe_max_datagram_size(&local_id, &peer_id)
+            .await
+            .unwrap();
+        assert_eq!(size, LINK_MTU);
+        let metrics = left.peer_metrics(&local_id, &peer_id).unwrap();
+        assert_eq!(metrics.probed_datagram_size, Some(LINK_MTU));
+        let stats = left.ping_peer(&local_id, &peer_id, 900, Some(1000)).await;
+        assert!(stats.unwrap().unwrap().intact);
+
+        // Optional probe after the channel establishment
+        let options = NodeOptions {
+            query_min_timeout_ms: 50,
+            mtu_probe_enabled: true,
+            ..Default::default()
+        };
+        let (left, right) = (make_node(options, 0), make_node(Default::default(), 0));
+        let (local_id, peer_id) = connect(&left, &right);
+        left.ensure_channel(&local_id, &peer_id, Some(1000))
+            .await
+            .unwrap();
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while left
+                .peer_metrics(&local_id, &peer_id)
+                .unwrap()
+                .probed_datagram_size
+                != Some(LINK_MTU)
+            {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn multipart_messages() {
+        let network = VirtualNetwork::new(0);
+        let make_node = || {
+            let node = add_virtual_node(&network, Default::default());
+            node.add_echo_subscriber().unwrap();
+            node.start().unwrap();
+            node
+        };
+        let (left, right) = (make_node(), make_node());
+        let local_id = *left.key_by_tag(0).unwrap().id();
+        let right_key = right.key_by_tag(0).unwrap();
+        left.add_peer(
+            NewPeerContext::AdnlPacket,
+            &local_id,
+            right_key.id(),
+            right.socket_addr(),
+            *right_key.full_id(),
+        )
+        .unwrap();
+
+        // Both the query and the answer are split into one, two, three and more parts
+        for payload_len in [100, 1500, 2500, 6000] {
+            let stats = left
+                .ping_peer(&local_id, right_key.id(), payload_len, Some(1000))
+                .await;
+            assert!(stats.unwrap().unwrap().intact, "{payload_len}");
+        }
+    }
+
+    /// Echo subscriber which counts processed queries
+    #[derive(Default)]
+    struct CountingEcho {
+        processed: std::sync::atomic::AtomicUsize,
+    }
+
+    #[async_trait::async_trait]
+    impl QuerySubscriber for CountingEcho {
+        async fn try_consume_query<'a>(
+            &self,
+            ctx: SubscriberContext<'a>,
+            constructor: u32,
+            query: std::borrow::Cow<'a, [u8]>,
+        ) -> Result<QueryConsumingResult<'a>> {
+            self.processed.fetch_add(1, Ordering::Relaxed);
+            EchoSubscriber
+                .try_consume_query(ctx, constructor, query)
+                .await
+        }
+    }
+
+    #[tokio::test]
+    async fn query_retransmits_over_lossy_link() {
+        const QUERIES: usize = 50;
+
+        let network = VirtualNetwork::new(1);
+        let make_node = |subscriber: Option<Arc<CountingEcho>>| {
+            let node = add_virtual_node(&network, Default::default());
+            if let Some(subscriber) = subscriber {
+                node.add_query_subscriber(subscriber).unwrap();
+            }
+            node.start().unwrap();
+            node
+        };
+        let counter = Arc::new(CountingEcho::default());
+        let client = make_node(None);
+        let server = make_node(Some(counter.clone()));
+
+        let local_id = *client.key_by_tag(0).unwrap().id();
+        let server_key = server.key_by_tag(0).unwrap();
+        let peer_id = *server_key.id();
+        client
+            .add_peer(
+                NewPeerContext::AdnlPacket,
+                &local_id,
+                &peer_id,
+                server.socket_addr(),
+                *server_key.full_id(),
+            )
+            .unwrap();
+        client
+            .ensure_channel(&local_id, &peer_id, Some(1000))
+            .await
+            .unwrap();
+
+        network.connect(
+            client.socket_addr(),
+            server.socket_addr(),
+            LinkOptions {
+                latency_ms: 5,
+                loss: 0.3,
+                ..Default::default()
+            },
+        );
+
+        let run_queries = |options: QueryOptions| {
+            let client = &client;
+            let queries = (0..QUERIES).map(move |i| {
+                let client = client.clone();
+                let options = options.clone();
+                async move {
+                    let query = proto::rpc::NetworkEcho {
+                        data: (i as u32).to_le_bytes().to_vec(),
+                    };
+                    client
+                        .query_with_options::<_, proto::adnl::EchoAnswer>(
+                            &local_id,
+                            &peer_id,
+                            query,
+                            Some(600),
+                            options,
+                        )
+                        .await
+                        .unwrap()
+                        .map(|answer| answer.data == (i as u32).to_le_bytes())
+                }
+            });
+            async move {
+                let answers = futures_util::future::join_all(queries).await;
+                assert!(answers.iter().flatten().all(|intact| *intact));
+                answers.iter().flatten().count()
+            }
+        };
+
+        let answered_once = run_queries(Default::default()).await;
+        assert_eq!(client.metrics().query_retransmits, 0);
+        assert!(counter.processed.swap(0, Ordering::Relaxed) <= QUERIES);
+
+        let answered_with_retries = run_queries(QueryOptions {
+            retries: 5,
+            retry_interval: Duration::from_millis(50),
+            ..Default::default()
+        })
+        .await;
+        assert!(
+            answered_with_retries > answered_once && answered_with_retries >= QUERIES - 5,
+            "once: {answered_once}, with retries: {answered_with_retries}"
+        );
+
+        let traffic = client.metrics().traffic;
+        assert_eq!(traffic.queries_sent, 2 * QUERIES as u64);
+        assert_eq!(
+            traffic.queries_answered + traffic.queries_timed_out,
+            traffic.queries_sent
+        );
+        assert!(traffic.bytes_received > 0);
+        assert!(server.metrics().traffic.handshake_packets_received > 0);
+        assert_eq!(client.metrics().peers_by_context.adnl_packet, 1);
+
+        // Retransmissions were answered without processing the query again
+        assert!(client.metrics().query_retransmits > 0);
+        assert!(server.metrics().queries_deduplicated > 0);
+        assert!(counter.processed.load(Ordering::Relaxed) <= QUERIES);
+    }
+
+    #[tokio::test]
+    async fn channel_setup_over_lossy_link() {
+        let network = VirtualNetwork::new(3);
+        network.set_default_link(LinkOptions {
+            latency_ms: 5,
+            loss: 0.5,
+            ..Default::default()
+        });
+        let options = NodeOptions {
+            channel_setup_retry_base_ms: 50,
+            channel_setup_retry_max_ms: 200,
+            channel_setup_timeout_ms: 5000,
+            ..Default::default()
+        };
+        let make_node = || {
+            let node = add_virtual_node(&network, options);
+            node.add_query_subscriber(Arc::new(EchoSubscriber)).unwrap();
+            node.start().unwrap();
+            node
+        };
+        let (client, server) = (make_node(), make_node());
+
+        let connect = |left: &Arc<Node>, right: &Arc<Node>| {
+            let local_id = *left.key_by_tag(0).unwrap().id();
+            let peer_key = right.key_by_tag(0).unwrap();
+            left.add_peer(
+                NewPeerContext::AdnlPacket,
+                &local_id,
+                peer_key.id(),
+                right.socket_addr(),
+                *peer_key.full_id(),
+            )
+            .unwrap();
+            (local_id, *peer_key.id())
+        };
+        let (local_id, peer_id) = connect(&client, &server);
+        connect(&server, &client);
+
+        // A single `CreateChannel` is confirmed with 25% chance
+        let channel = client
+            .ensure_channel(&local_id, &peer_id, Some(5000))
+            .await
+            .unwrap();
+        assert!(channel.created);
+        let retransmits = client.metrics().channel_setup_retransmits;
+        assert!((1..=10).contains(&retransmits), "{retransmits}");
+        assert_eq!(client.metrics().channel_setup_timeouts, 0);
+
+        // Duplicate confirmations of the retransmitted messages are ignored
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(client.channel_setups.is_empty());
+        assert_eq!(client.metrics().channel_setup_retransmits, retransmits);
+    }
+
+    #[tokio::test]
+    async fn channel_setup_timeout() {
+        let options = NodeOptions {
+            channel_setup_retry_base_ms: 20,
+            channel_setup_retry_max_ms: 40,
+            channel_setup_timeout_ms: 200,
+            ..Default::default()
+        };
+        let network = VirtualNetwork::new(1);
+        let client = add_virtual_node(&network, options);
+        client.start().unwrap();
+
+        let local_id = *client.key_by_tag(0).unwrap().id();
+        let peer_key = ed25519::SecretKey::generate(&mut rand::thread_rng());
+        let peer_id = NodeIdFull::new(ed25519::PublicKey::from(&peer_key));
+        client
+            .add_peer(
+                NewPeerContext::AdnlPacket,
+                &local_id,
+                &peer_id.compute_short_id(),
+                SocketAddrV4::new(std::net::Ipv4Addr::LOCALHOST, 1),
+                peer_id,
+            )
+            .unwrap();
+
+        let query = proto::rpc::NetworkEcho { data: vec![1] };
+        let error = client
+            .query::<_, proto::adnl::EchoAnswer>(
+                &local_id,
+                &peer_id.compute_short_id(),
+                query,
+                Some(2000),
+            )
+            .await
+            .unwrap_err();
+        let error = error.downcast_ref::<ChannelSetupTimeout>().unwrap();
+        assert!(error.attempts >= 4, "{}", error.attempts);
+
+        let metrics = client.metrics();
+        assert_eq!(metrics.channel_setup_timeouts, 1);
+        assert_eq!(metrics.channel_setup_retransmits, error.attempts as u64 - 1);
+    }
+
+    #[tokio::test]
+    async fn channels_of_local_keys() {
+        let network = VirtualNetwork::new(0);
+        let keystore = Keystore::builder()
+            .with_tagged_keys([(rand::random(), 0), (rand::random(), 1)])
+            .unwrap()
+            .build();
+        let left = network.add_node(keystore, Default::default(), None);
+        left.start().unwrap();
+        let right = add_virtual_node(&network, Default::default());
+        right.add_echo_subscriber().unwrap();
+        right.start().unwrap();
+        let right_key = right.key_by_tag(0).unwrap();
+
+        let local_ids = [0, 1].map(|tag| {
+            let local_id = *left.key_by_tag(tag).unwrap().id();
+            left.add_peer(
+                NewPeerContext::AdnlPacket,
+                &local_id,
+                right_key.id(),
+                right.socket_addr(),
+                *right_key.full_id(),
+            )
+            .unwrap();
+            local_id
+        });
+
+        // Each local key has its own channel to the same peer (e.g. after the key rotation)
+        for local_id in &local_ids {
+            let info = left
+                .ensure_channel(local_id, right_key.id(), Some(1000))
+                .await
+                .unwrap();
+            assert!(info.created);
+        }
+        for local_id in &local_ids {
+            let info = left
+                .ensure_channel(local_id, right_key.id(), Some(1000))
+                .await
+                .unwrap();
+            assert!(!info.created);
+            let stats = left
+                .ping_peer(local_id, right_key.id(), 10, Some(1000))
+                .await;
+            assert!(stats.unwrap().unwrap().intact);
+        }
+        assert_eq!(left.metrics().channels_by_peers_len, 2);
+
+        // Removed peer of one key doesn't affect the channel of the other
+        left.remove_peer(&local_ids[1], right_key.id()).unwrap();
+        assert_eq!(left.metrics().channels_by_peers_len, 1);
+        let info = left
+            .ensure_channel(&local_ids[0], right_key.id(), Some(1000))
+            .await
+            .unwrap();
+        assert!(!info.created);
+    }
+
+    #[test]
+    fn tasks_spawned_on_injected_runtime() {
+        let ambient = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let network = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .enable_all()
+            .build()
+            .unwrap();
+
+        ambient.block_on(async {
+            let make_node = || {
+                Node::with_runtime(
+                    SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0),
+                    make_keystore(),
+                    Default::default(),
+                    None,
+                    network.handle().clone(),
+                )
+                .unwrap()
+            };
+            let (left, right) = (make_node(), make_node());
+            right.add_echo_subscriber().unwrap();
+            left.start().unwrap();
+            right.start().unwrap();
+
+            let left_id = *left.key_by_tag(0).unwrap().id();
+            let right_key = right.key_by_tag(0).unwrap().clone();
+            left.add_peer(
+                NewPeerContext::AdnlPacket,
+                &left_id,
+                right_key.id(),
+                right.socket_addr(),
+                *right_key.full_id(),
+            )
+            .unwrap();
+
+            let stats = left
+                .ping_peer(&left_id, right_key.id(), 16, Some(1000))
+                .await
+                .unwrap()
+                .unwrap();
+            assert!(stats.intact);
+
+            assert!(network.metrics().num_alive_tasks() > 0);
+            assert_eq!(ambient.metrics().num_alive_tasks(), 0);
+
+            left.shutdown().await;
+            right.shutdown().await;
+        });
+    }
+
+    #[tokio::test]
+    async fn ephemeral_answer_keys() {
+        let network = VirtualNetwork::new(0);
+        let make_node = || {
+            let node = add_virtual_node(&network, Default::default());
+            node.add_echo_subscriber().unwrap();
+            node.start().unwrap();
+            node
+        };
+        let (client, server) = (make_node(), make_node());
+
+        let local_id = *client.key_by_tag(0).unwrap().id();
+        let server_key = server.key_by_tag(0).unwrap();
+        client
+            .add_peer(
+                NewPeerContext::AdnlPacket,
+                &local_id,
+                server_key.id(),
+                server.socket_addr(),
+                *server_key.full_id(),
+            )
+            .unwrap();
+
+        for ephemeral_answer_key in [true, false, true] {
+            let data = rand::random::<[u8; 32]>().to_vec();
+            let answer = client
+                .query_with_options::<_, proto::adnl::EchoAnswer>(
+                    &local_id,
+                    server_key.id(),
+                    proto::rpc::NetworkEcho { data: data.clone() },
+                    Some(1000),
+                    QueryOptions {
+                        ephemeral_answer_key,
+                        ..Default::default()
+                    },
+                )
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(answer.data, data);
+        }
+
+        // Answer key can't be used to decrypt the answer for another one
+        let answer_key = Key::from_bytes(rand::random());
+        let query = tl_proto::serialize(proto::rpc::PrivateQueryWithAnswerKey {
+            answer_key: answer_key.public_key().as_bytes(),
+            query: &tl_proto::serialize(proto::rpc::AdnlPing { value: 1 }),
+        });
+        let answer = client
+            .query_raw(&local_id, server_key.id(), &query, Some(1000))
+            .await
+            .unwrap()
+            .unwrap();
+        let other_key = Key::from_bytes(rand::random());
+        assert!(decrypt_answer(&other_key, server_key.public_key(), &answer).is_err());
+        let answer = decrypt_answer(&answer_key, server_key.public_key(), &answer).unwrap();
+        let pong = tl_proto::deserialize::<proto::adnl::Pong>(&answer).unwrap();
+        assert_eq!(pong.value, 1);
+    }
 }
@@ -0,0 +1,144 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use tokio::sync::mpsc;
+
+use super::Node;
+use crate::adnl::node_id::NodeIdShort;
+use crate::adnl::queries_cache::QueryId;
+use crate::proto;
+use crate::util::*;
+
+/// Smallest probed datagram size (min IPv4 reassembly buffer without IP and UDP headers)
+pub const MIN_PROBED_DATAGRAM_SIZE: usize = 548;
+/// Largest probed datagram size (receive buffer size)
+pub const MAX_PROBED_DATAGRAM_SIZE: usize = 2048;
+
+/// Number of lost probes of the same size after which it is considered too large
+const PROBE_ATTEMPTS: usize = 2;
+
+/// Peers with the new channels which must be probed
+pub(super) type MtuProbeTx = mpsc::UnboundedSender<(NodeIdShort, NodeIdShort)>;
+pub(super) type MtuProbeRx = mpsc::UnboundedReceiver<(NodeIdShort, NodeIdShort)>;
+
+impl Node {
+    /// Finds the max size of the datagram which is delivered to the peer.
+    ///
+    /// Establishes the channel if needed and then does a binary search with ping
+    /// queries padded to the probed size. Each probe waits for the answer during
+    /// [`NodeOptions::query_min_timeout_ms`].
+    ///
+    /// The result overrides [`NodeOptions::max_datagram_size`] for this peer
+    /// and is shown in [`PeerMetrics::probed_datagram_size`].
+    ///
+    /// [`NodeOptions::query_min_timeout_ms`]: crate::adnl::NodeOptions::query_min_timeout_ms
+    /// [`NodeOptions::max_datagram_size`]: crate::adnl::NodeOptions::max_datagram_size
+    /// [`PeerMetrics::probed_datagram_size`]: crate::adnl::PeerMetrics::probed_datagram_size
+    pub async fn probe_max_datagram_size(
+        &self,
+        local_id: &NodeIdShort,
+        peer_id: &NodeIdShort,
+    ) -> Result<usize> {
+        self.ensure_channel(local_id, peer_id, None).await?;
+
+        // All packet layouts are aligned to 4 bytes
+        let mut low = MIN_PROBED_DATAGRAM_SIZE / 4;
+        let mut high = MAX_PROBED_DATAGRAM_SIZE / 4;
+        if !self.probe_datagram(local_id, peer_id, low * 4).await? {
+            return Err(MtuProbeError::MinSizeNotDelivered.into());
+        }
+
+        while low < high {
+            let mid = (low + high).div_ceil(2);
+            if self.probe_datagram(local_id, peer_id, mid * 4).await? {
+                low = mid;
+            } else {
+                high = mid - 1;
+            }
+        }
+
+        let size = low * 4;
+        if let Some(peer) = self.get_peers(local_id)?.get(peer_id) {
+            peer.set_probed_datagram_size(size);
+        }
+        Ok(size)
+    }
+
+    /// Returns `true` if the padded ping query of the specified size was answered
+    async fn probe_datagram(
+        &self,
+        local_id: &NodeIdShort,
+        peer_id: &NodeIdShort,
+        size: usize,
+    ) -> Result<bool> {
+        for _ in 0..PROBE_ATTEMPTS {
+            let query_id = QueryId(gen_fast_bytes());
+            let query = tl_proto::serialize(proto::rpc::AdnlPing {
+                value: rand::random(),
+            });
+
+            let pending_query = self.queries.add_query(local_id, peer_id, query_id);
+            self.send_padded_query(local_id, peer_id, &query_id, &query, size)?;
+
+            let timeout = self.options.load().query_min_timeout_ms;
+            let answered = tokio::select! {
+                answer = pending_query.wait() => answer.is_some(),
+                _ = self.clock.sleep(Duration::from_millis(timeout)) => false,
+            };
+            if answered {
+                return Ok(true);
+            }
+        }
+
+        tracing::trace!(%peer_id, size, "MTU probe lost");
+        Ok(false)
+    }
+
+    /// Starts a process that probes peers after the channel establishment
+    /// (see [`NodeOptions::mtu_probe_enabled`])
+    ///
+    /// [`NodeOptions::mtu_probe_enabled`]: crate::adnl::NodeOptions::mtu_probe_enabled
+    pub(super) fn start_mtu_prober(self: &Arc<Self>, mut mtu_probe_rx: MtuProbeRx) {
+        let node = Arc::downgrade(self);
+        let complete_signal = self.tasks.token().clone();
+        let runtime = self.runtime.clone();
+
+        self.tasks.spawn(&self.runtime, "adnl_mtu_prober", async move {
+            while let Some((local_id, peer_id)) = tokio::select! {
+                item = mtu_probe_rx.recv() => item,
+                _ = complete_signal.cancelled() => None,
+            } {
+                let node = match node.upgrade() {
+                    Some(node) => node,
+                    None => break,
+                };
+
+                let complete_signal = complete_signal.clone();
+                let probe_node = node.clone();
+                node.tasks.spawn(&runtime, "adnl_mtu_probe", async move {
+                    let node = probe_node;
+                    tokio::select! {
+                        result = node.probe_max_datagram_size(&local_id, &peer_id) => match result {
+                            Ok(size) => {
+                                tracing::debug!(%local_id, %peer_id, size, "max datagram size probed");
+                            }
+                            Err(e) => {
+                                tracing::debug!(%local_id, %peer_id, "failed to probe max datagram size: {e:?}");
+                            }
+                        },
+                        _ = complete_signal.cancelled() => {}
+                    }
+                });
+            }
+
+            tracing::debug!("MTU prober finished");
+        });
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+enum MtuProbeError {
+    #[error("Datagram of the min size was not delivered")]
+    MinSizeNotDelivered,
+}
@@ -0,0 +1,156 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::adnl::channel::AdnlChannelError;
+use crate::adnl::handshake::HandshakeError;
+
+/// Reason why the incoming packet was dropped before processing its messages
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum PacketDropReason {
+    /// Packet is too short to contain a header
+    BadLength,
+    /// Packet is addressed to an unknown key or channel id.
+    ///
+    /// NOTE: such packets are dropped before any key exchange
+    UnknownChannel,
+    /// Decrypted data doesn't match the checksum (wrong key or corrupted data)
+    ChecksumMismatch,
+    /// Packet header contains invalid key material
+    DecryptError,
+    /// Decrypted data is not a valid ADNL packet (or has too many messages or trailing data)
+    ParseError,
+    /// Packet has an unsupported ADNL version
+    UnsupportedVersion,
+    /// Packet signature is missing (when required), malformed or invalid
+    InvalidSignature,
+    /// Source address was denied by the [`PeerFilter`](crate::adnl::PeerFilter)
+    DeniedAddress,
+    /// Source peer id was denied by the [`PeerFilter`](crate::adnl::PeerFilter)
+    DeniedPeer,
+    /// Exact copy of the recent handshake packet or a channel packet with the seqno
+    /// which was already received
+    Replayed,
+    /// Channel packet with the seqno which is older than the tracked window
+    /// (see [`NodeOptions::seqno_window`](crate::adnl::NodeOptions::seqno_window))
+    SeqnoTooOld,
+}
+
+impl From<&HandshakeError> for PacketDropReason {
+    fn from(error: &HandshakeError) -> Self {
+        match error {
+            HandshakeError::BadHandshakePacketLength => Self::BadLength,
+            HandshakeError::BadHandshakePacketChecksum => Self::ChecksumMismatch,
+            HandshakeError::InvalidPublicKey => Self::DecryptError,
+        }
+    }
+}
+
+impl From<&AdnlChannelError> for PacketDropReason {
+    fn from(error: &AdnlChannelError) -> Self {
+        match error {
+            AdnlChannelError::ChannelMessageIsTooShort(_) => Self::BadLength,
+            AdnlChannelError::InvalidChannelMessageChecksum => Self::ChecksumMismatch,
+        }
+    }
+}
+
+/// Number of dropped incoming packets for each reason
+#[derive(Debug, Default, Copy, Clone, serde::Serialize)]
+pub struct PacketDropMetrics {
+    #[serde(rename = "bad_length_total")]
+    pub bad_length: u64,
+    #[serde(rename = "unknown_channel_total")]
+    pub unknown_channel: u64,
+    #[serde(rename = "checksum_mismatch_total")]
+    pub checksum_mismatch: u64,
+    #[serde(rename = "decrypt_error_total")]
+    pub decrypt_error: u64,
+    #[serde(rename = "parse_error_total")]
+    pub parse_error: u64,
+    #[serde(rename = "unsupported_version_total")]
+    pub unsupported_version: u64,
+    #[serde(rename = "invalid_signature_total")]
+    pub invalid_signature: u64,
+    #[serde(rename = "denied_address_total")]
+    pub denied_address: u64,
+    #[serde(rename = "denied_peer_total")]
+    pub denied_peer: u64,
+    #[serde(rename = "replayed_total")]
+    pub replayed: u64,
+    #[serde(rename = "seqno_too_old_total")]
+    pub seqno_too_old: u64,
+}
+
+#[derive(Default)]
+pub(super) struct PacketDrops {
+    bad_length: AtomicU64,
+    unknown_channel: AtomicU64,
+    checksum_mismatch: AtomicU64,
+    decrypt_error: AtomicU64,
+    parse_error: AtomicU64,
+    unsupported_version: AtomicU64,
+    invalid_signature: AtomicU64,
+    denied_address: AtomicU64,
+    denied_peer: AtomicU64,
+    replayed: AtomicU64,
+    seqno_too_old: AtomicU64,
+}
+
+impl PacketDrops {
+    /// Counts dropped packet
+    pub fn add(&self, reason: PacketDropReason) {
+        let counter = match reason {
+            PacketDropReason::BadLength => &self.bad_length,
+            PacketDropReason::UnknownChannel => &self.unknown_channel,
+            PacketDropReason::ChecksumMismatch => &self.checksum_mismatch,
+            PacketDropReason::DecryptError => &self.decrypt_error,
+            PacketDropReason::ParseError => &self.parse_error,
+            PacketDropReason::UnsupportedVersion => &self.unsupported_version,
+            PacketDropReason::InvalidSignature => &self.invalid_signature,
+            PacketDropReason::DeniedAddress => &self.denied_address,
+            PacketDropReason::DeniedPeer => &self.denied_peer,
+            PacketDropReason::Replayed => &self.replayed,
+            PacketDropReason::SeqnoTooOld => &self.seqno_too_old,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn metrics(&self) -> PacketDropMetrics {
+        PacketDropMetrics {
+            bad_length: self.bad_length.load(Ordering::Relaxed),
+            unknown_channel: self.unknown_channel.load(Ordering::Relaxed),
+            checksum_mismatch: self.checksum_mismatch.load(Ordering::Relaxed),
+            decrypt_error: self.decrypt_error.load(Ordering::Relaxed),
+            parse_error: self.parse_error.load(Ordering::Relaxed),
+            unsupported_version: self.unsupported_version.load(Ordering::Relaxed),
+            invalid_signature: self.invalid_signature.load(Ordering::Relaxed),
+            denied_address: self.denied_address.load(Ordering::Relaxed),
+            denied_peer: self.denied_peer.load(Ordering::Relaxed),
+            replayed: self.replayed.load(Ordering::Relaxed),
+            seqno_too_old: self.seqno_too_old.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counters() {
+        let drops = PacketDrops::default();
+
+        drops.add(PacketDropReason::BadLength);
+        drops.add(PacketDropReason::from(
+            &HandshakeError::BadHandshakePacketChecksum,
+        ));
+        drops.add(PacketDropReason::from(
+            &AdnlChannelError::InvalidChannelMessageChecksum,
+        ));
+
+        let metrics = drops.metrics();
+        assert_eq!(metrics.bad_length, 1);
+        assert_eq!(metrics.checksum_mismatch, 2);
+        assert_eq!(metrics.unknown_channel, 0);
+    }
+}
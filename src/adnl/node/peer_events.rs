@@ -0,0 +1,78 @@
+use std::collections::VecDeque;
+
+use dashmap::mapref::entry::Entry;
+
+use crate::adnl::node_id::NodeIdShort;
+use crate::subscriber::PeerLostReason;
+use crate::util::FastDashMap;
+
+/// Peer lifecycle events which are waiting for the subscriber hooks.
+///
+/// Events of the same peer are delivered one by one in the order in which
+/// they were pushed, events of different peers are delivered independently.
+#[derive(Default)]
+pub(super) struct PeerEvents {
+    queues: FastDashMap<(NodeIdShort, NodeIdShort), PeerEventsQueue>,
+}
+
+impl PeerEvents {
+    /// Remembers the event.
+    ///
+    /// NOTE: must be called while the peer state change is still locked,
+    /// so that the events are ordered the same way as the changes
+    pub fn push(&self, local_id: &NodeIdShort, peer_id: &NodeIdShort, event: PeerEvent) {
+        self.queues
+            .entry((*local_id, *peer_id))
+            .or_default()
+            .events
+            .push_back(event);
+    }
+
+    /// Calls `f` for all remembered events of the peer unless
+    /// they are already delivered by someone else (including `f` itself)
+    pub fn deliver<F>(&self, local_id: &NodeIdShort, peer_id: &NodeIdShort, mut f: F)
+    where
+        F: FnMut(PeerEvent),
+    {
+        let key = (*local_id, *peer_id);
+        match self.queues.get_mut(&key) {
+            Some(mut queue) if !queue.delivering => queue.delivering = true,
+            _ => return,
+        }
+
+        loop {
+            // NOTE: the queue is removed when it becomes empty, which resets the flag
+            let event = match self.queues.entry(key) {
+                Entry::Occupied(mut entry) => match entry.get_mut().events.pop_front() {
+                    Some(event) => event,
+                    None => {
+                        entry.remove();
+                        return;
+                    }
+                },
+                Entry::Vacant(_) => return,
+            };
+            f(event);
+        }
+    }
+
+    /// Number of peers with undelivered events
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.queues.len()
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(super) enum PeerEvent {
+    Added,
+    ChannelEstablished,
+    Lost(PeerLostReason),
+}
+
+#[derive(Default)]
+struct PeerEventsQueue {
+    events: VecDeque<PeerEvent>,
+    /// Whether the hooks are being called for this peer
+    delivering: bool,
+}
@@ -0,0 +1,191 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+use crate::adnl::node_id::NodeIdShort;
+use crate::adnl::peer::{NewPeerContext, Peers};
+use crate::util::FastDashMap;
+
+/// Pin counts of the peers which are never evicted, see [`Node::pin_peer`]
+///
+/// [`Node::pin_peer`]: super::Node::pin_peer
+pub(super) type PinnedPeers = FastDashMap<NodeIdShort, usize>;
+
+/// Least recently active peers of each local key and context,
+/// see [`NodeOptions::max_peers_per_context`].
+///
+/// Peer activity is updated without touching the queue, so the outdated entries
+/// are refreshed lazily when they reach the top.
+///
+/// [`NodeOptions::max_peers_per_context`]: super::NodeOptions::max_peers_per_context
+#[derive(Default)]
+pub(super) struct PeerEviction {
+    queues: FastDashMap<(NodeIdShort, NewPeerContext), EvictionQueue>,
+}
+
+impl PeerEviction {
+    /// Remembers the new peer
+    pub fn insert(
+        &self,
+        local_id: &NodeIdShort,
+        ctx: NewPeerContext,
+        peer_id: &NodeIdShort,
+        last_active_ms: u64,
+        peers: &Peers,
+    ) {
+        let mut queue = self.queues.entry((*local_id, ctx)).or_default();
+        queue.len += 1;
+        queue.heap.push(Reverse((last_active_ms, *peer_id)));
+        queue.compact(ctx, peers);
+    }
+
+    /// Forgets the removed peer. Its queue entry is dropped when it reaches the top
+    pub fn remove(&self, local_id: &NodeIdShort, ctx: NewPeerContext) {
+        if let Some(mut queue) = self.queues.get_mut(&(*local_id, ctx)) {
+            queue.len = queue.len.saturating_sub(1);
+        }
+    }
+
+    /// Returns the least recently active unpinned peer (except the `new_peer_id`)
+    /// if there are more than `max_peers` unpinned peers in the context.
+    ///
+    /// NOTE: the returned peer is no longer in the queue, so it must be removed
+    pub fn least_active(
+        &self,
+        local_id: &NodeIdShort,
+        ctx: NewPeerContext,
+        new_peer_id: &NodeIdShort,
+        max_peers: usize,
+        peers: &Peers,
+        pinned_peers: &PinnedPeers,
+    ) -> Option<NodeIdShort> {
+        let mut queue = self.queues.get_mut(&(*local_id, ctx))?;
+        if queue.len <= max_peers {
+            return None;
+        }
+
+        let pinned_count = pinned_peers
+            .iter()
+            .filter(|item| matches!(peers.get(item.key()), Some(peer) if peer.context() == ctx))
+            .count();
+        if queue.len.saturating_sub(pinned_count) <= max_peers {
+            return None;
+        }
+
+        let mut skipped = Vec::new();
+        let mut result = None;
+        while let Some(Reverse((last_active_ms, peer_id))) = queue.heap.pop() {
+            let current_ms = match peers.get(&peer_id) {
+                Some(peer) if peer.context() == ctx => peer.last_active_ms(),
+                // Removed peer
+                _ => continue,
+            };
+
+            if peer_id == *new_peer_id || pinned_peers.contains_key(&peer_id) {
+                skipped.push(Reverse((current_ms, peer_id)));
+            } else if current_ms > last_active_ms {
+                queue.heap.push(Reverse((current_ms, peer_id)));
+            } else {
+                result = Some(peer_id);
+                break;
+            }
+        }
+        queue.heap.extend(skipped);
+        result
+    }
+}
+
+#[derive(Default)]
+struct EvictionQueue {
+    /// Number of peers in the context (including the pinned ones)
+    len: usize,
+    /// Peers by the last known activity time. Can contain entries of the removed peers
+    heap: BinaryHeap<Reverse<(u64, NodeIdShort)>>,
+}
+
+impl EvictionQueue {
+    /// Rebuilds the queue from the peers table if it has too many removed peers
+    fn compact(&mut self, ctx: NewPeerContext, peers: &Peers) {
+        if self.heap.len() <= self.len * 2 + MIN_COMPACTED_LEN {
+            return;
+        }
+
+        self.heap = peers
+            .iter()
+            .filter(|peer| peer.context() == ctx)
+            .map(|peer| Reverse((peer.last_active_ms(), *peer.key())))
+            .collect();
+        self.len = self.heap.len();
+    }
+}
+
+const MIN_COMPACTED_LEN: usize = 64;
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    use super::*;
+    use crate::adnl::peer::Peer;
+    use crate::adnl::ComputeNodeIds;
+
+    #[test]
+    fn lazy_refresh_and_compaction() {
+        const CTX: NewPeerContext = NewPeerContext::PublicOverlay;
+
+        let local_id = NodeIdShort::new([0; 32]);
+        let peers = Peers::default();
+        let pinned_peers = PinnedPeers::default();
+        let eviction = PeerEviction::default();
+
+        let add_peer = |now_ms: u64| {
+            let key = everscale_crypto::ed25519::SecretKey::generate(&mut rand::thread_rng());
+            let (full_id, peer_id) = key.compute_node_ids();
+            let addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1);
+            peers.insert(peer_id, Peer::new(0, addr, full_id, CTX, now_ms));
+            eviction.insert(&local_id, CTX, &peer_id, now_ms, &peers);
+            peer_id
+        };
+        let remove_peer = |peer_id: &NodeIdShort| {
+            peers.remove(peer_id);
+            eviction.remove(&local_id, CTX);
+        };
+        let least_active = |new_peer_id: &NodeIdShort, max_peers: usize| {
+            eviction.least_active(
+                &local_id,
+                CTX,
+                new_peer_id,
+                max_peers,
+                &peers,
+                &pinned_peers,
+            )
+        };
+
+        let first = add_peer(1);
+        let second = add_peer(2);
+        let third = add_peer(3);
+        assert_eq!(least_active(&third, 3), None);
+
+        // Activity is noticed when the entry reaches the top
+        peers.get(&first).unwrap().set_active(10);
+        assert_eq!(least_active(&third, 2), Some(second));
+        remove_peer(&second);
+        assert_eq!(least_active(&third, 2), None);
+
+        // Pinned peers are skipped
+        let fourth = add_peer(4);
+        pinned_peers.insert(third, 1);
+        assert_eq!(least_active(&fourth, 2), None);
+        pinned_peers.clear();
+        assert_eq!(least_active(&fourth, 2), Some(third));
+        remove_peer(&third);
+
+        // Entries of the removed peers don't pile up
+        for i in 0..1000 {
+            let peer_id = add_peer(100 + i);
+            remove_peer(&peer_id);
+        }
+        let queue = eviction.queues.get(&(local_id, CTX)).unwrap();
+        assert_eq!(queue.len, 2);
+        assert!(queue.heap.len() <= 3 * 2 + MIN_COMPACTED_LEN);
+    }
+}
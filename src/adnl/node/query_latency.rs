@@ -0,0 +1,239 @@
+use std::hash::Hash;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::adnl::NodeIdShort;
+use crate::util::FastDashMap;
+
+/// Upper bounds (inclusive) of the latency histogram buckets in milliseconds.
+/// The last bucket of [`LatencyHistogram::buckets`] counts all longer queries
+pub const LATENCY_BUCKETS_MS: [u64; 16] = [
+    1, 2, 4, 8, 16, 32, 64, 128, 256, 512, 1024, 2048, 4096, 8192, 16384, 32768,
+];
+
+const BUCKET_COUNT: usize = LATENCY_BUCKETS_MS.len() + 1;
+
+/// Instant latency histogram of the answered queries
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct LatencyHistogram {
+    /// Number of queries in each bucket, see [`LATENCY_BUCKETS_MS`]
+    pub buckets: [u64; BUCKET_COUNT],
+    /// Total number of queries
+    pub count: u64,
+    /// Sum of all durations in milliseconds
+    pub sum_ms: u64,
+}
+
+impl LatencyHistogram {
+    /// Upper bound of the bucket which contains the `q`-th quantile (`0.0..=1.0`).
+    ///
+    /// Returns `None` if there are no queries or the quantile is in the last bucket
+    pub fn quantile_ms(&self, q: f64) -> Option<u64> {
+        if self.count == 0 {
+            return None;
+        }
+
+        let rank = ((self.count as f64 * q.clamp(0.0, 1.0)).ceil() as u64).max(1);
+        let mut total = 0;
+        for (bucket, count) in self.buckets.iter().enumerate() {
+            total += count;
+            if total >= rank {
+                return LATENCY_BUCKETS_MS.get(bucket).copied();
+            }
+        }
+        None
+    }
+}
+
+/// Query latencies snapshot, see [`Node::latency_report`]
+///
+/// [`Node::latency_report`]: crate::adnl::Node::latency_report
+#[derive(Debug, Default, Clone)]
+pub struct LatencyReport {
+    /// Latencies of the tracked peers
+    pub by_peer: Vec<(NodeIdShort, LatencyHistogram)>,
+    /// Latencies of all peers beyond the tracked peers limit
+    pub other_peers: LatencyHistogram,
+    /// Latencies of the tracked query constructors
+    pub by_constructor: Vec<(u32, LatencyHistogram)>,
+    /// Latencies of all queries beyond the tracked constructors limit (or without constructor)
+    pub other_constructors: LatencyHistogram,
+}
+
+#[derive(Default)]
+pub(super) struct QueryLatencies {
+    by_peer: KeyedHistograms<NodeIdShort>,
+    by_constructor: KeyedHistograms<u32>,
+}
+
+impl QueryLatencies {
+    pub fn record(
+        &self,
+        peer_id: &NodeIdShort,
+        constructor: Option<u32>,
+        elapsed: Duration,
+        max_peers: usize,
+        max_constructors: usize,
+    ) {
+        let elapsed_ms = elapsed.as_millis() as u64;
+        self.by_peer.record(Some(peer_id), elapsed_ms, max_peers);
+        self.by_constructor
+            .record(constructor.as_ref(), elapsed_ms, max_constructors);
+    }
+
+    pub fn report(&self) -> LatencyReport {
+        let (by_peer, other_peers) = self.by_peer.snapshot();
+        let (by_constructor, other_constructors) = self.by_constructor.snapshot();
+        LatencyReport {
+            by_peer,
+            other_peers,
+            by_constructor,
+            other_constructors,
+        }
+    }
+}
+
+struct KeyedHistograms<K> {
+    histograms: FastDashMap<K, Arc<AtomicHistogram>>,
+    other: AtomicHistogram,
+}
+
+impl<K> Default for KeyedHistograms<K>
+where
+    K: Eq + Hash,
+{
+    fn default() -> Self {
+        Self {
+            histograms: Default::default(),
+            other: Default::default(),
+        }
+    }
+}
+
+impl<K> KeyedHistograms<K>
+where
+    K: Eq + Hash + Copy,
+{
+    fn record(&self, key: Option<&K>, elapsed_ms: u64, max_keys: usize) {
+        let key = match key {
+            Some(key) => key,
+            None => return self.other.record(elapsed_ms),
+        };
+
+        if let Some(histogram) = self.histograms.get(key) {
+            return histogram.record(elapsed_ms);
+        }
+
+        // NOTE: the limit can be slightly exceeded by concurrent insertions
+        if self.histograms.len() < max_keys {
+            let histogram = self.histograms.entry(*key).or_default().clone();
+            histogram.record(elapsed_ms);
+        } else {
+            self.other.record(elapsed_ms);
+        }
+    }
+
+    fn snapshot(&self) -> (Vec<(K, LatencyHistogram)>, LatencyHistogram) {
+        let histograms = self
+            .histograms
+            .iter()
+            .map(|item| (*item.key(), item.snapshot()))
+            .collect();
+        (histograms, self.other.snapshot())
+    }
+}
+
+#[derive(Default)]
+struct AtomicHistogram {
+    buckets: [AtomicU64; BUCKET_COUNT],
+    count: AtomicU64,
+    sum_ms: AtomicU64,
+}
+
+impl AtomicHistogram {
+    fn record(&self, elapsed_ms: u64) {
+        self.buckets[bucket_index(elapsed_ms)].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_ms.fetch_add(elapsed_ms, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> LatencyHistogram {
+        let mut buckets = [0; BUCKET_COUNT];
+        for (bucket, value) in buckets.iter_mut().zip(&self.buckets) {
+            *bucket = value.load(Ordering::Relaxed);
+        }
+        LatencyHistogram {
+            buckets,
+            count: self.count.load(Ordering::Relaxed),
+            sum_ms: self.sum_ms.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Index of the first bucket with the upper bound not less than the value
+fn bucket_index(elapsed_ms: u64) -> usize {
+    if elapsed_ms <= 1 {
+        0
+    } else {
+        // ceil(log2(elapsed_ms))
+        let index = (u64::BITS - (elapsed_ms - 1).leading_zeros()) as usize;
+        index.min(BUCKET_COUNT - 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bucket_assignment() {
+        for (elapsed_ms, bucket) in [
+            (0, 0),
+            (1, 0),
+            (2, 1),
+            (3, 2),
+            (4, 2),
+            (5, 3),
+            (100, 7),
+            (128, 7),
+            (129, 8),
+            (32768, 15),
+            (32769, 16),
+            (u64::MAX, 16),
+        ] {
+            assert_eq!(bucket_index(elapsed_ms), bucket, "{elapsed_ms} ms");
+            if bucket < LATENCY_BUCKETS_MS.len() {
+                assert!(elapsed_ms <= LATENCY_BUCKETS_MS[bucket]);
+            }
+        }
+
+        let latencies = QueryLatencies::default();
+        let peers = [NodeIdShort::random(), NodeIdShort::random()];
+        for elapsed_ms in 1..=100 {
+            let elapsed = Duration::from_millis(elapsed_ms);
+            latencies.record(&peers[0], Some(1), elapsed, 1, 1);
+            latencies.record(&peers[1], Some(2), elapsed * 10, 1, 1);
+        }
+        latencies.record(&peers[0], None, Duration::ZERO, 1, 1);
+
+        let report = latencies.report();
+        assert_eq!(report.by_peer.len(), 1);
+        let (peer_id, histogram) = report.by_peer[0];
+        assert_eq!(peer_id, peers[0]);
+        assert_eq!(histogram.count, 101);
+        assert_eq!(histogram.sum_ms, 5050);
+        assert_eq!(histogram.quantile_ms(0.5), Some(64));
+        assert_eq!(histogram.quantile_ms(0.99), Some(128));
+
+        // Second peer and constructor exceed the limits
+        assert_eq!(report.other_peers.count, 100);
+        assert_eq!(report.other_peers.quantile_ms(0.99), Some(1024));
+        let mut expected = histogram;
+        expected.buckets[0] -= 1;
+        expected.count -= 1;
+        assert_eq!(report.by_constructor, [(1, expected)]);
+        assert_eq!(report.other_constructors.count, 101);
+        assert_eq!(LatencyHistogram::default().quantile_ms(0.5), None);
+    }
+}
@@ -0,0 +1,221 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+use super::traffic::TrafficMetrics;
+use super::Node;
+
+/// Rolling per-second rates of the node counters, see [`NodeOptions::rates_sample_interval_sec`]
+///
+/// [`NodeOptions::rates_sample_interval_sec`]: crate::adnl::NodeOptions::rates_sample_interval_sec
+#[derive(Debug, Default, Copy, Clone, serde::Serialize)]
+pub struct NodeRates {
+    /// Rates over the last minute
+    pub last_1m: Rates,
+    /// Rates over the last five minutes
+    pub last_5m: Rates,
+}
+
+/// Per-second rates over the sampling window.
+///
+/// The window is shorter right after the start, rates are zero until there are two samples
+#[derive(Debug, Default, Copy, Clone, serde::Serialize)]
+pub struct Rates {
+    /// Covered time in seconds
+    pub window_sec: f64,
+    pub packets_received: f64,
+    pub packets_sent: f64,
+    pub bytes_received: f64,
+    pub bytes_sent: f64,
+    /// Outgoing and incoming ADNL queries
+    pub queries: f64,
+    /// Outgoing and incoming RLDP queries
+    pub rldp_queries: f64,
+    /// Incoming overlay broadcast messages (including FEC parts and duplicates)
+    pub broadcasts: f64,
+}
+
+const COUNTERS: usize = 7;
+
+type Counters = [u64; COUNTERS];
+
+const LONG_WINDOW: Duration = Duration::from_secs(300);
+const SHORT_WINDOW: Duration = Duration::from_secs(60);
+
+/// Periodic snapshots of the monotonic counters
+pub(super) struct RatesSampler {
+    interval: Duration,
+    rldp_queries: AtomicU64,
+    broadcasts: AtomicU64,
+    history: Mutex<RatesHistory>,
+}
+
+impl RatesSampler {
+    pub fn new(interval_sec: u32) -> Self {
+        Self {
+            interval: Duration::from_secs(interval_sec.max(1) as u64),
+            rldp_queries: Default::default(),
+            broadcasts: Default::default(),
+            history: Default::default(),
+        }
+    }
+
+    #[cfg(feature = "rldp")]
+    pub fn add_rldp_query(&self) {
+        self.rldp_queries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[cfg(feature = "overlay")]
+    pub fn add_broadcast(&self) {
+        self.broadcasts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn rates(&self) -> NodeRates {
+        let history = self.history.lock();
+        NodeRates {
+            last_1m: history.rates(SHORT_WINDOW),
+            last_5m: history.rates(LONG_WINDOW),
+        }
+    }
+
+    fn sample(&self, now: Instant, traffic: &TrafficMetrics) {
+        let counters = [
+            traffic.packets_received,
+            traffic.packets_sent,
+            traffic.bytes_received,
+            traffic.bytes_sent,
+            traffic.queries_sent + traffic.queries_consumed + traffic.queries_unhandled,
+            self.rldp_queries.load(Ordering::Relaxed),
+            self.broadcasts.load(Ordering::Relaxed),
+        ];
+        self.history.lock().push(now, counters);
+    }
+}
+
+#[derive(Default)]
+struct RatesHistory {
+    /// Last raw counters values
+    last: Option<Counters>,
+    /// Accumulated increments which survive counter resets
+    totals: Counters,
+    /// Accumulated increments by sample time, oldest first
+    samples: VecDeque<(Instant, Counters)>,
+}
+
+impl RatesHistory {
+    fn push(&mut self, now: Instant, counters: Counters) {
+        if let Some(last) = &self.last {
+            for ((total, value), last) in self.totals.iter_mut().zip(counters).zip(last) {
+                // Counter which decreased was reset and started from zero
+                *total += if value >= *last { value - last } else { value };
+            }
+        }
+        self.last = Some(counters);
+        self.samples.push_back((now, self.totals));
+
+        // Keep one sample at the start of the longest window
+        while matches!(
+            self.samples.get(1),
+            Some((at, _)) if now.saturating_duration_since(*at) >= LONG_WINDOW
+        ) {
+            self.samples.pop_front();
+        }
+    }
+
+    fn rates(&self, window: Duration) -> Rates {
+        let (now, latest) = match self.samples.back() {
+            Some(sample) => sample,
+            None => return Rates::default(),
+        };
+        let (since, earliest) = match self
+            .samples
+            .iter()
+            .find(|(at, _)| now.saturating_duration_since(*at) <= window)
+        {
+            Some(sample) => sample,
+            None => return Rates::default(),
+        };
+
+        let window_sec = now.saturating_duration_since(*since).as_secs_f64();
+        if window_sec == 0.0 {
+            return Rates::default();
+        }
+
+        let rate = |i: usize| (latest[i] - earliest[i]) as f64 / window_sec;
+        Rates {
+            window_sec,
+            packets_received: rate(0),
+            packets_sent: rate(1),
+            bytes_received: rate(2),
+            bytes_sent: rate(3),
+            queries: rate(4),
+            rldp_queries: rate(5),
+            broadcasts: rate(6),
+        }
+    }
+}
+
+impl Node {
+    /// Starts a task which periodically samples counters for [`Node::rates`]
+    pub(super) fn start_rates_sampler(self: &Arc<Self>) {
+        let interval = match &self.rates {
+            Some(rates) => rates.interval,
+            None => return,
+        };
+
+        let node = Arc::downgrade(self);
+        let complete_signal = self.tasks.token().clone();
+        let clock = self.clock.clone();
+
+        self.tasks
+            .spawn(&self.runtime, "adnl_rates_sampler", async move {
+                loop {
+                    match node.upgrade() {
+                        Some(node) => {
+                            if let Some(rates) = &node.rates {
+                                rates.sample(clock.instant(), &node.traffic.metrics());
+                            }
+                        }
+                        None => break,
+                    }
+
+                    tokio::select! {
+                        _ = clock.sleep(interval) => {},
+                        _ = complete_signal.cancelled() => break,
+                    }
+                }
+            });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rates_survive_counter_resets() {
+        let start = Instant::now();
+        let at = |sec: u64| start + Duration::from_secs(sec);
+
+        let mut history = RatesHistory::default();
+        assert_eq!(history.rates(SHORT_WINDOW).packets_sent, 0.0);
+
+        // 10 packets per second during 10 minutes
+        for sec in 0..=600 {
+            history.push(at(sec), [0, sec * 10, 0, 0, 0, 0, 0]);
+        }
+        let rates = history.rates(SHORT_WINDOW);
+        assert_eq!(rates.window_sec, 60.0);
+        assert_eq!(rates.packets_sent, 10.0);
+        assert_eq!(history.rates(LONG_WINDOW).window_sec, 300.0);
+        assert!(history.samples.len() <= 302);
+
+        // Counter restarts from zero
+        history.push(at(601), [0, 10, 0, 0, 0, 0, 0]);
+        history.push(at(602), [0, 20, 0, 0, 0, 0, 0]);
+        assert_eq!(history.rates(SHORT_WINDOW).packets_sent, 10.0);
+    }
+}
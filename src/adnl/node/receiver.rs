@@ -1,29 +1,38 @@
 use std::borrow::Cow;
+use std::net::{SocketAddr, SocketAddrV4};
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use anyhow::Result;
 use everscale_crypto::ed25519;
 use tl_proto::TlRead;
-use tokio::net::UdpSocket;
 
+use crate::adnl::answer_encryption::encrypt_answer;
 use crate::adnl::channel::*;
 use crate::adnl::handshake::*;
 use crate::adnl::node_id::{NodeIdFull, NodeIdShort};
 use crate::adnl::packet_view::*;
 use crate::adnl::peer::*;
 use crate::adnl::queries_cache::*;
+use crate::adnl::reputation::ReputationEvent;
+use crate::adnl::socket::NodeSocket;
 use crate::adnl::transfer::*;
 use crate::adnl::Node;
+
+use super::handshake_replays::handshake_fingerprint;
+use super::incoming_queries::{IncomingQueryLimits, IncomingQueryState};
+use super::packet_drops::PacketDropReason;
+use super::peer_events::PeerEvent;
 use crate::proto;
 use crate::subscriber::*;
 use crate::util::*;
 
 impl Node {
-    /// Starts a process that listens for and processes packets from the UDP socket
+    /// Starts a process that listens for and processes packets from the socket
     pub(super) fn start_receiver(
         self: &Arc<Self>,
-        socket: Arc<UdpSocket>,
+        socket: NodeSocket,
         message_subscribers: Vec<Arc<dyn MessageSubscriber>>,
         query_subscribers: Vec<Arc<dyn QuerySubscriber>>,
     ) {
@@ -32,130 +41,260 @@ impl Node {
         struct ReceiverContext {
             node: Arc<Node>,
             message_subscribers: Vec<Arc<dyn MessageSubscriber>>,
-            query_subscribers: Vec<Arc<dyn QuerySubscriber>>,
+            query_subscribers: QuerySubscribers,
         }
 
         const RECV_BUFFER_SIZE: usize = 2048;
 
-        let complete_signal = self.cancellation_token.clone();
+        let complete_signal = self.tasks.token().clone();
         let ctx = Arc::new(ReceiverContext {
             node: self.clone(),
             message_subscribers,
-            query_subscribers,
+            query_subscribers: QuerySubscribers::new(query_subscribers),
         });
+        let runtime = self.runtime.clone();
+
+        self.tasks
+            .spawn(&self.runtime, "adnl_receiver", async move {
+                let mut buffer = None;
+
+                tokio::pin!(let cancelled = complete_signal.cancelled(););
+
+                loop {
+                    // SAFETY: buffer capacity is always `RECV_BUFFER_SIZE` at the point of creating slice
+                    // NOTE: we don't need to initialize it before writing to it
+                    let raw_buffer = unsafe {
+                        let buffer =
+                            buffer.get_or_insert_with(|| Vec::with_capacity(RECV_BUFFER_SIZE));
+                        std::slice::from_raw_parts_mut(buffer.as_mut_ptr(), buffer.capacity())
+                    };
+
+                    // Receive packet
+                    tokio::pin!(let recv = socket.recv_from(raw_buffer););
+                    let result = match select(recv, &mut cancelled).await {
+                        Either::Left((left, _)) => left,
+                        Either::Right(_) => break,
+                    };
+
+                    let received_at = ctx.node.clock.instant();
+                    let (len, source) = match result {
+                        Ok((0, _)) => continue,
+                        Ok((len, SocketAddr::V4(source))) => (len, source),
+                        Ok(_) => continue,
+                        Err(e) => {
+                            if ctx.node.should_log("failed to receive data") {
+                                tracing::warn!("failed to receive data: {e}");
+                            }
+                            continue;
+                        }
+                    };
+                    ctx.node.traffic.add_received(len);
+
+                    let mut buffer = match buffer.take() {
+                        Some(mut buffer) => {
+                            // SAFETY: at this point we have initialized at least `len` bytes of partially
+                            // initialized data of len `RECV_BUFFER_SIZE`
+                            unsafe { buffer.set_len(len) };
+                            buffer
+                        }
+                        None => continue,
+                    };
+
+                    // Process packet
+                    let packet_ctx = ctx.clone();
+                    ctx.node.tasks.spawn(&runtime, "adnl_packet", async move {
+                        let ctx = packet_ctx;
+                        if let Err(error) = ctx
+                            .node
+                            .handle_received_data(
+                                PacketView::from(buffer.as_mut_slice()),
+                                source,
+                                received_at,
+                                &ctx.message_subscribers,
+                                &ctx.query_subscribers,
+                            )
+                            .await
+                        {
+                            if tracing::enabled!(tracing::Level::TRACE)
+                                && ctx.node.should_log("failed to handle received data")
+                            {
+                                tracing::trace!(?error, "failed to handle received data");
+                            }
+                        }
+                    });
+                }
 
-        tokio::spawn(async move {
-            let mut buffer = None;
-
-            tokio::pin!(let cancelled = complete_signal.cancelled(););
-
-            loop {
-                // SAFETY: buffer capacity is always `RECV_BUFFER_SIZE` at the point of creating slice
-                // NOTE: we don't need to initialize it before writing to it
-                let raw_buffer = unsafe {
-                    let buffer = buffer.get_or_insert_with(|| Vec::with_capacity(RECV_BUFFER_SIZE));
-                    std::slice::from_raw_parts_mut(buffer.as_mut_ptr(), buffer.capacity())
-                };
-
-                // Receive packet
-                tokio::pin!(let recv = socket.recv_from(raw_buffer););
-                let result = match select(recv, &mut cancelled).await {
-                    Either::Left((left, _)) => left,
-                    Either::Right(_) => break,
-                };
-
-                let len = match result {
-                    Ok((0, _)) => continue,
-                    Ok((len, _)) => len,
-                    Err(e) => {
-                        tracing::warn!("failed to receive data: {e}");
-                        continue;
-                    }
-                };
-
-                let mut buffer = match buffer.take() {
-                    Some(mut buffer) => {
-                        // SAFETY: at this point we have initialized at least `len` bytes of partially
-                        // initialized data of len `RECV_BUFFER_SIZE`
-                        unsafe { buffer.set_len(len) };
-                        buffer
-                    }
-                    None => continue,
-                };
-
-                // Process packet
-                let ctx = ctx.clone();
-                tokio::spawn(async move {
-                    if let Err(error) = ctx
-                        .node
-                        .handle_received_data(
-                            PacketView::from(buffer.as_mut_slice()),
-                            &ctx.message_subscribers,
-                            &ctx.query_subscribers,
-                        )
-                        .await
-                    {
-                        tracing::trace!(?error, "failed to handle received data");
-                    }
-                });
-            }
-
-            tracing::debug!("receiver loop finished");
-        });
+                tracing::debug!("receiver loop finished");
+            });
     }
 
     /// Decrypts and processes received data
     async fn handle_received_data(
         self: &Arc<Self>,
         mut data: PacketView<'_>,
+        source: SocketAddrV4,
+        received_at: Instant,
         message_subscribers: &[Arc<dyn MessageSubscriber>],
-        query_subscribers: &[Arc<dyn QuerySubscriber>],
+        query_subscribers: &QuerySubscribers,
     ) -> Result<()> {
+        // Save packet header for diagnostics, because decryption modifies the buffer
+        let mut header = [0u8; 32];
+        let header_len = std::cmp::min(data.remaining(), header.len());
+        header[..header_len].copy_from_slice(&data.as_bytes()[..header_len]);
+        let header = &header[..header_len];
+
+        // Drop packets from the denied addresses before decryption
+        if let Some(filter) = &self.peer_filter {
+            if !filter.check_addr(source) {
+                self.drop_packet(PacketDropReason::DeniedAddress, source, header);
+                return Ok(());
+            }
+        }
+
+        // Drop exact replays of the handshake packets before decryption
+        let replay_window = self.options.load().handshake_replay_window;
+        let fingerprint = match data.get_array::<32>(0) {
+            Ok(id)
+                if replay_window > 0
+                    && self.keystore.keys().contains_key(&NodeIdShort::new(*id)) =>
+            {
+                handshake_fingerprint(data.as_bytes()).copied()
+            }
+            _ => None,
+        };
+        if let Some(fingerprint) = &fingerprint {
+            if self.handshake_replays.contains(fingerprint) {
+                self.drop_packet(PacketDropReason::Replayed, source, header);
+                return Ok(());
+            }
+        }
+
         // Decrypt packet and extract peers
-        let (priority, local_id, peer_id, version) = if let Some((local_id, version)) =
-            parse_handshake_packet(self.keystore.keys(), &mut data)?
-        {
-            (false, local_id, None, version)
-        } else if let Some(channel) = self.channels_by_id.get(&data[0..32]) {
-            let (channel, priority) = match channel.value() {
-                ChannelReceiver::Priority(channel) => (channel, true),
-                ChannelReceiver::Ordinary(channel) => (channel, false),
+        let (priority, local_id, peer_id, version, established) =
+            match parse_handshake_packet_in_place(
+                |id| self.keystore.keys().get(id).map(Arc::as_ref),
+                &mut data,
+            ) {
+                Ok(Some((local_id, version))) => {
+                    // NOTE: only authentic packets are remembered
+                    if let Some(fingerprint) = fingerprint {
+                        if !self.handshake_replays.insert(fingerprint, replay_window) {
+                            self.drop_packet(PacketDropReason::Replayed, source, header);
+                            return Ok(());
+                        }
+                    }
+                    self.traffic
+                        .handshake_packets_received
+                        .fetch_add(1, Ordering::Relaxed);
+                    (false, local_id, None, version, false)
+                }
+                Ok(None) => match data
+                    .get_array::<32>(0)
+                    .ok()
+                    .and_then(|id| self.channels_by_id.get(id))
+                {
+                    Some(channel) => {
+                        let (channel, priority) = match channel.value() {
+                            ChannelReceiver::Priority(channel) => (channel, true),
+                            ChannelReceiver::Ordinary(channel) => (channel, false),
+                        };
+                        let version = match channel.decrypt(&mut data, priority) {
+                            Ok(version) => version,
+                            Err(e) => {
+                                self.drop_packet((&e).into(), source, header);
+                                return Err(e.into());
+                            }
+                        };
+                        let established = channel.set_ready();
+                        channel.reset_drop_timeout();
+                        (
+                            priority,
+                            *channel.local_id(),
+                            Some(*channel.peer_id()),
+                            version,
+                            established,
+                        )
+                    }
+                    None => {
+                        self.traffic
+                            .packets_to_unknown_local_id
+                            .fetch_add(1, Ordering::Relaxed);
+                        self.drop_packet(PacketDropReason::UnknownChannel, source, header);
+                        return Ok(());
+                    }
+                },
+                Err(e) => {
+                    self.drop_packet((&e).into(), source, header);
+                    return Err(e.into());
+                }
             };
-            let version = channel.decrypt(&mut data, priority)?;
-            channel.set_ready();
-            channel.reset_drop_timeout();
-            (
-                priority,
-                *channel.local_id(),
-                Some(*channel.peer_id()),
-                version,
-            )
-        } else {
-            tracing::trace!(
-                key_id = hex::encode(&data[0..32]),
-                "received message to unknown key ID",
-            );
-            return Ok(());
-        };
+
+        if let (true, Some(peer_id)) = (established, &peer_id) {
+            self.on_channel_established(&local_id, peer_id);
+        }
 
         if let Some(version) = version {
             if version != ADNL_INITIAL_VERSION {
+                self.drop_packet(PacketDropReason::UnsupportedVersion, source, header);
                 return Err(AdnlReceiverError::UnsupportedVersion.into());
             }
         }
 
         // Parse packet
-        let mut packet =
-            tl_proto::deserialize::<proto::adnl::IncomingPacketContents>(data.as_slice())
-                .map_err(|_| AdnlReceiverError::InvalidPacket)?;
+        let (mut packet, trailing) = match proto::adnl::IncomingPacketContents::read_with_limit(
+            data.as_bytes(),
+            self.options.load().max_messages_per_packet as usize,
+        ) {
+            Ok(result) => result,
+            Err(_) => {
+                self.drop_packet(PacketDropReason::ParseError, source, header);
+                return Err(AdnlReceiverError::InvalidPacket.into());
+            }
+        };
+
+        if trailing > 0 && self.options.load().reject_trailing_data {
+            self.drop_packet(PacketDropReason::ParseError, source, header);
+            return Err(AdnlReceiverError::TrailingData(trailing).into());
+        }
+
+        // Queries from the packets without channel messages are answered the same way
+        let reply_to = (peer_id.is_none()
+            && self.options.load().allow_channelless_queries
+            && !packet.messages.iter().any(|message| {
+                matches!(
+                    message,
+                    proto::adnl::Message::CreateChannel { .. }
+                        | proto::adnl::Message::ConfirmChannel { .. }
+                )
+            }))
+        .then_some(ChannellessReply {
+            destination: source,
+            max_answer_len: data
+                .as_bytes()
+                .len()
+                .saturating_mul(CHANNELLESS_AMPLIFICATION_FACTOR),
+        });
 
         // Validate packet
-        let peer_id = match self.check_packet(&data, &mut packet, &local_id, peer_id, priority)? {
-            // New packet
-            Some(peer_id) => peer_id,
-            // Repeated packet
-            None => return Ok(()),
-        };
+        let peer_id =
+            match self.check_packet(&data, &mut packet, source, &local_id, peer_id, priority) {
+                // New packet
+                Ok(Ok(peer_id)) => peer_id,
+                // Repeated or too old packet
+                Ok(Err(reason)) => {
+                    self.drop_packet(reason, source, header);
+                    return Ok(());
+                }
+                Err(e) => {
+                    if let Some(reason) = e
+                        .downcast_ref::<AdnlPacketError>()
+                        .and_then(AdnlPacketError::drop_reason)
+                    {
+                        self.drop_packet(reason, source, header);
+                    }
+                    return Err(e);
+                }
+            };
 
         // Process message(s)
         for message in packet.messages {
@@ -163,9 +302,11 @@ impl Node {
                 &local_id,
                 &peer_id,
                 message,
+                received_at,
                 message_subscribers,
                 query_subscribers,
                 priority,
+                reply_to,
             )
             .await?;
         }
@@ -174,14 +315,30 @@ impl Node {
         Ok(())
     }
 
+    /// Counts dropped packet and logs its header (sampled)
+    fn drop_packet(&self, reason: PacketDropReason, source: SocketAddrV4, header: &[u8]) {
+        self.packet_drops.add(reason);
+
+        if tracing::enabled!(tracing::Level::DEBUG) && self.should_log("dropped incoming packet") {
+            tracing::debug!(
+                ?reason,
+                %source,
+                header = hex::encode(header),
+                "dropped incoming packet"
+            );
+        }
+    }
+
     async fn process_message(
         self: &Arc<Self>,
         local_id: &NodeIdShort,
         peer_id: &NodeIdShort,
         message: proto::adnl::Message<'_>,
+        received_at: Instant,
         message_subscribers: &[Arc<dyn MessageSubscriber>],
-        query_subscribers: &[Arc<dyn QuerySubscriber>],
+        query_subscribers: &QuerySubscribers,
         priority: bool,
+        reply_to: Option<ChannellessReply>,
     ) -> Result<()> {
         use dashmap::mapref::entry::Entry;
 
@@ -199,22 +356,27 @@ impl Node {
                 Entry::Vacant(entry) => {
                     let entry = entry.insert(Arc::new(Transfer::new(total_size as usize)));
                     let transfer = entry.value().clone();
-                    tracing::debug!(
-                        %local_id,
-                        %peer_id,
-                        total = total_size,
-                        transfer_id = %DisplayTransferId(&transfer_id),
-                        "started ADNL transfer"
-                    );
-
-                    tokio::spawn({
+                    if tracing::enabled!(tracing::Level::DEBUG)
+                        && self.should_log("started ADNL transfer")
+                    {
+                        tracing::debug!(
+                            %local_id,
+                            %peer_id,
+                            total = total_size,
+                            transfer_id = %DisplayTransferId(&transfer_id),
+                            "started ADNL transfer"
+                        );
+                    }
+
+                    self.tasks.spawn(&self.runtime, "adnl_transfer_gc", {
                         let incoming_transfers = self.incoming_transfers.clone();
                         let transfer = transfer.clone();
-                        let transfer_timeout = self.options.transfer_timeout_sec;
+                        let transfer_timeout = self.options.load().transfer_timeout_sec;
+                        let clock = self.clock.clone();
 
                         async move {
                             loop {
-                                tokio::time::sleep(Duration::from_secs(transfer_timeout)).await;
+                                clock.sleep(Duration::from_secs(transfer_timeout)).await;
                                 if !transfer.timings().is_expired(transfer_timeout) {
                                     continue;
                                 }
@@ -262,8 +424,7 @@ impl Node {
         // Process message
         match alt_message.unwrap_or(message) {
             proto::adnl::Message::Answer { query_id, answer } => {
-                self.process_message_answer(query_id, answer);
-                Ok(())
+                self.process_message_answer(local_id, peer_id, &QueryId(*query_id), answer)
             }
             proto::adnl::Message::ConfirmChannel { key, date, .. } => self
                 .process_message_confirm_channel(
@@ -280,46 +441,267 @@ impl Node {
                     date,
                 ),
             proto::adnl::Message::Custom { data } => {
+                if data.get(..4) == Some(&proto::adnl::Capabilities::TL_ID.to_le_bytes()) {
+                    return self.process_message_capabilities(local_id, peer_id, data);
+                }
+
                 let ctx = SubscriberContext {
                     adnl: self,
                     local_id,
                     peer_id,
                 };
-                if process_message_custom(ctx, message_subscribers, data).await? {
+                let message_ctx = MessageContext::new(ctx, data, received_at);
+                if process_message_custom(ctx, message_ctx, message_subscribers, data).await? {
+                    self.traffic
+                        .messages_consumed
+                        .fetch_add(1, Ordering::Relaxed);
                     Ok(())
                 } else {
+                    self.traffic
+                        .messages_unhandled
+                        .fetch_add(1, Ordering::Relaxed);
                     Err(AdnlReceiverError::NoSubscribersForCustomMessage.into())
                 }
             }
             proto::adnl::Message::Nop => Ok(()),
             proto::adnl::Message::Query { query_id, query } => {
+                // Answer must be encrypted to the one-time key from the wrapper
+                let (query, answer_key) = if query.get(..4)
+                    == Some(&proto::rpc::PrivateQueryWithAnswerKey::TL_ID.to_le_bytes())
+                {
+                    let wrapper =
+                        tl_proto::deserialize::<proto::rpc::PrivateQueryWithAnswerKey>(query)?;
+                    let answer_key = ed25519::PublicKey::from_bytes(*wrapper.answer_key)
+                        .ok_or(AdnlReceiverError::InvalidPacket)?;
+                    (wrapper.query, Some(answer_key))
+                } else {
+                    (query, None)
+                };
+
+                // Retransmitted queries are not processed again
+                let incoming_query_id = QueryId(*query_id);
+                let retransmit_limits = {
+                    let options = self.options.load();
+                    IncomingQueryLimits {
+                        capacity: options.query_retransmit_window,
+                        max_size: options.query_retransmit_max_size,
+                        ttl: Duration::from_millis(options.incoming_query_timeout_ms),
+                    }
+                };
+                match self.incoming_queries.begin(
+                    peer_id,
+                    &incoming_query_id,
+                    &retransmit_limits,
+                    self.clock.instant(),
+                ) {
+                    IncomingQueryState::New => {}
+                    IncomingQueryState::InProgress | IncomingQueryState::Answered(None) => {
+                        self.queries_deduplicated.fetch_add(1, Ordering::Relaxed);
+                        return Ok(());
+                    }
+                    IncomingQueryState::Answered(Some(answer)) => {
+                        self.queries_deduplicated.fetch_add(1, Ordering::Relaxed);
+                        return self
+                            .send_answer(local_id, peer_id, query_id, &answer, priority, reply_to);
+                    }
+                }
+
                 let ctx = SubscriberContext {
                     adnl: self,
                     local_id,
                     peer_id,
                 };
-                match process_query(ctx, query_subscribers, Cow::Borrowed(query)).await? {
-                    QueryProcessingResult::Processed(Some(answer)) => self.send_message(
-                        local_id,
-                        peer_id,
-                        proto::adnl::Message::Answer {
-                            query_id,
-                            answer: &answer,
-                        },
-                        priority,
-                    ),
-                    QueryProcessingResult::Processed(None) => Ok(()),
-                    QueryProcessingResult::Rejected => {
+                let query_ctx = QueryContext {
+                    transport: QueryTransport::Adnl,
+                    query_id: incoming_query_id,
+                    trace_id: TraceId::from(&incoming_query_id),
+                    query_len: query.len(),
+                    received_at,
+                    deadline: received_at
+                        + Duration::from_millis(self.options.load().incoming_query_timeout_ms),
+                    local_id: *local_id,
+                    max_answer_size: None,
+                };
+                let result =
+                    process_query(ctx, query_ctx, query_subscribers, Cow::Borrowed(query)).await;
+                let counter = match &result {
+                    Ok(
+                        QueryProcessingResult::Processed(_) | QueryProcessingResult::Deferred(_),
+                    ) => &self.traffic.queries_consumed,
+                    Ok(QueryProcessingResult::Rejected) => &self.traffic.queries_unhandled,
+                    Err(_) => &self.traffic.queries_unhandled,
+                };
+                counter.fetch_add(1, Ordering::Relaxed);
+
+                let route = AnswerRoute {
+                    local_id: *local_id,
+                    peer_id: *peer_id,
+                    query_id: incoming_query_id,
+                    trace_id: query_ctx.trace_id,
+                    answer_key,
+                    priority,
+                    reply_to,
+                };
+                match result {
+                    Ok(QueryProcessingResult::Processed(Some(answer))) => {
+                        self.send_query_answer(&route, answer, &retransmit_limits)
+                    }
+                    Ok(QueryProcessingResult::Deferred(query)) => {
+                        query.register(self, move |adnl, answer| {
+                            if let Err(e) =
+                                adnl.send_query_answer(&route, answer, &retransmit_limits)
+                            {
+                                tracing::debug!(
+                                    peer_id = %route.peer_id,
+                                    trace_id = %route.trace_id,
+                                    "failed to send deferred ADNL answer: {e:?}"
+                                );
+                            }
+                        });
+                        Ok(())
+                    }
+                    Ok(QueryProcessingResult::Processed(None)) => {
+                        self.incoming_queries.finish(
+                            peer_id,
+                            &incoming_query_id,
+                            None,
+                            &retransmit_limits,
+                            self.clock.instant(),
+                        );
+                        Ok(())
+                    }
+                    Ok(QueryProcessingResult::Rejected) => {
+                        self.incoming_queries.forget(peer_id, &incoming_query_id);
                         Err(AdnlReceiverError::NoSubscribersForQuery.into())
                     }
+                    Err(e) => {
+                        self.incoming_queries.forget(peer_id, &incoming_query_id);
+                        Err(e)
+                    }
                 }
             }
             _ => Err(AdnlReceiverError::UnknownMessage.into()),
         }
     }
 
-    fn process_message_answer(&self, query_id: &QueryId, answer: &[u8]) {
-        self.queries.update_query(query_id, answer);
+    /// Encrypts and remembers the answer to the incoming query, and sends it
+    fn send_query_answer(
+        &self,
+        route: &AnswerRoute,
+        answer: Vec<u8>,
+        retransmit_limits: &IncomingQueryLimits,
+    ) -> Result<()> {
+        let answer = match &route.answer_key {
+            Some(answer_key) => encrypt_answer(
+                self.keystore.key_by_id(&route.local_id)?,
+                answer_key,
+                &answer,
+            ),
+            None => answer,
+        };
+        self.incoming_queries.finish(
+            &route.peer_id,
+            &route.query_id,
+            Some(&answer),
+            retransmit_limits,
+            self.clock.instant(),
+        );
+        tracing::debug!(
+            peer_id = %route.peer_id,
+            trace_id = %route.trace_id,
+            len = answer.len(),
+            "sending ADNL answer"
+        );
+        self.send_answer(
+            &route.local_id,
+            &route.peer_id,
+            &route.query_id.0,
+            &answer,
+            route.priority,
+            route.reply_to,
+        )
+    }
+
+    /// Sends the answer over the channel or back to the source of the channel-less query.
+    ///
+    /// NOTE: the source address is not authenticated, so the larger answers
+    /// are sent the usual way, to the known address of the peer
+    fn send_answer(
+        &self,
+        local_id: &NodeIdShort,
+        peer_id: &NodeIdShort,
+        query_id: &[u8; 32],
+        answer: &[u8],
+        priority: bool,
+        reply_to: Option<ChannellessReply>,
+    ) -> Result<()> {
+        let message = proto::adnl::Message::Answer { query_id, answer };
+        match reply_to {
+            Some(reply) if answer.len() <= reply.max_answer_len => {
+                self.send_channelless_message(local_id, peer_id, message, reply.destination)
+            }
+            _ => self.send_message(local_id, peer_id, message, priority),
+        }
+    }
+
+    fn process_message_answer(
+        &self,
+        local_id: &NodeIdShort,
+        peer_id: &NodeIdShort,
+        query_id: &QueryId,
+        answer: &[u8],
+    ) -> Result<()> {
+        match self
+            .queries
+            .update_query(local_id, peer_id, query_id, answer)
+        {
+            QueryUpdateResult::Updated
+            | QueryUpdateResult::Unknown
+            | QueryUpdateResult::Cancelled => Ok(()),
+            QueryUpdateResult::PeerMismatch => {
+                // Stop processing the rest of the packet from this peer
+                self.answers_spoofed.fetch_add(1, Ordering::Relaxed);
+                self.report_peer(peer_id, ReputationEvent::Spoofing);
+                if self.should_log("answer from unexpected peer") {
+                    tracing::warn!(%local_id, %peer_id, %query_id, "answer from unexpected peer");
+                }
+                Err(AdnlReceiverError::AnswerFromUnexpectedPeer.into())
+            }
+        }
+    }
+
+    fn process_message_capabilities(
+        &self,
+        local_id: &NodeIdShort,
+        peer_id: &NodeIdShort,
+        data: &[u8],
+    ) -> Result<()> {
+        let capabilities = tl_proto::deserialize::<proto::adnl::Capabilities>(data)?;
+
+        // Ignore capabilities which were not sent after the channel establishment
+        let channel = self.channels_by_peers.get(&(*local_id, *peer_id));
+        if !matches!(channel, Some(channel) if channel.ready()) {
+            tracing::trace!(%local_id, %peer_id, "ignoring capabilities without channel");
+            return Ok(());
+        }
+
+        let peers = self.get_peers(local_id)?;
+        let peer = peers
+            .get(peer_id)
+            .ok_or(AdnlReceiverError::UnknownPeerInChannel)?;
+        let capabilities = PeerCapabilities {
+            version: capabilities.version,
+            features: capabilities.features,
+        };
+        if !peer.try_update_capabilities(
+            capabilities,
+            self.clock.now(),
+            CAPABILITIES_UPDATE_INTERVAL_SEC,
+        ) {
+            tracing::trace!(%local_id, %peer_id, "ignoring too frequent capabilities update");
+        }
+
+        Ok(())
     }
 
     fn process_message_confirm_channel(
@@ -354,15 +736,27 @@ impl Node {
         )
     }
 
+    fn check_peer_filter(
+        &self,
+        source: SocketAddrV4,
+        peer_id: &NodeIdShort,
+    ) -> Result<(), AdnlPacketError> {
+        match &self.peer_filter {
+            Some(filter) if !filter.check_peer(source, peer_id) => Err(AdnlPacketError::DeniedPeer),
+            _ => Ok(()),
+        }
+    }
+
     /// Validates incoming packet. Attempts to extract peer id
     fn check_packet(
         &self,
         raw_packet: &PacketView<'_>,
         packet: &mut proto::adnl::IncomingPacketContents<'_>,
+        source: SocketAddrV4,
         local_id: &NodeIdShort,
         peer_id: Option<NodeIdShort>,
         priority: bool,
-    ) -> Result<Option<NodeIdShort>> {
+    ) -> Result<Result<NodeIdShort, PacketDropReason>> {
         use std::cmp::Ordering;
 
         fn verify(
@@ -372,18 +766,17 @@ impl Node {
             mandatory: bool,
         ) -> Result<(), AdnlPacketError> {
             if let Some(signature) = signature.take() {
-                // SAFETY: called only once on same packet
-                let (message, signature) = unsafe {
-                    let origin = raw_packet.as_slice().as_ptr() as *mut u8;
-                    let packet = std::slice::from_raw_parts_mut(origin, raw_packet.len());
-                    signature
-                        .extract(packet)
-                        .ok_or(AdnlPacketError::SignatureNotFound)?
-                };
-
-                if !public_key.verify_raw(message, &signature) {
-                    return Err(AdnlPacketError::InvalidSignature);
-                }
+                with_serialize_buffer(|message| {
+                    let signature = signature
+                        .extract_to(raw_packet.as_bytes(), message)
+                        .ok_or(AdnlPacketError::SignatureNotFound)?;
+
+                    if public_key.verify_raw(message, &signature) {
+                        Ok(())
+                    } else {
+                        Err(AdnlPacketError::InvalidSignature)
+                    }
+                })?;
             } else if mandatory {
                 return Err(AdnlPacketError::SignatureNotFound);
             }
@@ -399,22 +792,28 @@ impl Node {
             }
             (peer_id, true)
         } else if let Some(public_key) = packet.from {
-            let full_id: NodeIdFull = public_key.try_into()?;
+            let full_id =
+                NodeIdFull::try_from(public_key).map_err(|_| AdnlPacketError::InvalidPublicKey)?;
             let peer_id = full_id.compute_short_id();
 
             if matches!(packet.from_short, Some(id) if peer_id.as_slice() != id) {
                 return Err(AdnlPacketError::InvalidPeerId.into());
             }
+            self.check_peer_filter(source, &peer_id)?;
 
             verify(
                 raw_packet,
                 &mut packet.signature,
                 full_id.public_key(),
-                self.options.packet_signature_required,
+                self.options.load().packet_signature_required,
             )?;
 
             if let Some(list) = &packet.address {
-                let addr = parse_address_list(list, self.options.clock_tolerance_sec)?;
+                let addr = parse_first_address(
+                    list,
+                    self.clock.now(),
+                    self.options.load().clock_tolerance_sec,
+                )?;
                 self.add_peer(
                     NewPeerContext::AdnlPacket,
                     local_id,
@@ -431,11 +830,16 @@ impl Node {
             return Err(AdnlPacketError::NoKeyDataInPacket.into());
         };
 
+        // NOTE: peer id from the public key was already checked
+        if check_signature {
+            self.check_peer_filter(source, &peer_id)?;
+        }
+
         // Check timings
 
         let peers = self.get_peers(local_id)?;
         let peer = if from_channel {
-            if self.channels_by_peers.contains_key(&peer_id) {
+            if self.channels_by_peers.contains_key(&(*local_id, peer_id)) {
                 peers.get(&peer_id)
             } else {
                 return Err(AdnlPacketError::UnknownChannel.into());
@@ -464,7 +868,7 @@ impl Node {
                 return Err(AdnlPacketError::DstReinitDateTooNew.into());
             }
 
-            if peer_reinit_date > now() + self.options.clock_tolerance_sec {
+            if peer_reinit_date > self.clock.now() + self.options.load().clock_tolerance_sec {
                 return Err(AdnlPacketError::SrcReinitDateTooNew.into());
             }
 
@@ -480,14 +884,15 @@ impl Node {
             }
         }
 
-        if self.options.packet_history_enabled {
+        // NOTE: channel packets are always checked
+        let options = self.options.load();
+        if from_channel || options.packet_history_enabled {
             if let Some(seqno) = packet.seqno {
-                if !peer
-                    .receiver_state()
-                    .history(priority)
-                    .deliver_packet(seqno)
-                {
-                    return Ok(None);
+                let history = peer.receiver_state().history(priority);
+                match history.check_seqno(seqno, options.seqno_window) {
+                    SeqnoCheck::New => {}
+                    SeqnoCheck::Duplicate => return Ok(Err(PacketDropReason::Replayed)),
+                    SeqnoCheck::TooOld => return Ok(Err(PacketDropReason::SeqnoTooOld)),
                 }
             }
         }
@@ -499,7 +904,9 @@ impl Node {
             }
         }
 
-        Ok(Some(peer_id))
+        peer.set_active(self.clock.now_ms());
+        peer.set_observed_addr(source);
+        Ok(Ok(peer_id))
     }
 
     fn create_channel(
@@ -513,19 +920,31 @@ impl Node {
         use dashmap::mapref::entry::Entry;
 
         let peers = self.get_peers(local_id)?;
-        let peer = match peers.get(peer_id) {
+        let peer_entry = match peers.get(peer_id) {
             Some(peer) => peer,
             None => return Err(AdnlReceiverError::UnknownPeerInChannel.into()),
         };
-        let peer = peer.value();
+        let peer = peer_entry.value();
 
-        match self.channels_by_peers.entry(*peer_id) {
+        let confirmed = context == ChannelCreationContext::ConfirmChannel;
+        let established = match self.channels_by_peers.entry((*local_id, *peer_id)) {
             Entry::Occupied(mut entry) => {
                 let channel = entry.get();
 
                 if channel.is_still_valid(&peer_channel_public_key, peer_channel_date) {
-                    if context == ChannelCreationContext::ConfirmChannel {
-                        channel.set_ready();
+                    let established = confirmed && channel.set_ready();
+                    let reply = !confirmed && !channel.ready();
+                    drop(entry);
+                    if established {
+                        self.peer_events
+                            .push(local_id, peer_id, PeerEvent::ChannelEstablished);
+                    }
+                    drop(peer_entry);
+                    if established {
+                        self.on_channel_established(local_id, peer_id);
+                    }
+                    if reply {
+                        self.confirm_channel(local_id, peer_id);
                     }
                     return Ok(());
                 }
@@ -553,6 +972,7 @@ impl Node {
                     *new_channel.priority_channel_in_id(),
                     ChannelReceiver::Priority(new_channel),
                 );
+                confirmed
             }
             Entry::Vacant(entry) => {
                 let new_channel = entry
@@ -573,13 +993,34 @@ impl Node {
                     *new_channel.priority_channel_in_id(),
                     ChannelReceiver::Priority(new_channel),
                 );
+                confirmed
             }
+        };
+        if established {
+            // NOTE: the event is pushed while the peer can't be removed
+            self.peer_events
+                .push(local_id, peer_id, PeerEvent::ChannelEstablished);
+        }
+        drop(peer_entry);
+
+        if established {
+            self.on_channel_established(local_id, peer_id);
+        } else if !confirmed {
+            self.confirm_channel(local_id, peer_id);
         }
 
         tracing::trace!(%local_id, %peer_id, "{context} channel");
 
         Ok(())
     }
+
+    /// Sends an empty message to the peer which requested the channel.
+    /// `ConfirmChannel` is added to it automatically
+    fn confirm_channel(&self, local_id: &NodeIdShort, peer_id: &NodeIdShort) {
+        if let Err(e) = self.send_message(local_id, peer_id, proto::adnl::Message::Nop, false) {
+            tracing::debug!(%local_id, %peer_id, "failed to confirm channel: {e:?}");
+        }
+    }
 }
 
 /// Duplicated channel
@@ -590,13 +1031,14 @@ pub enum ChannelReceiver {
 
 async fn process_message_custom<'a>(
     ctx: SubscriberContext<'a>,
+    message_ctx: MessageContext<'a>,
     subscribers: &[Arc<dyn MessageSubscriber>],
-    data: &[u8],
+    data: &'a [u8],
 ) -> Result<bool> {
     let constructor = u32::read_from(data, &mut 0)?;
     for subscriber in subscribers {
         if subscriber
-            .try_consume_custom(ctx, constructor, data)
+            .try_consume_custom_ext(ctx, message_ctx, constructor, data)
             .await?
         {
             return Ok(true);
@@ -605,7 +1047,31 @@ async fn process_message_custom<'a>(
     Ok(false)
 }
 
+/// Where the answer to the incoming ADNL query is sent
+#[derive(Copy, Clone)]
+struct AnswerRoute {
+    local_id: NodeIdShort,
+    peer_id: NodeIdShort,
+    query_id: QueryId,
+    trace_id: TraceId,
+    answer_key: Option<ed25519::PublicKey>,
+    priority: bool,
+    reply_to: Option<ChannellessReply>,
+}
+
+/// Source of the channel-less query which can receive the answer
+#[derive(Copy, Clone)]
+struct ChannellessReply {
+    destination: SocketAddrV4,
+    /// Max size of the answer which is sent to the unauthenticated source
+    max_answer_len: usize,
+}
+
 const ADNL_INITIAL_VERSION: u16 = 0;
+/// Max ratio of the channel-less answer size to the query packet size
+const CHANNELLESS_AMPLIFICATION_FACTOR: usize = 3;
+/// Min interval between accepted capabilities updates from the same peer
+const CAPABILITIES_UPDATE_INTERVAL_SEC: u32 = 10;
 
 #[derive(thiserror::Error, Debug)]
 enum AdnlReceiverError {
@@ -621,6 +1087,10 @@ enum AdnlReceiverError {
     NoSubscribersForQuery,
     #[error("Unsupported version")]
     UnsupportedVersion,
+    #[error("Answer from unexpected peer")]
+    AnswerFromUnexpectedPeer,
+    #[error("Trailing data after packet contents: {0} bytes")]
+    TrailingData(usize),
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -645,8 +1115,225 @@ enum AdnlPacketError {
     SrcReinitDateTooOld,
     #[error("Confirmation seqno is too new")]
     ConfirmationSeqnoTooNew,
+    #[error("Invalid public key")]
+    InvalidPublicKey,
     #[error("Signature not found")]
     SignatureNotFound,
     #[error("Invalid signature")]
     InvalidSignature,
+    #[error("Peer is denied by the filter")]
+    DeniedPeer,
+}
+
+impl AdnlPacketError {
+    /// Returns drop reason for malformed packets
+    fn drop_reason(&self) -> Option<PacketDropReason> {
+        match self {
+            Self::InvalidPublicKey => Some(PacketDropReason::ParseError),
+            Self::SignatureNotFound | Self::InvalidSignature => {
+                Some(PacketDropReason::InvalidSignature)
+            }
+            Self::DeniedPeer => Some(PacketDropReason::DeniedPeer),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(feature = "fuzzing")]
+impl Node {
+    /// Processes the datagram as if it was received from the socket
+    pub(crate) async fn receive_datagram(
+        self: &Arc<Self>,
+        data: &mut [u8],
+        source: SocketAddrV4,
+        query_subscribers: &QuerySubscribers,
+    ) -> Result<()> {
+        let message_subscribers = self
+            .message_subscribers
+            .get()
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+
+        self.handle_received_data(
+            PacketView::from(data),
+            source,
+            self.clock.instant(),
+            message_subscribers,
+            query_subscribers,
+        )
+        .await
+    }
+
+    /// Encrypts the packet contents with the established channel to the specified peer.
+    /// Returns `false` if there is no such channel.
+    pub(crate) fn encrypt_for_channel(
+        &self,
+        local_id: &NodeIdShort,
+        peer_id: &NodeIdShort,
+        data: &mut Vec<u8>,
+    ) -> bool {
+        match self.channels_by_peers.get(&(*local_id, *peer_id)) {
+            Some(channel) => {
+                channel.encrypt(data, false, None);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use everscale_crypto::tl;
+    use rand::Rng;
+
+    use super::*;
+    use crate::adnl::{Key, Keystore};
+    use crate::util::fixtures::make_adnl_node;
+
+    /// Builds handshake packet contents with a nop message from the specified key
+    fn make_contents(from: tl::PublicKey<'_>, signer: Option<&Key>) -> Vec<u8> {
+        let message = tl_proto::serialize(proto::adnl::Message::Nop);
+        let mut packet = proto::adnl::OutgoingPacketContents {
+            rand1: &[1, 2, 3],
+            from: Some(from),
+            messages: proto::adnl::OutgoingMessages::Single(&message),
+            address: AddressListBuilder::new()
+                .with_address(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 30303))
+                .build(),
+            seqno: 1,
+            confirm_seqno: 0,
+            reinit_dates: None,
+            signature: None,
+            rand2: &[4, 5, 6, 7, 8, 9, 10],
+        };
+        let signature = match signer {
+            Some(signer) => signer.sign(&packet),
+            None => [0; 64],
+        };
+        packet.signature = Some(&signature);
+        tl_proto::serialize(packet)
+    }
+
+    async fn feed(node: &Arc<Node>, mut packet: Vec<u8>) -> Result<()> {
+        node.handle_received_data(
+            PacketView::from(packet.as_mut_slice()),
+            SocketAddrV4::new(Ipv4Addr::LOCALHOST, 30303),
+            Instant::now(),
+            &[],
+            &QuerySubscribers::new(Vec::new()),
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn malformed_contents_are_counted() {
+        let node = make_adnl_node(Default::default());
+        let local_id = *node.key_by_tag(0).unwrap().full_id();
+        let peer_key = Key::from_bytes(rand::random());
+
+        // Public key which is not a valid curve point
+        let invalid_key = std::iter::repeat_with(rand::random::<[u8; 32]>)
+            .find(|key| ed25519::PublicKey::from_bytes(*key).is_none())
+            .unwrap();
+        let contents = make_contents(tl::PublicKey::Ed25519 { key: &invalid_key }, None);
+        let packet = build_handshake_packet(&local_id, &contents, None);
+        assert!(feed(&node, packet).await.is_err());
+        assert_eq!(node.metrics().packets_dropped.parse_error, 1);
+
+        // Invalid signature
+        let contents = make_contents(peer_key.full_id().as_tl(), None);
+        let packet = build_handshake_packet(&local_id, &contents, None);
+        assert!(feed(&node, packet).await.is_err());
+        assert_eq!(node.metrics().packets_dropped.invalid_signature, 1);
+
+        // Valid packet
+        let contents = make_contents(peer_key.full_id().as_tl(), Some(&peer_key));
+        let packet = build_handshake_packet(&local_id, &contents, None);
+        feed(&node, packet).await.unwrap();
+        assert_eq!(node.metrics().peer_count, 1);
+    }
+
+    #[tokio::test]
+    async fn handshake_key_is_selected_by_id() {
+        let mut keystore = Keystore::builder();
+        for tag in 0..12 {
+            keystore = keystore.with_tagged_key(rand::random(), tag).unwrap();
+        }
+        let node = Node::new(
+            SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0),
+            keystore.build(),
+            Default::default(),
+            None,
+        )
+        .unwrap();
+        let make_signed_contents = || {
+            let peer_key = Key::from_bytes(rand::random());
+            make_contents(peer_key.full_id().as_tl(), Some(&peer_key))
+        };
+
+        // Any of the local keys
+        for tag in [0, 11] {
+            let contents = make_signed_contents();
+            let local_id = *node.key_by_tag(tag).unwrap().full_id();
+            let packet = build_handshake_packet(&local_id, &contents, None);
+            feed(&node, packet).await.unwrap();
+        }
+        let metrics = node.metrics();
+        assert_eq!(metrics.traffic.handshake_packets_received, 2);
+
+        // Unknown destination id is dropped without decryption
+        let unknown = Key::from_bytes(rand::random());
+        let packet = build_handshake_packet(unknown.full_id(), &make_signed_contents(), None);
+        feed(&node, packet).await.unwrap();
+        let metrics = node.metrics();
+        assert_eq!(metrics.traffic.handshake_packets_received, 2);
+        assert_eq!(metrics.traffic.packets_to_unknown_local_id, 1);
+        assert_eq!(metrics.packets_dropped.unknown_channel, 1);
+        assert_eq!(metrics.packets_dropped.checksum_mismatch, 0);
+    }
+
+    #[tokio::test]
+    async fn arbitrary_packets_never_panic() {
+        let node = make_adnl_node(Default::default());
+        let local_id = *node.key_by_tag(0).unwrap().full_id();
+        let peer_key = Key::from_bytes(rand::random());
+        let contents = make_contents(peer_key.full_id().as_tl(), Some(&peer_key));
+
+        let mut rng = rand::thread_rng();
+        for _ in 0..500 {
+            let packet = match rng.gen_range(0..4) {
+                // Random bytes
+                0 => (0..rng.gen_range(0..256)).map(|_| rng.gen()).collect(),
+                // Truncated contents
+                1 => {
+                    let len = rng.gen_range(0..=contents.len());
+                    build_handshake_packet(&local_id, &contents[..len], None)
+                }
+                // Corrupted contents
+                2 => {
+                    let mut contents = contents.clone();
+                    for _ in 0..rng.gen_range(1..4) {
+                        let i = rng.gen_range(0..contents.len());
+                        contents[i] = rng.gen();
+                    }
+                    build_handshake_packet(&local_id, &contents, None)
+                }
+                // Random contents
+                _ => {
+                    let contents = (0..rng.gen_range(0..256))
+                        .map(|_| rng.gen())
+                        .collect::<Vec<u8>>();
+                    build_handshake_packet(&local_id, &contents, None)
+                }
+            };
+            feed(&node, packet).await.ok();
+        }
+
+        let drops = node.metrics().packets_dropped;
+        assert!(drops.parse_error > 0);
+        assert!(drops.invalid_signature > 0);
+    }
 }
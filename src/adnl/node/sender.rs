@@ -1,10 +1,10 @@
 use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 
 use anyhow::Result;
 use sha2::Digest;
 use tl_proto::TlWrite;
-use tokio::net::UdpSocket;
 use tokio::sync::mpsc;
 
 use crate::adnl::channel::*;
@@ -12,37 +12,54 @@ use crate::adnl::handshake::*;
 use crate::adnl::keystore::Key;
 use crate::adnl::node_id::NodeIdShort;
 use crate::adnl::peer::*;
+use crate::adnl::queries_cache::QueryId;
+use crate::adnl::send_queue::{PacketToSend, SendQueue, SendQueuePolicy};
+use crate::adnl::socket::NodeSocket;
 use crate::adnl::Node;
 
 use crate::proto;
 use crate::util::*;
 
 impl Node {
-    /// Starts a process that forwards packets from the sender queue to the UDP socket
+    /// Starts a process that forwards packets from the peer send queues to the socket
     pub(super) fn start_sender(
         self: &Arc<Self>,
-        socket: Arc<UdpSocket>,
+        socket: NodeSocket,
         mut sender_queue_rx: SenderQueueRx,
     ) {
         use futures_util::future::{select, Either};
 
-        let complete_signal = self.cancellation_token.clone();
+        let complete_signal = self.tasks.token().clone();
+        let packet_buffers = self.packet_buffers.clone();
+        let sender_queue_tx = self.sender_queue_tx.clone();
+        let traffic = self.traffic.clone();
 
-        tokio::spawn(async move {
+        self.tasks.spawn(&self.runtime, "adnl_sender", async move {
             tokio::pin!(let cancelled = complete_signal.cancelled(););
 
-            while let Some(packet) = {
+            while let Some(queue) = {
                 tokio::pin!(let recv = sender_queue_rx.recv(););
                 match select(recv, &mut cancelled).await {
-                    Either::Left((packet, _)) => packet,
+                    Either::Left((queue, _)) => queue,
                     Either::Right(_) => {
                         tracing::debug!("sender loop finished");
                         return;
                     }
                 }
             } {
-                // Send packet
-                socket.send_to(&packet.data, packet.destination).await.ok();
+                let (packet, has_more) = queue.pop();
+
+                // Send one packet per queue at a time to share the socket between peers
+                if has_more {
+                    sender_queue_tx.send(queue).ok();
+                }
+
+                if let Some(packet) = packet {
+                    if let Ok(len) = socket.send_to(&packet.data, packet.destination).await {
+                        traffic.add_sent(len);
+                    }
+                    packet_buffers.put(packet.data);
+                }
             }
         });
     }
@@ -53,6 +70,29 @@ impl Node {
         peer_id: &NodeIdShort,
         message: proto::adnl::Message,
         priority: bool,
+    ) -> Result<()> {
+        self.send_message_impl(local_id, peer_id, message, priority, None)
+    }
+
+    /// Sends the message in a handshake packet without channel messages
+    /// to the specified address, see [`NodeOptions::allow_channelless_queries`]
+    pub(super) fn send_channelless_message(
+        &self,
+        local_id: &NodeIdShort,
+        peer_id: &NodeIdShort,
+        message: proto::adnl::Message,
+        destination: SocketAddrV4,
+    ) -> Result<()> {
+        self.send_message_impl(local_id, peer_id, message, false, Some(destination))
+    }
+
+    fn send_message_impl(
+        &self,
+        local_id: &NodeIdShort,
+        peer_id: &NodeIdShort,
+        message: proto::adnl::Message,
+        priority: bool,
+        destination: Option<SocketAddrV4>,
     ) -> Result<()> {
         const MAX_ADNL_MESSAGE_SIZE: usize = 1024;
 
@@ -63,6 +103,8 @@ impl Node {
         const MSG_NOP_SIZE: usize = 4;
         const MSG_QUERY_SIZE: usize = 44;
         const MSG_PART_PREFIX_SIZE: usize = 40;
+        /// Part message size without data (including the max bytes header and alignment)
+        const MSG_PART_MAX_OVERHEAD: usize = 44 + 4 + 3;
 
         // Find peer by id
         let peers = self.get_peers(local_id)?;
@@ -72,11 +114,23 @@ impl Node {
         };
         let peer = peer.value();
 
+        let options = self.options.load();
+
+        // Queries are never dropped with `SendQueuePolicy::BlockQueries`
+        let force = matches!(message, proto::adnl::Message::Query { .. })
+            && options.peer_send_queue_policy == SendQueuePolicy::BlockQueries;
+
         // Get local key
         let local_key = self.keystore.key_by_id(local_id)?;
-        let channel = self.channels_by_peers.get(peer_id);
-        let mut force_handshake = false;
+        let channel = self.channels_by_peers.get(&(*local_id, *peer_id));
+        let channelless = destination.is_some()
+            || options.allow_channelless_queries
+                && matches!(message, proto::adnl::Message::Query { .. })
+                && !matches!(&channel, Some(channel) if channel.ready());
+
+        let mut force_handshake = channelless;
         let (additional_size, additional_message) = match &channel {
+            _ if channelless => (0, None),
             Some(channel) if channel.ready() => (0, None),
             Some(channel_data) => {
                 tracing::trace!(%local_id, %peer_id, "sending ConfirmChannel");
@@ -93,12 +147,13 @@ impl Node {
             }
             None => {
                 tracing::trace!(%local_id, %peer_id, "sending CreateChannel");
+                let date = self.begin_channel_setup(local_id, peer_id);
 
                 (
                     MSG_CREATE_CHANNEL_SIZE,
                     Some(proto::adnl::Message::CreateChannel {
                         key: peer.channel_key().public_key.as_bytes(),
-                        date: now(),
+                        date,
                     }),
                 )
             }
@@ -122,21 +177,37 @@ impl Node {
             _ => MessageSigner::Random(local_key),
         };
 
-        if size <= MAX_ADNL_MESSAGE_SIZE {
-            let mut buffer = Vec::with_capacity(size);
-            let messages = match additional_message {
-                Some(additional_message) => {
-                    additional_message.write_to(&mut buffer);
-                    message.write_to(&mut buffer);
-                    proto::adnl::OutgoingMessages::Pair(&buffer)
-                }
-                None => {
-                    message.write_to(&mut buffer);
-                    proto::adnl::OutgoingMessages::Single(&buffer)
-                }
-            };
+        let max_datagram_size = peer
+            .probed_datagram_size()
+            .unwrap_or(options.max_datagram_size);
+        let datagram = DatagramLimit {
+            signer,
+            version: options.version,
+            max_size: max_datagram_size,
+        };
+        let mut frame = self.make_packet_frame(peer, true);
+        if let Some(destination) = destination {
+            frame.destination = destination;
+        }
+        let pair = additional_message.is_some();
+
+        if size <= MAX_ADNL_MESSAGE_SIZE && datagram.trim_to_fit(&mut frame, pair, size) {
+            with_serialize_buffer(|buffer| {
+                buffer.reserve(size);
+                let messages = match additional_message {
+                    Some(additional_message) => {
+                        additional_message.write_to(buffer);
+                        message.write_to(buffer);
+                        proto::adnl::OutgoingMessages::Pair(buffer)
+                    }
+                    None => {
+                        message.write_to(buffer);
+                        proto::adnl::OutgoingMessages::Single(buffer)
+                    }
+                };
 
-            self.send_packet(peer_id, peer, signer, messages)
+                self.send_packet(peer_id, peer, signer, messages, &frame, force)
+            })
         } else {
             pub fn build_part_message<'a>(
                 data: &'a [u8],
@@ -157,66 +228,190 @@ impl Node {
                     },
                 };
 
-                *offset += len;
+                *offset = len;
                 result
             }
 
-            let data = tl_proto::serialize(message);
-            let hash: [u8; 32] = sha2::Sha256::digest(&data).into();
-            let mut offset = 0;
-
-            let mut buffer = Vec::with_capacity(MAX_ADNL_MESSAGE_SIZE);
-            if let Some(additional_message) = additional_message {
-                additional_message.write_to(&mut buffer);
-
-                let message = build_part_message(
-                    &data,
-                    &hash,
-                    MAX_ADNL_MESSAGE_SIZE - MSG_PART_PREFIX_SIZE - additional_size,
-                    &mut offset,
-                );
-                message.write_to(&mut buffer);
-
-                ok!(self.send_packet(
-                    peer_id,
-                    peer,
-                    signer,
-                    proto::adnl::OutgoingMessages::Pair(&buffer),
-                ));
+            // Each part must carry at least one byte of the message
+            if !datagram.trim_to_fit(
+                &mut frame,
+                pair,
+                additional_size + MSG_PART_MAX_OVERHEAD + 1,
+            ) {
+                return Err(AdnlSenderError::DatagramSizeTooSmall(max_datagram_size).into());
             }
 
-            while offset < data.len() {
-                buffer.clear();
-                let message = build_part_message(&data, &hash, MAX_ADNL_MESSAGE_SIZE, &mut offset);
-                message.write_to(&mut buffer);
-
-                ok!(self.send_packet(
-                    peer_id,
-                    peer,
-                    signer,
-                    proto::adnl::OutgoingMessages::Single(&buffer),
-                ));
+            let first_part_len = std::cmp::min(
+                MAX_ADNL_MESSAGE_SIZE - MSG_PART_PREFIX_SIZE - additional_size,
+                datagram.available(&frame, pair) - MSG_PART_MAX_OVERHEAD - additional_size,
+            );
+            let part_len = std::cmp::min(
+                MAX_ADNL_MESSAGE_SIZE,
+                datagram.available(&frame, false) - MSG_PART_MAX_OVERHEAD,
+            );
+
+            with_serialize_buffer(|data| {
+                message.write_to(data);
+                let hash: [u8; 32] = sha2::Sha256::digest(&data).into();
+                let mut offset = 0;
+
+                with_serialize_buffer(|buffer| {
+                    if let Some(additional_message) = additional_message {
+                        additional_message.write_to(buffer);
+
+                        let message = build_part_message(data, &hash, first_part_len, &mut offset);
+                        message.write_to(buffer);
+
+                        ok!(self.send_packet(
+                            peer_id,
+                            peer,
+                            signer,
+                            proto::adnl::OutgoingMessages::Pair(buffer),
+                            &frame,
+                            force,
+                        ));
+                    }
+
+                    while offset < data.len() {
+                        buffer.clear();
+                        let message = build_part_message(data, &hash, part_len, &mut offset);
+                        message.write_to(buffer);
+
+                        ok!(self.send_packet(
+                            peer_id,
+                            peer,
+                            signer,
+                            proto::adnl::OutgoingMessages::Single(buffer),
+                            &frame,
+                            force,
+                        ));
+                    }
+
+                    Ok(())
+                })
+            })
+        }
+    }
+
+    /// Sends the query over the ready channel in a datagram of exactly `datagram_size` bytes
+    /// (rounded down to 4 bytes). The packet is padded with zeros.
+    ///
+    /// Returns the actual datagram size
+    pub(super) fn send_padded_query(
+        &self,
+        local_id: &NodeIdShort,
+        peer_id: &NodeIdShort,
+        query_id: &QueryId,
+        query: &[u8],
+        datagram_size: usize,
+    ) -> Result<usize> {
+        let peers = self.get_peers(local_id)?;
+        let peer = match peers.get(peer_id) {
+            Some(peer) => peer,
+            None => return Err(AdnlSenderError::UnknownPeer.into()),
+        };
+        let peer = peer.value();
+
+        let channel = match self.channels_by_peers.get(&(*local_id, *peer_id)) {
+            Some(channel) if channel.ready() => channel,
+            _ => return Err(AdnlSenderError::NoReadyChannel.into()),
+        };
+        let signer = MessageSigner::Channel {
+            channel: channel.value(),
+            priority: false,
+        };
+
+        with_serialize_buffer(|buffer| {
+            proto::adnl::Message::Query {
+                query_id: query_id.as_slice(),
+                query,
+            }
+            .write_to(buffer);
+
+            let datagram = DatagramLimit {
+                signer,
+                version: self.options.load().version,
+                max_size: datagram_size,
+            };
+            let mut frame = self.make_packet_frame(peer, false);
+
+            let available = datagram.available(&frame, false);
+            if available < buffer.len() {
+                return Err(AdnlSenderError::DatagramSizeTooSmall(datagram_size).into());
             }
 
-            Ok(())
+            // Size of the `rand1` field with bytes header and alignment
+            let field_size = ((available - buffer.len()) & !3) + 4;
+            frame.zero_padding = if field_size <= 256 {
+                field_size - 3
+            } else {
+                field_size - 4
+            };
+            let size = datagram.packet_size(&frame, false) + buffer.len();
+
+            ok!(self.send_packet(
+                peer_id,
+                peer,
+                signer,
+                proto::adnl::OutgoingMessages::Single(buffer),
+                &frame,
+                false,
+            ));
+            Ok(size)
+        })
+    }
+
+    /// Prepares optional packet fields for the peer
+    fn make_packet_frame(&self, peer: &Peer, random_padding: bool) -> PacketFrame {
+        // Adjust socket addr
+        let mut local_addr = self.socket_addr;
+        let mut peer_addr = peer.addr();
+
+        if self.options.load().use_loopback_for_neighbours
+            && local_addr.ip() == peer_addr.ip()
+            && !peer_addr.ip().is_loopback()
+        {
+            local_addr.set_ip(Ipv4Addr::LOCALHOST);
+            peer_addr.set_ip(Ipv4Addr::LOCALHOST);
+        }
+
+        let now = self.clock.now();
+        let expire_at = now + self.options.load().address_list_timeout_sec;
+        let mut address = match self.address_list.get() {
+            Some(address_list) => address_list.build_with_defaults(now, self.start_time, expire_at),
+            None => proto::adnl::AddressList {
+                addresses: smallvec::smallvec![proto::adnl::Address::from(&local_addr)],
+                version: now,
+                reinit_date: self.start_time,
+                priority: 0,
+                expire_at,
+            },
+        };
+        peer.compat().apply_to_address_list(&mut address);
+
+        PacketFrame {
+            destination: peer_addr,
+            address,
+            random_padding,
+            zero_padding: 0,
         }
     }
 
-    /// Encodes and sends packet to the peer
+    /// Encodes and enqueues packet to the peer.
+    ///
+    /// Forced packets are enqueued even if the peer send queue is full
     fn send_packet(
         &self,
         peer_id: &NodeIdShort,
         peer: &Peer,
         mut signer: MessageSigner,
         messages: proto::adnl::OutgoingMessages,
+        frame: &PacketFrame,
+        force: bool,
     ) -> Result<()> {
-        const MAX_PRIORITY_ATTEMPTS: u64 = 10;
-
         // Determine whether priority channels are supported by remote peer
         let priority = if let MessageSigner::Channel { priority, .. } = &mut signer {
-            if peer.receiver_state().history(*priority).seqno() == 0
-                && peer.sender_state().history(true).seqno() > MAX_PRIORITY_ATTEMPTS
-            {
+            if !peer.compat().allows_priority_channel() {
                 *priority = false;
             }
             *priority
@@ -225,39 +420,20 @@ impl Node {
             false
         };
 
-        // Adjust socket addr
-        let mut local_addr = self.socket_addr;
-        let mut peer_addr = peer.addr();
-
-        if self.options.use_loopback_for_neighbours
-            && local_addr.ip() == peer_addr.ip()
-            && !peer_addr.ip().is_loopback()
-        {
-            local_addr.set_ip(Ipv4Addr::LOCALHOST);
-            peer_addr.set_ip(Ipv4Addr::LOCALHOST);
-        }
-
         // Generate on-stack random data
         let rand_bytes: [u8; 10] = gen_fast_bytes();
-
-        let now = now();
-        let address = proto::adnl::AddressList {
-            address: Some(proto::adnl::Address::from(&local_addr)),
-            version: now,
-            reinit_date: self.start_time,
-            expire_at: now + self.options.address_list_timeout_sec,
-        };
+        let (rand1, rand2) = frame.padding(&rand_bytes);
 
         let mut packet = proto::adnl::OutgoingPacketContents {
-            rand1: &rand_bytes[..3],
+            rand1,
             from: match signer {
                 MessageSigner::Channel { .. } => None,
                 MessageSigner::Random(local_key) => Some(local_key.full_id().as_tl()),
             },
             messages,
-            address,
+            address: frame.address.clone(),
             seqno: peer.sender_state().history(priority).bump_seqno(),
-            confirm_seqno: peer.receiver_state().history(priority).seqno(),
+            confirm_seqno: peer.receiver_state().history(priority).contiguous_seqno(),
             reinit_dates: match signer {
                 MessageSigner::Channel { .. } => None,
                 MessageSigner::Random(_) => Some(proto::adnl::ReinitDates {
@@ -266,7 +442,7 @@ impl Node {
                 }),
             },
             signature: None,
-            rand2: &rand_bytes[3..],
+            rand2,
         };
 
         let signature = match signer {
@@ -277,13 +453,13 @@ impl Node {
         packet.signature = signature.as_ref().map(<[u8; 64]>::as_slice);
 
         // Serialize packet
-        let adnl_version = self.options.version;
+        let adnl_version = self.options.load().version;
         let prefix_len = match &signer {
             MessageSigner::Channel { .. } => Channel::compute_prefix_len(adnl_version),
             MessageSigner::Random(..) => compute_handshake_prefix_len(adnl_version),
         };
 
-        let mut data = Vec::with_capacity(prefix_len + packet.max_size_hint());
+        let mut data = self.packet_buffers.get(prefix_len + packet.max_size_hint());
         packet.write_to(&mut data);
 
         match signer {
@@ -291,18 +467,43 @@ impl Node {
                 channel.encrypt(&mut data, priority, adnl_version)
             }
             MessageSigner::Random(_) => {
-                build_handshake_packet(peer_id, peer.id(), &mut data, adnl_version)
+                build_handshake_packet_in_place(peer_id, peer.id(), &mut data, adnl_version);
+                self.handshake_packets_sent.fetch_add(1, Ordering::Relaxed);
             }
         }
 
-        if self
-            .sender_queue_tx
-            .send(PacketToSend {
-                destination: peer_addr,
+        debug_assert_eq!(
+            data.len(),
+            DatagramLimit {
+                signer,
+                version: adnl_version,
+                max_size: 0,
+            }
+            .packet_size(frame, !packet.messages.is_single())
+                + match packet.messages {
+                    proto::adnl::OutgoingMessages::Single(raw)
+                    | proto::adnl::OutgoingMessages::Pair(raw) => raw.len(),
+                }
+        );
+
+        let options = self.options.load();
+        let queue = peer.send_queue();
+        let result = queue.push(
+            PacketToSend {
+                destination: frame.destination,
                 data,
-            })
-            .is_err()
-        {
+            },
+            options.peer_send_queue_capacity,
+            options.peer_send_queue_policy,
+            force,
+        );
+
+        if let Some(dropped) = result.dropped {
+            self.packets_send_dropped.fetch_add(1, Ordering::Relaxed);
+            self.packet_buffers.put(dropped.data);
+        }
+
+        if result.schedule && self.sender_queue_tx.send(queue.clone()).is_err() {
             return Err(AdnlSenderError::FailedToSendPacket.into());
         }
 
@@ -310,6 +511,101 @@ impl Node {
     }
 }
 
+/// Optional packet fields which can be trimmed to fit into the datagram
+struct PacketFrame {
+    destination: SocketAddrV4,
+    /// Local address list (in the order of preference)
+    address: proto::adnl::AddressList,
+    /// Whether to fill `rand1` and `rand2` with random bytes
+    random_padding: bool,
+    /// Number of zero bytes in `rand1` (overrides random bytes)
+    zero_padding: usize,
+}
+
+impl PacketFrame {
+    /// Returns `rand1` and `rand2` fields
+    fn padding<'a>(&self, rand_bytes: &'a [u8; 10]) -> (&'a [u8], &'a [u8]) {
+        static ZEROS: [u8; MAX_PADDING] = [0; MAX_PADDING];
+
+        if self.zero_padding > 0 {
+            (&ZEROS[..self.zero_padding.min(MAX_PADDING)], &[])
+        } else if self.random_padding {
+            (&rand_bytes[..3], &rand_bytes[3..])
+        } else {
+            (&[], &[])
+        }
+    }
+}
+
+/// Max number of zero padding bytes
+const MAX_PADDING: usize = 4096;
+
+/// Datagram size limit for the packets of the specified signer
+#[derive(Copy, Clone)]
+struct DatagramLimit<'a> {
+    signer: MessageSigner<'a>,
+    version: Option<u16>,
+    max_size: usize,
+}
+
+impl DatagramLimit<'_> {
+    /// Serialized packet size without messages
+    fn packet_size(&self, frame: &PacketFrame, pair: bool) -> usize {
+        const SIGNATURE_SIZE: usize = 68; // 1 byte length, 64 bytes data, 3 bytes padding
+        const REINIT_DATES_SIZE: usize = 8;
+
+        let rand_bytes = [0; 10];
+        let (rand1, rand2) = frame.padding(&rand_bytes);
+
+        let (prefix_len, signed_size) = match self.signer {
+            MessageSigner::Channel { .. } => (Channel::compute_prefix_len(self.version), 0),
+            MessageSigner::Random(key) => (
+                compute_handshake_prefix_len(self.version),
+                key.full_id().as_tl().max_size_hint() + REINIT_DATES_SIZE + SIGNATURE_SIZE,
+            ),
+        };
+
+        prefix_len
+            + 4 // constructor
+            + rand1.max_size_hint()
+            + 4 // flags
+            + signed_size
+            + if pair { 4 } else { 0 } // messages vector length
+            + frame.address.max_size_hint()
+            + 8 // seqno
+            + 8 // confirm_seqno
+            + rand2.max_size_hint()
+    }
+
+    /// Max size of messages which fit into the datagram
+    fn available(&self, frame: &PacketFrame, pair: bool) -> usize {
+        self.max_size.saturating_sub(self.packet_size(frame, pair))
+    }
+
+    /// Removes random padding and then less preferred addresses until
+    /// `messages_size` fits into the datagram. Returns `false` if it doesn't fit
+    fn trim_to_fit(&self, frame: &mut PacketFrame, pair: bool, messages_size: usize) -> bool {
+        let fits = |frame: &PacketFrame| self.available(frame, pair) >= messages_size;
+        if fits(frame) {
+            return true;
+        }
+
+        frame.random_padding = false;
+        if fits(frame) {
+            return true;
+        }
+
+        while frame.address.addresses.len() > 1 {
+            frame.address.addresses.pop();
+            if fits(frame) {
+                return true;
+            }
+        }
+
+        false
+    }
+}
+
 #[derive(Copy, Clone)]
 enum MessageSigner<'a> {
     Channel {
@@ -319,13 +615,9 @@ enum MessageSigner<'a> {
     Random(&'a Arc<Key>),
 }
 
-pub struct PacketToSend {
-    destination: SocketAddrV4,
-    data: Vec<u8>,
-}
-
-pub type SenderQueueTx = mpsc::UnboundedSender<PacketToSend>;
-pub type SenderQueueRx = mpsc::UnboundedReceiver<PacketToSend>;
+/// Peer send queues which have packets to send
+pub type SenderQueueTx = mpsc::UnboundedSender<Arc<SendQueue>>;
+pub type SenderQueueRx = mpsc::UnboundedReceiver<Arc<SendQueue>>;
 
 #[derive(thiserror::Error, Debug)]
 enum AdnlSenderError {
@@ -335,4 +627,8 @@ enum AdnlSenderError {
     UnexpectedMessageToSend,
     #[error("Failed to send ADNL packet")]
     FailedToSendPacket,
+    #[error("No ready channel with the peer")]
+    NoReadyChannel,
+    #[error("Max datagram size {0} is too small")]
+    DatagramSizeTooSmall(usize),
 }
@@ -0,0 +1,120 @@
+use std::time::Duration;
+
+use super::Node;
+use crate::util::TaskGroup;
+
+/// Protocol on top of the ADNL node (RLDP, overlays) which must be shut down before it
+pub(crate) struct DependentLayer {
+    name: &'static str,
+    tasks: TaskGroup,
+}
+
+impl DependentLayer {
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Background tasks of the layer, cancelled with the ADNL node
+    pub fn tasks(&self) -> &TaskGroup {
+        &self.tasks
+    }
+
+    pub fn is_shut_down(&self) -> bool {
+        self.tasks.is_cancelled()
+    }
+
+    /// Cancels all background tasks of the layer
+    pub fn shutdown(&self) {
+        self.tasks.cancel();
+    }
+}
+
+impl Node {
+    /// Stops all background tasks of the node.
+    ///
+    /// Supported shutdown order is top to bottom: overlays ([`overlay::Node::shutdown`]),
+    /// RLDP ([`rldp::Node::shutdown`]) and then ADNL. Layers which are still running
+    /// are shut down here with a warning. Waits up to [`NodeOptions::shutdown_timeout_ms`]
+    /// for the tasks of the layers and then for the own tasks.
+    ///
+    /// [`overlay::Node::shutdown`]: crate::overlay::Node::shutdown
+    /// [`rldp::Node::shutdown`]: crate::rldp::Node::shutdown
+    /// [`NodeOptions::shutdown_timeout_ms`]: super::NodeOptions::shutdown_timeout_ms
+    pub async fn shutdown(&self) {
+        let layers = self.layers.lock().clone();
+        for layer in &layers {
+            if !layer.is_shut_down() {
+                tracing::warn!(
+                    layer = layer.name(),
+                    "layer is still running, shutting it down"
+                );
+                layer.shutdown();
+            }
+        }
+
+        let timeout = Duration::from_millis(self.options.load().shutdown_timeout_ms);
+        let mut deadline = self.clock.sleep(timeout);
+
+        let layers_stopped =
+            futures_util::future::join_all(layers.iter().map(|l| l.tasks().wait()));
+        tokio::select! {
+            _ = layers_stopped => {}
+            _ = &mut deadline => {
+                for layer in &layers {
+                    let tasks = layer.tasks().len();
+                    if tasks > 0 {
+                        tracing::warn!(layer = layer.name(), tasks, "layer tasks did not stop in time");
+                    }
+                }
+            }
+        }
+
+        self.tasks.cancel();
+        tokio::select! {
+            _ = self.tasks.wait() => {}
+            _ = deadline => {
+                tracing::warn!(tasks = self.tasks.len(), "ADNL tasks did not stop in time");
+            }
+        }
+        tracing::debug!("ADNL node shut down");
+    }
+
+    /// Cancels all tasks without waiting for them
+    pub(crate) fn abort(&self) {
+        for layer in self.layers.lock().iter() {
+            layer.shutdown();
+        }
+        self.tasks.cancel();
+    }
+
+    /// Registers the protocol which must be shut down before the node.
+    /// Its tasks are also cancelled with the node
+    #[cfg(feature = "rldp")]
+    pub(crate) fn register_layer(&self, name: &'static str) -> std::sync::Arc<DependentLayer> {
+        let layer = std::sync::Arc::new(DependentLayer {
+            name,
+            tasks: self.tasks.child(),
+        });
+        self.layers.lock().push(layer.clone());
+        layer
+    }
+
+    /// Spawns a task which is cancelled with the node
+    #[cfg(feature = "dht")]
+    pub(crate) fn spawn_task<F>(&self, name: &'static str, future: F)
+    where
+        F: std::future::Future<Output = ()> + Send + 'static,
+    {
+        self.tasks.spawn(&self.runtime, name, future);
+    }
+}
+
+impl Drop for Node {
+    fn drop(&mut self) {
+        if !self.tasks.is_cancelled() && self.init_state.get_mut().is_none() {
+            tracing::warn!("ADNL node was dropped without shutdown");
+        }
+        // Cancel all tasks on drop
+        self.abort();
+    }
+}
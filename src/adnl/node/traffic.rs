@@ -0,0 +1,98 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Total number of processed packets, queries and messages
+#[derive(Debug, Default, Copy, Clone, serde::Serialize)]
+pub struct TrafficMetrics {
+    /// Datagrams received from the socket (including dropped ones)
+    #[serde(rename = "packets_received_total")]
+    pub packets_received: u64,
+    /// Total size of the received datagrams in bytes
+    #[serde(rename = "bytes_received_total")]
+    pub bytes_received: u64,
+    /// Datagrams sent to the socket
+    #[serde(rename = "packets_sent_total")]
+    pub packets_sent: u64,
+    /// Total size of the sent datagrams in bytes
+    #[serde(rename = "bytes_sent_total")]
+    pub bytes_sent: u64,
+    /// Authentic incoming handshake packets (packets without channel)
+    #[serde(rename = "handshake_packets_received_total")]
+    pub handshake_packets_received: u64,
+    /// Packets addressed to an id which is neither a local key nor a channel.
+    /// They are dropped before any key exchange.
+    ///
+    /// NOTE: key and channel ids take the same place in the packet, so the packets
+    /// of the unknown channels are counted here too (as `packets_dropped.unknown_channel`)
+    #[serde(rename = "packets_to_unknown_local_id_total")]
+    pub packets_to_unknown_local_id: u64,
+    /// Outgoing ADNL queries
+    #[serde(rename = "queries_sent_total")]
+    pub queries_sent: u64,
+    /// Outgoing ADNL queries which were answered in time
+    #[serde(rename = "queries_answered_total")]
+    pub queries_answered: u64,
+    /// Outgoing ADNL queries which were not answered in time
+    #[serde(rename = "queries_timed_out_total")]
+    pub queries_timed_out: u64,
+    /// Incoming ADNL queries which were consumed by the subscribers
+    #[serde(rename = "queries_consumed_total")]
+    pub queries_consumed: u64,
+    /// Incoming ADNL queries which no subscriber has consumed
+    #[serde(rename = "queries_unhandled_total")]
+    pub queries_unhandled: u64,
+    /// Incoming custom messages which were consumed by the subscribers
+    #[serde(rename = "messages_consumed_total")]
+    pub messages_consumed: u64,
+    /// Incoming custom messages which no subscriber has consumed
+    #[serde(rename = "messages_unhandled_total")]
+    pub messages_unhandled: u64,
+}
+
+#[derive(Default)]
+pub(super) struct Traffic {
+    pub packets_received: AtomicU64,
+    pub bytes_received: AtomicU64,
+    pub packets_sent: AtomicU64,
+    pub bytes_sent: AtomicU64,
+    pub handshake_packets_received: AtomicU64,
+    pub packets_to_unknown_local_id: AtomicU64,
+    pub queries_sent: AtomicU64,
+    pub queries_answered: AtomicU64,
+    pub queries_timed_out: AtomicU64,
+    pub queries_consumed: AtomicU64,
+    pub queries_unhandled: AtomicU64,
+    pub messages_consumed: AtomicU64,
+    pub messages_unhandled: AtomicU64,
+}
+
+impl Traffic {
+    /// Counts received datagram
+    pub fn add_received(&self, len: usize) {
+        self.packets_received.fetch_add(1, Ordering::Relaxed);
+        self.bytes_received.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    /// Counts sent datagram
+    pub fn add_sent(&self, len: usize) {
+        self.packets_sent.fetch_add(1, Ordering::Relaxed);
+        self.bytes_sent.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    pub fn metrics(&self) -> TrafficMetrics {
+        TrafficMetrics {
+            packets_received: self.packets_received.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            packets_sent: self.packets_sent.load(Ordering::Relaxed),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            handshake_packets_received: self.handshake_packets_received.load(Ordering::Relaxed),
+            packets_to_unknown_local_id: self.packets_to_unknown_local_id.load(Ordering::Relaxed),
+            queries_sent: self.queries_sent.load(Ordering::Relaxed),
+            queries_answered: self.queries_answered.load(Ordering::Relaxed),
+            queries_timed_out: self.queries_timed_out.load(Ordering::Relaxed),
+            queries_consumed: self.queries_consumed.load(Ordering::Relaxed),
+            queries_unhandled: self.queries_unhandled.load(Ordering::Relaxed),
+            messages_consumed: self.messages_consumed.load(Ordering::Relaxed),
+            messages_unhandled: self.messages_unhandled.load(Ordering::Relaxed),
+        }
+    }
+}
@@ -4,6 +4,8 @@ use std::convert::TryFrom;
 use everscale_crypto::{ed25519, tl};
 use rand::Rng;
 
+use crate::util::*;
+
 /// Full ADNL node id.
 ///
 /// See [`PublicKey::Ed25519`]
@@ -26,7 +28,7 @@ impl NodeIdFull {
 
     /// Represents public key as a TL structure
     #[inline(always)]
-    pub fn as_tl(&self) -> tl::PublicKey {
+    pub fn as_tl(&self) -> tl::PublicKey<'_> {
         self.0.as_tl()
     }
 
@@ -42,9 +44,17 @@ impl NodeIdFull {
         }
     }
 
+    /// Verifies the signature of raw bytes
+    pub fn verify_raw(&self, data: &[u8], other_signature: &[u8]) -> Result<(), NodeIdFullError> {
+        match <[u8; 64]>::try_from(other_signature) {
+            Ok(other_signature) if self.0.verify_raw(data, &other_signature) => Ok(()),
+            _ => Err(NodeIdFullError::InvalidSignature),
+        }
+    }
+
     /// Hashes inner public key
     pub fn compute_short_id(&self) -> NodeIdShort {
-        NodeIdShort::new(tl_proto::hash(self.0.as_tl()))
+        NodeIdShort::new(hash_public_key(self.0.as_tl().into()))
     }
 }
 
@@ -107,13 +117,33 @@ impl NodeIdShort {
     }
 }
 
+impl NodeIdShort {
+    /// Parses short id from base64 string (with padding)
+    pub fn from_base64(s: &str) -> Result<Self, ParseIdError> {
+        match s.len() {
+            44 => parse_id(s).map(Self),
+            len => Err(ParseIdError::InvalidLength(len)),
+        }
+    }
+
+    /// Encodes short id as base64 string (with padding)
+    pub fn to_base64(&self) -> String {
+        id_to_base64(&self.0)
+    }
+}
+
+/// Displays short id as hex.
+///
+/// NOTE: unlike [`NodeIdShort::to_base64`] and serde, this is deliberately not base64.
+/// Ids were always displayed as hex, and existing logs and log processing depend on it.
+/// Both forms are accepted by [`FromStr`](std::str::FromStr)
 impl std::fmt::Display for NodeIdShort {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let mut output = [0u8; 64];
         hex::encode_to_slice(self.0, &mut output).ok();
 
-        // SAFETY: output is guaranteed to contain only [0-9a-f]
-        let output = unsafe { std::str::from_utf8_unchecked(&output) };
+        // NOTE: output always contains only [0-9a-f]
+        let output = std::str::from_utf8(&output).map_err(|_| std::fmt::Error)?;
         f.write_str(output)
     }
 }
@@ -125,6 +155,28 @@ impl std::fmt::Debug for NodeIdShort {
     }
 }
 
+/// Parses short id from base64 or hex string
+impl std::str::FromStr for NodeIdShort {
+    type Err = ParseIdError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_id(s).map(Self)
+    }
+}
+
+/// Serializes short id as base64 string for human-readable formats
+impl serde::Serialize for NodeIdShort {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serialize_id(&self.0, serializer)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for NodeIdShort {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserialize_id(deserializer).map(Self)
+    }
+}
+
 impl PartialEq<[u8]> for NodeIdShort {
     #[inline(always)]
     fn eq(&self, other: &[u8]) -> bool {
@@ -153,6 +205,13 @@ impl From<&NodeIdShort> for [u8; 32] {
     }
 }
 
+impl From<PublicKeyVariant<'_>> for NodeIdShort {
+    #[inline(always)]
+    fn from(key: PublicKeyVariant<'_>) -> Self {
+        Self(hash_public_key(key))
+    }
+}
+
 impl From<[u8; 32]> for NodeIdShort {
     #[inline(always)]
     fn from(id: [u8; 32]) -> Self {
@@ -167,7 +226,7 @@ impl Borrow<[u8; 32]> for NodeIdShort {
     }
 }
 
-impl<'a> Borrow<[u8; 32]> for &'a NodeIdShort {
+impl Borrow<[u8; 32]> for &NodeIdShort {
     #[inline(always)]
     fn borrow(&self) -> &[u8; 32] {
         &self.0
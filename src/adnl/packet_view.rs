@@ -1,5 +1,9 @@
-use std::ops::{Index, IndexMut, Range, RangeFrom, RangeTo};
+use std::ops::Range;
 
+/// Mutable view into the received packet.
+///
+/// Packet is decrypted in place, after which its header is marked as consumed,
+/// so that later stages see only the payload. None of the accessors panic.
 pub struct PacketView<'a> {
     bytes: &'a mut [u8],
 }
@@ -10,58 +14,84 @@ impl<'a> PacketView<'a> {
         self.bytes.as_ptr()
     }
 
+    /// Remaining (not consumed) bytes
     #[inline(always)]
-    pub const fn as_slice(&self) -> &[u8] {
+    pub const fn as_bytes(&self) -> &[u8] {
         self.bytes
     }
 
+    /// Remaining (not consumed) bytes
     #[inline(always)]
-    pub fn len(&self) -> usize {
-        self.bytes.len()
+    pub fn as_bytes_mut(&mut self) -> &mut [u8] {
+        self.bytes
     }
 
-    pub fn remove_prefix(&mut self, prefix_len: usize) {
-        let len = self.bytes.len();
-        let ptr = self.bytes.as_mut_ptr();
-        // SAFETY: `bytes` is already a reference bounded by a lifetime
-        self.bytes =
-            unsafe { std::slice::from_raw_parts_mut(ptr.add(prefix_len), len - prefix_len) };
+    /// Number of remaining bytes
+    #[inline(always)]
+    pub const fn remaining(&self) -> usize {
+        self.bytes.len()
     }
-}
-
-impl Index<RangeTo<usize>> for PacketView<'_> {
-    type Output = [u8];
 
-    fn index(&self, index: RangeTo<usize>) -> &Self::Output {
-        self.bytes.index(index)
+    /// Returns the specified range of the remaining bytes without consuming them
+    pub fn get(&self, range: Range<usize>) -> Result<&[u8], PacketViewError> {
+        let remaining = self.bytes.len();
+        match self.bytes.get(range.clone()) {
+            Some(bytes) => Ok(bytes),
+            None => Err(PacketViewError::UnexpectedEnd {
+                requested: range.end,
+                remaining,
+            }),
+        }
     }
-}
 
-impl Index<Range<usize>> for PacketView<'_> {
-    type Output = [u8];
+    /// Returns `N` bytes at the specified offset without consuming them
+    pub fn get_array<const N: usize>(&self, offset: usize) -> Result<&[u8; N], PacketViewError> {
+        let bytes = self.get(offset..offset.saturating_add(N))?;
+        // NOTE: length is guaranteed to be `N` here
+        bytes
+            .try_into()
+            .map_err(|_| PacketViewError::UnexpectedEnd {
+                requested: N,
+                remaining: bytes.len(),
+            })
+    }
 
-    fn index(&self, index: Range<usize>) -> &Self::Output {
-        self.bytes.index(index)
+    /// Splits the remaining bytes into two mutable parts without consuming them
+    pub fn split_at_mut(&mut self, mid: usize) -> Result<(&mut [u8], &mut [u8]), PacketViewError> {
+        self.check_remaining(mid)?;
+        Ok(self.bytes.split_at_mut(mid))
     }
-}
 
-impl IndexMut<Range<usize>> for PacketView<'_> {
-    fn index_mut(&mut self, index: Range<usize>) -> &mut Self::Output {
-        self.bytes.index_mut(index)
+    /// Consumes and returns the first `n` bytes
+    pub fn split_prefix(&mut self, n: usize) -> Result<&'a [u8], PacketViewError> {
+        self.check_remaining(n)?;
+        let (prefix, rest) = std::mem::take(&mut self.bytes).split_at_mut(n);
+        self.bytes = rest;
+        Ok(prefix)
     }
-}
 
-impl Index<RangeFrom<usize>> for PacketView<'_> {
-    type Output = [u8];
+    /// Consumes 4 bytes as a little-endian integer
+    pub fn read_u32_le(&mut self) -> Result<u32, PacketViewError> {
+        let bytes = self.split_prefix(4)?;
+        let mut value = [0; 4];
+        value.copy_from_slice(bytes);
+        Ok(u32::from_le_bytes(value))
+    }
 
-    fn index(&self, index: RangeFrom<usize>) -> &Self::Output {
-        self.bytes.index(index)
+    /// Marks the first `n` bytes as consumed (e.g. the header after decryption)
+    pub fn remove_prefix(&mut self, n: usize) -> Result<(), PacketViewError> {
+        self.split_prefix(n).map(|_| ())
     }
-}
 
-impl IndexMut<RangeFrom<usize>> for PacketView<'_> {
-    fn index_mut(&mut self, index: RangeFrom<usize>) -> &mut Self::Output {
-        self.bytes.index_mut(index)
+    fn check_remaining(&self, n: usize) -> Result<(), PacketViewError> {
+        if n <= self.bytes.len() {
+            Ok(())
+        } else {
+            Err(PacketViewError::UnexpectedEnd {
+                requested: n,
+                remaining: self.bytes.len(),
+            })
+        }
     }
 }
 
@@ -70,3 +100,70 @@ impl<'a> From<&'a mut [u8]> for PacketView<'a> {
         Self { bytes }
     }
 }
+
+#[derive(thiserror::Error, Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PacketViewError {
+    #[error("Unexpected end of packet: {requested} bytes requested, {remaining} remaining")]
+    UnexpectedEnd { requested: usize, remaining: usize },
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+
+    use super::*;
+
+    #[test]
+    fn cursor() {
+        let mut bytes = *b"\x01\x00\x00\x00headerpayload";
+        let mut view = PacketView::from(bytes.as_mut_slice());
+
+        assert_eq!(view.read_u32_le(), Ok(1));
+        assert_eq!(view.get_array::<6>(0), Ok(b"header"));
+        assert_eq!(view.split_prefix(6), Ok(b"header".as_slice()));
+        assert_eq!(view.as_bytes(), b"payload");
+
+        let (head, tail) = view.split_at_mut(3).unwrap();
+        head.make_ascii_uppercase();
+        assert_eq!(tail, b"load");
+        assert_eq!(view.as_bytes(), b"PAYload");
+
+        assert_eq!(
+            view.remove_prefix(8),
+            Err(PacketViewError::UnexpectedEnd {
+                requested: 8,
+                remaining: 7
+            })
+        );
+        assert_eq!(view.remaining(), 7);
+        view.remove_prefix(7).unwrap();
+        assert_eq!(view.remaining(), 0);
+        assert!(view.read_u32_le().is_err());
+    }
+
+    #[test]
+    fn arbitrary_operations_never_panic() {
+        let mut rng = rand::thread_rng();
+        for _ in 0..1000 {
+            let mut bytes = vec![0u8; rng.gen_range(0..64)];
+            let mut view = PacketView::from(bytes.as_mut_slice());
+
+            for _ in 0..16 {
+                let n = rng.gen_range(0..80);
+                let before = view.remaining();
+                let consumed = match rng.gen_range(0..6) {
+                    0 => view.split_prefix(n).map(|prefix| prefix.len()),
+                    1 => view.read_u32_le().map(|_| 4),
+                    2 => view.remove_prefix(n).map(|_| n),
+                    3 => view.get(n / 2..n).map(|_| 0),
+                    4 => view.get_array::<32>(n).map(|_| 0),
+                    _ => view.split_at_mut(n).map(|_| 0),
+                };
+                match consumed {
+                    Ok(consumed) => assert_eq!(view.remaining(), before - consumed),
+                    Err(_) => assert_eq!(view.remaining(), before),
+                }
+            }
+        }
+    }
+}
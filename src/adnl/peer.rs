@@ -1,9 +1,13 @@
 use std::net::{Ipv4Addr, SocketAddrV4};
-use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use std::sync::Arc;
 
 use everscale_crypto::ed25519;
+use parking_lot::Mutex;
 
 use super::node_id::{NodeIdFull, NodeIdShort};
+use super::send_queue::SendQueue;
+use crate::proto;
 use crate::util::*;
 
 pub type Peers = FastDashMap<NodeIdShort, Peer>;
@@ -14,26 +18,91 @@ pub struct Peer {
     id: NodeIdFull,
     /// IPv4 address
     addr: AtomicU64,
+    /// Source address of the last authentic incoming packet (zero if unknown)
+    observed_addr: AtomicU64,
     /// Adnl channel key pair to encrypt messages from our side
     channel_key: ed25519::KeyPair,
     /// Packets receiver state
     receiver_state: PeerState,
     /// Packets sender state
     sender_state: PeerState,
+    /// Announced capabilities and the timestamp of the last update
+    capabilities: Mutex<(PeerCapabilities, u32)>,
+    /// Outgoing packets which were not sent yet
+    send_queue: Arc<SendQueue>,
+    /// Max datagram size found by the MTU probe (zero if unknown)
+    probed_datagram_size: AtomicUsize,
+    /// The context in which the peer was first added
+    context: NewPeerContext,
+    /// Time of the last authentic incoming packet (or of the creation)
+    last_active_ms: AtomicU64,
+    /// Explicitly set compatibility profile
+    compat: AtomicU8,
 }
 
 impl Peer {
     /// Creates new peer with receiver state initialized with the local reinit date
-    pub fn new(local_reinit_date: u32, addr: SocketAddrV4, id: NodeIdFull) -> Self {
+    pub fn new(
+        local_reinit_date: u32,
+        addr: SocketAddrV4,
+        id: NodeIdFull,
+        context: NewPeerContext,
+        now_ms: u64,
+    ) -> Self {
         Self {
             id,
             addr: AtomicU64::new(pack_socket_addr(&addr)),
+            observed_addr: AtomicU64::new(0),
             channel_key: ed25519::KeyPair::generate(&mut rand::thread_rng()),
             receiver_state: PeerState::for_receive_with_reinit_date(local_reinit_date),
             sender_state: PeerState::for_send(),
+            capabilities: Default::default(),
+            send_queue: Default::default(),
+            probed_datagram_size: AtomicUsize::new(0),
+            context,
+            last_active_ms: AtomicU64::new(now_ms),
+            compat: AtomicU8::new(PeerCompat::Full as u8),
         }
     }
 
+    /// Compatibility profile of the peer. [`PeerCompat::Full`] is downgraded
+    /// to [`PeerCompat::NoPriorityChannel`] if the peer ignores priority packets
+    pub fn compat(&self) -> PeerCompat {
+        const MAX_PRIORITY_ATTEMPTS: u64 = 10;
+
+        match PeerCompat::from_u8(self.compat.load(Ordering::Acquire)) {
+            PeerCompat::Full
+                if self.receiver_state.history(true).seqno() == 0
+                    && self.sender_state.history(true).seqno() > MAX_PRIORITY_ATTEMPTS =>
+            {
+                PeerCompat::NoPriorityChannel
+            }
+            compat => compat,
+        }
+    }
+
+    #[inline(always)]
+    pub fn set_compat(&self, compat: PeerCompat) {
+        self.compat.store(compat as u8, Ordering::Release);
+    }
+
+    /// The context in which the peer was first added
+    #[inline(always)]
+    pub fn context(&self) -> NewPeerContext {
+        self.context
+    }
+
+    /// Time of the last authentic incoming packet (or of the creation)
+    #[inline(always)]
+    pub fn last_active_ms(&self) -> u64 {
+        self.last_active_ms.load(Ordering::Acquire)
+    }
+
+    #[inline(always)]
+    pub fn set_active(&self, now_ms: u64) {
+        self.last_active_ms.fetch_max(now_ms, Ordering::AcqRel);
+    }
+
     /// Tries to update peer reinit date
     ///
     /// It is only allowed to update peer reinit date if it is greater or equal to the known one
@@ -72,6 +141,22 @@ impl Peer {
         self.addr.store(pack_socket_addr(&addr), Ordering::Release);
     }
 
+    /// Source address of the last authentic incoming packet.
+    /// Differs from [`Peer::addr`] for the peers behind NAT
+    #[inline(always)]
+    pub fn observed_addr(&self) -> Option<SocketAddrV4> {
+        match self.observed_addr.load(Ordering::Acquire) {
+            0 => None,
+            addr => Some(unpack_socket_addr(addr)),
+        }
+    }
+
+    #[inline(always)]
+    pub fn set_observed_addr(&self, addr: SocketAddrV4) {
+        self.observed_addr
+            .store(pack_socket_addr(&addr), Ordering::Release);
+    }
+
     /// Adnl channel key pair to encrypt messages from our side
     #[inline(always)]
     pub fn channel_key(&self) -> &ed25519::KeyPair {
@@ -90,6 +175,48 @@ impl Peer {
         &self.sender_state
     }
 
+    /// Capabilities announced by the remote peer
+    #[inline(always)]
+    pub fn capabilities(&self) -> PeerCapabilities {
+        self.capabilities.lock().0
+    }
+
+    /// Outgoing packets which were not sent yet
+    #[inline(always)]
+    pub fn send_queue(&self) -> &Arc<SendQueue> {
+        &self.send_queue
+    }
+
+    /// Max datagram size found by the MTU probe
+    #[inline(always)]
+    pub fn probed_datagram_size(&self) -> Option<usize> {
+        match self.probed_datagram_size.load(Ordering::Acquire) {
+            0 => None,
+            size => Some(size),
+        }
+    }
+
+    #[inline(always)]
+    pub fn set_probed_datagram_size(&self, size: usize) {
+        self.probed_datagram_size.store(size, Ordering::Release);
+    }
+
+    /// Updates capabilities if the previous update was at least `interval` seconds ago.
+    /// Returns `false` if the update was ignored
+    pub fn try_update_capabilities(
+        &self,
+        capabilities: PeerCapabilities,
+        now: u32,
+        interval: u32,
+    ) -> bool {
+        let mut current = self.capabilities.lock();
+        if current.1 != 0 && now < current.1.saturating_add(interval) {
+            return false;
+        }
+        *current = (capabilities, now);
+        true
+    }
+
     /// Generates new channel key pair and resets receiver/sender states
     ///
     /// NOTE: Receiver state increments its reinit date so the peer will reset states
@@ -102,6 +229,28 @@ impl Peer {
         self.channel_key = ed25519::KeyPair::generate(&mut rand::thread_rng());
         self.receiver_state = PeerState::for_receive_with_reinit_date(reinit_date + 1);
         self.sender_state = PeerState::for_send();
+        *self.capabilities.get_mut() = Default::default();
+    }
+}
+
+/// Protocol version and features announced by the remote peer
+/// after the channel was established.
+///
+/// Peers which didn't announce anything have all-zero capabilities.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct PeerCapabilities {
+    pub version: u32,
+    pub features: u64,
+}
+
+impl PeerCapabilities {
+    /// Peer decompresses RLDP queries and compresses answers to them
+    pub const RLDP_COMPRESSION: u64 = 1;
+
+    /// Whether all specified feature bits are set
+    #[inline(always)]
+    pub fn supports(&self, features: u64) -> bool {
+        self.features & features == features
     }
 }
 
@@ -164,6 +313,48 @@ impl PeerState {
     }
 }
 
+/// Set of the optional packet fields emitted to the peer.
+///
+/// Allows talking to old nodes which reject packets with newer fields,
+/// see [`Node::set_peer_compat`]
+///
+/// [`Node::set_peer_compat`]: crate::adnl::Node::set_peer_compat
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub enum PeerCompat {
+    /// All fields. Priority channel is used until the peer turns out to ignore it
+    #[default]
+    Full,
+    /// Packets are never sent over the priority channel
+    NoPriorityChannel,
+    /// Same as [`PeerCompat::NoPriorityChannel`], the address list contains
+    /// only the most preferred address and has zero priority
+    Legacy,
+}
+
+impl PeerCompat {
+    /// Whether packets can be sent over the priority channel
+    #[inline(always)]
+    pub fn allows_priority_channel(&self) -> bool {
+        matches!(self, Self::Full)
+    }
+
+    /// Removes address list fields which are not supported by the profile
+    pub fn apply_to_address_list(&self, address_list: &mut proto::adnl::AddressList) {
+        if let Self::Legacy = self {
+            address_list.addresses.truncate(1);
+            address_list.priority = 0;
+        }
+    }
+
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => Self::NoPriorityChannel,
+            2 => Self::Legacy,
+            _ => Self::Full,
+        }
+    }
+}
+
 /// The context in which the new peer is added
 #[derive(Debug, Copy, Clone, Hash, Eq, PartialEq)]
 pub enum NewPeerContext {
@@ -172,9 +363,29 @@ pub enum NewPeerContext {
     PublicOverlay,
 }
 
-/// New peers filter
+/// Peers filter, see [`AllowAllPeers`] and [`CidrAndIdListFilter`]
+///
+/// [`AllowAllPeers`]: crate::adnl::AllowAllPeers
+/// [`CidrAndIdListFilter`]: crate::adnl::CidrAndIdListFilter
 pub trait PeerFilter: Send + Sync {
+    /// Checks the new peer before it is added (from packets, DHT, overlays or explicitly)
     fn check(&self, ctx: NewPeerContext, addr: SocketAddrV4, peer_id: &NodeIdShort) -> bool;
+
+    /// Checks the source address of each incoming packet before it is decrypted.
+    ///
+    /// Allows all addresses by default
+    fn check_addr(&self, addr: SocketAddrV4) -> bool {
+        let _ = addr;
+        true
+    }
+
+    /// Checks each incoming packet as soon as its source peer id is known.
+    ///
+    /// Allows all peers by default
+    fn check_peer(&self, addr: SocketAddrV4, peer_id: &NodeIdShort) -> bool {
+        let _ = (addr, peer_id);
+        true
+    }
 }
 
 #[cfg(test)]
@@ -190,4 +401,87 @@ mod tests {
         let unpacked = unpack_socket_addr(packed);
         assert_eq!(unpacked, test);
     }
+
+    #[test]
+    fn compat_profile_fields() {
+        use tl_proto::TlWrite;
+
+        let addresses = [
+            SocketAddrV4::new(Ipv4Addr::new(1, 2, 3, 4), 30303),
+            SocketAddrV4::new(Ipv4Addr::new(5, 6, 7, 8), 30303),
+        ];
+
+        for (compat, priority_channel, address_count, priority) in [
+            (PeerCompat::Full, true, 2, 5),
+            (PeerCompat::NoPriorityChannel, false, 2, 5),
+            (PeerCompat::Legacy, false, 1, 0),
+        ] {
+            assert_eq!(compat.allows_priority_channel(), priority_channel);
+
+            let mut address = proto::adnl::AddressList {
+                addresses: addresses.iter().map(proto::adnl::Address::from).collect(),
+                version: 1,
+                reinit_date: 2,
+                priority: 5,
+                expire_at: 3,
+            };
+            compat.apply_to_address_list(&mut address);
+
+            let packet = proto::adnl::OutgoingPacketContents {
+                rand1: &[],
+                from: None,
+                messages: proto::adnl::OutgoingMessages::Single(&tl_proto::serialize(
+                    proto::adnl::Message::Nop,
+                )),
+                address,
+                seqno: 10,
+                confirm_seqno: 9,
+                reinit_dates: None,
+                signature: None,
+                rand2: &[],
+            };
+            let mut data = Vec::with_capacity(packet.max_size_hint());
+            packet.write_to(&mut data);
+
+            let (contents, trailing) = proto::adnl::IncomingPacketContents::read_with_limit(
+                &data,
+                proto::adnl::DEFAULT_MAX_PACKET_MESSAGES,
+            )
+            .unwrap();
+            assert_eq!(trailing, 0);
+
+            let address = contents.address.unwrap();
+            assert_eq!(address.addresses.len(), address_count);
+            assert_eq!(SocketAddrV4::from(address.addresses[0]), addresses[0]);
+            assert_eq!(address.priority, priority);
+            assert_eq!((address.version, address.reinit_date), (1, 2));
+            assert_eq!(address.expire_at, 3);
+            assert_eq!(contents.seqno, Some(10));
+            assert_eq!(contents.confirm_seqno, Some(9));
+            assert!(contents.from.is_none() && contents.from_short.is_none());
+            assert!(contents.reinit_dates.is_none() && contents.signature.is_none());
+        }
+    }
+
+    #[test]
+    fn compat_falls_back_to_ordinary_channel() {
+        let peer = Peer::new(
+            1,
+            SocketAddrV4::new(Ipv4Addr::LOCALHOST, 30303),
+            NodeIdFull::new(ed25519::KeyPair::generate(&mut rand::thread_rng()).public_key),
+            NewPeerContext::AdnlPacket,
+            0,
+        );
+        assert_eq!(peer.compat(), PeerCompat::Full);
+
+        // Peer never answers over the priority channel
+        for _ in 0..11 {
+            peer.sender_state().history(true).bump_seqno();
+        }
+        assert_eq!(peer.compat(), PeerCompat::NoPriorityChannel);
+
+        // Explicit profile is not affected
+        peer.set_compat(PeerCompat::Legacy);
+        assert_eq!(peer.compat(), PeerCompat::Legacy);
+    }
 }
@@ -0,0 +1,219 @@
+use std::collections::HashSet;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::str::FromStr;
+
+use super::node_id::NodeIdShort;
+use super::peer::{NewPeerContext, PeerFilter};
+
+/// Peer filter which accepts everything
+#[derive(Debug, Default, Copy, Clone)]
+pub struct AllowAllPeers;
+
+impl PeerFilter for AllowAllPeers {
+    fn check(&self, _: NewPeerContext, _: SocketAddrV4, _: &NodeIdShort) -> bool {
+        true
+    }
+}
+
+/// Static peer filter with lists of IPv4 networks and node ids.
+///
+/// Denied entries take priority. Non-empty allowed lists restrict
+/// addresses (or peers) to the listed ones only.
+///
+/// # Example
+///
+/// ```
+/// # use everscale_network::adnl;
+/// let filter: adnl::CidrAndIdListFilter = serde_json::from_str(r#"{
+///     "denied_networks": ["10.0.0.0/8"],
+///     "allowed_networks": ["10.0.0.0/8", "192.168.0.0/16"]
+/// }"#).unwrap();
+/// ```
+#[derive(Debug, Default, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct CidrAndIdListFilter {
+    /// Networks from which all packets are dropped
+    pub denied_networks: Vec<Ipv4Cidr>,
+    /// Peers which are never accepted
+    pub denied_peers: HashSet<NodeIdShort>,
+    /// If not empty, packets are only accepted from these networks
+    pub allowed_networks: Vec<Ipv4Cidr>,
+    /// If not empty, only these peers are accepted
+    pub allowed_peers: HashSet<NodeIdShort>,
+}
+
+impl PeerFilter for CidrAndIdListFilter {
+    fn check(&self, _: NewPeerContext, addr: SocketAddrV4, peer_id: &NodeIdShort) -> bool {
+        self.check_peer(addr, peer_id)
+    }
+
+    fn check_addr(&self, addr: SocketAddrV4) -> bool {
+        let ip = addr.ip();
+        !self.denied_networks.iter().any(|net| net.contains(ip))
+            && (self.allowed_networks.is_empty()
+                || self.allowed_networks.iter().any(|net| net.contains(ip)))
+    }
+
+    fn check_peer(&self, addr: SocketAddrV4, peer_id: &NodeIdShort) -> bool {
+        self.check_addr(addr)
+            && !self.denied_peers.contains(peer_id)
+            && (self.allowed_peers.is_empty() || self.allowed_peers.contains(peer_id))
+    }
+}
+
+/// IPv4 network in CIDR notation (e.g. `10.0.0.0/8`)
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct Ipv4Cidr {
+    addr: Ipv4Addr,
+    prefix_len: u8,
+}
+
+impl Ipv4Cidr {
+    /// Creates a network from the address and the prefix length.
+    /// Host bits of the address are cleared
+    pub fn new(addr: Ipv4Addr, prefix_len: u8) -> Result<Self, ParseCidrError> {
+        if prefix_len > 32 {
+            return Err(ParseCidrError::InvalidPrefixLength);
+        }
+        let addr = Ipv4Addr::from(u32::from(addr) & mask(prefix_len));
+        Ok(Self { addr, prefix_len })
+    }
+
+    /// Network address
+    pub fn addr(&self) -> Ipv4Addr {
+        self.addr
+    }
+
+    /// Number of leading bits of the network address
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+
+    /// Whether the address belongs to this network
+    pub fn contains(&self, ip: &Ipv4Addr) -> bool {
+        u32::from(*ip) & mask(self.prefix_len) == u32::from(self.addr)
+    }
+}
+
+fn mask(prefix_len: u8) -> u32 {
+    match prefix_len {
+        0 => 0,
+        len => u32::MAX << (32 - len as u32),
+    }
+}
+
+impl std::fmt::Display for Ipv4Cidr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+impl FromStr for Ipv4Cidr {
+    type Err = ParseCidrError;
+
+    /// Parses `a.b.c.d/len`. Address without prefix length is treated as `/32`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => (
+                addr,
+                prefix_len
+                    .parse()
+                    .map_err(|_| ParseCidrError::InvalidPrefixLength)?,
+            ),
+            None => (s, 32),
+        };
+        let addr = addr.parse().map_err(|_| ParseCidrError::InvalidAddress)?;
+        Self::new(addr, prefix_len)
+    }
+}
+
+impl serde::Serialize for Ipv4Cidr {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Ipv4Cidr {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use serde::de::{Error, Visitor};
+
+        struct CidrVisitor;
+
+        impl<'de> Visitor<'de> for CidrVisitor {
+            type Value = Ipv4Cidr;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("IPv4 network in CIDR notation")
+            }
+
+            fn visit_str<E: Error>(self, v: &str) -> Result<Self::Value, E> {
+                v.parse().map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_str(CidrVisitor)
+    }
+}
+
+/// Error while parsing [`Ipv4Cidr`]
+#[derive(thiserror::Error, Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ParseCidrError {
+    #[error("Invalid network address")]
+    InvalidAddress,
+    #[error("Invalid network prefix length")]
+    InvalidPrefixLength,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cidr_parsing() {
+        let net: Ipv4Cidr = "10.1.2.3/8".parse().unwrap();
+        assert_eq!(net.to_string(), "10.0.0.0/8");
+        assert!(net.contains(&Ipv4Addr::new(10, 255, 0, 1)));
+        assert!(!net.contains(&Ipv4Addr::new(11, 0, 0, 1)));
+
+        let host: Ipv4Cidr = "192.168.0.1".parse().unwrap();
+        assert_eq!(host.prefix_len(), 32);
+        assert!(host.contains(&Ipv4Addr::new(192, 168, 0, 1)));
+        assert!(!host.contains(&Ipv4Addr::new(192, 168, 0, 2)));
+
+        let any: Ipv4Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(any.contains(&Ipv4Addr::BROADCAST));
+
+        for invalid in ["10.0.0.0/33", "10.0.0/8", "10.0.0.0/", "abc"] {
+            assert!(invalid.parse::<Ipv4Cidr>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn lists_filter() {
+        let allowed_peer = NodeIdShort::new([1; 32]);
+        let denied_peer = NodeIdShort::new([2; 32]);
+        let addr = |a, b, c, d| SocketAddrV4::new(Ipv4Addr::new(a, b, c, d), 30303);
+
+        let filter: CidrAndIdListFilter = serde_json::from_value(serde_json::json!({
+            "denied_networks": ["10.0.1.0/24"],
+            "denied_peers": [denied_peer.to_string()],
+            "allowed_networks": ["10.0.0.0/16"],
+        }))
+        .unwrap();
+
+        assert!(filter.check_addr(addr(10, 0, 0, 1)));
+        assert!(!filter.check_addr(addr(10, 0, 1, 1)));
+        assert!(!filter.check_addr(addr(10, 1, 0, 1)));
+        assert!(filter.check_peer(addr(10, 0, 0, 1), &allowed_peer));
+        assert!(!filter.check_peer(addr(10, 0, 0, 1), &denied_peer));
+
+        // Allowlist of ids
+        let filter = CidrAndIdListFilter {
+            allowed_peers: [allowed_peer].into_iter().collect(),
+            ..Default::default()
+        };
+        assert!(filter.check_addr(addr(1, 2, 3, 4)));
+        assert!(filter.check(NewPeerContext::Dht, addr(1, 2, 3, 4), &allowed_peer));
+        assert!(!filter.check(NewPeerContext::Dht, addr(1, 2, 3, 4), &denied_peer));
+    }
+}
@@ -60,7 +60,7 @@ impl PeersSet {
         self.state.read().is_full()
     }
 
-    pub fn iter(&self) -> Iter {
+    pub fn iter(&self) -> Iter<'_> {
         Iter::new(self.state.read())
     }
 
@@ -17,9 +17,13 @@ impl QuerySubscriber for PingSubscriber {
     ) -> Result<QueryConsumingResult<'a>> {
         if constructor == proto::rpc::AdnlPing::TL_ID {
             let proto::rpc::AdnlPing { value } = tl_proto::deserialize(&query)?;
-            QueryConsumingResult::consume(proto::adnl::Pong { value })
+            Ok(QueryConsumingResult::answer(proto::adnl::Pong { value }))
         } else {
-            Ok(QueryConsumingResult::Rejected(query))
+            Ok(QueryConsumingResult::reject(query))
         }
     }
+
+    fn interested_constructors(&self) -> &[u32] {
+        &[proto::rpc::AdnlPing::TL_ID]
+    }
 }
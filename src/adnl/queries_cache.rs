@@ -1,14 +1,115 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::{Arc, Weak};
 
-use tokio::sync::oneshot;
+use tokio::sync::Notify;
 
+use crate::adnl::node_id::NodeIdShort;
 use crate::util::FastDashMap;
 
-pub type QueryId = [u8; 32];
+/// ADNL or RLDP query id
+#[derive(Default, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct QueryId(pub [u8; 32]);
 
+impl QueryId {
+    /// Wraps raw query id bytes
+    #[inline(always)]
+    pub const fn new(id: [u8; 32]) -> Self {
+        Self(id)
+    }
+
+    /// Raw query id bytes
+    #[inline(always)]
+    pub const fn as_slice(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+impl From<[u8; 32]> for QueryId {
+    #[inline(always)]
+    fn from(id: [u8; 32]) -> Self {
+        Self(id)
+    }
+}
+
+impl std::fmt::Display for QueryId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut output = [0u8; 64];
+        hex::encode_to_slice(self.0, &mut output).ok();
+
+        // NOTE: output always contains only [0-9a-f]
+        let output = std::str::from_utf8(&output).map_err(|_| std::fmt::Error)?;
+        f.write_str(output)
+    }
+}
+
+impl std::fmt::Debug for QueryId {
+    #[inline(always)]
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Display::fmt(self, f)
+    }
+}
+
+/// Compact id which links logs of the same query on both sides.
+///
+/// Consists of the first 8 bytes of the ADNL query id or the RLDP query transfer id
+#[derive(Default, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct TraceId(pub [u8; 8]);
+
+impl TraceId {
+    /// Takes the prefix of the query or transfer id
+    pub fn from_id(id: &[u8; 32]) -> Self {
+        let mut prefix = [0; 8];
+        prefix.copy_from_slice(&id[..8]);
+        Self(prefix)
+    }
+
+    /// Derives the trace id of the custom message from its data
+    pub fn for_message(data: &[u8]) -> Self {
+        use sha2::Digest;
+
+        let hash: [u8; 32] = sha2::Sha256::digest(data).into();
+        Self::from_id(&hash)
+    }
+}
+
+impl From<&QueryId> for TraceId {
+    #[inline(always)]
+    fn from(id: &QueryId) -> Self {
+        Self::from_id(id.as_slice())
+    }
+}
+
+impl std::fmt::Display for TraceId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut output = [0u8; 16];
+        hex::encode_to_slice(self.0, &mut output).ok();
+
+        // NOTE: output always contains only [0-9a-f]
+        let output = std::str::from_utf8(&output).map_err(|_| std::fmt::Error)?;
+        f.write_str(output)
+    }
+}
+
+impl std::fmt::Debug for TraceId {
+    #[inline(always)]
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Display::fmt(self, f)
+    }
+}
+
+/// Max number of cancelled entries which are kept to recognize late answers
+const MAX_CANCELLED_QUERIES: usize = 1024;
+
+/// Pending queries, which can only be answered by the peers they were sent to
+///
+/// Queries are keyed only by the random query id. The expected peer is stored
+/// in the entry and checked on the answer, so cancellation and taking out
+/// the answer don't need to know the peer
 #[derive(Default)]
 pub struct QueriesCache {
-    queries: FastDashMap<QueryId, DataTx>,
+    queries: FastDashMap<QueryId, PendingQueryEntry>,
+    cancelled: AtomicUsize,
+    answers_dropped: AtomicU64,
 }
 
 impl QueriesCache {
@@ -17,58 +118,334 @@ impl QueriesCache {
         self.queries.is_empty()
     }
 
+    /// Number of entries (including recently cancelled ones)
     pub fn len(&self) -> usize {
         self.queries.len()
     }
 
-    pub fn add_query(self: &Arc<Self>, query_id: QueryId) -> PendingAdnlQuery {
-        let (tx, rx) = oneshot::channel();
+    /// Total number of answers which were received after the waiter was dropped
+    pub fn answers_dropped(&self) -> u64 {
+        self.answers_dropped.load(Ordering::Relaxed)
+    }
+
+    pub fn add_query(
+        self: &Arc<Self>,
+        local_id: &NodeIdShort,
+        peer_id: &NodeIdShort,
+        query_id: QueryId,
+    ) -> PendingAdnlQuery {
+        let notify = Arc::new(Notify::new());
 
-        self.queries.insert(query_id, tx);
+        self.queries.insert(
+            query_id,
+            PendingQueryEntry {
+                local_id: *local_id,
+                peer_id: *peer_id,
+                state: QueryState::Pending,
+                notify: notify.clone(),
+            },
+        );
 
         PendingAdnlQuery {
             query_id,
-            data_rx: Some(rx),
+            notify,
             cache: Arc::downgrade(self),
-            finished: false,
         }
     }
 
-    pub fn update_query(&self, query_id: &QueryId, answer: &[u8]) {
-        if let Some((_, tx)) = self.queries.remove(query_id) {
-            tx.send(answer.to_vec()).ok();
+    /// Wakes the waiter if the answer came from the expected peer
+    pub fn update_query(
+        &self,
+        local_id: &NodeIdShort,
+        peer_id: &NodeIdShort,
+        query_id: &QueryId,
+        answer: &[u8],
+    ) -> QueryUpdateResult {
+        let mut entry = match self.queries.get_mut(query_id) {
+            Some(entry) => entry,
+            None => return QueryUpdateResult::Unknown,
+        };
+
+        if &entry.local_id != local_id || &entry.peer_id != peer_id {
+            return QueryUpdateResult::PeerMismatch;
+        }
+
+        match entry.state {
+            QueryState::Pending => {
+                entry.state = QueryState::Answered(answer.to_vec());
+                entry.notify.notify_one();
+                QueryUpdateResult::Updated
+            }
+            QueryState::Cancelled => {
+                drop(entry);
+                if self
+                    .queries
+                    .remove_if(query_id, |_, entry| {
+                        matches!(entry.state, QueryState::Cancelled)
+                    })
+                    .is_some()
+                {
+                    self.cancelled.fetch_sub(1, Ordering::Relaxed);
+                }
+                self.answers_dropped.fetch_add(1, Ordering::Relaxed);
+                QueryUpdateResult::Cancelled
+            }
+            // Duplicate answer
+            QueryState::Answered(_) | QueryState::TakenOut => QueryUpdateResult::Unknown,
+        }
+    }
+
+    /// Takes the answer out of the entry if it was already received.
+    ///
+    /// Answer can only be taken once
+    pub fn try_take_answer(&self, query_id: &QueryId) -> Option<Vec<u8>> {
+        let mut entry = self.queries.get_mut(query_id)?;
+        if !matches!(entry.state, QueryState::Answered(_)) {
+            return None;
+        }
+        match std::mem::replace(&mut entry.state, QueryState::TakenOut) {
+            QueryState::Answered(answer) => Some(answer),
+            _ => None,
+        }
+    }
+
+    /// Returns `false` if the answer will never be received
+    fn is_waiting(&self, query_id: &QueryId) -> bool {
+        match self.queries.get(query_id) {
+            Some(entry) => matches!(entry.state, QueryState::Pending | QueryState::Answered(_)),
+            None => false,
         }
     }
+
+    /// Marks pending entry as cancelled or removes it if it is already finished
+    fn cancel_query(&self, query_id: &QueryId) {
+        let mut entry = match self.queries.get_mut(query_id) {
+            Some(entry) => entry,
+            None => return,
+        };
+
+        match entry.state {
+            QueryState::Pending => {
+                entry.state = QueryState::Cancelled;
+                drop(entry);
+
+                // Keep the entry to silently drop the late answer
+                if self.cancelled.fetch_add(1, Ordering::Relaxed) + 1 > MAX_CANCELLED_QUERIES {
+                    self.remove_cancelled();
+                }
+            }
+            QueryState::Cancelled => {}
+            QueryState::Answered(_) | QueryState::TakenOut => {
+                if matches!(entry.state, QueryState::Answered(_)) {
+                    self.answers_dropped.fetch_add(1, Ordering::Relaxed);
+                }
+                drop(entry);
+                self.queries.remove(query_id);
+            }
+        }
+    }
+
+    fn remove_cancelled(&self) {
+        self.queries.retain(|_, entry| {
+            let cancelled = matches!(entry.state, QueryState::Cancelled);
+            if cancelled {
+                self.cancelled.fetch_sub(1, Ordering::Relaxed);
+            }
+            !cancelled
+        });
+    }
 }
 
+/// Handle to the pending query.
+///
+/// Query is marked as cancelled when the handle is dropped
 pub struct PendingAdnlQuery {
     query_id: QueryId,
-    data_rx: Option<DataRx>,
+    notify: Arc<Notify>,
     cache: Weak<QueriesCache>,
-    finished: bool,
 }
 
 impl PendingAdnlQuery {
-    pub async fn wait(mut self) -> Option<Vec<u8>> {
-        // SAFETY: `data_rx` is guaranteed to be `Some`
-        let data_rx = unsafe { self.data_rx.take().unwrap_unchecked() };
-        let data = data_rx.await.ok();
-        self.finished = true;
-        data
+    /// Waits for the answer.
+    ///
+    /// This method is cancellation safe: the answer is kept until
+    /// it is taken by another call of this method or [`QueriesCache::try_take_answer`].
+    pub async fn wait(&self) -> Option<Vec<u8>> {
+        loop {
+            let notified = self.notify.notified();
+
+            let cache = self.cache.upgrade()?;
+            if let Some(answer) = cache.try_take_answer(&self.query_id) {
+                return Some(answer);
+            }
+            if !cache.is_waiting(&self.query_id) {
+                return None;
+            }
+            drop(cache);
+
+            notified.await;
+        }
     }
 }
 
 impl Drop for PendingAdnlQuery {
     fn drop(&mut self) {
-        if self.finished {
-            return;
-        }
-
         if let Some(cache) = self.cache.upgrade() {
-            cache.queries.remove(&self.query_id);
+            cache.cancel_query(&self.query_id);
         }
     }
 }
 
-type DataTx = oneshot::Sender<Vec<u8>>;
-type DataRx = oneshot::Receiver<Vec<u8>>;
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum QueryUpdateResult {
+    /// Answer was delivered to the waiter
+    Updated,
+    /// There is no pending query with such id (e.g. it was already answered)
+    Unknown,
+    /// Waiter was dropped before the answer was received
+    Cancelled,
+    /// Query was sent to another peer
+    PeerMismatch,
+}
+
+struct PendingQueryEntry {
+    local_id: NodeIdShort,
+    peer_id: NodeIdShort,
+    state: QueryState,
+    notify: Arc<Notify>,
+}
+
+enum QueryState {
+    /// Waiting for the answer
+    Pending,
+    /// Answer was received but not yet taken
+    Answered(Vec<u8>),
+    /// Answer was taken by the waiter
+    TakenOut,
+    /// Waiter was dropped before the answer was received
+    Cancelled,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn make_ids() -> (NodeIdShort, NodeIdShort) {
+        (
+            NodeIdShort::new(rand::random()),
+            NodeIdShort::new(rand::random()),
+        )
+    }
+
+    #[tokio::test]
+    async fn query_states() {
+        let cache = Arc::new(QueriesCache::default());
+        let (local_id, peer_id) = make_ids();
+
+        // Answer is kept until it is taken
+        let query = cache.add_query(&local_id, &peer_id, QueryId([1; 32]));
+        assert!(cache.try_take_answer(&QueryId([1; 32])).is_none());
+        assert_eq!(
+            cache.update_query(&local_id, &peer_id, &QueryId([1; 32]), b"answer"),
+            QueryUpdateResult::Updated
+        );
+        assert_eq!(
+            cache.update_query(&local_id, &peer_id, &QueryId([1; 32]), b"duplicate"),
+            QueryUpdateResult::Unknown
+        );
+        assert_eq!(
+            cache.try_take_answer(&QueryId([1; 32])).as_deref(),
+            Some(&b"answer"[..])
+        );
+        assert!(cache.try_take_answer(&QueryId([1; 32])).is_none());
+        assert!(query.wait().await.is_none());
+        drop(query);
+        assert!(cache.is_empty());
+
+        // Cancelled wait future doesn't lose the answer
+        let query = cache.add_query(&local_id, &peer_id, QueryId([2; 32]));
+        assert!(
+            tokio::time::timeout(Duration::from_millis(10), query.wait())
+                .await
+                .is_err()
+        );
+        cache.update_query(&local_id, &peer_id, &QueryId([2; 32]), b"answer");
+        assert_eq!(query.wait().await.as_deref(), Some(&b"answer"[..]));
+        drop(query);
+
+        // Late answer is silently dropped
+        let query = cache.add_query(&local_id, &peer_id, QueryId([3; 32]));
+        drop(query);
+        assert_eq!(cache.len(), 1);
+        assert_eq!(
+            cache.update_query(&local_id, &peer_id, &QueryId([3; 32]), b"late"),
+            QueryUpdateResult::Cancelled
+        );
+        assert_eq!(cache.answers_dropped(), 1);
+        assert!(cache.is_empty());
+
+        // Cancelled entries are bounded
+        for i in 0..=MAX_CANCELLED_QUERIES {
+            let mut query_id = [0; 32];
+            query_id[..8].copy_from_slice(&(i as u64).to_le_bytes());
+            drop(cache.add_query(&local_id, &peer_id, QueryId(query_id)));
+        }
+        assert!(cache.is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn answer_and_drop_race() {
+        const QUERIES: usize = 2000;
+
+        let cache = Arc::new(QueriesCache::default());
+        let (local_id, peer_id) = make_ids();
+
+        let mut handles = Vec::with_capacity(QUERIES);
+        for i in 0..QUERIES {
+            let query_id = QueryId(rand::random());
+            let query = cache.add_query(&local_id, &peer_id, query_id);
+
+            let waiter = tokio::spawn(async move {
+                let timeout = Duration::from_micros((i % 50) as u64);
+                tokio::time::timeout(timeout, query.wait())
+                    .await
+                    .ok()
+                    .flatten()
+            });
+
+            let cache = cache.clone();
+            let answerer = tokio::spawn(async move {
+                tokio::task::yield_now().await;
+                cache.update_query(&local_id, &peer_id, &query_id, b"answer")
+            });
+
+            handles.push((waiter, answerer));
+        }
+
+        let mut received = 0;
+        let mut evicted = 0;
+        for (waiter, answerer) in handles {
+            let answer = waiter.await.unwrap();
+            let result = answerer.await.unwrap();
+            if let Some(answer) = answer {
+                assert_eq!(answer, b"answer");
+                assert_eq!(result, QueryUpdateResult::Updated);
+                received += 1;
+            } else if result == QueryUpdateResult::Unknown {
+                // Cancelled entry was removed before the answer arrived
+                evicted += 1;
+            }
+        }
+
+        // Each answer was either received or counted as dropped
+        let dropped = cache.answers_dropped() as usize;
+        assert_eq!(
+            received + dropped + evicted,
+            QUERIES,
+            "received: {received}, dropped: {dropped}, evicted: {evicted}"
+        );
+        assert!(cache.is_empty());
+    }
+}
@@ -0,0 +1,101 @@
+use std::borrow::Cow;
+use std::net::SocketAddrV4;
+
+use anyhow::Result;
+
+use super::node_id::{NodeIdFull, NodeIdShort};
+use crate::proto;
+use crate::subscriber::{
+    MessageSubscriber, QueryConsumingResult, QuerySubscriber, SubscriberContext,
+};
+use crate::util::FastHashSet;
+
+/// Helps the peers behind NAT to connect to each other, see [`crate::adnl::Node::connect_via`].
+///
+/// Answers [`proto::rpc::NetworkRendezvous`] queries of the trusted peers on the rendezvous
+/// peer (others always get [`proto::adnl::RendezvousPeer::NotFound`], so that the observed
+/// addresses are not revealed to anyone). Starts the simultaneous open on
+/// [`proto::adnl::HolePunch`] messages on the target if they were sent by the trusted
+/// rendezvous peer, or by the peer through which [`crate::adnl::Node::connect_via`]
+/// is in progress.
+///
+/// NOTE: the target sends handshake packets to the address from the rendezvous peer,
+/// so only the peers which don't abuse it for the traffic reflection should be trusted
+pub struct RendezvousSubscriber {
+    trusted_peers: FastHashSet<NodeIdShort>,
+}
+
+impl RendezvousSubscriber {
+    pub fn new<I>(trusted_peers: I) -> Self
+    where
+        I: IntoIterator<Item = NodeIdShort>,
+    {
+        Self {
+            trusted_peers: trusted_peers.into_iter().collect(),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl QuerySubscriber for RendezvousSubscriber {
+    async fn try_consume_query<'a>(
+        &self,
+        ctx: SubscriberContext<'a>,
+        constructor: u32,
+        query: Cow<'a, [u8]>,
+    ) -> Result<QueryConsumingResult<'a>> {
+        if constructor != proto::rpc::NetworkRendezvous::TL_ID {
+            return Ok(QueryConsumingResult::reject(query));
+        }
+
+        let proto::rpc::NetworkRendezvous { target } = tl_proto::deserialize(&query)?;
+        if !self.trusted_peers.contains(ctx.peer_id) {
+            tracing::debug!(peer_id = %ctx.peer_id, "ignored rendezvous query from untrusted peer");
+            return Ok(QueryConsumingResult::answer(
+                proto::adnl::RendezvousPeer::NotFound,
+            ));
+        }
+
+        let target = NodeIdShort::new(*target);
+        let answer = match ctx
+            .adnl
+            .introduce_peers(ctx.local_id, ctx.peer_id, &target)?
+        {
+            Some((id, addr)) => proto::adnl::RendezvousPeer::Found {
+                id: id.as_tl().as_equivalent_owned(),
+                address: (&addr).into(),
+            },
+            None => proto::adnl::RendezvousPeer::NotFound,
+        };
+        Ok(QueryConsumingResult::answer(answer))
+    }
+
+    fn interested_constructors(&self) -> &[u32] {
+        &[proto::rpc::NetworkRendezvous::TL_ID]
+    }
+}
+
+#[async_trait::async_trait]
+impl MessageSubscriber for RendezvousSubscriber {
+    async fn try_consume_custom<'a>(
+        &self,
+        ctx: SubscriberContext<'a>,
+        constructor: u32,
+        data: &'a [u8],
+    ) -> Result<bool> {
+        if constructor != proto::adnl::HolePunch::TL_ID {
+            return Ok(false);
+        }
+
+        let proto::adnl::HolePunch { id, address } = tl_proto::deserialize(data)?;
+        if !self.trusted_peers.contains(ctx.peer_id) && !ctx.adnl.is_connecting_via(ctx.peer_id) {
+            tracing::debug!(peer_id = %ctx.peer_id, "ignored hole punch from untrusted peer");
+            return Ok(true);
+        }
+
+        let peer_id_full = NodeIdFull::try_from(id)?;
+        ctx.adnl
+            .start_hole_punch(ctx.local_id, peer_id_full, SocketAddrV4::from(address))?;
+        Ok(true)
+    }
+}
@@ -0,0 +1,224 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use arc_swap::ArcSwap;
+
+use super::node_id::NodeIdShort;
+use crate::util::{Clock, FastDashMap};
+
+/// Peer reputation storage which is consulted and updated by all layers
+/// (e.g. to keep scores in the application database between restarts),
+/// see [`Node::set_reputation`]
+///
+/// [`Node::set_reputation`]: super::Node::set_reputation
+pub trait PeerReputation: Send + Sync {
+    /// Current score of the peer. Zero for unknown peers, negative for the misbehaving ones
+    fn score(&self, peer_id: &NodeIdShort) -> i32;
+
+    /// Records the peer behaviour
+    fn report(&self, peer_id: &NodeIdShort, event: ReputationEvent);
+}
+
+/// Observed peer behaviour, see [`PeerReputation::report`]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+#[non_exhaustive]
+pub enum ReputationEvent {
+    /// Peer returned a valid answer to the query
+    QueryAnswered,
+    /// Peer didn't answer the query in time
+    QueryTimeout,
+    /// Peer rejected the query or the query failed
+    QueryFailed,
+    /// Peer answered with something which is not a valid answer
+    MalformedAnswer,
+    /// Peer forwarded our own broadcast back
+    BroadcastEcho,
+    /// Peer sent a broadcast (or its part) with an invalid signature
+    BadBroadcastSignature,
+    /// Peer sent data which was not decoded into a valid message
+    /// (e.g. signed FEC broadcast with a corrupted payload or an RLDP transfer with garbage)
+    CorruptTransfer,
+    /// Peer answered a query which was sent to another peer
+    /// (or sent a part of an RLDP transfer with another peer)
+    Spoofing,
+}
+
+/// Default in-memory reputation storage.
+///
+/// Scores decay towards zero with the specified half-life, so that old failures
+/// are eventually forgiven. Own broadcast echoes prove that the peer is alive,
+/// but not that it behaves well, so they can only restore the score up to zero.
+pub struct InMemoryReputation {
+    clock: Arc<dyn Clock>,
+    half_life: Duration,
+    scores: FastDashMap<NodeIdShort, StoredScore>,
+    /// Forgiven peers are removed when the table grows up to this size
+    shrink_at: AtomicUsize,
+}
+
+#[derive(Copy, Clone)]
+struct StoredScore {
+    value: f64,
+    updated_at: Instant,
+}
+
+impl InMemoryReputation {
+    /// One hour
+    pub const DEFAULT_HALF_LIFE: Duration = Duration::from_secs(3600);
+
+    /// Lowest score, reached with a single [`ReputationEvent::Spoofing`]
+    pub const MIN_SCORE: i32 = -100;
+    /// Highest score, so that the good history doesn't cover the new failures for too long
+    pub const MAX_SCORE: i32 = 20;
+
+    pub fn new(clock: Arc<dyn Clock>, half_life: Duration) -> Self {
+        Self {
+            clock,
+            half_life,
+            scores: Default::default(),
+            shrink_at: AtomicUsize::new(MIN_SHRINK_LEN),
+        }
+    }
+
+    /// Score change for the event
+    pub fn delta(event: ReputationEvent) -> i32 {
+        match event {
+            ReputationEvent::QueryAnswered | ReputationEvent::BroadcastEcho => 1,
+            ReputationEvent::QueryTimeout | ReputationEvent::QueryFailed => -2,
+            ReputationEvent::MalformedAnswer => -5,
+            ReputationEvent::BadBroadcastSignature | ReputationEvent::CorruptTransfer => -50,
+            ReputationEvent::Spoofing => -100,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.scores.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scores.is_empty()
+    }
+
+    /// Removes peers with negligible scores
+    fn shrink(&self) {
+        let now = self.clock.instant();
+        self.scores
+            .retain(|_, score| self.decayed(score, now).round() != 0.0);
+        self.shrink_at.store(
+            (self.scores.len() * 2).max(MIN_SHRINK_LEN),
+            Ordering::Relaxed,
+        );
+    }
+
+    fn decayed(&self, score: &StoredScore, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(score.updated_at);
+        if self.half_life.is_zero() {
+            return score.value;
+        }
+        score.value * 0.5f64.powf(elapsed.as_secs_f64() / self.half_life.as_secs_f64())
+    }
+}
+
+impl PeerReputation for InMemoryReputation {
+    fn score(&self, peer_id: &NodeIdShort) -> i32 {
+        match self.scores.get(peer_id) {
+            Some(score) => self.decayed(&score, self.clock.instant()).round() as i32,
+            None => 0,
+        }
+    }
+
+    fn report(&self, peer_id: &NodeIdShort, event: ReputationEvent) {
+        if self.scores.len() >= self.shrink_at.load(Ordering::Relaxed) {
+            self.shrink();
+        }
+
+        let now = self.clock.instant();
+        let mut score = self.scores.entry(*peer_id).or_insert(StoredScore {
+            value: 0.0,
+            updated_at: now,
+        });
+
+        let mut value = self.decayed(&score, now);
+        let delta = Self::delta(event) as f64;
+        value = match event {
+            ReputationEvent::BroadcastEcho if value < 0.0 => (value + delta).min(0.0),
+            ReputationEvent::BroadcastEcho => value,
+            _ => value + delta,
+        };
+
+        *score = StoredScore {
+            value: value.clamp(Self::MIN_SCORE as f64, Self::MAX_SCORE as f64),
+            updated_at: now,
+        };
+    }
+}
+
+const MIN_SHRINK_LEN: usize = 1024;
+
+/// Replaceable reputation storage shared between the layers
+#[derive(Clone)]
+pub(crate) struct SharedReputation(Arc<ArcSwap<Arc<dyn PeerReputation>>>);
+
+impl SharedReputation {
+    pub fn new(reputation: Arc<dyn PeerReputation>) -> Self {
+        Self(Arc::new(ArcSwap::from_pointee(reputation)))
+    }
+
+    pub fn get(&self) -> Arc<dyn PeerReputation> {
+        self.0.load().as_ref().clone()
+    }
+
+    pub fn set(&self, reputation: Arc<dyn PeerReputation>) {
+        self.0.store(Arc::new(reputation));
+    }
+
+    #[cfg(feature = "overlay")]
+    pub fn score(&self, peer_id: &NodeIdShort) -> i32 {
+        self.0.load().score(peer_id)
+    }
+
+    pub fn report(&self, peer_id: &NodeIdShort, event: ReputationEvent) {
+        tracing::trace!(%peer_id, ?event, "peer reputation event");
+        self.0.load().report(peer_id, event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::ManualClock;
+
+    #[test]
+    fn scores_decay() {
+        let clock = ManualClock::new(1000);
+        let reputation = InMemoryReputation::new(Arc::new(clock.clone()), Duration::from_secs(60));
+        let peer_id = NodeIdShort::new([1; 32]);
+
+        reputation.report(&peer_id, ReputationEvent::CorruptTransfer);
+        assert_eq!(reputation.score(&peer_id), -50);
+
+        // Echo can't make the score positive
+        reputation.report(&peer_id, ReputationEvent::BroadcastEcho);
+        assert_eq!(reputation.score(&peer_id), -49);
+
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(reputation.score(&peer_id), -25);
+        reputation.report(&peer_id, ReputationEvent::Spoofing);
+        assert_eq!(reputation.score(&peer_id), InMemoryReputation::MIN_SCORE);
+
+        clock.advance(Duration::from_secs(600));
+        assert_eq!(reputation.score(&peer_id), 0);
+        reputation.shrink();
+        assert!(reputation.is_empty());
+
+        for _ in 0..3 {
+            reputation.report(&peer_id, ReputationEvent::BroadcastEcho);
+        }
+        assert_eq!(reputation.score(&peer_id), 0);
+        for _ in 0..30 {
+            reputation.report(&peer_id, ReputationEvent::QueryAnswered);
+        }
+        assert_eq!(reputation.score(&peer_id), InMemoryReputation::MAX_SCORE);
+    }
+}
@@ -0,0 +1,185 @@
+use std::collections::VecDeque;
+use std::net::SocketAddrV4;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+
+/// What to do with an outgoing packet when the peer send queue is full
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SendQueuePolicy {
+    /// Drop the packet which is being sent
+    #[default]
+    DropNewest,
+    /// Drop the oldest packet in the queue
+    DropOldest,
+    /// Wait for the free space before sending queries (see [`Node::query`]).
+    /// Query packets are never dropped, other packets are dropped as with `DropNewest`
+    ///
+    /// [`Node::query`]: crate::adnl::Node::query
+    BlockQueries,
+}
+
+/// Outgoing packets of a single peer
+#[derive(Default)]
+pub struct SendQueue {
+    state: Mutex<SendQueueState>,
+    dropped: AtomicU64,
+    space_available: Notify,
+}
+
+impl SendQueue {
+    /// Number of packets waiting to be sent
+    pub fn len(&self) -> usize {
+        self.state.lock().packets.len()
+    }
+
+    /// Total number of packets dropped because of the full queue
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Adds the packet to the queue. `capacity` of zero means an unbounded queue.
+    ///
+    /// Forced packets are queued even if the queue is full
+    pub fn push(
+        &self,
+        packet: PacketToSend,
+        capacity: usize,
+        policy: SendQueuePolicy,
+        force: bool,
+    ) -> PushResult {
+        let mut state = self.state.lock();
+
+        let dropped = if force || capacity == 0 || state.packets.len() < capacity {
+            None
+        } else if policy == SendQueuePolicy::DropOldest {
+            state.packets.pop_front()
+        } else {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return PushResult {
+                schedule: false,
+                dropped: Some(packet),
+            };
+        };
+        if dropped.is_some() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+
+        state.packets.push_back(packet);
+        PushResult {
+            schedule: !std::mem::replace(&mut state.scheduled, true),
+            dropped,
+        }
+    }
+
+    /// Takes the next packet. The second value is `false` when the queue became empty
+    /// and must be scheduled again by the next [`SendQueue::push`]
+    pub fn pop(&self) -> (Option<PacketToSend>, bool) {
+        let mut state = self.state.lock();
+        let packet = state.packets.pop_front();
+        let has_more = !state.packets.is_empty();
+        state.scheduled = has_more;
+        drop(state);
+
+        self.space_available.notify_waiters();
+        (packet, has_more)
+    }
+
+    /// Waits until the queue has less than `max_len` packets
+    pub async fn wait_for_space(&self, max_len: usize) {
+        if max_len == 0 {
+            return;
+        }
+        loop {
+            let notified = self.space_available.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            if self.len() < max_len {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+#[derive(Default)]
+struct SendQueueState {
+    packets: VecDeque<PacketToSend>,
+    /// Whether the queue is in the sender loop ready list
+    scheduled: bool,
+}
+
+pub struct PushResult {
+    /// Queue must be added to the sender loop ready list
+    pub schedule: bool,
+    /// Packet which was dropped instead of being sent
+    pub dropped: Option<PacketToSend>,
+}
+
+pub struct PacketToSend {
+    pub destination: SocketAddrV4,
+    pub data: Vec<u8>,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    fn packet(i: u8) -> PacketToSend {
+        PacketToSend {
+            destination: SocketAddrV4::new(Ipv4Addr::LOCALHOST, 123),
+            data: vec![i],
+        }
+    }
+
+    fn drain(queue: &SendQueue) -> Vec<u8> {
+        std::iter::from_fn(|| queue.pop().0.map(|packet| packet.data[0])).collect()
+    }
+
+    #[test]
+    fn drop_policies() {
+        let queue = SendQueue::default();
+        assert!(
+            queue
+                .push(packet(0), 2, SendQueuePolicy::DropNewest, false)
+                .schedule
+        );
+        assert!(
+            !queue
+                .push(packet(1), 2, SendQueuePolicy::DropNewest, false)
+                .schedule
+        );
+        let result = queue.push(packet(2), 2, SendQueuePolicy::DropNewest, false);
+        assert_eq!(result.dropped.unwrap().data, [2]);
+        assert_eq!(drain(&queue), [0, 1]);
+
+        // Empty queue must be scheduled again
+        assert!(
+            queue
+                .push(packet(0), 2, SendQueuePolicy::DropOldest, false)
+                .schedule
+        );
+        queue.push(packet(1), 2, SendQueuePolicy::DropOldest, false);
+        let result = queue.push(packet(2), 2, SendQueuePolicy::DropOldest, false);
+        assert_eq!(result.dropped.unwrap().data, [0]);
+        assert_eq!(drain(&queue), [1, 2]);
+
+        queue.push(packet(0), 1, SendQueuePolicy::BlockQueries, false);
+        assert!(queue
+            .push(packet(1), 1, SendQueuePolicy::BlockQueries, false)
+            .dropped
+            .is_some());
+        assert!(queue
+            .push(packet(2), 1, SendQueuePolicy::BlockQueries, true)
+            .dropped
+            .is_none());
+        assert_eq!(drain(&queue), [0, 2]);
+
+        assert_eq!(queue.dropped(), 3);
+    }
+}
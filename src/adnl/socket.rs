@@ -1,9 +1,38 @@
-use std::net::Ipv4Addr;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
 use std::sync::Arc;
 
 use anyhow::Result;
 use tokio::net::UdpSocket;
 
+#[cfg(any(test, feature = "test-utils"))]
+use super::virtual_network::VirtualSocket;
+
+/// Transport used by the sender and receiver loops
+#[derive(Clone)]
+pub enum NodeSocket {
+    Udp(Arc<UdpSocket>),
+    #[cfg(any(test, feature = "test-utils"))]
+    Virtual(Arc<VirtualSocket>),
+}
+
+impl NodeSocket {
+    pub async fn send_to(&self, data: &[u8], target: SocketAddrV4) -> std::io::Result<usize> {
+        match self {
+            Self::Udp(socket) => socket.send_to(data, target).await,
+            #[cfg(any(test, feature = "test-utils"))]
+            Self::Virtual(socket) => Ok(socket.send_to(data, target).await),
+        }
+    }
+
+    pub async fn recv_from(&self, buffer: &mut [u8]) -> std::io::Result<(usize, SocketAddr)> {
+        match self {
+            Self::Udp(socket) => socket.recv_from(buffer).await,
+            #[cfg(any(test, feature = "test-utils"))]
+            Self::Virtual(socket) => socket.recv_from(buffer).await,
+        }
+    }
+}
+
 pub fn make_udp_socket(port: u16) -> Result<Arc<UdpSocket>> {
     let udp_socket = std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port))?;
     udp_socket.set_nonblocking(true)?;
@@ -20,6 +49,31 @@ pub fn make_udp_socket(port: u16) -> Result<Arc<UdpSocket>> {
     Ok(Arc::new(UdpSocket::from_std(udp_socket)?))
 }
 
+/// Registers the pre-bound non-blocking socket in the current runtime IO driver.
+/// Returns the socket with its local address
+pub fn wrap_udp_socket(udp_socket: std::net::UdpSocket) -> Result<(Arc<UdpSocket>, SocketAddrV4)> {
+    let local_addr = match udp_socket.local_addr()? {
+        SocketAddr::V4(addr) if addr.port() != 0 => addr,
+        SocketAddr::V4(_) => return Err(SocketError::NotBound.into()),
+        SocketAddr::V6(_) => return Err(SocketError::NotIpv4.into()),
+    };
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::io::AsRawFd;
+
+        let flags = unsafe { libc::fcntl(udp_socket.as_raw_fd(), libc::F_GETFL) };
+        cvt(flags)?;
+        if flags & libc::O_NONBLOCK == 0 {
+            return Err(SocketError::Blocking.into());
+        }
+    }
+    #[cfg(not(unix))]
+    udp_socket.set_nonblocking(true)?;
+
+    Ok((Arc::new(UdpSocket::from_std(udp_socket)?), local_addr))
+}
+
 #[cfg(unix)]
 fn set_reuse_port(socket: libc::c_int, reuse: bool) -> Result<()> {
     unsafe {
@@ -104,3 +158,13 @@ fn cvt(res: libc::c_int) -> std::io::Result<()> {
         Ok(())
     }
 }
+
+#[derive(thiserror::Error, Debug)]
+enum SocketError {
+    #[error("Socket is not bound")]
+    NotBound,
+    #[error("Only IPv4 sockets are supported")]
+    NotIpv4,
+    #[error("Socket is not in non-blocking mode")]
+    Blocking,
+}
@@ -0,0 +1,609 @@
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+use tokio::runtime::Handle;
+use tokio::sync::mpsc;
+
+use super::keystore::Keystore;
+use super::node::{Node, NodeOptions};
+use super::peer::PeerFilter;
+use super::socket::NodeSocket;
+use crate::util::{spawn_named, Clock, FastDashMap, FastHashMap, FastHashSet, SystemClock};
+
+/// Port which is used for all virtual addresses
+const VIRTUAL_PORT: u16 = 30303;
+
+/// Directed link parameters
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct LinkOptions {
+    /// Base one-way delay in milliseconds.
+    ///
+    /// Default: `0`
+    pub latency_ms: u64,
+
+    /// Max random delay in milliseconds which is added to the latency.
+    /// Packets are still delivered in the order they were sent.
+    ///
+    /// Default: `0`
+    pub jitter_ms: u64,
+
+    /// Probability of the packet loss (`0.0..=1.0`).
+    ///
+    /// Default: `0.0`
+    pub loss: f64,
+
+    /// Probability that the packet will be delayed out of order (`0.0..=1.0`).
+    ///
+    /// Default: `0.0`
+    pub reorder: f64,
+
+    /// Additional delay in milliseconds for the reordered packets.
+    ///
+    /// Default: `10`
+    pub reorder_delay_ms: u64,
+
+    /// Larger packets are dropped (like fragmented datagrams on a link with small MTU).
+    ///
+    /// Default: None
+    pub max_datagram_size: Option<usize>,
+}
+
+impl LinkOptions {
+    /// Link which drops all packets
+    pub fn disconnected() -> Self {
+        Self {
+            loss: 1.0,
+            ..Default::default()
+        }
+    }
+
+    fn is_disconnected(&self) -> bool {
+        self.loss >= 1.0
+    }
+}
+
+impl Default for LinkOptions {
+    fn default() -> Self {
+        Self {
+            latency_ms: 0,
+            jitter_ms: 0,
+            loss: 0.0,
+            reorder: 0.0,
+            reorder_delay_ms: 10,
+            max_datagram_size: None,
+        }
+    }
+}
+
+/// In-memory network for the ADNL nodes.
+///
+/// Replaces UDP sockets with in-memory channels, so multiple
+/// nodes can be linked in a single process without binding real ports.
+/// Each directed link between two nodes can be configured with latency,
+/// jitter, packet loss and reordering.
+///
+/// # Topologies
+///
+/// Nodes are identified by their virtual socket addresses. A topology is built
+/// by changing the default link and overriding some of the directed links.
+///
+/// Ring (each node is only connected to its neighbours):
+///
+/// ```
+/// # use everscale_network::adnl::{self, LinkOptions, VirtualNetwork};
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() -> anyhow::Result<()> {
+/// let network = VirtualNetwork::new(123);
+/// network.set_default_link(LinkOptions::disconnected());
+///
+/// let nodes = (0..4)
+///     .map(|_| {
+///         let keystore = adnl::Keystore::builder()
+///             .with_tagged_key(rand::random(), 0)?
+///             .build();
+///         Ok(network.add_node(keystore, Default::default(), None))
+///     })
+///     .collect::<anyhow::Result<Vec<_>>>()?;
+///
+/// for (i, node) in nodes.iter().enumerate() {
+///     let next = &nodes[(i + 1) % nodes.len()];
+///     network.connect(node.socket_addr(), next.socket_addr(), LinkOptions::default());
+/// }
+/// # Ok(())
+/// # }
+/// ```
+///
+/// Star (all traffic goes through the hub):
+///
+/// ```
+/// # use std::net::SocketAddrV4;
+/// # use everscale_network::adnl::{LinkOptions, VirtualNetwork};
+/// fn make_star(network: &VirtualNetwork, hub: SocketAddrV4, leaves: &[SocketAddrV4]) {
+///     network.set_default_link(LinkOptions::disconnected());
+///     for leaf in leaves {
+///         let link = LinkOptions {
+///             latency_ms: 20,
+///             jitter_ms: 5,
+///             ..Default::default()
+///         };
+///         network.connect(hub, *leaf, link);
+///     }
+/// }
+/// ```
+///
+/// Partition and heal:
+///
+/// ```
+/// # use std::net::SocketAddrV4;
+/// # use everscale_network::adnl::VirtualNetwork;
+/// async fn split_brain(network: &VirtualNetwork, left: &[SocketAddrV4], right: &[SocketAddrV4]) {
+///     network.partition(left, right);
+///     // ... check that each side keeps working on its own
+///
+///     network.heal();
+///     // ... check that both sides converge again
+/// }
+/// ```
+#[derive(Clone)]
+pub struct VirtualNetwork {
+    inner: Arc<VirtualNetworkInner>,
+}
+
+impl VirtualNetwork {
+    /// Creates an empty network which uses system time for delays.
+    ///
+    /// `seed` is used for the packet loss, jitter and reordering decisions
+    pub fn new(seed: u64) -> Self {
+        Self::with_clock(seed, Arc::new(SystemClock))
+    }
+
+    /// Creates an empty network with a custom clock.
+    ///
+    /// The same clock is used for all created nodes
+    pub fn with_clock(seed: u64, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            inner: Arc::new(VirtualNetworkInner {
+                clock,
+                rng: Mutex::new(SmallRng::seed_from_u64(seed)),
+                endpoints: Default::default(),
+                queues: Default::default(),
+                default_link: Mutex::new(LinkOptions::default()),
+                links: Default::default(),
+                partitioned: Default::default(),
+                uplink_delays: Default::default(),
+                nats: Default::default(),
+                next_node: AtomicU32::new(1),
+                packets_delivered: Default::default(),
+                packets_dropped: Default::default(),
+            }),
+        }
+    }
+
+    /// Creates a new ADNL node with a unique virtual address.
+    ///
+    /// NOTE: node must be started as usual
+    pub fn add_node(
+        &self,
+        keystore: Keystore,
+        options: NodeOptions,
+        peer_filter: Option<Arc<dyn PeerFilter>>,
+    ) -> Arc<Node> {
+        let index = self.inner.next_node.fetch_add(1, Ordering::Relaxed);
+        let [_, a, b, c] = index.to_be_bytes();
+        let addr = SocketAddrV4::new(Ipv4Addr::new(10, a, b, c), VIRTUAL_PORT);
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.inner.endpoints.insert(addr, tx);
+
+        let socket = Arc::new(VirtualSocket {
+            addr,
+            network: self.inner.clone(),
+            rx: tokio::sync::Mutex::new(rx),
+        });
+
+        Node::with_socket(
+            addr,
+            NodeSocket::Virtual(socket),
+            keystore,
+            options,
+            peer_filter,
+            self.inner.clock.clone(),
+            Handle::current(),
+        )
+    }
+
+    /// Sets the parameters of all links which were not configured explicitly
+    pub fn set_default_link(&self, options: LinkOptions) {
+        *self.inner.default_link.lock() = options;
+    }
+
+    /// Overrides the parameters of the directed link
+    pub fn set_link(&self, from: SocketAddrV4, to: SocketAddrV4, options: LinkOptions) {
+        self.inner.links.lock().insert((from, to), options);
+    }
+
+    /// Overrides the parameters of the link in both directions
+    pub fn connect(&self, left: SocketAddrV4, right: SocketAddrV4, options: LinkOptions) {
+        let mut links = self.inner.links.lock();
+        links.insert((left, right), options);
+        links.insert((right, left), options);
+    }
+
+    /// Drops all packets between two nodes in both directions
+    pub fn disconnect(&self, left: SocketAddrV4, right: SocketAddrV4) {
+        self.connect(left, right, LinkOptions::disconnected());
+    }
+
+    /// Removes the override of the directed link
+    pub fn reset_link(&self, from: SocketAddrV4, to: SocketAddrV4) {
+        self.inner.links.lock().remove(&(from, to));
+    }
+
+    /// Disconnects each node of the left group from each node of the right group.
+    ///
+    /// Link overrides are kept and will be used again after [`VirtualNetwork::heal`]
+    pub fn partition(&self, left: &[SocketAddrV4], right: &[SocketAddrV4]) {
+        let mut partitioned = self.inner.partitioned.lock();
+        for left in left {
+            for right in right {
+                partitioned.insert((*left, *right));
+                partitioned.insert((*right, *left));
+            }
+        }
+    }
+
+    /// Removes all partitions
+    pub fn heal(&self) {
+        self.inner.partitioned.lock().clear();
+    }
+
+    /// Makes each packet send from the node take the specified time
+    /// (like a slow uplink which blocks the socket). Zero removes the delay
+    pub fn set_uplink_delay(&self, addr: SocketAddrV4, delay: Duration) {
+        let mut uplink_delays = self.inner.uplink_delays.lock();
+        if delay.is_zero() {
+            uplink_delays.remove(&addr);
+        } else {
+            uplink_delays.insert(addr, delay);
+        }
+    }
+
+    /// Places the node behind an address-restricted NAT. Returns its external address.
+    ///
+    /// Packets from the node leave with the external address. Packets to the external
+    /// address are only delivered from the IPs which the node has sent packets to.
+    /// The internal address becomes unreachable for other nodes.
+    ///
+    /// Links are still configured with the internal address
+    pub fn add_nat(&self, addr: SocketAddrV4) -> SocketAddrV4 {
+        let [_, a, b, c] = addr.ip().octets();
+        let external = SocketAddrV4::new(Ipv4Addr::new(100, a, b, c), addr.port());
+
+        let mut nats = self.inner.nats.lock();
+        nats.by_external.insert(external, addr);
+        nats.by_internal.insert(
+            addr,
+            Nat {
+                external,
+                allowed_ips: Default::default(),
+            },
+        );
+        external
+    }
+
+    /// Total number of packets delivered to the nodes
+    pub fn packets_delivered(&self) -> u64 {
+        self.inner.packets_delivered.load(Ordering::Relaxed)
+    }
+
+    /// Total number of packets dropped by the links (or sent to unknown addresses)
+    pub fn packets_dropped(&self) -> u64 {
+        self.inner.packets_dropped.load(Ordering::Relaxed)
+    }
+}
+
+struct VirtualNetworkInner {
+    clock: Arc<dyn Clock>,
+    rng: Mutex<SmallRng>,
+    endpoints: FastDashMap<SocketAddrV4, PacketsTx>,
+    /// Ordered delivery queues for the delayed packets of each directed link
+    queues: FastDashMap<(SocketAddrV4, SocketAddrV4), DelayedPacketsTx>,
+    default_link: Mutex<LinkOptions>,
+    links: Mutex<FastHashMap<(SocketAddrV4, SocketAddrV4), LinkOptions>>,
+    partitioned: Mutex<FastHashSet<(SocketAddrV4, SocketAddrV4)>>,
+    uplink_delays: Mutex<FastHashMap<SocketAddrV4, Duration>>,
+    nats: Mutex<Nats>,
+    next_node: AtomicU32,
+    packets_delivered: AtomicU64,
+    packets_dropped: AtomicU64,
+}
+
+impl VirtualNetworkInner {
+    fn link_options(&self, from: SocketAddrV4, to: SocketAddrV4) -> LinkOptions {
+        if self.partitioned.lock().contains(&(from, to)) {
+            return LinkOptions::disconnected();
+        }
+        match self.links.lock().get(&(from, to)) {
+            Some(options) => *options,
+            None => *self.default_link.lock(),
+        }
+    }
+
+    /// Returns the source and the destination addresses after NAT,
+    /// or `None` if the packet is filtered
+    fn translate(
+        &self,
+        from: SocketAddrV4,
+        to: SocketAddrV4,
+    ) -> Option<(SocketAddrV4, SocketAddrV4)> {
+        let mut nats = self.nats.lock();
+        if nats.by_internal.contains_key(&to) {
+            return None;
+        }
+
+        let source = match nats.by_internal.get_mut(&from) {
+            Some(nat) => {
+                nat.allowed_ips.insert(*to.ip());
+                nat.external
+            }
+            None => from,
+        };
+
+        let destination = match nats.by_external.get(&to) {
+            Some(internal) => {
+                let nat = nats.by_internal.get(internal)?;
+                if !nat.allowed_ips.contains(source.ip()) {
+                    return None;
+                }
+                *internal
+            }
+            None => to,
+        };
+
+        Some((source, destination))
+    }
+
+    fn send(self: &Arc<Self>, from: SocketAddrV4, to: SocketAddrV4, data: &[u8]) {
+        let (source, to) = match self.translate(from, to) {
+            Some(addrs) => addrs,
+            None => {
+                self.packets_dropped.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+        };
+
+        let options = self.link_options(from, to);
+        if options.is_disconnected()
+            || matches!(options.max_datagram_size, Some(max) if data.len() > max)
+        {
+            self.packets_dropped.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+
+        let (lost, delay, reordered) = {
+            let mut rng = self.rng.lock();
+            let lost = options.loss > 0.0 && rng.gen_bool(options.loss);
+            let jitter = match options.jitter_ms {
+                0 => 0,
+                jitter => rng.gen_range(0..=jitter),
+            };
+            let reordered = options.reorder > 0.0 && rng.gen_bool(options.reorder.min(1.0));
+            (lost, options.latency_ms + jitter, reordered)
+        };
+
+        if lost {
+            self.packets_dropped.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+
+        let packet = (source, data.to_vec());
+        if reordered {
+            // Deliver separately from the link queue
+            let network = Arc::downgrade(self);
+            let sleep = self
+                .clock
+                .sleep(Duration::from_millis(delay + options.reorder_delay_ms));
+            spawn_named(&Handle::current(), "virtual_reordered_packet", async move {
+                sleep.await;
+                if let Some(network) = network.upgrade() {
+                    network.deliver(to, packet);
+                }
+            });
+        } else if delay == 0 {
+            self.deliver(to, packet);
+        } else {
+            let deadline = self.clock.instant() + Duration::from_millis(delay);
+            let queue = self
+                .queues
+                .entry((from, to))
+                .or_insert_with(|| self.spawn_link_queue(to))
+                .clone();
+            queue.send((deadline, packet)).ok();
+        }
+    }
+
+    /// Starts a task which delivers delayed packets in order
+    fn spawn_link_queue(self: &Arc<Self>, to: SocketAddrV4) -> DelayedPacketsTx {
+        let (tx, mut rx) = mpsc::unbounded_channel::<(Instant, Packet)>();
+
+        // NOTE: task is stopped when the network is dropped
+        let network = Arc::downgrade(self);
+        let clock = self.clock.clone();
+        spawn_named(&Handle::current(), "virtual_link_queue", async move {
+            while let Some((deadline, packet)) = rx.recv().await {
+                if clock.instant() < deadline {
+                    clock.sleep_until(deadline).await;
+                }
+                match Weak::upgrade(&network) {
+                    Some(network) => network.deliver(to, packet),
+                    None => break,
+                }
+            }
+        });
+
+        tx
+    }
+
+    fn deliver(&self, to: SocketAddrV4, packet: Packet) {
+        let delivered = match self.endpoints.get(&to) {
+            Some(endpoint) => endpoint.send(packet).is_ok(),
+            None => false,
+        };
+
+        let counter = match delivered {
+            true => &self.packets_delivered,
+            false => &self.packets_dropped,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Address-restricted NAT mappings
+#[derive(Default)]
+struct Nats {
+    by_internal: FastHashMap<SocketAddrV4, Nat>,
+    by_external: FastHashMap<SocketAddrV4, SocketAddrV4>,
+}
+
+struct Nat {
+    external: SocketAddrV4,
+    /// Remote IPs which are allowed to send packets to the node
+    allowed_ips: FastHashSet<Ipv4Addr>,
+}
+
+/// Node endpoint of the virtual network
+pub struct VirtualSocket {
+    addr: SocketAddrV4,
+    network: Arc<VirtualNetworkInner>,
+    rx: tokio::sync::Mutex<PacketsRx>,
+}
+
+impl VirtualSocket {
+    pub async fn send_to(&self, data: &[u8], target: SocketAddrV4) -> usize {
+        let uplink_delay = self.network.uplink_delays.lock().get(&self.addr).copied();
+        if let Some(delay) = uplink_delay {
+            self.network.clock.sleep(delay).await;
+        }
+
+        self.network.send(self.addr, target, data);
+        data.len()
+    }
+
+    pub async fn recv_from(&self, buffer: &mut [u8]) -> std::io::Result<(usize, SocketAddr)> {
+        let mut rx = self.rx.lock().await;
+        match rx.recv().await {
+            Some((source, data)) => {
+                // Truncate packet like UDP socket does
+                let len = std::cmp::min(data.len(), buffer.len());
+                buffer[..len].copy_from_slice(&data[..len]);
+                Ok((len, SocketAddr::V4(source)))
+            }
+            // NOTE: endpoint is only removed when the socket is dropped
+            None => futures_util::future::pending().await,
+        }
+    }
+}
+
+impl Drop for VirtualSocket {
+    fn drop(&mut self) {
+        self.network.endpoints.remove(&self.addr);
+    }
+}
+
+type Packet = (SocketAddrV4, Vec<u8>);
+type PacketsTx = mpsc::UnboundedSender<Packet>;
+type PacketsRx = mpsc::UnboundedReceiver<Packet>;
+type DelayedPacketsTx = mpsc::UnboundedSender<(Instant, Packet)>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::adnl::NewPeerContext;
+    use crate::util::fixtures::add_virtual_node;
+
+    fn make_pair(network: &VirtualNetwork) -> (Arc<Node>, Arc<Node>) {
+        let make_node = || {
+            let node = add_virtual_node(network, Default::default());
+            node.add_echo_subscriber().unwrap();
+            node.start().unwrap();
+            node
+        };
+
+        let (left, right) = (make_node(), make_node());
+        let left_id = *left.key_by_tag(0).unwrap().id();
+        let right_key = right.key_by_tag(0).unwrap();
+        left.add_peer(
+            NewPeerContext::AdnlPacket,
+            &left_id,
+            right_key.id(),
+            right.socket_addr(),
+            *right_key.full_id(),
+        )
+        .unwrap();
+
+        (left, right)
+    }
+
+    async fn ping(left: &Node, right: &Node) -> Option<Duration> {
+        let left_id = left.key_by_tag(0).unwrap().id();
+        let right_id = right.key_by_tag(0).unwrap().id();
+        let stats = left
+            .ping_peer(left_id, right_id, 16, Some(200))
+            .await
+            .unwrap()?;
+        assert!(stats.intact);
+        Some(stats.rtt)
+    }
+
+    #[tokio::test]
+    async fn links_and_partitions() {
+        let network = VirtualNetwork::new(1);
+        let (left, right) = make_pair(&network);
+        assert_ne!(left.socket_addr(), right.socket_addr());
+
+        // Latency is applied in both directions
+        network.set_default_link(LinkOptions {
+            latency_ms: 20,
+            ..Default::default()
+        });
+        let rtt = ping(&left, &right).await.unwrap();
+        assert!(rtt >= Duration::from_millis(40), "rtt: {rtt:?}");
+
+        // Lossy link with jitter and reordering still delivers most of the pings
+        network.connect(
+            left.socket_addr(),
+            right.socket_addr(),
+            LinkOptions {
+                latency_ms: 2,
+                jitter_ms: 5,
+                loss: 0.2,
+                reorder: 0.2,
+                reorder_delay_ms: 5,
+                ..Default::default()
+            },
+        );
+        let mut answered = 0;
+        for _ in 0..20 {
+            answered += ping(&left, &right).await.is_some() as usize;
+        }
+        assert!(answered > 0 && answered < 20, "answered: {answered}");
+        assert!(network.packets_dropped() > 0);
+
+        // Partition overrides links
+        network.reset_link(left.socket_addr(), right.socket_addr());
+        network.reset_link(right.socket_addr(), left.socket_addr());
+        network.set_default_link(LinkOptions::default());
+        network.partition(&[left.socket_addr()], &[right.socket_addr()]);
+        assert!(ping(&left, &right).await.is_none());
+
+        network.heal();
+        let delivered = network.packets_delivered();
+        assert!(ping(&left, &right).await.is_some());
+        assert!(network.packets_delivered() > delivered);
+    }
+}
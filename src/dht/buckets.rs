@@ -19,7 +19,9 @@ impl Buckets {
     }
 
     /// Returns iterator over all buckets, starting from the most distant
-    pub fn iter(&self) -> std::slice::Iter<FastDashMap<adnl::NodeIdShort, proto::dht::NodeOwned>> {
+    pub fn iter(
+        &self,
+    ) -> std::slice::Iter<'_, FastDashMap<adnl::NodeIdShort, proto::dht::NodeOwned>> {
         self.buckets.iter()
     }
 
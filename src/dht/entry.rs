@@ -9,7 +9,6 @@ use super::node::Node;
 use super::streams::DhtValuesStream;
 use crate::adnl;
 use crate::proto;
-use crate::util::now;
 
 /// DHT entry builder
 #[must_use]
@@ -119,7 +118,7 @@ impl<'a> EntryWithData<'a> {
 
     /// Sets expiration time for the value as `now + ttl`
     pub fn with_ttl(mut self, ttl: u32) -> Self {
-        self.expire_at = Some(now() + ttl);
+        self.expire_at = Some(self.inner.dht.adnl().clock().now() + ttl);
         self
     }
 
@@ -163,9 +162,9 @@ impl<'a> EntryWithData<'a> {
                 signature: Default::default(),
             },
             value: &self.data,
-            ttl: self
-                .expire_at
-                .unwrap_or_else(|| now() + self.inner.dht.options().value_ttl_sec),
+            ttl: self.expire_at.unwrap_or_else(|| {
+                self.inner.dht.adnl().clock().now() + self.inner.dht.options().value_ttl_sec
+            }),
             signature: Default::default(),
         }
     }
@@ -112,10 +112,13 @@ impl Node {
         let key = adnl.key_by_tag(key_tag)?.clone();
 
         let buckets = Buckets::new(key.id());
-        let storage = Storage::new(StorageOptions {
-            max_key_name_len: options.max_key_name_len,
-            max_key_index: options.max_key_index,
-        });
+        let storage = Storage::new(
+            StorageOptions {
+                max_key_name_len: options.max_key_name_len,
+                max_key_index: options.max_key_index,
+            },
+            adnl.clock().clone(),
+        );
 
         let state = Arc::new(NodeState {
             key: key.clone(),
@@ -144,11 +147,12 @@ impl Node {
 
         let state = Arc::downgrade(&dht_node.state);
         let interval = Duration::from_millis(dht_node.options.storage_gc_interval_ms);
-        tokio::spawn(async move {
+        dht_node.adnl.spawn_task("dht_storage_gc", async move {
             loop {
                 tokio::time::sleep(interval).await;
-                if let Some(state) = state.upgrade() {
-                    state.storage.gc();
+                match state.upgrade() {
+                    Some(state) => state.storage.gc(),
+                    None => break,
                 }
             }
         });
@@ -301,7 +305,7 @@ impl Node {
             for node in received
                 .into_iter()
                 .flatten()
-                .chain(std::mem::take(&mut nodes).into_iter())
+                .chain(std::mem::take(&mut nodes))
             {
                 let peer_id = match adnl::NodeIdFull::try_from(node.id.as_equivalent_ref())
                     .map(|full_id| full_id.compute_short_id())
@@ -347,7 +351,11 @@ impl Node {
         let mut values = self.entry(peer_id, KEY_ADDRESS).values();
         while let Some((key, BoxedWrapper(value))) = values.next().await {
             match (
-                parse_address_list(&value, self.adnl.options().clock_tolerance_sec),
+                parse_first_address(
+                    &value,
+                    self.adnl.clock().now(),
+                    self.adnl.options().clock_tolerance_sec,
+                ),
                 adnl::NodeIdFull::try_from(key.id.as_equivalent_ref()),
             ) {
                 (Ok(addr), Ok(full_id)) => return Ok((addr, full_id)),
@@ -394,7 +402,7 @@ impl Node {
                 signature: Default::default(),
             },
             value: &value,
-            ttl: now() + self.options.value_ttl_sec,
+            ttl: self.adnl.clock().now() + self.options.value_ttl_sec,
             signature: Default::default(),
         };
 
@@ -419,21 +427,20 @@ impl Node {
         key: &adnl::Key,
         addr: SocketAddrV4,
     ) -> Result<bool> {
+        let clock = self.adnl.clock().clone();
         let clock_tolerance_sec = self.adnl.options().clock_tolerance_sec;
 
         self.entry(key.id(), KEY_ADDRESS)
             .with_data(
-                proto::adnl::AddressList {
-                    address: Some(proto::adnl::Address::from(&addr)),
-                    version: now(),
-                    reinit_date: self.adnl.start_time(),
-                    expire_at: 0,
-                }
-                .into_boxed(),
+                AddressListBuilder::new()
+                    .with_address(addr)
+                    .with_reinit_date(self.adnl.start_time())
+                    .build()
+                    .into_boxed(),
             )
             .sign_and_store(key)?
             .then_check(move |_, BoxedWrapper(address_list)| {
-                match parse_address_list(&address_list, clock_tolerance_sec)? {
+                match parse_first_address(&address_list, clock.now(), clock_tolerance_sec)? {
                     stored_addr if stored_addr == addr => Ok(true),
                     stored_addr => {
                         tracing::warn!(
@@ -448,13 +455,25 @@ impl Node {
             .await
     }
 
+    /// Updates the peer penalty and reports the answered queries to the peer reputation.
+    ///
+    /// DHT peers are often unreachable, so timeouts and failures are only accounted
+    /// in the DHT penalty and don't lower the node-wide score used by the overlays
+    fn record_query_result<T>(&self, peer_id: &adnl::NodeIdShort, result: &Result<Option<T>>) {
+        if let Ok(Some(_)) = result {
+            self.adnl
+                .report_peer(peer_id, adnl::ReputationEvent::QueryAnswered);
+        }
+        self.state.update_peer_status(peer_id, result.is_ok());
+    }
+
     async fn query<Q, A>(&self, peer_id: &adnl::NodeIdShort, query: Q) -> Result<Option<A>>
     where
         Q: TlWrite,
         for<'a> A: TlRead<'a, Repr = tl_proto::Boxed> + 'static,
     {
         let result = self.adnl.query(&self.local_id, peer_id, query, None).await;
-        self.state.update_peer_status(peer_id, result.is_ok());
+        self.record_query_result(peer_id, &result);
         result
     }
 
@@ -468,11 +487,11 @@ impl Node {
             .query_raw(
                 &self.local_id,
                 peer_id,
-                query,
+                &query,
                 Some(self.options.query_timeout_ms),
             )
             .await;
-        self.state.update_peer_status(peer_id, result.is_ok());
+        self.record_query_result(peer_id, &result);
         result
     }
 
@@ -489,7 +508,7 @@ impl Node {
             .adnl
             .query_with_prefix::<Q, A>(&self.local_id, peer_id, &self.query_prefix, query, None)
             .await;
-        self.state.update_peer_status(peer_id, result.is_ok());
+        self.record_query_result(peer_id, &result);
         result
     }
 
@@ -501,9 +520,9 @@ impl Node {
         for<'a> T: TlRead<'a, Repr = tl_proto::Boxed> + 'static,
     {
         match tl_proto::deserialize::<proto::dht::ValueResult>(result)? {
-            proto::dht::ValueResult::ValueFound(BoxedWrapper(mut value)) => {
+            proto::dht::ValueResult::ValueFound(BoxedWrapper(value)) => {
                 if value.key.update_rule == proto::dht::UpdateRule::Signature {
-                    verify_signed_dht_value(&mut value)?;
+                    verify_signed_dht_value(&value)?;
                 }
 
                 let parsed = tl_proto::deserialize(value.value)?;
@@ -560,34 +579,29 @@ impl NodeState {
     }
 
     fn sign_local_node(&self, addr_list: proto::adnl::AddressList) -> proto::dht::NodeOwned {
-        let mut node = proto::dht::NodeOwned {
-            id: self.key.full_id().as_tl().as_equivalent_owned(),
-            addr_list,
-            version: addr_list.version,
-            signature: Default::default(),
-        };
-        node.signature = self.key.sign(node.as_boxed()).to_vec().into();
-        node
+        sign_dht_node(&self.key, addr_list)
     }
 
     fn add_dht_peer(
         &self,
         adnl: &adnl::Node,
-        mut peer: proto::dht::NodeOwned,
+        peer: proto::dht::NodeOwned,
     ) -> Result<Option<adnl::NodeIdShort>> {
         let peer_id_full = adnl::NodeIdFull::try_from(peer.id.as_equivalent_ref())?;
 
         // Verify signature
-        let signature = std::mem::take(&mut peer.signature);
-        if peer_id_full.verify(peer.as_boxed(), &signature).is_err() {
+        if verify_dht_node(&peer.as_equivalent_ref()).is_err() {
             tracing::warn!("invalid DHT peer signature");
             return Ok(None);
         }
-        peer.signature = signature;
 
         // Parse remaining peer data
         let peer_id = peer_id_full.compute_short_id();
-        let peer_addr = parse_address_list(&peer.addr_list, adnl.options().clock_tolerance_sec)?;
+        let peer_addr = parse_first_address(
+            &peer.addr_list,
+            adnl.clock().now(),
+            adnl.options().clock_tolerance_sec,
+        )?;
 
         // Add new ADNL peer
         let is_new_peer = adnl.add_peer(
@@ -718,30 +732,42 @@ impl QuerySubscriber for NodeState {
                     QueryConsumingResult::Consumed(answer) => {
                         Ok(QueryConsumingResult::Consumed(answer))
                     }
-                    QueryConsumingResult::Rejected(_) => Err(DhtNodeError::UnexpectedQuery.into()),
+                    QueryConsumingResult::Cacheable(answer, ttl) => {
+                        Ok(QueryConsumingResult::Cacheable(answer, ttl))
+                    }
+                    QueryConsumingResult::ConsumedLarge(answer) => {
+                        Ok(QueryConsumingResult::ConsumedLarge(answer))
+                    }
+                    QueryConsumingResult::ConsumedDeferred(token) => {
+                        Ok(QueryConsumingResult::ConsumedDeferred(token))
+                    }
+                    QueryConsumingResult::Rejected(_) | QueryConsumingResult::RejectedWith(..) => {
+                        Err(DhtNodeError::UnexpectedQuery.into())
+                    }
                 }
             }
-            _ => Ok(QueryConsumingResult::Rejected(query)),
+            _ => Ok(QueryConsumingResult::reject(query)),
         }
     }
+
+    fn interested_constructors(&self) -> &[u32] {
+        &[
+            proto::rpc::DhtPing::TL_ID,
+            proto::rpc::DhtFindNode::TL_ID,
+            proto::rpc::DhtFindValue::TL_ID,
+            proto::rpc::DhtGetSignedAddressList::TL_ID,
+            proto::rpc::DhtStore::TL_ID,
+            proto::rpc::DhtQuery::TL_ID,
+        ]
+    }
 }
 
-fn verify_signed_dht_value(value: &mut proto::dht::Value<'_>) -> Result<()> {
+fn verify_signed_dht_value(value: &proto::dht::Value<'_>) -> Result<()> {
     if value.key.key.id != &tl_proto::hash(value.key.id) {
         return Err(DhtNodeError::InvalidValueKey.into());
     }
 
-    let full_id = adnl::NodeIdFull::try_from(value.key.id)?;
-
-    let key_signature = std::mem::take(&mut value.key.signature);
-    full_id.verify(value.key.as_boxed(), key_signature)?;
-    value.key.signature = key_signature;
-
-    let value_signature = std::mem::take(&mut value.signature);
-    full_id.verify(value.as_boxed(), value_signature)?;
-    value.signature = value_signature;
-
-    Ok(())
+    verify_dht_value(value)
 }
 
 /// Instant DHT node metrics
@@ -766,3 +792,114 @@ enum DhtNodeError {
     #[error("Invalid value key")]
     InvalidValueKey,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::fixtures::add_virtual_node;
+
+    fn make_node(network: &adnl::VirtualNetwork) -> Arc<Node> {
+        let adnl = add_virtual_node(network, Default::default());
+        Node::new(adnl, 0, Default::default()).unwrap()
+    }
+
+    fn signed_node(dht: &Node) -> proto::dht::NodeOwned {
+        dht.state.sign_local_node(dht.adnl.build_address_list())
+    }
+
+    #[tokio::test]
+    async fn store_and_find_overlay_node() {
+        let network = adnl::VirtualNetwork::new(0);
+
+        // left <-> middle <-> right
+        let left = make_node(&network);
+        let middle = make_node(&network);
+        let right = make_node(&network);
+        for dht in [&left, &middle, &right] {
+            dht.adnl.start().unwrap();
+        }
+        left.add_dht_peer(signed_node(&middle)).unwrap().unwrap();
+        right.add_dht_peer(signed_node(&middle)).unwrap().unwrap();
+
+        // Right node announces itself as an overlay member
+        let overlay_id_full = overlay::IdFull::for_workchain_overlay(0, &[1; 32]);
+        let overlay_id = overlay_id_full.compute_short_id();
+        let overlay_node = sign_overlay_node(right.key(), &overlay_id, now());
+
+        assert!(right
+            .store_address(right.key(), right.adnl.socket_addr())
+            .await
+            .unwrap());
+        assert!(right
+            .store_overlay_node(&overlay_id_full, overlay_node.as_equivalent_ref())
+            .await
+            .unwrap());
+
+        // Value with an invalid signature is rejected before storing
+        let mut invalid_node = overlay_node.clone();
+        invalid_node.version += 1;
+        assert!(right
+            .store_overlay_node(&overlay_id_full, invalid_node.as_equivalent_ref())
+            .await
+            .is_err());
+
+        // Left node finds it through the middle one
+        let (addr, full_id) = left.find_address(right.key().id()).await.unwrap();
+        assert_eq!(addr, right.adnl.socket_addr());
+        assert_eq!(&full_id, right.key().full_id());
+
+        let nodes = left.find_overlay_nodes(&overlay_id).await.unwrap();
+        assert_eq!(nodes.len(), 1);
+        assert_eq!(nodes[0].0, right.adnl.socket_addr());
+        assert!(nodes[0].1.as_equivalent_ref() == overlay_node.as_equivalent_ref());
+    }
+
+    #[tokio::test]
+    async fn values_expire_by_node_clock() {
+        let clock = ManualClock::new(1_000_000);
+        let network = adnl::VirtualNetwork::with_clock(0, Arc::new(clock.clone()));
+        let dht = make_node(&network);
+
+        let value = dht
+            .entry(dht.key().id(), "test")
+            .with_data_raw(b"hello")
+            .with_ttl(10)
+            .sign(dht.key());
+        assert_eq!(value.ttl, 1_000_010);
+        assert!(dht.storage().insert(value.as_equivalent_ref()).unwrap());
+
+        let key = tl_proto::hash_as_boxed(value.key.key.as_equivalent_ref());
+        assert!(dht.storage().get_ref(&key).is_some());
+
+        clock.advance(Duration::from_secs(10));
+        assert!(dht.storage().get_ref(&key).is_none());
+        assert!(dht.storage().insert(value.as_equivalent_ref()).is_err());
+    }
+
+    #[tokio::test]
+    async fn timeouts_do_not_lower_reputation() {
+        let network = adnl::VirtualNetwork::new(0);
+
+        let adnl = add_virtual_node(
+            &network,
+            adnl::NodeOptions {
+                query_min_timeout_ms: 50,
+                query_default_timeout_ms: 50,
+                ..Default::default()
+            },
+        );
+        let dht = Node::new(adnl, 0, Default::default()).unwrap();
+        dht.adnl.start().unwrap();
+
+        // Peer is never started, so all queries time out
+        let unreachable = make_node(&network);
+        let peer_id = dht
+            .add_dht_peer(signed_node(&unreachable))
+            .unwrap()
+            .unwrap();
+
+        assert!(!dht.ping(&peer_id).await.unwrap());
+        assert!(!dht.ping(&peer_id).await.unwrap());
+        assert_eq!(dht.adnl.reputation().score(&peer_id), 0);
+    }
+}
@@ -1,12 +1,11 @@
-use std::convert::TryFrom;
 use std::ops::Deref;
+use std::sync::Arc;
 
 use anyhow::Result;
 use smallvec::SmallVec;
 use tl_proto::{BoxedConstructor, HashWrapper, TlWrite};
 
 use super::KEY_NODES;
-use crate::adnl;
 use crate::overlay;
 use crate::proto;
 use crate::util::*;
@@ -20,13 +19,16 @@ pub struct StorageOptions {
 pub struct Storage {
     storage: FastDashMap<StorageKeyId, proto::dht::ValueOwned>,
     options: StorageOptions,
+    /// Clock of the ADNL node, used to check value expiration
+    clock: Arc<dyn Clock>,
 }
 
 impl Storage {
-    pub fn new(options: StorageOptions) -> Self {
+    pub fn new(options: StorageOptions, clock: Arc<dyn Clock>) -> Self {
         Self {
             storage: Default::default(),
             options,
+            clock,
         }
     }
 
@@ -46,7 +48,7 @@ impl Storage {
         key: &StorageKeyId,
     ) -> Option<impl Deref<Target = proto::dht::ValueOwned> + '_> {
         match self.storage.get(key) {
-            Some(item) if item.ttl > now() => Some(item),
+            Some(item) if item.ttl > self.clock.now() => Some(item),
             _ => None,
         }
     }
@@ -55,7 +57,7 @@ impl Storage {
     ///
     /// NOTE: Values with `UpdateRule::Anybody` can't be inserted
     pub fn insert(&self, value: proto::dht::Value<'_>) -> Result<bool> {
-        if value.ttl <= now() {
+        if value.ttl <= self.clock.now() {
             return Err(StorageError::ValueExpired.into());
         }
 
@@ -78,23 +80,15 @@ impl Storage {
 
     /// Removes all outdated value
     pub fn gc(&self) {
-        let now = now();
+        let now = self.clock.now();
         self.storage.retain(|_, value| value.ttl > now);
     }
 
     /// Inserts signed value into the storage
-    fn insert_signed_value(&self, mut value: proto::dht::Value<'_>) -> Result<bool> {
+    fn insert_signed_value(&self, value: proto::dht::Value<'_>) -> Result<bool> {
         use dashmap::mapref::entry::Entry;
 
-        let full_id = adnl::NodeIdFull::try_from(value.key.id)?;
-
-        let key_signature = std::mem::take(&mut value.key.signature);
-        full_id.verify(value.key.as_boxed(), key_signature)?;
-        value.key.signature = key_signature;
-
-        let value_signature = std::mem::take(&mut value.signature);
-        full_id.verify(value.as_boxed(), value_signature)?;
-        value.signature = value_signature;
+        verify_dht_value(&value)?;
 
         let key = tl_proto::hash_as_boxed(value.key.key);
         Ok(match self.storage.entry(key) {
@@ -154,7 +148,7 @@ impl Storage {
             Entry::Occupied(mut entry) => {
                 let value = {
                     let old_nodes = match entry.get().ttl {
-                        old_ttl if old_ttl < now() => None,
+                        old_ttl if old_ttl < self.clock.now() => None,
                         old_ttl if old_ttl > value.ttl => return Ok(false),
                         _ => Some(deserialize_overlay_nodes(&entry.get().value)?),
                     };
@@ -222,7 +216,7 @@ fn make_overlay_nodes_value<const N: usize>(
 
 fn deserialize_overlay_nodes(
     data: &[u8],
-) -> tl_proto::TlResult<SmallVec<[proto::overlay::Node; 5]>> {
+) -> tl_proto::TlResult<SmallVec<[proto::overlay::Node<'_>; 5]>> {
     match tl_proto::deserialize_as_boxed(data) {
         Ok(proto::overlay::Nodes { nodes }) => Ok(nodes),
         Err(e) => Err(e),
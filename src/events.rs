@@ -0,0 +1,181 @@
+use std::net::SocketAddrV4;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use tokio::sync::broadcast;
+
+use crate::adnl;
+use crate::overlay;
+use crate::subscriber::PeerLostReason;
+
+/// Network event, see [`adnl::Node::events`].
+///
+/// Ids have the same format as the fields of the corresponding tracing spans.
+/// All timestamps are unix timestamps in milliseconds from the node clock.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub enum NetworkEvent {
+    /// New remote ADNL peer was added
+    PeerAdded {
+        local_id: adnl::NodeIdShort,
+        peer_id: adnl::NodeIdShort,
+        addr: SocketAddrV4,
+        timestamp_ms: u64,
+    },
+    /// New remote ADNL peer was rejected by the [`adnl::PeerFilter`]
+    /// or due to its reserved address (see [`adnl::NodeOptions::reject_reserved_public_addresses`])
+    PeerRejected {
+        local_id: adnl::NodeIdShort,
+        peer_id: adnl::NodeIdShort,
+        addr: SocketAddrV4,
+        timestamp_ms: u64,
+    },
+    /// Remote ADNL peer was removed or its state was reset
+    PeerLost {
+        local_id: adnl::NodeIdShort,
+        peer_id: adnl::NodeIdShort,
+        reason: PeerLostReason,
+        timestamp_ms: u64,
+    },
+    /// Channel with the remote ADNL peer became ready
+    ChannelEstablished {
+        local_id: adnl::NodeIdShort,
+        peer_id: adnl::NodeIdShort,
+        timestamp_ms: u64,
+    },
+    /// New overlay was added
+    OverlayAdded {
+        overlay_id: overlay::IdShort,
+        timestamp_ms: u64,
+    },
+    /// Overlay was removed
+    OverlayRemoved {
+        overlay_id: overlay::IdShort,
+        timestamp_ms: u64,
+    },
+    /// Number of active public overlay peers dropped below `low_peers_threshold`
+    OverlayPeersLow {
+        overlay_id: overlay::IdShort,
+        peers: usize,
+        timestamp_ms: u64,
+    },
+    /// Number of new incoming broadcasts during the last second reached
+    /// `broadcast_storm_threshold` in total (without `source`), or exceeded it
+    /// for the single `source`. Broadcasts of this source are not forwarded
+    /// to the neighbours for `storm_cooldown_ms`, but are still delivered locally
+    BroadcastStorm {
+        overlay_id: overlay::IdShort,
+        source: Option<adnl::NodeIdShort>,
+        broadcasts: u32,
+        timestamp_ms: u64,
+    },
+    /// Local key of the overlay was replaced, see [`overlay::Overlay::rotate_key`]
+    OverlayKeyRotated {
+        overlay_id: overlay::IdShort,
+        old_id: adnl::NodeIdShort,
+        new_id: adnl::NodeIdShort,
+        grace_period_ms: u64,
+        timestamp_ms: u64,
+    },
+    /// RLDP transfer was fully sent or received
+    RldpTransferCompleted {
+        local_id: adnl::NodeIdShort,
+        peer_id: adnl::NodeIdShort,
+        transfer_id: [u8; 32],
+        direction: RldpTransferDirection,
+        size: u64,
+        elapsed_ms: u64,
+        timestamp_ms: u64,
+    },
+    /// RLDP transfer was not completed
+    RldpTransferFailed {
+        local_id: adnl::NodeIdShort,
+        peer_id: adnl::NodeIdShort,
+        transfer_id: [u8; 32],
+        direction: RldpTransferDirection,
+        reason: RldpTransferFailure,
+        elapsed_ms: u64,
+        timestamp_ms: u64,
+    },
+}
+
+impl NetworkEvent {
+    /// Unix timestamp in milliseconds when the event was emitted
+    pub fn timestamp_ms(&self) -> u64 {
+        match self {
+            Self::PeerAdded { timestamp_ms, .. }
+            | Self::PeerRejected { timestamp_ms, .. }
+            | Self::PeerLost { timestamp_ms, .. }
+            | Self::ChannelEstablished { timestamp_ms, .. }
+            | Self::OverlayAdded { timestamp_ms, .. }
+            | Self::OverlayRemoved { timestamp_ms, .. }
+            | Self::OverlayPeersLow { timestamp_ms, .. }
+            | Self::BroadcastStorm { timestamp_ms, .. }
+            | Self::OverlayKeyRotated { timestamp_ms, .. }
+            | Self::RldpTransferCompleted { timestamp_ms, .. }
+            | Self::RldpTransferFailed { timestamp_ms, .. } => *timestamp_ms,
+        }
+    }
+}
+
+/// See [`NetworkEvent::RldpTransferCompleted`]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum RldpTransferDirection {
+    Incoming,
+    Outgoing,
+}
+
+/// See [`NetworkEvent::RldpTransferFailed`]
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum RldpTransferFailure {
+    /// Remote peer didn't confirm the transfer in time
+    TimedOut,
+    /// Transfer was closed before all data was received
+    Incomplete,
+    /// Transfer failed with an error
+    Error(String),
+}
+
+/// Lossy events channel which never blocks the sender
+pub(crate) struct EventsSender {
+    tx: broadcast::Sender<NetworkEvent>,
+    capacity: usize,
+    dropped: AtomicU64,
+}
+
+impl EventsSender {
+    pub fn new(capacity: usize) -> Self {
+        // NOTE: broadcast channel capacity is always a power of two
+        let capacity = capacity.max(1).next_power_of_two();
+        let (tx, _) = broadcast::channel(capacity);
+        Self {
+            tx,
+            capacity,
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<NetworkEvent> {
+        self.tx.subscribe()
+    }
+
+    /// Sends the event if there are any receivers
+    pub fn emit<F>(&self, f: F)
+    where
+        F: FnOnce() -> NetworkEvent,
+    {
+        if self.tx.receiver_count() == 0 {
+            return;
+        }
+
+        // The oldest event will be overwritten for the lagging receivers
+        if self.tx.len() >= self.capacity {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        self.tx.send(f()).ok();
+    }
+
+    /// Total number of events which were overwritten before all receivers got them
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,222 @@
+//! Entry points for the fuzz targets in `fuzz/`.
+//!
+//! NOTE: This module is not a part of the public API.
+
+use std::sync::Arc;
+
+use once_cell::sync::Lazy;
+use tl_proto::TlRead;
+
+use crate::adnl;
+use crate::overlay;
+use crate::proto;
+use crate::rldp;
+use crate::subscriber::QuerySubscribers;
+
+/// Processes an incoming ADNL datagram.
+///
+/// The first byte selects how the rest of the input is delivered:
+/// - `0` - as is
+/// - `1` - as packet contents inside a handshake packet
+/// - `2` - as packet contents encrypted with an established channel
+pub fn adnl_datagram(data: &[u8]) {
+    let (mode, payload) = match data.split_first() {
+        Some((mode, payload)) => (*mode, payload),
+        None => return,
+    };
+
+    let harness = &*HARNESS;
+    let mut packet = match mode {
+        0 => payload.to_vec(),
+        1 => adnl::build_handshake_packet(harness.target_key.full_id(), payload, None),
+        2 => {
+            let mut packet = payload.to_vec();
+            let (local_id, peer_id) = (harness.peer_key.id(), harness.target_key.id());
+            if !harness
+                .peer
+                .encrypt_for_channel(local_id, peer_id, &mut packet)
+            {
+                return;
+            }
+            packet
+        }
+        _ => return,
+    };
+
+    let _ = harness.runtime.block_on(harness.target.receive_datagram(
+        &mut packet,
+        harness.peer.socket_addr(),
+        &harness.query_subscribers,
+    ));
+}
+
+/// Processes a serialized `overlay.Broadcast` from the known peer.
+///
+/// FEC broadcast parts are also decoded in place, because the overlay
+/// does it in a background task.
+pub fn overlay_broadcast(data: &[u8]) {
+    let harness = &*HARNESS;
+    let adnl = &harness.target;
+    let local_id = harness.target_key.id();
+    let peer_id = harness.peer_key.id();
+
+    match proto::overlay::Broadcast::read_from(data, &mut 0) {
+        Ok(proto::overlay::Broadcast::Broadcast(broadcast)) => {
+            let _ = harness.runtime.block_on(
+                harness
+                    .overlay
+                    .receive_broadcast(adnl, local_id, peer_id, broadcast, data),
+            );
+        }
+        Ok(proto::overlay::Broadcast::BroadcastFec(broadcast)) => {
+            if let Ok(mut decoder) = rldp::RaptorQDecoder::with_params(broadcast.fec) {
+                let _ = decoder.decode(broadcast.seqno, broadcast.data.to_vec());
+            }
+
+            let _ = harness.runtime.block_on(
+                harness
+                    .overlay
+                    .receive_fec_broadcast(adnl, local_id, peer_id, broadcast, data),
+            );
+        }
+        _ => {}
+    }
+}
+
+/// Feeds a sequence of serialized `rldp.MessagePart` into a new incoming transfer
+pub fn rldp_message_parts(data: &[u8]) {
+    const MAX_TRANSFER_SIZE: u32 = 1 << 20;
+
+    let mut transfer = rldp::IncomingTransfer::new([0; 32], MAX_TRANSFER_SIZE);
+
+    let mut offset = 0;
+    while let Ok(message) = proto::rldp::MessagePart::read_from(data, &mut offset) {
+        if let proto::rldp::MessagePart::MessagePart {
+            fec_type,
+            part,
+            total_size,
+            seqno,
+            data,
+            ..
+        } = message
+        {
+            let message = rldp::MessagePart {
+                fec_type,
+                part,
+                total_size,
+                seqno,
+                data: data.to_vec(),
+            };
+            if transfer.process_chunk(message).is_err() {
+                break;
+            }
+        }
+    }
+}
+
+/// Scaffolding which is built once per process
+struct Harness {
+    runtime: tokio::runtime::Runtime,
+    target: Arc<adnl::Node>,
+    target_key: Arc<adnl::Key>,
+    peer: Arc<adnl::Node>,
+    peer_key: Arc<adnl::Key>,
+    overlay: Arc<overlay::Overlay>,
+    query_subscribers: QuerySubscribers,
+    _network: adnl::VirtualNetwork,
+}
+
+static HARNESS: Lazy<Harness> = Lazy::new(|| {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .build()
+        .unwrap();
+
+    // NOTE: Background tasks of the nodes are polled during each `block_on`
+    let (network, target, peer, overlay) = runtime.block_on(async {
+        let network = adnl::VirtualNetwork::new(0);
+        let make_node = |key: [u8; 32]| {
+            let keystore = adnl::Keystore::builder()
+                .with_tagged_key(key, 0)
+                .unwrap()
+                .build();
+            network.add_node(keystore, Default::default(), None)
+        };
+
+        let target = make_node([1; 32]);
+        let peer = make_node([2; 32]);
+
+        let overlay_node = overlay::Node::new(target.clone(), 0).unwrap();
+        let overlay_id = overlay::IdFull::for_workchain_overlay(0, &[0; 32]).compute_short_id();
+        let (overlay, _) = overlay_node
+            .add_public_overlay(&overlay_id, Default::default())
+            .unwrap();
+
+        target.add_echo_subscriber().unwrap();
+        target.start().unwrap();
+        peer.start().unwrap();
+
+        (network, target, peer, overlay)
+    });
+
+    let target_key = target.key_by_tag(0).unwrap().clone();
+    let peer_key = peer.key_by_tag(0).unwrap().clone();
+    target
+        .add_peer(
+            adnl::NewPeerContext::AdnlPacket,
+            target_key.id(),
+            peer_key.id(),
+            peer.socket_addr(),
+            *peer_key.full_id(),
+        )
+        .unwrap();
+    peer.add_peer(
+        adnl::NewPeerContext::AdnlPacket,
+        peer_key.id(),
+        target_key.id(),
+        target.socket_addr(),
+        *target_key.full_id(),
+    )
+    .unwrap();
+
+    // Establish channel
+    runtime
+        .block_on(peer.ping_peer(peer_key.id(), target_key.id(), 0, Some(1000)))
+        .unwrap()
+        .unwrap();
+
+    Harness {
+        runtime,
+        target,
+        target_key,
+        peer,
+        peer_key,
+        overlay,
+        query_subscribers: QuerySubscribers::new(vec![Arc::new(adnl::EchoSubscriber)]),
+        _network: network,
+    }
+});
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+
+    fn replay_corpus(target: &str, f: fn(&[u8])) {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("fuzz/corpus")
+            .join(target);
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let data = std::fs::read(entry.unwrap().path()).unwrap();
+            f(&data);
+        }
+    }
+
+    #[test]
+    fn regression_corpus() {
+        replay_corpus("adnl_datagram", adnl_datagram);
+        replay_corpus("overlay_broadcast", overlay_broadcast);
+        replay_corpus("rldp_message_parts", rldp_message_parts);
+    }
+}
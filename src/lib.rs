@@ -15,12 +15,21 @@ macro_rules! ok {
 pub use everscale_crypto as crypto;
 pub use tl_proto as tl;
 
-pub use subscriber::{MessageSubscriber, QueryConsumingResult, QuerySubscriber, SubscriberContext};
+pub use events::{NetworkEvent, RldpTransferDirection, RldpTransferFailure};
+pub use subscriber::{
+    AnswerError, AnswerToken, MessageContext, MessageSubscriber, PeerLostReason,
+    QueryConsumingResult, QueryContext, QuerySubscriber, QueryTransport, RejectReason,
+    SubscriberContext,
+};
 pub use util::NetworkBuilder;
 
 pub mod adnl;
 #[cfg(feature = "dht")]
 pub mod dht;
+mod events;
+#[cfg(feature = "fuzzing")]
+#[doc(hidden)]
+pub mod fuzzing;
 pub mod overlay;
 pub mod proto;
 #[cfg(feature = "rldp")]
@@ -0,0 +1,436 @@
+use std::net::{SocketAddrV4, ToSocketAddrs};
+use std::sync::Arc;
+
+use anyhow::Result;
+
+use super::node::Node;
+use super::overlay::{Overlay, OverlayOptions};
+use super::overlay_id::IdFull;
+use crate::proto;
+use crate::util::NetworkBuilder;
+use crate::{adnl, rldp};
+
+/// Builder of the minimal full node network stack.
+///
+/// Creates ADNL, RLDP and overlay nodes, registers all subscribers in the required
+/// order, starts ADNL and joins the workchain overlay of the specified zero state.
+///
+/// Misconfiguration is reported as [`BootstrapError`] before any socket is bound.
+///
+/// # Examples
+///
+/// ```
+/// use std::net::SocketAddrV4;
+///
+/// use anyhow::Result;
+/// use everscale_network::{adnl, overlay, proto};
+///
+/// /// Static peers can be found e.g. with `dht::Node::find_overlay_nodes`
+/// async fn run(
+///     zero_state_file_hash: [u8; 32],
+///     static_peers: Vec<(SocketAddrV4, proto::overlay::NodeOwned)>,
+/// ) -> Result<()> {
+///     const KEY_TAG: usize = 0;
+///
+///     let keystore = adnl::Keystore::builder()
+///         .with_tagged_key([0; 32], KEY_TAG)?
+///         .build();
+///
+///     let network = overlay::FullNodeBuilder::new(
+///         "0.0.0.0:30303",
+///         keystore,
+///         KEY_TAG,
+///         zero_state_file_hash,
+///     )
+///     .with_static_peers(static_peers)
+///     .build()?;
+///
+///     let broadcast = network.workchain_overlay.wait_for_broadcast().await;
+///     println!("received {} bytes", broadcast.data.len());
+///     Ok(())
+/// }
+/// ```
+pub struct FullNodeBuilder<T> {
+    addr: T,
+    keystore: adnl::Keystore,
+    key_tag: usize,
+    zero_state_file_hash: [u8; 32],
+    workchain: i32,
+    adnl_options: adnl::NodeOptions,
+    rldp_options: rldp::NodeOptions,
+    overlay_options: OverlayOptions,
+    static_peers: Vec<(SocketAddrV4, proto::overlay::NodeOwned)>,
+}
+
+impl<T> FullNodeBuilder<T>
+where
+    T: ToSocketAddrs,
+{
+    /// Creates a builder for the node with the specified local address and keys.
+    ///
+    /// `key_tag` is the tag of the keystore key used for the overlay.
+    pub fn new(
+        addr: T,
+        keystore: adnl::Keystore,
+        key_tag: usize,
+        zero_state_file_hash: [u8; 32],
+    ) -> Self {
+        Self {
+            addr,
+            keystore,
+            key_tag,
+            zero_state_file_hash,
+            workchain: -1,
+            adnl_options: Default::default(),
+            rldp_options: Default::default(),
+            overlay_options: Default::default(),
+            static_peers: Vec::new(),
+        }
+    }
+
+    /// Workchain of the joined overlay.
+    ///
+    /// Default: `-1` (masterchain)
+    pub fn with_workchain(mut self, workchain: i32) -> Self {
+        self.workchain = workchain;
+        self
+    }
+
+    pub fn with_adnl_options(mut self, options: adnl::NodeOptions) -> Self {
+        self.adnl_options = options;
+        self
+    }
+
+    pub fn with_rldp_options(mut self, options: rldp::NodeOptions) -> Self {
+        self.rldp_options = options;
+        self
+    }
+
+    pub fn with_overlay_options(mut self, options: OverlayOptions) -> Self {
+        self.overlay_options = options;
+        self
+    }
+
+    /// Adds a signed overlay node which will be used as an initial overlay peer
+    pub fn with_static_peer(mut self, addr: SocketAddrV4, node: proto::overlay::NodeOwned) -> Self {
+        self.static_peers.push((addr, node));
+        self
+    }
+
+    /// Adds signed overlay nodes which will be used as initial overlay peers
+    pub fn with_static_peers<I>(mut self, peers: I) -> Self
+    where
+        I: IntoIterator<Item = (SocketAddrV4, proto::overlay::NodeOwned)>,
+    {
+        self.static_peers.extend(peers);
+        self
+    }
+
+    /// Creates and starts all nodes
+    pub fn build(self) -> Result<FullNodeNetwork> {
+        if self.keystore.key_by_tag(self.key_tag).is_err() {
+            return Err(BootstrapError::KeyTagNotFound(self.key_tag).into());
+        }
+        if self.static_peers.is_empty() {
+            return Err(BootstrapError::NoStaticPeers.into());
+        }
+        self.overlay_options.validate()?;
+
+        let (adnl, rldp, overlay) =
+            NetworkBuilder::with_adnl(self.addr, self.keystore, self.adnl_options)
+                .with_rldp(self.rldp_options)
+                .with_overlay(self.key_tag)
+                .build()?;
+
+        let overlay_id = IdFull::for_workchain_overlay(self.workchain, &self.zero_state_file_hash)
+            .compute_short_id();
+        let (workchain_overlay, _) =
+            overlay.add_public_overlay(&overlay_id, self.overlay_options)?;
+
+        let added = workchain_overlay.add_public_peers(
+            &adnl,
+            self.static_peers
+                .iter()
+                .map(|(addr, node)| (*addr, node.as_equivalent_ref())),
+        )?;
+        if added.is_empty() {
+            overlay.shutdown();
+            rldp.shutdown();
+            adnl.abort();
+            return Err(BootstrapError::NoValidPeers.into());
+        }
+
+        Ok(FullNodeNetwork {
+            adnl,
+            rldp,
+            overlay,
+            workchain_overlay,
+        })
+    }
+}
+
+/// Started network stack of the full node
+pub struct FullNodeNetwork {
+    pub adnl: Arc<adnl::Node>,
+    pub rldp: Arc<rldp::Node>,
+    pub overlay: Arc<Node>,
+    /// Joined workchain overlay
+    pub workchain_overlay: Arc<Overlay>,
+}
+
+impl FullNodeNetwork {
+    /// Shuts down overlays, RLDP and ADNL in the supported order
+    pub async fn shutdown(&self) {
+        self.overlay.shutdown();
+        self.rldp.shutdown();
+        self.adnl.shutdown().await;
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum BootstrapError {
+    #[error("Keystore has no key with tag {0}")]
+    KeyTagNotFound(usize),
+    #[error("No static peers specified")]
+    NoStaticPeers,
+    #[error("None of the static peers belong to the overlay")]
+    NoValidPeers,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+    use std::net::Ipv4Addr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use super::*;
+    use crate::overlay::BroadcastTarget;
+    use crate::subscriber::*;
+    use crate::util::fixtures::make_keystore;
+
+    const KEY_TAG: usize = 0;
+    const ZERO_STATE_FILE_HASH: [u8; 32] = [1; 32];
+
+    fn local_addr() -> SocketAddrV4 {
+        SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0)
+    }
+
+    #[tokio::test]
+    async fn misconfiguration() {
+        let err = FullNodeBuilder::new(local_addr(), make_keystore(), 1, ZERO_STATE_FILE_HASH)
+            .build()
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err.downcast_ref::<BootstrapError>(),
+            Some(BootstrapError::KeyTagNotFound(1))
+        ));
+
+        let err =
+            FullNodeBuilder::new(local_addr(), make_keystore(), KEY_TAG, ZERO_STATE_FILE_HASH)
+                .build()
+                .err()
+                .unwrap();
+        assert!(matches!(
+            err.downcast_ref::<BootstrapError>(),
+            Some(BootstrapError::NoStaticPeers)
+        ));
+
+        // Peer of another overlay
+        let (_, _, other) =
+            NetworkBuilder::with_adnl(local_addr(), make_keystore(), Default::default())
+                .with_rldp(Default::default())
+                .with_overlay(KEY_TAG)
+                .build()
+                .unwrap();
+        let other_id = IdFull::for_workchain_overlay(0, &ZERO_STATE_FILE_HASH).compute_short_id();
+        let (other_overlay, _) = other
+            .add_public_overlay(&other_id, Default::default())
+            .unwrap();
+
+        let err =
+            FullNodeBuilder::new(local_addr(), make_keystore(), KEY_TAG, ZERO_STATE_FILE_HASH)
+                .with_static_peer(other.adnl().socket_addr(), other_overlay.sign_local_node())
+                .build()
+                .err()
+                .unwrap();
+        assert!(matches!(
+            err.downcast_ref::<BootstrapError>(),
+            Some(BootstrapError::NoValidPeers)
+        ));
+    }
+
+    #[tokio::test]
+    async fn receives_broadcasts_from_static_peer() {
+        let (peer_adnl, _, peer) =
+            NetworkBuilder::with_adnl(local_addr(), make_keystore(), Default::default())
+                .with_rldp(Default::default())
+                .with_overlay(KEY_TAG)
+                .build()
+                .unwrap();
+        let overlay_id =
+            IdFull::for_workchain_overlay(-1, &ZERO_STATE_FILE_HASH).compute_short_id();
+        let (peer_overlay, _) = peer
+            .add_public_overlay(&overlay_id, Default::default())
+            .unwrap();
+
+        let network =
+            FullNodeBuilder::new(local_addr(), make_keystore(), KEY_TAG, ZERO_STATE_FILE_HASH)
+                .with_static_peer(peer_adnl.socket_addr(), peer_overlay.sign_local_node())
+                .build()
+                .unwrap();
+        assert_eq!(network.workchain_overlay.id(), &overlay_id);
+
+        peer_overlay
+            .add_public_peer(
+                &peer_adnl,
+                network.adnl.socket_addr(),
+                network
+                    .workchain_overlay
+                    .sign_local_node()
+                    .as_equivalent_ref(),
+            )
+            .unwrap()
+            .unwrap();
+
+        peer_overlay.broadcast(
+            &peer_adnl,
+            vec![0xaa; 100],
+            None,
+            BroadcastTarget::RandomNeighbours,
+        );
+
+        let broadcast = tokio::time::timeout(
+            Duration::from_secs(5),
+            network.workchain_overlay.wait_for_broadcast(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(broadcast.data, vec![0xaa; 100]);
+    }
+
+    struct Pong;
+
+    #[async_trait::async_trait]
+    impl QuerySubscriber for Pong {
+        async fn try_consume_query<'a>(
+            &self,
+            _: SubscriberContext<'a>,
+            _: u32,
+            query: Cow<'a, [u8]>,
+        ) -> Result<QueryConsumingResult<'a>> {
+            let proto::rpc::AdnlPing { value } = tl_proto::deserialize(&query)?;
+            Ok(QueryConsumingResult::answer(proto::adnl::Pong { value }))
+        }
+    }
+
+    /// Counts warnings and errors of all spans
+    struct WarningCounter(Arc<AtomicUsize>);
+
+    impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for WarningCounter {
+        fn on_event(
+            &self,
+            event: &tracing::Event<'_>,
+            _: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            if *event.metadata().level() <= tracing::Level::WARN {
+                self.0.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn ordered_shutdown() {
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let warnings = Arc::new(AtomicUsize::new(0));
+        let _guard = tracing::subscriber::set_default(
+            tracing_subscriber::registry().with(WarningCounter(warnings.clone())),
+        );
+
+        let (peer_adnl, peer_rldp, peer) =
+            NetworkBuilder::with_adnl(local_addr(), make_keystore(), Default::default())
+                .with_rldp(Default::default())
+                .with_overlay(KEY_TAG)
+                .build()
+                .unwrap();
+        let overlay_id =
+            IdFull::for_workchain_overlay(-1, &ZERO_STATE_FILE_HASH).compute_short_id();
+        let (peer_overlay, _) = peer
+            .add_public_overlay(&overlay_id, Default::default())
+            .unwrap();
+        peer.add_overlay_subscriber(overlay_id, Arc::new(Pong));
+
+        let network =
+            FullNodeBuilder::new(local_addr(), make_keystore(), KEY_TAG, ZERO_STATE_FILE_HASH)
+                .with_static_peer(peer_adnl.socket_addr(), peer_overlay.sign_local_node())
+                .build()
+                .unwrap();
+        peer_overlay
+            .add_public_peer(
+                &peer_adnl,
+                network.adnl.socket_addr(),
+                network
+                    .workchain_overlay
+                    .sign_local_node()
+                    .as_equivalent_ref(),
+            )
+            .unwrap()
+            .unwrap();
+
+        // Exercise all layers
+        peer_overlay.broadcast(
+            &peer_adnl,
+            vec![0xaa; 100],
+            None,
+            BroadcastTarget::RandomNeighbours,
+        );
+        tokio::time::timeout(
+            Duration::from_secs(5),
+            network.workchain_overlay.wait_for_broadcast(),
+        )
+        .await
+        .unwrap();
+
+        let peer_id = *peer_overlay.overlay_key().id();
+        let ping = proto::rpc::AdnlPing { value: 1 };
+        let pong = network
+            .workchain_overlay
+            .adnl_query(&network.adnl, &peer_id, ping, None)
+            .await
+            .unwrap();
+        assert!(pong.is_some());
+        let (pong, _) = network
+            .workchain_overlay
+            .rldp_query(&network.rldp, &peer_id, ping, None)
+            .await
+            .unwrap();
+        assert!(pong.is_some());
+
+        peer.shutdown();
+        peer_rldp.shutdown();
+        peer_adnl.shutdown().await;
+        network.shutdown().await;
+
+        // Queries fail instead of hanging after shutdown
+        assert!(network
+            .workchain_overlay
+            .rldp_query(&network.rldp, &peer_id, ping, None)
+            .await
+            .is_err());
+
+        drop((peer_overlay, peer, peer_rldp, peer_adnl, network));
+
+        let metrics = tokio::runtime::Handle::current().metrics();
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while metrics.num_alive_tasks() > 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .ok();
+        assert_eq!(metrics.num_alive_tasks(), 0);
+        assert_eq!(warnings.load(Ordering::Relaxed), 0);
+    }
+}
@@ -0,0 +1,139 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use tl_proto::{Bare, BoxedConstructor, BoxedWrapper, TlError, TlRead, TlResult};
+
+use crate::adnl;
+use crate::util::FastDashMap;
+
+/// Typed handler of the incoming broadcasts, see [`Overlay::register_broadcast_handler`]
+///
+/// [`Overlay::register_broadcast_handler`]: crate::overlay::Overlay::register_broadcast_handler
+pub type BroadcastHandler<T> = Arc<dyn Fn(adnl::NodeIdShort, T) + Send + Sync>;
+
+/// Typed broadcast handlers by TL constructor id
+#[derive(Default)]
+pub struct BroadcastHandlers {
+    handlers: FastDashMap<u32, Arc<ErasedHandler>>,
+}
+
+impl BroadcastHandlers {
+    /// Adds handler for the `T` constructor. Returns `false` if
+    /// there is already a handler for this constructor
+    pub fn register<T>(&self, handler: BroadcastHandler<T>) -> bool
+    where
+        T: BoxedConstructor + for<'a> TlRead<'a, Repr = Bare> + 'static,
+    {
+        use dashmap::mapref::entry::Entry;
+
+        match self.handlers.entry(T::TL_ID) {
+            Entry::Vacant(entry) => {
+                entry.insert(Arc::new(ErasedHandler {
+                    handle: Box::new(move |from, data| {
+                        let mut offset = 0;
+                        let BoxedWrapper(broadcast) =
+                            BoxedWrapper::<T>::read_from(data, &mut offset)?;
+                        if offset != data.len() {
+                            return Err(TlError::InvalidData);
+                        }
+                        handler(from, broadcast);
+                        Ok(())
+                    }),
+                    decode_errors: AtomicU64::new(0),
+                }));
+                true
+            }
+            Entry::Occupied(_) => false,
+        }
+    }
+
+    /// Decodes the broadcast and passes it to the handler of its constructor.
+    /// Returns `false` if there is no such handler.
+    pub fn handle(&self, from: adnl::NodeIdShort, data: &[u8]) -> bool {
+        let handler = match u32::read_from(data, &mut 0) {
+            Ok(constructor) => match self.handlers.get(&constructor) {
+                Some(handler) => handler.clone(),
+                None => return false,
+            },
+            Err(_) => return false,
+        };
+
+        // NOTE: the map is not locked here, so the handler can register other handlers
+        if (handler.handle)(from, data).is_err() {
+            handler.decode_errors.fetch_add(1, Ordering::Relaxed);
+        }
+        true
+    }
+
+    /// Number of broadcasts which were not decoded for each registered constructor
+    pub fn decode_errors(&self) -> Vec<(u32, u64)> {
+        self.handlers
+            .iter()
+            .map(|item| (*item.key(), item.decode_errors.load(Ordering::Relaxed)))
+            .collect()
+    }
+
+    /// Total number of broadcasts which were not decoded
+    pub fn total_decode_errors(&self) -> u64 {
+        self.handlers
+            .iter()
+            .map(|item| item.decode_errors.load(Ordering::Relaxed))
+            .sum()
+    }
+}
+
+struct ErasedHandler {
+    handle: Box<DecodeAndHandle>,
+    decode_errors: AtomicU64,
+}
+
+type DecodeAndHandle = dyn Fn(adnl::NodeIdShort, &[u8]) -> TlResult<()> + Send + Sync;
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+
+    use super::*;
+    use crate::proto;
+
+    #[test]
+    fn dispatch_by_constructor() {
+        let handlers = BroadcastHandlers::default();
+        let from = adnl::NodeIdShort::random();
+
+        let received = Arc::new(AtomicUsize::new(0));
+        let handler: BroadcastHandler<proto::overlay::NodesOwned> = Arc::new({
+            let received = received.clone();
+            move |peer_id, nodes| {
+                assert_eq!(peer_id, from);
+                assert!(nodes.nodes.is_empty());
+                received.fetch_add(1, Ordering::Relaxed);
+            }
+        });
+        assert!(handlers.register(handler.clone()));
+        assert!(!handlers.register(handler));
+
+        let nodes = tl_proto::serialize(
+            proto::overlay::Nodes {
+                nodes: Default::default(),
+            }
+            .into_boxed(),
+        );
+        assert!(handlers.handle(from, &nodes));
+        assert_eq!(received.load(Ordering::Relaxed), 1);
+
+        // Decode failures are counted
+        assert!(handlers.handle(from, &nodes[..6]));
+        assert!(handlers.handle(from, &[nodes.as_slice(), &[0; 4]].concat()));
+        assert_eq!(received.load(Ordering::Relaxed), 1);
+        assert_eq!(
+            handlers.decode_errors(),
+            [(proto::overlay::Nodes::TL_ID, 2)]
+        );
+        assert_eq!(handlers.total_decode_errors(), 2);
+
+        // Unknown constructors are left for the raw stream
+        assert!(!handlers.handle(from, &[1, 2, 3, 4]));
+        assert!(!handlers.handle(from, &[1]));
+    }
+}
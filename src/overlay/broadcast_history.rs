@@ -0,0 +1,104 @@
+use std::collections::VecDeque;
+
+use super::overlay::BroadcastId;
+
+/// Recent verified ordinary broadcasts in their serialized form,
+/// bounded by the number of entries and their total size
+pub struct BroadcastHistory {
+    entries: VecDeque<(BroadcastId, Vec<u8>)>,
+    total_size: usize,
+    max_len: usize,
+    max_size: usize,
+}
+
+impl BroadcastHistory {
+    pub fn new(max_len: usize, max_size: usize) -> Self {
+        Self {
+            entries: VecDeque::new(),
+            total_size: 0,
+            max_len,
+            max_size,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.max_len > 0
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Total size of the stored broadcasts in bytes
+    pub fn total_size(&self) -> usize {
+        self.total_size
+    }
+
+    /// Stores the broadcast and removes the oldest ones which don't fit anymore.
+    /// Broadcasts larger than the whole history are ignored
+    pub fn push(&mut self, broadcast_id: BroadcastId, data: &[u8]) {
+        if !self.is_enabled() || data.len() > self.max_size {
+            return;
+        }
+
+        while self.entries.len() >= self.max_len || self.total_size + data.len() > self.max_size {
+            match self.entries.pop_front() {
+                Some((_, data)) => self.total_size -= data.len(),
+                None => break,
+            }
+        }
+
+        self.total_size += data.len();
+        self.entries.push_back((broadcast_id, data.to_vec()));
+    }
+
+    pub fn get(&self, broadcast_id: &BroadcastId) -> Option<&[u8]> {
+        self.entries
+            .iter()
+            .find(|(id, _)| id == broadcast_id)
+            .map(|(_, data)| data.as_slice())
+    }
+
+    /// Ids of the stored broadcasts, newest first
+    pub fn ids(&self) -> impl Iterator<Item = &BroadcastId> {
+        self.entries.iter().rev().map(|(id, _)| id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn history_is_bounded() {
+        let mut history = BroadcastHistory::new(3, 100);
+        for i in 0..5 {
+            history.push([i; 32], &[i; 10]);
+        }
+        assert_eq!(history.len(), 3);
+        assert_eq!(history.total_size(), 30);
+        assert_eq!(
+            history.ids().copied().collect::<Vec<_>>(),
+            [[4; 32], [3; 32], [2; 32]]
+        );
+        assert_eq!(history.get(&[3; 32]), Some([3; 10].as_slice()));
+        assert_eq!(history.get(&[1; 32]), None);
+
+        // Large broadcast evicts several old ones
+        history.push([5; 32], &[5; 85]);
+        assert_eq!(
+            history.ids().copied().collect::<Vec<_>>(),
+            [[5; 32], [4; 32]]
+        );
+        assert_eq!(history.total_size(), 95);
+
+        // Too large broadcast is ignored
+        history.push([6; 32], &[6; 101]);
+        assert_eq!(history.len(), 2);
+
+        // Disabled history stores nothing
+        let mut history = BroadcastHistory::new(0, 100);
+        history.push([0; 32], &[0; 10]);
+        assert_eq!(history.len(), 0);
+    }
+}
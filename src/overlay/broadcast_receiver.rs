@@ -1,63 +1,182 @@
-use std::sync::atomic::{AtomicU32, Ordering};
-use std::sync::Arc;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 
-use crossbeam_queue::SegQueue;
-use tokio::sync::Barrier;
+use parking_lot::Mutex;
+use tokio::sync::Notify;
 
+/// Queue of the received items, bounded by default.
+///
+/// When the bounded queue is full, the oldest item is dropped and the next
+/// [`BroadcastReceiver::recv`] call returns [`BroadcastLagged`] with
+/// the number of items dropped since the previous report.
 pub struct BroadcastReceiver<T> {
-    data: SegQueue<T>,
-    barriers: SegQueue<Arc<Barrier>>,
-    sync_lock: AtomicU32,
+    state: Mutex<ReceiverState<T>>,
+    capacity: Option<usize>,
+    data_available: Notify,
+    waiters: AtomicUsize,
+    dropped: AtomicU64,
 }
 
-impl<T: Send + 'static> BroadcastReceiver<T> {
+struct ReceiverState<T> {
+    /// Items with their enqueue timestamps
+    items: VecDeque<(T, u64)>,
+    /// Items dropped since the last [`BroadcastLagged`] report
+    lagged: u64,
+}
+
+impl<T> BroadcastReceiver<T> {
+    /// Creates a queue which keeps at most `capacity` items or grows forever if it is `None`
+    pub fn new(capacity: Option<usize>) -> Self {
+        Self {
+            state: Mutex::new(ReceiverState {
+                items: VecDeque::new(),
+                lagged: 0,
+            }),
+            capacity,
+            data_available: Notify::new(),
+            waiters: Default::default(),
+            dropped: Default::default(),
+        }
+    }
+
     pub fn data_len(&self) -> usize {
-        self.data.len()
+        self.state.lock().items.len()
     }
 
-    pub fn barriers_len(&self) -> usize {
-        self.barriers.len()
+    /// Max number of queued items, `None` for the unbounded queue
+    pub fn capacity(&self) -> Option<usize> {
+        self.capacity
     }
 
-    pub fn push(self: &Arc<Self>, data: T) {
-        self.data.push(data);
-        let receiver = self.clone();
-        tokio::spawn(async move {
-            while receiver.sync_lock.load(Ordering::Acquire) > 0 {
-                if let Some(barrier) = receiver.barriers.pop() {
-                    barrier.wait().await;
-                    break;
-                } else {
-                    tokio::task::yield_now().await;
-                }
+    /// Enqueue timestamp of the oldest item (in ms)
+    pub fn oldest_enqueued_at_ms(&self) -> Option<u64> {
+        self.state.lock().items.front().map(|(_, at)| *at)
+    }
+
+    /// Total number of items dropped because the queue was full
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Number of pending [`BroadcastReceiver::recv`] calls
+    pub fn waiters_len(&self) -> usize {
+        self.waiters.load(Ordering::Acquire)
+    }
+
+    pub fn push(&self, data: T, now_ms: u64) {
+        {
+            let mut state = self.state.lock();
+            if matches!(self.capacity, Some(capacity) if state.items.len() >= capacity) {
+                state.items.pop_front();
+                state.lagged += 1;
+                self.dropped.fetch_add(1, Ordering::Relaxed);
             }
-        });
+            state.items.push_back((data, now_ms));
+        }
+        self.data_available.notify_one();
     }
 
-    pub async fn pop(&self) -> T {
-        self.sync_lock.fetch_add(1, Ordering::Release);
+    /// Waits for the next item. Returns an error once after some items were dropped,
+    /// the next call returns the oldest remaining item.
+    ///
+    /// NOTE: cancellation safe, the item is not lost if the future is dropped
+    pub async fn recv(&self) -> Result<T, BroadcastLagged> {
+        self.waiters.fetch_add(1, Ordering::AcqRel);
+        let _guard = WaiterGuard(&self.waiters);
+
         loop {
-            match self.data.pop() {
-                Some(data) => {
-                    self.sync_lock.fetch_sub(1, Ordering::Release);
-                    return data;
+            {
+                let mut state = self.state.lock();
+                if state.lagged > 0 {
+                    return Err(BroadcastLagged(std::mem::take(&mut state.lagged)));
                 }
-                None => {
-                    let barrier = Arc::new(Barrier::new(2));
-                    self.barriers.push(barrier.clone());
-                    barrier.wait().await;
+                if let Some((data, _)) = state.items.pop_front() {
+                    return Ok(data);
                 }
             }
+            // NOTE: `notify_one` stores a permit if there are no waiters,
+            // so the item pushed after the check above is not missed
+            self.data_available.notified().await;
         }
     }
 }
 
-impl<T> Default for BroadcastReceiver<T> {
-    fn default() -> Self {
-        Self {
-            data: Default::default(),
-            barriers: Default::default(),
-            sync_lock: Default::default(),
+/// Some received broadcasts were dropped because the consumer didn't keep up,
+/// see [`OverlayOptions::received_broadcasts_capacity`]
+///
+/// [`OverlayOptions::received_broadcasts_capacity`]: super::OverlayOptions::received_broadcasts_capacity
+#[derive(thiserror::Error, Debug, Copy, Clone, Eq, PartialEq)]
+#[error("broadcast receiver lagged behind, {0} broadcasts were dropped")]
+pub struct BroadcastLagged(pub u64);
+
+struct WaiterGuard<'a>(&'a AtomicUsize);
+
+impl Drop for WaiterGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn cancelled_pop_does_not_block_queue() {
+        let receiver = Arc::new(BroadcastReceiver::<u32>::new(None));
+
+        // Cancelled waiter
+        let pop = tokio::time::timeout(Duration::from_millis(10), receiver.recv()).await;
+        assert!(pop.is_err());
+        assert_eq!(receiver.waiters_len(), 0);
+
+        let waiter = tokio::spawn({
+            let receiver = receiver.clone();
+            async move { receiver.recv().await }
+        });
+        tokio::task::yield_now().await;
+        receiver.push(1, 0);
+        assert_eq!(waiter.await.unwrap(), Ok(1));
+
+        // Items pushed without waiters are kept
+        receiver.push(2, 0);
+        receiver.push(3, 0);
+        assert_eq!(receiver.recv().await, Ok(2));
+        assert_eq!(receiver.recv().await, Ok(3));
+        assert_eq!(receiver.data_len(), 0);
+    }
+
+    #[tokio::test]
+    async fn full_queue_reports_lag() {
+        let receiver = BroadcastReceiver::<u32>::new(Some(2));
+        assert_eq!(receiver.capacity(), Some(2));
+        assert_eq!(receiver.oldest_enqueued_at_ms(), None);
+
+        for i in 0..5 {
+            receiver.push(i, 100 + i as u64);
+        }
+        assert_eq!(receiver.data_len(), 2);
+        assert_eq!(receiver.dropped(), 3);
+        assert_eq!(receiver.oldest_enqueued_at_ms(), Some(103));
+
+        // Lag is reported once, then the oldest remaining items are returned
+        assert_eq!(receiver.recv().await, Err(BroadcastLagged(3)));
+        assert_eq!(receiver.recv().await, Ok(3));
+        receiver.push(5, 105);
+        assert_eq!(receiver.recv().await, Ok(4));
+        assert_eq!(receiver.recv().await, Ok(5));
+        assert_eq!(receiver.dropped(), 3);
+
+        // Unbounded queue never drops
+        let receiver = BroadcastReceiver::<u32>::new(None);
+        for i in 0..1000 {
+            receiver.push(i, 0);
         }
+        assert_eq!(receiver.data_len(), 1000);
+        assert_eq!(receiver.dropped(), 0);
+        assert_eq!(receiver.recv().await, Ok(0));
     }
 }
@@ -11,13 +11,34 @@ pub use overlay_id::{IdFull, IdShort};
 
 mod overlay_id;
 
+#[cfg(feature = "overlay")]
+mod bootstrap;
+#[cfg(feature = "overlay")]
+mod broadcast_handlers;
+#[cfg(feature = "overlay")]
+mod broadcast_history;
 #[cfg(feature = "overlay")]
 mod broadcast_receiver;
 #[cfg(feature = "overlay")]
 mod node;
 #[cfg(feature = "overlay")]
+mod outgoing_queue;
+#[cfg(feature = "overlay")]
 #[allow(clippy::module_inception)]
 mod overlay;
+#[cfg(feature = "overlay")]
+mod peer_scores;
+#[cfg(feature = "overlay")]
+mod peer_validation;
+#[cfg(feature = "overlay")]
+mod storm_throttle;
+#[cfg(all(feature = "overlay", any(test, feature = "test-utils")))]
+mod test_cluster;
+
+#[cfg(all(test, feature = "overlay"))]
+pub(crate) use self::overlay::{
+    make_broadcast_to_sign, make_fec_part_to_sign, BROADCAST_FLAG_ANY_SENDER,
+};
 
 #[cfg(feature = "overlay")]
 mod node_impl {
@@ -27,11 +48,21 @@ mod node_impl {
     use frunk_core::hlist::{HCons, HList, IntoTuple2, Selector};
     use frunk_core::indices::There;
 
-    pub use super::node::Node;
+    pub use super::bootstrap::{BootstrapError, FullNodeBuilder, FullNodeNetwork};
+    pub use super::broadcast_handlers::BroadcastHandler;
+    pub use super::broadcast_receiver::BroadcastLagged;
+    pub use super::node::{NetworkMetricsSnapshot, Node, OverlaySubscriber};
+    pub use super::outgoing_queue::BroadcastClass;
     pub use super::overlay::{
-        BroadcastTarget, ExistingPeersFilter, IncomingBroadcastInfo, OutgoingBroadcastInfo,
-        Overlay, OverlayMetrics, OverlayOptions, ReceivedPeersMap,
+        compute_query_id, BroadcastTarget, ExistingPeersFilter, IncomingBroadcastInfo,
+        OutgoingBroadcastInfo, Overlay, OverlayMetrics, OverlayOptions, OverlayQueryTransport,
+        OverlayTuning, QueryAnyOptions, ReceivedPeersMap,
+    };
+    pub use super::peer_validation::{
+        ExternalPeer, ExternalPeerStatus, ExternalPeersReport, PeerRejectReason,
     };
+    #[cfg(any(test, feature = "test-utils"))]
+    pub use super::test_cluster::{ClusterNode, OverlayTestCluster};
 
     use crate::rldp;
     use crate::util::{DeferredInitialization, NetworkBuilder};
@@ -1,15 +1,20 @@
 use std::borrow::Cow;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 
 use anyhow::Result;
+use parking_lot::RwLock;
+use sha2::Digest;
 use tl_proto::{BoxedConstructor, TlRead};
 
 use super::overlay::{Overlay, OverlayMetrics, OverlayOptions};
 use super::overlay_id::IdShort;
 use crate::adnl;
 use crate::proto;
+use crate::rldp;
 use crate::subscriber::*;
 use crate::util::*;
+use crate::NetworkEvent;
 
 /// P2P messages distribution layer group
 pub struct Node {
@@ -24,7 +29,15 @@ pub struct Node {
 impl Node {
     pub fn new(adnl: Arc<adnl::Node>, key_tag: usize) -> Result<Arc<Self>> {
         let node_key = adnl.key_by_tag(key_tag)?.clone();
-        let state = Arc::new(NodeState::default());
+        let state = Arc::new(NodeState {
+            layer: adnl.register_layer("overlay"),
+            overlays: Default::default(),
+            pinned_peers: Default::default(),
+            subscribers: Default::default(),
+            message_subscribers: Default::default(),
+            default_subscriber: Default::default(),
+            unknown_overlay_messages: Default::default(),
+        });
 
         adnl.add_query_subscriber(state.clone())?;
         adnl.add_message_subscriber(state.clone())?;
@@ -36,6 +49,15 @@ impl Node {
         }))
     }
 
+    /// Stops broadcasts and background tasks of all overlays.
+    /// Incoming overlay messages and queries are ignored afterwards.
+    ///
+    /// Must be called before [`rldp::Node::shutdown`] and [`adnl::Node::shutdown`]
+    pub fn shutdown(&self) {
+        self.state.layer.shutdown();
+        tracing::debug!("overlay node shut down");
+    }
+
     /// Returns inner query subscriber
     pub fn query_subscriber(&self) -> Arc<dyn QuerySubscriber> {
         self.state.clone()
@@ -43,10 +65,28 @@ impl Node {
 
     /// Returns metrics for all overlays
     pub fn metrics(&self) -> impl Iterator<Item = (IdShort, OverlayMetrics)> + '_ {
+        self.overlays()
+            .into_iter()
+            .map(|overlay| (*overlay.id(), overlay.metrics()))
+    }
+
+    /// Returns ADNL, RLDP and overlays metrics collected at once
+    pub fn full_metrics(&self, rldp: Option<&rldp::Node>) -> NetworkMetricsSnapshot {
+        NetworkMetricsSnapshot {
+            captured_at_ms: self.adnl.clock().now_ms(),
+            adnl: self.adnl.metrics(),
+            rldp: rldp.map(rldp::Node::metrics),
+            overlays: self.metrics().collect(),
+        }
+    }
+
+    /// Copies the current overlays so that no map guards are held while they are used
+    fn overlays(&self) -> Vec<Arc<Overlay>> {
         self.state
             .overlays
             .iter()
-            .map(|item| (*item.id(), item.metrics()))
+            .map(|item| item.value().clone())
+            .collect()
     }
 
     /// Underlying ADNL node
@@ -54,6 +94,23 @@ impl Node {
         &self.adnl
     }
 
+    /// Adds overlay unicast messages subscriber
+    pub fn add_overlay_message_subscriber(
+        &self,
+        overlay_id: IdShort,
+        subscriber: Arc<dyn MessageSubscriber>,
+    ) -> bool {
+        use dashmap::mapref::entry::Entry;
+
+        match self.state.message_subscribers.entry(overlay_id) {
+            Entry::Vacant(entry) => {
+                entry.insert(subscriber);
+                true
+            }
+            Entry::Occupied(_) => false,
+        }
+    }
+
     /// Adds overlay queries subscriber
     pub fn add_overlay_subscriber(
         &self,
@@ -71,152 +128,1335 @@ impl Node {
         }
     }
 
-    /// Creates new public overlay
+    /// Sets the catch-all subscriber for queries to overlays without their own subscriber
+    pub fn set_default_subscriber(&self, subscriber: Arc<dyn OverlaySubscriber>) {
+        *self.state.default_subscriber.write() = Some(subscriber);
+    }
+
+    /// Number of overlay messages and broadcasts dropped because their overlay is unknown
+    pub fn unknown_overlay_messages(&self) -> u64 {
+        self.state.unknown_overlay_messages.load(Ordering::Relaxed)
+    }
+
+    /// Creates new public overlay. Returns an error if the options are inconsistent
+    /// (see [`OverlayOptions::validate`])
     pub fn add_public_overlay(
         &self,
         overlay_id: &IdShort,
         options: OverlayOptions,
-    ) -> (Arc<Overlay>, bool) {
+    ) -> Result<(Arc<Overlay>, bool)> {
         use dashmap::mapref::entry::Entry;
 
+        options.validate()?;
+
         match self.state.overlays.entry(*overlay_id) {
             Entry::Vacant(entry) => {
-                let overlay = Overlay::new(self.node_key.clone(), *overlay_id, &[], options);
+                let overlay = Overlay::new(
+                    self.node_key.clone(),
+                    *overlay_id,
+                    &[],
+                    false,
+                    options,
+                    &self.adnl,
+                    self.state.layer.clone(),
+                );
                 entry.insert(overlay.clone());
-                (overlay, true)
+                self.emit_overlay_added(overlay_id);
+                Ok((overlay, true))
             }
-            Entry::Occupied(entry) => (entry.get().clone(), false),
+            Entry::Occupied(entry) => Ok((entry.get().clone(), false)),
         }
     }
 
-    /// Creates new private overlay
+    /// Creates new private overlay. Returns an error if the options are inconsistent
+    /// (see [`OverlayOptions::validate`])
     pub fn add_private_overlay(
         &self,
         overlay_id: &IdShort,
         overlay_key: Arc<adnl::Key>,
         peers: &[adnl::NodeIdShort],
         options: OverlayOptions,
-    ) -> (Arc<Overlay>, bool) {
+    ) -> Result<(Arc<Overlay>, bool)> {
         use dashmap::mapref::entry::Entry;
 
+        options.validate()?;
+
         match self.state.overlays.entry(*overlay_id) {
             Entry::Vacant(entry) => {
-                let overlay = Overlay::new(overlay_key, *overlay_id, peers, options);
+                // Private overlay members must not be evicted by random public peers
+                for peer_id in peers {
+                    self.adnl.pin_peer(peer_id);
+                }
+                self.state.pinned_peers.insert(*overlay_id, peers.to_vec());
+
+                let overlay = Overlay::new(
+                    overlay_key,
+                    *overlay_id,
+                    peers,
+                    true,
+                    options,
+                    &self.adnl,
+                    self.state.layer.clone(),
+                );
                 entry.insert(overlay.clone());
-                (overlay, true)
+                self.emit_overlay_added(overlay_id);
+                Ok((overlay, true))
             }
-            Entry::Occupied(entry) => (entry.get().clone(), false),
+            Entry::Occupied(entry) => Ok((entry.get().clone(), false)),
         }
     }
 
+    /// Removes overlay with all its subscribers. Returns whether the overlay existed
+    pub fn remove_overlay(&self, overlay_id: &IdShort) -> bool {
+        self.state.subscribers.remove(overlay_id);
+        self.state.message_subscribers.remove(overlay_id);
+
+        if let Some((_, peers)) = self.state.pinned_peers.remove(overlay_id) {
+            for peer_id in &peers {
+                self.adnl.unpin_peer(peer_id);
+            }
+        }
+
+        let removed = self.state.overlays.remove(overlay_id).is_some();
+        if removed {
+            self.adnl
+                .emit_event(|timestamp_ms| NetworkEvent::OverlayRemoved {
+                    overlay_id: *overlay_id,
+                    timestamp_ms,
+                });
+        }
+        removed
+    }
+
     /// Returns overlay by specified id
     #[inline(always)]
     pub fn get_overlay(&self, overlay_id: &IdShort) -> Result<Arc<Overlay>> {
         self.state.get_overlay(overlay_id)
     }
+
+    /// Writes known peers of each overlay into a separate file `<hex overlay id>.peers`
+    /// in the specified directory, see [`Overlay::export_peers_tl`].
+    ///
+    /// NOTE: uses blocking IO
+    #[cfg(feature = "persistence")]
+    pub fn save_peers<P: AsRef<std::path::Path>>(&self, dir: P) -> Result<()> {
+        let dir = dir.as_ref();
+        std::fs::create_dir_all(dir)?;
+
+        for overlay in self.state.overlays.iter() {
+            let path = peers_file_path(dir, overlay.id());
+            let tmp_path = path.with_extension("peers.tmp");
+            std::fs::write(&tmp_path, overlay.export_peers_tl(&self.adnl))?;
+            std::fs::rename(tmp_path, path)?;
+        }
+        Ok(())
+    }
+
+    /// Imports peers of each overlay from the files written by [`Node::save_peers`].
+    /// Returns the total number of added peers.
+    ///
+    /// Missing or corrupted files are skipped.
+    ///
+    /// NOTE: uses blocking IO
+    #[cfg(feature = "persistence")]
+    pub fn load_peers<P: AsRef<std::path::Path>>(&self, dir: P) -> usize {
+        let dir = dir.as_ref();
+
+        let mut total = 0;
+        for overlay in self.state.overlays.iter() {
+            let path = peers_file_path(dir, overlay.id());
+            let data = match std::fs::read(&path) {
+                Ok(data) => data,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => {
+                    tracing::warn!(path = %path.display(), "failed to read overlay peers: {e}");
+                    continue;
+                }
+            };
+            match overlay.import_peers_tl(&self.adnl, &data) {
+                Ok(peers) => total += peers.len(),
+                Err(e) => {
+                    tracing::warn!(path = %path.display(), "failed to import overlay peers: {e:?}");
+                }
+            }
+        }
+        total
+    }
+
+    fn emit_overlay_added(&self, overlay_id: &IdShort) {
+        self.adnl
+            .emit_event(|timestamp_ms| NetworkEvent::OverlayAdded {
+                overlay_id: *overlay_id,
+                timestamp_ms,
+            });
+    }
+}
+
+impl Drop for Node {
+    fn drop(&mut self) {
+        if !self.state.layer.is_shut_down() {
+            tracing::warn!("overlay node was dropped without shutdown");
+            self.shutdown();
+        }
+    }
+}
+
+#[cfg(feature = "persistence")]
+fn peers_file_path(dir: &std::path::Path, overlay_id: &IdShort) -> std::path::PathBuf {
+    dir.join(format!("{}.peers", hex::encode(overlay_id.as_slice())))
 }
 
-#[derive(Default)]
 struct NodeState {
+    /// Background tasks of all overlays
+    layer: Arc<adnl::DependentLayer>,
     /// Overlays by ids
     overlays: FastDashMap<IdShort, Arc<Overlay>>,
+    /// Peers which were pinned by the private overlays
+    pinned_peers: FastDashMap<IdShort, Vec<adnl::NodeIdShort>>,
     /// Overlay query subscribers
     subscribers: FastDashMap<IdShort, Arc<dyn QuerySubscriber>>,
+    /// Overlay unicast messages subscribers
+    message_subscribers: FastDashMap<IdShort, Arc<dyn MessageSubscriber>>,
+    /// Catch-all query subscriber
+    default_subscriber: RwLock<Option<Arc<dyn OverlaySubscriber>>>,
+    /// Messages for unknown overlays
+    unknown_overlay_messages: AtomicU64,
 }
 
 impl NodeState {
+    fn default_subscriber(&self) -> Option<Arc<dyn OverlaySubscriber>> {
+        self.default_subscriber.read().clone()
+    }
+
     fn get_overlay(&self, overlay_id: &IdShort) -> Result<Arc<Overlay>> {
         match self.overlays.get(overlay_id) {
             Some(overlay) => Ok(overlay.clone()),
             None => Err(NodeError::UnknownOverlay.into()),
         }
     }
+
+    /// Forwards unicast message to the overlay subscriber.
+    ///
+    /// Unhandled messages are counted and silently dropped
+    async fn consume_overlay_message<'a>(
+        &self,
+        ctx: SubscriberContext<'a>,
+        message_ctx: MessageContext<'a>,
+        overlay: &Overlay,
+        data: &'a [u8],
+    ) -> Result<bool> {
+        let consumer = self
+            .message_subscribers
+            .get(overlay.id())
+            .map(|item| item.value().clone());
+
+        if let (Some(consumer), Ok(constructor)) = (consumer, u32::read_from(data, &mut 0)) {
+            if consumer
+                .try_consume_custom_ext(ctx, message_ctx, constructor, data)
+                .await?
+            {
+                return Ok(true);
+            }
+        }
+
+        overlay.add_unhandled_message();
+        if tracing::enabled!(tracing::Level::DEBUG)
+            && ctx.adnl.should_log("unhandled overlay message")
+        {
+            tracing::debug!(
+                overlay_id = %overlay.id(),
+                peer_id = %ctx.peer_id,
+                len = data.len(),
+                "unhandled overlay message"
+            );
+        }
+        Ok(true)
+    }
 }
 
 #[async_trait::async_trait]
 impl MessageSubscriber for NodeState {
-    async fn try_consume_custom<'a>(
+    async fn try_consume_custom_ext<'a>(
         &self,
         ctx: SubscriberContext<'a>,
+        message_ctx: MessageContext<'a>,
         constructor: u32,
         data: &'a [u8],
     ) -> Result<bool> {
         if constructor != proto::overlay::Message::TL_ID {
             return Ok(false);
         }
+        if self.layer.is_shut_down() {
+            return Ok(true);
+        }
 
         let mut offset = 4; // skip `overlay::Message` constructor
         let overlay_id = IdShort::from(<[u8; 32]>::read_from(data, &mut offset)?);
-        let broadcast = proto::overlay::Broadcast::read_from(data, &mut offset)?;
+        let overlay = match self.get_overlay(&overlay_id) {
+            Ok(overlay) => overlay,
+            Err(e) => {
+                self.unknown_overlay_messages
+                    .fetch_add(1, Ordering::Relaxed);
+                return Err(e);
+            }
+        };
 
-        // TODO: check that offset == data.len()
+        let payload = &data[offset..];
 
-        let overlay = self.get_overlay(&overlay_id)?;
+        // Payloads with trailing data are not broadcasts even if the constructor matches
+        let broadcast = match proto::overlay::Broadcast::read_from(data, &mut offset) {
+            Ok(_) if offset != data.len() => Err(tl_proto::TlError::InvalidData),
+            broadcast => broadcast,
+        };
         match broadcast {
-            proto::overlay::Broadcast::Broadcast(broadcast) => {
+            Ok(proto::overlay::Broadcast::Broadcast(broadcast)) => {
+                ctx.adnl.record_broadcast();
                 overlay
                     .receive_broadcast(ctx.adnl, ctx.local_id, ctx.peer_id, broadcast, data)
                     .await?;
                 Ok(true)
             }
-            proto::overlay::Broadcast::BroadcastFec(broadcast) => {
+            Ok(proto::overlay::Broadcast::BroadcastFec(broadcast)) => {
+                ctx.adnl.record_broadcast();
                 overlay
                     .receive_fec_broadcast(ctx.adnl, ctx.local_id, ctx.peer_id, broadcast, data)
                     .await?;
                 Ok(true)
             }
-            _ => Err(NodeError::UnsupportedOverlayBroadcastMessage.into()),
+            Ok(proto::overlay::Broadcast::Unicast { data }) => {
+                self.consume_overlay_message(ctx, message_ctx, &overlay, data)
+                    .await
+            }
+            // Other known broadcast types are not supported yet
+            Ok(_) => {
+                overlay.add_unhandled_message();
+                if tracing::enabled!(tracing::Level::DEBUG)
+                    && ctx.adnl.should_log("unsupported overlay broadcast message")
+                {
+                    tracing::debug!(
+                        %overlay_id,
+                        peer_id = %ctx.peer_id,
+                        "unsupported overlay broadcast message"
+                    );
+                }
+                Ok(true)
+            }
+            // Arbitrary payload after `overlay::Message` prefix (see [`Overlay::send_message`])
+            Err(_) => {
+                self.consume_overlay_message(ctx, message_ctx, &overlay, payload)
+                    .await
+            }
         }
     }
 }
 
 #[async_trait::async_trait]
 impl QuerySubscriber for NodeState {
-    async fn try_consume_query<'a>(
+    async fn try_consume_query_ext<'a>(
         &self,
         ctx: SubscriberContext<'a>,
+        query_ctx: QueryContext,
         constructor: u32,
         query: Cow<'a, [u8]>,
     ) -> Result<QueryConsumingResult<'a>> {
         if constructor != proto::rpc::OverlayQuery::TL_ID {
-            return Ok(QueryConsumingResult::Rejected(query));
+            return Ok(QueryConsumingResult::reject(query));
+        }
+        if self.layer.is_shut_down() {
+            return Ok(QueryConsumingResult::no_answer());
         }
 
-        let mut offset = 4; // skip `rpc::OverlayQuery` constructor
-        let overlay_id = IdShort::from(<[u8; 32]>::read_from(&query, &mut offset)?);
+        let (overlay_id, mut offset) = match split_overlay_query(&query)? {
+            Some(split) => split,
+            None => return Ok(QueryConsumingResult::reject(query)),
+        };
 
         let constructor = u32::read_from(&query, &mut std::convert::identity(offset))?;
+
+        // Unknown overlays are handled by the catch-all subscriber if there is one
+        let overlay = self.overlays.get(&overlay_id).map(|item| item.clone());
+        if overlay.is_none() && !self.subscribers.contains_key(&overlay_id) {
+            if let Some(consumer) = self.default_subscriber() {
+                return consume_default_query(
+                    consumer.as_ref(),
+                    ctx,
+                    query_ctx,
+                    &overlay_id,
+                    constructor,
+                    query,
+                    offset,
+                )
+                .await;
+            }
+        }
+
         if constructor == proto::rpc::OverlayGetRandomPeers::TL_ID {
             let query = proto::rpc::OverlayGetRandomPeers::read_from(&query, &mut offset)?;
             let overlay = self.get_overlay(&overlay_id)?;
-            return QueryConsumingResult::consume(
+            return Ok(QueryConsumingResult::answer(
                 overlay.process_get_random_peers(query).into_boxed(),
-            );
+            ));
+        }
+
+        // Retained broadcasts, other queries are passed to the subscriber if retention is disabled
+        if constructor == proto::rpc::OverlayGetBroadcast::TL_ID {
+            let overlay = self.get_overlay(&overlay_id)?;
+            let query = proto::rpc::OverlayGetBroadcast::read_from(&query, &mut offset.clone())?;
+            if let Some(answer) = overlay.process_get_broadcast(query) {
+                return Ok(QueryConsumingResult::Consumed(Some(answer)));
+            }
+        } else if constructor == proto::rpc::OverlayGetBroadcastList::TL_ID {
+            let overlay = self.get_overlay(&overlay_id)?;
+            let query =
+                proto::rpc::OverlayGetBroadcastList::read_from(&query, &mut offset.clone())?;
+            if let Some(answer) = overlay.process_get_broadcast_list(query) {
+                return Ok(QueryConsumingResult::answer(answer.into_boxed()));
+            }
         }
 
         let consumer = match self.subscribers.get(&overlay_id) {
             Some(consumer) => consumer.clone(),
-            None => return Err(NodeError::NoConsumerFound.into()),
+            None => {
+                return match self.default_subscriber() {
+                    Some(consumer) => {
+                        consume_default_query(
+                            consumer.as_ref(),
+                            ctx,
+                            query_ctx,
+                            &overlay_id,
+                            constructor,
+                            query,
+                            offset,
+                        )
+                        .await
+                    }
+                    None => Ok(QueryConsumingResult::reject_with(
+                        query,
+                        RejectReason::TemporarilyUnavailable,
+                    )),
+                };
+            }
         };
 
+        let cache_key = compute_answer_cache_key(query_ctx.transport, &query[offset..]);
+        if let Some(answer) = overlay
+            .as_ref()
+            .and_then(|overlay| overlay.find_cached_answer(&cache_key))
+        {
+            return Ok(QueryConsumingResult::Consumed(Some(answer)));
+        }
+
         match consumer
-            .try_consume_query(ctx, constructor, Cow::Borrowed(&query[offset..]))
+            .try_consume_query_ext(ctx, query_ctx, constructor, Cow::Borrowed(&query[offset..]))
             .await?
         {
             QueryConsumingResult::Consumed(result) => Ok(QueryConsumingResult::Consumed(result)),
-            QueryConsumingResult::Rejected(_) => Err(NodeError::UnsupportedQuery.into()),
+            QueryConsumingResult::ConsumedLarge(answer) => {
+                Ok(QueryConsumingResult::ConsumedLarge(answer))
+            }
+            QueryConsumingResult::Cacheable(answer, ttl) => {
+                if let Some(overlay) = &overlay {
+                    overlay.cache_answer(cache_key, &answer, ttl);
+                }
+                Ok(QueryConsumingResult::Cacheable(answer, ttl))
+            }
+            QueryConsumingResult::ConsumedDeferred(token) => {
+                Ok(QueryConsumingResult::ConsumedDeferred(token))
+            }
+            // Pass the original query to the next subscribers
+            QueryConsumingResult::Rejected(_) => Ok(QueryConsumingResult::reject(query)),
+            QueryConsumingResult::RejectedWith(_, reason) => {
+                Ok(QueryConsumingResult::reject_with(query, reason))
+            }
+        }
+    }
+}
+
+/// Passes the query without `overlay.query` prefixes to the catch-all subscriber
+async fn consume_default_query<'a>(
+    consumer: &dyn OverlaySubscriber,
+    ctx: SubscriberContext<'a>,
+    query_ctx: QueryContext,
+    overlay_id: &IdShort,
+    constructor: u32,
+    query: Cow<'a, [u8]>,
+    offset: usize,
+) -> Result<QueryConsumingResult<'a>> {
+    match consumer
+        .try_consume_query(
+            ctx,
+            query_ctx,
+            overlay_id,
+            constructor,
+            Cow::Borrowed(&query[offset..]),
+        )
+        .await?
+    {
+        QueryConsumingResult::Consumed(result) => Ok(QueryConsumingResult::Consumed(result)),
+        QueryConsumingResult::ConsumedLarge(answer) => {
+            Ok(QueryConsumingResult::ConsumedLarge(answer))
+        }
+        QueryConsumingResult::Cacheable(answer, ttl) => {
+            Ok(QueryConsumingResult::Cacheable(answer, ttl))
+        }
+        QueryConsumingResult::ConsumedDeferred(token) => {
+            Ok(QueryConsumingResult::ConsumedDeferred(token))
+        }
+        // Pass the original query to the next subscribers
+        QueryConsumingResult::Rejected(_) => Ok(QueryConsumingResult::reject(query)),
+        QueryConsumingResult::RejectedWith(_, reason) => {
+            Ok(QueryConsumingResult::reject_with(query, reason))
+        }
+    }
+}
+
+/// Hash of the query bytes and the transport through which it was received
+/// (answer size limits are different for ADNL and RLDP)
+fn compute_answer_cache_key(transport: QueryTransport, query: &[u8]) -> AnswerCacheKey {
+    let mut hasher = sha2::Sha256::new();
+    hasher.update([transport as u8]);
+    hasher.update(query);
+    hasher.finalize().into()
+}
+
+/// Peels off all leading `overlay.query` prefixes with the same overlay id.
+///
+/// Returns overlay id and the offset of the remaining query,
+/// or `None` if there is nothing except prefixes.
+fn split_overlay_query(query: &[u8]) -> tl_proto::TlResult<Option<(IdShort, usize)>> {
+    let mut offset = 0;
+    let proto::rpc::OverlayQuery { overlay } = TlRead::read_from(query, &mut offset)?;
+    let overlay_id = IdShort::from(*overlay);
+
+    loop {
+        if offset >= query.len() {
+            return Ok(None);
+        }
+
+        let mut next_offset = offset;
+        match proto::rpc::OverlayQuery::read_from(query, &mut next_offset) {
+            Ok(proto::rpc::OverlayQuery { overlay }) if overlay == overlay_id.as_slice() => {
+                offset = next_offset;
+            }
+            _ => return Ok(Some((overlay_id, offset))),
         }
     }
 }
 
 #[derive(thiserror::Error, Debug)]
 enum NodeError {
-    #[error("Unsupported overlay broadcast message")]
-    UnsupportedOverlayBroadcastMessage,
     #[error("Unknown overlay")]
     UnknownOverlay,
-    #[error("No consumer for message in overlay")]
-    NoConsumerFound,
-    #[error("Unsupported query")]
-    UnsupportedQuery,
+}
+
+/// Catch-all handler for queries to overlays without their own subscriber,
+/// see [`Node::set_default_subscriber`]
+#[async_trait::async_trait]
+pub trait OverlaySubscriber: Send + Sync {
+    /// Tries to consume the query (without `overlay.query` prefix) to the specified overlay.
+    ///
+    /// The overlay and its subscriber can be created here with [`Node::add_public_overlay`]
+    /// and [`Node::add_overlay_subscriber`], subsequent queries will then skip this handler.
+    async fn try_consume_query<'a>(
+        &self,
+        ctx: SubscriberContext<'a>,
+        query_ctx: QueryContext,
+        overlay_id: &IdShort,
+        constructor: u32,
+        query: Cow<'a, [u8]>,
+    ) -> Result<QueryConsumingResult<'a>>;
+}
+
+/// Metrics of all layers, see [`Node::full_metrics`]
+///
+/// Serialized field names are stable: cumulative counters end with `_total`,
+/// sizes with `_bytes`, durations with `_ms`.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct NetworkMetricsSnapshot {
+    /// Unix timestamp in milliseconds from the node clock
+    pub captured_at_ms: u64,
+    pub adnl: adnl::NodeMetrics,
+    pub rldp: Option<rldp::NodeMetrics>,
+    /// Serialized as a map from the overlay id (base64) to its metrics
+    #[serde(serialize_with = "serialize_overlays_metrics")]
+    pub overlays: Vec<(IdShort, OverlayMetrics)>,
+}
+
+fn serialize_overlays_metrics<S>(
+    overlays: &[(IdShort, OverlayMetrics)],
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    serializer.collect_map(overlays.iter().map(|(id, metrics)| (id, metrics)))
+}
+
+#[cfg(feature = "json")]
+impl NetworkMetricsSnapshot {
+    /// Serializes the snapshot for the admin endpoints
+    pub fn to_json(&self) -> String {
+        // NOTE: All fields are plain numbers or ids, so it never fails
+        serde_json::to_string(self).expect("metrics snapshot is always serializable")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use super::*;
+    use crate::overlay::BroadcastTarget;
+    use crate::util::fixtures::{add_overlay_node, add_virtual_node};
+
+    fn overlay_query(overlay_id: &[u8; 32]) -> Vec<u8> {
+        tl_proto::serialize(proto::rpc::OverlayQuery {
+            overlay: overlay_id,
+        })
+    }
+
+    #[tokio::test]
+    async fn full_metrics_snapshot() {
+        let network = adnl::VirtualNetwork::new(0);
+        let adnl = add_virtual_node(&network, Default::default());
+        let rldp = rldp::Node::new(adnl.clone(), Vec::new(), Default::default()).unwrap();
+        let node = Node::new(adnl.clone(), 0).unwrap();
+
+        let overlay_ids = [[1; 32], [2; 32]].map(IdShort::from);
+        for overlay_id in &overlay_ids {
+            node.add_public_overlay(overlay_id, Default::default())
+                .unwrap();
+        }
+
+        let snapshot = node.full_metrics(Some(&rldp));
+        assert!(snapshot.captured_at_ms > 0);
+        assert!(snapshot.rldp.is_some());
+        let mut ids = snapshot
+            .overlays
+            .iter()
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        ids.sort_unstable_by_key(|id| *id.as_slice());
+        assert_eq!(ids, overlay_ids);
+
+        let value = serde_json::to_value(&snapshot).unwrap();
+        assert_eq!(value["adnl"]["peer_count"], 0);
+        let overlays = value["overlays"].as_object().unwrap();
+        assert_eq!(overlays.len(), 2);
+        for overlay_id in &overlay_ids {
+            let key = serde_json::to_value(overlay_id).unwrap();
+            assert!(overlays.contains_key(key.as_str().unwrap()));
+        }
+        assert!(node.full_metrics(None).rldp.is_none());
+    }
+
+    #[tokio::test]
+    async fn metrics_json_shape() {
+        fn key_paths(prefix: &str, value: &serde_json::Value, paths: &mut Vec<String>) {
+            match value {
+                serde_json::Value::Object(fields) => {
+                    for (key, value) in fields {
+                        key_paths(&format!("{prefix}{key}."), value, paths);
+                    }
+                }
+                serde_json::Value::Array(items) => {
+                    for (i, value) in items.iter().enumerate() {
+                        key_paths(&format!("{prefix}{i}."), value, paths);
+                    }
+                }
+                _ => paths.push(prefix.trim_end_matches('.').to_owned()),
+            }
+        }
+
+        let network = adnl::VirtualNetwork::new(0);
+        let adnl = add_virtual_node(&network, Default::default());
+        let rldp = rldp::Node::new(adnl.clone(), Vec::new(), Default::default()).unwrap();
+        let node = Node::new(adnl, 0).unwrap();
+        node.add_public_overlay(&IdShort::from([1; 32]), Default::default())
+            .unwrap();
+
+        let value = serde_json::to_value(node.full_metrics(Some(&rldp))).unwrap();
+        let mut paths = Vec::new();
+        key_paths("", &value, &mut paths);
+        paths.sort_unstable();
+        // Renamed fields break the admin endpoints
+        let expected = [
+            "adnl.answers_dropped_total",
+            "adnl.answers_expired_total",
+            "adnl.answers_spoofed_total",
+            "adnl.answers_too_large_total",
+            "adnl.answers_unverified_total",
+            "adnl.channel_setup_retransmits_total",
+            "adnl.channel_setup_timeouts_total",
+            "adnl.channels_by_id_len",
+            "adnl.channels_by_peers_len",
+            "adnl.deferred_answers_expired_total",
+            "adnl.deferred_answers_len",
+            "adnl.events_dropped_total",
+            "adnl.handshake_packets_sent_total",
+            "adnl.incoming_transfers_len",
+            "adnl.large_answers_len",
+            "adnl.large_answers_size_bytes",
+            "adnl.log_messages_suppressed_total",
+            "adnl.packets_dropped.bad_length_total",
+            "adnl.packets_dropped.checksum_mismatch_total",
+            "adnl.packets_dropped.decrypt_error_total",
+            "adnl.packets_dropped.denied_address_total",
+            "adnl.packets_dropped.denied_peer_total",
+            "adnl.packets_dropped.invalid_signature_total",
+            "adnl.packets_dropped.parse_error_total",
+            "adnl.packets_dropped.replayed_total",
+            "adnl.packets_dropped.seqno_too_old_total",
+            "adnl.packets_dropped.unknown_channel_total",
+            "adnl.packets_dropped.unsupported_version_total",
+            "adnl.packets_send_dropped_total",
+            "adnl.peer_count",
+            "adnl.peers_by_context.adnl_packet",
+            "adnl.peers_by_context.dht",
+            "adnl.peers_by_context.public_overlay",
+            "adnl.peers_evicted_total",
+            "adnl.peers_rejected_total",
+            "adnl.queries_deduplicated_total",
+            "adnl.query_count",
+            "adnl.query_retransmits_total",
+            "adnl.reserved_addresses_rejected_total",
+            "adnl.traffic.bytes_received_total",
+            "adnl.traffic.bytes_sent_total",
+            "adnl.traffic.handshake_packets_received_total",
+            "adnl.traffic.messages_consumed_total",
+            "adnl.traffic.messages_unhandled_total",
+            "adnl.traffic.packets_received_total",
+            "adnl.traffic.packets_sent_total",
+            "adnl.traffic.packets_to_unknown_local_id_total",
+            "adnl.traffic.queries_answered_total",
+            "adnl.traffic.queries_consumed_total",
+            "adnl.traffic.queries_sent_total",
+            "adnl.traffic.queries_timed_out_total",
+            "adnl.traffic.queries_unhandled_total",
+            "captured_at_ms",
+            "overlays.{id}.answer_cache_hits_total",
+            "overlays.{id}.answer_cache_len",
+            "overlays.{id}.answer_cache_size_bytes",
+            "overlays.{id}.broadcast_decode_errors_total",
+            "overlays.{id}.broadcast_history_len",
+            "overlays.{id}.broadcast_history_size_bytes",
+            "overlays.{id}.broadcasts_corrupted_total",
+            "overlays.{id}.broadcasts_duplicated_total",
+            "overlays.{id}.fec_transfers_expired_total",
+            "overlays.{id}.finished_broadcasts_len",
+            "overlays.{id}.ignored_peers_len",
+            "overlays.{id}.incoming_fec_transfers",
+            "overlays.{id}.known_peers",
+            "overlays.{id}.neighbours",
+            "overlays.{id}.node_count",
+            "overlays.{id}.oldest_received_broadcast_age_ms",
+            "overlays.{id}.originated_packets_dropped_total",
+            "overlays.{id}.originated_queue_len",
+            "overlays.{id}.outgoing_broadcast_packets_per_sec",
+            "overlays.{id}.outgoing_broadcast_packets_total",
+            "overlays.{id}.own_broadcast_echoes_total",
+            "overlays.{id}.owned_broadcasts_len",
+            "overlays.{id}.received_broadcasts_barrier_count",
+            "overlays.{id}.received_broadcasts_capacity",
+            "overlays.{id}.received_broadcasts_data_len",
+            "overlays.{id}.received_broadcasts_dropped_total",
+            "overlays.{id}.received_peers_len",
+            "overlays.{id}.relayed_packets_dropped_total",
+            "overlays.{id}.relayed_queue_len",
+            "overlays.{id}.suppressed_broadcast_forwards_total",
+            "overlays.{id}.throttled_broadcast_sources",
+            "overlays.{id}.unhandled_messages_total",
+            "rldp.answers_too_large_total",
+            "rldp.outgoing_transfers",
+            "rldp.outgoing_transfers_rejected_total",
+            "rldp.pacing_ticks_total",
+            "rldp.peer_count",
+            "rldp.queued_outgoing_transfers",
+            "rldp.symbols_sent_total",
+            "rldp.transfers_cache_len",
+        ]
+        // Overlays are keyed by the base64 id
+        .map(|path| path.replace("{id}", "AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE="));
+        assert_eq!(paths, expected);
+
+        #[cfg(feature = "json")]
+        assert!(node.full_metrics(None).to_json().contains(r#""rldp":null"#));
+    }
+
+    #[test]
+    fn split_bundles() {
+        let overlay_id = [1; 32];
+        let prefix = overlay_query(&overlay_id);
+        let query = tl_proto::serialize(proto::rpc::AdnlPing { value: 1 });
+
+        // Only prefix
+        assert!(split_overlay_query(&prefix).unwrap().is_none());
+
+        // Prefix and query
+        let data = [prefix.as_slice(), &query].concat();
+        let (id, offset) = split_overlay_query(&data).unwrap().unwrap();
+        assert_eq!(id.as_slice(), &overlay_id);
+        assert_eq!(&data[offset..], query.as_slice());
+
+        // Two prefixes and query
+        let data = [prefix.as_slice(), &prefix, &query].concat();
+        let (id, offset) = split_overlay_query(&data).unwrap().unwrap();
+        assert_eq!(id.as_slice(), &overlay_id);
+        assert_eq!(&data[offset..], query.as_slice());
+
+        // Prefix with other overlay id is left for the subscriber
+        let other = overlay_query(&[2; 32]);
+        let data = [prefix.as_slice(), &other, &query].concat();
+        let (_, offset) = split_overlay_query(&data).unwrap().unwrap();
+        assert_eq!(
+            &data[offset..],
+            [other.as_slice(), &query].concat().as_slice()
+        );
+    }
+
+    #[tokio::test]
+    async fn broadcasts_over_virtual_ring() {
+        const NODES: usize = 4;
+
+        let network = adnl::VirtualNetwork::new(3);
+        network.set_default_link(adnl::LinkOptions::disconnected());
+
+        let overlay_id = super::super::IdFull::for_workchain(0, &[1; 32]).compute_short_id();
+        let nodes = (0..NODES)
+            .map(|_| {
+                let (adnl, node, overlay) = add_overlay_node(
+                    &network,
+                    &overlay_id,
+                    Default::default(),
+                    Default::default(),
+                );
+                adnl.start().unwrap();
+                (adnl, overlay, node)
+            })
+            .collect::<Vec<_>>();
+
+        // Each node only knows its ring neighbours
+        let ring_link = adnl::LinkOptions {
+            latency_ms: 5,
+            jitter_ms: 2,
+            ..Default::default()
+        };
+        for (i, (adnl, overlay, _)) in nodes.iter().enumerate() {
+            for j in [(i + 1) % NODES, (i + NODES - 1) % NODES] {
+                let (peer_adnl, peer_overlay, _) = &nodes[j];
+                network.connect(adnl.socket_addr(), peer_adnl.socket_addr(), ring_link);
+                overlay
+                    .add_public_peer(
+                        adnl,
+                        peer_adnl.socket_addr(),
+                        peer_overlay.sign_local_node().as_equivalent_ref(),
+                    )
+                    .unwrap();
+            }
+        }
+
+        let receive_all = |len: usize| {
+            let receivers = nodes[1..].iter().map(|(_, overlay, _)| {
+                let overlay = overlay.clone();
+                async move {
+                    let broadcast =
+                        tokio::time::timeout(Duration::from_secs(5), overlay.wait_for_broadcast())
+                            .await
+                            .expect("broadcast not received");
+                    assert_eq!(broadcast.data.len(), len);
+                }
+            });
+            futures_util::future::join_all(receivers)
+        };
+
+        // Ordinary broadcast reaches the opposite node through neighbours
+        let (adnl, overlay, _) = &nodes[0];
+        overlay.broadcast(adnl, vec![1; 100], None, BroadcastTarget::RandomNeighbours);
+        receive_all(100).await;
+
+        // FEC broadcast survives packet loss
+        for (i, (adnl, _, _)) in nodes.iter().enumerate() {
+            let (peer_adnl, _, _) = &nodes[(i + 1) % NODES];
+            let lossy_link = adnl::LinkOptions {
+                loss: 0.05,
+                ..ring_link
+            };
+            network.connect(adnl.socket_addr(), peer_adnl.socket_addr(), lossy_link);
+        }
+        let data: Vec<u8> = (0..4 * 1024).map(|_| rand::random()).collect();
+        overlay.broadcast(adnl, data, None, BroadcastTarget::RandomNeighbours);
+        receive_all(4 * 1024).await;
+    }
+
+    /// Forwards received overlay messages to the channel and optionally sends them back
+    struct MessageEcho {
+        overlay: Option<Arc<Overlay>>,
+        received_tx: tokio::sync::mpsc::UnboundedSender<(adnl::NodeIdShort, Vec<u8>)>,
+    }
+
+    #[async_trait::async_trait]
+    impl MessageSubscriber for MessageEcho {
+        async fn try_consume_custom<'a>(
+            &self,
+            ctx: SubscriberContext<'a>,
+            _: u32,
+            data: &'a [u8],
+        ) -> Result<bool> {
+            if let Some(overlay) = &self.overlay {
+                overlay.send_message(ctx.adnl, ctx.peer_id, data)?;
+            }
+            self.received_tx.send((*ctx.peer_id, data.to_vec())).ok();
+            Ok(true)
+        }
+    }
+
+    #[tokio::test]
+    async fn unicast_messages_echo() {
+        let network = adnl::VirtualNetwork::new(0);
+        let overlay_id = super::super::IdFull::for_workchain(0, &[1; 32]).compute_short_id();
+
+        let make_node = |echo: bool| {
+            let (adnl, node, overlay) = add_overlay_node(
+                &network,
+                &overlay_id,
+                Default::default(),
+                Default::default(),
+            );
+            let (received_tx, received_rx) = tokio::sync::mpsc::unbounded_channel();
+            let subscriber = Arc::new(MessageEcho {
+                overlay: echo.then(|| overlay.clone()),
+                received_tx,
+            });
+            assert!(node.add_overlay_message_subscriber(overlay_id, subscriber));
+            adnl.start().unwrap();
+            (adnl, node, overlay, received_rx)
+        };
+        let (client_adnl, _client_node, client, mut client_rx) = make_node(false);
+        let (server_adnl, _server_node, server, mut server_rx) = make_node(true);
+
+        client
+            .add_public_peer(
+                &client_adnl,
+                server_adnl.socket_addr(),
+                server.sign_local_node().as_equivalent_ref(),
+            )
+            .unwrap();
+        let server_id = *server.overlay_key().id();
+        let client_id = *client.overlay_key().id();
+
+        async fn recv_message(
+            rx: &mut tokio::sync::mpsc::UnboundedReceiver<(adnl::NodeIdShort, Vec<u8>)>,
+        ) -> (adnl::NodeIdShort, Vec<u8>) {
+            tokio::time::timeout(Duration::from_secs(1), rx.recv())
+                .await
+                .expect("message not received")
+                .unwrap()
+        }
+
+        let ping = proto::rpc::AdnlPing { value: 123 };
+        client
+            .send_message_typed(&client_adnl, &server_id, ping)
+            .unwrap();
+        let expected = tl_proto::serialize(ping);
+        assert_eq!(
+            recv_message(&mut server_rx).await,
+            (client_id, expected.clone())
+        );
+        assert_eq!(recv_message(&mut client_rx).await, (server_id, expected));
+
+        // Raw payload which starts with a broadcast constructor is delivered as is
+        let mut raw = tl_proto::serialize(proto::overlay::Broadcast::Unicast { data: &[1; 4] });
+        raw.extend_from_slice(&[2; 4]);
+        client.send_message(&client_adnl, &server_id, &raw).unwrap();
+        assert_eq!(recv_message(&mut server_rx).await, (client_id, raw.clone()));
+        assert_eq!(recv_message(&mut client_rx).await, (server_id, raw));
+
+        assert_eq!(server.metrics().unhandled_messages, 0);
+    }
+
+    #[tokio::test]
+    async fn unicast_messages_routing() {
+        let network = adnl::VirtualNetwork::new(0);
+        let overlay_id = super::super::IdFull::for_workchain(0, &[1; 32]).compute_short_id();
+
+        let (client_adnl, _client_node, client) = add_overlay_node(
+            &network,
+            &overlay_id,
+            Default::default(),
+            Default::default(),
+        );
+        let (server_adnl, server_node, server) = add_overlay_node(
+            &network,
+            &overlay_id,
+            Default::default(),
+            Default::default(),
+        );
+        let (received_tx, mut received_rx) = tokio::sync::mpsc::unbounded_channel();
+        let subscriber = Arc::new(MessageEcho {
+            overlay: None,
+            received_tx,
+        });
+        assert!(server_node.add_overlay_message_subscriber(overlay_id, subscriber.clone()));
+        assert!(!server_node.add_overlay_message_subscriber(overlay_id, subscriber));
+        client_adnl.start().unwrap();
+        server_adnl.start().unwrap();
+
+        client
+            .add_public_peer(
+                &client_adnl,
+                server_adnl.socket_addr(),
+                server.sign_local_node().as_equivalent_ref(),
+            )
+            .unwrap();
+        let server_id = *server.overlay_key().id();
+        let client_id = *client.overlay_key().id();
+
+        let send = |message: proto::overlay::Broadcast<'_>| {
+            client
+                .send_message_typed(&client_adnl, &server_id, message)
+                .unwrap()
+        };
+
+        // Wrapped payload is delivered to the overlay subscriber with the sender id
+        let ping = tl_proto::serialize(proto::rpc::AdnlPing { value: 123 });
+        send(proto::overlay::Broadcast::Unicast { data: &ping });
+        let received = tokio::time::timeout(Duration::from_secs(1), received_rx.recv())
+            .await
+            .unwrap();
+        assert_eq!(received, Some((client_id, ping)));
+
+        // Payloads without constructor and unsupported messages are counted and dropped
+        send(proto::overlay::Broadcast::Unicast { data: &[1, 2] });
+        send(proto::overlay::Broadcast::BroadcastNotFound);
+        for _ in 0..100 {
+            if server.metrics().unhandled_messages >= 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(server.metrics().unhandled_messages, 2);
+        assert!(received_rx.try_recv().is_err());
+    }
+
+    #[derive(Default)]
+    struct CacheablePong {
+        calls: AtomicUsize,
+    }
+
+    #[async_trait::async_trait]
+    impl QuerySubscriber for CacheablePong {
+        async fn try_consume_query<'a>(
+            &self,
+            _: SubscriberContext<'a>,
+            _: u32,
+            query: Cow<'a, [u8]>,
+        ) -> Result<QueryConsumingResult<'a>> {
+            self.calls.fetch_add(1, Ordering::Relaxed);
+            let proto::rpc::AdnlPing { value } = tl_proto::deserialize(&query)?;
+            Ok(QueryConsumingResult::answer(proto::adnl::Pong { value })
+                .with_cache_ttl(Duration::from_secs(60)))
+        }
+    }
+
+    #[tokio::test]
+    async fn cached_answers() {
+        let network = adnl::VirtualNetwork::new(0);
+        let make_node = || {
+            let adnl = add_virtual_node(&network, Default::default());
+            let node = Node::new(adnl.clone(), 0).unwrap();
+            adnl.start().unwrap();
+            (adnl, node)
+        };
+        let (server_adnl, server) = make_node();
+        let (client_adnl, client) = make_node();
+
+        let server_key = server_adnl.key_by_tag(0).unwrap().clone();
+        let client_key = client_adnl.key_by_tag(0).unwrap().clone();
+        for (adnl, local_key, peer) in [
+            (&server_adnl, &server_key, &client_adnl),
+            (&client_adnl, &client_key, &server_adnl),
+        ] {
+            let peer_key = peer.key_by_tag(0).unwrap();
+            adnl.add_peer(
+                adnl::NewPeerContext::AdnlPacket,
+                local_key.id(),
+                peer_key.id(),
+                peer.socket_addr(),
+                *peer_key.full_id(),
+            )
+            .unwrap();
+        }
+
+        let public_id = super::super::IdFull::for_workchain(0, &[1; 32]).compute_short_id();
+        let private_id = super::super::IdFull::for_workchain(0, &[2; 32]).compute_short_id();
+
+        let mut overlays = Vec::new();
+        for (overlay_id, is_private) in [(public_id, false), (private_id, true)] {
+            let subscriber = Arc::new(CacheablePong::default());
+            assert!(server.add_overlay_subscriber(overlay_id, subscriber.clone()));
+
+            let (server_overlay, client_overlay) = if is_private {
+                let peers = [*server_key.id(), *client_key.id()];
+                let overlays = (
+                    server
+                        .add_private_overlay(
+                            &overlay_id,
+                            server_key.clone(),
+                            &peers,
+                            Default::default(),
+                        )
+                        .unwrap()
+                        .0,
+                    client
+                        .add_private_overlay(
+                            &overlay_id,
+                            client_key.clone(),
+                            &peers,
+                            Default::default(),
+                        )
+                        .unwrap()
+                        .0,
+                );
+
+                // Private overlay members are never evicted
+                assert!(server_adnl.is_peer_pinned(client_key.id()));
+                overlays
+            } else {
+                (
+                    server
+                        .add_public_overlay(&overlay_id, Default::default())
+                        .unwrap()
+                        .0,
+                    client
+                        .add_public_overlay(&overlay_id, Default::default())
+                        .unwrap()
+                        .0,
+                )
+            };
+            overlays.push((subscriber, server_overlay, client_overlay));
+        }
+
+        for (i, (subscriber, server_overlay, client_overlay)) in overlays.iter().enumerate() {
+            for value in [1, 1, 2, 1] {
+                let pong = client_overlay
+                    .adnl_query_typed::<_, proto::adnl::Pong>(
+                        &client_adnl,
+                        server_key.id(),
+                        proto::rpc::AdnlPing { value },
+                        None,
+                    )
+                    .await
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(pong.value, value);
+            }
+
+            let calls = subscriber.calls.load(Ordering::Relaxed);
+            let metrics = server_overlay.metrics();
+            if i == 0 {
+                // Public overlay caches answers by default
+                assert_eq!(calls, 2);
+                assert_eq!(metrics.answer_cache_hits, 2);
+                assert_eq!(metrics.answer_cache_len, 2);
+            } else {
+                // Private overlay doesn't
+                assert_eq!(calls, 4);
+                assert_eq!(metrics.answer_cache_hits, 0);
+                assert_eq!(metrics.answer_cache_len, 0);
+            }
+        }
+
+        // Cache is disabled at runtime
+        let (subscriber, server_overlay, client_overlay) = &overlays[0];
+        server_overlay
+            .update_tuning(|tuning| tuning.answer_cache_max_size = Some(0))
+            .unwrap();
+        assert!(server_overlay
+            .update_tuning(|tuning| tuning.fec_broadcast_wave_len = 0)
+            .is_err());
+        assert_eq!(server_overlay.tuning().answer_cache_max_size, Some(0));
+        client_overlay
+            .adnl_query_typed::<_, proto::adnl::Pong>(
+                &client_adnl,
+                server_key.id(),
+                proto::rpc::AdnlPing { value: 1 },
+                None,
+            )
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(subscriber.calls.load(Ordering::Relaxed), 3);
+    }
+
+    /// Creates overlays on the first query
+    struct LazyOverlays {
+        node: std::sync::Weak<Node>,
+        subscriber: Arc<CacheablePong>,
+        calls: AtomicUsize,
+    }
+
+    #[async_trait::async_trait]
+    impl OverlaySubscriber for LazyOverlays {
+        async fn try_consume_query<'a>(
+            &self,
+            ctx: SubscriberContext<'a>,
+            query_ctx: QueryContext,
+            overlay_id: &IdShort,
+            constructor: u32,
+            query: Cow<'a, [u8]>,
+        ) -> Result<QueryConsumingResult<'a>> {
+            self.calls.fetch_add(1, Ordering::Relaxed);
+            let node = self.node.upgrade().unwrap();
+            node.add_public_overlay(overlay_id, Default::default())?;
+            node.add_overlay_subscriber(*overlay_id, self.subscriber.clone());
+            self.subscriber
+                .try_consume_query_ext(ctx, query_ctx, constructor, query)
+                .await
+        }
+    }
+
+    #[tokio::test]
+    async fn default_subscriber_creates_overlays() {
+        let network = adnl::VirtualNetwork::new(0);
+        let make_node = || {
+            let adnl = add_virtual_node(&network, Default::default());
+            let node = Node::new(adnl.clone(), 0).unwrap();
+            adnl.start().unwrap();
+            (adnl, node)
+        };
+        let (server_adnl, server) = make_node();
+        let (client_adnl, client) = make_node();
+
+        let server_key = server_adnl.key_by_tag(0).unwrap().clone();
+        let client_key = client_adnl.key_by_tag(0).unwrap();
+        client_adnl
+            .add_peer(
+                adnl::NewPeerContext::AdnlPacket,
+                client_key.id(),
+                server_key.id(),
+                server_adnl.socket_addr(),
+                *server_key.full_id(),
+            )
+            .unwrap();
+
+        let default_subscriber = Arc::new(LazyOverlays {
+            node: Arc::downgrade(&server),
+            subscriber: Default::default(),
+            calls: Default::default(),
+        });
+        server.set_default_subscriber(default_subscriber.clone());
+
+        let overlay_id = super::super::IdFull::for_workchain(0, &[1; 32]).compute_short_id();
+        let (client_overlay, _) = client
+            .add_public_overlay(&overlay_id, Default::default())
+            .unwrap();
+        assert!(server.get_overlay(&overlay_id).is_err());
+
+        // The first query creates the overlay, the second one uses its subscriber
+        for value in [1, 2] {
+            let pong = client_overlay
+                .adnl_query_typed::<_, proto::adnl::Pong>(
+                    &client_adnl,
+                    server_key.id(),
+                    proto::rpc::AdnlPing { value },
+                    None,
+                )
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(pong.value, value);
+        }
+        assert!(server.get_overlay(&overlay_id).is_ok());
+        assert_eq!(default_subscriber.calls.load(Ordering::Relaxed), 1);
+        assert_eq!(
+            default_subscriber.subscriber.calls.load(Ordering::Relaxed),
+            2
+        );
+
+        // Messages for unknown overlays are still dropped
+        let unknown_id = super::super::IdFull::for_workchain(0, &[2; 32]).compute_short_id();
+        let (unknown_overlay, _) = client
+            .add_public_overlay(&unknown_id, Default::default())
+            .unwrap();
+        unknown_overlay
+            .send_message(&client_adnl, server_key.id(), &[1, 2, 3, 4])
+            .unwrap();
+        for _ in 0..100 {
+            if server.unknown_overlay_messages() > 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(server.unknown_overlay_messages(), 1);
+        assert!(server.get_overlay(&unknown_id).is_err());
+    }
+
+    #[cfg(feature = "persistence")]
+    #[tokio::test]
+    async fn peers_files() {
+        let network = adnl::VirtualNetwork::new(0);
+        let dir = std::env::temp_dir().join(format!("overlay-peers-{}", rand::random::<u64>()));
+
+        let overlay_id = super::super::IdFull::for_workchain(0, &[1; 32]).compute_short_id();
+        let other_id = super::super::IdFull::for_workchain(1, &[1; 32]).compute_short_id();
+        let make_node = || {
+            let (_, node, overlay) = add_overlay_node(
+                &network,
+                &overlay_id,
+                Default::default(),
+                Default::default(),
+            );
+            node.add_public_overlay(&other_id, Default::default())
+                .unwrap();
+            (node, overlay)
+        };
+
+        let (left, left_overlay) = make_node();
+        let (right, right_overlay) = make_node();
+        left_overlay
+            .add_public_peer(
+                left.adnl(),
+                right.adnl().socket_addr(),
+                right_overlay.sign_local_node().as_equivalent_ref(),
+            )
+            .unwrap();
+        left.save_peers(&dir).unwrap();
+
+        // Corrupted file is skipped
+        std::fs::write(peers_file_path(&dir, &other_id), [1, 2, 3]).unwrap();
+
+        let (restarted, restarted_overlay) = make_node();
+        assert_eq!(restarted.load_peers(&dir), 1);
+        assert!(restarted_overlay.is_known_peer(right.adnl().key_by_tag(0).unwrap().id()));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn removed_private_overlays_release_pins() {
+        let network = adnl::VirtualNetwork::new(0);
+        let adnl = add_virtual_node(&network, Default::default());
+        let node = Node::new(adnl.clone(), 0).unwrap();
+        let key = adnl.key_by_tag(0).unwrap();
+
+        let peer_id = adnl::NodeIdShort::new([1; 32]);
+        let overlay_ids = [[2; 32], [3; 32]]
+            .map(|id| super::super::IdFull::for_workchain(0, &id).compute_short_id());
+
+        // Explicit pin is kept after all overlays are removed
+        assert!(adnl.pin_peer(&peer_id));
+        for overlay_id in &overlay_ids {
+            node.add_private_overlay(overlay_id, key.clone(), &[peer_id], Default::default())
+                .unwrap();
+        }
+
+        assert!(node.remove_overlay(&overlay_ids[0]));
+        assert!(adnl.is_peer_pinned(&peer_id));
+        assert!(node.remove_overlay(&overlay_ids[1]));
+        assert!(adnl.is_peer_pinned(&peer_id));
+
+        assert!(adnl.unpin_peer(&peer_id));
+        assert!(!adnl.is_peer_pinned(&peer_id));
+    }
 }
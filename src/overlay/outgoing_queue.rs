@@ -0,0 +1,177 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+
+use super::overlay_id::IdShort;
+use crate::adnl;
+
+/// Priority of the outgoing broadcast packets, see [`Overlay::broadcast_ext`]
+///
+/// [`Overlay::broadcast_ext`]: super::Overlay::broadcast_ext
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum BroadcastClass {
+    /// Own broadcasts, always sent before the relayed ones
+    Originated,
+    /// Broadcasts of the other nodes which are forwarded to the neighbours
+    Relayed,
+}
+
+/// Outgoing broadcast packets, originated packets are sent first
+#[derive(Default)]
+pub(super) struct OutgoingQueue {
+    state: Mutex<QueueState>,
+    originated_dropped: AtomicU64,
+    relayed_dropped: AtomicU64,
+}
+
+#[derive(Default)]
+struct QueueState {
+    originated: VecDeque<OutgoingPacket>,
+    relayed: VecDeque<OutgoingPacket>,
+    /// Whether some task is sending the packets
+    draining: bool,
+}
+
+struct OutgoingPacket {
+    local_id: adnl::NodeIdShort,
+    peer_id: adnl::NodeIdShort,
+    data: Arc<[u8]>,
+}
+
+impl OutgoingQueue {
+    /// Number of queued packets of each class
+    pub fn len(&self) -> (usize, usize) {
+        let state = self.state.lock();
+        (state.originated.len(), state.relayed.len())
+    }
+
+    /// Total number of packets of each class which were dropped because the queue was full
+    pub fn dropped(&self) -> (u64, u64) {
+        (
+            self.originated_dropped.load(Ordering::Relaxed),
+            self.relayed_dropped.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Enqueues the packet for each neighbour.
+    ///
+    /// New originated packets are dropped when the queue is full,
+    /// the oldest relayed packets are replaced with the new ones.
+    /// Returns `true` if the caller must start [`OutgoingQueue::drain`]
+    pub fn push(
+        &self,
+        class: BroadcastClass,
+        local_id: &adnl::NodeIdShort,
+        neighbours: &[adnl::NodeIdShort],
+        data: &[u8],
+        capacity: usize,
+    ) -> bool {
+        if neighbours.is_empty() {
+            return false;
+        }
+
+        let data = Arc::<[u8]>::from(data);
+        let mut dropped = 0;
+
+        let mut state = self.state.lock();
+        let queue = match class {
+            BroadcastClass::Originated => &mut state.originated,
+            BroadcastClass::Relayed => &mut state.relayed,
+        };
+        for peer_id in neighbours {
+            if queue.len() >= capacity {
+                dropped += 1;
+                if class == BroadcastClass::Originated {
+                    continue;
+                }
+                queue.pop_front();
+            }
+            queue.push_back(OutgoingPacket {
+                local_id: *local_id,
+                peer_id: *peer_id,
+                data: data.clone(),
+            });
+        }
+        let start = !std::mem::replace(&mut state.draining, true);
+        drop(state);
+
+        if dropped > 0 {
+            let counter = match class {
+                BroadcastClass::Originated => &self.originated_dropped,
+                BroadcastClass::Relayed => &self.relayed_dropped,
+            };
+            counter.fetch_add(dropped, Ordering::Relaxed);
+        }
+        start
+    }
+
+    /// Sends queued packets until the queue is empty
+    pub async fn drain(&self, adnl: &adnl::Node, overlay_id: &IdShort) {
+        while let Some(packet) = self.pop() {
+            match adnl.send_custom_message(&packet.local_id, &packet.peer_id, &packet.data) {
+                Err(e) if adnl.should_log("failed to distribute broadcast") => tracing::warn!(
+                    %overlay_id,
+                    peer_id = %packet.peer_id,
+                    "failed to distribute broadcast: {e}"
+                ),
+                _ => {}
+            }
+
+            // Let the new packets be enqueued in between
+            tokio::task::yield_now().await;
+        }
+    }
+
+    fn pop(&self) -> Option<OutgoingPacket> {
+        let mut state = self.state.lock();
+        let packet = match state.originated.pop_front() {
+            Some(packet) => Some(packet),
+            None => state.relayed.pop_front(),
+        };
+        if packet.is_none() {
+            state.draining = false;
+        }
+        packet
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn originated_packets_go_first() {
+        let queue = OutgoingQueue::default();
+        let local_id = adnl::NodeIdShort::new(rand::random());
+        let peers = [0; 4].map(|_| adnl::NodeIdShort::new(rand::random()));
+
+        // Only the latest relayed packets are kept
+        assert!(queue.push(BroadcastClass::Relayed, &local_id, &peers, &[1], 3));
+        assert!(!queue.push(BroadcastClass::Relayed, &local_id, &peers[..1], &[2], 3));
+        assert_eq!(queue.len(), (0, 3));
+
+        // New originated packets are dropped
+        queue.push(BroadcastClass::Originated, &local_id, &peers, &[3], 2);
+        assert_eq!(queue.len(), (2, 3));
+        assert_eq!(queue.dropped(), (2, 2));
+
+        let order = std::iter::from_fn(|| queue.pop())
+            .map(|packet| (packet.peer_id, packet.data[0]))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            order,
+            [
+                (peers[0], 3),
+                (peers[1], 3),
+                (peers[2], 1),
+                (peers[3], 1),
+                (peers[0], 2)
+            ]
+        );
+
+        // Next push must restart the sender
+        assert!(queue.push(BroadcastClass::Relayed, &local_id, &peers, &[4], 3));
+    }
+}
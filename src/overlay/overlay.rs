@@ -1,25 +1,39 @@
 use std::convert::TryFrom;
 use std::net::SocketAddrV4;
-use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use anyhow::Result;
+use arc_swap::ArcSwap;
 use crossbeam_queue::SegQueue;
 use parking_lot::Mutex;
 use sha2::Digest;
 use smallvec::SmallVec;
-use tl_proto::{HashWrapper, TlWrite};
-use tokio::sync::mpsc;
+use tl_proto::{BoxedConstructor, BoxedWrapper, HashWrapper, TlRead, TlWrite};
+use tokio::runtime::Handle;
+use tokio::sync::{mpsc, Semaphore};
+use tracing::Instrument;
 
+use super::outgoing_queue::*;
 use super::overlay_id::IdShort;
-use super::{broadcast_receiver::*, MAX_OVERLAY_PEERS};
+use super::peer_scores::weighted_shuffle;
+use super::peer_validation::*;
+use super::{
+    broadcast_handlers::*, broadcast_history::*, broadcast_receiver::*, storm_throttle::*,
+    MAX_OVERLAY_PEERS,
+};
 use crate::adnl;
+use crate::events::EventsSender;
 use crate::proto;
 use crate::rldp::{self, compression, RaptorQDecoder, RaptorQEncoder};
 use crate::util::*;
+use crate::NetworkEvent;
 
-/// Overlay configuration
+/// Overlay configuration.
+///
+/// Only [`OverlayOptions::tuning`] can be changed at runtime
+/// with [`Overlay::update_tuning`].
 #[derive(Debug, Copy, Clone, serde::Serialize, serde::Deserialize)]
 #[serde(default)]
 pub struct OverlayOptions {
@@ -28,6 +42,180 @@ pub struct OverlayOptions {
     /// Default: `200`
     pub max_neighbours: u32,
 
+    /// Max number of FEC broadcasts which are decoded simultaneously
+    /// on the blocking threads.
+    ///
+    /// Default: `2`
+    pub decode_threads: usize,
+
+    /// Max number of received broadcasts waiting for [`Overlay::recv_broadcast`].
+    /// The oldest broadcast is dropped when the queue is full.
+    /// `None` keeps all of them until they are consumed.
+    ///
+    /// Default: `Some(1024)`
+    pub received_broadcasts_capacity: Option<usize>,
+
+    /// Max number of the recent verified ordinary broadcasts which are kept
+    /// to be served to the peers which missed them (see [`Overlay::fetch_missed_broadcasts`]).
+    /// `0` disables the retention.
+    ///
+    /// NOTE: FEC broadcasts are not retained
+    ///
+    /// Default: `0`
+    pub broadcast_history: usize,
+
+    /// Max total size of the retained broadcasts in bytes.
+    ///
+    /// Default: `1048576` (1 MB)
+    pub broadcast_history_max_bytes: usize,
+
+    /// Runtime-tunable part of the configuration
+    #[serde(flatten)]
+    pub tuning: OverlayTuning,
+}
+
+impl OverlayOptions {
+    /// Public overlay with many unknown peers, same as [`OverlayOptions::default`].
+    ///
+    /// Broadcasts are forwarded by every receiver, so a moderate fan-out
+    /// is enough to reach the whole overlay without flooding it.
+    pub fn public_default() -> Self {
+        Self::default()
+    }
+
+    /// Private overlay of a small set of known and trusted peers (e.g. validators).
+    ///
+    /// All members are usually neighbours of each other, so broadcasts need
+    /// a smaller fan-out. Answers are not cached because the queries between
+    /// validators are rarely repeated and must always reflect the current state.
+    /// Recent broadcasts are retained for the members which reconnect.
+    /// Broadcasts are always signed and verified, there is nothing to enable for that.
+    pub fn private_validator() -> Self {
+        Self {
+            broadcast_history: 256,
+            tuning: OverlayTuning {
+                broadcast_target_count: 3,
+                secondary_broadcast_target_count: 2,
+                secondary_fec_broadcast_target_count: 2,
+                answer_cache_max_size: Some(0),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    /// Gateway which relays a lot of large broadcasts from untrusted sources.
+    ///
+    /// More broadcasts are remembered to drop the duplicates which arrive late,
+    /// more FEC broadcasts are decoded in parallel, and sources which exceed
+    /// the rate limit are muted sooner and for longer.
+    pub fn gateway_high_throughput() -> Self {
+        Self {
+            decode_threads: 8,
+            tuning: OverlayTuning {
+                max_broadcast_log: 10000,
+                broadcast_storm_threshold: 500,
+                storm_cooldown_ms: 120000,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    /// Overrides [`OverlayOptions::max_neighbours`]
+    pub fn with_max_neighbours(mut self, max_neighbours: u32) -> Self {
+        self.max_neighbours = max_neighbours;
+        self
+    }
+
+    /// Overrides [`OverlayOptions::decode_threads`]
+    pub fn with_decode_threads(mut self, decode_threads: usize) -> Self {
+        self.decode_threads = decode_threads;
+        self
+    }
+
+    /// Overrides [`OverlayTuning::max_broadcast_log`]
+    pub fn with_max_broadcast_log(mut self, max_broadcast_log: u32) -> Self {
+        self.tuning.max_broadcast_log = max_broadcast_log;
+        self
+    }
+
+    /// Overrides [`OverlayTuning::broadcast_target_count`]
+    pub fn with_broadcast_target_count(mut self, broadcast_target_count: u32) -> Self {
+        self.tuning.broadcast_target_count = broadcast_target_count;
+        self
+    }
+
+    /// Overrides [`OverlayTuning::answer_cache_max_size`]
+    pub fn with_answer_cache_max_size(mut self, answer_cache_max_size: Option<usize>) -> Self {
+        self.tuning.answer_cache_max_size = answer_cache_max_size;
+        self
+    }
+
+    /// Overrides [`OverlayTuning::broadcast_storm_threshold`]
+    /// and [`OverlayTuning::storm_cooldown_ms`]
+    pub fn with_storm_throttling(mut self, threshold_per_sec: u32, cooldown_ms: u64) -> Self {
+        self.tuning.broadcast_storm_threshold = threshold_per_sec;
+        self.tuning.storm_cooldown_ms = cooldown_ms;
+        self
+    }
+
+    /// Overrides [`OverlayOptions::received_broadcasts_capacity`]
+    pub fn with_received_broadcasts_capacity(mut self, capacity: Option<usize>) -> Self {
+        self.received_broadcasts_capacity = capacity;
+        self
+    }
+
+    /// Overrides [`OverlayOptions::broadcast_history`]
+    /// and [`OverlayOptions::broadcast_history_max_bytes`]
+    pub fn with_broadcast_history(mut self, max_len: usize, max_bytes: usize) -> Self {
+        self.broadcast_history = max_len;
+        self.broadcast_history_max_bytes = max_bytes;
+        self
+    }
+
+    /// Overrides the whole runtime-tunable part
+    pub fn with_tuning(mut self, tuning: OverlayTuning) -> Self {
+        self.tuning = tuning;
+        self
+    }
+
+    /// Checks that the values are consistent
+    pub fn validate(&self) -> Result<()> {
+        if self.max_neighbours == 0 || self.max_neighbours > MAX_OVERLAY_PEERS {
+            return Err(OverlayOptionsError::InvalidMaxNeighbours(self.max_neighbours).into());
+        }
+        if self.decode_threads == 0 {
+            return Err(OverlayOptionsError::ZeroValue("decode_threads").into());
+        }
+        if self.received_broadcasts_capacity == Some(0) {
+            return Err(OverlayOptionsError::ZeroValue("received_broadcasts_capacity").into());
+        }
+        if self.broadcast_history > 0 && self.broadcast_history_max_bytes == 0 {
+            return Err(OverlayOptionsError::ZeroValue("broadcast_history_max_bytes").into());
+        }
+        self.tuning.validate()
+    }
+}
+
+impl Default for OverlayOptions {
+    fn default() -> Self {
+        Self {
+            max_neighbours: 200,
+            decode_threads: 2,
+            received_broadcasts_capacity: Some(1024),
+            broadcast_history: 0,
+            broadcast_history_max_bytes: 1 << 20,
+            tuning: Default::default(),
+        }
+    }
+}
+
+/// Overlay configuration which can be changed at runtime
+/// with [`Overlay::update_tuning`].
+#[derive(Debug, Copy, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct OverlayTuning {
     /// Max simultaneous broadcasts.
     ///
     /// Default: `1000`
@@ -74,21 +262,147 @@ pub struct OverlayOptions {
     /// Default: `10` ms
     pub fec_broadcast_wave_interval_ms: u64,
 
+    /// Own broadcast packets are sent to the neighbours in random order, spread over
+    /// this interval to avoid bursts. FEC broadcasts are spread within each wave.
+    /// `0` sends to all neighbours back-to-back.
+    ///
+    /// Default: `5` ms
+    pub broadcast_spread_duration_ms: u64,
+
     /// Overlay broadcast timeout. It will be forcefully dropped if not received in this time.
     ///
     /// Default: `60` sec
     pub broadcast_timeout_sec: u64,
 
+    /// Incoming FEC broadcast which received no parts during this time is dropped
+    /// with its pending parts. The broadcast id is kept, so the late parts are ignored.
+    /// Checked every `broadcast_gc_interval_ms`.
+    ///
+    /// Default: `60000` ms
+    pub fec_transfer_timeout_ms: u64,
+
     /// Whether requests will be compressed.
     ///
     /// Default: `false`
     pub force_compression: bool,
+
+    /// Number of new incoming broadcasts per second after which
+    /// [`NetworkEvent::BroadcastStorm`] is emitted. Broadcasts of the single source
+    /// which exceeds it are no longer forwarded to the neighbours (own broadcasts
+    /// are never throttled). `0` disables the detection.
+    ///
+    /// Default: `1000`
+    pub broadcast_storm_threshold: u32,
+
+    /// How long the broadcasts of the source are not forwarded after the storm detection.
+    ///
+    /// Default: `30000` ms
+    pub storm_cooldown_ms: u64,
+
+    /// Max total size of the cached query answers,
+    /// see [`QueryConsumingResult::Cacheable`](crate::QueryConsumingResult::Cacheable).
+    /// `0` disables the cache.
+    ///
+    /// Default: `None` (`1` MB for public overlays, disabled for private)
+    pub answer_cache_max_size: Option<usize>,
+
+    /// Cached answers are dropped after this interval regardless of the TTL hint.
+    ///
+    /// Default: `10000` ms
+    pub answer_cache_max_ttl_ms: u64,
+
+    /// Imported or external peers which were signed earlier than this interval ago
+    /// are skipped, see [`Overlay::import_peers_tl`] and [`Overlay::add_external_peers`].
+    /// `0` disables the check.
+    ///
+    /// Default: `604800` seconds (one week)
+    pub stored_peer_ttl_sec: u32,
+
+    /// How long the previous key is still used by the in-flight outgoing broadcasts
+    /// and recognized as local after [`Overlay::rotate_key`].
+    ///
+    /// Default: `60000` ms
+    pub key_rotation_grace_period_ms: u64,
+
+    /// Public peers which sent corrupted broadcasts are removed if their reputation
+    /// score is not greater than this value, see [`Overlay::peer_score`].
+    /// Such peers are also rejected when added again.
+    ///
+    /// Default: `-50`
+    pub ban_reputation_score: i32,
+
+    /// Whether the own signed node is included into the answers to `overlay.getRandomPeers`
+    /// (that's how the querying peers learn about us). Own queries always include it.
+    ///
+    /// Default: `None` (included for public overlays, not included for private)
+    pub include_self_in_random_peers: Option<bool>,
+
+    /// Max number of own broadcast packets waiting to be sent (one for each neighbour).
+    /// They are sent before the relayed ones, new packets are dropped when the queue is full.
+    ///
+    /// Default: `4096`
+    pub originated_queue_capacity: usize,
+
+    /// Max number of relayed broadcast packets waiting to be sent (one for each neighbour).
+    /// The oldest packets are dropped when the queue is full.
+    ///
+    /// Default: `4096`
+    pub relayed_queue_capacity: usize,
+
+    /// Number of active public peers below which [`NetworkEvent::OverlayPeersLow`]
+    /// is emitted when a peer is removed. `0` disables the event.
+    ///
+    /// Default: `0`
+    pub low_peers_threshold: usize,
 }
 
-impl Default for OverlayOptions {
+impl OverlayTuning {
+    /// Checks that the values are consistent
+    pub fn validate(&self) -> Result<()> {
+        if self.max_broadcast_log == 0 {
+            return Err(OverlayOptionsError::ZeroValue("max_broadcast_log").into());
+        }
+        if self.broadcast_gc_interval_ms == 0 {
+            return Err(OverlayOptionsError::ZeroValue("broadcast_gc_interval_ms").into());
+        }
+        if self.overlay_peers_timeout_ms < self.broadcast_gc_interval_ms {
+            return Err(OverlayOptionsError::PeersTimeoutTooSmall {
+                timeout: self.overlay_peers_timeout_ms,
+                gc_interval: self.broadcast_gc_interval_ms,
+            }
+            .into());
+        }
+        if self.max_ordinary_broadcast_len < rldp::MAX_TRANSMISSION_UNIT as usize {
+            return Err(OverlayOptionsError::OrdinaryBroadcastTooSmall(
+                self.max_ordinary_broadcast_len,
+            )
+            .into());
+        }
+        if self.fec_broadcast_wave_len == 0 {
+            return Err(OverlayOptionsError::ZeroValue("fec_broadcast_wave_len").into());
+        }
+        if self.broadcast_timeout_sec == 0 {
+            return Err(OverlayOptionsError::ZeroValue("broadcast_timeout_sec").into());
+        }
+        if self.fec_transfer_timeout_ms == 0 {
+            return Err(OverlayOptionsError::ZeroValue("fec_transfer_timeout_ms").into());
+        }
+        if self.originated_queue_capacity == 0 {
+            return Err(OverlayOptionsError::ZeroValue("originated_queue_capacity").into());
+        }
+        if self.relayed_queue_capacity == 0 {
+            return Err(OverlayOptionsError::ZeroValue("relayed_queue_capacity").into());
+        }
+        if self.broadcast_storm_threshold > 0 && self.storm_cooldown_ms == 0 {
+            return Err(OverlayOptionsError::ZeroValue("storm_cooldown_ms").into());
+        }
+        Ok(())
+    }
+}
+
+impl Default for OverlayTuning {
     fn default() -> Self {
         Self {
-            max_neighbours: 200,
             max_broadcast_log: 1000,
             broadcast_gc_interval_ms: 1000,
             overlay_peers_timeout_ms: 60000,
@@ -98,20 +412,99 @@ impl Default for OverlayOptions {
             secondary_fec_broadcast_target_count: 3,
             fec_broadcast_wave_len: 20,
             fec_broadcast_wave_interval_ms: 10,
+            broadcast_spread_duration_ms: 5,
             broadcast_timeout_sec: 60,
+            fec_transfer_timeout_ms: 60000,
             force_compression: false,
+            broadcast_storm_threshold: 1000,
+            storm_cooldown_ms: 30000,
+            answer_cache_max_size: None,
+            answer_cache_max_ttl_ms: 10000,
+            stored_peer_ttl_sec: 604800,
+            key_rotation_grace_period_ms: 60000,
+            ban_reputation_score: -50,
+            include_self_in_random_peers: None,
+            originated_queue_capacity: 4096,
+            relayed_queue_capacity: 4096,
+            low_peers_threshold: 0,
+        }
+    }
+}
+
+/// Transport of the [`Overlay::query_any`] queries
+#[derive(Copy, Clone)]
+pub enum OverlayQueryTransport<'a> {
+    Adnl(&'a adnl::Node),
+    Rldp(&'a rldp::Node),
+}
+
+/// Peers selection and answers verification for [`Overlay::query_any`]
+#[derive(Clone)]
+pub struct QueryAnyOptions {
+    /// Max number of peers to query.
+    ///
+    /// Default: `5`
+    pub attempts: u32,
+
+    /// Time to wait for the answer from each peer.
+    ///
+    /// Default: `1000` ms
+    pub per_attempt_timeout_ms: u64,
+
+    /// Max number of peers which are queried at the same time.
+    ///
+    /// Default: `1`
+    pub parallelism: u32,
+
+    /// Checks the raw answer of each peer. Rejected answers penalize the peer
+    /// and the next one is queried.
+    ///
+    /// Default: None
+    pub verify: Option<AnswerVerifier>,
+}
+
+impl Default for QueryAnyOptions {
+    fn default() -> Self {
+        Self {
+            attempts: 5,
+            per_attempt_timeout_ms: 1000,
+            parallelism: 1,
+            verify: None,
         }
     }
 }
 
+impl std::fmt::Debug for QueryAnyOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("QueryAnyOptions")
+            .field("attempts", &self.attempts)
+            .field("per_attempt_timeout_ms", &self.per_attempt_timeout_ms)
+            .field("parallelism", &self.parallelism)
+            .field("verify", &self.verify.is_some())
+            .finish()
+    }
+}
+
 /// P2P messages distribution layer
 pub struct Overlay {
     /// Unique overlay id
     id: IdShort,
-    /// Local ADNL key
-    node_key: Arc<adnl::Key>,
-    // Configuration
-    options: OverlayOptions,
+    /// Local ADNL key, see [`Overlay::rotate_key`]
+    node_key: ArcSwap<adnl::Key>,
+    /// Local key before the last rotation
+    previous_key: Mutex<Option<RotatedKey>>,
+    /// More persistent list of peers size
+    max_neighbours: u32,
+    /// Runtime configuration
+    tuning: ArcSwap<OverlayTuning>,
+    /// Time source (shared with ADNL node)
+    clock: Arc<dyn Clock>,
+    /// Runtime of the background tasks (shared with ADNL node)
+    runtime: Handle,
+    /// Background tasks of the overlay node
+    layer: Arc<adnl::DependentLayer>,
+    /// Limits the number of FEC broadcasts being decoded on the blocking threads
+    decode_permits: Arc<Semaphore>,
 
     /// Broadcasts in progress
     owned_broadcasts: FastDashMap<BroadcastId, Arc<OwnedBroadcast>>,
@@ -119,11 +512,43 @@ pub struct Overlay {
     finished_broadcasts: SegQueue<BroadcastId>,
     /// Broadcasts removal queue len
     finished_broadcast_count: AtomicU32,
+    /// Number of dropped messages without consumer
+    unhandled_messages: AtomicU64,
+    /// New incoming broadcasts during the current second
+    broadcast_rate: Mutex<BroadcastRate>,
+    /// New incoming broadcasts rates of each source
+    storm_throttle: Mutex<StormThrottle>,
+    /// Broadcasts which were not forwarded because of the storm
+    suppressed_forwards: AtomicU64,
+    /// Broadcasts which data didn't match the signature or the declared hash
+    broadcasts_corrupted: AtomicU64,
+    /// Ordinary broadcasts which were received again
+    broadcasts_duplicated: AtomicU64,
+    /// Own broadcasts which were forwarded back by the neighbours
+    own_broadcast_echoes: AtomicU64,
+    /// Incoming FEC broadcasts which were dropped by the timeout
+    fec_transfers_expired: AtomicU64,
+    /// Own broadcast packets sent to the neighbours
+    outgoing_packets: AtomicU64,
+    /// Own broadcast packets sent during the current and the previous second
+    outgoing_rate: Mutex<PacketRate>,
+    /// Broadcast packets waiting to be sent
+    outgoing_queue: Arc<OutgoingQueue>,
+    /// Whether the overlay was created with the explicit peers list
+    is_private: bool,
+    /// Answers for the cacheable queries
+    answer_cache: Mutex<AnswerCache>,
+    /// Queries answered from the cache
+    answer_cache_hits: AtomicU64,
 
     /// New peers to add
     received_peers: Arc<Mutex<ReceivedPeersMap>>,
     /// Complete incoming broadcasts queue
-    received_broadcasts: Arc<BroadcastReceiver<IncomingBroadcastInfo>>,
+    received_broadcasts: BroadcastReceiver<IncomingBroadcastInfo>,
+    /// Typed handlers of the incoming broadcasts
+    broadcast_handlers: BroadcastHandlers,
+    /// Recent broadcasts for the peers which missed them
+    broadcast_history: Mutex<BroadcastHistory>,
 
     /// Raw overlay nodes
     nodes: FastDashMap<adnl::NodeIdShort, proto::overlay::NodeOwned>,
@@ -133,6 +558,10 @@ pub struct Overlay {
     known_peers: adnl::PeersSet,
     /// Random peers subset
     neighbours: adnl::PeersSet,
+    /// Peer scores of the ADNL node, see [`Overlay::peer_score`]
+    reputation: adnl::SharedReputation,
+    /// Events channel of the ADNL node
+    events: Arc<EventsSender>,
 
     /// Serialized [`proto::rpc::OverlayQuery`] with own overlay id
     query_prefix: Vec<u8>,
@@ -146,7 +575,10 @@ impl Overlay {
         node_key: Arc<adnl::Key>,
         id: IdShort,
         peers: &[adnl::NodeIdShort],
+        is_private: bool,
         options: OverlayOptions,
+        adnl: &adnl::Node,
+        layer: Arc<adnl::DependentLayer>,
     ) -> Arc<Self> {
         let query_prefix = tl_proto::serialize(proto::rpc::OverlayQuery {
             overlay: id.as_slice(),
@@ -159,62 +591,129 @@ impl Overlay {
 
         let overlay = Arc::new(Self {
             id,
-            node_key,
-            options,
+            node_key: ArcSwap::new(node_key),
+            previous_key: Default::default(),
+            max_neighbours: options.max_neighbours,
+            tuning: ArcSwap::from_pointee(options.tuning),
+            clock: adnl.clock().clone(),
+            runtime: adnl.runtime().clone(),
+            layer,
+            decode_permits: Arc::new(Semaphore::new(options.decode_threads)),
             owned_broadcasts: FastDashMap::default(),
             finished_broadcasts: SegQueue::new(),
             finished_broadcast_count: AtomicU32::new(0),
+            unhandled_messages: AtomicU64::new(0),
+            broadcast_rate: Default::default(),
+            storm_throttle: Default::default(),
+            suppressed_forwards: AtomicU64::new(0),
+            broadcasts_corrupted: AtomicU64::new(0),
+            broadcasts_duplicated: AtomicU64::new(0),
+            own_broadcast_echoes: AtomicU64::new(0),
+            fec_transfers_expired: AtomicU64::new(0),
+            outgoing_packets: AtomicU64::new(0),
+            outgoing_rate: Default::default(),
+            outgoing_queue: Default::default(),
+            is_private,
+            answer_cache: Default::default(),
+            answer_cache_hits: AtomicU64::new(0),
             received_peers: Arc::new(Default::default()),
-            received_broadcasts: Arc::new(BroadcastReceiver::default()),
+            received_broadcasts: BroadcastReceiver::new(options.received_broadcasts_capacity),
+            broadcast_handlers: Default::default(),
+            broadcast_history: Mutex::new(BroadcastHistory::new(
+                options.broadcast_history,
+                options.broadcast_history_max_bytes,
+            )),
             nodes: FastDashMap::default(),
             ignored_peers: FastDashSet::default(),
             known_peers,
             neighbours: adnl::PeersSet::with_capacity(options.max_neighbours),
+            reputation: adnl.shared_reputation().clone(),
+            events: adnl.events_sender().clone(),
             query_prefix,
             message_prefix,
         });
 
         if !peers.is_empty() {
-            overlay.update_neighbours(overlay.options.max_neighbours);
+            overlay.update_neighbours(overlay.max_neighbours);
         }
 
         let overlay_ref = Arc::downgrade(&overlay);
-        let gc_interval = Duration::from_millis(options.broadcast_gc_interval_ms);
-        tokio::spawn(async move {
-            let mut peers_timeout = 0;
-            while let Some(overlay) = overlay_ref.upgrade() {
-                while overlay.finished_broadcast_count.load(Ordering::Acquire)
-                    > options.max_broadcast_log
-                {
-                    if let Some(broadcast_id) = overlay.finished_broadcasts.pop() {
-                        overlay.owned_broadcasts.remove(&broadcast_id);
+        overlay
+            .layer
+            .tasks()
+            .spawn(&overlay.runtime, "overlay_peers_gc", async move {
+                let mut peers_timeout = 0;
+                while let Some(overlay) = overlay_ref.upgrade() {
+                    let options = overlay.tuning();
+                    while overlay.finished_broadcast_count.load(Ordering::Acquire)
+                        > options.max_broadcast_log
+                    {
+                        if let Some(broadcast_id) = overlay.finished_broadcasts.pop() {
+                            overlay.owned_broadcasts.remove(&broadcast_id);
+                        }
+                        overlay
+                            .finished_broadcast_count
+                            .fetch_sub(1, Ordering::Release);
                     }
-                    overlay
-                        .finished_broadcast_count
-                        .fetch_sub(1, Ordering::Release);
-                }
 
-                peers_timeout += options.broadcast_gc_interval_ms;
-                if peers_timeout > options.overlay_peers_timeout_ms {
-                    overlay.update_neighbours(1);
-                    peers_timeout = 0;
-                }
+                    overlay.storm_throttle.lock().shrink(overlay.clock.now_ms());
+                    overlay.drop_expired_fec_transfers(options.fec_transfer_timeout_ms);
 
-                tokio::time::sleep(gc_interval).await;
-            }
-        });
+                    peers_timeout += options.broadcast_gc_interval_ms;
+                    if peers_timeout > options.overlay_peers_timeout_ms {
+                        overlay.update_neighbours(1);
+                        peers_timeout = 0;
+                    }
+
+                    let gc_interval = Duration::from_millis(options.broadcast_gc_interval_ms);
+                    overlay.clock.sleep(gc_interval).await;
+                }
+            });
 
         overlay
     }
 
-    /// Configuration
-    #[inline(always)]
-    pub fn options(&self) -> &OverlayOptions {
-        &self.options
+    /// Runtime configuration
+    pub fn tuning(&self) -> Arc<OverlayTuning> {
+        self.tuning.load_full()
+    }
+
+    /// Changes configuration at runtime. New values are used for all subsequent broadcasts.
+    ///
+    /// Returns an error and leaves the configuration unchanged
+    /// if the new values are inconsistent (see [`OverlayTuning::validate`]).
+    ///
+    /// NOTE: `f` is applied again if the configuration was changed concurrently
+    pub fn update_tuning<F>(&self, mut f: F) -> Result<()>
+    where
+        F: FnMut(&mut OverlayTuning),
+    {
+        let mut result = Ok(());
+        self.tuning.rcu(|current| {
+            let mut tuning = **current;
+            f(&mut tuning);
+            result = tuning.validate();
+            match result {
+                Ok(()) => Arc::new(tuning),
+                Err(_) => current.clone(),
+            }
+        });
+        result
     }
 
     /// Instant metrics
     pub fn metrics(&self) -> OverlayMetrics {
+        let (answer_cache_len, answer_cache_size) = {
+            let cache = self.answer_cache.lock();
+            (cache.len(), cache.total_size())
+        };
+        let (broadcast_history_len, broadcast_history_size) = {
+            let history = self.broadcast_history.lock();
+            (history.len(), history.total_size())
+        };
+        let (originated_queue_len, relayed_queue_len) = self.outgoing_queue.len();
+        let (originated_packets_dropped, relayed_packets_dropped) = self.outgoing_queue.dropped();
+
         OverlayMetrics {
             owned_broadcasts_len: self.owned_broadcasts.len(),
             finished_broadcasts_len: self.finished_broadcast_count.load(Ordering::Acquire),
@@ -222,7 +721,45 @@ impl Overlay {
             known_peers: self.known_peers.len(),
             neighbours: self.neighbours.len(),
             received_broadcasts_data_len: self.received_broadcasts.data_len(),
-            received_broadcasts_barrier_count: self.received_broadcasts.barriers_len(),
+            received_broadcasts_barrier_count: self.received_broadcasts.waiters_len(),
+            received_broadcasts_capacity: self.received_broadcasts.capacity(),
+            received_broadcasts_dropped: self.received_broadcasts.dropped(),
+            oldest_received_broadcast_age_ms: self
+                .received_broadcasts
+                .oldest_enqueued_at_ms()
+                .map(|at| self.clock.now_ms().saturating_sub(at)),
+            unhandled_messages: self.unhandled_messages.load(Ordering::Relaxed),
+            received_peers_len: self.received_peers.lock().len(),
+            ignored_peers_len: self.ignored_peers.len(),
+            outgoing_broadcast_packets: self.outgoing_packets.load(Ordering::Relaxed),
+            outgoing_broadcast_rate: self.outgoing_rate.lock().last_second(self.clock.now()),
+            answer_cache_len,
+            answer_cache_size,
+            answer_cache_hits: self.answer_cache_hits.load(Ordering::Relaxed),
+            broadcast_history_len,
+            broadcast_history_size,
+            throttled_broadcast_sources: self
+                .storm_throttle
+                .lock()
+                .throttled_len(self.clock.now_ms()),
+            suppressed_broadcast_forwards: self.suppressed_forwards.load(Ordering::Relaxed),
+            broadcast_decode_errors: self.broadcast_handlers.total_decode_errors(),
+            broadcasts_corrupted: self.broadcasts_corrupted.load(Ordering::Relaxed),
+            broadcasts_duplicated: self.broadcasts_duplicated.load(Ordering::Relaxed),
+            own_broadcast_echoes: self.own_broadcast_echoes.load(Ordering::Relaxed),
+            incoming_fec_transfers: self
+                .owned_broadcasts
+                .iter()
+                .filter(|item| {
+                    matches!(item.value().as_ref(), OwnedBroadcast::Incoming(transfer)
+                        if !transfer.completed.load(Ordering::Acquire))
+                })
+                .count(),
+            fec_transfers_expired: self.fec_transfers_expired.load(Ordering::Relaxed),
+            originated_queue_len,
+            relayed_queue_len,
+            originated_packets_dropped,
+            relayed_packets_dropped,
         }
     }
 
@@ -232,8 +769,91 @@ impl Overlay {
     }
 
     /// Returns local ADNL key for public overlay
-    pub fn overlay_key(&self) -> &Arc<adnl::Key> {
-        &self.node_key
+    pub fn overlay_key(&self) -> Arc<adnl::Key> {
+        self.node_key.load_full()
+    }
+
+    fn local_id(&self) -> adnl::NodeIdShort {
+        *self.node_key.load().id()
+    }
+
+    /// Replaces the local key for the new broadcasts and queries.
+    ///
+    /// Outgoing broadcasts which are already in progress are sent with the previous key
+    /// during [`OverlayTuning::key_rotation_grace_period_ms`], own broadcasts and nodes
+    /// signed with any of two keys are recognized as local during this period.
+    /// Emits [`NetworkEvent::OverlayKeyRotated`].
+    ///
+    /// NOTE: The new key must be in the ADNL keystore. Known public peers and
+    /// members of the private overlay are added for the new key with the same addresses
+    /// (members stay pinned, see [`adnl::Node::pin_peer`])
+    pub fn rotate_key(&self, adnl: &adnl::Node, new_key: Arc<adnl::Key>) -> Result<()> {
+        let new_id = *new_key.id();
+        adnl.key_by_id(&new_id)?;
+
+        let grace_period_ms = self.tuning.load().key_rotation_grace_period_ms;
+        let old_key = {
+            let mut previous_key = self.previous_key.lock();
+            let old_key = self.node_key.load_full();
+            if old_key.id() == &new_id {
+                return Ok(());
+            }
+            self.node_key.store(new_key);
+            *previous_key = Some(RotatedKey {
+                key: old_key.clone(),
+                trusted_until: self.clock.instant() + Duration::from_millis(grace_period_ms),
+            });
+            old_key
+        };
+
+        if self.is_private {
+            for peer_id in self.known_peers.iter() {
+                adnl.copy_peer(old_key.id(), &new_id, peer_id)?;
+            }
+        }
+
+        for item in self.nodes.iter() {
+            let peer_id = item.key();
+            let addr = match adnl.get_peer_address(old_key.id(), peer_id) {
+                Some(addr) => addr,
+                None => continue,
+            };
+            let peer_id_full = match adnl::NodeIdFull::try_from(item.value().as_equivalent_ref().id)
+            {
+                Ok(peer_id_full) => peer_id_full,
+                Err(_) => continue,
+            };
+            adnl.add_peer(
+                adnl::NewPeerContext::PublicOverlay,
+                &new_id,
+                peer_id,
+                addr,
+                peer_id_full,
+            )?;
+        }
+
+        tracing::info!(overlay_id = %self.id, old_id = %old_key.id(), %new_id, "overlay key rotated");
+        adnl.emit_event(|timestamp_ms| NetworkEvent::OverlayKeyRotated {
+            overlay_id: self.id,
+            old_id: *old_key.id(),
+            new_id,
+            grace_period_ms,
+            timestamp_ms,
+        });
+        Ok(())
+    }
+
+    /// Whether the id belongs to the current local key or to the previous one
+    /// during the grace period
+    fn is_local_id(&self, id: &adnl::NodeIdShort) -> bool {
+        if self.node_key.load().id() == id {
+            return true;
+        }
+        matches!(
+            &*self.previous_key.lock(),
+            Some(previous) if previous.key.id() == id
+                && self.clock.instant() < previous.trusted_until
+        )
     }
 
     /// Verifies and adds new peer to the overlay. Returns `Some` short peer id
@@ -246,12 +866,25 @@ impl Overlay {
         addr: SocketAddrV4,
         node: proto::overlay::Node<'_>,
     ) -> Result<Option<adnl::NodeIdShort>> {
-        if let Err(e) = self.id.verify_overlay_node(&node) {
-            tracing::warn!(overlay_id = %self.id, %addr, "invalid public overlay node: {e:?}");
-            return Ok(None);
-        }
+        self.add_public_peer_impl(adnl, addr, node, false)
+    }
 
-        let peer_id_full = adnl::NodeIdFull::try_from(node.id)?;
+    fn add_public_peer_impl(
+        &self,
+        adnl: &adnl::Node,
+        addr: SocketAddrV4,
+        node: proto::overlay::Node<'_>,
+        check_freshness: bool,
+    ) -> Result<Option<adnl::NodeIdShort>> {
+        let peer_id_full = match self.validate_peer(addr, &node, check_freshness) {
+            Ok(peer_id_full) => peer_id_full,
+            Err(reason) => {
+                if adnl.should_log("invalid public overlay node") {
+                    tracing::warn!(overlay_id = %self.id, %addr, ?reason, "invalid public overlay node");
+                }
+                return Ok(None);
+            }
+        };
         let peer_id = peer_id_full.compute_short_id();
 
         let is_new_peer = adnl.add_peer(
@@ -280,16 +913,19 @@ impl Overlay {
     where
         I: IntoIterator<Item = (SocketAddrV4, proto::overlay::Node<'a>)>,
     {
-        let local_id = self.overlay_key().id();
+        let local_id = &self.local_id();
 
         let mut result = Vec::new();
         for (addr, node) in nodes {
-            if let Err(e) = self.id.verify_overlay_node(&node) {
-                tracing::warn!(overlay_id = %self.id, %addr, "invalid public overlay node: {e:?}");
-                continue;
-            }
-
-            let peer_id_full = adnl::NodeIdFull::try_from(node.id)?;
+            let peer_id_full = match self.validate_peer(addr, &node, false) {
+                Ok(peer_id_full) => peer_id_full,
+                Err(reason) => {
+                    if adnl.should_log("invalid public overlay node") {
+                        tracing::warn!(overlay_id = %self.id, %addr, ?reason, "invalid public overlay node");
+                    }
+                    continue;
+                }
+            };
             let peer_id = peer_id_full.compute_short_id();
 
             let is_new_peer = adnl.add_peer(
@@ -309,18 +945,160 @@ impl Overlay {
         Ok(result)
     }
 
-    /// Removes peer from random peers and adds it to ignored peers
+    /// Verifies and adds peers from an external discovery service (e.g. some registry).
+    ///
+    /// Each entry passes the same checks as the peers received from the random peers
+    /// exchange, plus the declared ADNL id and the address are checked.
+    /// Returns the status of each entry.
+    pub fn add_external_peers(
+        &self,
+        adnl: &adnl::Node,
+        peers: Vec<ExternalPeer<'_>>,
+    ) -> Result<ExternalPeersReport> {
+        let local_id = &self.local_id();
+
+        let mut report = ExternalPeersReport {
+            entries: Vec::with_capacity(peers.len()),
+        };
+        for peer in peers {
+            let status = match self.validate_peer(peer.addr, &peer.node, true) {
+                Ok(peer_id_full) => {
+                    let peer_id = peer_id_full.compute_short_id();
+                    match peer.peer_id {
+                        Some(declared) if declared != peer_id => {
+                            ExternalPeerStatus::Rejected(PeerRejectReason::IdMismatch)
+                        }
+                        _ => match adnl.add_peer_with_outcome(
+                            adnl::NewPeerContext::PublicOverlay,
+                            local_id,
+                            &peer_id,
+                            peer.addr,
+                            peer_id_full,
+                        )? {
+                            adnl::AddPeerOutcome::Ignored => {
+                                ExternalPeerStatus::Rejected(PeerRejectReason::Ignored)
+                            }
+                            adnl::AddPeerOutcome::Updated => {
+                                self.insert_public_peer(&peer_id, peer.node);
+                                ExternalPeerStatus::Updated(peer_id)
+                            }
+                            adnl::AddPeerOutcome::Added { .. } => {
+                                self.insert_public_peer(&peer_id, peer.node);
+                                ExternalPeerStatus::Added(peer_id)
+                            }
+                        },
+                    }
+                }
+                Err(reason) => ExternalPeerStatus::Rejected(reason),
+            };
+
+            if let ExternalPeerStatus::Rejected(reason) = status {
+                tracing::debug!(overlay_id = %self.id, addr = %peer.addr, ?reason, "external peer rejected");
+            }
+            report.entries.push(status);
+        }
+
+        Ok(report)
+    }
+
+    /// Serializes known public peers with their last known addresses.
+    ///
+    /// The result is a boxed TL `network.overlayPeers` (see [`proto::overlay::StoredPeers`]):
+    /// ```text
+    /// network.overlayPeer ip:int port:int node:overlay.node = network.OverlayPeer;
+    /// network.overlayPeers overlay:int256 peers:(vector bytes) = network.OverlayPeers;
+    /// ```
+    /// where each `peers` item is a serialized boxed `network.overlayPeer`.
+    pub fn export_peers_tl(&self, adnl: &adnl::Node) -> Vec<u8> {
+        let local_id = &self.local_id();
+
+        let peers = self
+            .nodes
+            .iter()
+            .filter(|item| !self.ignored_peers.contains(item.key()))
+            .filter_map(|item| {
+                let addr = adnl.get_peer_address(local_id, item.key())?;
+                let addr = proto::adnl::Address::from(&addr);
+                Some(tl_proto::serialize(proto::overlay::StoredPeer {
+                    ip: addr.ip,
+                    port: addr.port,
+                    node: item.value().as_equivalent_ref(),
+                }))
+            })
+            .collect::<Vec<_>>();
+
+        tl_proto::serialize(proto::overlay::StoredPeers {
+            overlay: self.id.as_slice(),
+            peers: peers.iter().map(Vec::as_slice).collect(),
+        })
+    }
+
+    /// Verifies and adds peers exported by [`Overlay::export_peers_tl`].
+    /// Returns a list of successfully added peers.
+    ///
+    /// Corrupted entries, entries with invalid signatures and entries older than
+    /// [`OverlayTuning::stored_peer_ttl_sec`] are skipped.
+    pub fn import_peers_tl(
+        &self,
+        adnl: &adnl::Node,
+        data: &[u8],
+    ) -> Result<Vec<adnl::NodeIdShort>> {
+        let stored = tl_proto::deserialize::<proto::overlay::StoredPeers>(data)?;
+        if stored.overlay != self.id.as_slice() {
+            return Err(OverlayError::OverlayIdMismatch.into());
+        }
+
+        let mut result = Vec::new();
+        for entry in stored.peers {
+            let peer = match tl_proto::deserialize::<proto::overlay::StoredPeer>(entry) {
+                Ok(peer) => peer,
+                Err(e) => {
+                    tracing::debug!(overlay_id = %self.id, "skipping corrupted stored peer: {e:?}");
+                    continue;
+                }
+            };
+            let addr = SocketAddrV4::from(proto::adnl::Address {
+                ip: peer.ip,
+                port: peer.port,
+            });
+            match self.add_public_peer_impl(adnl, addr, peer.node, true) {
+                Ok(Some(peer_id)) => result.push(peer_id),
+                Ok(None) => {}
+                Err(e) => {
+                    tracing::debug!(overlay_id = %self.id, %addr, "skipping stored peer: {e:?}");
+                }
+            }
+        }
+
+        Ok(result)
+    }
+
+    /// Removes peer from random peers and adds it to ignored peers.
+    ///
+    /// Emits [`NetworkEvent::OverlayPeersLow`] if the number of active peers
+    /// drops below [`OverlayTuning::low_peers_threshold`]
     pub fn remove_public_peer(&self, peer_id: &adnl::NodeIdShort) -> bool {
         if !self.ignored_peers.insert(*peer_id) {
             return false;
         }
         tracing::warn!(overlay_id = %self.id, %peer_id, "removing public overlay peer");
         if self.neighbours.contains(peer_id) {
-            self.update_neighbours(self.options.max_neighbours);
+            self.update_neighbours(self.max_neighbours);
+        }
+        if self.known_peers.contains(peer_id) {
+            self.check_low_peers();
         }
         true
     }
 
+    /// Number of known public peers which are not removed
+    pub fn active_public_peer_count(&self) -> usize {
+        self.known_peers
+            .iter()
+            .filter(|peer_id| !self.ignored_peers.contains(*peer_id))
+            .count()
+    }
+
     /// Checks whether the specified peer has ever been in this public overlay
     ///
     /// NOTE: Peer might have been excluded. If you need to check whether the
@@ -351,16 +1129,48 @@ impl Overlay {
         &self.message_prefix
     }
 
-    /// Sends direct ADNL message ([`proto::adnl::Message::Custom`]) to the given peer.
+    /// Hash of the query in the same form as it is sent to the peers
+    /// (after the [`Overlay::query_prefix`]). See [`compute_query_id`]
+    pub fn compute_query_id<Q>(&self, query: &Q) -> [u8; 32]
+    where
+        Q: TlWrite,
+    {
+        compute_query_id(&self.make_query_data(query))
+    }
+
+    /// Same as [`Overlay::compute_query_id`] for the already serialized query
+    pub fn compute_raw_query_id(&self, query: &[u8]) -> [u8; 32] {
+        self.compute_query_id(&tl_proto::RawBytes::<tl_proto::Boxed>::new(query))
+    }
+
+    /// Serializes query after the [`Overlay::query_prefix`]
+    fn make_query_data<Q>(&self, query: Q) -> Vec<u8>
+    where
+        Q: TlWrite,
+    {
+        let prefix = self.query_prefix();
+        let mut query_data = Vec::with_capacity(prefix.len() + query.max_size_hint());
+        query_data.extend_from_slice(prefix);
+        query.write_to(&mut query_data);
+        query_data
+    }
+
+    /// Sends direct ADNL message ([`proto::adnl::Message::Custom`]) with raw data
+    /// after the [`Overlay::message_prefix`] to the given peer.
+    ///
+    /// Remote peer delivers the data to the overlay message subscriber
+    /// (see [`Node::add_overlay_message_subscriber`]).
     ///
     /// NOTE: Local id ([`Overlay::overlay_key`]) will be used as sender
+    ///
+    /// [`Node::add_overlay_message_subscriber`]: crate::overlay::Node::add_overlay_message_subscriber
     pub fn send_message(
         &self,
         adnl: &adnl::Node,
         peer_id: &adnl::NodeIdShort,
         data: &[u8],
     ) -> Result<()> {
-        let local_id = self.overlay_key().id();
+        let local_id = &self.local_id();
 
         let mut buffer = Vec::with_capacity(self.message_prefix().len() + data.len());
         buffer.extend_from_slice(self.message_prefix());
@@ -368,7 +1178,29 @@ impl Overlay {
         adnl.send_custom_message(local_id, peer_id, &buffer)
     }
 
-    /// Sends ADNL query directly to the given peer. In case of timeout returns `Ok(None)`
+    /// Serializes the message after the [`Overlay::message_prefix`]
+    /// and sends it directly to the given peer.
+    ///
+    /// See [`Overlay::send_message`]
+    pub fn send_message_typed<M>(
+        &self,
+        adnl: &adnl::Node,
+        peer_id: &adnl::NodeIdShort,
+        message: M,
+    ) -> Result<()>
+    where
+        M: TlWrite,
+    {
+        let local_id = &self.local_id();
+
+        let prefix = self.message_prefix();
+        let mut buffer = Vec::with_capacity(prefix.len() + message.max_size_hint());
+        buffer.extend_from_slice(prefix);
+        message.write_to(&mut buffer);
+        adnl.send_custom_message(local_id, peer_id, &buffer)
+    }
+
+    /// Sends ADNL query directly to the given peer. In case of timeout returns `Ok(None)`
     ///
     /// NOTE: Local id ([`Overlay::overlay_key`]) will be used as sender
     pub async fn adnl_query<Q>(
@@ -381,10 +1213,26 @@ impl Overlay {
     where
         Q: TlWrite,
     {
-        let local_id = self.overlay_key().id();
+        self.adnl_query_raw(adnl, peer_id, &tl_proto::serialize(query), timeout)
+            .await
+    }
+
+    /// Sends already serialized query (without the [`Overlay::query_prefix`])
+    /// directly to the given peer. In case of timeout returns `Ok(None)`
+    ///
+    /// NOTE: Local id ([`Overlay::overlay_key`]) will be used as sender
+    pub async fn adnl_query_raw(
+        &self,
+        adnl: &adnl::Node,
+        peer_id: &adnl::NodeIdShort,
+        query: &[u8],
+        timeout: Option<u64>,
+    ) -> Result<Option<Vec<u8>>> {
+        let local_id = &self.local_id();
         type Value = tl_proto::OwnedRawBytes<tl_proto::Boxed>;
+        let query = tl_proto::RawBytes::<tl_proto::Boxed>::new(query);
         match adnl
-            .query_with_prefix::<Q, Value>(local_id, peer_id, self.query_prefix(), query, timeout)
+            .query_with_prefix::<_, Value>(local_id, peer_id, self.query_prefix(), query, timeout)
             .await?
         {
             Some(answer) => Ok(Some(answer.into_inner())),
@@ -392,6 +1240,47 @@ impl Overlay {
         }
     }
 
+    /// Sends ADNL query directly to the given peer and deserializes the answer.
+    /// In case of timeout returns `Ok(None)`
+    ///
+    /// NOTE: Local id ([`Overlay::overlay_key`]) will be used as sender
+    pub async fn adnl_query_typed<Q, A>(
+        &self,
+        adnl: &adnl::Node,
+        peer_id: &adnl::NodeIdShort,
+        query: Q,
+        timeout: Option<u64>,
+    ) -> Result<Option<A>>
+    where
+        Q: TlWrite,
+        for<'a> A: TlRead<'a, Repr = tl_proto::Boxed> + 'static,
+    {
+        match self.adnl_query(adnl, peer_id, query, timeout).await? {
+            Some(answer) => deserialize_answer(&answer).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Sends ADNL query directly to the given peer and deserializes the answer.
+    /// Also returns the query id. In case of timeout returns `Ok((query_id, None))`
+    ///
+    /// NOTE: Local id ([`Overlay::overlay_key`]) will be used as sender
+    pub async fn adnl_query_traced<Q, A>(
+        &self,
+        adnl: &adnl::Node,
+        peer_id: &adnl::NodeIdShort,
+        query: Q,
+        timeout: Option<u64>,
+    ) -> Result<(adnl::QueryId, Option<A>)>
+    where
+        Q: TlWrite,
+        for<'a> A: TlRead<'a, Repr = tl_proto::Boxed> + 'static,
+    {
+        let local_id = &self.local_id();
+        adnl.query_with_prefix_traced(local_id, peer_id, self.query_prefix(), query, timeout)
+            .await
+    }
+
     /// Sends RLDP query directly to the given peer. In case of timeout returns `Ok((None, max_timeout))`
     ///
     /// NOTE: Local id ([`Overlay::overlay_key`]) will be used as sender
@@ -405,19 +1294,193 @@ impl Overlay {
     where
         Q: TlWrite,
     {
-        let local_id = self.overlay_key().id();
+        self.rldp_query_raw(rldp, peer_id, &tl_proto::serialize(query), roundtrip)
+            .await
+    }
 
-        let prefix = self.query_prefix();
-        let mut query_data = Vec::with_capacity(prefix.len() + query.max_size_hint());
-        query_data.extend_from_slice(prefix);
-        query.write_to(&mut query_data);
+    /// Sends already serialized query (without the [`Overlay::query_prefix`])
+    /// directly to the given peer. In case of timeout returns `Ok((None, max_timeout))`
+    ///
+    /// NOTE: Local id ([`Overlay::overlay_key`]) will be used as sender
+    pub async fn rldp_query_raw(
+        &self,
+        rldp: &rldp::Node,
+        peer_id: &adnl::NodeIdShort,
+        query: &[u8],
+        roundtrip: Option<u64>,
+    ) -> Result<(Option<Vec<u8>>, u64)> {
+        let (_, answer, roundtrip) = self
+            .rldp_query_traced(
+                rldp,
+                peer_id,
+                tl_proto::RawBytes::<tl_proto::Boxed>::new(query),
+                roundtrip,
+            )
+            .await?;
+        Ok((answer, roundtrip))
+    }
+
+    /// Sends RLDP query directly to the given peer and also returns the query id.
+    /// In case of timeout returns `Ok((query_id, None, max_timeout))`
+    ///
+    /// NOTE: Local id ([`Overlay::overlay_key`]) will be used as sender
+    pub async fn rldp_query_traced<Q>(
+        &self,
+        rldp: &rldp::Node,
+        peer_id: &adnl::NodeIdShort,
+        query: Q,
+        roundtrip: Option<u64>,
+    ) -> Result<(adnl::QueryId, Option<Vec<u8>>, u64)>
+    where
+        Q: TlWrite,
+    {
+        let local_id = &self.local_id();
+        let query_data = self.make_query_data(query);
+        rldp.query_traced(local_id, peer_id, query_data, roundtrip)
+            .await
+    }
+
+    /// Sends RLDP query directly to the given peer and deserializes the answer.
+    /// In case of timeout returns `Ok((None, max_timeout))`
+    ///
+    /// NOTE: Local id ([`Overlay::overlay_key`]) will be used as sender
+    pub async fn rldp_query_typed<Q, A>(
+        &self,
+        rldp: &rldp::Node,
+        peer_id: &adnl::NodeIdShort,
+        query: Q,
+        roundtrip: Option<u64>,
+    ) -> Result<(Option<A>, u64)>
+    where
+        Q: TlWrite,
+        for<'a> A: TlRead<'a, Repr = tl_proto::Boxed> + 'static,
+    {
+        match self.rldp_query(rldp, peer_id, query, roundtrip).await? {
+            (Some(answer), roundtrip) => Ok((Some(deserialize_answer(&answer)?), roundtrip)),
+            (None, roundtrip) => Ok((None, roundtrip)),
+        }
+    }
+
+    /// Queries up to `options.attempts` neighbours until one of them returns a valid answer.
+    /// Returns the first answer with the id of the peer which sent it,
+    /// or `Ok(None)` if no peer has answered.
+    ///
+    /// Peers are selected randomly, preferring the ones which answered before.
+    /// Peers which didn't answer are tried less often, and the ones which sent
+    /// malformed (or rejected by [`QueryAnyOptions::verify`]) answers are tried even less.
+    ///
+    /// NOTE: Local id ([`Overlay::overlay_key`]) will be used as sender
+    pub async fn query_any<Q, A>(
+        &self,
+        transport: OverlayQueryTransport<'_>,
+        query: Q,
+        options: QueryAnyOptions,
+    ) -> Result<Option<(adnl::NodeIdShort, A)>>
+    where
+        Q: TlWrite,
+        for<'a> A: TlRead<'a, Repr = tl_proto::Boxed> + 'static,
+    {
+        use futures_util::stream::{FuturesUnordered, StreamExt};
+
+        let local_id = *self.overlay_key().id();
+        let query_data = bytes::Bytes::from(self.make_query_data(query));
+        let timeout = options.per_attempt_timeout_ms;
+        let adnl: &adnl::Node = match transport {
+            OverlayQueryTransport::Adnl(adnl) => adnl,
+            OverlayQueryTransport::Rldp(rldp) => rldp.adnl(),
+        };
 
-        rldp.query(local_id, peer_id, query_data, roundtrip).await
+        let peers = self
+            .neighbours
+            .get_random_peers(self.neighbours.len() as u32, None);
+        let mut peers = weighted_shuffle(&self.reputation, peers)
+            .into_iter()
+            .take(options.attempts as usize);
+
+        let query_peer = |peer_id: adnl::NodeIdShort| {
+            let query_data = query_data.clone();
+            async move {
+                let answer = match transport {
+                    OverlayQueryTransport::Adnl(adnl) => {
+                        adnl.query_raw(&local_id, &peer_id, &query_data, Some(timeout))
+                            .await
+                    }
+                    OverlayQueryTransport::Rldp(rldp) => {
+                        let query = rldp.query(&local_id, &peer_id, query_data.to_vec(), None);
+                        tokio::select! {
+                            result = query => result.map(|(answer, _)| answer),
+                            _ = self.clock.sleep(Duration::from_millis(timeout)) => Ok(None),
+                        }
+                    }
+                };
+                (peer_id, answer)
+            }
+        };
+
+        let mut in_flight = FuturesUnordered::new();
+        in_flight.extend(
+            peers
+                .by_ref()
+                .take(options.parallelism.max(1) as usize)
+                .map(query_peer),
+        );
+
+        while let Some((peer_id, answer)) = in_flight.next().await {
+            let outcome = match answer {
+                Ok(Some(answer)) => match tl_proto::deserialize::<A>(&answer) {
+                    Ok(parsed) => {
+                        let verified = match &options.verify {
+                            Some(verify) => adnl.verify_answer(&peer_id, &answer, verify).is_ok(),
+                            None => true,
+                        };
+                        if verified {
+                            self.reputation
+                                .report(&peer_id, adnl::ReputationEvent::QueryAnswered);
+                            return Ok(Some((peer_id, parsed)));
+                        }
+                        // NOTE: the peer was already penalized
+                        None
+                    }
+                    Err(_)
+                        if tl_proto::deserialize::<proto::adnl::QueryRejected>(&answer).is_ok() =>
+                    {
+                        Some(adnl::ReputationEvent::QueryFailed)
+                    }
+                    Err(e) => {
+                        tracing::debug!(overlay_id = %self.id, %peer_id, "malformed answer: {e:?}");
+                        Some(adnl::ReputationEvent::MalformedAnswer)
+                    }
+                },
+                Ok(None) => Some(adnl::ReputationEvent::QueryTimeout),
+                Err(e) => {
+                    tracing::debug!(overlay_id = %self.id, %peer_id, "query failed: {e:?}");
+                    Some(adnl::ReputationEvent::QueryFailed)
+                }
+            };
+            if let Some(outcome) = outcome {
+                self.reputation.report(&peer_id, outcome);
+            }
+
+            if let Some(peer_id) = peers.next() {
+                in_flight.push(query_peer(peer_id));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Reputation score of the peer, see [`adnl::Node::set_reputation`].
+    ///
+    /// Grows with each valid answer to [`Overlay::query_any`] and decreases with each failure
+    /// or corrupted broadcast. Public peers with the score not greater than
+    /// [`OverlayTuning::ban_reputation_score`] are removed and not added back
+    pub fn peer_score(&self, peer_id: &adnl::NodeIdShort) -> i32 {
+        self.reputation.score(peer_id)
     }
 
     /// Distributes provided message to the neighbours subset.
     ///
-    /// See `broadcast_target_count` in [`OverlayOptions`]
+    /// See `broadcast_target_count` in [`OverlayTuning`]
     ///
     /// NOTE: If `data` len is greater than
     pub fn broadcast(
@@ -427,26 +1490,78 @@ impl Overlay {
         source: Option<&Arc<adnl::Key>>,
         target: BroadcastTarget,
     ) -> OutgoingBroadcastInfo {
-        let local_id = self.overlay_key().id();
+        self.broadcast_ext(adnl, data, source, target, BroadcastClass::Originated)
+    }
+
+    /// Same as [`Overlay::broadcast`], but with the explicit priority of its packets
+    /// in the outgoing queue (e.g. to resend old broadcasts as [`BroadcastClass::Relayed`])
+    pub fn broadcast_ext(
+        self: &Arc<Self>,
+        adnl: &Arc<adnl::Node>,
+        data: Vec<u8>,
+        source: Option<&Arc<adnl::Key>>,
+        target: BroadcastTarget,
+        class: BroadcastClass,
+    ) -> OutgoingBroadcastInfo {
+        let local_key = self.overlay_key();
+        let local_id = local_key.id();
 
         let key = match source {
             Some(key) => key,
-            None => &self.node_key,
+            None => &local_key,
         };
 
-        if data.len() <= self.options.max_ordinary_broadcast_len {
-            self.send_broadcast(adnl, local_id, data, key, target)
+        if data.len() <= self.tuning.load().max_ordinary_broadcast_len {
+            self.send_broadcast(adnl, local_id, data, key, target, class)
         } else {
-            self.send_fec_broadcast(adnl, local_id, data, key, target)
+            self.send_fec_broadcast(adnl, local_id, data, key, target, class)
         }
     }
 
     /// Waits until the next received broadcast.
     ///
+    /// Dropped broadcasts are skipped, they are only visible in the metrics.
+    /// See [`Overlay::recv_broadcast`] to handle them explicitly.
+    ///
     /// NOTE: It is important to keep polling this method because otherwise
-    /// received broadcasts queue will consume all the memory.
+    /// received broadcasts are dropped (or consume all the memory with the unbounded queue).
     pub async fn wait_for_broadcast(&self) -> IncomingBroadcastInfo {
-        self.received_broadcasts.pop().await
+        loop {
+            match self.received_broadcasts.recv().await {
+                Ok(broadcast) => return broadcast,
+                Err(BroadcastLagged(dropped)) => {
+                    tracing::debug!(overlay_id = %self.id, dropped, "broadcast receiver lagged");
+                }
+            }
+        }
+    }
+
+    /// Waits until the next received broadcast. Returns an error once
+    /// if some broadcasts were dropped since the previous call because the queue was full,
+    /// see [`OverlayOptions::received_broadcasts_capacity`].
+    pub async fn recv_broadcast(&self) -> Result<IncomingBroadcastInfo, BroadcastLagged> {
+        self.received_broadcasts.recv().await
+    }
+
+    /// Adds typed handler for the incoming broadcasts with the `T` constructor.
+    /// Returns `false` if there is already a handler for this constructor.
+    ///
+    /// Handlers are called after the broadcast verification, instead of passing
+    /// the broadcast to [`Overlay::wait_for_broadcast`]. Broadcasts with other
+    /// constructors are still received there.
+    ///
+    /// NOTE: handler is called in the receiver task, so it must not block
+    pub fn register_broadcast_handler<T>(&self, handler: BroadcastHandler<T>) -> bool
+    where
+        T: BoxedConstructor + for<'a> TlRead<'a, Repr = tl_proto::Bare> + 'static,
+    {
+        self.broadcast_handlers.register(handler)
+    }
+
+    /// Number of broadcasts which were not decoded by the typed handlers for each
+    /// registered constructor, see [`Overlay::register_broadcast_handler`]
+    pub fn broadcast_decode_errors(&self) -> Vec<(u32, u64)> {
+        self.broadcast_handlers.decode_errors()
     }
 
     /// Take received peers map
@@ -457,22 +1572,7 @@ impl Overlay {
 
     /// Returns raw signed overlay node
     pub fn sign_local_node(&self) -> proto::overlay::NodeOwned {
-        let key = self.overlay_key();
-        let version = now();
-
-        let node_to_sign = &proto::overlay::NodeToSign {
-            id: key.id().as_slice(),
-            overlay: self.id().as_slice(),
-            version,
-        };
-        let signature = key.sign(node_to_sign);
-
-        proto::overlay::NodeOwned {
-            id: key.full_id().as_tl().as_equivalent_owned(),
-            overlay: *self.id().as_slice(),
-            version,
-            signature: signature.to_vec().into(),
-        }
+        sign_overlay_node(&self.overlay_key(), self.id(), self.clock.now())
     }
 
     /// Exchanges random peers with the specified peer. Returns `Ok(None)` in case of timeout.
@@ -505,7 +1605,7 @@ impl Overlay {
         existing_peers: &dyn ExistingPeersFilter,
     ) -> Result<Option<Vec<adnl::NodeIdShort>>> {
         let query = proto::rpc::OverlayGetRandomPeersOwned {
-            peers: self.prepare_random_peers(),
+            peers: self.prepare_random_peers(true),
         };
         let answer = match self.adnl_query(adnl, peer_id, query, timeout).await? {
             Some(answer) => answer,
@@ -515,7 +1615,7 @@ impl Overlay {
             }
         };
 
-        let answer = tl_proto::deserialize_as_boxed(&answer)?;
+        let answer = deserialize_answer::<BoxedWrapper<proto::overlay::Nodes>>(&answer)?.0;
         tracing::trace!(overlay_id = %self.id, %peer_id, "got random peers");
         let proto::overlay::Nodes { nodes } = self.filter_nodes(answer);
 
@@ -531,7 +1631,9 @@ impl Overlay {
                     }
                 }
                 Err(e) => {
-                    tracing::warn!(overlay_id = %self.id, %peer_id, "failed to process peer: {e}");
+                    if adnl.should_log("failed to process peer") {
+                        tracing::warn!(overlay_id = %self.id, %peer_id, "failed to process peer: {e}");
+                    }
                     None
                 }
             })
@@ -540,9 +1642,9 @@ impl Overlay {
     }
 
     /// Process ordinary broadcast
-    pub(super) async fn receive_broadcast(
+    pub(crate) async fn receive_broadcast(
         self: &Arc<Self>,
-        adnl: &adnl::Node,
+        adnl: &Arc<adnl::Node>,
         local_id: &adnl::NodeIdShort,
         peer_id: &adnl::NodeIdShort,
         broadcast: proto::overlay::OverlayBroadcast<'_>,
@@ -554,6 +1656,7 @@ impl Overlay {
 
         let node_id = adnl::NodeIdFull::try_from(broadcast.src)?;
         let node_peer_id = node_id.compute_short_id();
+
         let source = match broadcast.flags {
             flags if flags & BROADCAST_FLAG_ANY_SENDER == 0 => Some(node_peer_id),
             _ => None,
@@ -567,6 +1670,7 @@ impl Overlay {
                     Ok(()) => {
                         let broadcast_id = broadcast_to_sign.compute_broadcast_id();
                         if !self.create_broadcast(broadcast_id) {
+                            self.count_known_broadcast(peer_id, &broadcast_id);
                             return Ok(());
                         }
                         Some((broadcast_id, decompressed))
@@ -577,40 +1681,79 @@ impl Overlay {
             None => None,
         };
 
+        // NOTE: the signed hash is always recomputed from the payload
         let (broadcast_id, data) = match broadcast_data {
             Some((id, data)) => (id, data),
             None => {
                 let broadcast_to_sign =
                     make_broadcast_to_sign(broadcast.data, broadcast.date, source.as_ref());
-                node_id.verify(&broadcast_to_sign, broadcast.signature)?;
+                if node_id
+                    .verify(&broadcast_to_sign, broadcast.signature)
+                    .is_err()
+                {
+                    self.penalize_corrupted_broadcast(
+                        peer_id,
+                        adnl::ReputationEvent::BadBroadcastSignature,
+                    );
+                    return Err(OverlayError::CorruptedBroadcast.into());
+                }
 
                 let broadcast_id = broadcast_to_sign.compute_broadcast_id();
                 if !self.create_broadcast(broadcast_id) {
+                    self.count_known_broadcast(peer_id, &broadcast_id);
                     return Ok(());
                 }
                 (broadcast_id, broadcast.data.to_vec())
             }
         };
 
-        self.received_broadcasts.push(IncomingBroadcastInfo {
+        // Own broadcast which is no longer remembered (the echo is credited
+        // only for the known ones, so that it can't be forged with our id as a source)
+        if self.is_local_id(&node_peer_id) {
+            return Ok(());
+        }
+
+        self.broadcast_span(&broadcast_id, "incoming").in_scope(
+            || tracing::trace!(len = data.len(), source = %node_peer_id, "broadcast received"),
+        );
+        self.count_incoming_broadcast(adnl);
+        let throttled = self.count_source_broadcast(adnl, &node_peer_id);
+        if let Some(raw_broadcast) = raw_data.strip_prefix(self.message_prefix.as_slice()) {
+            self.broadcast_history
+                .lock()
+                .push(broadcast_id, raw_broadcast);
+        }
+        self.push_received_broadcast(IncomingBroadcastInfo {
             packets: 1,
             data,
             from: node_peer_id,
         });
-
-        let neighbours = self
-            .neighbours
-            .get_random_peers(self.options.secondary_broadcast_target_count, Some(peer_id));
-        self.distribute_broadcast(adnl, local_id, &neighbours, raw_data);
         self.spawn_broadcast_gc_task(broadcast_id);
 
+        if throttled {
+            self.suppressed_forwards.fetch_add(1, Ordering::Relaxed);
+            return Ok(());
+        }
+
+        let neighbours = self.neighbours.get_random_peers(
+            self.tuning.load().secondary_broadcast_target_count,
+            Some(peer_id),
+        );
+        self.distribute_broadcast(
+            adnl,
+            BroadcastClass::Relayed,
+            local_id,
+            &neighbours,
+            raw_data,
+        );
+
         Ok(())
     }
 
     /// Process FEC broadcast
-    pub(super) async fn receive_fec_broadcast(
+    pub(crate) async fn receive_fec_broadcast(
         self: &Arc<Self>,
-        adnl: &adnl::Node,
+        adnl: &Arc<adnl::Node>,
         local_id: &adnl::NodeIdShort,
         peer_id: &adnl::NodeIdShort,
         broadcast: proto::overlay::OverlayBroadcastFec<'_>,
@@ -625,26 +1768,47 @@ impl Overlay {
         let broadcast_id = *broadcast.data_hash;
         let node_id = adnl::NodeIdFull::try_from(broadcast.src)?;
         let source = node_id.compute_short_id();
+        if self.is_own_broadcast(&broadcast_id) {
+            self.record_own_broadcast_echo(peer_id);
+            return Ok(());
+        }
+        // NOTE: parts of the unknown broadcasts with our id as a source are not credited
+        if self.is_local_id(&source) {
+            return Ok(());
+        }
 
         let signature = match broadcast.signature.len() {
             64 => broadcast.signature.try_into().unwrap(),
             _ => return Err(OverlayError::UnsupportedSignature.into()),
         };
 
+        if broadcast.fec.total_len != broadcast.data_size {
+            return Err(OverlayError::DataSizeMismatch.into());
+        }
+        if broadcast.data_size > MAX_FEC_BROADCAST_LEN {
+            return Err(OverlayError::TooBigBroadcast.into());
+        }
+
         let transfer = match self.owned_broadcasts.entry(broadcast_id) {
             // First packet of the broadcast
             Entry::Vacant(entry) => {
-                self.spawn_fec_transfer_receiver(broadcast.fec, broadcast_id, source, entry)?
+                let transfer =
+                    self.spawn_fec_transfer_receiver(broadcast.fec, broadcast_id, source, entry)?;
+                self.count_incoming_broadcast(adnl);
+                self.count_source_broadcast(adnl, &source);
+                transfer
             }
             // Broadcast was already started
             Entry::Occupied(entry) => entry.get().clone(),
         };
         let transfer = match transfer.as_ref() {
             OwnedBroadcast::Incoming(transfer) => transfer,
-            OwnedBroadcast::Other => return Ok(()),
+            OwnedBroadcast::Other | OwnedBroadcast::Outgoing => return Ok(()),
         };
 
-        transfer.updated_at.refresh();
+        transfer
+            .updated_at_ms
+            .store(self.clock.now_ms(), Ordering::Release);
         if transfer.source != source {
             tracing::trace!(
                 overlay_id = %self.id,
@@ -662,6 +1826,7 @@ impl Overlay {
         // Send broadcast to the processing queue
         if !transfer.completed.load(Ordering::Acquire) {
             transfer.broadcast_tx.send(BroadcastFec {
+                peer_id: *peer_id,
                 node_id,
                 data_hash: broadcast_id,
                 data_size: broadcast.data_size,
@@ -675,16 +1840,116 @@ impl Overlay {
         }
 
         // Redistribute broadcast
+        if self
+            .storm_throttle
+            .lock()
+            .is_throttled(&source, self.clock.now_ms())
+        {
+            self.suppressed_forwards.fetch_add(1, Ordering::Relaxed);
+            return Ok(());
+        }
         let neighbours = self.neighbours.get_random_peers(
-            self.options.secondary_fec_broadcast_target_count,
+            self.tuning.load().secondary_fec_broadcast_target_count,
             Some(peer_id),
         );
-        self.distribute_broadcast(adnl, local_id, &neighbours, raw_data);
+        self.distribute_broadcast(
+            adnl,
+            BroadcastClass::Relayed,
+            local_id,
+            &neighbours,
+            raw_data,
+        );
 
         Ok(())
     }
 
     /// Process random peers request
+    /// Retained broadcast or [`proto::overlay::Broadcast::BroadcastNotFound`].
+    /// Returns `None` if the retention is disabled
+    pub(super) fn process_get_broadcast(
+        &self,
+        query: proto::rpc::OverlayGetBroadcast<'_>,
+    ) -> Option<Vec<u8>> {
+        let history = self.broadcast_history.lock();
+        if !history.is_enabled() {
+            return None;
+        }
+        Some(match history.get(query.hash) {
+            Some(broadcast) => broadcast.to_vec(),
+            None => tl_proto::serialize(proto::overlay::Broadcast::BroadcastNotFound),
+        })
+    }
+
+    /// Ids of the retained broadcasts which are not in the query list, newest first.
+    /// Returns `None` if the retention is disabled
+    pub(super) fn process_get_broadcast_list(
+        &self,
+        query: proto::rpc::OverlayGetBroadcastList,
+    ) -> Option<proto::overlay::BroadcastList> {
+        let history = self.broadcast_history.lock();
+        if !history.is_enabled() {
+            return None;
+        }
+        let hashes = history
+            .ids()
+            .filter(|id| !query.list.hashes.contains(id))
+            .take(MAX_BROADCAST_LIST_LEN)
+            .copied()
+            .collect();
+        Some(proto::overlay::BroadcastList { hashes })
+    }
+
+    /// Fetches the recent broadcasts which were retained by the peer
+    /// (see [`OverlayOptions::broadcast_history`]), but not received by us.
+    /// They are verified and delivered the same way as the broadcasts sent to us.
+    ///
+    /// Returns the number of fetched broadcasts.
+    pub async fn fetch_missed_broadcasts(
+        self: &Arc<Self>,
+        adnl: &Arc<adnl::Node>,
+        peer_id: &adnl::NodeIdShort,
+        timeout: Option<u64>,
+    ) -> Result<usize> {
+        let query = proto::rpc::OverlayGetBroadcastList {
+            list: Default::default(),
+        };
+        let list = match self.adnl_query(adnl, peer_id, query, timeout).await? {
+            Some(answer) => {
+                deserialize_answer::<BoxedWrapper<proto::overlay::BroadcastList>>(&answer)?.0
+            }
+            None => return Ok(0),
+        };
+
+        let local_id = *self.overlay_key().id();
+        let mut fetched = 0;
+        for hash in &list.hashes {
+            if self.owned_broadcasts.contains_key(hash) {
+                continue;
+            }
+
+            let query = proto::rpc::OverlayGetBroadcast { hash };
+            let answer = match self.adnl_query(adnl, peer_id, query, timeout).await? {
+                Some(answer) => answer,
+                None => continue,
+            };
+            let broadcast = match tl_proto::deserialize(&answer)? {
+                proto::overlay::Broadcast::Broadcast(broadcast) => broadcast,
+                proto::overlay::Broadcast::BroadcastNotFound => continue,
+                _ => return Err(OverlayError::UnexpectedBroadcast.into()),
+            };
+
+            let mut raw_data = Vec::with_capacity(self.message_prefix.len() + answer.len());
+            raw_data.extend_from_slice(&self.message_prefix);
+            raw_data.extend_from_slice(&answer);
+            self.receive_broadcast(adnl, &local_id, peer_id, broadcast, &raw_data)
+                .await?;
+            fetched += 1;
+        }
+
+        tracing::debug!(overlay_id = %self.id, %peer_id, fetched, "fetched missed broadcasts");
+        Ok(fetched)
+    }
+
     pub(super) fn process_get_random_peers(
         &self,
         query: proto::rpc::OverlayGetRandomPeers<'_>,
@@ -713,22 +1978,28 @@ impl Overlay {
         drop(received_peers);
 
         // Return random peers from our side
-        self.prepare_random_peers()
+        let include_self = self
+            .tuning
+            .load()
+            .include_self_in_random_peers
+            .unwrap_or(!self.is_private);
+        self.prepare_random_peers(include_self)
     }
 
     /// Send ordinary broadcast
     fn send_broadcast(
         self: &Arc<Self>,
-        adnl: &adnl::Node,
+        adnl: &Arc<adnl::Node>,
         local_id: &adnl::NodeIdShort,
         mut data: Vec<u8>,
         key: &Arc<adnl::Key>,
         target: BroadcastTarget,
+        class: BroadcastClass,
     ) -> OutgoingBroadcastInfo {
-        let date = now();
+        let date = self.clock.now();
         let broadcast_to_sign = make_broadcast_to_sign(&data, date, None);
         let broadcast_id = broadcast_to_sign.compute_broadcast_id();
-        if !self.create_broadcast(broadcast_id) {
+        if !self.create_outgoing_broadcast(broadcast_id) {
             tracing::warn!(
                 overlay_id = %self.id,
                 broadcast_id = %DisplayBroadcastId(&broadcast_id),
@@ -738,7 +2009,7 @@ impl Overlay {
         }
         let signature = key.sign(broadcast_to_sign);
 
-        if self.options.force_compression {
+        if self.tuning.load().force_compression {
             if let Err(e) = compression::compress(&mut data) {
                 tracing::warn!(
                     overlay_id = %self.id,
@@ -761,21 +2032,36 @@ impl Overlay {
         buffer.extend_from_slice(&self.message_prefix);
         broadcast.write_to(&mut buffer);
         drop(data);
+        self.broadcast_history
+            .lock()
+            .push(broadcast_id, &buffer[self.message_prefix.len()..]);
 
-        let neighbours = match target {
-            BroadcastTarget::RandomNeighbours => OwnedBroadcastTarget::Neighbours(
-                self.neighbours
-                    .get_random_peers(self.options.broadcast_target_count, None),
-            ),
-            BroadcastTarget::Explicit(neighbours) => OwnedBroadcastTarget::Explicit(neighbours),
-        };
+        let neighbours = self.select_broadcast_targets(target);
 
-        self.distribute_broadcast(adnl, local_id, neighbours.as_ref(), &buffer);
+        let recipient_count = neighbours.len();
+        let spread = Duration::from_millis(self.tuning.load().broadcast_spread_duration_ms);
+        if spread.is_zero() || recipient_count < 2 {
+            self.send_own_packets(adnl, class, local_id, &neighbours, &[buffer]);
+        } else {
+            let overlay = self.clone();
+            let adnl = adnl.clone();
+            let local_id = *local_id;
+            self.layer
+                .tasks()
+                .spawn(&self.runtime, "overlay_broadcast_spread", async move {
+                    overlay
+                        .spread_own_packets(&adnl, class, &local_id, &neighbours, &[buffer], spread)
+                        .await;
+                });
+        }
         self.spawn_broadcast_gc_task(broadcast_id);
 
+        self.broadcast_span(&broadcast_id, "outgoing")
+            .in_scope(|| tracing::trace!(recipient_count, "broadcast sent"));
+
         OutgoingBroadcastInfo {
             packets: 1,
-            recipient_count: neighbours.as_ref().len(),
+            recipient_count,
         }
     }
 
@@ -787,9 +2073,10 @@ impl Overlay {
         mut data: Vec<u8>,
         key: &Arc<adnl::Key>,
         target: BroadcastTarget,
+        class: BroadcastClass,
     ) -> OutgoingBroadcastInfo {
         let broadcast_id = sha2::Sha256::digest(&data).into();
-        if !self.create_broadcast(broadcast_id) {
+        if !self.create_outgoing_broadcast(broadcast_id) {
             tracing::warn!(
                 overlay_id = %self.id,
                 broadcast_id = %DisplayBroadcastId(&broadcast_id),
@@ -798,7 +2085,7 @@ impl Overlay {
             return Default::default();
         }
 
-        if self.options.force_compression {
+        if self.tuning.load().force_compression {
             if let Err(e) = compression::compress(&mut data) {
                 tracing::warn!(
                     overlay_id = %self.id,
@@ -818,53 +2105,84 @@ impl Overlay {
         // NOTE: Data is already in encoder and not needed anymore
         drop(data);
 
-        let neighbours = match target {
-            BroadcastTarget::RandomNeighbours => OwnedBroadcastTarget::Neighbours(
-                self.neighbours
-                    .get_random_peers(self.options.broadcast_target_count, None),
-            ),
-            BroadcastTarget::Explicit(neighbours) => OwnedBroadcastTarget::Explicit(neighbours),
-        };
+        let neighbours = self.select_broadcast_targets(target);
 
         let info = OutgoingBroadcastInfo {
             packets: (data_size / outgoing_transfer.encoder.params().packet_len + 1) * 3 / 2,
-            recipient_count: neighbours.as_ref().len(),
+            recipient_count: neighbours.len(),
         };
 
         // Spawn sender
-        let wave_len = self.options.fec_broadcast_wave_len;
-        let waves_interval = Duration::from_millis(self.options.fec_broadcast_wave_interval_ms);
+        let options = self.tuning.load();
+        let wave_len = options.fec_broadcast_wave_len;
+        let waves_interval = Duration::from_millis(options.fec_broadcast_wave_interval_ms);
+        let spread = Duration::from_millis(options.broadcast_spread_duration_ms);
+        drop(options);
         let overlay = self.clone();
         let adnl = adnl.clone();
         let local_id = *local_id;
         let key = key.clone();
-        tokio::spawn(async move {
-            // Send broadcast in waves
-            'outer: while outgoing_transfer.seqno <= info.packets {
-                for _ in 0..wave_len {
-                    let data = match overlay.prepare_fec_broadcast(&mut outgoing_transfer, &key) {
-                        Ok(data) => data,
-                        // Rare case, it is easier to just ignore it
-                        Err(e) => {
-                            tracing::warn!(
-                                overlay_id = %overlay.id,
-                                broadcast_id = %DisplayBroadcastId(&broadcast_id),
-                                "failed to send overlay broadcast: {e}"
-                            );
-                            break 'outer;
+        let uses_local_key = self.is_local_id(key.id());
+        let span = self.broadcast_span(&broadcast_id, "outgoing");
+        self.layer.tasks().spawn(
+            &self.runtime,
+            "overlay_fec_broadcast_sender",
+            async move {
+                let started_at = overlay.clock.instant();
+
+                // Send broadcast in waves
+                let mut wave = Vec::with_capacity(wave_len);
+                loop {
+                    // Rotated key is no longer trusted after the grace period
+                    if uses_local_key && !overlay.is_local_id(key.id()) {
+                        tracing::debug!("broadcast key expired");
+                        break;
+                    }
+
+                    let mut failed = false;
+                    while wave.len() < wave_len && outgoing_transfer.seqno <= info.packets {
+                        match overlay.prepare_fec_broadcast(&mut outgoing_transfer, &key) {
+                            Ok(data) => wave.push(data),
+                            // Rare case, it is easier to just ignore it
+                            Err(e) => {
+                                tracing::warn!(
+                                    overlay_id = %overlay.id,
+                                    broadcast_id = %DisplayBroadcastId(&broadcast_id),
+                                    "failed to send overlay broadcast: {e}"
+                                );
+                                failed = true;
+                                break;
+                            }
                         }
-                    };
+                    }
 
-                    overlay.distribute_broadcast(&adnl, &local_id, neighbours.as_ref(), &data);
-                    if outgoing_transfer.seqno > info.packets {
-                        break 'outer;
+                    // NOTE: Each neighbour receives the whole wave in order
+                    overlay
+                        .spread_own_packets(&adnl, class, &local_id, &neighbours, &wave, spread)
+                        .await;
+                    wave.clear();
+
+                    if failed || outgoing_transfer.seqno > info.packets {
+                        break;
                     }
+
+                    // Sleep between waves
+                    overlay.clock.sleep(waves_interval).await;
                 }
 
-                // Sleep between waves
-                tokio::time::sleep(waves_interval).await;
+                let elapsed = overlay
+                    .clock
+                    .instant()
+                    .saturating_duration_since(started_at);
+                tracing::Span::current().record("elapsed_ms", elapsed.as_millis() as u64);
+                tracing::trace!(
+                    packets = outgoing_transfer.seqno,
+                    recipient_count = info.recipient_count,
+                    "broadcast sent"
+                );
             }
-        });
+            .instrument(span),
+        );
 
         // Schedule broadcast cleanup
         self.spawn_broadcast_gc_task(broadcast_id);
@@ -875,35 +2193,68 @@ impl Overlay {
 
     /// Verifies and retains only valid remote peers
     fn filter_nodes<'a>(&self, mut nodes: proto::overlay::Nodes<'a>) -> proto::overlay::Nodes<'a> {
-        nodes.nodes.retain(|node| {
-            if !matches!(
-                node.id,
-                everscale_crypto::tl::PublicKey::Ed25519 { key }
-                if key != self.node_key.full_id().public_key().as_bytes()
-            ) {
-                return false;
-            }
-
-            if let Err(e) = self.id.verify_overlay_node(node) {
-                tracing::warn!(overlay_id = %self.id, "invalid overlay node: {e:?}");
-                return false;
-            }
-
-            true
-        });
+        nodes
+            .nodes
+            .retain(|node| match self.validate_node(node, false) {
+                Ok(_) => true,
+                // Own node is returned back by the peers, banned peers are still shared
+                Err(PeerRejectReason::LocalNode | PeerRejectReason::BadReputation) => false,
+                Err(reason) => {
+                    tracing::warn!(overlay_id = %self.id, ?reason, "invalid overlay node");
+                    false
+                }
+            });
 
         nodes
     }
 
-    /// Creates nodes list
-    fn prepare_random_peers(&self) -> proto::overlay::NodesOwned {
-        const MAX_PEERS_IN_RESPONSE: u32 = 4;
+    /// Checks the overlay node from any source (see [`validate_overlay_node`]).
+    /// Stale nodes are only rejected with `check_freshness`
+    fn validate_node(
+        &self,
+        node: &proto::overlay::Node<'_>,
+        check_freshness: bool,
+    ) -> Result<adnl::NodeIdFull, PeerRejectReason> {
+        let ttl_sec = if check_freshness {
+            self.tuning.load().stored_peer_ttl_sec
+        } else {
+            0
+        };
+        let peer_id_full =
+            validate_overlay_node(&self.id, &self.local_id(), node, self.clock.now(), ttl_sec)?;
+        let peer_id = peer_id_full.compute_short_id();
+        if self.is_local_id(&peer_id) {
+            return Err(PeerRejectReason::LocalNode);
+        }
+        if self.is_banned(&peer_id) {
+            return Err(PeerRejectReason::BadReputation);
+        }
+        Ok(peer_id_full)
+    }
+
+    /// Checks the overlay node with the address at which it will be added
+    fn validate_peer(
+        &self,
+        addr: SocketAddrV4,
+        node: &proto::overlay::Node<'_>,
+        check_freshness: bool,
+    ) -> Result<adnl::NodeIdFull, PeerRejectReason> {
+        let peer_id_full = self.validate_node(node, check_freshness)?;
+        validate_peer_addr(&addr)?;
+        Ok(peer_id_full)
+    }
 
-        let mut nodes = SmallVec::with_capacity(MAX_PEERS_IN_RESPONSE as usize + 1);
-        nodes.push(self.sign_local_node());
+    /// Creates nodes list with up to [`MAX_RANDOM_PEERS_NODES`] items,
+    /// optionally starting with the freshly signed own node
+    fn prepare_random_peers(&self, include_self: bool) -> proto::overlay::NodesOwned {
+        let mut nodes = SmallVec::with_capacity(MAX_RANDOM_PEERS_NODES);
+        if include_self {
+            nodes.push(self.sign_local_node());
+        }
 
-        let peers = adnl::PeersSet::with_capacity(MAX_PEERS_IN_RESPONSE);
-        peers.randomly_fill_from(&self.neighbours, MAX_PEERS_IN_RESPONSE, None);
+        let amount = (MAX_RANDOM_PEERS_NODES - nodes.len()) as u32;
+        let peers = adnl::PeersSet::with_capacity(amount);
+        peers.randomly_fill_from(&self.neighbours, amount, None);
         for peer_id in &peers {
             if let Some(node) = self.nodes.get(peer_id) {
                 nodes.push(node.clone());
@@ -943,19 +2294,152 @@ impl Overlay {
         }
     }
 
-    /// Adds new broadcast id
-    fn create_broadcast(&self, broadcast_id: BroadcastId) -> bool {
-        use dashmap::mapref::entry::Entry;
+    /// Creates a span for the broadcast with the specified direction
+    fn broadcast_span(&self, broadcast_id: &BroadcastId, direction: &'static str) -> tracing::Span {
+        tracing::debug_span!(
+            "overlay_broadcast",
+            overlay_id = %self.id,
+            broadcast_id = %DisplayBroadcastId(broadcast_id),
+            direction,
+            elapsed_ms = tracing::field::Empty,
+        )
+    }
 
-        match self.owned_broadcasts.entry(broadcast_id) {
+    /// Emits [`NetworkEvent::OverlayPeersLow`] when the number of active peers
+    /// reaches the threshold from above
+    fn check_low_peers(&self) {
+        let threshold = self.tuning.load().low_peers_threshold;
+        if threshold == 0 {
+            return;
+        }
+
+        let peers = self.active_public_peer_count();
+        if peers + 1 != threshold {
+            return;
+        }
+
+        tracing::warn!(overlay_id = %self.id, peers, threshold, "too few overlay peers");
+        self.events.emit(|| NetworkEvent::OverlayPeersLow {
+            overlay_id: self.id,
+            peers,
+            timestamp_ms: self.clock.now_ms(),
+        });
+    }
+
+    /// Adds new broadcast id
+    /// Emits [`NetworkEvent::BroadcastStorm`] once per second if there are
+    /// too many new incoming broadcasts
+    fn count_incoming_broadcast(&self, adnl: &adnl::Node) {
+        let threshold = self.tuning.load().broadcast_storm_threshold;
+        if threshold == 0 {
+            return;
+        }
+
+        let now = self.clock.now();
+        let mut rate = self.broadcast_rate.lock();
+        if rate.second != now {
+            *rate = BroadcastRate {
+                second: now,
+                count: 0,
+            };
+        }
+        rate.count += 1;
+        if rate.count != threshold {
+            return;
+        }
+        drop(rate);
+
+        tracing::debug!(overlay_id = %self.id, threshold, "broadcast storm detected");
+        adnl.emit_event(|timestamp_ms| NetworkEvent::BroadcastStorm {
+            overlay_id: self.id,
+            source: None,
+            broadcasts: threshold,
+            timestamp_ms,
+        });
+    }
+
+    /// Adds new broadcast id of the source. Returns `true` if
+    /// the broadcasts of this source must not be forwarded.
+    ///
+    /// Emits [`NetworkEvent::BroadcastStorm`] when the source becomes throttled
+    fn count_source_broadcast(&self, adnl: &adnl::Node, source: &adnl::NodeIdShort) -> bool {
+        let options = self.tuning.load();
+        let threshold = options.broadcast_storm_threshold;
+        // NOTE: Own broadcasts are never throttled
+        if threshold == 0 || adnl.key_by_id(source).is_ok() {
+            return false;
+        }
+
+        let now_ms = self.clock.now_ms();
+        let cooldown_ms = options.storm_cooldown_ms;
+        let mut storm_throttle = self.storm_throttle.lock();
+        if !storm_throttle.count(source, now_ms, threshold, cooldown_ms) {
+            return storm_throttle.is_throttled(source, now_ms);
+        }
+        drop(storm_throttle);
+
+        tracing::warn!(
+            overlay_id = %self.id,
+            %source,
+            threshold,
+            cooldown_ms,
+            "broadcast storm detected, forwarding suspended"
+        );
+        adnl.emit_event(|timestamp_ms| NetworkEvent::BroadcastStorm {
+            overlay_id: self.id,
+            source: Some(*source),
+            broadcasts: threshold + 1,
+            timestamp_ms,
+        });
+        true
+    }
+
+    fn create_broadcast(&self, broadcast_id: BroadcastId) -> bool {
+        self.insert_broadcast(broadcast_id, OwnedBroadcast::Other)
+    }
+
+    fn create_outgoing_broadcast(&self, broadcast_id: BroadcastId) -> bool {
+        self.insert_broadcast(broadcast_id, OwnedBroadcast::Outgoing)
+    }
+
+    fn insert_broadcast(&self, broadcast_id: BroadcastId, broadcast: OwnedBroadcast) -> bool {
+        use dashmap::mapref::entry::Entry;
+
+        match self.owned_broadcasts.entry(broadcast_id) {
             Entry::Vacant(entry) => {
-                entry.insert(Arc::new(OwnedBroadcast::Other));
+                entry.insert(Arc::new(broadcast));
                 true
             }
             Entry::Occupied(_) => false,
         }
     }
 
+    fn is_own_broadcast(&self, broadcast_id: &BroadcastId) -> bool {
+        matches!(
+            self.owned_broadcasts
+                .get(broadcast_id)
+                .as_deref()
+                .map(AsRef::as_ref),
+            Some(OwnedBroadcast::Outgoing)
+        )
+    }
+
+    /// Counts the broadcast which was already received or sent
+    fn count_known_broadcast(&self, peer_id: &adnl::NodeIdShort, broadcast_id: &BroadcastId) {
+        if self.is_own_broadcast(broadcast_id) {
+            self.record_own_broadcast_echo(peer_id);
+        } else {
+            self.broadcasts_duplicated.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Own broadcast forwarded back is not delivered, but proves that the neighbour is alive
+    fn record_own_broadcast_echo(&self, peer_id: &adnl::NodeIdShort) {
+        self.own_broadcast_echoes.fetch_add(1, Ordering::Relaxed);
+        self.reputation
+            .report(peer_id, adnl::ReputationEvent::BroadcastEcho);
+    }
+
     /// Creates incoming FEC broadcast
     fn spawn_fec_transfer_receiver(
         self: &Arc<Self>,
@@ -964,6 +2448,7 @@ impl Overlay {
         peer_id: adnl::NodeIdShort,
         entry: VacantBroadcastEntry<'_>,
     ) -> Result<Arc<OwnedBroadcast>> {
+        let mut decoder = RaptorQDecoder::with_params(fec_type)?;
         let (broadcast_tx, mut broadcast_rx) = mpsc::unbounded_channel();
 
         let entry = entry
@@ -972,82 +2457,138 @@ impl Overlay {
                 history: PacketsHistory::for_recv(),
                 broadcast_tx,
                 source: peer_id,
-                updated_at: Default::default(),
+                updated_at_ms: AtomicU64::new(self.clock.now_ms()),
             })))
             .clone();
 
         // Spawn packets receiver
         let overlay = self.clone();
-        tokio::spawn(async move {
-            let mut decoder = RaptorQDecoder::with_params(fec_type);
-
-            // For each fec broadcast packet
-            let mut packets = 0;
-            while let Some(broadcast) = broadcast_rx.recv().await {
-                packets += 1;
-
-                // Add new data to the encoder
-                match process_fec_broadcast(&mut decoder, broadcast) {
-                    // Broadcast complete and successfully decoded
-                    Ok(Some(data)) => {
-                        let data = IncomingBroadcastInfo {
-                            packets,
-                            data,
-                            from: peer_id,
-                        };
-                        overlay.received_broadcasts.push(data);
-                        break;
-                    }
-                    // Broadcast is not complete yet
-                    Ok(None) => continue,
-                    // Error during decoding
-                    Err(e) => {
-                        tracing::warn!(
-                            overlay_id = %overlay.id,
-                            broadcast_id = %DisplayBroadcastId(&broadcast_id),
-                            "error when receiving overlay broadcast: {e}"
+        let span = self.broadcast_span(&broadcast_id, "incoming");
+        self.layer.tasks().spawn(
+            &self.runtime,
+            "overlay_fec_broadcast_receiver",
+            async move {
+                let started_at = overlay.clock.instant();
+
+                // For each fec broadcast packet
+                let mut packets = 0;
+                while let Some(broadcast) = broadcast_rx.recv().await {
+                    packets += 1;
+
+                    // Signatures are checked here, only the decoding is offloaded
+                    if verify_fec_broadcast(&broadcast).is_err() {
+                        // Corrupted part is skipped, the rest are still decoded
+                        overlay.penalize_corrupted_broadcast(
+                            &broadcast.peer_id,
+                            adnl::ReputationEvent::BadBroadcastSignature,
                         );
-                        break;
+                        continue;
                     }
-                }
-            }
+                    let mut parts = vec![(broadcast.seqno, broadcast.data_size, broadcast.data)];
+
+                    let result = if decoder.may_complete() {
+                        // Decode all parts which have arrived so far in one go
+                        while let Ok(broadcast) = broadcast_rx.try_recv() {
+                            packets += 1;
+                            match verify_fec_broadcast(&broadcast) {
+                                Ok(()) => parts.push((
+                                    broadcast.seqno,
+                                    broadcast.data_size,
+                                    broadcast.data,
+                                )),
+                                Err(_) => overlay.penalize_corrupted_broadcast(
+                                    &broadcast.peer_id,
+                                    adnl::ReputationEvent::BadBroadcastSignature,
+                                ),
+                            }
+                        }
 
-            // Mark broadcast as completed
-            if let Some(broadcast) = overlay.owned_broadcasts.get(&broadcast_id) {
-                match broadcast.value().as_ref() {
-                    OwnedBroadcast::Incoming(transfer) => {
-                        transfer.completed.store(true, Ordering::Release);
-                    }
-                    _ => {
-                        tracing::error!(
-                            overlay_id = %overlay.id,
-                            broadcast_id = %DisplayBroadcastId(&broadcast_id),
-                            "incoming fec broadcast mismatch"
-                        );
+                        let permit = overlay.decode_permits.clone().acquire_owned().await;
+                        let decoded = overlay
+                            .runtime
+                            .spawn_blocking(move || {
+                                let _permit = permit;
+                                let result = decode_fec_parts(&mut decoder, parts, &broadcast_id);
+                                (decoder, result)
+                            })
+                            .await;
+                        match decoded {
+                            Ok((returned, result)) => {
+                                decoder = returned;
+                                result
+                            }
+                            Err(e) => {
+                                tracing::error!(
+                                    overlay_id = %overlay.id,
+                                    broadcast_id = %DisplayBroadcastId(&broadcast_id),
+                                    "failed to decode overlay broadcast: {e}"
+                                );
+                                break;
+                            }
+                        }
+                    } else {
+                        decode_fec_parts(&mut decoder, parts, &broadcast_id)
+                    };
+
+                    match result {
+                        // Broadcast complete and successfully decoded
+                        Ok(Some(data)) => {
+                            let elapsed = overlay
+                                .clock
+                                .instant()
+                                .saturating_duration_since(started_at);
+                            tracing::Span::current()
+                                .record("elapsed_ms", elapsed.as_millis() as u64);
+                            tracing::trace!(
+                                packets,
+                                len = data.len(),
+                                source = %peer_id,
+                                "broadcast received"
+                            );
+
+                            let data = IncomingBroadcastInfo {
+                                packets,
+                                data,
+                                from: peer_id,
+                            };
+                            overlay.push_received_broadcast(data);
+                            break;
+                        }
+                        // Broadcast is not complete yet
+                        Ok(None) => continue,
+                        Err(e) => match e.downcast_ref::<OverlayError>() {
+                            // Source signed the parts of the corrupted data
+                            Some(OverlayError::DataHashMismatch) => {
+                                overlay.penalize_corrupted_broadcast(
+                                    &peer_id,
+                                    adnl::ReputationEvent::CorruptTransfer,
+                                );
+                                break;
+                            }
+                            // Error during decoding
+                            _ => {
+                                tracing::warn!(
+                                    overlay_id = %overlay.id,
+                                    broadcast_id = %DisplayBroadcastId(&broadcast_id),
+                                    "error when receiving overlay broadcast: {e}"
+                                );
+                                break;
+                            }
+                        },
                     }
                 }
-            }
-        });
 
-        // Spawn broadcast cleanup task
-        let overlay = self.clone();
-        let broadcast_timeout_sec = self.options.broadcast_timeout_sec;
-        tokio::spawn(async move {
-            loop {
-                tokio::time::sleep(Duration::from_millis(broadcast_timeout_sec * 100)).await;
-
-                // Find incoming broadcast
+                // Mark broadcast as completed, the decoder is dropped with this task
                 if let Some(broadcast) = overlay.owned_broadcasts.get(&broadcast_id) {
                     match broadcast.value().as_ref() {
-                        // Keep waiting if broadcast is not expired or not complete
-                        OwnedBroadcast::Incoming(transfer)
-                            if !transfer.completed.load(Ordering::Acquire)
-                                && !transfer.updated_at.is_expired(broadcast_timeout_sec) =>
-                        {
-                            continue
+                        OwnedBroadcast::Incoming(transfer) => {
+                            if !transfer.completed.swap(true, Ordering::AcqRel) {
+                                overlay.spawn_broadcast_gc_task(broadcast_id);
+                            }
                         }
-                        OwnedBroadcast::Incoming(_) => {}
-                        _ => {
+                        // Expired broadcast was already dropped
+                        OwnedBroadcast::Other => {}
+                        OwnedBroadcast::Outgoing => {
                             tracing::error!(
                                 overlay_id = %overlay.id,
                                 broadcast_id = %DisplayBroadcastId(&broadcast_id),
@@ -1056,12 +2597,9 @@ impl Overlay {
                         }
                     }
                 }
-
-                break;
             }
-
-            overlay.spawn_broadcast_gc_task(broadcast_id);
-        });
+            .instrument(span),
+        );
 
         Ok(entry)
     }
@@ -1073,7 +2611,7 @@ impl Overlay {
         key: &Arc<adnl::Key>,
     ) -> Result<Vec<u8>> {
         let chunk = transfer.encoder.encode(&mut transfer.seqno)?;
-        let date = now();
+        let date = self.clock.now();
 
         let broadcast_to_sign = &make_fec_part_to_sign(
             &transfer.broadcast_id,
@@ -1110,56 +2648,250 @@ impl Overlay {
         Ok(buffer)
     }
 
-    /// Sends ADNL messages to neighbours
-    fn distribute_broadcast(
+    /// Returns recipients of own broadcast in random order: up to
+    /// [`OverlayTuning::broadcast_target_count`] random neighbours or the shuffled explicit ids,
+    /// so that the spread sends don't reach the same neighbours first
+    fn select_broadcast_targets(&self, target: BroadcastTarget) -> Vec<adnl::NodeIdShort> {
+        use rand::seq::SliceRandom;
+
+        match target {
+            // NOTE: Random peers are already shuffled
+            BroadcastTarget::RandomNeighbours => self
+                .neighbours
+                .get_random_peers(self.tuning.load().broadcast_target_count, None),
+            BroadcastTarget::Explicit(neighbours) => {
+                let mut neighbours = neighbours.as_ref().clone();
+                neighbours.shuffle(&mut rand::thread_rng());
+                neighbours
+            }
+        }
+    }
+
+    /// Sends packets of own broadcast to each neighbour with equal intervals
+    /// so that all neighbours are reached within `spread`
+    async fn spread_own_packets(
         &self,
-        adnl: &adnl::Node,
+        adnl: &Arc<adnl::Node>,
+        class: BroadcastClass,
         local_id: &adnl::NodeIdShort,
         neighbours: &[adnl::NodeIdShort],
-        data: &[u8],
+        packets: &[Vec<u8>],
+        spread: Duration,
+    ) {
+        if spread.is_zero() || neighbours.len() < 2 {
+            return self.send_own_packets(adnl, class, local_id, neighbours, packets);
+        }
+
+        let interval = spread / neighbours.len() as u32;
+        for (i, peer_id) in neighbours.iter().enumerate() {
+            if i > 0 {
+                self.clock.sleep(interval).await;
+            }
+            self.send_own_packets(
+                adnl,
+                class,
+                local_id,
+                std::slice::from_ref(peer_id),
+                packets,
+            );
+        }
+    }
+
+    fn send_own_packets(
+        &self,
+        adnl: &Arc<adnl::Node>,
+        class: BroadcastClass,
+        local_id: &adnl::NodeIdShort,
+        neighbours: &[adnl::NodeIdShort],
+        packets: &[Vec<u8>],
     ) {
         for peer_id in neighbours {
-            if let Err(e) = adnl.send_custom_message(local_id, peer_id, data) {
-                tracing::warn!(
-                    overlay_id = %self.id,
-                    %peer_id,
-                    "failed to distribute broadcast: {e}"
+            for packet in packets {
+                self.distribute_broadcast(
+                    adnl,
+                    class,
+                    local_id,
+                    std::slice::from_ref(peer_id),
+                    packet,
                 );
             }
         }
+
+        let count = (neighbours.len() * packets.len()) as u32;
+        self.outgoing_packets
+            .fetch_add(count as u64, Ordering::Relaxed);
+        self.outgoing_rate.lock().add(self.clock.now(), count);
+    }
+
+    /// Enqueues broadcast packet for the neighbours, see [`OutgoingQueue`]
+    fn distribute_broadcast(
+        &self,
+        adnl: &Arc<adnl::Node>,
+        class: BroadcastClass,
+        local_id: &adnl::NodeIdShort,
+        neighbours: &[adnl::NodeIdShort],
+        data: &[u8],
+    ) {
+        let capacity = {
+            let tuning = self.tuning.load();
+            match class {
+                BroadcastClass::Originated => tuning.originated_queue_capacity,
+                BroadcastClass::Relayed => tuning.relayed_queue_capacity,
+            }
+        };
+        if !self
+            .outgoing_queue
+            .push(class, local_id, neighbours, data, capacity)
+        {
+            return;
+        }
+
+        let queue = self.outgoing_queue.clone();
+        let adnl = adnl.clone();
+        let overlay_id = self.id;
+        self.layer
+            .tasks()
+            .spawn(&self.runtime, "overlay_broadcast_sender", async move {
+                queue.drain(&adnl, &overlay_id).await;
+            });
+    }
+
+    pub(super) fn add_unhandled_message(&self) {
+        self.unhandled_messages.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns the cached answer for the identical query, if any
+    pub(super) fn find_cached_answer(&self, key: &AnswerCacheKey) -> Option<Vec<u8>> {
+        if self.answer_cache_max_size(&self.tuning.load()) == 0 {
+            return None;
+        }
+
+        let answer = self.answer_cache.lock().get(key, self.clock.instant());
+        if answer.is_some() {
+            self.answer_cache_hits.fetch_add(1, Ordering::Relaxed);
+        }
+        answer
+    }
+
+    /// Stores the answer with the TTL limited by `answer_cache_max_ttl_ms`
+    pub(super) fn cache_answer(&self, key: AnswerCacheKey, answer: &[u8], ttl: Duration) {
+        let options = self.tuning.load();
+        let max_size = self.answer_cache_max_size(&options);
+        let ttl = ttl.min(Duration::from_millis(options.answer_cache_max_ttl_ms));
+
+        let now = self.clock.instant();
+        let mut cache = self.answer_cache.lock();
+        cache.shrink(now, max_size);
+        if max_size > 0 && !ttl.is_zero() {
+            cache.insert(key, answer.to_vec(), now + ttl, max_size);
+        }
+    }
+
+    fn answer_cache_max_size(&self, options: &OverlayTuning) -> usize {
+        match options.answer_cache_max_size {
+            Some(max_size) => max_size,
+            None if self.is_private => 0,
+            None => DEFAULT_ANSWER_CACHE_MAX_SIZE,
+        }
+    }
+
+    /// Counts corrupted broadcast data and stops using the public peer which sent it
+    /// if its reputation is too low
+    fn penalize_corrupted_broadcast(
+        &self,
+        peer_id: &adnl::NodeIdShort,
+        event: adnl::ReputationEvent,
+    ) {
+        self.broadcasts_corrupted.fetch_add(1, Ordering::Relaxed);
+        tracing::warn!(overlay_id = %self.id, %peer_id, "corrupted broadcast received");
+        self.reputation.report(peer_id, event);
+        if !self.is_private && self.is_banned(peer_id) {
+            self.remove_public_peer(peer_id);
+        }
+    }
+
+    /// See [`OverlayTuning::ban_reputation_score`]
+    fn is_banned(&self, peer_id: &adnl::NodeIdShort) -> bool {
+        self.reputation.score(peer_id) <= self.tuning.load().ban_reputation_score
     }
 
     fn is_broadcast_outdated(&self, date: u32) -> bool {
-        date + (self.options.broadcast_timeout_sec as u32) < now()
+        date + (self.tuning.load().broadcast_timeout_sec as u32) < self.clock.now()
+    }
+
+    /// Passes verified broadcast to its typed handler or to the raw broadcasts queue
+    fn push_received_broadcast(&self, broadcast: IncomingBroadcastInfo) {
+        if !self
+            .broadcast_handlers
+            .handle(broadcast.from, &broadcast.data)
+        {
+            self.received_broadcasts
+                .push(broadcast, self.clock.now_ms());
+        }
+    }
+
+    /// Drops incoming FEC broadcasts which received no parts during the timeout.
+    /// Only the broadcast id is kept until the broadcast GC
+    fn drop_expired_fec_transfers(self: &Arc<Self>, timeout_ms: u64) {
+        let now = self.clock.now_ms();
+
+        let mut expired = Vec::new();
+        for mut item in self.owned_broadcasts.iter_mut() {
+            let is_expired = match item.value().as_ref() {
+                OwnedBroadcast::Incoming(transfer) => {
+                    let updated_at = transfer.updated_at_ms.load(Ordering::Acquire);
+                    now.saturating_sub(updated_at) >= timeout_ms
+                        && !transfer.completed.swap(true, Ordering::AcqRel)
+                }
+                OwnedBroadcast::Other | OwnedBroadcast::Outgoing => false,
+            };
+            if is_expired {
+                // Closes the parts queue, so the receiver task drops the decoder
+                *item.value_mut() = Arc::new(OwnedBroadcast::Other);
+                expired.push(*item.key());
+            }
+        }
+
+        for broadcast_id in expired {
+            tracing::debug!(
+                overlay_id = %self.id,
+                broadcast_id = %DisplayBroadcastId(&broadcast_id),
+                "incoming fec broadcast expired"
+            );
+            self.fec_transfers_expired.fetch_add(1, Ordering::Relaxed);
+            self.spawn_broadcast_gc_task(broadcast_id);
+        }
     }
 
     fn spawn_broadcast_gc_task(self: &Arc<Self>, broadcast_id: BroadcastId) {
         let overlay = self.clone();
-        tokio::spawn(async move {
-            tokio::time::sleep(Duration::from_secs(overlay.options.broadcast_timeout_sec)).await;
-            overlay
-                .finished_broadcast_count
-                .fetch_add(1, Ordering::Release);
-            overlay.finished_broadcasts.push(broadcast_id);
-        });
+        self.layer
+            .tasks()
+            .spawn(&self.runtime, "overlay_broadcast_gc", async move {
+                overlay
+                    .clock
+                    .sleep(Duration::from_secs(
+                        overlay.tuning.load().broadcast_timeout_sec,
+                    ))
+                    .await;
+                overlay
+                    .finished_broadcast_count
+                    .fetch_add(1, Ordering::Release);
+                overlay.finished_broadcasts.push(broadcast_id);
+            });
     }
 }
 
 /// Overlay broadcast target
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub enum BroadcastTarget {
     /// Select N random peers from current neighbours
+    #[default]
     RandomNeighbours,
     /// Explicit neighbour ids
     Explicit(Arc<Vec<adnl::NodeIdShort>>),
 }
 
-impl Default for BroadcastTarget {
-    fn default() -> Self {
-        Self::RandomNeighbours
-    }
-}
-
 /// Filter for overlay peers exchange.
 pub trait ExistingPeersFilter: Send + Sync {
     fn contains(&self, peer_id: &adnl::NodeIdShort) -> bool;
@@ -1195,22 +2927,8 @@ where
     }
 }
 
-enum OwnedBroadcastTarget {
-    Neighbours(Vec<adnl::NodeIdShort>),
-    Explicit(Arc<Vec<adnl::NodeIdShort>>),
-}
-
-impl AsRef<[adnl::NodeIdShort]> for OwnedBroadcastTarget {
-    fn as_ref(&self) -> &[adnl::NodeIdShort] {
-        match self {
-            OwnedBroadcastTarget::Neighbours(neighbours) => neighbours.as_ref(),
-            OwnedBroadcastTarget::Explicit(neighbours) => neighbours.as_ref(),
-        }
-    }
-}
-
 /// Instant overlay metrics
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, serde::Serialize)]
 pub struct OverlayMetrics {
     pub owned_broadcasts_len: usize,
     pub finished_broadcasts_len: u32,
@@ -1218,17 +2936,91 @@ pub struct OverlayMetrics {
     pub known_peers: usize,
     pub neighbours: usize,
     pub received_broadcasts_data_len: usize,
+    /// Number of pending [`Overlay::wait_for_broadcast`] calls
     pub received_broadcasts_barrier_count: usize,
+    /// Max number of received broadcasts in the queue, `None` for the unbounded queue
+    pub received_broadcasts_capacity: Option<usize>,
+    /// Total number of received broadcasts which were dropped because the queue was full
+    #[serde(rename = "received_broadcasts_dropped_total")]
+    pub received_broadcasts_dropped: u64,
+    /// Time since the oldest broadcast in the queue was received
+    pub oldest_received_broadcast_age_ms: Option<u64>,
+    #[serde(rename = "unhandled_messages_total")]
+    pub unhandled_messages: u64,
+    /// New peers which were not taken yet, see [`Overlay::take_new_peers`]
+    pub received_peers_len: usize,
+    pub ignored_peers_len: usize,
+    /// Total number of own broadcast packets sent to the neighbours
+    #[serde(rename = "outgoing_broadcast_packets_total")]
+    pub outgoing_broadcast_packets: u64,
+    /// Own broadcast packets sent to the neighbours during the last complete second
+    #[serde(rename = "outgoing_broadcast_packets_per_sec")]
+    pub outgoing_broadcast_rate: u32,
+    /// Number of cached query answers
+    pub answer_cache_len: usize,
+    /// Total size of cached query answers in bytes
+    #[serde(rename = "answer_cache_size_bytes")]
+    pub answer_cache_size: usize,
+    /// Total number of queries answered from the cache
+    #[serde(rename = "answer_cache_hits_total")]
+    pub answer_cache_hits: u64,
+    /// Number of retained broadcasts, see [`OverlayOptions::broadcast_history`]
+    pub broadcast_history_len: usize,
+    /// Total size of retained broadcasts in bytes
+    #[serde(rename = "broadcast_history_size_bytes")]
+    pub broadcast_history_size: usize,
+    /// Number of sources which broadcasts are not forwarded because of the storm
+    pub throttled_broadcast_sources: usize,
+    /// Total number of broadcast packets which were not forwarded because of the storm
+    #[serde(rename = "suppressed_broadcast_forwards_total")]
+    pub suppressed_broadcast_forwards: u64,
+    /// Total number of broadcasts which were not decoded by the typed handlers,
+    /// see [`Overlay::broadcast_decode_errors`]
+    #[serde(rename = "broadcast_decode_errors_total")]
+    pub broadcast_decode_errors: u64,
+    /// Total number of incoming broadcasts (or FEC broadcast parts) which data didn't
+    /// match the signature or the declared hash
+    #[serde(rename = "broadcasts_corrupted_total")]
+    pub broadcasts_corrupted: u64,
+    /// Total number of ordinary broadcasts which were received again and ignored
+    /// (e.g. forwarded back by the neighbours)
+    #[serde(rename = "broadcasts_duplicated_total")]
+    pub broadcasts_duplicated: u64,
+    /// Total number of own broadcasts (or FEC broadcast parts) which were forwarded back
+    /// by the neighbours and ignored
+    #[serde(rename = "own_broadcast_echoes_total")]
+    pub own_broadcast_echoes: u64,
+    /// Number of incoming FEC broadcasts which are still being decoded
+    pub incoming_fec_transfers: usize,
+    /// Total number of incoming FEC broadcasts which were dropped by the timeout,
+    /// see [`OverlayTuning::fec_transfer_timeout_ms`]
+    #[serde(rename = "fec_transfers_expired_total")]
+    pub fec_transfers_expired: u64,
+    /// Number of own broadcast packets waiting to be sent
+    pub originated_queue_len: usize,
+    /// Number of relayed broadcast packets waiting to be sent
+    pub relayed_queue_len: usize,
+    /// Total number of own broadcast packets dropped because the queue was full,
+    /// see [`OverlayTuning::originated_queue_capacity`]
+    #[serde(rename = "originated_packets_dropped_total")]
+    pub originated_packets_dropped: u64,
+    /// Total number of relayed broadcast packets dropped because the queue was full,
+    /// see [`OverlayTuning::relayed_queue_capacity`]
+    #[serde(rename = "relayed_packets_dropped_total")]
+    pub relayed_packets_dropped: u64,
 }
 
-fn process_fec_broadcast(
-    decoder: &mut RaptorQDecoder,
-    broadcast: BroadcastFec,
-) -> Result<Option<Vec<u8>>> {
-    let broadcast_id = &broadcast.data_hash;
+/// SHA256 of the serialized query with all its prefixes.
+///
+/// Unlike the random ADNL query id, it is the same on both sides,
+/// so it can be used to match the query in the logs of both nodes.
+pub fn compute_query_id(query_data: &[u8]) -> [u8; 32] {
+    sha2::Sha256::digest(query_data).into()
+}
 
+fn verify_fec_broadcast(broadcast: &BroadcastFec) -> Result<()> {
     let broadcast_to_sign = &make_fec_part_to_sign(
-        broadcast_id,
+        &broadcast.data_hash,
         broadcast.data_size,
         broadcast.date,
         broadcast.flags,
@@ -1241,15 +3033,35 @@ fn process_fec_broadcast(
             None
         },
     );
-    broadcast
+    match broadcast
         .node_id
-        .verify(broadcast_to_sign, &broadcast.signature)?;
+        .verify(broadcast_to_sign, &broadcast.signature)
+    {
+        Ok(()) => Ok(()),
+        Err(_) => Err(OverlayError::CorruptedBroadcastPart.into()),
+    }
+}
+
+/// Feeds verified `(seqno, data_size, data)` parts to the decoder and checks the decoded data
+fn decode_fec_parts(
+    decoder: &mut RaptorQDecoder,
+    parts: Vec<(u32, u32, Vec<u8>)>,
+    broadcast_id: &BroadcastId,
+) -> Result<Option<Vec<u8>>> {
+    for (seqno, data_size, data) in parts {
+        if data_size != decoder.params().total_len {
+            return Err(OverlayError::DataSizeMismatch.into());
+        }
+
+        let result = match decoder.decode(seqno, data)? {
+            Some(result) => result,
+            None => continue,
+        };
 
-    match decoder.decode(broadcast.seqno, broadcast.data) {
-        Some(result) if result.len() != broadcast.data_size as usize => {
-            Err(OverlayError::DataSizeMismatch.into())
+        if result.len() != data_size as usize {
+            return Err(OverlayError::DataSizeMismatch.into());
         }
-        Some(result) => match compression::decompress(&result) {
+        return match compression::decompress(&result) {
             Some(decompressed)
                 if sha2::Sha256::digest(&decompressed).as_slice() == broadcast_id =>
             {
@@ -1263,14 +3075,14 @@ fn process_fec_broadcast(
                     Err(OverlayError::DataHashMismatch.into())
                 }
             }
-        },
-        None => Ok(None),
+        };
     }
+    Ok(None)
 }
 
 #[derive(TlWrite)]
 #[tl(boxed, id = "overlay.broadcast.toSign", scheme = "scheme.tl")]
-struct OverlayBroadcastToSign {
+pub(crate) struct OverlayBroadcastToSign {
     hash: [u8; 32],
     date: u32,
 }
@@ -1281,27 +3093,24 @@ impl OverlayBroadcastToSign {
     }
 }
 
-fn make_broadcast_to_sign(
+pub(crate) fn make_broadcast_to_sign(
     data: &[u8],
     date: u32,
     source: Option<&adnl::NodeIdShort>,
 ) -> OverlayBroadcastToSign {
-    const BROADCAST_ID: u32 = tl_proto::id!("overlay.broadcast.id", scheme = "scheme.tl");
-
-    let mut broadcast_hash = sha2::Sha256::new();
-    broadcast_hash.update(BROADCAST_ID.to_le_bytes());
-    broadcast_hash.update(source.map(adnl::NodeIdShort::as_slice).unwrap_or(&[0; 32]));
-    broadcast_hash.update(sha2::Sha256::digest(data).as_slice());
-    broadcast_hash.update(BROADCAST_FLAG_ANY_SENDER.to_le_bytes());
-    let broadcast_hash = broadcast_hash.finalize();
+    let broadcast_hash = tl_proto::hash(OverlayBroadcastId {
+        src: source.map(adnl::NodeIdShort::as_slice).unwrap_or(&[0; 32]),
+        data_hash: sha2::Sha256::digest(data).into(),
+        flags: BROADCAST_FLAG_ANY_SENDER,
+    });
 
     OverlayBroadcastToSign {
-        hash: broadcast_hash.into(),
+        hash: broadcast_hash,
         date,
     }
 }
 
-fn make_fec_part_to_sign(
+pub(crate) fn make_fec_part_to_sign(
     data_hash: &[u8; 32],
     data_size: u32,
     date: u32,
@@ -1311,37 +3120,58 @@ fn make_fec_part_to_sign(
     seqno: u32,
     source: Option<adnl::NodeIdShort>,
 ) -> OverlayBroadcastToSign {
-    const BROADCAST_FEC_ID: u32 = tl_proto::id!("overlay.broadcastFec.id", scheme = "scheme.tl");
-    const BROADCAST_FEC_PART_ID: u32 =
-        tl_proto::id!("overlay.broadcastFec.partId", scheme = "scheme.tl");
-
-    let mut broadcast_hash = sha2::Sha256::new();
-    broadcast_hash.update(BROADCAST_FEC_ID.to_le_bytes());
-    broadcast_hash.update(
-        source
+    let broadcast_hash = tl_proto::hash(OverlayBroadcastFecId {
+        src: source
             .as_ref()
             .map(adnl::NodeIdShort::as_slice)
             .unwrap_or(&[0; 32]),
-    );
-    broadcast_hash.update(tl_proto::hash(params));
-    broadcast_hash.update(data_hash);
-    broadcast_hash.update(data_size.to_le_bytes());
-    broadcast_hash.update(flags.to_le_bytes());
-    let broadcast_hash = broadcast_hash.finalize();
-
-    let mut part_hash = sha2::Sha256::new();
-    part_hash.update(BROADCAST_FEC_PART_ID.to_le_bytes());
-    part_hash.update(broadcast_hash);
-    part_hash.update(sha2::Sha256::digest(part).as_slice());
-    part_hash.update(seqno.to_le_bytes());
-    let part_hash = part_hash.finalize();
+        fec_type: tl_proto::hash(params),
+        data_hash,
+        size: data_size,
+        flags,
+    });
+
+    let part_hash = tl_proto::hash(OverlayBroadcastFecPartId {
+        broadcast_hash,
+        data_hash: sha2::Sha256::digest(part).into(),
+        seqno,
+    });
 
     OverlayBroadcastToSign {
-        hash: part_hash.into(),
+        hash: part_hash,
         date,
     }
 }
 
+/// Hashed directly (without intermediate serialization) to get broadcast hash
+#[derive(TlWrite)]
+#[tl(boxed, id = "overlay.broadcast.id", scheme = "scheme.tl")]
+struct OverlayBroadcastId<'a> {
+    src: &'a [u8; 32],
+    data_hash: [u8; 32],
+    flags: u32,
+}
+
+/// Hashed directly (without intermediate serialization) to get FEC broadcast hash
+#[derive(TlWrite)]
+#[tl(boxed, id = "overlay.broadcastFec.id", scheme = "scheme.tl")]
+struct OverlayBroadcastFecId<'a> {
+    src: &'a [u8; 32],
+    fec_type: [u8; 32],
+    data_hash: &'a [u8; 32],
+    size: u32,
+    flags: u32,
+}
+
+/// Hashed directly (without intermediate serialization) to get FEC broadcast part hash
+#[derive(TlWrite)]
+#[tl(boxed, id = "overlay.broadcastFec.partId", scheme = "scheme.tl")]
+struct OverlayBroadcastFecPartId {
+    broadcast_hash: [u8; 32],
+    data_hash: [u8; 32],
+    seqno: u32,
+}
+
 /// Received overlay broadcast
 pub struct IncomingBroadcastInfo {
     pub packets: u32,
@@ -1361,7 +3191,8 @@ struct IncomingFecTransfer {
     history: PacketsHistory,
     broadcast_tx: BroadcastFecTx,
     source: adnl::NodeIdShort,
-    updated_at: UpdatedAt,
+    /// Time of the last received part
+    updated_at_ms: AtomicU64,
 }
 
 struct OutgoingFecTransfer {
@@ -1372,11 +3203,14 @@ struct OutgoingFecTransfer {
 
 enum OwnedBroadcast {
     Other,
+    Outgoing,
     Incoming(IncomingFecTransfer),
 }
 
 #[derive(Debug)]
 struct BroadcastFec {
+    /// Neighbour which sent this part
+    peer_id: adnl::NodeIdShort,
     node_id: adnl::NodeIdFull,
     data_hash: BroadcastId,
     data_size: u32,
@@ -1411,7 +3245,7 @@ impl std::fmt::Display for DisplayBroadcastId<'_> {
     }
 }
 
-type BroadcastId = [u8; 32];
+pub(super) type BroadcastId = [u8; 32];
 
 #[derive(thiserror::Error, Debug)]
 enum OverlayError {
@@ -1421,6 +3255,1763 @@ enum OverlayError {
     DataSizeMismatch,
     #[error("Data hash mismatch")]
     DataHashMismatch,
+    #[error("Broadcast data doesn't match the signature")]
+    CorruptedBroadcast,
+    #[error("FEC broadcast part doesn't match the signature")]
+    CorruptedBroadcastPart,
+    #[error("Too big FEC broadcast")]
+    TooBigBroadcast,
+    #[error("Overlay id mismatch")]
+    OverlayIdMismatch,
+    #[error("Unexpected broadcast type")]
+    UnexpectedBroadcast,
+}
+
+#[derive(thiserror::Error, Debug)]
+enum OverlayOptionsError {
+    #[error("`max_neighbours` must be in range 1..={}, got {0}", MAX_OVERLAY_PEERS)]
+    InvalidMaxNeighbours(u32),
+    #[error("`{0}` must not be zero")]
+    ZeroValue(&'static str),
+    #[error(
+        "`overlay_peers_timeout_ms` ({timeout}) is less than `broadcast_gc_interval_ms` ({gc_interval})"
+    )]
+    PeersTimeoutTooSmall { timeout: u64, gc_interval: u64 },
+    #[error(
+        "`max_ordinary_broadcast_len` ({0}) is smaller than a single FEC symbol ({})",
+        rldp::MAX_TRANSMISSION_UNIT
+    )]
+    OrdinaryBroadcastTooSmall(usize),
+}
+
+/// See [`Overlay::rotate_key`]
+struct RotatedKey {
+    key: Arc<adnl::Key>,
+    trusted_until: Instant,
+}
+
+#[derive(Default)]
+struct BroadcastRate {
+    /// Unix timestamp of the current window
+    second: u32,
+    count: u32,
+}
+
+#[derive(Default)]
+struct PacketRate {
+    /// Unix timestamp of the current window
+    second: u32,
+    count: u32,
+    /// Number of packets during the previous window
+    previous: u32,
+}
+
+impl PacketRate {
+    fn add(&mut self, now: u32, packets: u32) {
+        if self.second != now {
+            self.previous = if self.second + 1 == now {
+                self.count
+            } else {
+                0
+            };
+            self.second = now;
+            self.count = 0;
+        }
+        self.count = self.count.saturating_add(packets);
+    }
+
+    fn last_second(&self, now: u32) -> u32 {
+        if self.second == now {
+            self.previous
+        } else if self.second + 1 == now {
+            self.count
+        } else {
+            0
+        }
+    }
 }
 
-const BROADCAST_FLAG_ANY_SENDER: u32 = 1; // Any sender
+pub(crate) const BROADCAST_FLAG_ANY_SENDER: u32 = 1; // Any sender
+
+/// Max size of the incoming FEC broadcast data
+const MAX_FEC_BROADCAST_LEN: u32 = 16 << 20;
+
+/// Answer cache size for public overlays if it is not specified explicitly
+const DEFAULT_ANSWER_CACHE_MAX_SIZE: usize = 1 << 20;
+
+/// Max number of broadcast ids in the `overlay.getBroadcastList` answer
+const MAX_BROADCAST_LIST_LEN: usize = 256;
+
+/// Max number of nodes in the `overlay.getRandomPeers` query and answer
+const MAX_RANDOM_PEERS_NODES: usize = 5;
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+
+    use super::*;
+    use crate::util::fixtures::{add_overlay_node, add_virtual_node};
+
+    fn sha256(data: &[u8]) -> [u8; 32] {
+        sha2::Sha256::digest(data).into()
+    }
+
+    #[test]
+    fn default_options_snapshot() {
+        let options = serde_json::to_value(OverlayOptions::default()).unwrap();
+        assert_eq!(
+            options,
+            serde_json::json!({
+                "max_neighbours": 200,
+                "decode_threads": 2,
+                "received_broadcasts_capacity": 1024,
+                "broadcast_history": 0,
+                "broadcast_history_max_bytes": 1048576,
+                "max_broadcast_log": 1000,
+                "broadcast_gc_interval_ms": 1000,
+                "overlay_peers_timeout_ms": 60000,
+                "max_ordinary_broadcast_len": 768,
+                "broadcast_target_count": 5,
+                "secondary_broadcast_target_count": 3,
+                "secondary_fec_broadcast_target_count": 3,
+                "fec_broadcast_wave_len": 20,
+                "fec_broadcast_wave_interval_ms": 10,
+                "broadcast_spread_duration_ms": 5,
+                "broadcast_timeout_sec": 60,
+                "fec_transfer_timeout_ms": 60000,
+                "force_compression": false,
+                "broadcast_storm_threshold": 1000,
+                "storm_cooldown_ms": 30000,
+                "answer_cache_max_size": null,
+                "answer_cache_max_ttl_ms": 10000,
+                "stored_peer_ttl_sec": 604800,
+                "key_rotation_grace_period_ms": 60000,
+                "ban_reputation_score": -50,
+                "include_self_in_random_peers": null,
+                "originated_queue_capacity": 4096,
+                "relayed_queue_capacity": 4096,
+                "low_peers_threshold": 0,
+            })
+        );
+
+        // Flat configs are still accepted
+        let parsed: OverlayOptions =
+            serde_json::from_str(r#"{"max_neighbours":10,"broadcast_timeout_sec":5}"#).unwrap();
+        assert_eq!(parsed.max_neighbours, 10);
+        assert_eq!(parsed.tuning.broadcast_timeout_sec, 5);
+        assert_eq!(parsed.tuning.max_broadcast_log, 1000);
+    }
+
+    #[test]
+    fn options_validation() {
+        OverlayOptions::default().validate().unwrap();
+
+        let check = |f: fn(&mut OverlayOptions), expected: &str| {
+            let mut options = OverlayOptions::default();
+            f(&mut options);
+            let err = options.validate().unwrap_err().to_string();
+            assert!(err.contains(expected), "unexpected error: {err}");
+        };
+        check(|o| o.max_neighbours = 0, "max_neighbours");
+        check(|o| o.decode_threads = 0, "decode_threads");
+        check(
+            |o| o.received_broadcasts_capacity = Some(0),
+            "received_broadcasts_capacity",
+        );
+        check(
+            |o| o.max_neighbours = MAX_OVERLAY_PEERS + 1,
+            "max_neighbours",
+        );
+        check(
+            |o| o.tuning.max_ordinary_broadcast_len = 100,
+            "FEC symbol (768)",
+        );
+        check(
+            |o| o.tuning.broadcast_gc_interval_ms = 0,
+            "broadcast_gc_interval_ms",
+        );
+        check(
+            |o| o.tuning.overlay_peers_timeout_ms = 10,
+            "`overlay_peers_timeout_ms` (10)",
+        );
+        check(
+            |o| o.tuning.fec_broadcast_wave_len = 0,
+            "fec_broadcast_wave_len",
+        );
+        check(|o| o.tuning.storm_cooldown_ms = 0, "storm_cooldown_ms");
+
+        // Disabled throttling doesn't need the cooldown
+        let mut options = OverlayOptions::default();
+        options.tuning.broadcast_storm_threshold = 0;
+        options.tuning.storm_cooldown_ms = 0;
+        options.validate().unwrap();
+    }
+
+    // Serializes into an intermediate buffer and hashes it
+    fn hash_serialized<T: TlWrite<Repr = tl_proto::Boxed>>(data: T) -> [u8; 32] {
+        sha256(&tl_proto::serialize(data))
+    }
+
+    #[test]
+    fn streaming_hashes_are_the_same() {
+        let mut rng = rand::thread_rng();
+        for _ in 0..100 {
+            let source = rng.gen::<bool>().then(adnl::NodeIdShort::random);
+            let data: Vec<u8> = (0..rng.gen_range(0..1024)).map(|_| rng.gen()).collect();
+            let date = rng.gen();
+
+            // Ordinary broadcast
+            let broadcast_to_sign = make_broadcast_to_sign(&data, date, source.as_ref());
+            let mut expected = Vec::new();
+            expected.extend_from_slice(&u32::to_le_bytes(tl_proto::id!(
+                "overlay.broadcast.id",
+                scheme = "scheme.tl"
+            )));
+            expected.extend_from_slice(source.as_ref().map(|id| id.as_slice()).unwrap_or(&[0; 32]));
+            expected.extend_from_slice(&sha256(&data));
+            expected.extend_from_slice(&BROADCAST_FLAG_ANY_SENDER.to_le_bytes());
+            assert_eq!(broadcast_to_sign.hash, sha256(&expected));
+            assert_eq!(
+                broadcast_to_sign.compute_broadcast_id(),
+                hash_serialized(&broadcast_to_sign)
+            );
+
+            // FEC broadcast part
+            let data_hash = sha256(&data);
+            let params = proto::rldp::RaptorQFecType {
+                total_len: rng.gen(),
+                packet_len: rng.gen(),
+                packet_count: rng.gen(),
+            };
+            let (data_size, flags, seqno) = (rng.gen(), rng.gen(), rng.gen());
+            let part_to_sign = make_fec_part_to_sign(
+                &data_hash, data_size, date, flags, &params, &data, seqno, source,
+            );
+
+            let mut broadcast_id = Vec::new();
+            broadcast_id.extend_from_slice(&u32::to_le_bytes(tl_proto::id!(
+                "overlay.broadcastFec.id",
+                scheme = "scheme.tl"
+            )));
+            broadcast_id
+                .extend_from_slice(source.as_ref().map(|id| id.as_slice()).unwrap_or(&[0; 32]));
+            broadcast_id.extend_from_slice(&hash_serialized(params));
+            broadcast_id.extend_from_slice(&data_hash);
+            broadcast_id.extend_from_slice(&data_size.to_le_bytes());
+            broadcast_id.extend_from_slice(&flags.to_le_bytes());
+
+            let mut part_id = Vec::new();
+            part_id.extend_from_slice(&u32::to_le_bytes(tl_proto::id!(
+                "overlay.broadcastFec.partId",
+                scheme = "scheme.tl"
+            )));
+            part_id.extend_from_slice(&sha256(&broadcast_id));
+            part_id.extend_from_slice(&sha256(&data));
+            part_id.extend_from_slice(&seqno.to_le_bytes());
+            assert_eq!(part_to_sign.hash, sha256(&part_id));
+
+            // Overlay ids
+            let overlay_id = super::super::IdFull::for_workchain_overlay(rng.gen(), &rng.gen());
+            assert_eq!(
+                overlay_id.compute_short_id().as_slice(),
+                &hash_serialized(everscale_crypto::tl::PublicKey::Overlay {
+                    name: overlay_id.as_slice()
+                })
+            );
+        }
+    }
+
+    #[test]
+    fn fec_parts_data_size_is_checked() {
+        let data = vec![0xaa; 4000];
+        let broadcast_id = sha256(&data);
+        let encode = |count: usize| {
+            let mut encoder = RaptorQEncoder::with_data(&data);
+            let decoder = RaptorQDecoder::with_params(*encoder.params()).unwrap();
+            let parts = (0..count as u32)
+                .map(|mut seqno| {
+                    let part = encoder.encode(&mut seqno).unwrap();
+                    (seqno, data.len() as u32, part)
+                })
+                .collect::<Vec<_>>();
+            (decoder, parts)
+        };
+
+        let (mut decoder, parts) = encode(8);
+        assert_eq!(
+            decode_fec_parts(&mut decoder, parts, &broadcast_id).unwrap(),
+            Some(data.clone())
+        );
+
+        // Only the first part of the batch matches the data size
+        let (mut decoder, mut parts) = encode(8);
+        parts[1].1 += 1;
+        assert!(matches!(
+            decode_fec_parts(&mut decoder, parts, &broadcast_id)
+                .unwrap_err()
+                .downcast_ref::<OverlayError>(),
+            Some(OverlayError::DataSizeMismatch)
+        ));
+    }
+
+    #[tokio::test]
+    async fn query_ids_known_answer() {
+        const EMPTY_PEERS_QUERY_ID: &str =
+            "9d4d7fbd0b6295696e4e7c1fec2ff0b7db17efd9d1c70434e584bc9252db32a2";
+        const SINGLE_PEER_QUERY_ID: &str =
+            "e74f669558ae92ffbd6c8f62e2ec2934e1fd9e7e2580b554abdb1546fdbb4d1b";
+
+        let network = adnl::VirtualNetwork::new(0);
+        let (_adnl, _node, overlay) = add_overlay_node(
+            &network,
+            &IdShort::from([0x11; 32]),
+            Default::default(),
+            Default::default(),
+        );
+
+        let mut query = proto::rpc::OverlayGetRandomPeersOwned {
+            peers: proto::overlay::NodesOwned {
+                nodes: Default::default(),
+            },
+        };
+        assert_eq!(
+            hex::encode(overlay.compute_query_id(&query)),
+            EMPTY_PEERS_QUERY_ID
+        );
+
+        query.peers.nodes.push(proto::overlay::NodeOwned {
+            id: everscale_crypto::tl::PublicKeyOwned::Ed25519 { key: [0x22; 32] },
+            overlay: [0x11; 32],
+            version: 1700000000,
+            signature: vec![0x33; 64].into(),
+        });
+        assert_eq!(
+            hex::encode(overlay.compute_query_id(&query)),
+            SINGLE_PEER_QUERY_ID
+        );
+
+        // Raw bytes are hashed as is
+        let mut query_data = tl_proto::serialize(proto::rpc::OverlayQuery {
+            overlay: &[0x11; 32],
+        });
+        query.write_to(&mut query_data);
+        assert_eq!(
+            hex::encode(compute_query_id(&query_data)),
+            SINGLE_PEER_QUERY_ID
+        );
+    }
+
+    #[tokio::test]
+    async fn broadcast_storm_events() {
+        let clock = ManualClock::new(1000);
+        let network = adnl::VirtualNetwork::with_clock(0, Arc::new(clock.clone()));
+        let adnl = add_virtual_node(&network, Default::default());
+        let node = super::super::Node::new(adnl.clone(), 0).unwrap();
+
+        let mut events = adnl.events();
+        let overlay_id =
+            super::super::IdFull::for_workchain_overlay(0, &[0; 32]).compute_short_id();
+        let (overlay, _) = node
+            .add_public_overlay(
+                &overlay_id,
+                OverlayOptions {
+                    tuning: OverlayTuning {
+                        broadcast_storm_threshold: 3,
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        assert!(matches!(
+            events.try_recv().unwrap(),
+            NetworkEvent::OverlayAdded { overlay_id: id, .. } if id == overlay_id
+        ));
+
+        // Only one event per second
+        for _ in 0..2 {
+            for _ in 0..5 {
+                overlay.count_incoming_broadcast(&adnl);
+            }
+            assert!(matches!(
+                events.try_recv().unwrap(),
+                NetworkEvent::BroadcastStorm {
+                    source: None,
+                    broadcasts: 3,
+                    ..
+                }
+            ));
+            assert!(events.try_recv().is_err());
+            clock.advance(Duration::from_secs(1));
+        }
+
+        assert!(node.remove_overlay(&overlay_id));
+        assert!(!node.remove_overlay(&overlay_id));
+        assert!(matches!(
+            events.try_recv().unwrap(),
+            NetworkEvent::OverlayRemoved { overlay_id: id, .. } if id == overlay_id
+        ));
+    }
+
+    #[tokio::test]
+    async fn low_peers_events() {
+        let network = adnl::VirtualNetwork::new(0);
+        let adnl = add_virtual_node(&network, Default::default());
+        let node = super::super::Node::new(adnl.clone(), 0).unwrap();
+
+        let overlay_id =
+            super::super::IdFull::for_workchain_overlay(0, &[0; 32]).compute_short_id();
+        let (overlay, _) = node
+            .add_public_overlay(
+                &overlay_id,
+                OverlayOptions {
+                    tuning: OverlayTuning {
+                        low_peers_threshold: 2,
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        let mut events = adnl.events();
+
+        let peers = [(); 3].map(|_| adnl::NodeIdShort::random());
+        overlay.known_peers.extend(peers);
+        assert_eq!(overlay.active_public_peer_count(), 3);
+
+        // Unknown peers don't change the count
+        assert!(overlay.remove_public_peer(&adnl::NodeIdShort::random()));
+        assert!(overlay.remove_public_peer(&peers[0]));
+        assert!(events.try_recv().is_err());
+
+        // Event is emitted once the count drops below the threshold
+        assert!(overlay.remove_public_peer(&peers[1]));
+        assert!(matches!(
+            events.try_recv().unwrap(),
+            NetworkEvent::OverlayPeersLow { overlay_id: id, peers: 1, .. } if id == overlay_id
+        ));
+        assert!(!overlay.remove_public_peer(&peers[1]));
+        assert!(overlay.remove_public_peer(&peers[2]));
+        assert!(events.try_recv().is_err());
+        assert_eq!(overlay.active_public_peer_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn stored_peers_import() {
+        const NOW: u32 = 1_000_000;
+
+        let clock = ManualClock::new(NOW);
+        let network = adnl::VirtualNetwork::with_clock(0, Arc::new(clock));
+        let overlay_id =
+            super::super::IdFull::for_workchain_overlay(0, &[0; 32]).compute_short_id();
+        let (left_adnl, _, left) = add_overlay_node(
+            &network,
+            &overlay_id,
+            Default::default(),
+            Default::default(),
+        );
+        let (right_adnl, _, right) = add_overlay_node(
+            &network,
+            &overlay_id,
+            Default::default(),
+            Default::default(),
+        );
+
+        // Fresh and expired peers
+        let addr = "1.2.3.4:30303".parse::<SocketAddrV4>().unwrap();
+        let fresh = adnl::Key::from_bytes(rand::random());
+        let expired = adnl::Key::from_bytes(rand::random());
+        for (key, version) in [(&fresh, NOW - 10), (&expired, NOW - 700000)] {
+            let node = sign_overlay_node(key, &overlay_id, version);
+            left.add_public_peer(&left_adnl, addr, node.as_equivalent_ref())
+                .unwrap()
+                .unwrap();
+        }
+
+        // Add corrupted entries
+        let exported = left.export_peers_tl(&left_adnl);
+        let mut stored = tl_proto::deserialize::<proto::overlay::StoredPeers>(&exported).unwrap();
+        assert_eq!(stored.peers.len(), 2);
+
+        let mut tampered =
+            sign_overlay_node(&adnl::Key::from_bytes(rand::random()), &overlay_id, NOW);
+        tampered.version += 1;
+        let tampered = tl_proto::serialize(proto::overlay::StoredPeer {
+            ip: 1,
+            port: 2,
+            node: tampered.as_equivalent_ref(),
+        });
+        stored.peers.push(&tampered);
+        stored.peers.push(&[1, 2, 3]);
+        let data = tl_proto::serialize(stored);
+
+        let imported = right.import_peers_tl(&right_adnl, &data).unwrap();
+        assert_eq!(imported, [*fresh.id()]);
+        assert_eq!(
+            right_adnl.get_peer_address(right.overlay_key().id(), fresh.id()),
+            Some(addr)
+        );
+        assert!(right.is_known_peer(fresh.id()));
+        assert!(!right.is_known_peer(expired.id()));
+
+        // Peers of the other overlay are not imported
+        let other_id = super::super::IdFull::for_workchain_overlay(1, &[0; 32]).compute_short_id();
+        let (other_adnl, _, other) =
+            add_overlay_node(&network, &other_id, Default::default(), Default::default());
+        assert!(other.import_peers_tl(&other_adnl, &data).is_err());
+        assert!(right.import_peers_tl(&right_adnl, &[1, 2, 3]).is_err());
+    }
+
+    #[tokio::test]
+    async fn random_peers_answer() {
+        let network = adnl::VirtualNetwork::new(0);
+        let keystore = adnl::Keystore::builder()
+            .with_tagged_keys([(rand::random(), 0), (rand::random(), 1)])
+            .unwrap()
+            .build();
+        let adnl = network.add_node(keystore, Default::default(), None);
+        let node = super::super::Node::new(adnl.clone(), 0).unwrap();
+
+        let overlay_id =
+            super::super::IdFull::for_workchain_overlay(0, &[0; 32]).compute_short_id();
+        let (overlay, _) = node
+            .add_public_overlay(&overlay_id, Default::default())
+            .unwrap();
+        let (private, _) = node
+            .add_private_overlay(
+                &super::super::IdFull::for_workchain_overlay(1, &[0; 32]).compute_short_id(),
+                adnl.key_by_tag(1).unwrap().clone(),
+                &[],
+                Default::default(),
+            )
+            .unwrap();
+
+        for i in 0..10u8 {
+            let key = adnl::Key::from_bytes(rand::random());
+            let addr = SocketAddrV4::new(std::net::Ipv4Addr::new(1, 2, 3, i + 1), 30303);
+            let node = sign_overlay_node(&key, &overlay_id, overlay.clock.now());
+            overlay
+                .add_public_peer(&adnl, addr, node.as_equivalent_ref())
+                .unwrap()
+                .unwrap();
+        }
+
+        let query = || proto::rpc::OverlayGetRandomPeers {
+            peers: proto::overlay::Nodes {
+                nodes: Default::default(),
+            },
+        };
+        let is_self = |node: &proto::overlay::NodeOwned, key: &adnl::Key| {
+            node.id.as_equivalent_ref() == key.full_id().as_tl()
+        };
+
+        // Own node goes first and is signed with the current key
+        let check_self_included = || {
+            let key = overlay.overlay_key();
+            let nodes = overlay.process_get_random_peers(query()).nodes;
+            assert_eq!(nodes.len(), MAX_RANDOM_PEERS_NODES);
+            assert!(is_self(&nodes[0], &key));
+            let peer_id = validate_overlay_node(
+                &overlay_id,
+                &adnl::NodeIdShort::default(),
+                &nodes[0].as_equivalent_ref(),
+                overlay.clock.now(),
+                0,
+            )
+            .unwrap();
+            assert_eq!(&peer_id.compute_short_id(), key.id());
+            assert!(nodes[1..].iter().all(|node| !is_self(node, &key)));
+        };
+        check_self_included();
+        overlay
+            .rotate_key(&adnl, adnl.key_by_tag(1).unwrap().clone())
+            .unwrap();
+        check_self_included();
+
+        overlay
+            .update_tuning(|tuning| tuning.include_self_in_random_peers = Some(false))
+            .unwrap();
+        let nodes = overlay.process_get_random_peers(query()).nodes;
+        assert_eq!(nodes.len(), MAX_RANDOM_PEERS_NODES);
+        assert!(nodes
+            .iter()
+            .all(|node| !is_self(node, &overlay.overlay_key())));
+
+        // Private overlays don't share the own node by default
+        assert!(private.process_get_random_peers(query()).nodes.is_empty());
+        private
+            .update_tuning(|tuning| tuning.include_self_in_random_peers = Some(true))
+            .unwrap();
+        let nodes = private.process_get_random_peers(query()).nodes;
+        assert_eq!(nodes.len(), 1);
+        assert!(is_self(&nodes[0], &private.overlay_key()));
+
+        // Concurrent updates are not lost
+        let max_broadcast_log = private.tuning().max_broadcast_log;
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for _ in 0..1000 {
+                        private
+                            .update_tuning(|tuning| tuning.max_broadcast_log += 1)
+                            .unwrap();
+                    }
+                });
+            }
+        });
+        assert_eq!(private.tuning().max_broadcast_log, max_broadcast_log + 4000);
+        assert!(private.tuning().include_self_in_random_peers.unwrap());
+    }
+
+    #[tokio::test]
+    async fn external_peers_validation() {
+        const NOW: u32 = 1_000_000;
+
+        let clock = ManualClock::new(NOW);
+        let network = adnl::VirtualNetwork::with_clock(0, Arc::new(clock));
+        let overlay_id =
+            super::super::IdFull::for_workchain_overlay(0, &[0; 32]).compute_short_id();
+        let other_id = super::super::IdFull::for_workchain_overlay(1, &[0; 32]).compute_short_id();
+        let (adnl, _node, overlay) = add_overlay_node(
+            &network,
+            &overlay_id,
+            Default::default(),
+            Default::default(),
+        );
+
+        let addr = "1.2.3.4:30303".parse::<SocketAddrV4>().unwrap();
+        let key = || adnl::Key::from_bytes(rand::random());
+
+        let valid = key();
+        let valid_node = sign_overlay_node(&valid, &overlay_id, NOW - 10);
+        let other_overlay_node = sign_overlay_node(&key(), &other_id, NOW);
+        let mut tampered_node = sign_overlay_node(&key(), &overlay_id, NOW);
+        tampered_node.version += 1;
+        let outdated_node = sign_overlay_node(&key(), &overlay_id, NOW - 700000);
+        let mismatched_node = sign_overlay_node(&key(), &overlay_id, NOW);
+        let bad_addr = key();
+        let bad_addr_node = sign_overlay_node(&bad_addr, &overlay_id, NOW);
+        let local_node = overlay.sign_local_node();
+
+        fn peer(
+            peer_id: Option<adnl::NodeIdShort>,
+            addr: SocketAddrV4,
+            node: &proto::overlay::NodeOwned,
+        ) -> ExternalPeer<'_> {
+            ExternalPeer {
+                peer_id,
+                addr,
+                node: node.as_equivalent_ref(),
+            }
+        }
+        let report = overlay
+            .add_external_peers(
+                &adnl,
+                vec![
+                    peer(Some(*valid.id()), addr, &valid_node),
+                    peer(None, addr, &other_overlay_node),
+                    peer(None, addr, &tampered_node),
+                    peer(None, addr, &outdated_node),
+                    peer(Some(*valid.id()), addr, &mismatched_node),
+                    peer(None, "0.0.0.0:30303".parse().unwrap(), &bad_addr_node),
+                    peer(None, addr, &local_node),
+                    peer(None, "1.2.3.5:30303".parse().unwrap(), &valid_node),
+                ],
+            )
+            .unwrap();
+
+        assert_eq!(
+            report.entries,
+            [
+                ExternalPeerStatus::Added(*valid.id()),
+                ExternalPeerStatus::Rejected(PeerRejectReason::OverlayMismatch),
+                ExternalPeerStatus::Rejected(PeerRejectReason::InvalidSignature),
+                ExternalPeerStatus::Rejected(PeerRejectReason::Outdated),
+                ExternalPeerStatus::Rejected(PeerRejectReason::IdMismatch),
+                ExternalPeerStatus::Rejected(PeerRejectReason::InvalidAddress),
+                ExternalPeerStatus::Rejected(PeerRejectReason::LocalNode),
+                ExternalPeerStatus::Updated(*valid.id()),
+            ]
+        );
+        assert_eq!(report.accepted().count(), 2);
+
+        assert!(overlay.is_known_peer(valid.id()));
+        assert!(!overlay.is_known_peer(bad_addr.id()));
+        assert_eq!(
+            adnl.get_peer_address(overlay.overlay_key().id(), valid.id()),
+            Some("1.2.3.5:30303".parse().unwrap())
+        );
+    }
+
+    #[tokio::test]
+    async fn broadcast_storm_throttling() {
+        const STORM_LEN: usize = 200;
+        const THRESHOLD: u32 = 20;
+
+        let clock = ManualClock::new(1000);
+        let network = adnl::VirtualNetwork::with_clock(0, Arc::new(clock.clone()));
+        let overlay_id =
+            super::super::IdFull::for_workchain_overlay(0, &[0; 32]).compute_short_id();
+
+        let make_node = || {
+            let options = adnl::NodeOptions {
+                peer_send_queue_capacity: 0,
+                ..Default::default()
+            };
+            let overlay_options = OverlayOptions {
+                tuning: OverlayTuning {
+                    broadcast_spread_duration_ms: 0,
+                    broadcast_storm_threshold: THRESHOLD,
+                    storm_cooldown_ms: 2000,
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            let (adnl, node, overlay) =
+                add_overlay_node(&network, &overlay_id, options, overlay_options);
+            adnl.start().unwrap();
+            (adnl, node, overlay)
+        };
+        let connect =
+            |(left_adnl, _, left): &(Arc<adnl::Node>, _, Arc<Overlay>),
+             (right_adnl, _, right): &(Arc<adnl::Node>, _, Arc<Overlay>)| {
+                left.add_public_peer(
+                    left_adnl,
+                    right_adnl.socket_addr(),
+                    right.sign_local_node().as_equivalent_ref(),
+                )
+                .unwrap();
+                right
+                    .add_public_peer(
+                        right_adnl,
+                        left_adnl.socket_addr(),
+                        left.sign_local_node().as_equivalent_ref(),
+                    )
+                    .unwrap();
+            };
+
+        // Storm source -> forwarder -> receiver
+        let source = make_node();
+        let forwarder = make_node();
+        let receiver = make_node();
+        connect(&source, &forwarder);
+        connect(&forwarder, &receiver);
+        let source_id = *source.2.overlay_key().id();
+        let mut events = forwarder.0.events();
+
+        // NOTE: `wait_for_broadcast` must not be cancelled, so sources are collected separately
+        let collect_sources = |overlay: &Arc<Overlay>| {
+            let overlay = overlay.clone();
+            let (sources_tx, sources_rx) = mpsc::unbounded_channel();
+            tokio::spawn(async move {
+                while sources_tx
+                    .send(overlay.wait_for_broadcast().await.from)
+                    .is_ok()
+                {}
+            });
+            sources_rx
+        };
+        async fn receive_all(
+            sources_rx: &mut mpsc::UnboundedReceiver<adnl::NodeIdShort>,
+        ) -> Vec<adnl::NodeIdShort> {
+            let timeout = Duration::from_millis(200);
+            let mut received = Vec::new();
+            while let Ok(Some(source)) = tokio::time::timeout(timeout, sources_rx.recv()).await {
+                received.push(source);
+            }
+            received
+        }
+        let mut forwarded = collect_sources(&forwarder.2);
+        let mut received = collect_sources(&receiver.2);
+
+        // Synthetic storm of unique broadcasts during one second
+        for i in 0..STORM_LEN {
+            let data = (i as u32).to_le_bytes().to_vec();
+            let info = source
+                .2
+                .broadcast(&source.0, data, None, BroadcastTarget::RandomNeighbours);
+            // Own broadcasts are never throttled
+            assert_eq!(info.recipient_count, 1);
+            tokio::task::yield_now().await;
+        }
+
+        // All broadcasts are delivered locally, but only the first ones are forwarded
+        assert_eq!(receive_all(&mut forwarded).await.len(), STORM_LEN);
+        assert_eq!(receive_all(&mut received).await.len(), THRESHOLD as usize);
+
+        let metrics = forwarder.2.metrics();
+        assert_eq!(metrics.throttled_broadcast_sources, 1);
+        assert_eq!(
+            metrics.suppressed_broadcast_forwards,
+            (STORM_LEN - THRESHOLD as usize) as u64
+        );
+
+        let mut detected = 0;
+        while let Ok(event) = events.try_recv() {
+            if let NetworkEvent::BroadcastStorm {
+                source: Some(source),
+                broadcasts,
+                ..
+            } = event
+            {
+                assert_eq!(source, source_id);
+                assert_eq!(broadcasts, THRESHOLD + 1);
+                detected += 1;
+            }
+        }
+        assert_eq!(detected, 1);
+
+        // Other sources are still forwarded
+        forwarder.2.broadcast(
+            &forwarder.0,
+            vec![1; 10],
+            None,
+            BroadcastTarget::RandomNeighbours,
+        );
+        assert_eq!(receive_all(&mut received).await.len(), 1);
+
+        // Throttling expires after the cooldown
+        clock.advance(Duration::from_secs(3));
+        source.2.broadcast(
+            &source.0,
+            vec![2; 10],
+            None,
+            BroadcastTarget::RandomNeighbours,
+        );
+        assert_eq!(receive_all(&mut received).await, [source_id]);
+        assert_eq!(forwarder.2.metrics().throttled_broadcast_sources, 0);
+    }
+
+    #[tokio::test]
+    async fn persistent_reputation() {
+        /// Scores from the application database
+        #[derive(Default)]
+        struct StoredReputation {
+            scores: Mutex<FastHashMap<adnl::NodeIdShort, i32>>,
+            events: Mutex<Vec<(adnl::NodeIdShort, adnl::ReputationEvent)>>,
+        }
+
+        impl adnl::PeerReputation for StoredReputation {
+            fn score(&self, peer_id: &adnl::NodeIdShort) -> i32 {
+                self.scores.lock().get(peer_id).copied().unwrap_or_default()
+            }
+
+            fn report(&self, peer_id: &adnl::NodeIdShort, event: adnl::ReputationEvent) {
+                self.events.lock().push((*peer_id, event));
+                *self.scores.lock().entry(*peer_id).or_default() +=
+                    adnl::InMemoryReputation::delta(event);
+            }
+        }
+
+        let network = adnl::VirtualNetwork::new(0);
+        let overlay_id =
+            super::super::IdFull::for_workchain_overlay(0, &[0; 32]).compute_short_id();
+        let make_node = || {
+            let (adnl, node, overlay) = add_overlay_node(
+                &network,
+                &overlay_id,
+                Default::default(),
+                Default::default(),
+            );
+            (adnl, node, overlay)
+        };
+
+        let (adnl, _node, overlay) = make_node();
+        let (banned_adnl, _, banned) = make_node();
+        let (good_adnl, _, good) = make_node();
+        let banned_id = *banned.overlay_key().id();
+        let good_id = *good.overlay_key().id();
+
+        // Storage is replaced after the overlay was created
+        let stored = Arc::new(StoredReputation::default());
+        stored.scores.lock().insert(banned_id, -80);
+        adnl.set_reputation(stored.clone());
+        assert_eq!(overlay.peer_score(&banned_id), -80);
+
+        let (banned_node, good_node) = (banned.sign_local_node(), good.sign_local_node());
+        let report = overlay
+            .add_external_peers(
+                &adnl,
+                vec![
+                    ExternalPeer {
+                        peer_id: None,
+                        addr: banned_adnl.socket_addr(),
+                        node: banned_node.as_equivalent_ref(),
+                    },
+                    ExternalPeer {
+                        peer_id: None,
+                        addr: good_adnl.socket_addr(),
+                        node: good_node.as_equivalent_ref(),
+                    },
+                ],
+            )
+            .unwrap();
+        assert_eq!(
+            report.entries,
+            [
+                ExternalPeerStatus::Rejected(PeerRejectReason::BadReputation),
+                ExternalPeerStatus::Added(good_id),
+            ]
+        );
+
+        // Broadcast with an invalid signature from the good peer
+        let key = good.overlay_key();
+        let broadcast = proto::overlay::OverlayBroadcast {
+            src: key.full_id().as_tl(),
+            certificate: proto::overlay::Certificate::EmptyCertificate,
+            flags: BROADCAST_FLAG_ANY_SENDER,
+            data: &[1; 100],
+            date: overlay.clock.now(),
+            signature: &[0; 64],
+        };
+        let local_id = *overlay.overlay_key().id();
+        assert!(overlay
+            .receive_broadcast(&adnl, &local_id, &good_id, broadcast, &[])
+            .await
+            .is_err());
+
+        assert_eq!(
+            *stored.events.lock(),
+            [(good_id, adnl::ReputationEvent::BadBroadcastSignature)]
+        );
+        assert_eq!(overlay.peer_score(&good_id), -50);
+        assert!(!overlay.is_active_public_peer(&good_id));
+    }
+
+    #[tokio::test]
+    async fn corrupted_broadcasts_are_not_delivered() {
+        let network = adnl::VirtualNetwork::new(0);
+        let overlay_id =
+            super::super::IdFull::for_workchain_overlay(0, &[0; 32]).compute_short_id();
+        let make_node = || {
+            let (adnl, node, overlay) = add_overlay_node(
+                &network,
+                &overlay_id,
+                Default::default(),
+                Default::default(),
+            );
+            adnl.start().unwrap();
+            (adnl, node, overlay)
+        };
+
+        let (adnl, _node, overlay) = make_node();
+        let local_id = *overlay.overlay_key().id();
+        let source = make_node();
+        let relays = [make_node(), make_node()];
+        for (peer_adnl, _, peer_overlay) in std::iter::once(&source).chain(&relays) {
+            overlay
+                .add_public_peer(
+                    &adnl,
+                    peer_adnl.socket_addr(),
+                    peer_overlay.sign_local_node().as_equivalent_ref(),
+                )
+                .unwrap();
+        }
+        let key = source.2.overlay_key().clone();
+        let source_id = *key.id();
+        let relay_ids = [0, 1].map(|i| *relays[i].2.overlay_key().id());
+
+        let receive =
+            || tokio::time::timeout(Duration::from_millis(200), overlay.wait_for_broadcast());
+
+        // Ordinary broadcast with a flipped byte in the payload
+        let data = vec![1; 100];
+        let date = overlay.clock.now();
+        let signature = key.sign(make_broadcast_to_sign(&data, date, None));
+        let mut corrupted = data.clone();
+        corrupted[42] ^= 1;
+        let make_broadcast = |data| proto::overlay::OverlayBroadcast {
+            src: key.full_id().as_tl(),
+            certificate: proto::overlay::Certificate::EmptyCertificate,
+            flags: BROADCAST_FLAG_ANY_SENDER,
+            data,
+            date,
+            signature: &signature,
+        };
+
+        let result = overlay
+            .receive_broadcast(
+                &adnl,
+                &local_id,
+                &relay_ids[0],
+                make_broadcast(&corrupted),
+                &[],
+            )
+            .await;
+        assert!(result.is_err());
+        assert_eq!(overlay.metrics().broadcasts_corrupted, 1);
+        assert!(!overlay.is_active_public_peer(&relay_ids[0]));
+        assert!(receive().await.is_err());
+
+        overlay
+            .receive_broadcast(&adnl, &local_id, &relay_ids[1], make_broadcast(&data), &[])
+            .await
+            .unwrap();
+        assert_eq!(receive().await.unwrap().data, data);
+
+        // FEC broadcast parts, optionally corrupted after signing
+        let fec_parts = |data: &[u8], corrupt_signed: bool| {
+            let mut transfer = OutgoingFecTransfer {
+                broadcast_id: sha256(data),
+                encoder: RaptorQEncoder::with_data(data),
+                seqno: 0,
+            };
+            (0..12)
+                .map(|_| {
+                    let packet = source.2.prepare_fec_broadcast(&mut transfer, &key).unwrap();
+                    let prefix_len = source.2.message_prefix().len();
+                    match tl_proto::deserialize(&packet[prefix_len..]).unwrap() {
+                        proto::overlay::Broadcast::BroadcastFec(part) => {
+                            let mut part_data = part.data.to_vec();
+                            let mut signature: [u8; 64] = part.signature.try_into().unwrap();
+                            if part.seqno == 0 {
+                                part_data[0] ^= 1;
+                                if corrupt_signed {
+                                    signature = key.sign(make_fec_part_to_sign(
+                                        part.data_hash,
+                                        part.data_size,
+                                        part.date,
+                                        part.flags,
+                                        &part.fec,
+                                        &part_data,
+                                        part.seqno,
+                                        None,
+                                    ));
+                                }
+                            }
+                            (part.seqno, part.date, part.fec, part_data, signature)
+                        }
+                        _ => unreachable!(),
+                    }
+                })
+                .collect::<Vec<_>>()
+        };
+        let receive_fec = |data: Vec<u8>, corrupt_signed: bool| {
+            let parts = fec_parts(&data, corrupt_signed);
+            let overlay = overlay.clone();
+            let adnl = adnl.clone();
+            let key = key.clone();
+            async move {
+                let data_hash = sha256(&data);
+                for (seqno, date, fec, part_data, signature) in &parts {
+                    let part = proto::overlay::OverlayBroadcastFec {
+                        src: key.full_id().as_tl(),
+                        certificate: proto::overlay::Certificate::EmptyCertificate,
+                        data_hash: &data_hash,
+                        data_size: data.len() as u32,
+                        flags: BROADCAST_FLAG_ANY_SENDER,
+                        data: part_data,
+                        seqno: *seqno,
+                        fec: *fec,
+                        date: *date,
+                        signature,
+                    };
+                    overlay
+                        .receive_fec_broadcast(&adnl, &local_id, &relay_ids[1], part, &[])
+                        .await
+                        .unwrap();
+                }
+            }
+        };
+
+        // Corrupted symbol is skipped and the rest of the broadcast is decoded
+        let data: Vec<u8> = (0..4000).map(|_| rand::random()).collect();
+        receive_fec(data.clone(), false).await;
+        assert_eq!(receive().await.unwrap().data, data);
+        assert_eq!(overlay.metrics().broadcasts_corrupted, 2);
+        assert!(!overlay.is_active_public_peer(&relay_ids[1]));
+
+        // Corrupted symbol signed by the source is detected by the data hash
+        let data: Vec<u8> = (0..4000).map(|_| rand::random()).collect();
+        receive_fec(data, true).await;
+        assert!(receive().await.is_err());
+        assert_eq!(overlay.metrics().broadcasts_corrupted, 3);
+        assert!(!overlay.is_active_public_peer(&source_id));
+    }
+
+    #[tokio::test]
+    async fn stalled_fec_transfers_expire() {
+        let clock = ManualClock::new(1000);
+        let network = adnl::VirtualNetwork::with_clock(0, Arc::new(clock.clone()));
+        let overlay_id =
+            super::super::IdFull::for_workchain_overlay(0, &[0; 32]).compute_short_id();
+        let make_node = || {
+            let options = OverlayOptions {
+                tuning: OverlayTuning {
+                    fec_transfer_timeout_ms: 5000,
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            add_overlay_node(&network, &overlay_id, Default::default(), options)
+        };
+
+        let (adnl, _node, overlay) = make_node();
+        let local_id = *overlay.overlay_key().id();
+        let (_, _source_node, source) = make_node();
+        let key = source.overlay_key().clone();
+
+        let data: Vec<u8> = (0..4000).map(|_| rand::random()).collect();
+        let data_hash = sha256(&data);
+        let mut transfer = OutgoingFecTransfer {
+            broadcast_id: data_hash,
+            encoder: RaptorQEncoder::with_data(&data),
+            seqno: 0,
+        };
+        let packets = (0..12)
+            .map(|_| source.prepare_fec_broadcast(&mut transfer, &key).unwrap())
+            .collect::<Vec<_>>();
+        let parts = packets
+            .iter()
+            .map(|packet| {
+                let prefix_len = source.message_prefix().len();
+                match tl_proto::deserialize(&packet[prefix_len..]).unwrap() {
+                    proto::overlay::Broadcast::BroadcastFec(part) => part,
+                    _ => unreachable!(),
+                }
+            })
+            .collect::<Vec<_>>();
+
+        // Not enough symbols to decode the broadcast
+        for part in &parts[..2] {
+            overlay
+                .receive_fec_broadcast(&adnl, &local_id, key.id(), *part, &[])
+                .await
+                .unwrap();
+        }
+        tokio::task::yield_now().await;
+        assert_eq!(overlay.metrics().incoming_fec_transfers, 1);
+        assert_eq!(overlay.metrics().fec_transfers_expired, 0);
+
+        clock.advance(Duration::from_secs(6));
+        let expired = async {
+            while overlay.metrics().fec_transfers_expired == 0 {
+                tokio::task::yield_now().await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(1), expired)
+            .await
+            .unwrap();
+
+        let metrics = overlay.metrics();
+        assert_eq!(metrics.incoming_fec_transfers, 0);
+        assert_eq!(metrics.fec_transfers_expired, 1);
+
+        // Broadcast id is kept, so the late symbols don't start a new transfer
+        assert_eq!(metrics.owned_broadcasts_len, 1);
+        for part in &parts[2..] {
+            overlay
+                .receive_fec_broadcast(&adnl, &local_id, key.id(), *part, &[])
+                .await
+                .unwrap();
+        }
+        assert_eq!(overlay.metrics().incoming_fec_transfers, 0);
+        let receive =
+            tokio::time::timeout(Duration::from_millis(200), overlay.wait_for_broadcast());
+        assert!(receive.await.is_err());
+    }
+
+    #[tokio::test]
+    async fn channelless_peers_exchange() {
+        let network = adnl::VirtualNetwork::new(0);
+        let overlay_id =
+            super::super::IdFull::for_workchain_overlay(0, &[0; 32]).compute_short_id();
+        let make_node = || {
+            let options = adnl::NodeOptions {
+                allow_channelless_queries: true,
+                ..Default::default()
+            };
+            let (adnl, node, overlay) =
+                add_overlay_node(&network, &overlay_id, options, Default::default());
+            adnl.start().unwrap();
+            (adnl, node, overlay)
+        };
+
+        let (left_adnl, _left_node, left) = make_node();
+        let (right_adnl, _right_node, right) = make_node();
+        let right_id = *right.overlay_key().id();
+        left.add_public_peer(
+            &left_adnl,
+            right_adnl.socket_addr(),
+            right.sign_local_node().as_equivalent_ref(),
+        )
+        .unwrap();
+
+        let peers = left
+            .exchange_random_peers(&left_adnl, &right_id, Some(1000))
+            .await
+            .unwrap();
+        assert!(peers.is_some());
+
+        // Both the query and the answer were sent as handshake packets
+        for adnl in [&left_adnl, &right_adnl] {
+            let metrics = adnl.metrics();
+            assert_eq!(metrics.traffic.packets_sent, 1);
+            assert_eq!(metrics.handshake_packets_sent, 1);
+            assert_eq!(metrics.traffic.handshake_packets_received, 1);
+            assert_eq!(metrics.channels_by_peers_len, 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn own_broadcast_echoes_are_not_delivered() {
+        let network = adnl::VirtualNetwork::new(0);
+        let overlay_id =
+            super::super::IdFull::for_workchain_overlay(0, &[0; 32]).compute_short_id();
+        let make_node = || {
+            let (adnl, node, overlay) = add_overlay_node(
+                &network,
+                &overlay_id,
+                Default::default(),
+                Default::default(),
+            );
+            adnl.start().unwrap();
+            (adnl, node, overlay)
+        };
+
+        let (adnl, _node, overlay) = make_node();
+        let (relay_adnl, _relay_node, relay_overlay) = make_node();
+        overlay
+            .add_public_peer(
+                &adnl,
+                relay_adnl.socket_addr(),
+                relay_overlay.sign_local_node().as_equivalent_ref(),
+            )
+            .unwrap();
+        relay_overlay
+            .add_public_peer(
+                &relay_adnl,
+                adnl.socket_addr(),
+                overlay.sign_local_node().as_equivalent_ref(),
+            )
+            .unwrap();
+        let local_id = *overlay.overlay_key().id();
+        let relay_id = *relay_overlay.overlay_key().id();
+
+        overlay
+            .reputation
+            .report(&relay_id, adnl::ReputationEvent::QueryFailed);
+        assert_eq!(overlay.peer_score(&relay_id), -2);
+
+        // Ordinary broadcasts signed by our key. Echo is credited only for the broadcasts
+        // which were actually sent, otherwise it could be forged with our id as a source
+        let key = overlay.overlay_key().clone();
+        let date = overlay.clock.now();
+        for (data, is_sent, echoes, score) in
+            [(vec![2; 100], false, 0, -2), (vec![1; 100], true, 1, -1)]
+        {
+            let to_sign = make_broadcast_to_sign(&data, date, None);
+            if is_sent {
+                assert!(overlay.create_outgoing_broadcast(to_sign.compute_broadcast_id()));
+            }
+            let signature = key.sign(to_sign);
+            let broadcast = proto::overlay::OverlayBroadcast {
+                src: key.full_id().as_tl(),
+                certificate: proto::overlay::Certificate::EmptyCertificate,
+                flags: BROADCAST_FLAG_ANY_SENDER,
+                data: &data,
+                date,
+                signature: &signature,
+            };
+            overlay
+                .receive_broadcast(&adnl, &local_id, &relay_id, broadcast, &[])
+                .await
+                .unwrap();
+            assert_eq!(overlay.metrics().own_broadcast_echoes, echoes);
+            assert_eq!(overlay.peer_score(&relay_id), score);
+        }
+
+        // FEC broadcast from the custom key, forwarded back by the relay
+        let custom_key = Arc::new(adnl::Key::from_bytes(rand::random()));
+        let data: Vec<u8> = (0..4000).map(|_| rand::random()).collect();
+        overlay.broadcast(
+            &adnl,
+            data.clone(),
+            Some(&custom_key),
+            BroadcastTarget::RandomNeighbours,
+        );
+        assert_eq!(
+            tokio::time::timeout(Duration::from_secs(1), relay_overlay.wait_for_broadcast())
+                .await
+                .unwrap()
+                .data,
+            data
+        );
+
+        let mut transfer = OutgoingFecTransfer {
+            broadcast_id: sha256(&data),
+            encoder: RaptorQEncoder::with_data(&data),
+            seqno: 0,
+        };
+        for _ in 0..2 {
+            let packet = overlay
+                .prepare_fec_broadcast(&mut transfer, &custom_key)
+                .unwrap();
+            let part = match tl_proto::deserialize(&packet[overlay.message_prefix().len()..]) {
+                Ok(proto::overlay::Broadcast::BroadcastFec(part)) => part,
+                _ => unreachable!(),
+            };
+            overlay
+                .receive_fec_broadcast(&adnl, &local_id, &relay_id, part, &[])
+                .await
+                .unwrap();
+        }
+        let metrics = overlay.metrics();
+        assert_eq!(metrics.own_broadcast_echoes, 3);
+        assert_eq!(metrics.broadcasts_duplicated, 0);
+        assert_eq!(metrics.broadcasts_corrupted, 0);
+
+        // Echoes don't raise the score above zero
+        assert_eq!(overlay.peer_score(&relay_id), 0);
+
+        // Nothing is delivered
+        assert!(
+            tokio::time::timeout(Duration::from_millis(200), overlay.wait_for_broadcast())
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn raw_queries_match_typed() {
+        // Records all queries and answers with their data
+        #[derive(Default)]
+        struct Recorder(Mutex<Vec<Vec<u8>>>);
+
+        #[async_trait::async_trait]
+        impl crate::QuerySubscriber for Recorder {
+            async fn try_consume_query<'a>(
+                &self,
+                _: crate::SubscriberContext<'a>,
+                _: u32,
+                query: std::borrow::Cow<'a, [u8]>,
+            ) -> Result<crate::QueryConsumingResult<'a>> {
+                self.0.lock().push(query.to_vec());
+                crate::QueryConsumingResult::consume(proto::adnl::EchoAnswer {
+                    data: query.into_owned(),
+                    received_at: 0,
+                })
+            }
+        }
+
+        let network = adnl::VirtualNetwork::new(0);
+        let overlay_id =
+            super::super::IdFull::for_workchain_overlay(0, &[0; 32]).compute_short_id();
+        let make_node = || {
+            let (adnl, node, overlay) = add_overlay_node(
+                &network,
+                &overlay_id,
+                Default::default(),
+                Default::default(),
+            );
+            let rldp = rldp::Node::new(
+                adnl.clone(),
+                vec![node.query_subscriber()],
+                Default::default(),
+            )
+            .unwrap();
+            adnl.start().unwrap();
+            (adnl, node, rldp, overlay)
+        };
+
+        let (adnl, _node, rldp, overlay) = make_node();
+        let (server_adnl, server_node, _server_rldp, server_overlay) = make_node();
+        let recorder = Arc::new(Recorder::default());
+        server_node.add_overlay_subscriber(overlay_id, recorder.clone());
+        let peer_id = overlay
+            .add_public_peer(
+                &adnl,
+                server_adnl.socket_addr(),
+                server_overlay.sign_local_node().as_equivalent_ref(),
+            )
+            .unwrap()
+            .unwrap();
+
+        let query = || proto::rpc::NetworkEcho {
+            data: vec![1, 2, 3],
+        };
+        let raw = tl_proto::serialize(query());
+        assert_eq!(
+            overlay.compute_query_id(&query()),
+            overlay.compute_raw_query_id(&raw)
+        );
+
+        let typed = overlay
+            .adnl_query(&adnl, &peer_id, query(), Some(1000))
+            .await
+            .unwrap()
+            .unwrap();
+        let untyped = overlay
+            .adnl_query_raw(&adnl, &peer_id, &raw, Some(1000))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(typed, untyped);
+
+        let (typed, _) = overlay
+            .rldp_query(&rldp, &peer_id, query(), None)
+            .await
+            .unwrap();
+        let (untyped, _) = overlay
+            .rldp_query_raw(&rldp, &peer_id, &raw, None)
+            .await
+            .unwrap();
+        assert_eq!(typed.unwrap(), untyped.unwrap());
+
+        let answer = overlay
+            .adnl_query_typed::<_, proto::adnl::EchoAnswer>(&adnl, &peer_id, query(), Some(1000))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(answer.data, raw);
+
+        // Both paths send the same bytes
+        let received = std::mem::take(&mut *recorder.0.lock());
+        assert_eq!(received, vec![raw; 5]);
+    }
+
+    #[tokio::test]
+    async fn key_rotation_during_broadcast() {
+        const GRACE_PERIOD_MS: u64 = 10000;
+
+        let clock = ManualClock::new(1000);
+        let network = adnl::VirtualNetwork::with_clock(0, Arc::new(clock.clone()));
+        let overlay_id =
+            super::super::IdFull::for_workchain_overlay(0, &[0; 32]).compute_short_id();
+
+        let make_node = |options| {
+            let keystore = adnl::Keystore::builder()
+                .with_tagged_keys((0..4).map(|tag| (rand::random(), tag)))
+                .unwrap()
+                .build();
+            let adnl = network.add_node(keystore, Default::default(), None);
+            let node = super::super::Node::new(adnl.clone(), 0).unwrap();
+            let (overlay, _) = node.add_public_overlay(&overlay_id, options).unwrap();
+            adnl.start().unwrap();
+            (adnl, node, overlay)
+        };
+
+        // Slow FEC broadcasts which are sent in several waves
+        let (adnl, node, overlay) = make_node(OverlayOptions {
+            tuning: OverlayTuning {
+                fec_broadcast_wave_len: 4,
+                fec_broadcast_wave_interval_ms: 100,
+                key_rotation_grace_period_ms: GRACE_PERIOD_MS,
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        let (receiver_adnl, receiver_node, receiver) = make_node(Default::default());
+        overlay
+            .add_public_peer(
+                &adnl,
+                receiver_adnl.socket_addr(),
+                receiver.sign_local_node().as_equivalent_ref(),
+            )
+            .unwrap();
+        receiver
+            .add_public_peer(
+                &receiver_adnl,
+                adnl.socket_addr(),
+                overlay.sign_local_node().as_equivalent_ref(),
+            )
+            .unwrap();
+
+        let receive = || {
+            let receiver = receiver.clone();
+            let clock = clock.clone();
+            async move {
+                let handle = tokio::spawn(async move { receiver.wait_for_broadcast().await });
+                for _ in 0..1000 {
+                    if handle.is_finished() {
+                        break;
+                    }
+                    tokio::time::sleep(Duration::from_millis(1)).await;
+                    clock.advance(Duration::from_millis(10));
+                }
+                assert!(handle.is_finished());
+                handle.await.unwrap()
+            }
+        };
+
+        let old_id = *overlay.overlay_key().id();
+        let new_key = adnl.key_by_tag(1).unwrap().clone();
+        let mut events = adnl.events();
+
+        // Rotate the key after the first wave
+        overlay.broadcast(
+            &adnl,
+            vec![1; 8000],
+            None,
+            BroadcastTarget::RandomNeighbours,
+        );
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        overlay.rotate_key(&adnl, new_key.clone()).unwrap();
+        assert_eq!(overlay.overlay_key().id(), new_key.id());
+        let rotated = std::iter::from_fn(|| events.try_recv().ok()).find_map(|event| match event {
+            NetworkEvent::OverlayKeyRotated {
+                old_id,
+                new_id,
+                grace_period_ms,
+                ..
+            } => Some((old_id, new_id, grace_period_ms)),
+            _ => None,
+        });
+        assert_eq!(rotated, Some((old_id, *new_key.id(), GRACE_PERIOD_MS)));
+
+        // Old broadcast is completed with the old key
+        let broadcast = receive().await;
+        assert_eq!(broadcast.data.len(), 8000);
+        assert_eq!(broadcast.from, old_id);
+        assert!(overlay.is_local_id(&old_id));
+
+        // New broadcasts are signed with the new key
+        for len in [100, 4000] {
+            overlay.broadcast(&adnl, vec![2; len], None, BroadcastTarget::RandomNeighbours);
+            let broadcast = receive().await;
+            assert_eq!(broadcast.data.len(), len);
+            assert_eq!(broadcast.from, *new_key.id());
+        }
+        assert_eq!(
+            adnl::NodeIdFull::try_from(overlay.sign_local_node().as_equivalent_ref().id)
+                .unwrap()
+                .compute_short_id(),
+            *new_key.id()
+        );
+
+        // Old key is forgotten after the grace period
+        clock.advance(Duration::from_millis(GRACE_PERIOD_MS));
+        assert!(!overlay.is_local_id(&old_id));
+        assert!(overlay.is_local_id(new_key.id()));
+
+        // Members of the private overlay are reachable from the new key
+        let private_overlay_id =
+            super::super::IdFull::for_workchain_overlay(0, &[1; 32]).compute_short_id();
+        let (old_key, new_key) = (
+            adnl.key_by_tag(2).unwrap().clone(),
+            adnl.key_by_tag(3).unwrap().clone(),
+        );
+        let receiver_key = receiver_adnl.key_by_tag(0).unwrap();
+        let (private, _) = node
+            .add_private_overlay(
+                &private_overlay_id,
+                old_key.clone(),
+                &[*receiver_key.id()],
+                Default::default(),
+            )
+            .unwrap();
+        adnl.add_peer(
+            adnl::NewPeerContext::AdnlPacket,
+            old_key.id(),
+            receiver_key.id(),
+            receiver_adnl.socket_addr(),
+            *receiver_key.full_id(),
+        )
+        .unwrap();
+        let (private_receiver, _) = receiver_node
+            .add_private_overlay(
+                &private_overlay_id,
+                receiver_key.clone(),
+                &[*old_key.id(), *new_key.id()],
+                Default::default(),
+            )
+            .unwrap();
+
+        private.rotate_key(&adnl, new_key.clone()).unwrap();
+        assert_eq!(
+            adnl.get_peer_address(new_key.id(), receiver_key.id()),
+            Some(receiver_adnl.socket_addr())
+        );
+        assert!(adnl.is_peer_pinned(receiver_key.id()));
+
+        private.broadcast(&adnl, vec![3; 100], None, BroadcastTarget::RandomNeighbours);
+        let broadcast = tokio::time::timeout(
+            Duration::from_secs(1),
+            private_receiver.wait_for_broadcast(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(broadcast.data, [3; 100]);
+        assert_eq!(broadcast.from, *new_key.id());
+    }
+
+    #[tokio::test]
+    async fn originated_broadcast_overtakes_relayed() {
+        const RELAYED: u8 = 100;
+
+        let network = adnl::VirtualNetwork::new(0);
+        let overlay_id =
+            super::super::IdFull::for_workchain_overlay(0, &[0; 32]).compute_short_id();
+
+        let make_node = || {
+            let (adnl, node, overlay) = add_overlay_node(
+                &network,
+                &overlay_id,
+                Default::default(),
+                Default::default(),
+            );
+            adnl.start().unwrap();
+            (adnl, node, overlay)
+        };
+
+        let (adnl, _node, overlay) = make_node();
+        let (receiver_adnl, _receiver_node, receiver_overlay) = make_node();
+        overlay
+            .add_public_peer(
+                &adnl,
+                receiver_adnl.socket_addr(),
+                receiver_overlay.sign_local_node().as_equivalent_ref(),
+            )
+            .unwrap();
+        receiver_overlay
+            .add_public_peer(
+                &receiver_adnl,
+                adnl.socket_addr(),
+                overlay.sign_local_node().as_equivalent_ref(),
+            )
+            .unwrap();
+
+        // Sender task can't run until the test yields
+        for i in 0..RELAYED {
+            overlay.broadcast_ext(
+                &adnl,
+                vec![i; 10],
+                None,
+                BroadcastTarget::RandomNeighbours,
+                BroadcastClass::Relayed,
+            );
+        }
+        overlay.broadcast(
+            &adnl,
+            vec![255; 10],
+            None,
+            BroadcastTarget::RandomNeighbours,
+        );
+
+        let metrics = overlay.metrics();
+        assert_eq!(metrics.originated_queue_len, 1);
+        assert_eq!(metrics.relayed_queue_len, RELAYED as usize);
+
+        let first = receiver_overlay.wait_for_broadcast().await;
+        assert_eq!(first.data, vec![255; 10]);
+        for i in 0..RELAYED {
+            let broadcast = receiver_overlay.wait_for_broadcast().await;
+            assert_eq!(broadcast.data, vec![i; 10]);
+        }
+
+        let metrics = overlay.metrics();
+        assert_eq!(metrics.originated_queue_len + metrics.relayed_queue_len, 0);
+        assert_eq!(metrics.relayed_packets_dropped, 0);
+    }
+
+    #[tokio::test]
+    async fn broadcast_spreading() {
+        const RECEIVERS: u64 = 4;
+        const SPREAD_MS: u64 = 40;
+
+        let clock = ManualClock::new(1000);
+        let network = adnl::VirtualNetwork::with_clock(0, Arc::new(clock.clone()));
+        let overlay_id =
+            super::super::IdFull::for_workchain_overlay(0, &[0; 32]).compute_short_id();
+
+        let make_node = |options| {
+            let (adnl, node, overlay) =
+                add_overlay_node(&network, &overlay_id, Default::default(), options);
+            adnl.start().unwrap();
+            (adnl, node, overlay)
+        };
+
+        let (adnl, _node, overlay) = make_node(OverlayOptions {
+            tuning: OverlayTuning {
+                broadcast_spread_duration_ms: SPREAD_MS,
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        let receivers = (0..RECEIVERS)
+            .map(|_| {
+                let receiver = make_node(Default::default());
+                let (receiver_adnl, _, receiver_overlay) = &receiver;
+                overlay
+                    .add_public_peer(
+                        &adnl,
+                        receiver_adnl.socket_addr(),
+                        receiver_overlay.sign_local_node().as_equivalent_ref(),
+                    )
+                    .unwrap();
+                receiver_overlay
+                    .add_public_peer(
+                        receiver_adnl,
+                        adnl.socket_addr(),
+                        overlay.sign_local_node().as_equivalent_ref(),
+                    )
+                    .unwrap();
+                receiver
+            })
+            .collect::<Vec<_>>();
+
+        // Returns the virtual arrival times of the next broadcast
+        let receive_all = || {
+            let handles = receivers
+                .iter()
+                .map(|(_, _, overlay)| {
+                    let overlay = overlay.clone();
+                    let clock = clock.clone();
+                    tokio::spawn(async move {
+                        let broadcast = overlay.wait_for_broadcast().await;
+                        (clock.now_ms(), broadcast.data.len())
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            let clock = clock.clone();
+            async move {
+                for _ in 0..1000 {
+                    if handles.iter().all(|handle| handle.is_finished()) {
+                        break;
+                    }
+                    tokio::time::sleep(Duration::from_millis(1)).await;
+                    clock.advance(Duration::from_millis(1));
+                }
+                assert!(handles.iter().all(|handle| handle.is_finished()));
+
+                let mut result = Vec::new();
+                for handle in handles {
+                    result.push(handle.await.unwrap());
+                }
+                result
+            }
+        };
+
+        // Ordinary broadcast
+        let received = receive_all();
+        let info = overlay.broadcast(&adnl, vec![1; 100], None, BroadcastTarget::RandomNeighbours);
+        assert_eq!(info.recipient_count, RECEIVERS as usize);
+
+        let mut arrivals = received.await;
+        arrivals.sort_unstable();
+        let interval = SPREAD_MS / RECEIVERS;
+        for pair in arrivals.windows(2) {
+            let gap = pair[1].0 - pair[0].0;
+            assert!(
+                (interval..=interval + 2).contains(&gap),
+                "unexpected gap: {gap} ms"
+            );
+        }
+
+        let metrics = overlay.metrics();
+        assert_eq!(metrics.outgoing_broadcast_packets, RECEIVERS);
+
+        // FEC broadcast is decoded by all neighbours
+        let received = receive_all();
+        overlay.broadcast(
+            &adnl,
+            vec![2; 4000],
+            None,
+            BroadcastTarget::RandomNeighbours,
+        );
+        for (_, len) in received.await {
+            assert_eq!(len, 4000);
+        }
+    }
+}
@@ -1,10 +1,9 @@
 use std::borrow::Borrow;
-use std::convert::TryFrom;
 
 use anyhow::Result;
 
-use crate::adnl;
 use crate::proto;
+use crate::util::*;
 
 /// Full overlay id
 ///
@@ -26,8 +25,12 @@ impl IdFull {
         }))
     }
 
-    /// Constructs full overlay id for the workchain overlay
-    pub fn for_workchain_overlay(workchain: i32, zero_state_file_hash: &[u8; 32]) -> Self {
+    /// Constructs full overlay id for the workchain overlay.
+    ///
+    /// Id is a hash of the boxed `tonNode.shardPublicOverlayId` with the full shard
+    /// (`0x8000000000000000`), i.e. sha256 of 48 bytes:
+    /// `29d39e4d | workchain:int | 0000000000000080 | zero_state_file_hash:int256`
+    pub fn for_workchain(workchain: i32, zero_state_file_hash: &[u8; 32]) -> Self {
         Self(tl_proto::hash(proto::overlay::ShardPublicOverlayId {
             workchain,
             shard: 1u64 << 63,
@@ -35,6 +38,23 @@ impl IdFull {
         }))
     }
 
+    /// Constructs full overlay id for the workchain overlay.
+    ///
+    /// See [`IdFull::for_workchain`]
+    #[inline(always)]
+    pub fn for_workchain_overlay(workchain: i32, zero_state_file_hash: &[u8; 32]) -> Self {
+        Self::for_workchain(workchain, zero_state_file_hash)
+    }
+
+    /// Constructs full overlay id for the private overlay identified by an arbitrary name.
+    ///
+    /// Id is a hash of the boxed `pub.overlay name:bytes`,
+    /// i.e. sha256 of `cb45ba34 | name` where name is serialized as TL bytes
+    /// (length prefix and padding to 4 bytes).
+    pub fn for_private_overlay(name: &[u8]) -> Self {
+        Self(hash_public_key(PublicKeyVariant::Overlay(name)))
+    }
+
     /// Returns inner bytes
     pub fn as_slice(&self) -> &[u8; 32] {
         &self.0
@@ -42,8 +62,7 @@ impl IdFull {
 
     /// Hashes inner public key
     pub fn compute_short_id(&self) -> IdShort {
-        let key = everscale_crypto::tl::PublicKey::Overlay { name: &self.0 };
-        IdShort(tl_proto::hash(key))
+        IdShort(hash_public_key(PublicKeyVariant::Overlay(&self.0)))
     }
 }
 
@@ -64,18 +83,7 @@ impl IdShort {
             return Err(OverlayIdError::OverlayIdMismatch.into());
         }
 
-        let peer_id_full = adnl::NodeIdFull::try_from(node.id)?;
-        let peer_id = peer_id_full.compute_short_id();
-
-        let node_to_sign = &proto::overlay::NodeToSign {
-            id: peer_id.as_slice(),
-            overlay: node.overlay,
-            version: node.version,
-        };
-
-        peer_id_full.verify(node_to_sign, node.signature)?;
-
-        Ok(())
+        crate::util::verify_overlay_node(node)
     }
 
     /// Returns inner bytes
@@ -83,6 +91,19 @@ impl IdShort {
     pub const fn as_slice(&self) -> &[u8; 32] {
         &self.0
     }
+
+    /// Parses short overlay id from base64 string (with padding)
+    pub fn from_base64(s: &str) -> Result<Self, ParseIdError> {
+        match s.len() {
+            44 => parse_id(s).map(Self),
+            len => Err(ParseIdError::InvalidLength(len)),
+        }
+    }
+
+    /// Encodes short overlay id as base64 string (with padding)
+    pub fn to_base64(&self) -> String {
+        id_to_base64(&self.0)
+    }
 }
 
 impl PartialEq<[u8]> for IdShort {
@@ -109,6 +130,12 @@ impl From<&IdShort> for [u8; 32] {
     }
 }
 
+impl From<PublicKeyVariant<'_>> for IdShort {
+    fn from(key: PublicKeyVariant<'_>) -> Self {
+        Self(hash_public_key(key))
+    }
+}
+
 impl From<[u8; 32]> for IdShort {
     fn from(id: [u8; 32]) -> Self {
         Self(id)
@@ -121,20 +148,79 @@ impl Borrow<[u8; 32]> for IdShort {
     }
 }
 
-impl<'a> Borrow<[u8; 32]> for &'a IdShort {
+impl Borrow<[u8; 32]> for &IdShort {
     fn borrow(&self) -> &[u8; 32] {
         &self.0
     }
 }
 
+/// Displays short overlay id as hex, see [`NodeIdShort`](crate::adnl::NodeIdShort)
+/// for the reasons
 impl std::fmt::Display for IdShort {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         f.write_str(&hex::encode(self.0))
     }
 }
 
+/// Parses short overlay id from base64 or hex string
+impl std::str::FromStr for IdShort {
+    type Err = ParseIdError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_id(s).map(Self)
+    }
+}
+
+/// Serializes short overlay id as base64 string for human-readable formats
+impl serde::Serialize for IdShort {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serialize_id(&self.0, serializer)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for IdShort {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserialize_id(deserializer).map(Self)
+    }
+}
+
 #[derive(thiserror::Error, Debug)]
 enum OverlayIdError {
     #[error("Overlay id mismatch")]
     OverlayIdMismatch,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Pinned values, any change breaks the overlay ids of the existing networks
+    fn check(id: IdFull, full: &str, short: &str) {
+        assert_eq!(hex::encode(id.as_slice()), full);
+        assert_eq!(id.compute_short_id().to_string(), short);
+    }
+
+    #[test]
+    fn known_overlay_ids() {
+        check(
+            IdFull::for_workchain(0, &[1; 32]),
+            "a7a5b21807ef3d346702e108be826392cc0ce8be96345d1b0919c6e2619a3c72",
+            "92ce3c202d270c6e6deeb89426fecd36610c5bcb5ce46d9fde17ce99bd761e63",
+        );
+        assert_eq!(
+            IdFull::for_workchain_overlay(0, &[1; 32]),
+            IdFull::for_workchain(0, &[1; 32])
+        );
+
+        check(
+            IdFull::for_private_overlay(b""),
+            "23bb358b3b4346923ec4a26d4ba49e4fd2638c303bca5337abb7ae54fa5ba632",
+            "dd8e33ff10643000f18bbabcbf70686b9b8ae946ab6296840a9e3f0717ae8d06",
+        );
+        check(
+            IdFull::for_private_overlay(b"my-private-overlay"),
+            "f4b5278204f486ae01705f66854946ab6a6704a80555524c9b33df030ab27e5d",
+            "324ff520597d3db2b074aea9959dae2c247ddaac2de7819c302598ab555e28a9",
+        );
+    }
+}
@@ -0,0 +1,60 @@
+use rand::Rng;
+
+use crate::adnl;
+
+/// Orders peers randomly so that the peers with higher reputation scores
+/// are more likely to go first
+pub fn weighted_shuffle(
+    reputation: &adnl::SharedReputation,
+    peers: Vec<adnl::NodeIdShort>,
+) -> Vec<adnl::NodeIdShort> {
+    let mut rng = rand::thread_rng();
+
+    // Weighted random sampling without replacement (Efraimidis-Spirakis),
+    // each `SCORE_HALVING` points below zero halve the chance to be selected
+    let mut keyed = peers
+        .into_iter()
+        .map(|peer_id| {
+            let weight = 2f64.powf(reputation.score(&peer_id) as f64 / SCORE_HALVING);
+            let key = rng.gen::<f64>().powf(1.0 / weight);
+            (key, peer_id)
+        })
+        .collect::<Vec<_>>();
+    keyed.sort_unstable_by(|(left, _), (right, _)| right.total_cmp(left));
+
+    keyed.into_iter().map(|(_, peer_id)| peer_id).collect()
+}
+
+const SCORE_HALVING: f64 = 4.0;
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::util::SystemClock;
+
+    #[test]
+    fn low_scores_are_selected_later() {
+        let reputation = adnl::SharedReputation::new(Arc::new(adnl::InMemoryReputation::new(
+            Arc::new(SystemClock),
+            adnl::InMemoryReputation::DEFAULT_HALF_LIFE,
+        )));
+        let (good, bad) = (
+            adnl::NodeIdShort::new([1; 32]),
+            adnl::NodeIdShort::new([2; 32]),
+        );
+
+        reputation.report(&good, adnl::ReputationEvent::QueryAnswered);
+        for _ in 0..4 {
+            reputation.report(&bad, adnl::ReputationEvent::MalformedAnswer);
+        }
+        assert_eq!(reputation.score(&good), 1);
+        assert_eq!(reputation.score(&bad), -20);
+
+        let good_first = (0..1000)
+            .filter(|_| weighted_shuffle(&reputation, vec![bad, good])[0] == good)
+            .count();
+        assert!(good_first > 950, "{good_first}");
+    }
+}
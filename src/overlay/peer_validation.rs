@@ -0,0 +1,146 @@
+use std::convert::TryFrom;
+use std::net::SocketAddrV4;
+
+use super::overlay_id::IdShort;
+use crate::adnl;
+use crate::proto;
+
+/// Overlay peer from an external discovery service, see [`Overlay::add_external_peers`]
+///
+/// [`Overlay::add_external_peers`]: super::Overlay::add_external_peers
+#[derive(Debug, Copy, Clone)]
+pub struct ExternalPeer<'a> {
+    /// ADNL id declared by the discovery service, must match the key of the `node`
+    pub peer_id: Option<adnl::NodeIdShort>,
+    /// Address of the peer
+    pub addr: SocketAddrV4,
+    /// Signed overlay node info
+    pub node: proto::overlay::Node<'a>,
+}
+
+/// Per-entry result of [`Overlay::add_external_peers`]
+///
+/// [`Overlay::add_external_peers`]: super::Overlay::add_external_peers
+#[derive(Debug, Default, Clone)]
+pub struct ExternalPeersReport {
+    /// Statuses in the same order as the entries
+    pub entries: Vec<ExternalPeerStatus>,
+}
+
+impl ExternalPeersReport {
+    /// Ids of the added or updated peers
+    pub fn accepted(&self) -> impl Iterator<Item = &adnl::NodeIdShort> {
+        self.entries.iter().filter_map(|entry| match entry {
+            ExternalPeerStatus::Added(peer_id) | ExternalPeerStatus::Updated(peer_id) => {
+                Some(peer_id)
+            }
+            ExternalPeerStatus::Rejected(_) => None,
+        })
+    }
+}
+
+/// See [`ExternalPeersReport`]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ExternalPeerStatus {
+    /// New peer was added
+    Added(adnl::NodeIdShort),
+    /// Peer was already known, its address and node info were updated
+    Updated(adnl::NodeIdShort),
+    /// Peer was not added
+    Rejected(PeerRejectReason),
+}
+
+/// Why the overlay node was not accepted
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PeerRejectReason {
+    /// Node belongs to another overlay
+    OverlayMismatch,
+    /// Node key is not an ed25519 key
+    UnsupportedKey,
+    /// Node is the local overlay node
+    LocalNode,
+    /// Signature doesn't match the node key
+    InvalidSignature,
+    /// Node was signed earlier than [`OverlayTuning::stored_peer_ttl_sec`] ago
+    ///
+    /// [`OverlayTuning::stored_peer_ttl_sec`]: super::OverlayTuning::stored_peer_ttl_sec
+    Outdated,
+    /// Declared ADNL id doesn't match the node key
+    IdMismatch,
+    /// Address can't be used to reach the peer
+    InvalidAddress,
+    /// Peer was ignored by the ADNL node (e.g. by the peer filter)
+    Ignored,
+    /// Peer reputation score is too low,
+    /// see [`OverlayTuning::ban_reputation_score`]
+    ///
+    /// [`OverlayTuning::ban_reputation_score`]: super::OverlayTuning::ban_reputation_score
+    BadReputation,
+}
+
+/// Checks the overlay node received from the remote peer or from the external source.
+/// `ttl_sec == 0` disables the freshness check
+pub(super) fn validate_overlay_node(
+    overlay_id: &IdShort,
+    local_id: &adnl::NodeIdShort,
+    node: &proto::overlay::Node<'_>,
+    now: u32,
+    ttl_sec: u32,
+) -> Result<adnl::NodeIdFull, PeerRejectReason> {
+    if node.overlay != overlay_id.as_slice() {
+        return Err(PeerRejectReason::OverlayMismatch);
+    }
+    if !matches!(node.id, everscale_crypto::tl::PublicKey::Ed25519 { .. }) {
+        return Err(PeerRejectReason::UnsupportedKey);
+    }
+
+    let peer_id_full =
+        adnl::NodeIdFull::try_from(node.id).map_err(|_| PeerRejectReason::UnsupportedKey)?;
+    let peer_id = peer_id_full.compute_short_id();
+    if &peer_id == local_id {
+        return Err(PeerRejectReason::LocalNode);
+    }
+
+    crate::util::verify_overlay_node(node).map_err(|_| PeerRejectReason::InvalidSignature)?;
+
+    if ttl_sec > 0 && node.version.saturating_add(ttl_sec) < now {
+        return Err(PeerRejectReason::Outdated);
+    }
+
+    Ok(peer_id_full)
+}
+
+/// Checks that the peer can be reached at this address
+pub(super) fn validate_peer_addr(addr: &SocketAddrV4) -> Result<(), PeerRejectReason> {
+    let ip = addr.ip();
+    if addr.port() == 0 || ip.is_unspecified() || ip.is_broadcast() || ip.is_multicast() {
+        return Err(PeerRejectReason::InvalidAddress);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    #[test]
+    fn invalid_addresses() {
+        let valid = SocketAddrV4::new(Ipv4Addr::new(1, 2, 3, 4), 30303);
+        assert!(validate_peer_addr(&valid).is_ok());
+        assert!(validate_peer_addr(&SocketAddrV4::new(Ipv4Addr::LOCALHOST, 30303)).is_ok());
+
+        for addr in [
+            SocketAddrV4::new(Ipv4Addr::new(1, 2, 3, 4), 0),
+            SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 30303),
+            SocketAddrV4::new(Ipv4Addr::BROADCAST, 30303),
+            SocketAddrV4::new(Ipv4Addr::new(224, 0, 0, 1), 30303),
+        ] {
+            assert_eq!(
+                validate_peer_addr(&addr),
+                Err(PeerRejectReason::InvalidAddress)
+            );
+        }
+    }
+}
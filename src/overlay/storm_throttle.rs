@@ -0,0 +1,101 @@
+use crate::adnl;
+use crate::util::FastHashMap;
+
+/// Rates of new incoming broadcasts per source with temporarily throttled sources
+#[derive(Default)]
+pub struct StormThrottle {
+    sources: FastHashMap<adnl::NodeIdShort, SourceRate>,
+}
+
+impl StormThrottle {
+    /// Number of sources which broadcasts are not forwarded now
+    pub fn throttled_len(&self, now_ms: u64) -> usize {
+        self.sources
+            .values()
+            .filter(|rate| rate.throttled_until_ms > now_ms)
+            .count()
+    }
+
+    /// Whether broadcasts of the source must not be forwarded
+    pub fn is_throttled(&self, source: &adnl::NodeIdShort, now_ms: u64) -> bool {
+        matches!(self.sources.get(source), Some(rate) if rate.throttled_until_ms > now_ms)
+    }
+
+    /// Counts new broadcast of the source. Returns `true` if the source
+    /// has exceeded `threshold` during the current second and became throttled
+    pub fn count(
+        &mut self,
+        source: &adnl::NodeIdShort,
+        now_ms: u64,
+        threshold: u32,
+        cooldown_ms: u64,
+    ) -> bool {
+        let rate = self.sources.entry(*source).or_default();
+        if now_ms.saturating_sub(rate.window_start_ms) >= RATE_WINDOW_MS {
+            rate.window_start_ms = now_ms;
+            rate.count = 0;
+        }
+        rate.count = rate.count.saturating_add(1);
+
+        if rate.count <= threshold || rate.throttled_until_ms > now_ms {
+            return false;
+        }
+        rate.throttled_until_ms = now_ms + cooldown_ms;
+        true
+    }
+
+    /// Removes sources without recent broadcasts and expired throttling
+    pub fn shrink(&mut self, now_ms: u64) {
+        self.sources.retain(|_, rate| {
+            rate.throttled_until_ms > now_ms
+                || now_ms.saturating_sub(rate.window_start_ms) < RATE_WINDOW_MS
+        });
+    }
+}
+
+#[derive(Default)]
+struct SourceRate {
+    /// Unix timestamp in milliseconds of the current window
+    window_start_ms: u64,
+    count: u32,
+    throttled_until_ms: u64,
+}
+
+const RATE_WINDOW_MS: u64 = 1000;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn throttling_expires() {
+        let source = adnl::NodeIdShort::random();
+        let other = adnl::NodeIdShort::random();
+        let mut throttle = StormThrottle::default();
+
+        // Synthetic storm of 1000 broadcasts per second
+        let mut now_ms = 1_000_000;
+        let detected = (0..1000)
+            .filter(|i| throttle.count(&source, now_ms + i, 100, 5000))
+            .count();
+        assert_eq!(detected, 1);
+        assert!(throttle.is_throttled(&source, now_ms + 999));
+        assert!(!throttle.is_throttled(&other, now_ms + 999));
+        assert!(!throttle.count(&other, now_ms, 100, 5000));
+        assert_eq!(throttle.throttled_len(now_ms), 1);
+
+        // Storm continues, but it is detected only once per cooldown
+        now_ms += 1000;
+        assert!((0..1000).all(|i| !throttle.count(&source, now_ms + i, 100, 5000)));
+
+        // Throttling expires
+        now_ms += 4100;
+        assert!(!throttle.is_throttled(&source, now_ms));
+        throttle.shrink(now_ms);
+        assert_eq!(throttle.throttled_len(now_ms), 0);
+        assert!(throttle.sources.is_empty());
+
+        // And is detected again
+        assert!((0..101).any(|i| throttle.count(&source, now_ms + i, 100, 5000)));
+    }
+}
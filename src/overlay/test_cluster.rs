@@ -0,0 +1,611 @@
+use std::net::SocketAddrV4;
+use std::sync::Arc;
+
+use anyhow::Result;
+
+use super::node::Node;
+use super::overlay::{BroadcastTarget, OutgoingBroadcastInfo, Overlay, OverlayOptions};
+use super::{IdFull, IdShort};
+use crate::adnl;
+
+/// Multiple overlay nodes in one process, linked through the [`adnl::VirtualNetwork`].
+///
+/// All nodes join the same public overlay and know each other.
+///
+/// NOTE: must be created inside the tokio runtime
+pub struct OverlayTestCluster {
+    network: adnl::VirtualNetwork,
+    overlay_id: IdShort,
+    nodes: Vec<ClusterNode>,
+}
+
+impl OverlayTestCluster {
+    /// Creates `n` nodes on a new virtual network with default links
+    pub fn new(n: usize, options: OverlayOptions) -> Result<Self> {
+        Self::with_network(adnl::VirtualNetwork::new(0), n, options)
+    }
+
+    /// Creates `n` nodes on the specified virtual network
+    pub fn with_network(
+        network: adnl::VirtualNetwork,
+        n: usize,
+        options: OverlayOptions,
+    ) -> Result<Self> {
+        let overlay_id = IdFull::for_workchain_overlay(0, &[0; 32]).compute_short_id();
+
+        let nodes = (0..n)
+            .map(|_| {
+                let keystore = adnl::Keystore::builder()
+                    .with_tagged_key(rand::random(), 0)?
+                    .build();
+                let adnl = network.add_node(keystore, Default::default(), None);
+                let node = Node::new(adnl.clone(), 0)?;
+                let (overlay, _) = node.add_public_overlay(&overlay_id, options)?;
+                adnl.start()?;
+                Ok(ClusterNode {
+                    adnl,
+                    node,
+                    overlay,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        for node in &nodes {
+            for peer in &nodes {
+                if Arc::ptr_eq(&node.adnl, &peer.adnl) {
+                    continue;
+                }
+                node.overlay.add_public_peer(
+                    &node.adnl,
+                    peer.adnl.socket_addr(),
+                    peer.overlay.sign_local_node().as_equivalent_ref(),
+                )?;
+            }
+        }
+
+        Ok(Self {
+            network,
+            overlay_id,
+            nodes,
+        })
+    }
+
+    /// Underlying virtual network (e.g. to configure links)
+    pub fn network(&self) -> &adnl::VirtualNetwork {
+        &self.network
+    }
+
+    /// Short id of the common overlay
+    pub fn overlay_id(&self) -> &IdShort {
+        &self.overlay_id
+    }
+
+    /// Node with the specified index
+    pub fn node(&self, i: usize) -> &ClusterNode {
+        &self.nodes[i]
+    }
+
+    /// All nodes in the creation order
+    pub fn nodes(&self) -> &[ClusterNode] {
+        &self.nodes
+    }
+
+    /// Disconnects each group of node indices from all other groups.
+    /// Nodes which are not listed stay connected to everyone.
+    ///
+    /// See [`OverlayTestCluster::heal`]
+    pub fn partition(&self, groups: &[&[usize]]) {
+        let groups = groups
+            .iter()
+            .map(|group| group.iter().map(|&i| self.addr(i)).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+
+        for (i, left) in groups.iter().enumerate() {
+            for right in &groups[i + 1..] {
+                self.network.partition(left, right);
+            }
+        }
+    }
+
+    /// Removes all partitions
+    pub fn heal(&self) {
+        self.network.heal();
+    }
+
+    fn addr(&self, i: usize) -> SocketAddrV4 {
+        self.nodes[i].adnl.socket_addr()
+    }
+}
+
+/// Cluster member, see [`OverlayTestCluster::node`]
+pub struct ClusterNode {
+    adnl: Arc<adnl::Node>,
+    node: Arc<Node>,
+    overlay: Arc<Overlay>,
+}
+
+impl ClusterNode {
+    /// Underlying ADNL node
+    pub fn adnl(&self) -> &Arc<adnl::Node> {
+        &self.adnl
+    }
+
+    /// Overlay node
+    pub fn node(&self) -> &Arc<Node> {
+        &self.node
+    }
+
+    /// Common overlay of the cluster
+    pub fn overlay(&self) -> &Arc<Overlay> {
+        &self.overlay
+    }
+
+    /// Broadcasts data to random neighbours
+    pub fn broadcast(&self, data: Vec<u8>) -> OutgoingBroadcastInfo {
+        self.overlay
+            .broadcast(&self.adnl, data, None, BroadcastTarget::RandomNeighbours)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::overlay::{OverlayQueryTransport, OverlayTuning, QueryAnyOptions};
+    use crate::proto;
+    use crate::util::{AnswerVerificationFailed, AnswerVerifier};
+    use crate::{QueryConsumingResult, QuerySubscriber, SubscriberContext};
+
+    const NODES: usize = 6;
+
+    /// Each node relays broadcasts to all other nodes
+    fn flooding_options() -> OverlayOptions {
+        OverlayOptions {
+            tuning: OverlayTuning {
+                broadcast_target_count: NODES as u32,
+                secondary_broadcast_target_count: NODES as u32,
+                secondary_fec_broadcast_target_count: NODES as u32,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    /// Returns the data of the next broadcast received by each of the specified nodes
+    async fn receive(cluster: &OverlayTestCluster, nodes: &[usize]) -> Vec<Option<Vec<u8>>> {
+        let received = nodes.iter().map(|&i| {
+            let overlay = cluster.node(i).overlay().clone();
+            async move {
+                tokio::time::timeout(Duration::from_secs(30), overlay.wait_for_broadcast())
+                    .await
+                    .ok()
+                    .map(|broadcast| broadcast.data)
+            }
+        });
+        futures_util::future::join_all(received).await
+    }
+
+    /// Returns `true` if none of the specified nodes receive anything during a short period
+    async fn nothing_received(cluster: &OverlayTestCluster, nodes: &[usize]) -> bool {
+        let received = nodes.iter().map(|&i| {
+            let overlay = cluster.node(i).overlay().clone();
+            async move {
+                tokio::time::timeout(Duration::from_millis(200), overlay.wait_for_broadcast())
+                    .await
+                    .is_err()
+            }
+        });
+        futures_util::future::join_all(received)
+            .await
+            .into_iter()
+            .all(|empty| empty)
+    }
+
+    #[tokio::test]
+    async fn broadcast_reaches_all_nodes() {
+        let cluster = OverlayTestCluster::new(NODES, Default::default()).unwrap();
+        assert!(cluster
+            .nodes()
+            .iter()
+            .all(|node| node.overlay().metrics().neighbours == NODES - 1));
+
+        let info = cluster.node(0).broadcast(vec![1; 100]);
+        assert_eq!(info.recipient_count, NODES - 1);
+
+        let others = (1..NODES).collect::<Vec<_>>();
+        for data in receive(&cluster, &others).await {
+            assert_eq!(data.unwrap(), vec![1; 100]);
+        }
+    }
+
+    #[tokio::test]
+    async fn presets_deliver_broadcasts() {
+        const PRESET_NODES: usize = 4;
+
+        let presets = [
+            ("public_default", OverlayOptions::public_default()),
+            ("private_validator", OverlayOptions::private_validator()),
+            (
+                "gateway_high_throughput",
+                OverlayOptions::gateway_high_throughput(),
+            ),
+            (
+                "overridden",
+                OverlayOptions::private_validator()
+                    .with_decode_threads(1)
+                    .with_broadcast_target_count(PRESET_NODES as u32)
+                    .with_storm_throttling(10, 1000),
+            ),
+        ];
+
+        for (name, options) in presets {
+            options.validate().unwrap();
+            let cluster = OverlayTestCluster::new(PRESET_NODES, options).unwrap();
+            let others = (1..PRESET_NODES).collect::<Vec<_>>();
+
+            // Ordinary and FEC broadcasts
+            for data in [
+                vec![3; 100],
+                (0..4 * 1024).map(|_| rand::random()).collect(),
+            ] {
+                cluster.node(0).broadcast(data.clone());
+                for received in receive(&cluster, &others).await {
+                    assert!(received.as_ref() == Some(&data), "{name}");
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn slow_consumer_lags() {
+        let options = OverlayOptions::default().with_received_broadcasts_capacity(Some(2));
+        let cluster = OverlayTestCluster::new(2, options).unwrap();
+        let overlay = cluster.node(1).overlay();
+
+        for i in 0..5 {
+            cluster.node(0).broadcast(vec![i; 100]);
+        }
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while overlay.metrics().received_broadcasts_dropped < 3 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        let metrics = overlay.metrics();
+        assert_eq!(metrics.received_broadcasts_capacity, Some(2));
+        assert_eq!(metrics.received_broadcasts_data_len, 2);
+        assert_eq!(metrics.received_broadcasts_dropped, 3);
+        assert!(metrics.oldest_received_broadcast_age_ms.is_some());
+
+        // Lag is reported once, then the newest broadcasts are received
+        assert!(matches!(
+            overlay.recv_broadcast().await,
+            Err(crate::overlay::BroadcastLagged(3))
+        ));
+        assert_eq!(overlay.recv_broadcast().await.unwrap().data, vec![3; 100]);
+        assert_eq!(overlay.wait_for_broadcast().await.data, vec![4; 100]);
+        assert_eq!(overlay.metrics().oldest_received_broadcast_age_ms, None);
+    }
+
+    #[tokio::test]
+    async fn late_joiner_fetches_missed_broadcasts() {
+        let options = OverlayOptions::default().with_broadcast_history(16, 1 << 20);
+        let cluster = OverlayTestCluster::new(3, options).unwrap();
+        let (late, peer) = (cluster.node(2), cluster.node(1));
+        let peer_id = *peer.overlay().overlay_key().id();
+
+        // Broadcasts are sent while the node is unreachable
+        cluster.partition(&[&[0, 1], &[2]]);
+        let sent = (0..3).map(|i| vec![i; 100]).collect::<Vec<_>>();
+        for data in &sent {
+            cluster.node(0).broadcast(data.clone());
+            assert_eq!(receive(&cluster, &[1]).await[0].as_ref(), Some(data));
+        }
+        assert!(nothing_received(&cluster, &[2]).await);
+        assert_eq!(peer.overlay().metrics().broadcast_history_len, 3);
+
+        cluster.heal();
+        let fetched = late
+            .overlay()
+            .fetch_missed_broadcasts(late.adnl(), &peer_id, Some(1000))
+            .await
+            .unwrap();
+        assert_eq!(fetched, 3);
+
+        let mut received = Vec::new();
+        for _ in 0..3 {
+            received.push(receive(&cluster, &[2]).await.remove(0).unwrap());
+        }
+        received.sort();
+        assert_eq!(received, sent);
+
+        // Already known broadcasts are not fetched again
+        let fetched = late
+            .overlay()
+            .fetch_missed_broadcasts(late.adnl(), &peer_id, Some(1000))
+            .await
+            .unwrap();
+        assert_eq!(fetched, 0);
+        assert!(nothing_received(&cluster, &[2]).await);
+
+        // Retention is disabled by default
+        assert_eq!(OverlayOptions::default().broadcast_history, 0);
+    }
+
+    #[tokio::test]
+    async fn dedup_prevents_loops() {
+        let cluster = OverlayTestCluster::new(NODES, flooding_options()).unwrap();
+
+        cluster.node(0).broadcast(vec![2; 100]);
+
+        let others = (1..NODES).collect::<Vec<_>>();
+        for data in receive(&cluster, &others).await {
+            assert_eq!(data.unwrap(), vec![2; 100]);
+        }
+
+        // Forwarded copies are ignored
+        let all = (0..NODES).collect::<Vec<_>>();
+        assert!(nothing_received(&cluster, &all).await);
+
+        let duplicated = cluster
+            .nodes()
+            .iter()
+            .map(|node| node.overlay().metrics().broadcasts_duplicated)
+            .sum::<u64>();
+        assert!(duplicated > 0);
+    }
+
+    #[tokio::test]
+    async fn partition_and_heal() {
+        let cluster = OverlayTestCluster::new(NODES, flooding_options()).unwrap();
+        let (left, right): (&[usize], &[usize]) = (&[0, 1, 2], &[3, 4, 5]);
+
+        // Broadcast spreads only inside the partition
+        cluster.partition(&[left, right]);
+        cluster.node(0).broadcast(vec![3; 100]);
+        for data in receive(&cluster, &[1, 2]).await {
+            assert_eq!(data.unwrap(), vec![3; 100]);
+        }
+        assert!(nothing_received(&cluster, right).await);
+
+        // And reaches everyone after heal
+        cluster.heal();
+        cluster.node(4).broadcast(vec![4; 100]);
+        for data in receive(&cluster, &[0, 1, 2, 3, 5]).await {
+            assert_eq!(data.unwrap(), vec![4; 100]);
+        }
+
+        // Each broadcast is delivered only once
+        let all = (0..NODES).collect::<Vec<_>>();
+        assert!(nothing_received(&cluster, &all).await);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn fec_broadcast_over_lossy_links() {
+        let network = adnl::VirtualNetwork::new(3);
+        network.set_default_link(adnl::LinkOptions {
+            loss: 0.3,
+            ..Default::default()
+        });
+        let cluster = OverlayTestCluster::with_network(network, NODES, flooding_options()).unwrap();
+
+        let data: Vec<u8> = (0..4 * 1024).map(|_| rand::random()).collect();
+        let info = cluster.node(0).broadcast(data.clone());
+        assert!(info.packets > 1);
+
+        let others = (1..NODES).collect::<Vec<_>>();
+        for received in receive(&cluster, &others).await {
+            assert!(received.unwrap() == data);
+        }
+        assert!(cluster.network().packets_dropped() > 0);
+    }
+
+    /// Answers echo queries
+    struct Echo;
+
+    #[async_trait::async_trait]
+    impl QuerySubscriber for Echo {
+        async fn try_consume_query<'a>(
+            &self,
+            _: SubscriberContext<'a>,
+            constructor: u32,
+            query: Cow<'a, [u8]>,
+        ) -> anyhow::Result<QueryConsumingResult<'a>> {
+            if constructor != proto::rpc::NetworkEcho::TL_ID {
+                return Ok(QueryConsumingResult::reject(query));
+            }
+            let query = tl_proto::deserialize::<proto::rpc::NetworkEcho>(&query)?;
+            QueryConsumingResult::consume(proto::adnl::EchoAnswer {
+                data: query.data,
+                received_at: 0,
+            })
+        }
+    }
+
+    /// Answers all queries with something unexpected
+    struct Malformed;
+
+    #[async_trait::async_trait]
+    impl QuerySubscriber for Malformed {
+        async fn try_consume_query<'a>(
+            &self,
+            _: SubscriberContext<'a>,
+            _: u32,
+            _: Cow<'a, [u8]>,
+        ) -> anyhow::Result<QueryConsumingResult<'a>> {
+            QueryConsumingResult::consume(proto::adnl::Pong { value: 0 })
+        }
+    }
+
+    #[tokio::test]
+    async fn query_any_skips_silent_and_malformed_peers() {
+        const ECHO: usize = 1;
+        const MALFORMED: usize = 2;
+
+        // Only the malformed peer answers at first
+        let cluster = OverlayTestCluster::new(NODES, Default::default()).unwrap();
+        let overlay_id = *cluster.overlay_id();
+        cluster
+            .node(MALFORMED)
+            .node()
+            .add_overlay_subscriber(overlay_id, Arc::new(Malformed));
+
+        let client = cluster.node(0);
+        let id = |i: usize| *cluster.node(i).overlay().overlay_key().id();
+        let query_any = |query, attempts, parallelism| {
+            client.overlay().query_any::<_, proto::adnl::EchoAnswer>(
+                OverlayQueryTransport::Adnl(client.adnl()),
+                query,
+                QueryAnyOptions {
+                    attempts,
+                    per_attempt_timeout_ms: 200,
+                    parallelism,
+                    ..Default::default()
+                },
+            )
+        };
+        let echo = |data: u8| proto::rpc::NetworkEcho { data: vec![data] };
+
+        // Nobody answers yet, so each peer is tried once
+        assert!(query_any(echo(0), NODES as u32, 1).await.unwrap().is_none());
+        let overlay = client.overlay();
+        assert_eq!(overlay.peer_score(&id(MALFORMED)), -5);
+        for i in (1..NODES).filter(|&i| i != MALFORMED) {
+            assert_eq!(overlay.peer_score(&id(i)), -2);
+        }
+
+        cluster
+            .node(ECHO)
+            .node()
+            .add_overlay_subscriber(overlay_id, Arc::new(Echo));
+
+        // Echo is found among the peers which don't answer
+        let (peer_id, answer) = query_any(echo(1), NODES as u32, 1).await.unwrap().unwrap();
+        assert_eq!((peer_id, answer.data), (id(ECHO), vec![1]));
+        assert_eq!(overlay.peer_score(&id(ECHO)), -1);
+
+        // Parallel queries don't wait for the silent peers
+        let started_at = std::time::Instant::now();
+        let (peer_id, _) = query_any(echo(2), NODES as u32, NODES as u32)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(peer_id, id(ECHO));
+        assert!(started_at.elapsed() < Duration::from_millis(200));
+
+        // Nobody is queried without attempts
+        assert!(query_any(echo(3), 0, 1).await.unwrap().is_none());
+    }
+
+    /// Answers echo queries with the wrong data
+    struct Liar;
+
+    #[async_trait::async_trait]
+    impl QuerySubscriber for Liar {
+        async fn try_consume_query<'a>(
+            &self,
+            _: SubscriberContext<'a>,
+            _: u32,
+            _: Cow<'a, [u8]>,
+        ) -> anyhow::Result<QueryConsumingResult<'a>> {
+            QueryConsumingResult::consume(proto::adnl::EchoAnswer {
+                data: vec![0xff],
+                received_at: 0,
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn query_any_verifies_answers() {
+        const LIAR: usize = 1;
+
+        // Only the liar answers at first
+        let cluster = OverlayTestCluster::new(NODES, Default::default()).unwrap();
+        let overlay_id = *cluster.overlay_id();
+        cluster
+            .node(LIAR)
+            .node()
+            .add_overlay_subscriber(overlay_id, Arc::new(Liar));
+
+        let client = cluster.node(0);
+        let overlay = client.overlay();
+        let local_id = *overlay.overlay_key().id();
+        let liar_id = *cluster.node(LIAR).overlay().overlay_key().id();
+        let verify_echo = |data: u8| -> AnswerVerifier {
+            Arc::new(move |answer: &[u8]| {
+                matches!(
+                    tl_proto::deserialize::<proto::adnl::EchoAnswer>(answer),
+                    Ok(answer) if answer.data == [data]
+                )
+            })
+        };
+        let query_any = |data: u8, attempts| {
+            overlay.query_any::<_, proto::adnl::EchoAnswer>(
+                OverlayQueryTransport::Adnl(client.adnl()),
+                proto::rpc::NetworkEcho { data: vec![data] },
+                QueryAnyOptions {
+                    attempts,
+                    per_attempt_timeout_ms: 200,
+                    verify: Some(verify_echo(data)),
+                    ..Default::default()
+                },
+            )
+        };
+
+        // Wrong answer is not returned
+        assert!(query_any(0, NODES as u32).await.unwrap().is_none());
+        assert_eq!(overlay.peer_score(&liar_id), -5);
+        assert_eq!(client.adnl().metrics().answers_unverified, 1);
+
+        // Direct queries fail
+        let mut query = overlay.query_prefix().to_vec();
+        query.extend(tl_proto::serialize(proto::rpc::NetworkEcho {
+            data: vec![1],
+        }));
+        for _ in 0..4 {
+            let err = client
+                .adnl()
+                .query_with_options::<_, proto::adnl::EchoAnswer>(
+                    &local_id,
+                    &liar_id,
+                    tl_proto::RawBytes::<tl_proto::Boxed>::new(&query),
+                    Some(1000),
+                    adnl::QueryOptions {
+                        verify: Some(verify_echo(1)),
+                        ..Default::default()
+                    },
+                )
+                .await
+                .unwrap_err();
+            assert_eq!(
+                err.downcast::<AnswerVerificationFailed>().unwrap(),
+                AnswerVerificationFailed { peer_id: liar_id }
+            );
+        }
+        assert_eq!(overlay.peer_score(&liar_id), -25);
+        assert_eq!(client.adnl().metrics().answers_unverified, 5);
+
+        // Honest peers are preferred after that
+        for i in (1..NODES).filter(|&i| i != LIAR) {
+            cluster
+                .node(i)
+                .node()
+                .add_overlay_subscriber(overlay_id, Arc::new(Echo));
+        }
+        let mut from_liar = 0;
+        for i in 0..20 {
+            match query_any(i, 1).await.unwrap() {
+                Some((peer_id, answer)) => {
+                    assert_ne!(peer_id, liar_id);
+                    assert_eq!(answer.data, [i]);
+                }
+                None => from_liar += 1,
+            }
+        }
+        assert!(from_liar <= 2, "{from_liar}");
+    }
+}
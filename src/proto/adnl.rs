@@ -1,4 +1,4 @@
-use std::net::{Ipv4Addr, SocketAddrV4};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
 
 use smallvec::SmallVec;
 use tl_proto::{Bare, Boxed, BoxedConstructor, TlError, TlPacket, TlRead, TlResult, TlWrite};
@@ -7,7 +7,7 @@ use super::HashRef;
 
 #[derive(Clone)]
 pub struct OutgoingPacketContents<'tl> {
-    /// 7 or 3 random bytes
+    /// 7 or 3 random bytes (or padding of the MTU probe)
     pub rand1: &'tl [u8],
     pub from: Option<everscale_crypto::tl::PublicKey<'tl>>,
     pub messages: OutgoingMessages<'tl>,
@@ -25,7 +25,7 @@ impl<'tl> TlWrite for OutgoingPacketContents<'tl> {
 
     fn max_size_hint(&self) -> usize {
         4 // constructor
-            + 8 // rand1 (1 byte length, 7 bytes data)
+            + self.rand1.max_size_hint()
             + 4 // flags
             + self.from.max_size_hint()
             + self.messages.max_size_hint()
@@ -34,7 +34,7 @@ impl<'tl> TlWrite for OutgoingPacketContents<'tl> {
             + 8 // confirm_seqno
             + self.reinit_dates.max_size_hint()
             + self.signature.max_size_hint()
-            + 4 // rand2 (1 byte length, 3 bytes data)
+            + self.rand2.max_size_hint()
     }
 
     fn write_to<P>(&self, packet: &mut P)
@@ -122,14 +122,29 @@ pub struct IncomingPacketContents<'tl> {
     pub signature: Option<PacketContentsSignature>,
 }
 
-impl IncomingPacketContents<'_> {
+/// Max number of messages in the incoming packet, used by [`TlRead`] implementation.
+///
+/// NOTE: packets with more messages (including the single `message` field)
+/// are rejected with [`TlError::InvalidData`], use
+/// [`IncomingPacketContents::read_with_limit`] for a different limit
+pub const DEFAULT_MAX_PACKET_MESSAGES: usize = 64;
+
+impl<'tl> IncomingPacketContents<'tl> {
     const TL_ID: u32 = tl_proto::id!("adnl.packetContents", scheme = "scheme.tl");
-}
 
-impl<'tl> TlRead<'tl> for IncomingPacketContents<'tl> {
-    type Repr = Boxed;
+    /// Reads packet contents with at most `max_messages` messages.
+    /// Returns parsed contents and the number of unconsumed trailing bytes.
+    pub fn read_with_limit(packet: &'tl [u8], max_messages: usize) -> TlResult<(Self, usize)> {
+        let mut offset = 0;
+        let contents = ok!(Self::read_from_impl(packet, &mut offset, max_messages));
+        Ok((contents, packet.len().saturating_sub(offset)))
+    }
 
-    fn read_from(packet: &'tl [u8], offset: &mut usize) -> TlResult<Self> {
+    fn read_from_impl(
+        packet: &'tl [u8],
+        offset: &mut usize,
+        max_messages: usize,
+    ) -> TlResult<Self> {
         #[inline(always)]
         fn read_optional<'tl, T: TlRead<'tl>, const N: usize>(
             flags: u32,
@@ -154,7 +169,7 @@ impl<'tl> TlRead<'tl> for IncomingPacketContents<'tl> {
 
         ok!(<&[u8] as TlRead>::read_from(packet, offset)); // rand1
 
-        let flags_offset = *offset as u16;
+        let flags_offset = ok!(u16::try_from(*offset).map_err(|_| TlError::InvalidData));
         let flags = ok!(u32::read_from(packet, offset));
 
         let from = ok!(read_optional::<everscale_crypto::tl::PublicKey, 0>(
@@ -163,9 +178,21 @@ impl<'tl> TlRead<'tl> for IncomingPacketContents<'tl> {
         let from_short = ok!(read_optional::<HashRef, 1>(flags, packet, offset));
 
         let message = ok!(read_optional::<Message, 2>(flags, packet, offset));
-        let messages = ok!(read_optional::<SmallVec<[Message<'tl>; 2]>, 3>(
-            flags, packet, offset
-        ));
+        let messages = if flags & (0b1 << 3) != 0 {
+            let len = ok!(u32::read_from(packet, offset)) as usize;
+            match len.checked_add(message.is_some() as usize) {
+                Some(total) if total <= max_messages => {}
+                _ => return Err(TlError::InvalidData),
+            }
+
+            let mut messages = SmallVec::with_capacity(len);
+            for _ in 0..len {
+                messages.push(ok!(Message::read_from(packet, offset)));
+            }
+            Some(messages)
+        } else {
+            None
+        };
 
         let address = ok!(read_optional::<AddressList, 4>(flags, packet, offset));
         ok!(read_optional::<AddressList, 5>(flags, packet, offset)); // priority_address
@@ -179,16 +206,17 @@ impl<'tl> TlRead<'tl> for IncomingPacketContents<'tl> {
         let reinit_dates = ok!(read_optional::<ReinitDates, 10>(flags, packet, offset));
 
         let signature = if flags & (0b1 << 11) != 0 {
-            let signature_start = *offset as u16;
+            let signature_start = ok!(u16::try_from(*offset).map_err(|_| TlError::InvalidData));
             let signature = ok!(<&[u8]>::read_from(packet, offset));
-            let signature_end = *offset as u16;
+            let signature_end = ok!(u16::try_from(*offset).map_err(|_| TlError::InvalidData));
 
-            if signature.len() != 64 {
-                return Err(TlError::UnexpectedEof);
-            }
+            let signature = match <[u8; 64]>::try_from(signature) {
+                Ok(signature) => signature,
+                Err(_) => return Err(TlError::UnexpectedEof),
+            };
 
             Some(PacketContentsSignature {
-                signature: signature.try_into().unwrap(),
+                signature,
                 flags_offset,
                 signature_start,
                 signature_end,
@@ -224,6 +252,16 @@ impl<'tl> TlRead<'tl> for IncomingPacketContents<'tl> {
     }
 }
 
+/// Reads packet contents with at most [`DEFAULT_MAX_PACKET_MESSAGES`] messages
+impl<'tl> TlRead<'tl> for IncomingPacketContents<'tl> {
+    type Repr = Boxed;
+
+    #[inline(always)]
+    fn read_from(packet: &'tl [u8], offset: &mut usize) -> TlResult<Self> {
+        Self::read_from_impl(packet, offset, DEFAULT_MAX_PACKET_MESSAGES)
+    }
+}
+
 #[derive(Copy, Clone)]
 pub struct PacketContentsSignature {
     signature: [u8; 64],
@@ -233,50 +271,36 @@ pub struct PacketContentsSignature {
 }
 
 impl PacketContentsSignature {
-    /// Modifies the content of the packet even though the PacketView
-    /// is passed as a constant reference
-    ///
-    /// # Safety
+    /// Writes the signed part of the packet (the packet without the signature
+    /// and with the signature flag reset) into the buffer and returns the signature.
     ///
-    /// * Must be called only once on same packet
-    ///
-    pub unsafe fn extract<'a>(self, packet: &mut [u8]) -> Option<(&'a [u8], [u8; 64])> {
-        let origin = packet.as_mut_ptr();
-
-        // `packet` before:
+    /// Returns `None` if the signature offsets don't match the packet
+    pub fn extract_to(&self, packet: &[u8], buffer: &mut Vec<u8>) -> Option<[u8; 64]> {
+        // `packet`:
         // [............_*__.................|__________________|.........]
         // flags_offset ^     signature_start ^    signature_end ^
 
         // NOTE: `flags_offset + 1` is used because flags are stored in LE bytes order and
         // we need the second byte (signature mask - 0x0800)
-        let (signature_len, remaining) = match (packet.len() as u16, self.flags_offset + 1) {
-            (packet_len, flags_offset)
-                if flags_offset < packet_len
-                    && self.signature_start < self.signature_end
-                    && self.signature_end < packet_len =>
-            {
-                packet[flags_offset as usize] &= 0xf7; // reset signature bit
-
-                (
-                    self.signature_end - self.signature_start, // signature len
-                    packet_len - self.signature_end,           // remaining
-                )
-            }
-            _ => return None,
-        };
-
-        let src = origin.add(self.signature_end as usize);
-        let dst = origin.add(self.signature_start as usize);
-        std::ptr::copy(src, dst, remaining as usize);
+        let flags_offset = self.flags_offset as usize + 1;
+        let signature_start = self.signature_start as usize;
+        let signature_end = self.signature_end as usize;
+        if flags_offset >= signature_start
+            || signature_start >= signature_end
+            || signature_end >= packet.len()
+        {
+            return None;
+        }
 
-        // `packet` after:
-        // [............_0__.................||.........]-----removed-----]
+        // `buffer`:
+        // [............_0__.................||.........]
         // flags_offset ^     signature_start ^
+        buffer.reserve(packet.len() - (signature_end - signature_start));
+        buffer.extend_from_slice(&packet[..signature_start]);
+        buffer[flags_offset] &= 0xf7; // reset signature bit
+        buffer.extend_from_slice(&packet[signature_end..]);
 
-        Some((
-            std::slice::from_raw_parts(origin, packet.len() - signature_len as usize),
-            self.signature,
-        ))
+        Some(self.signature)
     }
 }
 
@@ -333,12 +357,13 @@ pub enum Message<'tl> {
     Reinit { date: u32 },
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Clone)]
 pub struct AddressList {
-    /// Single address instead of list, because only one is always passed
-    pub address: Option<Address>,
+    /// Addresses in the order of preference
+    pub addresses: SmallVec<[Address; 2]>,
     pub version: u32,
     pub reinit_date: u32,
+    pub priority: u32,
     pub expire_at: u32,
 }
 
@@ -351,23 +376,25 @@ impl TlWrite for AddressList {
 
     fn max_size_hint(&self) -> usize {
         // 4 bytes - address vector size
-        // optional address size
+        // 12 bytes - each address
         // 4 bytes - version
         // 4 bytes - reinit_date
         // 4 bytes - priority
         // 4 bytes - expire_at
-        20 + self.address.max_size_hint()
+        20 + self.addresses.len() * 12
     }
 
     fn write_to<P>(&self, packet: &mut P)
     where
         P: TlPacket,
     {
-        u32::write_to(&(self.address.is_some() as u32), packet);
-        self.address.write_to(packet);
+        u32::write_to(&(self.addresses.len() as u32), packet);
+        for address in &self.addresses {
+            address.write_to(packet);
+        }
         self.version.write_to(packet);
         self.reinit_date.write_to(packet);
-        0u32.write_to(packet); // priority
+        self.priority.write_to(packet);
         self.expire_at.write_to(packet);
     }
 }
@@ -376,29 +403,38 @@ impl<'tl> TlRead<'tl> for AddressList {
     type Repr = Bare;
 
     fn read_from(packet: &'tl [u8], offset: &mut usize) -> TlResult<Self> {
-        let address_count = ok!(u32::read_from(packet, offset));
-        let mut address = None;
+        let address_count = ok!(u32::read_from(packet, offset)) as usize;
+
+        // NOTE: addresses after the limit are skipped
+        let mut addresses = SmallVec::with_capacity(address_count.min(MAX_ADDRESS_LIST_LEN));
         for _ in 0..address_count {
-            let item = ok!(Address::read_from(packet, offset));
-            if address.is_none() {
-                address = Some(item);
+            let address = ok!(Address::read_from(packet, offset));
+            if addresses.len() < MAX_ADDRESS_LIST_LEN {
+                addresses.push(address);
             }
         }
 
         let version = ok!(u32::read_from(packet, offset));
         let reinit_date = ok!(u32::read_from(packet, offset));
-        let _priority = ok!(u32::read_from(packet, offset));
+        let priority = ok!(u32::read_from(packet, offset));
         let expire_at = ok!(u32::read_from(packet, offset));
 
         Ok(Self {
-            address,
+            addresses,
             version,
             reinit_date,
+            priority,
             expire_at,
         })
     }
 }
 
+/// Max number of addresses in the address list which are used.
+/// The rest are skipped when the list is parsed and ignored by the [`AddressListBuilder`]
+///
+/// [`AddressListBuilder`]: crate::util::AddressListBuilder
+pub const MAX_ADDRESS_LIST_LEN: usize = 16;
+
 #[derive(Debug, Copy, Clone, TlRead, TlWrite)]
 #[tl(boxed, id = "adnl.address.udp", scheme = "scheme.tl", size_hint = 8)]
 pub struct Address {
@@ -421,12 +457,171 @@ impl From<Address> for SocketAddrV4 {
     }
 }
 
+impl From<SocketAddrV4> for Address {
+    #[inline]
+    fn from(addr: SocketAddrV4) -> Self {
+        Self::from(&addr)
+    }
+}
+
+impl TryFrom<SocketAddr> for Address {
+    type Error = ParseAddressError;
+
+    fn try_from(addr: SocketAddr) -> Result<Self, Self::Error> {
+        match addr {
+            SocketAddr::V4(addr) => Ok(Self::from(&addr)),
+            SocketAddr::V6(_) => Err(ParseAddressError::Ipv6NotSupported),
+        }
+    }
+}
+
+/// Parses address from `ip:port` string (e.g. `1.2.3.4:30303`)
+impl std::str::FromStr for Address {
+    type Err = ParseAddressError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.parse::<SocketAddr>() {
+            Ok(addr) => Self::try_from(addr),
+            Err(_) => Err(ParseAddressError::InvalidAddress),
+        }
+    }
+}
+
+impl std::fmt::Display for Address {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", Ipv4Addr::from(self.ip), self.port)
+    }
+}
+
+/// Serializes address as `ip:port` string
+impl serde::Serialize for Address {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Address {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use serde::de::{Error, Visitor};
+
+        struct AddressVisitor;
+
+        impl<'de> Visitor<'de> for AddressVisitor {
+            type Value = Address;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("IPv4 socket address string")
+            }
+
+            fn visit_str<E: Error>(self, v: &str) -> Result<Self::Value, E> {
+                v.parse().map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_str(AddressVisitor)
+    }
+}
+
+/// Error while parsing [`Address`]
+#[derive(thiserror::Error, Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ParseAddressError {
+    #[error("Invalid socket address")]
+    InvalidAddress,
+    #[error("IPv6 addresses are not supported")]
+    Ipv6NotSupported,
+}
+
 #[derive(Debug, Copy, Clone, TlRead, TlWrite)]
 #[tl(boxed, id = "adnl.pong", size_hint = 8, scheme = "scheme.tl")]
 pub struct Pong {
     pub value: u64,
 }
 
+/// Answer to the [`crate::proto::rpc::NetworkEcho`] query
+#[derive(Debug, Clone, TlRead, TlWrite)]
+#[tl(boxed, id = "network.echoAnswer", scheme = "scheme.tl")]
+pub struct EchoAnswer {
+    pub data: Vec<u8>,
+    /// Unix timestamp in milliseconds when the query was received
+    pub received_at: u64,
+}
+
+/// Explicit answer for the rejected query
+#[derive(Debug, Copy, Clone, TlRead, TlWrite)]
+#[tl(
+    boxed,
+    id = "network.queryRejected",
+    size_hint = 4,
+    scheme = "scheme.tl"
+)]
+pub struct QueryRejected {
+    /// See [`crate::RejectReason`]
+    pub reason: u32,
+}
+
+/// Explicit RLDP answer for the query whose answer exceeds the declared `max_answer_size`
+#[derive(Debug, Copy, Clone, TlRead, TlWrite)]
+#[tl(
+    boxed,
+    id = "network.answerTooLarge",
+    size_hint = 8,
+    scheme = "scheme.tl"
+)]
+pub struct AnswerTooLarge {
+    /// Size of the answer data in bytes
+    pub size: u64,
+}
+
+/// Answer of the query wrapped into [`crate::proto::rpc::PrivateQueryWithAnswerKey`],
+/// encrypted with the shared secret of the local key and the answer key.
+///
+/// NOTE: this is a crate specific format, it is not understood by the other
+/// implementations
+#[derive(Debug, Copy, Clone, TlRead, TlWrite)]
+#[tl(boxed, id = "network.privateEncryptedAnswer", scheme = "scheme.tl")]
+pub(crate) struct PrivateEncryptedAnswer<'tl> {
+    /// SHA256 of the plain answer
+    pub checksum: HashRef<'tl>,
+    pub data: &'tl [u8],
+}
+
+/// Answer to the [`crate::proto::rpc::NetworkRendezvous`] query
+#[derive(Debug, Clone, TlRead, TlWrite)]
+#[tl(boxed, scheme = "scheme.tl")]
+pub enum RendezvousPeer {
+    /// Full id of the target and its address observed by the rendezvous peer
+    #[tl(id = "network.rendezvousPeer")]
+    Found {
+        id: everscale_crypto::tl::PublicKeyOwned,
+        address: Address,
+    },
+    #[tl(id = "network.rendezvousPeerNotFound")]
+    NotFound,
+}
+
+/// Custom message from the rendezvous peer with the peer to punch the hole to
+#[derive(Debug, Copy, Clone, TlRead, TlWrite)]
+#[tl(boxed, id = "network.holePunch", scheme = "scheme.tl")]
+pub struct HolePunch<'tl> {
+    pub id: everscale_crypto::tl::PublicKey<'tl>,
+    /// Address of the peer observed by the rendezvous peer
+    pub address: Address,
+}
+
+/// Custom message which is sent to the peer after the channel is established
+#[derive(Debug, Copy, Clone, TlRead, TlWrite)]
+#[tl(
+    boxed,
+    id = "network.capabilities",
+    size_hint = 12,
+    scheme = "scheme.tl"
+)]
+pub struct Capabilities {
+    pub version: u32,
+    /// See [`crate::adnl::PeerCapabilities`]
+    pub features: u64,
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -442,4 +637,173 @@ mod tests {
         let test = SocketAddrV4::from(test);
         assert_eq!(test, addr);
     }
+
+    #[test]
+    fn addr_string_conversion() {
+        let addr: Address = "1.2.3.4:30303".parse().unwrap();
+        assert_eq!(addr.ip, 0x01020304);
+        assert_eq!(addr.port, 30303);
+        assert_eq!(addr.to_string(), "1.2.3.4:30303");
+        assert_eq!(addr.to_string().parse::<Address>().unwrap().ip, addr.ip);
+
+        let json = serde_json::to_string(&addr).unwrap();
+        assert_eq!(json, "\"1.2.3.4:30303\"");
+        let parsed: Address = serde_json::from_str(&json).unwrap();
+        assert_eq!(SocketAddrV4::from(parsed), SocketAddrV4::from(addr));
+        assert!(serde_json::from_str::<Address>("\"1.2.3.4\"").is_err());
+
+        for bad in [
+            "1.2.3.4:65536",
+            "1.2.3.4:port",
+            "1.2.3.4",
+            "garbage:30303",
+            "",
+        ] {
+            assert_eq!(
+                bad.parse::<Address>().unwrap_err(),
+                ParseAddressError::InvalidAddress
+            );
+        }
+
+        let v6: SocketAddr = "[::1]:30303".parse().unwrap();
+        assert_eq!(
+            Address::try_from(v6).unwrap_err(),
+            ParseAddressError::Ipv6NotSupported
+        );
+        assert_eq!(
+            "[::1]:30303".parse::<Address>().unwrap_err(),
+            ParseAddressError::Ipv6NotSupported
+        );
+    }
+
+    fn make_packet(message: bool, messages: Option<u32>, trailing: &[u8]) -> Vec<u8> {
+        let mut packet = Vec::new();
+        packet.write_u32(IncomingPacketContents::TL_ID);
+        (&[] as &[u8]).write_to(&mut packet); // rand1
+        packet.write_u32(((message as u32) << 2) | ((messages.is_some() as u32) << 3));
+        if message {
+            Message::Nop.write_to(&mut packet);
+        }
+        if let Some(count) = messages {
+            packet.write_u32(count);
+            for _ in 0..count {
+                Message::Nop.write_to(&mut packet);
+            }
+        }
+        (&[] as &[u8]).write_to(&mut packet); // rand2
+        packet.extend_from_slice(trailing);
+        packet
+    }
+
+    #[test]
+    fn packet_messages_limit() {
+        let read = |packet: &[u8], max| {
+            IncomingPacketContents::read_with_limit(packet, max)
+                .map(|(contents, trailing)| (contents.messages.len(), trailing))
+        };
+
+        // Within the limit
+        assert!(matches!(read(&make_packet(true, None, &[]), 1), Ok((1, 0))));
+        assert!(matches!(
+            read(&make_packet(false, Some(4), &[]), 4),
+            Ok((4, 0))
+        ));
+        assert!(matches!(
+            read(&make_packet(true, Some(3), &[]), 4),
+            Ok((4, 0))
+        ));
+
+        // Limit exceeded
+        assert!(matches!(
+            read(&make_packet(false, Some(5), &[]), 4),
+            Err(TlError::InvalidData)
+        ));
+        assert!(matches!(
+            read(&make_packet(true, Some(4), &[]), 4),
+            Err(TlError::InvalidData)
+        ));
+
+        // Huge count is rejected before reading any items
+        let mut packet = make_packet(false, Some(0), &[]);
+        packet[12..16].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(read(&packet, 4), Err(TlError::InvalidData)));
+
+        // Default limit
+        let max = DEFAULT_MAX_PACKET_MESSAGES as u32;
+        let deserialize = |single, count| {
+            let packet = make_packet(single, Some(count), &[]);
+            tl_proto::deserialize::<IncomingPacketContents>(&packet)
+                .map(|contents| contents.messages.len())
+        };
+        assert!(matches!(deserialize(false, max), Ok(len) if len == max as usize));
+        assert!(matches!(deserialize(true, max - 1), Ok(len) if len == max as usize));
+        assert!(matches!(
+            deserialize(false, max + 1),
+            Err(TlError::InvalidData)
+        ));
+        assert!(matches!(deserialize(true, max), Err(TlError::InvalidData)));
+    }
+
+    #[test]
+    fn packet_signature_extraction() {
+        let message = tl_proto::serialize(Message::Nop);
+        let mut packet = OutgoingPacketContents {
+            rand1: &[1, 2, 3],
+            from: None,
+            messages: OutgoingMessages::Single(&message),
+            address: AddressList {
+                addresses: Default::default(),
+                version: 1,
+                reinit_date: 2,
+                priority: 0,
+                expire_at: 0,
+            },
+            seqno: 1,
+            confirm_seqno: 0,
+            reinit_dates: None,
+            signature: None,
+            rand2: &[4, 5, 6, 7, 8, 9, 10],
+        };
+        let unsigned = tl_proto::serialize(packet.clone());
+        packet.signature = Some(&[0xaa; 64]);
+        let signed = tl_proto::serialize(packet);
+
+        let parsed = IncomingPacketContents::read_from(&signed, &mut 0).unwrap();
+        let signature = parsed.signature.unwrap();
+
+        let mut buffer = Vec::new();
+        assert_eq!(signature.extract_to(&signed, &mut buffer), Some([0xaa; 64]));
+        assert_eq!(buffer, unsigned);
+
+        // Offsets beyond the packet
+        for len in [0, 8, signature.signature_end as usize] {
+            assert!(signature
+                .extract_to(&signed[..len], &mut Vec::new())
+                .is_none());
+        }
+    }
+
+    #[test]
+    fn packet_trailing_bytes() {
+        let read = |packet: &[u8]| {
+            IncomingPacketContents::read_with_limit(packet, DEFAULT_MAX_PACKET_MESSAGES)
+                .map(|(_, trailing)| trailing)
+        };
+
+        assert!(matches!(read(&make_packet(true, None, &[])), Ok(0)));
+        assert!(matches!(read(&make_packet(true, None, &[1, 2, 3])), Ok(3)));
+
+        // Truncated packet is still an error
+        let packet = make_packet(false, Some(2), &[]);
+        assert!(matches!(
+            read(&packet[..packet.len() - 1]),
+            Err(TlError::UnexpectedEof)
+        ));
+
+        // No messages at all
+        assert!(matches!(
+            read(&make_packet(false, None, &[])),
+            Err(TlError::UnexpectedEof)
+        ));
+    }
 }
@@ -40,7 +40,7 @@ impl BoxedConstructor for NodesOwned {
     const TL_ID: u32 = Nodes::TL_ID;
 }
 
-#[derive(Debug, Copy, Clone, TlWrite, TlRead)]
+#[derive(Debug, Clone, TlWrite, TlRead)]
 pub struct Node<'tl> {
     pub id: everscale_crypto::tl::PublicKey<'tl>,
     pub addr_list: adnl::AddressList,
@@ -56,7 +56,7 @@ impl Node<'_> {
     pub fn as_equivalent_owned(&self) -> NodeOwned {
         NodeOwned {
             id: self.id.as_equivalent_owned(),
-            addr_list: self.addr_list,
+            addr_list: self.addr_list.clone(),
             version: self.version,
             signature: self.signature.to_vec().into(),
         }
@@ -76,10 +76,10 @@ impl BoxedConstructor for NodeOwned {
 }
 
 impl NodeOwned {
-    pub fn as_equivalent_ref(&self) -> Node {
+    pub fn as_equivalent_ref(&self) -> Node<'_> {
         Node {
             id: self.id.as_equivalent_ref(),
-            addr_list: self.addr_list,
+            addr_list: self.addr_list.clone(),
             version: self.version,
             signature: &self.signature,
         }
@@ -122,7 +122,7 @@ impl BoxedConstructor for ValueOwned {
 }
 
 impl ValueOwned {
-    pub fn as_equivalent_ref(&self) -> Value {
+    pub fn as_equivalent_ref(&self) -> Value<'_> {
         Value {
             key: self.key.as_equivalent_ref(),
             value: &self.value,
@@ -213,7 +213,7 @@ impl BoxedConstructor for KeyOwned {
 }
 
 impl KeyOwned {
-    pub fn as_equivalent_ref(&self) -> Key {
+    pub fn as_equivalent_ref(&self) -> Key<'_> {
         Key {
             id: &self.id,
             name: &self.name,
@@ -8,4 +8,7 @@ pub mod overlay;
 pub mod rldp;
 pub mod rpc;
 
+#[cfg(test)]
+mod test_vectors;
+
 pub type HashRef<'a> = &'a [u8; 32];
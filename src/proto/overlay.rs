@@ -52,7 +52,7 @@ pub struct NodeOwned {
 }
 
 impl NodeOwned {
-    pub fn as_equivalent_ref(&self) -> Node {
+    pub fn as_equivalent_ref(&self) -> Node<'_> {
         Node {
             id: self.id.as_equivalent_ref(),
             overlay: &self.overlay,
@@ -62,6 +62,28 @@ impl NodeOwned {
     }
 }
 
+/// Stored known peers of the overlay, see [`Overlay::export_peers_tl`].
+///
+/// Each entry is a serialized [`StoredPeer`], so that corrupted entries
+/// can be skipped without losing the whole list.
+///
+/// [`Overlay::export_peers_tl`]: crate::overlay::Overlay::export_peers_tl
+#[derive(Debug, Clone, TlRead, TlWrite)]
+#[tl(boxed, id = "network.overlayPeers", scheme = "scheme.tl")]
+pub struct StoredPeers<'tl> {
+    pub overlay: HashRef<'tl>,
+    pub peers: Vec<&'tl [u8]>,
+}
+
+/// Signed overlay node with its last known address
+#[derive(Debug, Copy, Clone, TlRead, TlWrite)]
+#[tl(boxed, id = "network.overlayPeer", scheme = "scheme.tl")]
+pub struct StoredPeer<'tl> {
+    pub ip: u32,
+    pub port: u32,
+    pub node: Node<'tl>,
+}
+
 #[derive(TlWrite)]
 #[tl(boxed, id = "overlay.node.toSign", scheme = "scheme.tl")]
 pub struct NodeToSign<'tl> {
@@ -138,6 +160,16 @@ pub enum Broadcast<'tl> {
     Unicast { data: &'tl [u8] },
 }
 
+/// Ids of the broadcasts, see [`crate::proto::rpc::OverlayGetBroadcastList`]
+#[derive(Debug, Clone, Default, TlWrite, TlRead)]
+pub struct BroadcastList {
+    pub hashes: Vec<[u8; 32]>,
+}
+
+impl BoxedConstructor for BroadcastList {
+    const TL_ID: u32 = tl_proto::id!("overlay.broadcastList", scheme = "scheme.tl");
+}
+
 #[derive(Debug, Copy, Clone, TlWrite, TlRead)]
 pub struct OverlayBroadcast<'tl> {
     pub src: everscale_crypto::tl::PublicKey<'tl>,
@@ -8,6 +8,31 @@ pub struct AdnlPing {
     pub value: u64,
 }
 
+#[derive(Clone, TlWrite, TlRead)]
+#[tl(boxed, id = "network.echo", scheme = "scheme.tl")]
+pub struct NetworkEcho {
+    pub data: Vec<u8>,
+}
+
+/// Query wrapper with the one-time public key to which the answer of the
+/// wrapped query must be encrypted.
+///
+/// NOTE: this is a crate specific format, it is not understood by the other
+/// implementations
+#[derive(Copy, Clone, TlWrite, TlRead)]
+#[tl(boxed, id = "network.privateQueryWithAnswerKey", scheme = "scheme.tl")]
+pub(crate) struct PrivateQueryWithAnswerKey<'tl> {
+    pub answer_key: HashRef<'tl>,
+    pub query: &'tl [u8],
+}
+
+/// Asks the rendezvous peer for the observed address of the target
+#[derive(Copy, Clone, TlWrite, TlRead)]
+#[tl(boxed, id = "network.rendezvous", size_hint = 32, scheme = "scheme.tl")]
+pub struct NetworkRendezvous<'tl> {
+    pub target: HashRef<'tl>,
+}
+
 #[derive(Copy, Clone, TlWrite, TlRead)]
 #[tl(boxed, id = "overlay.query", size_hint = 32, scheme = "scheme.tl")]
 pub struct OverlayQuery<'tl> {
@@ -26,6 +51,24 @@ pub struct OverlayGetRandomPeersOwned {
     pub peers: overlay::NodesOwned,
 }
 
+#[derive(Copy, Clone, TlWrite, TlRead)]
+#[tl(
+    boxed,
+    id = "overlay.getBroadcast",
+    size_hint = 32,
+    scheme = "scheme.tl"
+)]
+pub struct OverlayGetBroadcast<'tl> {
+    pub hash: HashRef<'tl>,
+}
+
+/// Asks for the recent broadcast ids which are not in the list
+#[derive(Clone, TlWrite, TlRead)]
+#[tl(boxed, id = "overlay.getBroadcastList", scheme = "scheme.tl")]
+pub struct OverlayGetBroadcastList {
+    pub list: overlay::BroadcastList,
+}
+
 #[derive(TlWrite, TlRead)]
 #[tl(boxed, id = "dht.ping", size_hint = 8, scheme = "scheme.tl")]
 pub struct DhtPing {
@@ -0,0 +1,446 @@
+//! Wire-format corpus with the pinned byte layouts of the main packet types.
+//!
+//! Each vector is stored as a hex file in `src/proto/test_vectors/` and is produced
+//! by the current code from the fixed keys and data below. After an intentional
+//! format change regenerate the corpus and commit the diff:
+//!
+//! ```text
+//! cargo test --lib --all-features proto::test_vectors::regenerate_corpus -- --ignored
+//! ```
+
+use everscale_crypto::ed25519;
+use tl_proto::{BoxedConstructor, TlWrite};
+
+use crate::adnl;
+use crate::proto;
+
+/// Secret key of the node which produced the packets
+const LOCAL_SECRET: [u8; 32] = [1; 32];
+/// Secret key of the packets recipient
+const PEER_SECRET: [u8; 32] = [2; 32];
+/// One-time key of the handshake packet
+const HANDSHAKE_TEMP_SECRET: [u8; 32] = [3; 32];
+/// Channel key of the local node
+const LOCAL_CHANNEL_SECRET: [u8; 32] = [4; 32];
+/// Channel key of the recipient
+const PEER_CHANNEL_SECRET: [u8; 32] = [5; 32];
+
+/// Unix timestamp used in all dates
+const DATE: u32 = 1_700_000_000;
+/// Overlay id of the broadcasts
+#[cfg(feature = "overlay")]
+const OVERLAY_ID: [u8; 32] = [6; 32];
+
+struct Vector {
+    name: &'static str,
+    corpus: &'static str,
+    build: fn() -> Vec<u8>,
+}
+
+const VECTORS: &[Vector] = &[
+    Vector {
+        name: "handshake_packet",
+        corpus: include_str!("test_vectors/handshake_packet.hex"),
+        build: build_handshake_packet,
+    },
+    Vector {
+        name: "channel_packet_query",
+        corpus: include_str!("test_vectors/channel_packet_query.hex"),
+        build: build_channel_packet_query,
+    },
+    #[cfg(feature = "overlay")]
+    Vector {
+        name: "overlay_broadcast",
+        corpus: include_str!("test_vectors/overlay_broadcast.hex"),
+        build: build_overlay_broadcast,
+    },
+    #[cfg(feature = "overlay")]
+    Vector {
+        name: "overlay_fec_broadcast_part",
+        corpus: include_str!("test_vectors/overlay_fec_broadcast_part.hex"),
+        build: build_overlay_fec_broadcast_part,
+    },
+    #[cfg(feature = "rldp")]
+    Vector {
+        name: "rldp_message_part",
+        corpus: include_str!("test_vectors/rldp_message_part.hex"),
+        build: build_rldp_message_part,
+    },
+    Vector {
+        name: "signed_address_list",
+        corpus: include_str!("test_vectors/signed_address_list.hex"),
+        build: build_signed_address_list,
+    },
+];
+
+fn local_key() -> adnl::Key {
+    adnl::Key::from_bytes(LOCAL_SECRET)
+}
+
+fn peer_key() -> adnl::Key {
+    adnl::Key::from_bytes(PEER_SECRET)
+}
+
+fn address_list() -> proto::adnl::AddressList {
+    proto::adnl::AddressList {
+        addresses: smallvec::smallvec![proto::adnl::Address::from(
+            &"1.2.3.4:30303".parse().unwrap()
+        )],
+        version: DATE,
+        reinit_date: DATE,
+        priority: 0,
+        expire_at: DATE + 1000,
+    }
+}
+
+/// Channel between the local node and the peer from the side of `local`
+fn make_channel(
+    local: &adnl::Key,
+    local_channel_secret: [u8; 32],
+    peer: &adnl::Key,
+    peer_channel_secret: [u8; 32],
+) -> adnl::Channel {
+    let channel_key = ed25519::KeyPair::from(&ed25519::SecretKey::from_bytes(local_channel_secret));
+    let peer_channel_key =
+        ed25519::KeyPair::from(&ed25519::SecretKey::from_bytes(peer_channel_secret));
+    adnl::Channel::new(
+        *local.id(),
+        *peer.id(),
+        &channel_key,
+        peer_channel_key.public_key,
+        DATE,
+        adnl::ChannelCreationContext::ConfirmChannel,
+    )
+}
+
+fn serialize_messages(messages: &[proto::adnl::Message]) -> Vec<u8> {
+    let mut buffer = Vec::new();
+    for message in messages {
+        message.write_to(&mut buffer);
+    }
+    buffer
+}
+
+/// Serialized `adnl.packetContents` of the handshake packet
+fn handshake_packet_contents() -> Vec<u8> {
+    let local_key = local_key();
+    let channel_key = ed25519::KeyPair::from(&ed25519::SecretKey::from_bytes(LOCAL_CHANNEL_SECRET));
+    let messages = serialize_messages(&[
+        proto::adnl::Message::CreateChannel {
+            key: channel_key.public_key.as_bytes(),
+            date: DATE,
+        },
+        proto::adnl::Message::Custom { data: b"hello" },
+    ]);
+
+    let mut packet = proto::adnl::OutgoingPacketContents {
+        rand1: &[1, 2, 3],
+        from: Some(local_key.full_id().as_tl()),
+        messages: proto::adnl::OutgoingMessages::Pair(&messages),
+        address: address_list(),
+        seqno: 1,
+        confirm_seqno: 0,
+        reinit_dates: Some(proto::adnl::ReinitDates {
+            local: DATE,
+            target: 0,
+        }),
+        signature: None,
+        rand2: &[4, 5, 6, 7, 8, 9, 10],
+    };
+    let signature = local_key.sign(&packet);
+    packet.signature = Some(&signature);
+    tl_proto::serialize(packet)
+}
+
+fn build_handshake_packet() -> Vec<u8> {
+    let peer_key = peer_key();
+    let mut buffer = handshake_packet_contents();
+    adnl::build_handshake_packet_with_temp_key(
+        peer_key.id(),
+        peer_key.full_id(),
+        &mut buffer,
+        None,
+        &ed25519::SecretKey::from_bytes(HANDSHAKE_TEMP_SECRET),
+    );
+    buffer
+}
+
+/// Serialized `adnl.packetContents` of the channel packet
+fn channel_packet_contents() -> Vec<u8> {
+    let query = tl_proto::serialize(proto::rpc::AdnlPing { value: 42 });
+    let message = serialize_messages(&[proto::adnl::Message::Query {
+        query_id: &[7; 32],
+        query: &query,
+    }]);
+
+    tl_proto::serialize(proto::adnl::OutgoingPacketContents {
+        rand1: &[1, 2, 3],
+        from: None,
+        messages: proto::adnl::OutgoingMessages::Single(&message),
+        address: address_list(),
+        seqno: 2,
+        confirm_seqno: 1,
+        reinit_dates: None,
+        signature: None,
+        rand2: &[4, 5, 6, 7, 8, 9, 10],
+    })
+}
+
+fn build_channel_packet_query() -> Vec<u8> {
+    let channel = make_channel(
+        &local_key(),
+        LOCAL_CHANNEL_SECRET,
+        &peer_key(),
+        PEER_CHANNEL_SECRET,
+    );
+    let mut buffer = channel_packet_contents();
+    channel.encrypt(&mut buffer, false, None);
+    buffer
+}
+
+#[cfg(feature = "overlay")]
+fn build_overlay_broadcast() -> Vec<u8> {
+    let key = local_key();
+    let data = b"overlay broadcast";
+    let signature = key.sign(crate::overlay::make_broadcast_to_sign(data, DATE, None));
+
+    let mut buffer = tl_proto::serialize(proto::overlay::Message {
+        overlay: &OVERLAY_ID,
+    });
+    proto::overlay::Broadcast::Broadcast(proto::overlay::OverlayBroadcast {
+        src: key.full_id().as_tl(),
+        certificate: proto::overlay::Certificate::EmptyCertificate,
+        flags: crate::overlay::BROADCAST_FLAG_ANY_SENDER,
+        data,
+        date: DATE,
+        signature: &signature,
+    })
+    .write_to(&mut buffer);
+    buffer
+}
+
+/// Data of the FEC broadcast and the RLDP transfer (several FEC symbols)
+#[cfg(feature = "rldp")]
+fn fec_data() -> Vec<u8> {
+    (0..2000u32).map(|i| (i % 251) as u8).collect()
+}
+
+#[cfg(feature = "overlay")]
+fn build_overlay_fec_broadcast_part() -> Vec<u8> {
+    use sha2::Digest;
+
+    let key = local_key();
+    let data = fec_data();
+    let data_hash: [u8; 32] = sha2::Sha256::digest(&data).into();
+
+    let mut encoder = crate::rldp::RaptorQEncoder::with_data(&data);
+    let mut seqno = 0;
+    let part = encoder.encode(&mut seqno).unwrap();
+    let flags = crate::overlay::BROADCAST_FLAG_ANY_SENDER;
+
+    let signature = key.sign(crate::overlay::make_fec_part_to_sign(
+        &data_hash,
+        data.len() as u32,
+        DATE,
+        flags,
+        encoder.params(),
+        &part,
+        seqno,
+        None,
+    ));
+
+    let mut buffer = tl_proto::serialize(proto::overlay::Message {
+        overlay: &OVERLAY_ID,
+    });
+    proto::overlay::Broadcast::BroadcastFec(proto::overlay::OverlayBroadcastFec {
+        src: key.full_id().as_tl(),
+        certificate: proto::overlay::Certificate::EmptyCertificate,
+        data_hash: &data_hash,
+        data_size: data.len() as u32,
+        flags,
+        data: &part,
+        seqno,
+        fec: *encoder.params(),
+        date: DATE,
+        signature: &signature,
+    })
+    .write_to(&mut buffer);
+    buffer
+}
+
+#[cfg(feature = "rldp")]
+fn build_rldp_message_part() -> Vec<u8> {
+    let data = fec_data();
+    let mut encoder = crate::rldp::RaptorQEncoder::with_data(&data);
+    let mut seqno = 0;
+    let part = encoder.encode(&mut seqno).unwrap();
+
+    tl_proto::serialize(proto::rldp::MessagePart::MessagePart {
+        transfer_id: &[8; 32],
+        fec_type: *encoder.params(),
+        part: 0,
+        total_size: data.len() as u64,
+        seqno,
+        data: &part,
+    })
+}
+
+fn build_signed_address_list() -> Vec<u8> {
+    let node = crate::util::sign_dht_node(&local_key(), address_list());
+    tl_proto::serialize(node.as_equivalent_ref().into_boxed())
+}
+
+fn corpus_path(name: &str) -> String {
+    format!(
+        "{}/src/proto/test_vectors/{name}.hex",
+        env!("CARGO_MANIFEST_DIR")
+    )
+}
+
+fn decode_corpus(vector: &Vector) -> Vec<u8> {
+    hex::decode(vector.corpus.trim()).unwrap()
+}
+
+#[test]
+fn serializers_reproduce_corpus() {
+    for vector in VECTORS {
+        assert_eq!(
+            hex::encode((vector.build)()),
+            vector.corpus.trim(),
+            "{} differs from the corpus (see the module docs to regenerate it)",
+            vector.name
+        );
+    }
+}
+
+#[test]
+fn parsers_accept_corpus() {
+    #[cfg(feature = "overlay")]
+    use tl_proto::TlRead;
+
+    for vector in VECTORS {
+        let data = decode_corpus(vector);
+
+        match vector.name {
+            "handshake_packet" => {
+                let peer_key = std::sync::Arc::new(peer_key());
+                let (local_id, contents) =
+                    adnl::parse_handshake_packet(std::slice::from_ref(&peer_key), &data)
+                        .unwrap()
+                        .unwrap();
+                assert_eq!(&local_id, peer_key.id());
+                assert_eq!(contents, handshake_packet_contents());
+
+                let packet =
+                    tl_proto::deserialize::<proto::adnl::IncomingPacketContents>(&contents)
+                        .unwrap();
+                let from = adnl::NodeIdFull::try_from(packet.from.unwrap()).unwrap();
+                assert_eq!(from.compute_short_id(), *local_key().id());
+                assert_eq!(packet.messages.len(), 2);
+                assert!(packet.signature.is_some());
+            }
+            "channel_packet_query" => {
+                // Same channel from the side of the recipient
+                let channel = make_channel(
+                    &peer_key(),
+                    PEER_CHANNEL_SECRET,
+                    &local_key(),
+                    LOCAL_CHANNEL_SECRET,
+                );
+                let mut data = data;
+                let mut view = adnl::PacketView::from(data.as_mut_slice());
+                assert_eq!(channel.decrypt(&mut view, false).unwrap(), None);
+                assert_eq!(view.as_bytes(), channel_packet_contents());
+
+                let packet =
+                    tl_proto::deserialize::<proto::adnl::IncomingPacketContents>(view.as_bytes())
+                        .unwrap();
+                assert!(matches!(
+                    packet.messages.as_slice(),
+                    [proto::adnl::Message::Query {
+                        query_id: [7, ..],
+                        ..
+                    }]
+                ));
+                assert_eq!(packet.seqno, Some(2));
+            }
+            #[cfg(feature = "overlay")]
+            "overlay_broadcast" => {
+                let offset = &mut 0;
+                let message = proto::overlay::Message::read_from(&data, offset).unwrap();
+                assert_eq!(message.overlay, &OVERLAY_ID);
+                let broadcast = match proto::overlay::Broadcast::read_from(&data, offset).unwrap() {
+                    proto::overlay::Broadcast::Broadcast(broadcast) => broadcast,
+                    _ => panic!("unexpected broadcast type"),
+                };
+                assert_eq!(*offset, data.len());
+
+                let to_sign =
+                    crate::overlay::make_broadcast_to_sign(broadcast.data, broadcast.date, None);
+                adnl::NodeIdFull::try_from(broadcast.src)
+                    .unwrap()
+                    .verify(to_sign, broadcast.signature)
+                    .unwrap();
+            }
+            #[cfg(feature = "overlay")]
+            "overlay_fec_broadcast_part" => {
+                let offset = &mut 0;
+                proto::overlay::Message::read_from(&data, offset).unwrap();
+                let broadcast = match proto::overlay::Broadcast::read_from(&data, offset).unwrap() {
+                    proto::overlay::Broadcast::BroadcastFec(broadcast) => broadcast,
+                    _ => panic!("unexpected broadcast type"),
+                };
+                assert_eq!(*offset, data.len());
+                assert_eq!(broadcast.data_size, 2000);
+
+                let to_sign = crate::overlay::make_fec_part_to_sign(
+                    broadcast.data_hash,
+                    broadcast.data_size,
+                    broadcast.date,
+                    broadcast.flags,
+                    &broadcast.fec,
+                    broadcast.data,
+                    broadcast.seqno,
+                    None,
+                );
+                adnl::NodeIdFull::try_from(broadcast.src)
+                    .unwrap()
+                    .verify(to_sign, broadcast.signature)
+                    .unwrap();
+            }
+            #[cfg(feature = "rldp")]
+            "rldp_message_part" => {
+                let part = tl_proto::deserialize::<proto::rldp::MessagePart>(&data).unwrap();
+                assert!(matches!(
+                    part,
+                    proto::rldp::MessagePart::MessagePart {
+                        total_size: 2000,
+                        fec_type: proto::rldp::RaptorQFecType {
+                            packet_len: 768,
+                            ..
+                        },
+                        ..
+                    }
+                ));
+            }
+            "signed_address_list" => {
+                let tl_proto::BoxedWrapper(node) =
+                    tl_proto::deserialize::<tl_proto::BoxedWrapper<proto::dht::Node>>(&data)
+                        .unwrap();
+                crate::util::verify_dht_node(&node).unwrap();
+                assert_eq!(node.addr_list.addresses.len(), 1);
+            }
+            name => panic!("no parser check for {name}"),
+        }
+    }
+}
+
+/// Rewrites the corpus files with the output of the current code
+#[test]
+#[ignore]
+fn regenerate_corpus() {
+    for vector in VECTORS {
+        let data = hex::encode((vector.build)());
+        std::fs::write(corpus_path(vector.name), data + "\n").unwrap();
+    }
+}
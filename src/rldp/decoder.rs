@@ -6,24 +6,58 @@ pub struct RaptorQDecoder {
     engine: Decoder,
     params: RaptorQFecType,
     seqno: u32,
+    received: u32,
 }
 
 impl RaptorQDecoder {
-    pub fn with_params(params: RaptorQFecType) -> Self {
-        Self {
+    /// Creates decoder for the received FEC parameters
+    pub fn with_params(params: RaptorQFecType) -> Result<Self, DecoderError> {
+        if params.total_len == 0 {
+            return Err(DecoderError::EmptyData);
+        }
+        if params.packet_len < MIN_PACKET_LEN
+            || params.packet_len > MAX_PACKET_LEN
+            || params.packet_len % SYMBOL_ALIGNMENT != 0
+        {
+            return Err(DecoderError::InvalidPacketLen(params.packet_len));
+        }
+        if params.total_len as u64 > params.packet_len as u64 * MAX_SOURCE_SYMBOLS {
+            return Err(DecoderError::TooBigData(params.total_len));
+        }
+
+        Ok(Self {
             engine: Decoder::new(ObjectTransmissionInformation::with_defaults(
                 params.total_len as u64,
                 params.packet_len as u16,
             )),
             params,
             seqno: 0,
-        }
+            received: 0,
+        })
     }
 
-    pub fn decode(&mut self, seqno: u32, data: Vec<u8>) -> Option<Vec<u8>> {
+    pub fn decode(&mut self, seqno: u32, data: Vec<u8>) -> Result<Option<Vec<u8>>, DecoderError> {
+        if seqno > MAX_SEQNO {
+            return Err(DecoderError::InvalidSeqno(seqno));
+        }
+        if data.len() != self.params.packet_len as usize {
+            return Err(DecoderError::PacketLenMismatch);
+        }
+
         let packet = EncodingPacket::new(PayloadId::new(0, seqno), data);
         self.seqno = seqno;
-        self.engine.decode(packet)
+        self.received = self.received.saturating_add(1);
+        Ok(self.engine.decode(packet))
+    }
+
+    /// Whether the next packet can complete the data.
+    ///
+    /// Packets before that are only stored, so feeding them is cheap.
+    #[cfg(any(test, feature = "overlay"))]
+    pub fn may_complete(&self) -> bool {
+        let packet_len = self.params.packet_len as u64;
+        let source_symbols = (self.params.total_len as u64).div_ceil(packet_len);
+        self.received as u64 + 1 >= source_symbols
     }
 
     pub fn params(&self) -> &RaptorQFecType {
@@ -34,3 +68,83 @@ impl RaptorQDecoder {
         self.seqno
     }
 }
+
+#[derive(thiserror::Error, Debug)]
+pub enum DecoderError {
+    #[error("Empty FEC data")]
+    EmptyData,
+    #[error("Too big FEC data: {0}")]
+    TooBigData(u32),
+    #[error("Invalid FEC packet length: {0}")]
+    InvalidPacketLen(u32),
+    #[error("Invalid FEC packet seqno: {0}")]
+    InvalidSeqno(u32),
+    #[error("FEC packet length mismatch")]
+    PacketLenMismatch,
+}
+
+/// Symbol size must be a multiple of this value
+const SYMBOL_ALIGNMENT: u32 = 8;
+/// At least one sub-block of 8 aligned sub-symbols is required
+const MIN_PACKET_LEN: u32 = 8 * SYMBOL_ALIGNMENT;
+/// Max symbol size which fits into `u16`
+const MAX_PACKET_LEN: u32 = u16::MAX as u32 - u16::MAX as u32 % SYMBOL_ALIGNMENT;
+/// Encoding symbol id is a 24-bit integer
+const MAX_SEQNO: u32 = (1 << 24) - 1;
+/// Max number of source blocks (`u8`) times max source symbols per block
+const MAX_SOURCE_SYMBOLS: u64 = 255 * 56403;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rldp::RaptorQEncoder;
+
+    #[test]
+    fn invalid_params_and_packets() {
+        for (total_len, packet_len) in [
+            (0, 768),
+            (100, 0),
+            (100, 8),
+            (100, 770),
+            (100, 70000),
+            (u32::MAX, 64),
+        ] {
+            let params = RaptorQFecType {
+                total_len,
+                packet_len,
+                packet_count: 1,
+            };
+            assert!(RaptorQDecoder::with_params(params).is_err());
+        }
+
+        let params = RaptorQFecType {
+            total_len: 10,
+            packet_len: 768,
+            packet_count: 1,
+        };
+        let mut decoder = RaptorQDecoder::with_params(params).unwrap();
+        assert!(decoder.decode(0, vec![1; 5]).is_err());
+        assert!(decoder.decode(1 << 24, vec![1; 768]).is_err());
+        assert!(decoder.decode(u32::MAX, vec![1; 768]).is_err());
+        assert_eq!(decoder.decode(0, vec![1; 768]).unwrap().unwrap().len(), 10);
+    }
+
+    #[test]
+    fn roundtrip() {
+        let data = vec![0xaa; 4000];
+        let mut encoder = RaptorQEncoder::with_data(&data);
+        let mut decoder = RaptorQDecoder::with_params(*encoder.params()).unwrap();
+
+        let mut seqno = 0;
+        loop {
+            let packet = encoder.encode(&mut seqno).unwrap();
+            let may_complete = decoder.may_complete();
+            if let Some(decoded) = decoder.decode(seqno, packet).unwrap() {
+                assert!(may_complete);
+                assert_eq!(decoded, data);
+                break;
+            }
+            seqno += 1;
+        }
+    }
+}
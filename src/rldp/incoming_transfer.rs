@@ -34,6 +34,11 @@ impl IncomingTransfer {
         }
     }
 
+    #[inline(always)]
+    pub fn transfer_id(&self) -> &TransferId {
+        &self.transfer_id
+    }
+
     pub fn total_size(&self) -> Option<usize> {
         self.total_size
     }
@@ -78,9 +83,13 @@ impl IncomingTransfer {
                     return Err(IncomingTransferError::PacketParametersMismatch.into())
                 }
                 Some(decoder) => decoder,
-                None => self
-                    .decoder
-                    .get_or_insert_with(|| RaptorQDecoder::with_params(fec_type)),
+                None => {
+                    // Part can't be bigger than the remaining data
+                    if fec_type.total_len as usize > total_size - self.data.len() {
+                        return Err(IncomingTransferError::TooBigTransferSize.into());
+                    }
+                    self.decoder.insert(RaptorQDecoder::with_params(fec_type)?)
+                }
             },
             std::cmp::Ordering::Less => {
                 tl_proto::serialize_into(
@@ -96,7 +105,7 @@ impl IncomingTransfer {
         };
 
         // Decode message data
-        match decoder.decode(message.seqno, message.data) {
+        match decoder.decode(message.seqno, message.data)? {
             Some(data) if data.len() + self.data.len() > total_size => {
                 Err(IncomingTransferError::TooBigTransferSize.into())
             }
@@ -12,9 +12,20 @@ use anyhow::Result;
 use frunk_core::hlist::{HCons, HList, IntoTuple2, Selector};
 use frunk_core::indices::{Here, There};
 
+#[cfg(feature = "overlay")]
 pub(crate) use decoder::RaptorQDecoder;
+#[cfg(all(any(test, feature = "overlay"), not(feature = "test-utils")))]
 pub(crate) use encoder::RaptorQEncoder;
-pub use node::{Node, NodeMetrics, NodeOptions};
+#[cfg(feature = "test-utils")]
+#[doc(hidden)]
+pub use encoder::RaptorQEncoder;
+#[cfg(feature = "overlay")]
+pub(crate) use encoder::MAX_TRANSMISSION_UNIT;
+#[cfg(feature = "fuzzing")]
+pub(crate) use incoming_transfer::{IncomingTransfer, MessagePart};
+pub use node::{AnswerTooLargeError, Node, NodeMetrics, NodeOptions};
+pub use pacing::PacingTimer;
+pub use uploads::UploadMetrics;
 
 use crate::adnl;
 use crate::subscriber::QuerySubscriber;
@@ -26,7 +37,9 @@ mod encoder;
 mod incoming_transfer;
 mod node;
 mod outgoing_transfer;
+mod pacing;
 mod transfers_cache;
+mod uploads;
 
 pub(crate) type Deferred = Result<(Arc<adnl::Node>, Vec<Arc<dyn QuerySubscriber>>, NodeOptions)>;
 
@@ -100,7 +113,7 @@ where
     ///         query: Cow<'a, [u8]>,
     ///     ) -> Result<QueryConsumingResult<'a>> {
     ///         println!("received {constructor}");
-    ///         Ok(QueryConsumingResult::Rejected(query))
+    ///         Ok(QueryConsumingResult::reject(query))
     ///     }
     /// }
     ///
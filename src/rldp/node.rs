@@ -2,16 +2,22 @@ use std::sync::Arc;
 
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
+use tl_proto::{TlRead, TlWrite};
 use tokio::sync::Semaphore;
 
 use super::compression;
+use super::pacing::{Pacing, PacingTimer};
 use super::transfers_cache::*;
+use super::uploads::UploadMetrics;
 use crate::adnl;
 use crate::proto;
 use crate::subscriber::*;
 use crate::util::*;
 
-/// RLDP node configuration
+/// RLDP node configuration.
+///
+/// All fields except `max_peer_queries` and `max_outgoing_transfers_per_peer`
+/// can be changed at runtime with [`Node::update_options`].
 #[derive(Debug, Copy, Clone, Serialize, Deserialize)]
 #[serde(default)]
 pub struct NodeOptions {
@@ -21,7 +27,7 @@ pub struct NodeOptions {
     /// Default: `10485760` (10 MB)
     pub max_answer_size: u32,
 
-    /// Max parallel RLDP queries per peer.
+    /// Max parallel RLDP queries per peer. Construction-only.
     ///
     /// Default: `16`
     pub max_peer_queries: usize,
@@ -38,6 +44,9 @@ pub struct NodeOptions {
 
     /// Number of FEC messages to send in group. There will be a short delay between them.
     ///
+    /// A whole wave is sent on each tick of the pacing timer, so coarse timers
+    /// should be paired with longer waves.
+    ///
     /// Default: `10`
     pub query_wave_len: u32,
 
@@ -46,10 +55,35 @@ pub struct NodeOptions {
     /// Default: `10` ms
     pub query_wave_interval_ms: u64,
 
-    /// Whether requests will be compressed.
+    /// Interval between FEC broadcast waves with microsecond precision.
+    /// Overrides `query_wave_interval_ms` if specified.
+    ///
+    /// Default: `None`
+    pub query_wave_interval_us: Option<u64>,
+
+    /// Whether requests will be compressed even if the peer
+    /// didn't announce [`adnl::PeerCapabilities::RLDP_COMPRESSION`].
     ///
     /// Default: `false`
     pub force_compression: bool,
+
+    /// Max number of answers which are sent to the same peer at once.
+    /// Other answers wait for their turn, see [`Node::peer_metrics`]. Construction-only.
+    ///
+    /// Default: `4`
+    pub max_outgoing_transfers_per_peer: usize,
+
+    /// Max number of answers waiting for [`NodeOptions::max_outgoing_transfers_per_peer`].
+    /// New queries of the peer are answered with [`proto::adnl::QueryRejected`]
+    /// ([`RejectReason::TemporarilyUnavailable`]) when the queue is full.
+    ///
+    /// Default: `16`
+    pub max_queued_outgoing_transfers_per_peer: usize,
+
+    /// Max total rate of the answers sent to the same peer in bytes per second.
+    ///
+    /// Default: `None`
+    pub max_outgoing_rate_per_peer: Option<u64>,
 }
 
 impl Default for NodeOptions {
@@ -61,7 +95,11 @@ impl Default for NodeOptions {
             query_max_timeout_ms: 10000,
             query_wave_len: 10,
             query_wave_interval_ms: 10,
+            query_wave_interval_us: None,
             force_compression: false,
+            max_outgoing_transfers_per_peer: 4,
+            max_queued_outgoing_transfers_per_peer: 16,
+            max_outgoing_rate_per_peer: None,
         }
     }
 }
@@ -72,10 +110,12 @@ pub struct Node {
     adnl: Arc<adnl::Node>,
     /// Parallel requests limiter
     semaphores: FastDashMap<adnl::NodeIdShort, Arc<Semaphore>>,
-    /// Transfers handler
+    /// Transfers handler (also holds the configuration)
     transfers: Arc<TransfersCache>,
-    /// Configuration
-    options: NodeOptions,
+    /// Construction-only parallel requests limit
+    max_peer_queries: usize,
+    /// Construction-only parallel answers limit
+    max_outgoing_transfers_per_peer: usize,
 }
 
 impl Node {
@@ -85,15 +125,34 @@ impl Node {
         subscribers: Vec<Arc<dyn QuerySubscriber>>,
         options: NodeOptions,
     ) -> Result<Arc<Self>> {
-        let transfers = Arc::new(TransfersCache::new(subscribers, options));
+        Self::with_pacing_timer(adnl, subscribers, options, None)
+    }
+
+    /// Create new RLDP node with a custom timer for the outgoing transfers.
+    ///
+    /// The clock of the ADNL node is used if `pacing_timer` is `None`
+    pub fn with_pacing_timer(
+        adnl: Arc<adnl::Node>,
+        subscribers: Vec<Arc<dyn QuerySubscriber>>,
+        options: NodeOptions,
+        pacing_timer: Option<Arc<dyn PacingTimer>>,
+    ) -> Result<Arc<Self>> {
+        let transfers = Arc::new(TransfersCache::new(
+            subscribers,
+            options,
+            Pacing::new(pacing_timer),
+            adnl.register_layer("rldp"),
+        ));
 
         adnl.add_message_subscriber(transfers.clone())?;
+        adnl.add_local_features(adnl::PeerCapabilities::RLDP_COMPRESSION);
 
         Ok(Arc::new(Self {
             adnl,
             semaphores: Default::default(),
             transfers,
-            options,
+            max_peer_queries: options.max_peer_queries,
+            max_outgoing_transfers_per_peer: options.max_outgoing_transfers_per_peer,
         }))
     }
 
@@ -103,26 +162,100 @@ impl Node {
         &self.adnl
     }
 
-    #[inline(always)]
-    pub fn options(&self) -> &NodeOptions {
-        &self.options
+    /// Current configuration
+    pub fn options(&self) -> Arc<NodeOptions> {
+        self.transfers.options().clone()
+    }
+
+    /// Changes configuration at runtime. New values are used for all subsequent queries.
+    ///
+    /// Returns an error and leaves the configuration unchanged
+    /// if a construction-only field was modified.
+    ///
+    /// NOTE: `f` is applied again if the configuration was changed concurrently
+    pub fn update_options<F>(&self, mut f: F) -> Result<()>
+    where
+        F: FnMut(&mut NodeOptions),
+    {
+        self.transfers.update_options(|options| {
+            f(options);
+            if options.max_peer_queries != self.max_peer_queries {
+                return Err(NodeError::ConstructionOnlyOption("max_peer_queries").into());
+            }
+            if options.max_outgoing_transfers_per_peer != self.max_outgoing_transfers_per_peer {
+                return Err(
+                    NodeError::ConstructionOnlyOption("max_outgoing_transfers_per_peer").into(),
+                );
+            }
+            Ok(())
+        })
     }
 
     pub fn metrics(&self) -> NodeMetrics {
+        let uploads = self.transfers.uploads();
+        let (outgoing_transfers, queued_outgoing_transfers) = uploads.len();
         NodeMetrics {
             peer_count: self.semaphores.len(),
             transfers_cache_len: self.transfers.len(),
+            answers_too_large: self.transfers.answers_too_large(),
+            pacing_ticks: self.transfers.pacing().ticks(),
+            symbols_sent: self.transfers.pacing().symbols_sent(),
+            outgoing_transfers,
+            queued_outgoing_transfers,
+            outgoing_transfers_rejected: uploads.rejected(),
         }
     }
 
+    /// Answers which are being sent to the peer or wait for their turn
+    pub fn peer_metrics(&self, peer_id: &adnl::NodeIdShort) -> Option<UploadMetrics> {
+        self.transfers.uploads().peer(peer_id)
+    }
+
+    /// Stops all transfers and background tasks. Incoming messages are ignored afterwards
+    /// and new queries fail.
+    ///
+    /// Must be called after [`overlay::Node::shutdown`] and before [`adnl::Node::shutdown`].
+    ///
+    /// [`overlay::Node::shutdown`]: crate::overlay::Node::shutdown
+    pub fn shutdown(&self) {
+        self.transfers.layer().shutdown();
+        self.transfers.clear();
+        tracing::debug!("RLDP node shut down");
+    }
+
     /// Clears semaphores table
     pub fn gc(&self) {
-        let max_permits = self.options.max_peer_queries;
+        let max_permits = self.max_peer_queries;
         self.semaphores
             .retain(|_, semaphore| semaphore.available_permits() < max_permits);
+        self.transfers.uploads().gc();
     }
 
-    #[tracing::instrument(level = "debug", name = "rldp_query", skip_all, fields(%local_id, %peer_id, ?roundtrip))]
+    /// Serializes and sends RLDP query, then deserializes the answer.
+    /// In case of timeout returns `Ok((None, max_timeout))`
+    pub async fn query_typed<Q, A>(
+        &self,
+        local_id: &adnl::NodeIdShort,
+        peer_id: &adnl::NodeIdShort,
+        query: Q,
+        roundtrip: Option<u64>,
+    ) -> Result<(Option<A>, u64)>
+    where
+        Q: TlWrite,
+        for<'a> A: TlRead<'a, Repr = tl_proto::Boxed> + 'static,
+    {
+        match self
+            .query(local_id, peer_id, tl_proto::serialize(query), roundtrip)
+            .await?
+        {
+            (Some(answer), roundtrip) => Ok((Some(deserialize_answer(&answer)?), roundtrip)),
+            (None, roundtrip) => Ok((None, roundtrip)),
+        }
+    }
+
+    /// Sends serialized RLDP query. In case of timeout returns `Ok((None, max_timeout))`.
+    ///
+    /// Fails with [`AnswerTooLargeError`] if the answer exceeds [`NodeOptions::max_answer_size`]
     pub async fn query(
         &self,
         local_id: &adnl::NodeIdShort,
@@ -130,59 +263,153 @@ impl Node {
         data: Vec<u8>,
         roundtrip: Option<u64>,
     ) -> Result<(Option<Vec<u8>>, u64)> {
-        let (query_id, query) = self.make_query(data);
+        let (_, answer, roundtrip) = self
+            .query_traced(local_id, peer_id, data, roundtrip)
+            .await?;
+        Ok((answer, roundtrip))
+    }
+
+    /// Sends serialized RLDP query and checks the answer with `verify` before returning it.
+    ///
+    /// Fails with [`AnswerVerificationFailed`] if the answer was rejected
+    pub async fn query_verified(
+        &self,
+        local_id: &adnl::NodeIdShort,
+        peer_id: &adnl::NodeIdShort,
+        data: Vec<u8>,
+        roundtrip: Option<u64>,
+        verify: &AnswerVerifier,
+    ) -> Result<(Option<Vec<u8>>, u64)> {
+        let (answer, roundtrip) = self.query(local_id, peer_id, data, roundtrip).await?;
+        if let Some(answer) = &answer {
+            self.adnl.verify_answer(peer_id, answer, verify)?;
+        }
+        Ok((answer, roundtrip))
+    }
+
+    /// Sends serialized RLDP query and also returns its id
+    /// (see [`QueryContext::query_id`] on the remote side).
+    /// In case of timeout returns `Ok((query_id, None, max_timeout))`
+    ///
+    /// [`QueryContext::query_id`]: crate::QueryContext::query_id
+    #[tracing::instrument(
+        level = "debug",
+        name = "rldp_query",
+        skip_all,
+        fields(
+            %local_id,
+            %peer_id,
+            ?roundtrip,
+            query_id = tracing::field::Empty,
+            trace_id = tracing::field::Empty,
+        )
+    )]
+    pub async fn query_traced(
+        &self,
+        local_id: &adnl::NodeIdShort,
+        peer_id: &adnl::NodeIdShort,
+        data: Vec<u8>,
+        roundtrip: Option<u64>,
+    ) -> Result<(adnl::QueryId, Option<Vec<u8>>, u64)> {
+        let layer = self.transfers.layer();
+        if layer.is_shut_down() {
+            return Err(NodeError::ShutDown.into());
+        }
+
+        let (query_id, max_answer_size, query) = self.make_query(local_id, peer_id, data);
+        self.adnl.record_rldp_query();
+        tracing::Span::current().record("query_id", tracing::field::display(query_id));
 
         let peer = self
             .semaphores
             .entry(*peer_id)
-            .or_insert_with(|| Arc::new(Semaphore::new(self.options.max_peer_queries)))
+            .or_insert_with(|| Arc::new(Semaphore::new(self.max_peer_queries)))
             .value()
             .clone();
 
         let result = {
             let _permit = peer.acquire().await.ok();
-            self.transfers
-                .query(&self.adnl, local_id, peer_id, query, roundtrip)
-                .await
+            tokio::select! {
+                result = self.transfers.query(&self.adnl, local_id, peer_id, query, roundtrip) => result,
+                _ = layer.tasks().token().cancelled() => Err(NodeError::ShutDown.into()),
+            }
+        };
+
+        let (answer, roundtrip) = match result? {
+            (Some(answer), roundtrip) => (answer, roundtrip),
+            (None, roundtrip) => return Ok((query_id, None, roundtrip)),
         };
 
-        match result? {
-            (Some(answer), roundtrip) => match tl_proto::deserialize(&answer) {
-                Ok(proto::rldp::Message::Answer {
-                    query_id: answer_id,
-                    data,
-                }) if answer_id == &query_id => Ok((
+        let error = match tl_proto::deserialize(&answer) {
+            Ok(proto::rldp::Message::Answer {
+                query_id: answer_id,
+                data,
+            }) if answer_id == query_id.as_slice() => {
+                if let Ok(proto::adnl::AnswerTooLarge { size }) = tl_proto::deserialize(data) {
+                    return Err(AnswerTooLargeError {
+                        size,
+                        max_answer_size,
+                    }
+                    .into());
+                }
+                return Ok((
+                    query_id,
                     Some(compression::decompress(data).unwrap_or_else(|| data.to_vec())),
                     roundtrip,
-                )),
-                Ok(proto::rldp::Message::Answer { .. }) => Err(NodeError::QueryIdMismatch.into()),
-                Ok(proto::rldp::Message::Message { .. }) => {
-                    Err(NodeError::UnexpectedAnswer("RldpMessageView::Message").into())
-                }
-                Ok(proto::rldp::Message::Query { .. }) => {
-                    Err(NodeError::UnexpectedAnswer("RldpMessageView::Query").into())
-                }
-                Err(e) => Err(NodeError::InvalidPacketContent(e).into()),
-            },
-            (None, roundtrip) => Ok((None, roundtrip)),
-        }
+                ));
+            }
+            Ok(proto::rldp::Message::Answer { .. }) => NodeError::QueryIdMismatch,
+            Ok(proto::rldp::Message::Message { .. }) => {
+                NodeError::UnexpectedAnswer("RldpMessageView::Message")
+            }
+            Ok(proto::rldp::Message::Query { .. }) => {
+                NodeError::UnexpectedAnswer("RldpMessageView::Query")
+            }
+            Err(e) => NodeError::InvalidPacketContent(e),
+        };
+
+        // Peer has sent the whole transfer which is not an answer to this query
+        self.adnl
+            .report_peer(peer_id, adnl::ReputationEvent::CorruptTransfer);
+        Err(error.into())
     }
 
-    fn make_query(&self, mut data: Vec<u8>) -> ([u8; 32], Vec<u8>) {
-        if self.options.force_compression {
+    fn make_query(
+        &self,
+        local_id: &adnl::NodeIdShort,
+        peer_id: &adnl::NodeIdShort,
+        mut data: Vec<u8>,
+    ) -> (adnl::QueryId, u64, Vec<u8>) {
+        let options = self.transfers.options();
+        if options.force_compression
+            || self
+                .adnl
+                .peer_capabilities(local_id, peer_id)
+                .supports(adnl::PeerCapabilities::RLDP_COMPRESSION)
+        {
             if let Err(e) = compression::compress(&mut data) {
                 tracing::warn!("failed to compress RLDP query: {e:?}");
             }
         }
 
-        let query_id = gen_fast_bytes();
+        let query_id = adnl::QueryId(gen_fast_bytes());
+        let max_answer_size = options.max_answer_size as u64;
         let data = proto::rldp::Message::Query {
-            query_id: &query_id,
-            max_answer_size: self.options.max_answer_size as u64,
-            timeout: now() + self.options.query_max_timeout_ms as u32 / 1000,
+            query_id: query_id.as_slice(),
+            max_answer_size,
+            timeout: self.adnl.clock().now() + options.query_max_timeout_ms as u32 / 1000,
             data: &data,
         };
-        (query_id, tl_proto::serialize(data))
+        (query_id, max_answer_size, tl_proto::serialize(data))
+    }
+}
+
+impl Drop for Node {
+    fn drop(&mut self) {
+        if !self.transfers.layer().is_shut_down() {
+            tracing::warn!("RLDP node was dropped without shutdown");
+            self.shutdown();
+        }
     }
 }
 
@@ -210,10 +437,39 @@ impl MessageSubscriber for TransfersCache {
 }
 
 /// Instant RLDP node metrics
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, serde::Serialize)]
 pub struct NodeMetrics {
     pub peer_count: usize,
     pub transfers_cache_len: usize,
+    /// Number of incoming queries whose answers exceeded the requested size
+    #[serde(rename = "answers_too_large_total")]
+    pub answers_too_large: u64,
+    /// Number of pacing timer ticks between the waves of outgoing transfers
+    #[serde(rename = "pacing_ticks_total")]
+    pub pacing_ticks: u64,
+    /// Number of symbols sent by outgoing transfers
+    #[serde(rename = "symbols_sent_total")]
+    pub symbols_sent: u64,
+    /// Number of answers which are being sent
+    pub outgoing_transfers: usize,
+    /// Number of answers waiting for [`NodeOptions::max_outgoing_transfers_per_peer`]
+    pub queued_outgoing_transfers: usize,
+    /// Number of answers which were not sent because the peer queue was full,
+    /// see [`NodeOptions::max_queued_outgoing_transfers_per_peer`]
+    #[serde(rename = "outgoing_transfers_rejected_total")]
+    pub outgoing_transfers_rejected: u64,
+}
+
+/// Peer didn't send the answer because it exceeds [`NodeOptions::max_answer_size`].
+///
+/// The query can be repeated with a bigger limit
+#[derive(thiserror::Error, Debug, Copy, Clone, Eq, PartialEq)]
+#[error("Answer is too large: {size} bytes, max {max_answer_size} bytes")]
+pub struct AnswerTooLargeError {
+    /// Answer size reported by the peer
+    pub size: u64,
+    /// Limit which was declared in the query
+    pub max_answer_size: u64,
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -224,4 +480,862 @@ enum NodeError {
     InvalidPacketContent(tl_proto::TlError),
     #[error("Unknown query id")]
     QueryIdMismatch,
+    #[error("Option `{0}` can't be changed at runtime")]
+    ConstructionOnlyOption(&'static str),
+    #[error("RLDP node is shut down")]
+    ShutDown,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use super::*;
+    use crate::util::fixtures::add_virtual_node;
+    use crate::{NetworkEvent, RldpTransferDirection};
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn transfer_over_lossy_link() {
+        let network = adnl::VirtualNetwork::new(2);
+        network.set_default_link(adnl::LinkOptions {
+            latency_ms: 5,
+            jitter_ms: 5,
+            loss: 0.1,
+            reorder: 0.1,
+            reorder_delay_ms: 5,
+            ..Default::default()
+        });
+
+        // NOTE: encoding is slow in debug builds, so use a bigger min timeout
+        let options = NodeOptions {
+            query_min_timeout_ms: 5000,
+            ..Default::default()
+        };
+
+        let make_node = || {
+            let adnl = add_virtual_node(&network, Default::default());
+            let rldp =
+                Node::new(adnl.clone(), vec![Arc::new(adnl::EchoSubscriber)], options).unwrap();
+            adnl.start().unwrap();
+            rldp
+        };
+
+        let (left, right) = (make_node(), make_node());
+        let left_id = *left.adnl().key_by_tag(0).unwrap().id();
+        let right_key = right.adnl().key_by_tag(0).unwrap();
+        left.adnl()
+            .add_peer(
+                adnl::NewPeerContext::AdnlPacket,
+                &left_id,
+                right_key.id(),
+                right.adnl().socket_addr(),
+                *right_key.full_id(),
+            )
+            .unwrap();
+
+        let data: Vec<u8> = (0..128 * 1024).map(|_| rand::random()).collect();
+        let (answer, _) = left
+            .query_typed::<_, proto::adnl::EchoAnswer>(
+                &left_id,
+                right_key.id(),
+                proto::rpc::NetworkEcho { data: data.clone() },
+                None,
+            )
+            .await
+            .unwrap();
+
+        assert!(answer.unwrap().data == data);
+        assert!(network.packets_dropped() > 0);
+    }
+
+    /// Records ids of the received queries and answers them with a pong
+    #[derive(Default)]
+    struct QueryIdRecorder(parking_lot::Mutex<Vec<(QueryTransport, adnl::QueryId, adnl::TraceId)>>);
+
+    #[async_trait::async_trait]
+    impl QuerySubscriber for QueryIdRecorder {
+        async fn try_consume_query_ext<'a>(
+            &self,
+            _: SubscriberContext<'a>,
+            query_ctx: QueryContext,
+            _: u32,
+            _: Cow<'a, [u8]>,
+        ) -> Result<QueryConsumingResult<'a>> {
+            self.0
+                .lock()
+                .push((query_ctx.transport, query_ctx.query_id, query_ctx.trace_id));
+            Ok(QueryConsumingResult::answer(proto::adnl::Pong { value: 1 }))
+        }
+    }
+
+    #[tokio::test]
+    async fn query_ids_correlation() {
+        let network = adnl::VirtualNetwork::new(0);
+        let recorder = Arc::new(QueryIdRecorder::default());
+
+        let make_node = || {
+            let adnl = add_virtual_node(&network, Default::default());
+            adnl.add_query_subscriber(recorder.clone()).unwrap();
+            let rldp = Node::new(adnl.clone(), vec![recorder.clone()], Default::default()).unwrap();
+            adnl.start().unwrap();
+            rldp
+        };
+
+        let (left, right) = (make_node(), make_node());
+        let left_id = *left.adnl().key_by_tag(0).unwrap().id();
+        let right_key = right.adnl().key_by_tag(0).unwrap();
+        left.adnl()
+            .add_peer(
+                adnl::NewPeerContext::AdnlPacket,
+                &left_id,
+                right_key.id(),
+                right.adnl().socket_addr(),
+                *right_key.full_id(),
+            )
+            .unwrap();
+
+        let (adnl_query_id, pong) = left
+            .adnl()
+            .query_traced::<_, proto::adnl::Pong>(
+                &left_id,
+                right_key.id(),
+                proto::rpc::NetworkEcho { data: vec![1] },
+                None,
+            )
+            .await
+            .unwrap();
+        assert_eq!(pong.unwrap().value, 1);
+
+        let mut events = left.adnl().events();
+        let query = tl_proto::serialize(proto::rpc::NetworkEcho { data: vec![2] });
+        let (rldp_query_id, answer, _) = left
+            .query_traced(&left_id, right_key.id(), query, None)
+            .await
+            .unwrap();
+        assert!(answer.is_some());
+
+        // RLDP trace id is the prefix of the query transfer id
+        let rldp_trace_id = loop {
+            if let NetworkEvent::RldpTransferCompleted {
+                transfer_id,
+                direction: RldpTransferDirection::Outgoing,
+                ..
+            } = events.recv().await.unwrap()
+            {
+                break adnl::TraceId::from_id(&transfer_id);
+            }
+        };
+
+        assert_ne!(adnl_query_id, rldp_query_id);
+        assert_eq!(
+            *recorder.0.lock(),
+            [
+                (
+                    QueryTransport::Adnl,
+                    adnl_query_id,
+                    adnl::TraceId::from(&adnl_query_id)
+                ),
+                (QueryTransport::Rldp, rldp_query_id, rldp_trace_id)
+            ]
+        );
+        assert_eq!(adnl_query_id.to_string().len(), 64);
+        assert_eq!(rldp_trace_id.to_string().len(), 16);
+    }
+
+    /// Answers echo queries with the data repeated 100 times
+    /// and counts the answers which were not sent
+    #[derive(Default)]
+    struct LargeEcho(AtomicUsize, AtomicUsize);
+
+    #[async_trait::async_trait]
+    impl QuerySubscriber for LargeEcho {
+        async fn try_consume_query<'a>(
+            &self,
+            _: SubscriberContext<'a>,
+            constructor: u32,
+            query: Cow<'a, [u8]>,
+        ) -> Result<QueryConsumingResult<'a>> {
+            if constructor != proto::rpc::NetworkEcho::TL_ID {
+                return Ok(QueryConsumingResult::reject(query));
+            }
+            self.0.fetch_add(1, Ordering::Relaxed);
+
+            let query = tl_proto::deserialize::<proto::rpc::NetworkEcho>(&query)?;
+            Ok(QueryConsumingResult::large_answer(
+                proto::adnl::EchoAnswer {
+                    data: query.data.repeat(100),
+                    received_at: 0,
+                },
+            ))
+        }
+
+        fn on_answer_failed(
+            &self,
+            _: SubscriberContext<'_>,
+            _: &QueryContext,
+            _: u32,
+            _: AnswerError,
+        ) {
+            self.1.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[tokio::test]
+    async fn large_answers_routing() {
+        let network = adnl::VirtualNetwork::new(0);
+        let subscriber = Arc::new(LargeEcho::default());
+
+        let make_node = || {
+            let options = adnl::NodeOptions {
+                send_query_rejections: true,
+                ..Default::default()
+            };
+            let adnl = add_virtual_node(&network, options);
+            adnl.add_query_subscriber(subscriber.clone()).unwrap();
+            let rldp =
+                Node::new(adnl.clone(), vec![subscriber.clone()], Default::default()).unwrap();
+            adnl.start().unwrap();
+            rldp
+        };
+
+        let (left, right) = (make_node(), make_node());
+        let left_id = *left.adnl().key_by_tag(0).unwrap().id();
+        let right_key = right.adnl().key_by_tag(0).unwrap();
+        left.adnl()
+            .add_peer(
+                adnl::NewPeerContext::AdnlPacket,
+                &left_id,
+                right_key.id(),
+                right.adnl().socket_addr(),
+                *right_key.full_id(),
+            )
+            .unwrap();
+
+        let adnl_query = |len: usize| {
+            let adnl = left.adnl().clone();
+            async move {
+                adnl.query::<_, tl_proto::OwnedRawBytes<tl_proto::Boxed>>(
+                    &left_id,
+                    right_key.id(),
+                    proto::rpc::NetworkEcho { data: vec![1; len] },
+                    None,
+                )
+                .await
+                .unwrap()
+                .unwrap()
+                .into_inner()
+            }
+        };
+        let rldp_query = |len: usize| {
+            let query = tl_proto::serialize(proto::rpc::NetworkEcho { data: vec![1; len] });
+            let left = left.clone();
+            async move {
+                let (answer, _) = left
+                    .query(&left_id, right_key.id(), query, None)
+                    .await
+                    .unwrap();
+                tl_proto::deserialize::<proto::adnl::EchoAnswer>(&answer.unwrap())
+                    .unwrap()
+                    .data
+                    .len()
+            }
+        };
+        let calls = || subscriber.0.load(Ordering::Relaxed);
+        let failures = || subscriber.1.load(Ordering::Relaxed);
+
+        // Small answer is sent over ADNL
+        let answer = tl_proto::deserialize::<proto::adnl::EchoAnswer>(&adnl_query(2).await);
+        assert_eq!(answer.unwrap().data.len(), 200);
+
+        // Large answer is prepared once and then served over RLDP
+        let answer = tl_proto::deserialize::<proto::adnl::QueryRejected>(&adnl_query(20).await);
+        assert_eq!(answer.unwrap().reason, RejectReason::AnswerTooLarge.code());
+        assert_eq!(right.adnl().metrics().large_answers_len, 1);
+        assert_eq!(right.adnl().metrics().answers_too_large, 1);
+        assert_eq!((calls(), failures()), (2, 1));
+
+        assert_eq!(rldp_query(20).await, 2000);
+        assert_eq!(calls(), 2);
+
+        // Queries received over RLDP are answered as usual
+        assert_eq!(rldp_query(30).await, 3000);
+        assert_eq!(calls(), 3);
+
+        // Nothing is sent instead of the large answer if the rejections are disabled,
+        // but the answer is still served over RLDP
+        right
+            .adnl()
+            .update_options(|options| options.send_query_rejections = false)
+            .unwrap();
+        let answer = left
+            .adnl()
+            .query::<_, tl_proto::OwnedRawBytes<tl_proto::Boxed>>(
+                &left_id,
+                right_key.id(),
+                proto::rpc::NetworkEcho { data: vec![1; 40] },
+                Some(500),
+            )
+            .await
+            .unwrap();
+        assert!(answer.is_none());
+        assert_eq!(right.adnl().metrics().answers_too_large, 2);
+        assert_eq!(failures(), 2);
+        assert_eq!(rldp_query(40).await, 4000);
+        assert_eq!(calls(), 4);
+        right
+            .adnl()
+            .update_options(|options| options.send_query_rejections = true)
+            .unwrap();
+
+        // Large answers are not kept if the cache is disabled
+        right
+            .adnl()
+            .update_options(|options| options.large_answer_cache_ttl_ms = 0)
+            .unwrap();
+        let answer = tl_proto::deserialize::<proto::adnl::QueryRejected>(&adnl_query(25).await);
+        assert_eq!(answer.unwrap().reason, RejectReason::AnswerTooLarge.code());
+        assert_eq!(rldp_query(25).await, 2500);
+        assert_eq!(calls(), 6);
+    }
+
+    /// Answers echo queries with random data of the same length as the query data
+    #[derive(Default)]
+    struct RandomEcho(parking_lot::Mutex<Vec<Option<u64>>>);
+
+    #[async_trait::async_trait]
+    impl QuerySubscriber for RandomEcho {
+        async fn try_consume_query_ext<'a>(
+            &self,
+            _: SubscriberContext<'a>,
+            query_ctx: QueryContext,
+            _: u32,
+            query: Cow<'a, [u8]>,
+        ) -> Result<QueryConsumingResult<'a>> {
+            self.0.lock().push(query_ctx.max_answer_size);
+
+            let query = tl_proto::deserialize::<proto::rpc::NetworkEcho>(&query)?;
+            Ok(QueryConsumingResult::answer(proto::adnl::EchoAnswer {
+                data: (0..query.data.len()).map(|_| rand::random()).collect(),
+                received_at: 0,
+            }))
+        }
+    }
+
+    #[tokio::test]
+    async fn too_large_answers_are_reported() {
+        let network = adnl::VirtualNetwork::new(0);
+        let subscriber = Arc::new(RandomEcho::default());
+
+        let make_node = |max_answer_size| {
+            let adnl = add_virtual_node(&network, Default::default());
+            let options = NodeOptions {
+                max_answer_size,
+                ..Default::default()
+            };
+            let rldp = Node::new(adnl.clone(), vec![subscriber.clone()], options).unwrap();
+            adnl.start().unwrap();
+            rldp
+        };
+
+        let (left, right) = (make_node(1000), make_node(1000));
+        let left_id = *left.adnl().key_by_tag(0).unwrap().id();
+        let right_key = right.adnl().key_by_tag(0).unwrap();
+        left.adnl()
+            .add_peer(
+                adnl::NewPeerContext::AdnlPacket,
+                &left_id,
+                right_key.id(),
+                right.adnl().socket_addr(),
+                *right_key.full_id(),
+            )
+            .unwrap();
+
+        let query = |len: usize| {
+            left.query_typed::<_, proto::adnl::EchoAnswer>(
+                &left_id,
+                right_key.id(),
+                proto::rpc::NetworkEcho { data: vec![1; len] },
+                None,
+            )
+        };
+
+        let (answer, _) = query(500).await.unwrap();
+        assert_eq!(answer.unwrap().data.len(), 500);
+
+        // Requester is notified instead of waiting until the timeout
+        let err = query(2000).await.unwrap_err();
+        let err = err.downcast::<AnswerTooLargeError>().unwrap();
+        assert!(err.size > 2000);
+        assert_eq!(err.max_answer_size, 1000);
+        assert_eq!(right.metrics().answers_too_large, 1);
+
+        // Query can be repeated with a bigger limit
+        left.update_options(|options| options.max_answer_size = 4096)
+            .unwrap();
+        let (answer, _) = query(2000).await.unwrap();
+        assert_eq!(answer.unwrap().data.len(), 2000);
+
+        assert_eq!(*subscriber.0.lock(), [Some(1000), Some(1000), Some(4096)]);
+        assert_eq!(left.metrics().answers_too_large, 0);
+    }
+
+    /// Answers any query with 32 KB of random data
+    struct RandomAnswer;
+
+    #[async_trait::async_trait]
+    impl QuerySubscriber for RandomAnswer {
+        async fn try_consume_query<'a>(
+            &self,
+            _: SubscriberContext<'a>,
+            _: u32,
+            _: Cow<'a, [u8]>,
+        ) -> Result<QueryConsumingResult<'a>> {
+            Ok(QueryConsumingResult::answer(proto::adnl::EchoAnswer {
+                data: (0..32 * 1024).map(|_| rand::random()).collect(),
+                received_at: 0,
+            }))
+        }
+    }
+
+    #[tokio::test]
+    async fn uploads_are_shared_between_peers() {
+        let clock = ManualClock::new(1000);
+        let network = adnl::VirtualNetwork::with_clock(0, Arc::new(clock.clone()));
+        let make_node = |options: NodeOptions| {
+            let adnl = add_virtual_node(&network, Default::default());
+            let rldp = Node::new(adnl.clone(), vec![Arc::new(RandomAnswer)], options).unwrap();
+            adnl.start().unwrap();
+            rldp
+        };
+
+        let server = make_node(NodeOptions {
+            max_outgoing_transfers_per_peer: 2,
+            max_queued_outgoing_transfers_per_peer: 2,
+            ..Default::default()
+        });
+        let server_key = server.adnl().key_by_tag(0).unwrap();
+
+        // Both peers saturate the server, but one of them sends many more queries
+        let clients = [6, 1].map(|parallel_queries| {
+            let client = make_node(Default::default());
+            let local_id = *client.adnl().key_by_tag(0).unwrap().id();
+            client
+                .adnl()
+                .add_peer(
+                    adnl::NewPeerContext::AdnlPacket,
+                    &local_id,
+                    server_key.id(),
+                    server.adnl().socket_addr(),
+                    *server_key.full_id(),
+                )
+                .unwrap();
+
+            let received = Arc::new(AtomicUsize::new(0));
+            let rejected = Arc::new(AtomicUsize::new(0));
+            for _ in 0..parallel_queries {
+                let (client, received, rejected) =
+                    (client.clone(), received.clone(), rejected.clone());
+                let peer_id = *server_key.id();
+                tokio::spawn(async move {
+                    loop {
+                        let query = tl_proto::serialize(proto::rpc::AdnlPing { value: 0 });
+                        if let Ok((Some(answer), _)) =
+                            client.query(&local_id, &peer_id, query, None).await
+                        {
+                            if tl_proto::deserialize::<proto::adnl::QueryRejected>(&answer).is_ok()
+                            {
+                                rejected.fetch_add(1, Ordering::Relaxed);
+                            } else {
+                                received.fetch_add(answer.len(), Ordering::Relaxed);
+                            }
+                        }
+                    }
+                });
+            }
+            (local_id, received, rejected)
+        });
+
+        // Drive the transfers by the wave interval
+        for _ in 0..200 {
+            clock.advance(Duration::from_millis(10));
+            for _ in 0..32 {
+                tokio::task::yield_now().await;
+            }
+        }
+
+        let metrics = server.metrics();
+        assert!(metrics.outgoing_transfers <= 3);
+        assert!(metrics.outgoing_transfers_rejected > 0);
+        let (aggressive, polite) = (&clients[0], &clients[1]);
+        assert!(server.peer_metrics(&aggressive.0).unwrap().active <= 2);
+
+        // Overflowing queries are rejected instead of waiting for the timeout
+        assert!(aggressive.2.load(Ordering::Relaxed) > 0);
+        assert_eq!(polite.2.load(Ordering::Relaxed), 0);
+
+        let (aggressive, polite) = (
+            aggressive.1.load(Ordering::Relaxed) as f64,
+            polite.1.load(Ordering::Relaxed) as f64,
+        );
+        let share = aggressive / (aggressive + polite);
+        assert!((0.4..=0.6).contains(&share), "{aggressive} / {polite}");
+    }
+
+    /// Timer which ticks immediately and hangs after the specified number of ticks
+    struct MockTimer {
+        intervals: parking_lot::Mutex<Vec<std::time::Duration>>,
+        max_ticks: usize,
+    }
+
+    impl PacingTimer for MockTimer {
+        fn tick(
+            &self,
+            interval: std::time::Duration,
+        ) -> futures_util::future::BoxFuture<'static, ()> {
+            let mut intervals = self.intervals.lock();
+            intervals.push(interval);
+            if intervals.len() < self.max_ticks {
+                Box::pin(futures_util::future::ready(()))
+            } else {
+                Box::pin(futures_util::future::pending())
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn waves_are_sent_on_pacing_ticks() {
+        let network = adnl::VirtualNetwork::new(0);
+        let make_adnl = || add_virtual_node(&network, Default::default());
+
+        // Peer without RLDP never confirms the transfer
+        let right = make_adnl();
+        right.start().unwrap();
+        let right_key = right.key_by_tag(0).unwrap();
+
+        for wave_len in [1, 3, 7] {
+            let adnl = make_adnl();
+            let timer = Arc::new(MockTimer {
+                intervals: Default::default(),
+                max_ticks: 5,
+            });
+            let options = NodeOptions {
+                query_wave_len: wave_len,
+                query_wave_interval_us: Some(250),
+                ..Default::default()
+            };
+            let left =
+                Node::with_pacing_timer(adnl.clone(), Vec::new(), options, Some(timer.clone()))
+                    .unwrap();
+            adnl.start().unwrap();
+
+            let left_id = *adnl.key_by_tag(0).unwrap().id();
+            adnl.add_peer(
+                adnl::NewPeerContext::AdnlPacket,
+                &left_id,
+                right_key.id(),
+                right.socket_addr(),
+                *right_key.full_id(),
+            )
+            .unwrap();
+
+            let query = left.query(&left_id, right_key.id(), vec![1; 64 * 1024], None);
+            tokio::time::timeout(std::time::Duration::from_millis(100), query)
+                .await
+                .unwrap_err();
+
+            // Exactly one wave is sent before each tick
+            let metrics = left.metrics();
+            assert_eq!(metrics.pacing_ticks, 5);
+            assert_eq!(metrics.symbols_sent, 5 * wave_len as u64);
+            assert_eq!(
+                *timer.intervals.lock(),
+                [std::time::Duration::from_micros(250); 5]
+            );
+        }
+    }
+
+    /// Answers echo queries from a background task, empty echo queries are never answered
+    #[derive(Default)]
+    struct DeferredEcho(parking_lot::Mutex<Vec<AnswerToken>>);
+
+    #[async_trait::async_trait]
+    impl QuerySubscriber for DeferredEcho {
+        async fn try_consume_query_ext<'a>(
+            &self,
+            ctx: SubscriberContext<'a>,
+            query_ctx: QueryContext,
+            constructor: u32,
+            query: Cow<'a, [u8]>,
+        ) -> Result<QueryConsumingResult<'a>> {
+            if constructor != proto::rpc::NetworkEcho::TL_ID {
+                return Ok(QueryConsumingResult::reject(query));
+            }
+            let query = tl_proto::deserialize::<proto::rpc::NetworkEcho>(&query)?;
+
+            let token = AnswerToken::new(ctx, &query_ctx);
+            self.0.lock().push(token.clone());
+            if !query.data.is_empty() {
+                let adnl = ctx.adnl.clone();
+                let token = token.clone();
+                let answer = tl_proto::serialize(proto::adnl::EchoAnswer {
+                    data: query.data,
+                    received_at: 0,
+                });
+                tokio::spawn(async move {
+                    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+                    adnl.send_deferred_answer(token, answer).unwrap();
+                });
+            }
+            Ok(QueryConsumingResult::ConsumedDeferred(token))
+        }
+    }
+
+    /// Answers echo queries before returning the token
+    #[derive(Default)]
+    struct EarlyDeferredEcho;
+
+    #[async_trait::async_trait]
+    impl QuerySubscriber for EarlyDeferredEcho {
+        async fn try_consume_query_ext<'a>(
+            &self,
+            ctx: SubscriberContext<'a>,
+            query_ctx: QueryContext,
+            constructor: u32,
+            query: Cow<'a, [u8]>,
+        ) -> Result<QueryConsumingResult<'a>> {
+            if constructor != proto::rpc::NetworkEcho::TL_ID {
+                return Ok(QueryConsumingResult::reject(query));
+            }
+            let query = tl_proto::deserialize::<proto::rpc::NetworkEcho>(&query)?;
+            let answer = tl_proto::serialize(proto::adnl::EchoAnswer {
+                data: query.data,
+                received_at: 0,
+            });
+
+            // Tokens which are not returned are never awaited
+            let unused = AnswerToken::new(ctx, &query_ctx);
+            ctx.adnl.send_deferred_answer(unused, Vec::new())?;
+
+            let token = AnswerToken::new(ctx, &query_ctx);
+            ctx.adnl.send_deferred_answer(token.clone(), answer)?;
+            Ok(QueryConsumingResult::ConsumedDeferred(token))
+        }
+    }
+
+    #[tokio::test]
+    async fn early_deferred_answers() {
+        let network = adnl::VirtualNetwork::new(0);
+        let subscriber = Arc::new(EarlyDeferredEcho);
+
+        let make_node = || {
+            let adnl = add_virtual_node(&network, Default::default());
+            adnl.add_query_subscriber(subscriber.clone()).unwrap();
+            let rldp =
+                Node::new(adnl.clone(), vec![subscriber.clone()], Default::default()).unwrap();
+            adnl.start().unwrap();
+            rldp
+        };
+
+        let (left, right) = (make_node(), make_node());
+        let left_id = *left.adnl().key_by_tag(0).unwrap().id();
+        let right_key = right.adnl().key_by_tag(0).unwrap();
+        left.adnl()
+            .add_peer(
+                adnl::NewPeerContext::AdnlPacket,
+                &left_id,
+                right_key.id(),
+                right.adnl().socket_addr(),
+                *right_key.full_id(),
+            )
+            .unwrap();
+
+        let answer = left
+            .adnl()
+            .query::<_, proto::adnl::EchoAnswer>(
+                &left_id,
+                right_key.id(),
+                proto::rpc::NetworkEcho { data: vec![1; 10] },
+                None,
+            )
+            .await
+            .unwrap();
+        assert_eq!(answer.unwrap().data, vec![1; 10]);
+
+        let data: Vec<u8> = (0..4096).map(|_| rand::random()).collect();
+        let (answer, _) = left
+            .query_typed::<_, proto::adnl::EchoAnswer>(
+                &left_id,
+                right_key.id(),
+                proto::rpc::NetworkEcho { data: data.clone() },
+                None,
+            )
+            .await
+            .unwrap();
+        assert_eq!(answer.unwrap().data, data);
+
+        let metrics = right.adnl().metrics();
+        assert_eq!(metrics.deferred_answers_len, 0);
+        assert_eq!(metrics.deferred_answers_expired, 0);
+    }
+
+    #[tokio::test]
+    async fn deferred_answers_routing() {
+        let network = adnl::VirtualNetwork::new(0);
+        let subscriber = Arc::new(DeferredEcho::default());
+
+        let make_node = || {
+            let options = adnl::NodeOptions {
+                deferred_answer_ttl_ms: 500,
+                ..Default::default()
+            };
+            let adnl = add_virtual_node(&network, options);
+            adnl.add_query_subscriber(subscriber.clone()).unwrap();
+            let rldp =
+                Node::new(adnl.clone(), vec![subscriber.clone()], Default::default()).unwrap();
+            adnl.start().unwrap();
+            rldp
+        };
+
+        let (left, right) = (make_node(), make_node());
+        let left_id = *left.adnl().key_by_tag(0).unwrap().id();
+        let right_key = right.adnl().key_by_tag(0).unwrap();
+        left.adnl()
+            .add_peer(
+                adnl::NewPeerContext::AdnlPacket,
+                &left_id,
+                right_key.id(),
+                right.adnl().socket_addr(),
+                *right_key.full_id(),
+            )
+            .unwrap();
+
+        // Plain ADNL query
+        let query = tokio::spawn({
+            let adnl = left.adnl().clone();
+            let right_id = *right_key.id();
+            async move {
+                adnl.query::<_, proto::adnl::EchoAnswer>(
+                    &left_id,
+                    &right_id,
+                    proto::rpc::NetworkEcho { data: vec![1; 10] },
+                    None,
+                )
+                .await
+            }
+        });
+
+        // Answer is awaited by the token, not by the query processing
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert_eq!(right.adnl().metrics().deferred_answers_len, 1);
+
+        let answer = query.await.unwrap().unwrap();
+        assert_eq!(answer.unwrap().data, vec![1; 10]);
+        assert_eq!(right.adnl().metrics().deferred_answers_len, 0);
+
+        // RLDP query with the answer which doesn't fit into ADNL message
+        let data: Vec<u8> = (0..4096).map(|_| rand::random()).collect();
+        let (answer, _) = left
+            .query_typed::<_, proto::adnl::EchoAnswer>(
+                &left_id,
+                right_key.id(),
+                proto::rpc::NetworkEcho { data: data.clone() },
+                None,
+            )
+            .await
+            .unwrap();
+        assert_eq!(answer.unwrap().data, data);
+
+        let tokens = subscriber.0.lock().clone();
+        assert_eq!(
+            tokens.iter().map(|t| t.transport()).collect::<Vec<_>>(),
+            [QueryTransport::Adnl, QueryTransport::Rldp]
+        );
+        assert!(tokens.iter().all(|t| t.peer_id() == &left_id));
+
+        // Nothing is sent after the expiration
+        let answer = left
+            .adnl()
+            .query::<_, proto::adnl::EchoAnswer>(
+                &left_id,
+                right_key.id(),
+                proto::rpc::NetworkEcho { data: Vec::new() },
+                Some(1000),
+            )
+            .await
+            .unwrap();
+        assert!(answer.is_none());
+
+        let metrics = right.adnl().metrics();
+        assert_eq!(metrics.deferred_answers_len, 0);
+        assert_eq!(metrics.deferred_answers_expired, 1);
+
+        let token = subscriber.0.lock().last().cloned().unwrap();
+        assert!(right
+            .adnl()
+            .send_deferred_answer(token, Vec::new())
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn transfer_parts_from_unexpected_peer() {
+        const PACKET_LEN: u32 = 768;
+
+        let network = adnl::VirtualNetwork::new(0);
+        let adnl = add_virtual_node(&network, Default::default());
+        let rldp = Node::new(adnl.clone(), Vec::new(), Default::default()).unwrap();
+        let local_id = *adnl.key_by_tag(0).unwrap().id();
+        let (remote_id, attacker_id) = (
+            adnl::NodeIdShort::new([1; 32]),
+            adnl::NodeIdShort::new([2; 32]),
+        );
+
+        let transfer_id = [3; 32];
+        let part = |seqno: u32| proto::rldp::MessagePart::MessagePart {
+            transfer_id: &transfer_id,
+            fec_type: proto::rldp::RaptorQFecType {
+                total_len: PACKET_LEN * 4,
+                packet_len: PACKET_LEN,
+                packet_count: 4,
+            },
+            part: 0,
+            total_size: PACKET_LEN as u64 * 4,
+            seqno,
+            data: &[0; PACKET_LEN as usize],
+        };
+
+        // The first part binds the transfer to the peer which started it
+        rldp.transfers
+            .handle_message(&adnl, &local_id, &remote_id, part(0))
+            .await
+            .unwrap();
+
+        // Parts and confirmations of this transfer from other peers are rejected
+        let messages = [
+            part(1),
+            proto::rldp::MessagePart::Confirm {
+                transfer_id: &transfer_id,
+                part: 0,
+                seqno: 1,
+            },
+        ];
+        for message in messages {
+            assert!(rldp
+                .transfers
+                .handle_message(&adnl, &local_id, &attacker_id, message)
+                .await
+                .is_err());
+        }
+        assert_eq!(
+            adnl.reputation().score(&attacker_id),
+            adnl::InMemoryReputation::MIN_SCORE
+        );
+        assert_eq!(adnl.reputation().score(&remote_id), 0);
+
+        rldp.transfers
+            .handle_message(&adnl, &local_id, &remote_id, part(1))
+            .await
+            .unwrap();
+    }
 }
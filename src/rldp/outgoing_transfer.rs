@@ -36,6 +36,11 @@ impl OutgoingTransfer {
         &self.transfer_id
     }
 
+    /// Full transfer data size in bytes
+    pub fn total_size(&self) -> usize {
+        self.data.len()
+    }
+
     /// Encodes next part of the message. Returns packet count which is required to be sent.
     pub fn start_next_part(&mut self) -> Result<Option<u32>> {
         if self.is_finished() {
@@ -0,0 +1,60 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures_util::future::BoxFuture;
+
+use crate::util::Clock;
+
+/// Timer which paces outgoing RLDP transfers.
+///
+/// Symbols are sent in waves of [`NodeOptions::query_wave_len`] symbols,
+/// each wave is followed by a single tick. By default the clock of the
+/// underlying ADNL node is used (tokio timers for [`SystemClock`]).
+///
+/// [`NodeOptions::query_wave_len`]: super::NodeOptions::query_wave_len
+/// [`SystemClock`]: crate::util::SystemClock
+pub trait PacingTimer: Send + Sync + 'static {
+    /// Waits for the next tick. `interval` is the configured wave interval
+    fn tick(&self, interval: Duration) -> BoxFuture<'static, ()>;
+}
+
+/// Pacing timer with its counters, shared by all outgoing transfers
+pub(super) struct Pacing {
+    timer: Option<Arc<dyn PacingTimer>>,
+    ticks: AtomicU64,
+    symbols_sent: AtomicU64,
+}
+
+impl Pacing {
+    pub fn new(timer: Option<Arc<dyn PacingTimer>>) -> Self {
+        Self {
+            timer,
+            ticks: Default::default(),
+            symbols_sent: Default::default(),
+        }
+    }
+
+    /// Total number of ticks between the waves of outgoing transfers
+    pub fn ticks(&self) -> u64 {
+        self.ticks.load(Ordering::Relaxed)
+    }
+
+    /// Total number of symbols sent by outgoing transfers
+    pub fn symbols_sent(&self) -> u64 {
+        self.symbols_sent.load(Ordering::Relaxed)
+    }
+
+    pub fn add_symbol_sent(&self) {
+        self.symbols_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Waits for the next tick using the custom timer or the node clock
+    pub fn tick(&self, clock: &dyn Clock, interval: Duration) -> BoxFuture<'static, ()> {
+        self.ticks.fetch_add(1, Ordering::Relaxed);
+        match &self.timer {
+            Some(timer) => timer.tick(interval),
+            None => clock.sleep(interval),
+        }
+    }
+}
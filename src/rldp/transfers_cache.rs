@@ -1,45 +1,111 @@
 use std::borrow::Cow;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
 use anyhow::Result;
+use arc_swap::ArcSwap;
 use parking_lot::Mutex;
 use tl_proto::{TlRead, TlWrite};
 use tokio::sync::mpsc;
+use tracing::Instrument;
 
 use super::compression;
 use super::incoming_transfer::*;
 use super::outgoing_transfer::*;
+use super::pacing::Pacing;
+use super::uploads::{ActiveUpload, Uploads};
 use super::NodeOptions;
-use crate::adnl;
+use crate::adnl::{self, DisplayTransferId};
 use crate::proto;
 use crate::subscriber::*;
 use crate::util::*;
+use crate::{NetworkEvent, RldpTransferDirection, RldpTransferFailure};
 
 pub struct TransfersCache {
     transfers: Arc<FastDashMap<TransferId, RldpTransfer>>,
-    subscribers: Arc<Vec<Arc<dyn QuerySubscriber>>>,
-    query_options: QueryOptions,
-    max_answer_size: u32,
-    force_compression: bool,
+    subscribers: Arc<QuerySubscribers>,
+    options: ArcSwap<NodeOptions>,
+    answers_too_large: Arc<AtomicU64>,
+    pacing: Arc<Pacing>,
+    uploads: Arc<Uploads>,
+    layer: Arc<adnl::DependentLayer>,
 }
 
 impl TransfersCache {
-    pub fn new(subscribers: Vec<Arc<dyn QuerySubscriber>>, options: NodeOptions) -> Self {
+    pub fn new(
+        subscribers: Vec<Arc<dyn QuerySubscriber>>,
+        options: NodeOptions,
+        pacing: Pacing,
+        layer: Arc<adnl::DependentLayer>,
+    ) -> Self {
         Self {
             transfers: Arc::new(Default::default()),
-            subscribers: Arc::new(subscribers),
-            query_options: QueryOptions {
-                query_wave_len: options.query_wave_len,
-                query_wave_interval_ms: options.query_wave_interval_ms,
-                query_min_timeout_ms: options.query_min_timeout_ms,
-                query_max_timeout_ms: options.query_max_timeout_ms,
-            },
-            max_answer_size: options.max_answer_size,
-            force_compression: options.force_compression,
+            subscribers: Arc::new(QuerySubscribers::new(subscribers)),
+            options: ArcSwap::from_pointee(options),
+            answers_too_large: Default::default(),
+            pacing: Arc::new(pacing),
+            uploads: Arc::new(Uploads::new(options.max_outgoing_transfers_per_peer)),
+            layer,
         }
     }
 
+    /// Background tasks of the RLDP node
+    pub fn layer(&self) -> &adnl::DependentLayer {
+        &self.layer
+    }
+
+    /// Forgets all transfers
+    pub fn clear(&self) {
+        self.transfers.clear();
+    }
+
+    pub fn pacing(&self) -> &Pacing {
+        &self.pacing
+    }
+
+    pub fn uploads(&self) -> &Uploads {
+        &self.uploads
+    }
+
+    /// Number of incoming queries whose answers exceeded the requested size
+    pub fn answers_too_large(&self) -> u64 {
+        self.answers_too_large.load(Ordering::Relaxed)
+    }
+
+    fn answers(&self) -> Answers {
+        Answers {
+            transfers: self.transfers.clone(),
+            answers_too_large: self.answers_too_large.clone(),
+            pacing: self.pacing.clone(),
+            uploads: self.uploads.clone(),
+            layer: self.layer.clone(),
+        }
+    }
+
+    /// Current configuration
+    pub fn options(&self) -> arc_swap::Guard<Arc<NodeOptions>> {
+        self.options.load()
+    }
+
+    /// Atomically replaces configuration unless `f` fails.
+    /// New values are used for all subsequent transfers
+    pub fn update_options<F>(&self, mut f: F) -> Result<()>
+    where
+        F: FnMut(&mut NodeOptions) -> Result<()>,
+    {
+        let mut result = Ok(());
+        self.options.rcu(|current| {
+            let mut options = **current;
+            result = f(&mut options);
+            match result {
+                Ok(()) => Arc::new(options),
+                Err(_) => current.clone(),
+            }
+        });
+        result
+    }
+
     /// Sends serialized query and waits answer
     pub async fn query(
         &self,
@@ -49,22 +115,33 @@ impl TransfersCache {
         data: Vec<u8>,
         roundtrip: Option<u64>,
     ) -> Result<(Option<Vec<u8>>, u64)> {
+        let options = self.options.load_full();
+        let query_options = QueryOptions::from(options.as_ref());
+
         // Initiate outgoing transfer with new id
         let outgoing_transfer = OutgoingTransfer::new(data, None);
         let outgoing_transfer_id = *outgoing_transfer.transfer_id();
         let outgoing_transfer_state = outgoing_transfer.state().clone();
+        // NOTE: the field is declared by the `rldp_query` span
+        tracing::Span::current().record(
+            "trace_id",
+            tracing::field::display(adnl::TraceId::from_id(&outgoing_transfer_id)),
+        );
         self.transfers.insert(
             outgoing_transfer_id,
-            RldpTransfer::Outgoing(outgoing_transfer_state.clone()),
+            RldpTransfer::Outgoing(*peer_id, outgoing_transfer_state.clone()),
         );
 
         // Initiate incoming transfer with derived id
         let incoming_transfer_id = negate_id(outgoing_transfer_id);
-        let incoming_transfer = IncomingTransfer::new(incoming_transfer_id, self.max_answer_size);
+        let incoming_transfer =
+            IncomingTransfer::new(incoming_transfer_id, options.max_answer_size);
         let incoming_transfer_state = incoming_transfer.state().clone();
         let (parts_tx, parts_rx) = mpsc::unbounded_channel();
-        self.transfers
-            .insert(incoming_transfer_id, RldpTransfer::Incoming(parts_tx));
+        self.transfers.insert(
+            incoming_transfer_id,
+            RldpTransfer::Incoming(*peer_id, parts_tx),
+        );
 
         // Prepare contexts
         let outgoing_context = OutgoingContext {
@@ -72,6 +149,8 @@ impl TransfersCache {
             local_id: *local_id,
             peer_id: *peer_id,
             transfer: outgoing_transfer,
+            pacing: self.pacing.clone(),
+            upload: None,
         };
 
         let mut incoming_context = IncomingContext {
@@ -87,18 +166,21 @@ impl TransfersCache {
         let barrier = Arc::new(Mutex::new(None));
 
         // Spawn receiver
-        tokio::spawn({
-            let barrier = barrier.clone();
-            async move {
-                incoming_context
-                    .receive(Some(outgoing_transfer_state))
-                    .await;
-                *barrier.lock() = Some(incoming_context.transfer);
-            }
-        });
+        self.layer
+            .tasks()
+            .spawn(adnl.runtime(), "rldp_query_receiver", {
+                let barrier = barrier.clone();
+                async move {
+                    incoming_context
+                        .receive(Some(outgoing_transfer_state))
+                        .await;
+                    *barrier.lock() = Some(incoming_context.transfer);
+                }
+                .in_current_span()
+            });
 
         // Send data and wait until something is received
-        let result = outgoing_context.send(self.query_options, roundtrip).await;
+        let result = outgoing_context.send(query_options, roundtrip).await;
         if result.is_ok() {
             self.transfers
                 .insert(outgoing_transfer_id, RldpTransfer::Done);
@@ -106,28 +188,35 @@ impl TransfersCache {
 
         let result = match result {
             Ok((true, mut roundtrip)) => {
-                let mut start = Instant::now();
+                let clock = adnl.clock();
+                let mut start = clock.instant();
                 let mut updates = incoming_transfer_state.updates();
-                let mut timeout = self.query_options.compute_timeout(Some(roundtrip));
+                let mut timeout = query_options.compute_timeout(Some(roundtrip));
 
                 loop {
                     // Wait until `updates` will be the same for one interval
-                    tokio::time::sleep(Duration::from_millis(TRANSFER_LOOP_INTERVAL)).await;
+                    clock
+                        .sleep(Duration::from_millis(TRANSFER_LOOP_INTERVAL))
+                        .await;
+
+                    let now = clock.instant();
+                    let elapsed = now.saturating_duration_since(start);
 
                     let new_updates = incoming_transfer_state.updates();
                     if new_updates > updates {
                         // Reset start timestamp on update
-                        timeout = self.query_options.update_roundtrip(&mut roundtrip, &start);
+                        timeout = query_options.update_roundtrip(&mut roundtrip, elapsed);
                         updates = new_updates;
-                        start = Instant::now();
-                    } else if is_timed_out(&start, timeout, updates) {
+                        start = now;
+                    } else if is_timed_out(elapsed, timeout, updates) {
                         // Stop polling on timeout
                         break Ok((None, roundtrip));
                     }
 
                     // Check barrier data
                     if let Some(reply) = barrier.lock().take() {
-                        self.query_options.update_roundtrip(&mut roundtrip, &start);
+                        let elapsed = clock.instant().saturating_duration_since(start);
+                        query_options.update_roundtrip(&mut roundtrip, elapsed);
                         break Ok((Some(reply.into_data()), roundtrip));
                     }
                 }
@@ -145,11 +234,12 @@ impl TransfersCache {
             .insert(incoming_transfer_id, RldpTransfer::Done);
 
         // Clear transfers in background
-        tokio::spawn({
+        self.layer.tasks().spawn(adnl.runtime(), "rldp_query_gc", {
             let transfers = self.transfers.clone();
-            let interval = self.query_options.completion_interval();
+            let interval = query_options.completion_interval();
+            let clock = adnl.clock().clone();
             async move {
-                tokio::time::sleep(interval).await;
+                clock.sleep(interval).await;
                 transfers.remove(&outgoing_transfer_id);
                 transfers.remove(&incoming_transfer_id);
             }
@@ -171,6 +261,25 @@ impl TransfersCache {
         peer_id: &adnl::NodeIdShort,
         message: proto::rldp::MessagePart<'_>,
     ) -> Result<()> {
+        if self.layer.is_shut_down() {
+            return Ok(());
+        }
+
+        // NOTE: transfer ids are not bound to peers, so a peer which knows the id
+        // could inject parts into the transfer with someone else
+        let transfer_id = match &message {
+            proto::rldp::MessagePart::MessagePart { transfer_id, .. }
+            | proto::rldp::MessagePart::Confirm { transfer_id, .. }
+            | proto::rldp::MessagePart::Complete { transfer_id, .. } => *transfer_id,
+        };
+        if let Some(transfer) = self.transfers.get(transfer_id) {
+            if matches!(transfer.peer_id(), Some(expected) if expected != peer_id) {
+                drop(transfer);
+                adnl.report_peer(peer_id, adnl::ReputationEvent::Spoofing);
+                return Err(TransfersCacheError::TransferFromUnexpectedPeer.into());
+            }
+        }
+
         match message {
             proto::rldp::MessagePart::MessagePart {
                 transfer_id,
@@ -185,7 +294,7 @@ impl TransfersCache {
                     // If transfer exists
                     Some(item) => match item.value() {
                         // Forward message part on `incoming` state
-                        RldpTransfer::Incoming(parts_tx) => {
+                        RldpTransfer::Incoming(_, parts_tx) => {
                             let _ = parts_tx.send(MessagePart {
                                 fec_type,
                                 part,
@@ -199,21 +308,22 @@ impl TransfersCache {
                         _ => {
                             drop(item); // drop item ref to prevent DashMap deadlocks
 
-                            // Send confirm message
-                            let mut buffer = Vec::with_capacity(44);
-                            proto::rldp::MessagePart::Confirm {
-                                transfer_id,
-                                part,
-                                seqno,
-                            }
-                            .write_to(&mut buffer);
-                            ok!(adnl.send_custom_message(local_id, peer_id, &buffer));
-
-                            // Send complete message
-                            buffer.clear();
-                            proto::rldp::MessagePart::Complete { transfer_id, part }
-                                .write_to(&mut buffer);
-                            ok!(adnl.send_custom_message(local_id, peer_id, &buffer));
+                            ok!(with_serialize_buffer(|buffer| {
+                                // Send confirm message
+                                proto::rldp::MessagePart::Confirm {
+                                    transfer_id,
+                                    part,
+                                    seqno,
+                                }
+                                .write_to(buffer);
+                                ok!(adnl.send_custom_message(local_id, peer_id, buffer));
+
+                                // Send complete message
+                                buffer.clear();
+                                proto::rldp::MessagePart::Complete { transfer_id, part }
+                                    .write_to(buffer);
+                                adnl.send_custom_message(local_id, peer_id, buffer)
+                            }));
 
                             // Done
                             break;
@@ -246,7 +356,7 @@ impl TransfersCache {
                 seqno,
             } => {
                 if let Some(transfer) = self.transfers.get(transfer_id) {
-                    if let RldpTransfer::Outgoing(state) = transfer.value() {
+                    if let RldpTransfer::Outgoing(_, state) = transfer.value() {
                         if state.part() == part {
                             state.set_seqno_in(seqno);
                         }
@@ -255,7 +365,7 @@ impl TransfersCache {
             }
             proto::rldp::MessagePart::Complete { transfer_id, part } => {
                 if let Some(transfer) = self.transfers.get(transfer_id) {
-                    if let RldpTransfer::Outgoing(state) = transfer.value() {
+                    if let RldpTransfer::Outgoing(_, state) = transfer.value() {
                         state.set_part(part + 1);
                     }
                 }
@@ -280,71 +390,84 @@ impl TransfersCache {
             // Create new transfer
             Entry::Vacant(entry) => {
                 let (parts_tx, parts_rx) = mpsc::unbounded_channel();
-                entry.insert(RldpTransfer::Incoming(parts_tx.clone()));
+                entry.insert(RldpTransfer::Incoming(*peer_id, parts_tx.clone()));
                 (parts_tx, parts_rx)
             }
             // Or do nothing if it already exists
             Entry::Occupied(_) => return Ok(None),
         };
 
+        let options = self.options.load();
+        let query_options = QueryOptions::from(options.as_ref());
+
         // Prepare context
         let mut incoming_context = IncomingContext {
             adnl: adnl.clone(),
             local_id: *local_id,
             peer_id: *peer_id,
             parts_rx,
-            transfer: IncomingTransfer::new(transfer_id, self.max_answer_size),
+            transfer: IncomingTransfer::new(transfer_id, options.max_answer_size),
             transfer_id,
         };
 
         // Spawn processing task
         let subscribers = self.subscribers.clone();
-        let transfers = self.transfers.clone();
-        let query_options = self.query_options;
-        let force_compression = self.force_compression;
-        tokio::spawn(async move {
-            // Wait until incoming query is received
-            incoming_context.receive(None).await;
-            transfers.insert(transfer_id, RldpTransfer::Done);
-
-            // Process query
-            let outgoing_transfer_id = incoming_context
-                .answer(
-                    transfers.clone(),
-                    subscribers,
-                    query_options,
-                    force_compression,
-                )
-                .await
-                .unwrap_or_default();
-
-            // Clear transfers in background
-            tokio::time::sleep(query_options.completion_interval()).await;
-            if let Some(outgoing_transfer_id) = outgoing_transfer_id {
-                transfers.remove(&outgoing_transfer_id);
-            }
-            transfers.remove(&transfer_id);
-        });
+        let answers = self.answers();
+        let force_compression = options.force_compression;
+        let clock = adnl.clock().clone();
+        self.layer
+            .tasks()
+            .spawn(adnl.runtime(), "rldp_answer_handler", async move {
+                // Wait until incoming query is received
+                incoming_context.receive(None).await;
+                answers.transfers.insert(transfer_id, RldpTransfer::Done);
+
+                // Process query
+                let outgoing_transfer_id = incoming_context
+                    .answer(&answers, subscribers, query_options, force_compression)
+                    .await
+                    .unwrap_or_default();
+
+                // Clear transfers in background
+                clock.sleep(query_options.completion_interval()).await;
+                if let Some(outgoing_transfer_id) = outgoing_transfer_id {
+                    answers.transfers.remove(&outgoing_transfer_id);
+                }
+                answers.transfers.remove(&transfer_id);
+            });
 
         // Clear incoming transfer on timeout
         let transfers = self.transfers.clone();
-        let interval = self.query_options.completion_interval();
-        tokio::spawn(async move {
-            tokio::time::sleep(interval).await;
-            transfers.insert(transfer_id, RldpTransfer::Done);
-        });
+        let interval = query_options.completion_interval();
+        let sleep = adnl.clock().sleep(interval);
+        self.layer
+            .tasks()
+            .spawn(adnl.runtime(), "rldp_answer_gc", async move {
+                sleep.await;
+                transfers.insert(transfer_id, RldpTransfer::Done);
+            });
 
         // Done
         Ok(Some(parts_tx))
     }
 }
 
+/// Transfer state with the peer it is exchanged with
 enum RldpTransfer {
-    Incoming(MessagePartsTx),
-    Outgoing(Arc<OutgoingTransferState>),
+    Incoming(adnl::NodeIdShort, MessagePartsTx),
+    Outgoing(adnl::NodeIdShort, Arc<OutgoingTransferState>),
     Done,
 }
 
+impl RldpTransfer {
+    fn peer_id(&self) -> Option<&adnl::NodeIdShort> {
+        match self {
+            Self::Incoming(peer_id, _) | Self::Outgoing(peer_id, _) => Some(peer_id),
+            Self::Done => None,
+        }
+    }
+}
+
 struct IncomingContext {
     adnl: Arc<adnl::Node>,
     local_id: adnl::NodeIdShort,
@@ -355,8 +478,21 @@ struct IncomingContext {
 }
 
 impl IncomingContext {
-    #[tracing::instrument(level = "debug", skip_all)]
+    #[tracing::instrument(
+        level = "debug",
+        name = "rldp_transfer",
+        skip_all,
+        fields(
+            transfer_id = %DisplayTransferId(self.transfer.transfer_id()),
+            direction = "incoming",
+            size = tracing::field::Empty,
+            elapsed_ms = tracing::field::Empty,
+        )
+    )]
     async fn receive(&mut self, mut outgoing_transfer_state: Option<Arc<OutgoingTransferState>>) {
+        let started_at = self.adnl.clock().instant();
+        let mut completed = false;
+
         // For each incoming message part
         while let Some(message) = self.parts_rx.recv().await {
             // Trying to process its data
@@ -368,10 +504,12 @@ impl IncomingContext {
                         self.adnl
                             .send_custom_message(&self.local_id, &self.peer_id, reply)
                     {
-                        tracing::warn!("RLDP query error: {e}");
+                        if self.adnl.should_log("RLDP query error") {
+                            tracing::warn!("RLDP query error: {e}");
+                        }
                     }
                 }
-                Err(e) => tracing::warn!("RLDP error: {e}"),
+                Err(e) if self.adnl.should_log("RLDP error") => tracing::warn!("RLDP error: {e}"),
                 _ => {}
             }
 
@@ -386,15 +524,60 @@ impl IncomingContext {
             // Exit loop if all bytes were received
             match self.transfer.total_size() {
                 Some(total_size) if self.transfer.data().len() >= total_size => {
+                    completed = true;
                     break;
                 }
-                None => {
+                None if self.adnl.should_log("total size mismatch") => {
                     tracing::warn!("total size mismatch");
                 }
                 _ => {}
             }
         }
 
+        let elapsed_ms = self
+            .adnl
+            .clock()
+            .instant()
+            .saturating_duration_since(started_at)
+            .as_millis() as u64;
+
+        let span = tracing::Span::current();
+        span.record("size", self.transfer.total_size());
+        span.record("elapsed_ms", elapsed_ms);
+        if completed {
+            tracing::trace!("transfer received");
+        } else {
+            tracing::trace!("transfer closed before completion");
+        }
+
+        let transfer_id = *self.transfer.transfer_id();
+        let size = self.transfer.data().len() as u64;
+        self.adnl.emit_event(|timestamp_ms| {
+            let (local_id, peer_id) = (self.local_id, self.peer_id);
+            let direction = RldpTransferDirection::Incoming;
+            if completed {
+                NetworkEvent::RldpTransferCompleted {
+                    local_id,
+                    peer_id,
+                    transfer_id,
+                    direction,
+                    size,
+                    elapsed_ms,
+                    timestamp_ms,
+                }
+            } else {
+                NetworkEvent::RldpTransferFailed {
+                    local_id,
+                    peer_id,
+                    transfer_id,
+                    direction,
+                    reason: RldpTransferFailure::Incomplete,
+                    elapsed_ms,
+                    timestamp_ms,
+                }
+            }
+        });
+
         // Close and clear parts channel
         self.parts_rx.close();
         while self.parts_rx.recv().await.is_some() {}
@@ -403,37 +586,156 @@ impl IncomingContext {
     #[tracing::instrument(level = "debug", skip_all)]
     async fn answer(
         mut self,
-        transfers: Arc<FastDashMap<TransferId, RldpTransfer>>,
-        subscribers: Arc<Vec<Arc<dyn QuerySubscriber>>>,
+        answers: &Answers,
+        subscribers: Arc<QuerySubscribers>,
         query_options: QueryOptions,
         force_compression: bool,
     ) -> Result<Option<TransferId>> {
+        let received_at = self.adnl.clock().instant();
+        let target = AnswerTarget {
+            adnl: self.adnl.clone(),
+            local_id: self.local_id,
+            peer_id: self.peer_id,
+            transfer_id: self.transfer_id,
+        };
+
         // Deserialize incoming query
         let query = match OwnedRldpMessageQuery::from_data(self.transfer.take_data()) {
             Some(query) => query,
-            None => return Err(TransfersCacheError::UnexpectedMessage.into()),
+            None => {
+                self.adnl
+                    .report_peer(&self.peer_id, adnl::ReputationEvent::CorruptTransfer);
+                return Err(TransfersCacheError::UnexpectedMessage.into());
+            }
         };
 
+        // Reject queries of the peer which already waits for too many answers
+        let upload = match answers
+            .uploads
+            .enqueue(&self.peer_id, query_options.max_queued_uploads)
+        {
+            Some(upload) => upload,
+            None => {
+                tracing::debug!(peer_id = %self.peer_id, "too many queued RLDP answers");
+                let answer = rejection_answer(&query.query_id);
+                return target.send(answers, answer, query_options, None).await;
+            }
+        };
+
+        // Wait for the peer transfers limit before preparing the answer
+        let upload = upload.start().await;
+
         // Process query
         let ctx = SubscriberContext {
             adnl: &self.adnl,
             local_id: &self.local_id,
             peer_id: &self.peer_id,
         };
-        let answer = match process_rldp_query(ctx, &subscribers, query, force_compression).await? {
-            QueryProcessingResult::Processed(Some(answer)) => answer,
+        let (result, params) = process_rldp_query(
+            ctx,
+            &self.transfer_id,
+            received_at,
+            &subscribers,
+            query,
+            force_compression,
+        )
+        .await?;
+
+        let answer = match result {
+            QueryProcessingResult::Processed(Some(answer)) => {
+                params.build(&self.peer_id, &answers.answers_too_large, answer)
+            }
             QueryProcessingResult::Processed(None) => return Ok(None),
+            QueryProcessingResult::Deferred(query) => {
+                // NOTE: the peer slot is released until the answer is ready
+                drop(upload);
+                let answers = answers.clone();
+                query.register(&self.adnl, move |adnl, answer| {
+                    let answer = params.build(&target.peer_id, &answers.answers_too_large, answer);
+                    let layer = answers.layer.clone();
+                    layer.tasks().spawn(
+                        adnl.runtime(),
+                        "rldp_deferred_answer",
+                        target.send_deferred(answers, params, answer, query_options),
+                    );
+                });
+                return Ok(None);
+            }
             QueryProcessingResult::Rejected => {
                 return Err(TransfersCacheError::NoSubscribers.into())
             }
         };
 
+        target
+            .send(answers, answer, query_options, Some(upload))
+            .await
+    }
+}
+
+/// Shared state of the answer transfers
+#[derive(Clone)]
+struct Answers {
+    transfers: Arc<FastDashMap<TransferId, RldpTransfer>>,
+    answers_too_large: Arc<AtomicU64>,
+    pacing: Arc<Pacing>,
+    uploads: Arc<Uploads>,
+    layer: Arc<adnl::DependentLayer>,
+}
+
+/// Peer which waits for the answer to the incoming transfer
+struct AnswerTarget {
+    adnl: Arc<adnl::Node>,
+    local_id: adnl::NodeIdShort,
+    peer_id: adnl::NodeIdShort,
+    transfer_id: TransferId,
+}
+
+impl AnswerTarget {
+    /// Sends the answer from [`adnl::Node::send_deferred_answer`] within the peer limits
+    async fn send_deferred(
+        self,
+        answers: Answers,
+        params: RldpAnswerParams,
+        answer: Vec<u8>,
+        query_options: QueryOptions,
+    ) {
+        let (answer, upload) = match answers
+            .uploads
+            .enqueue(&self.peer_id, query_options.max_queued_uploads)
+        {
+            Some(upload) => (answer, Some(upload.start().await)),
+            None => {
+                tracing::debug!(peer_id = %self.peer_id, "too many queued RLDP answers");
+                (rejection_answer(&params.query_id), None)
+            }
+        };
+
+        let outgoing_transfer_id = self
+            .send(&answers, answer, query_options, upload)
+            .await
+            .unwrap_or_default();
+
+        // Clear transfer in background
+        let clock = self.adnl.clock().clone();
+        clock.sleep(query_options.completion_interval()).await;
+        if let Some(outgoing_transfer_id) = outgoing_transfer_id {
+            answers.transfers.remove(&outgoing_transfer_id);
+        }
+    }
+
+    async fn send(
+        &self,
+        answers: &Answers,
+        answer: Vec<u8>,
+        query_options: QueryOptions,
+        upload: Option<ActiveUpload>,
+    ) -> Result<Option<TransferId>> {
         // Create outgoing transfer
         let outgoing_transfer_id = negate_id(self.transfer_id);
         let outgoing_transfer = OutgoingTransfer::new(answer, Some(outgoing_transfer_id));
-        transfers.insert(
+        answers.transfers.insert(
             outgoing_transfer_id,
-            RldpTransfer::Outgoing(outgoing_transfer.state().clone()),
+            RldpTransfer::Outgoing(self.peer_id, outgoing_transfer.state().clone()),
         );
 
         // Prepare context
@@ -442,9 +744,17 @@ impl IncomingContext {
             local_id: self.local_id,
             peer_id: self.peer_id,
             transfer: outgoing_transfer,
+            pacing: answers.pacing.clone(),
+            upload,
         };
 
         // Send answer
+        tracing::debug!(
+            peer_id = %self.peer_id,
+            trace_id = %adnl::TraceId::from_id(&self.transfer_id),
+            len = outgoing_context.transfer.total_size(),
+            "sending RLDP answer"
+        );
         outgoing_context.send(query_options, None).await?;
 
         // Done
@@ -452,16 +762,98 @@ impl IncomingContext {
     }
 }
 
+/// Answer to the query which the peer is not allowed to make now
+fn rejection_answer(query_id: &[u8; 32]) -> Vec<u8> {
+    let rejection = tl_proto::serialize(proto::adnl::QueryRejected {
+        reason: RejectReason::TemporarilyUnavailable.code(),
+    });
+    tl_proto::serialize(proto::rldp::Message::Answer {
+        query_id,
+        data: &rejection,
+    })
+}
+
 struct OutgoingContext {
     adnl: Arc<adnl::Node>,
     local_id: adnl::NodeIdShort,
     peer_id: adnl::NodeIdShort,
     transfer: OutgoingTransfer,
+    pacing: Arc<Pacing>,
+    /// Peer limits for the answers
+    upload: Option<ActiveUpload>,
 }
 
 impl OutgoingContext {
-    #[tracing::instrument(level = "debug", skip_all)]
+    #[tracing::instrument(
+        level = "debug",
+        name = "rldp_transfer",
+        skip_all,
+        fields(
+            transfer_id = %DisplayTransferId(self.transfer.transfer_id()),
+            direction = "outgoing",
+            size = self.transfer.total_size(),
+            elapsed_ms = tracing::field::Empty,
+        )
+    )]
     async fn send(
+        self,
+        query_options: QueryOptions,
+        roundtrip: Option<u64>,
+    ) -> Result<(bool, u64)> {
+        let adnl = self.adnl.clone();
+        let (local_id, peer_id) = (self.local_id, self.peer_id);
+        let transfer_id = *self.transfer.transfer_id();
+        let size = self.transfer.total_size() as u64;
+
+        let clock = adnl.clock().clone();
+        let started_at = clock.instant();
+
+        let result = self.send_parts(query_options, roundtrip).await;
+
+        let elapsed_ms = clock
+            .instant()
+            .saturating_duration_since(started_at)
+            .as_millis() as u64;
+        tracing::Span::current().record("elapsed_ms", elapsed_ms);
+        match &result {
+            Ok((true, roundtrip)) => tracing::trace!(roundtrip, "transfer sent"),
+            Ok((false, roundtrip)) => tracing::trace!(roundtrip, "transfer timed out"),
+            Err(e) => tracing::trace!("transfer failed: {e:?}"),
+        }
+
+        adnl.emit_event(|timestamp_ms| {
+            let direction = RldpTransferDirection::Outgoing;
+            let reason = match &result {
+                Ok((true, _)) => None,
+                Ok((false, _)) => Some(RldpTransferFailure::TimedOut),
+                Err(e) => Some(RldpTransferFailure::Error(e.to_string())),
+            };
+            match reason {
+                None => NetworkEvent::RldpTransferCompleted {
+                    local_id,
+                    peer_id,
+                    transfer_id,
+                    direction,
+                    size,
+                    elapsed_ms,
+                    timestamp_ms,
+                },
+                Some(reason) => NetworkEvent::RldpTransferFailed {
+                    local_id,
+                    peer_id,
+                    transfer_id,
+                    direction,
+                    reason,
+                    elapsed_ms,
+                    timestamp_ms,
+                },
+            }
+        });
+
+        result
+    }
+
+    async fn send_parts(
         mut self,
         query_options: QueryOptions,
         roundtrip: Option<u64>,
@@ -470,49 +862,75 @@ impl OutgoingContext {
         let mut timeout = query_options.compute_timeout(roundtrip);
         let mut roundtrip = roundtrip.unwrap_or_default();
 
-        let waves_interval = Duration::from_millis(query_options.query_wave_interval_ms);
+        let waves_interval = query_options.query_wave_interval;
 
         // For each outgoing message part
         while let Some(packet_count) = ok!(self.transfer.start_next_part()) {
-            let wave_len = std::cmp::min(packet_count, query_options.query_wave_len);
+            let part_wave_len = std::cmp::min(packet_count, query_options.query_wave_len);
 
             let part = self.transfer.state().part();
 
-            let mut start = Instant::now();
+            let clock = self.adnl.clock().clone();
+            let mut start = clock.instant();
 
             let mut incoming_seqno = 0;
+            let mut wave = 0u32;
             'part: loop {
+                if wave > 0 {
+                    tracing::trace!(part, wave, "resending part");
+                }
+                wave += 1;
+
+                // Answers of the same peer share its wave
+                let wave_len = match &self.upload {
+                    Some(upload) => upload.wave_len(part_wave_len),
+                    None => part_wave_len,
+                };
+
                 // Send parts in waves
                 for _ in 0..wave_len {
-                    ok!(self.adnl.send_custom_message(
-                        &self.local_id,
-                        &self.peer_id,
-                        ok!(self.transfer.prepare_chunk()),
-                    ));
+                    // Pause encoding instead of overflowing the peer send queue
+                    self.adnl
+                        .wait_for_send_queue_space(&self.local_id, &self.peer_id)
+                        .await;
+
+                    let chunk = ok!(self.transfer.prepare_chunk());
+                    if let Some(upload) = &self.upload {
+                        let rate = query_options.max_upload_rate;
+                        upload.throttle(clock.as_ref(), chunk.len(), rate).await;
+                    }
+                    ok!(self
+                        .adnl
+                        .send_custom_message(&self.local_id, &self.peer_id, chunk));
+                    self.pacing.add_symbol_sent();
 
                     if ok!(self.transfer.is_finished_or_next_part(part)) {
                         break 'part;
                     }
                 }
 
-                tokio::time::sleep(waves_interval).await;
+                self.pacing.tick(clock.as_ref(), waves_interval).await;
                 if ok!(self.transfer.is_finished_or_next_part(part)) {
                     break 'part;
                 }
 
                 // Update timeout on incoming packets
+                let now = clock.instant();
+                let elapsed = now.saturating_duration_since(start);
+
                 let new_incoming_seqno = self.transfer.state().seqno_in();
                 if new_incoming_seqno > incoming_seqno {
-                    timeout = query_options.update_roundtrip(&mut roundtrip, &start);
+                    timeout = query_options.update_roundtrip(&mut roundtrip, elapsed);
                     incoming_seqno = new_incoming_seqno;
-                    start = Instant::now();
-                } else if is_timed_out(&start, timeout, incoming_seqno) {
+                    start = now;
+                } else if is_timed_out(elapsed, timeout, incoming_seqno) {
                     return Ok((false, query_options.big_roundtrip(roundtrip)));
                 }
             }
 
             // Update timeout
-            timeout = query_options.update_roundtrip(&mut roundtrip, &start);
+            let elapsed = clock.instant().saturating_duration_since(start);
+            timeout = query_options.update_roundtrip(&mut roundtrip, elapsed);
         }
 
         // Done
@@ -523,18 +941,37 @@ impl OutgoingContext {
 #[derive(Copy, Clone)]
 struct QueryOptions {
     query_wave_len: u32,
-    query_wave_interval_ms: u64,
+    query_wave_interval: Duration,
     query_min_timeout_ms: u64,
     query_max_timeout_ms: u64,
+    max_queued_uploads: usize,
+    max_upload_rate: Option<u64>,
+}
+
+impl From<&NodeOptions> for QueryOptions {
+    fn from(options: &NodeOptions) -> Self {
+        Self {
+            query_wave_len: options.query_wave_len,
+            query_wave_interval: match options.query_wave_interval_us {
+                Some(interval_us) => Duration::from_micros(interval_us),
+                None => Duration::from_millis(options.query_wave_interval_ms),
+            },
+            query_min_timeout_ms: options.query_min_timeout_ms,
+            query_max_timeout_ms: options.query_max_timeout_ms,
+            max_queued_uploads: options.max_queued_outgoing_transfers_per_peer,
+            max_upload_rate: options.max_outgoing_rate_per_peer,
+        }
+    }
 }
 
 impl QueryOptions {
     /// Updates provided roundtrip and returns timeout
-    fn update_roundtrip(&self, roundtrip: &mut u64, time: &Instant) -> u64 {
+    fn update_roundtrip(&self, roundtrip: &mut u64, elapsed: Duration) -> u64 {
+        let elapsed = elapsed.as_millis() as u64;
         *roundtrip = if *roundtrip == 0 {
-            time.elapsed().as_millis() as u64
+            elapsed
         } else {
-            (*roundtrip + time.elapsed().as_millis() as u64) / 2
+            (*roundtrip + elapsed) / 2
         };
         self.compute_timeout(Some(*roundtrip))
     }
@@ -560,46 +997,98 @@ impl QueryOptions {
 
 async fn process_rldp_query(
     ctx: SubscriberContext<'_>,
-    subscribers: &[Arc<dyn QuerySubscriber>],
+    transfer_id: &TransferId,
+    received_at: Instant,
+    subscribers: &QuerySubscribers,
     mut query: OwnedRldpMessageQuery,
     force_compression: bool,
-) -> Result<QueryProcessingResult<Vec<u8>>> {
-    let answer_compression = match compression::decompress(&query.data) {
+) -> Result<(QueryProcessingResult<Vec<u8>>, RldpAnswerParams)> {
+    ctx.adnl.record_rldp_query();
+
+    let compression = match compression::decompress(&query.data) {
         Some(decompressed) => {
             query.data = decompressed;
             true
         }
-        None => force_compression,
+        None => {
+            force_compression
+                || ctx
+                    .adnl
+                    .peer_capabilities(ctx.local_id, ctx.peer_id)
+                    .supports(adnl::PeerCapabilities::RLDP_COMPRESSION)
+        }
+    };
+    let params = RldpAnswerParams {
+        query_id: query.query_id,
+        max_answer_size: query.max_answer_size,
+        compression,
     };
 
-    match process_query(ctx, subscribers, Cow::Owned(query.data)).await? {
-        QueryProcessingResult::Processed(answer) => Ok(match answer {
-            Some(mut answer) => {
-                if answer_compression {
-                    if let Err(e) = compression::compress(&mut answer) {
-                        tracing::warn!("failed to compress RLDP answer: {e:?}");
-                    }
-                }
-                if answer.len() > query.max_answer_size as usize {
-                    return Err(TransfersCacheError::AnswerSizeExceeded.into());
-                }
+    // NOTE: query timeout is an absolute remote timestamp in seconds
+    let deadline = received_at
+        + Duration::from_secs(query.timeout.saturating_sub(ctx.adnl.clock().now()) as u64);
+
+    let query_ctx = QueryContext {
+        transport: QueryTransport::Rldp,
+        query_id: adnl::QueryId(query.query_id),
+        trace_id: adnl::TraceId::from_id(transfer_id),
+        query_len: query.data.len(),
+        received_at,
+        deadline,
+        local_id: *ctx.local_id,
+        max_answer_size: Some(query.max_answer_size),
+    };
 
-                QueryProcessingResult::Processed(Some(tl_proto::serialize(
-                    proto::rldp::Message::Answer {
-                        query_id: &query.query_id,
-                        data: &answer,
-                    },
-                )))
+    let result = process_query(ctx, query_ctx, subscribers, Cow::Owned(query.data)).await?;
+    Ok((result, params))
+}
+
+/// How the answer to the RLDP query is sent
+#[derive(Copy, Clone)]
+struct RldpAnswerParams {
+    query_id: [u8; 32],
+    max_answer_size: u64,
+    compression: bool,
+}
+
+impl RldpAnswerParams {
+    /// Wraps the subscriber answer into the RLDP answer message
+    fn build(
+        &self,
+        peer_id: &adnl::NodeIdShort,
+        answers_too_large: &AtomicU64,
+        mut answer: Vec<u8>,
+    ) -> Vec<u8> {
+        if self.compression {
+            if let Err(e) = compression::compress(&mut answer) {
+                tracing::warn!("failed to compress RLDP answer: {e:?}");
             }
-            None => QueryProcessingResult::Processed(None),
-        }),
-        _ => Ok(QueryProcessingResult::Rejected),
+        }
+        if answer.len() as u64 > self.max_answer_size {
+            // Notify the peer so that it could retry with a bigger limit
+            answers_too_large.fetch_add(1, Ordering::Relaxed);
+            tracing::debug!(
+                %peer_id,
+                len = answer.len(),
+                max_answer_size = self.max_answer_size,
+                "RLDP answer is too large"
+            );
+            answer = tl_proto::serialize(proto::adnl::AnswerTooLarge {
+                size: answer.len() as u64,
+            });
+        }
+
+        tl_proto::serialize(proto::rldp::Message::Answer {
+            query_id: &self.query_id,
+            data: &answer,
+        })
     }
 }
 
 struct OwnedRldpMessageQuery {
     query_id: [u8; 32],
     max_answer_size: u64,
+    timeout: u32,
     data: Vec<u8>,
 }
 
@@ -639,13 +1128,14 @@ impl OwnedRldpMessageQuery {
         Some(Self {
             query_id: params.query_id,
             max_answer_size: params.max_answer_size,
+            timeout: params.timeout,
             data,
         })
     }
 }
 
-fn is_timed_out(time: &Instant, timeout: u64, updates: u32) -> bool {
-    time.elapsed().as_millis() as u64 > timeout + timeout * (updates as u64) / 100
+fn is_timed_out(elapsed: Duration, timeout: u64, updates: u32) -> bool {
+    elapsed.as_millis() as u64 > timeout + timeout * (updates as u64) / 100
 }
 
 fn negate_id(id: [u8; 32]) -> [u8; 32] {
@@ -665,6 +1155,6 @@ enum TransfersCacheError {
     UnexpectedMessage,
     #[error("No subscribers for query")]
     NoSubscribers,
-    #[error("Answer size exceeded")]
-    AnswerSizeExceeded,
+    #[error("Transfer message from unexpected peer")]
+    TransferFromUnexpectedPeer,
 }
@@ -0,0 +1,254 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::adnl;
+use crate::util::{Clock, FastDashMap};
+
+/// Limits of the outgoing answer transfers for each peer.
+///
+/// Answers which don't fit into [`NodeOptions::max_outgoing_transfers_per_peer`]
+/// wait in the FIFO queue. All active transfers of all peers are sent in waves on
+/// the same pacing ticks, and the wave of each peer is split between its active
+/// transfers, so each peer gets an equal share of the uplink regardless of
+/// the number of its transfers.
+///
+/// [`NodeOptions::max_outgoing_transfers_per_peer`]: super::NodeOptions::max_outgoing_transfers_per_peer
+pub(super) struct Uploads {
+    peers: FastDashMap<adnl::NodeIdShort, Arc<PeerUploads>>,
+    max_transfers: usize,
+    rejected: AtomicU64,
+}
+
+impl Uploads {
+    pub fn new(max_transfers: usize) -> Self {
+        Self {
+            peers: Default::default(),
+            max_transfers,
+            rejected: Default::default(),
+        }
+    }
+
+    /// Reserves a place in the peer queue.
+    /// Returns `None` if there are already `max_queued` waiting answers
+    pub fn enqueue(&self, peer_id: &adnl::NodeIdShort, max_queued: usize) -> Option<QueuedUpload> {
+        let peer = self
+            .peers
+            .entry(*peer_id)
+            .or_insert_with(|| Arc::new(PeerUploads::new(self.max_transfers)))
+            .value()
+            .clone();
+
+        let queued = peer.queued.fetch_add(1, Ordering::AcqRel);
+        if queued >= max_queued && peer.transfers.available_permits() == 0 {
+            peer.queued.fetch_sub(1, Ordering::AcqRel);
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        Some(QueuedUpload { peer })
+    }
+
+    pub fn peer(&self, peer_id: &adnl::NodeIdShort) -> Option<UploadMetrics> {
+        self.peers.get(peer_id).map(|peer| peer.metrics())
+    }
+
+    /// Total number of active and queued transfers
+    pub fn len(&self) -> (usize, usize) {
+        self.peers.iter().fold((0, 0), |(active, queued), peer| {
+            let metrics = peer.metrics();
+            (active + metrics.active, queued + metrics.queued)
+        })
+    }
+
+    /// Total number of answers which were not sent because the queue was full
+    pub fn rejected(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
+    }
+
+    /// Removes peers without transfers
+    pub fn gc(&self) {
+        self.peers.retain(|_, peer| Arc::strong_count(peer) > 1);
+    }
+}
+
+/// Outgoing transfers of the peer
+struct PeerUploads {
+    transfers: Arc<Semaphore>,
+    queued: AtomicUsize,
+    active: AtomicUsize,
+    /// Bytes which can be sent without delay, negative if the peer is over its rate
+    budget: Mutex<Budget>,
+}
+
+struct Budget {
+    bytes: f64,
+    updated_at: Option<Instant>,
+}
+
+impl PeerUploads {
+    fn new(max_transfers: usize) -> Self {
+        Self {
+            transfers: Arc::new(Semaphore::new(max_transfers)),
+            queued: Default::default(),
+            active: Default::default(),
+            budget: Mutex::new(Budget {
+                bytes: 0.0,
+                updated_at: None,
+            }),
+        }
+    }
+
+    fn metrics(&self) -> UploadMetrics {
+        UploadMetrics {
+            active: self.active.load(Ordering::Acquire),
+            queued: self.queued.load(Ordering::Acquire),
+        }
+    }
+}
+
+/// Answer waiting for its turn to be sent
+pub(super) struct QueuedUpload {
+    peer: Arc<PeerUploads>,
+}
+
+impl QueuedUpload {
+    /// Waits until the number of active peer transfers is below the limit
+    pub async fn start(self) -> ActiveUpload {
+        let permit = self.peer.transfers.clone().acquire_owned().await.ok();
+        self.peer.active.fetch_add(1, Ordering::AcqRel);
+        ActiveUpload {
+            peer: self.peer.clone(),
+            _permit: permit,
+        }
+        // NOTE: `self` is dropped here, so the transfer is counted as active first
+    }
+}
+
+impl Drop for QueuedUpload {
+    fn drop(&mut self) {
+        self.peer.queued.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Answer which is being sent
+pub(super) struct ActiveUpload {
+    peer: Arc<PeerUploads>,
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl ActiveUpload {
+    /// Splits the wave of `wave_len` symbols between the active transfers of the peer
+    pub fn wave_len(&self, wave_len: u32) -> u32 {
+        let active = self.peer.active.load(Ordering::Acquire).max(1);
+        std::cmp::max(wave_len / active as u32, 1)
+    }
+
+    /// Waits until `len` bytes can be sent without exceeding the peer `rate` (bytes/sec).
+    ///
+    /// The budget is shared by all transfers of the peer and accumulates at most one second
+    pub async fn throttle(&self, clock: &dyn Clock, len: usize, rate: Option<u64>) {
+        let rate = match rate {
+            Some(rate) if rate > 0 => rate as f64,
+            _ => return,
+        };
+
+        let delay = {
+            let mut budget = self.peer.budget.lock();
+            let now = clock.instant();
+            let refill = match budget.updated_at {
+                Some(updated_at) => now.saturating_duration_since(updated_at).as_secs_f64() * rate,
+                None => rate,
+            };
+            budget.bytes = (budget.bytes + refill).min(rate) - len as f64;
+            budget.updated_at = Some(now);
+            budget.bytes
+        };
+
+        if delay < 0.0 {
+            clock.sleep(Duration::from_secs_f64(-delay / rate)).await;
+        }
+    }
+}
+
+impl Drop for ActiveUpload {
+    fn drop(&mut self) {
+        self.peer.active.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Outgoing answer transfers of the peer, see [`Node::peer_metrics`]
+///
+/// [`Node::peer_metrics`]: super::Node::peer_metrics
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, serde::Serialize)]
+pub struct UploadMetrics {
+    /// Number of answers which are being sent
+    pub active: usize,
+    /// Number of answers waiting for [`NodeOptions::max_outgoing_transfers_per_peer`]
+    ///
+    /// [`NodeOptions::max_outgoing_transfers_per_peer`]: super::NodeOptions::max_outgoing_transfers_per_peer
+    pub queued: usize,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::ManualClock;
+
+    #[tokio::test]
+    async fn queue_is_bounded() {
+        let uploads = Uploads::new(1);
+        let peer_id = adnl::NodeIdShort::new([1; 32]);
+
+        let first = uploads.enqueue(&peer_id, 1).unwrap().start().await;
+        let second = uploads.enqueue(&peer_id, 1).unwrap();
+        assert!(uploads.enqueue(&peer_id, 1).is_none());
+        assert_eq!(uploads.rejected(), 1);
+        assert_eq!(
+            uploads.peer(&peer_id),
+            Some(UploadMetrics {
+                active: 1,
+                queued: 1
+            })
+        );
+
+        let second = tokio::spawn(second.start());
+        tokio::task::yield_now().await;
+        assert!(!second.is_finished());
+        drop(first);
+        let second = second.await.unwrap();
+        assert_eq!(uploads.len(), (1, 0));
+
+        drop(second);
+        uploads.gc();
+        assert!(uploads.peer(&peer_id).is_none());
+    }
+
+    #[tokio::test]
+    async fn rate_is_shared() {
+        let clock = ManualClock::new(1000);
+        let uploads = Uploads::new(2);
+        let peer_id = adnl::NodeIdShort::new([1; 32]);
+        let (first, second) = (
+            uploads.enqueue(&peer_id, 0).unwrap().start().await,
+            uploads.enqueue(&peer_id, 0).unwrap().start().await,
+        );
+
+        // One second of budget is available at once
+        first.throttle(&clock, 600, Some(1000)).await;
+        second.throttle(&clock, 400, Some(1000)).await;
+
+        let throttled = futures_util::future::join(
+            first.throttle(&clock, 300, Some(1000)),
+            second.throttle(&clock, 200, Some(1000)),
+        );
+        tokio::pin!(throttled);
+        assert!(futures_util::poll!(&mut throttled).is_pending());
+        clock.advance(Duration::from_millis(400));
+        assert!(futures_util::poll!(&mut throttled).is_pending());
+        clock.advance(Duration::from_millis(100));
+        assert!(futures_util::poll!(&mut throttled).is_ready());
+    }
+}
@@ -1,31 +1,148 @@
 use std::borrow::Cow;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use anyhow::Result;
 use tl_proto::TlRead;
+use tracing::Instrument;
 
 use crate::adnl;
+use crate::proto;
+use crate::util::{Clock, FastHashMap};
 
 /// ADNL custom messages subscriber
 #[async_trait::async_trait]
 pub trait MessageSubscriber: Send + Sync {
+    /// Tries to consume custom message.
+    ///
+    /// Skips all messages by default.
     async fn try_consume_custom<'a>(
         &self,
         ctx: SubscriberContext<'a>,
         constructor: u32,
         data: &'a [u8],
-    ) -> Result<bool>;
+    ) -> Result<bool> {
+        let _ = (ctx, constructor, data);
+        Ok(false)
+    }
+
+    /// Tries to consume custom message with an ability to reply to it.
+    ///
+    /// Falls back to [`MessageSubscriber::try_consume_custom`] by default.
+    async fn try_consume_custom_ext<'a>(
+        &self,
+        ctx: SubscriberContext<'a>,
+        message_ctx: MessageContext<'a>,
+        constructor: u32,
+        data: &'a [u8],
+    ) -> Result<bool> {
+        let _ = message_ctx;
+        self.try_consume_custom(ctx, constructor, data).await
+    }
+
+    /// Called when a new remote peer is added.
+    ///
+    /// NOTE: Hooks of the same peer are never called concurrently and follow the order
+    /// of the peer state changes. Hooks of different peers can be called concurrently.
+    /// Events caused by a hook itself (e.g. removing the peer) are delivered after it returns
+    fn on_peer_added(&self, local_id: &adnl::NodeIdShort, peer_id: &adnl::NodeIdShort) {
+        let _ = (local_id, peer_id);
+    }
+
+    /// Called when a channel with the remote peer becomes ready.
+    /// Always called after [`MessageSubscriber::on_peer_added`] for the same peer.
+    fn on_channel_established(&self, local_id: &adnl::NodeIdShort, peer_id: &adnl::NodeIdShort) {
+        let _ = (local_id, peer_id);
+    }
+
+    /// Called when the remote peer is removed or its state is reset.
+    fn on_peer_lost(
+        &self,
+        local_id: &adnl::NodeIdShort,
+        peer_id: &adnl::NodeIdShort,
+        reason: PeerLostReason,
+    ) {
+        let _ = (local_id, peer_id, reason);
+    }
+}
+
+/// See [`MessageSubscriber::on_peer_lost`]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum PeerLostReason {
+    /// Peer was explicitly removed
+    Removed,
+    /// Channel with the peer was dropped due to timeout, peer state was reset
+    Reset,
+    /// Peer was removed to make room for the new one,
+    /// see [`adnl::NodeOptions::max_peers_per_context`]
+    Evicted,
 }
 
 /// ADNL, RLDP or overlay queries subscriber
 #[async_trait::async_trait]
 pub trait QuerySubscriber: Send + Sync {
+    /// Tries to consume query.
+    ///
+    /// Rejects all queries by default.
     async fn try_consume_query<'a>(
         &self,
         ctx: SubscriberContext<'a>,
         constructor: u32,
         query: Cow<'a, [u8]>,
-    ) -> Result<QueryConsumingResult<'a>>;
+    ) -> Result<QueryConsumingResult<'a>> {
+        let _ = (ctx, constructor);
+        Ok(QueryConsumingResult::Rejected(query))
+    }
+
+    /// Tries to consume query with additional info about it.
+    ///
+    /// Falls back to [`QuerySubscriber::try_consume_query`] by default.
+    async fn try_consume_query_ext<'a>(
+        &self,
+        ctx: SubscriberContext<'a>,
+        query_ctx: QueryContext,
+        constructor: u32,
+        query: Cow<'a, [u8]>,
+    ) -> Result<QueryConsumingResult<'a>> {
+        let _ = query_ctx;
+        self.try_consume_query(ctx, constructor, query).await
+    }
+
+    /// TL constructor ids of the queries which this subscriber handles.
+    ///
+    /// Queries with these constructors will be offered to this subscriber first.
+    /// Empty list means that all queries will be offered.
+    fn interested_constructors(&self) -> &[u32] {
+        &[]
+    }
+
+    /// Called when the answer returned by this subscriber was not sent.
+    ///
+    /// NOTE: The query is finished only after this call, so it must not block
+    fn on_answer_failed(
+        &self,
+        ctx: SubscriberContext<'_>,
+        query_ctx: &QueryContext,
+        constructor: u32,
+        error: AnswerError,
+    ) {
+        let _ = (ctx, query_ctx, constructor, error);
+    }
+}
+
+/// See [`QuerySubscriber::on_answer_failed`]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, thiserror::Error)]
+#[non_exhaustive]
+pub enum AnswerError {
+    /// Answer is longer than the transport allows,
+    /// see `NodeOptions::max_split_adnl_answer_len`
+    #[error("Answer is too large for {transport:?} transport ({len} > {max_len} bytes)")]
+    AnswerTooLargeForTransport {
+        transport: QueryTransport,
+        len: usize,
+        max_len: usize,
+    },
 }
 
 /// Message or query context.
@@ -38,46 +155,919 @@ pub struct SubscriberContext<'a> {
     pub peer_id: &'a adnl::NodeIdShort,
 }
 
+/// Incoming custom message info.
+///
+/// See [`MessageSubscriber::try_consume_custom_ext`]
+#[derive(Copy, Clone)]
+pub struct MessageContext<'a> {
+    adnl: &'a adnl::Node,
+    local_id: &'a adnl::NodeIdShort,
+    peer_id: &'a adnl::NodeIdShort,
+    data: &'a [u8],
+    /// Local timestamp when the packet with this message was received
+    pub received_at: Instant,
+}
+
+impl<'a> MessageContext<'a> {
+    pub(crate) fn new(ctx: SubscriberContext<'a>, data: &'a [u8], received_at: Instant) -> Self {
+        Self {
+            adnl: ctx.adnl,
+            local_id: ctx.local_id,
+            peer_id: ctx.peer_id,
+            data,
+            received_at,
+        }
+    }
+
+    /// Compact id of the message for logs (computed from the message data on each call).
+    ///
+    /// The sender can get the same id with [`adnl::TraceId::for_message`]
+    pub fn trace_id(&self) -> adnl::TraceId {
+        adnl::TraceId::for_message(self.data)
+    }
+
+    /// Sends custom message back to the sender using the same local key
+    pub fn reply(&self, data: &[u8]) -> Result<()> {
+        self.adnl
+            .send_custom_message(self.local_id, self.peer_id, data)
+    }
+}
+
+/// Incoming query info.
+///
+/// See [`QuerySubscriber::try_consume_query_ext`]
+#[derive(Debug, Copy, Clone)]
+pub struct QueryContext {
+    /// Protocol through which the query was received
+    pub transport: QueryTransport,
+    /// ADNL or RLDP query id (the same as the sender got from
+    /// [`adnl::Node::query_traced`] or [`rldp::Node::query_traced`])
+    ///
+    /// [`rldp::Node::query_traced`]: crate::rldp::Node::query_traced
+    pub query_id: adnl::QueryId,
+    /// Compact id for logs: prefix of the ADNL query id or the RLDP query transfer id
+    /// (see [`adnl::TraceId`])
+    pub trace_id: adnl::TraceId,
+    /// Raw query length in bytes (without transport wrappers)
+    pub query_len: usize,
+    /// Local timestamp when the query was received.
+    ///
+    /// NOTE: for RLDP queries this is the moment when the last part of the transfer was received
+    pub received_at: Instant,
+    /// Estimated moment after which the remote peer will no longer wait for the answer.
+    ///
+    /// Answers which are prepared later than this will not be sent
+    pub deadline: Instant,
+    /// Local ADNL key id to which the query was addressed
+    pub local_id: adnl::NodeIdShort,
+    /// Max answer size in bytes declared by the remote peer (only for RLDP queries).
+    ///
+    /// Larger answers are not sent, the peer receives [`proto::adnl::AnswerTooLarge`] instead
+    pub max_answer_size: Option<u64>,
+}
+
+impl QueryContext {
+    /// Time left until the deadline according to the ADNL node clock
+    /// (see [`adnl::Node::clock`])
+    pub fn remaining_time(&self, clock: &dyn Clock) -> Duration {
+        self.deadline.saturating_duration_since(clock.instant())
+    }
+
+    /// Whether the answer is no longer expected by the remote peer
+    /// according to the ADNL node clock (see [`adnl::Node::clock`])
+    pub fn is_expired(&self, clock: &dyn Clock) -> bool {
+        clock.instant() > self.deadline
+    }
+}
+
+/// Protocol through which the query was received
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum QueryTransport {
+    /// Plain ADNL query, answer must fit into `NodeOptions::max_split_adnl_answer_len`
+    Adnl,
+    /// RLDP query, answer size is limited by the RLDP node options
+    Rldp,
+}
+
 /// Subscriber response for consumed query
+///
+/// # Example
+///
+/// ```
+/// # use std::borrow::Cow;
+/// # use anyhow::Result;
+/// # use everscale_network::{proto, QueryConsumingResult, QuerySubscriber, SubscriberContext};
+/// struct PingSubscriber;
+///
+/// #[async_trait::async_trait]
+/// impl QuerySubscriber for PingSubscriber {
+///     async fn try_consume_query<'a>(
+///         &self,
+///         _: SubscriberContext<'a>,
+///         constructor: u32,
+///         query: Cow<'a, [u8]>,
+///     ) -> Result<QueryConsumingResult<'a>> {
+///         if constructor != proto::rpc::AdnlPing::TL_ID {
+///             return Ok(QueryConsumingResult::reject(query));
+///         }
+///
+///         let proto::rpc::AdnlPing { value } = tl_proto::deserialize(&query)?;
+///         Ok(QueryConsumingResult::answer(proto::adnl::Pong { value }))
+///     }
+/// }
+/// ```
 pub enum QueryConsumingResult<'a> {
-    /// Query is accepted and processed
+    /// Query is accepted and processed.
+    ///
+    /// Answer bytes are sent as is, so they must contain a valid boxed TL object.
+    /// Already serialized answers (e.g. from some cache) can be passed here directly,
+    /// see [`QueryConsumingResult::answer`] for typed answers.
     Consumed(Option<Vec<u8>>),
+    /// Same as `Consumed(Some(answer))`, but the answer can also be reused
+    /// for the identical queries during the specified time.
+    ///
+    /// Only overlay queries are cached (see `OverlayOptions::answer_cache_max_size`),
+    /// other subscribers just send the answer
+    Cacheable(Vec<u8>, Duration),
+    /// Same as `Consumed(Some(answer))`, but the answer can be bigger than an ADNL message.
+    ///
+    /// RLDP queries are answered as usual. Plain ADNL queries with the answer bigger than
+    /// `NodeOptions::max_adnl_answer_len` are rejected with [`RejectReason::AnswerTooLarge`]
+    /// (only if `NodeOptions::send_query_rejections` is enabled, otherwise nothing is sent),
+    /// and the answer is kept for a while to serve the same query repeated over RLDP
+    /// (see `NodeOptions::large_answer_cache_ttl_ms`). Such answers are reported
+    /// with [`QuerySubscriber::on_answer_failed`] as for the other answers which were too large
+    ConsumedLarge(Vec<u8>),
+    /// Query is accepted, but the answer will be sent later with
+    /// [`adnl::Node::send_deferred_answer`]. The subscriber returns immediately,
+    /// and nothing waits for the answer: it is sent directly by the node
+    /// if it arrives in time (see `NodeOptions::deferred_answer_ttl_ms`).
+    ///
+    /// Deferred answers are never cached
+    ConsumedDeferred(AnswerToken),
     /// Query rejected and will be processed by the next subscriber
     Rejected(Cow<'a, [u8]>),
+    /// Query rejected with the specified reason.
+    ///
+    /// Only [`RejectReason::NotMine`] allows the next subscribers to process it
+    RejectedWith(Cow<'a, [u8]>, RejectReason),
+}
+
+/// Pending answer to the incoming query, see [`QueryConsumingResult::ConsumedDeferred`]
+///
+/// Tokens are equal if they are created for the same query
+#[derive(Clone)]
+pub struct AnswerToken {
+    local_id: adnl::NodeIdShort,
+    peer_id: adnl::NodeIdShort,
+    transport: QueryTransport,
+    query_id: adnl::QueryId,
+    /// Transport through which the answer is sent
+    slot: Arc<adnl::DeferredAnswerSlot>,
+}
+
+impl AnswerToken {
+    /// Creates a pending answer to the query which is being consumed.
+    ///
+    /// The token must be returned as [`QueryConsumingResult::ConsumedDeferred`],
+    /// its clone is passed to [`adnl::Node::send_deferred_answer`]. The answer which
+    /// is sent before the token is returned is delivered right after that.
+    /// Tokens which were not returned are just dropped
+    pub fn new(ctx: SubscriberContext<'_>, query_ctx: &QueryContext) -> Self {
+        Self {
+            local_id: *ctx.local_id,
+            peer_id: *ctx.peer_id,
+            transport: query_ctx.transport,
+            query_id: query_ctx.query_id,
+            slot: Default::default(),
+        }
+    }
+
+    /// Remote peer which is waiting for the answer
+    pub fn peer_id(&self) -> &adnl::NodeIdShort {
+        &self.peer_id
+    }
+
+    /// Protocol through which the answer will be sent
+    pub fn transport(&self) -> QueryTransport {
+        self.transport
+    }
+
+    pub(crate) fn slot(&self) -> &Arc<adnl::DeferredAnswerSlot> {
+        &self.slot
+    }
+
+    fn id(
+        &self,
+    ) -> (
+        &adnl::NodeIdShort,
+        &adnl::NodeIdShort,
+        QueryTransport,
+        &adnl::QueryId,
+    ) {
+        (
+            &self.local_id,
+            &self.peer_id,
+            self.transport,
+            &self.query_id,
+        )
+    }
+}
+
+impl std::fmt::Debug for AnswerToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AnswerToken")
+            .field("local_id", &self.local_id)
+            .field("peer_id", &self.peer_id)
+            .field("transport", &self.transport)
+            .field("query_id", &self.query_id)
+            .finish()
+    }
+}
+
+impl Eq for AnswerToken {}
+
+impl PartialEq for AnswerToken {
+    fn eq(&self, other: &Self) -> bool {
+        self.id() == other.id()
+    }
+}
+
+impl std::hash::Hash for AnswerToken {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.id().hash(state)
+    }
+}
+
+/// Query rejection reason
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum RejectReason {
+    /// Query is not supported by this subscriber
+    NotMine,
+    /// Query is supported, but the remote peer is not allowed to make it
+    Unauthorized,
+    /// Query is supported, but can't be processed now
+    TemporarilyUnavailable,
+    /// Answer doesn't fit into ADNL message, the same query must be repeated over RLDP
+    AnswerTooLarge,
+}
+
+impl RejectReason {
+    /// Reason code used in [`proto::adnl::QueryRejected`]
+    pub fn code(self) -> u32 {
+        match self {
+            Self::NotMine => 0,
+            Self::Unauthorized => 1,
+            Self::TemporarilyUnavailable => 2,
+            Self::AnswerTooLarge => 3,
+        }
+    }
 }
 
-impl QueryConsumingResult<'_> {
+impl<'a> QueryConsumingResult<'a> {
+    /// Query is processed with the typed answer
+    pub fn answer<T>(answer: T) -> Self
+    where
+        T: tl_proto::TlWrite<Repr = tl_proto::Boxed>,
+    {
+        Self::Consumed(Some(tl_proto::serialize(answer)))
+    }
+
+    /// Query is processed with the typed answer which can be bigger than an ADNL message,
+    /// see [`QueryConsumingResult::ConsumedLarge`]
+    pub fn large_answer<T>(answer: T) -> Self
+    where
+        T: tl_proto::TlWrite<Repr = tl_proto::Boxed>,
+    {
+        Self::ConsumedLarge(tl_proto::serialize(answer))
+    }
+
+    /// Marks the answer as cacheable for the specified time (cache TTL hint).
+    ///
+    /// Other results are returned as is
+    pub fn with_cache_ttl(self, ttl: Duration) -> Self {
+        match self {
+            Self::Consumed(Some(answer)) | Self::Cacheable(answer, _) => {
+                Self::Cacheable(answer, ttl)
+            }
+            other => other,
+        }
+    }
+
+    /// Query is processed, but there will be no answer
+    pub fn no_answer() -> Self {
+        Self::Consumed(None)
+    }
+
+    /// Query will be processed by the next subscriber
+    pub fn reject(query: Cow<'a, [u8]>) -> Self {
+        Self::Rejected(query)
+    }
+
+    /// Query is rejected with the specified reason
+    pub fn reject_with(query: Cow<'a, [u8]>, reason: RejectReason) -> Self {
+        Self::RejectedWith(query, reason)
+    }
+
+    /// Same as [`QueryConsumingResult::answer`], but wrapped into `Result`
     pub fn consume<T>(answer: T) -> Result<Self>
     where
         T: tl_proto::TlWrite<Repr = tl_proto::Boxed>,
     {
-        Ok(Self::Consumed(Some(tl_proto::serialize(answer))))
+        Ok(Self::answer(answer))
+    }
+}
+
+impl From<Option<Vec<u8>>> for QueryConsumingResult<'_> {
+    fn from(answer: Option<Vec<u8>>) -> Self {
+        Self::Consumed(answer)
+    }
+}
+
+/// Query subscribers with dispatch by TL constructor id
+#[derive(Default)]
+pub(crate) struct QuerySubscribers {
+    /// All subscribers in registration order
+    all: Vec<Arc<dyn QuerySubscriber>>,
+    /// Interested subscribers followed by subscribers without declared constructors
+    by_constructor: FastHashMap<u32, Vec<Arc<dyn QuerySubscriber>>>,
+}
+
+impl QuerySubscribers {
+    pub fn new(all: Vec<Arc<dyn QuerySubscriber>>) -> Self {
+        let mut by_constructor = FastHashMap::<u32, Vec<_>>::default();
+        for subscriber in &all {
+            for constructor in subscriber.interested_constructors() {
+                let entry = by_constructor.entry(*constructor).or_default();
+                if !entry.iter().any(|item| Arc::ptr_eq(item, subscriber)) {
+                    entry.push(subscriber.clone());
+                }
+            }
+        }
+
+        for subscriber in &all {
+            if subscriber.interested_constructors().is_empty() {
+                for entry in by_constructor.values_mut() {
+                    entry.push(subscriber.clone());
+                }
+            }
+        }
+
+        Self {
+            all,
+            by_constructor,
+        }
+    }
+
+    /// Returns subscribers in the order in which the query should be offered
+    pub fn get(&self, constructor: u32) -> &[Arc<dyn QuerySubscriber>] {
+        match self.by_constructor.get(&constructor) {
+            Some(subscribers) => subscribers,
+            None => &self.all,
+        }
     }
 }
 
 pub(crate) async fn process_query<'a>(
     ctx: SubscriberContext<'a>,
-    subscribers: &[Arc<dyn QuerySubscriber>],
-    mut query: Cow<'_, [u8]>,
+    query_ctx: QueryContext,
+    subscribers: &QuerySubscribers,
+    query: Cow<'_, [u8]>,
 ) -> Result<QueryProcessingResult<Vec<u8>>> {
     let constructor = u32::read_from(&query, &mut 0)?;
+    let span = tracing::debug_span!(
+        "incoming_query",
+        transport = ?query_ctx.transport,
+        trace_id = %query_ctx.trace_id,
+        peer_id = %ctx.peer_id,
+        constructor = %format_args!("0x{constructor:08x}"),
+    );
+    process_query_impl(ctx, query_ctx, subscribers, constructor, query)
+        .instrument(span)
+        .await
+}
 
-    for subscriber in subscribers {
+async fn process_query_impl<'a>(
+    ctx: SubscriberContext<'a>,
+    query_ctx: QueryContext,
+    subscribers: &QuerySubscribers,
+    constructor: u32,
+    mut query: Cow<'_, [u8]>,
+) -> Result<QueryProcessingResult<Vec<u8>>> {
+    // Serve the query which was previously rejected over ADNL because of the answer size
+    if query_ctx.transport == QueryTransport::Rldp {
+        if let Some(answer) = ctx
+            .adnl
+            .find_large_answer(ctx.local_id, ctx.peer_id, &query)
+        {
+            return Ok(QueryProcessingResult::Processed(Some(answer)));
+        }
+    }
+
+    // NOTE: ADNL queries are always borrowed from the packet
+    let adnl_query = match &query {
+        Cow::Borrowed(query) if query_ctx.transport == QueryTransport::Adnl => Some(*query),
+        _ => None,
+    };
+
+    for subscriber in subscribers.get(constructor) {
         query = match subscriber
-            .try_consume_query(ctx, constructor, query)
+            .try_consume_query_ext(ctx, query_ctx, constructor, query)
             .await?
         {
+            QueryConsumingResult::Consumed(Some(_))
+            | QueryConsumingResult::Cacheable(..)
+            | QueryConsumingResult::ConsumedLarge(_)
+                if ctx.adnl.clock().instant() > query_ctx.deadline =>
+            {
+                ctx.adnl.add_expired_answer();
+                return Ok(QueryProcessingResult::Processed(None));
+            }
             QueryConsumingResult::Consumed(answer) => {
-                return Ok(QueryProcessingResult::Processed(answer))
+                let answer = answer.and_then(|answer| {
+                    checked_answer(ctx, &query_ctx, subscriber.as_ref(), constructor, answer)
+                });
+                return Ok(QueryProcessingResult::Processed(answer));
+            }
+            QueryConsumingResult::Cacheable(answer, _) => {
+                let answer =
+                    checked_answer(ctx, &query_ctx, subscriber.as_ref(), constructor, answer);
+                return Ok(QueryProcessingResult::Processed(answer));
+            }
+            QueryConsumingResult::ConsumedLarge(answer) => {
+                let adnl_query = match adnl_query {
+                    Some(query) if answer.len() > ctx.adnl.options().max_adnl_answer_len => query,
+                    _ => return Ok(QueryProcessingResult::Processed(Some(answer))),
+                };
+
+                tracing::debug!(
+                    peer_id = %ctx.peer_id,
+                    constructor,
+                    len = answer.len(),
+                    "answer is too large for ADNL"
+                );
+                let error = AnswerError::AnswerTooLargeForTransport {
+                    transport: query_ctx.transport,
+                    len: answer.len(),
+                    max_len: ctx.adnl.options().max_adnl_answer_len,
+                };
+                ctx.adnl
+                    .cache_large_answer(ctx.local_id, ctx.peer_id, adnl_query, answer);
+                return Ok(QueryProcessingResult::Processed(answer_too_large(
+                    ctx,
+                    &query_ctx,
+                    subscriber.as_ref(),
+                    constructor,
+                    error,
+                )));
+            }
+            QueryConsumingResult::ConsumedDeferred(token) => {
+                return Ok(QueryProcessingResult::Deferred(Box::new(DeferredQuery {
+                    token,
+                    subscriber: subscriber.clone(),
+                    constructor,
+                    query_ctx,
+                })));
+            }
+            QueryConsumingResult::Rejected(query)
+            | QueryConsumingResult::RejectedWith(query, RejectReason::NotMine) => query,
+            QueryConsumingResult::RejectedWith(_, reason) => {
+                tracing::debug!(
+                    peer_id = %ctx.peer_id,
+                    constructor,
+                    ?reason,
+                    "query rejected"
+                );
+                return Ok(QueryProcessingResult::Processed(rejection_answer(
+                    ctx.adnl, reason,
+                )));
             }
-            QueryConsumingResult::Rejected(query) => query,
         };
     }
 
-    Ok(QueryProcessingResult::Rejected)
+    match rejection_answer(ctx.adnl, RejectReason::NotMine) {
+        Some(answer) => Ok(QueryProcessingResult::Processed(Some(answer))),
+        None => Ok(QueryProcessingResult::Rejected),
+    }
+}
+
+/// Replaces the plain ADNL answer which is too large for the transport with the rejection
+fn checked_answer(
+    ctx: SubscriberContext<'_>,
+    query_ctx: &QueryContext,
+    subscriber: &dyn QuerySubscriber,
+    constructor: u32,
+    answer: Vec<u8>,
+) -> Option<Vec<u8>> {
+    let max_len = ctx.adnl.adnl_answer_len_limit();
+    if query_ctx.transport != QueryTransport::Adnl || answer.len() <= max_len {
+        return Some(answer);
+    }
+
+    let error = AnswerError::AnswerTooLargeForTransport {
+        transport: query_ctx.transport,
+        len: answer.len(),
+        max_len,
+    };
+    tracing::warn!(
+        peer_id = %ctx.peer_id,
+        constructor = %format_args!("0x{constructor:08x}"),
+        "answer not sent: {error}"
+    );
+    answer_too_large(ctx, query_ctx, subscriber, constructor, error)
+}
+
+/// Reports the answer which was not sent and returns the rejection to send instead
+fn answer_too_large(
+    ctx: SubscriberContext<'_>,
+    query_ctx: &QueryContext,
+    subscriber: &dyn QuerySubscriber,
+    constructor: u32,
+    error: AnswerError,
+) -> Option<Vec<u8>> {
+    ctx.adnl.add_answer_too_large(constructor);
+    subscriber.on_answer_failed(ctx, query_ctx, constructor, error);
+
+    rejection_answer(ctx.adnl, RejectReason::AnswerTooLarge)
+}
+
+fn rejection_answer(adnl: &adnl::Node, reason: RejectReason) -> Option<Vec<u8>> {
+    adnl.options().send_query_rejections.then(|| {
+        tl_proto::serialize(proto::adnl::QueryRejected {
+            reason: reason.code(),
+        })
+    })
 }
 
 pub(crate) enum QueryProcessingResult<T> {
     Processed(Option<T>),
+    /// Answer will be sent with [`adnl::Node::send_deferred_answer`]
+    Deferred(Box<DeferredQuery>),
     Rejected,
 }
+
+/// Query which was consumed with [`QueryConsumingResult::ConsumedDeferred`]
+pub(crate) struct DeferredQuery {
+    token: AnswerToken,
+    subscriber: Arc<dyn QuerySubscriber>,
+    constructor: u32,
+    query_ctx: QueryContext,
+}
+
+impl DeferredQuery {
+    /// Sends the answer from [`adnl::Node::send_deferred_answer`] using `respond`
+    /// after the same checks as for the inline answer
+    pub fn register<F>(self, adnl: &Arc<adnl::Node>, respond: F)
+    where
+        F: FnOnce(&Arc<adnl::Node>, Vec<u8>) + Send + 'static,
+    {
+        let Self {
+            token,
+            subscriber,
+            constructor,
+            query_ctx,
+        } = self;
+        let (local_id, peer_id) = (token.local_id, token.peer_id);
+
+        let responder = Box::new(move |adnl: &Arc<adnl::Node>, answer| {
+            let ctx = SubscriberContext {
+                adnl,
+                local_id: &local_id,
+                peer_id: &peer_id,
+            };
+            if let Some(answer) =
+                checked_answer(ctx, &query_ctx, subscriber.as_ref(), constructor, answer)
+            {
+                respond(adnl, answer);
+            }
+        });
+        adnl.register_deferred_answer(&token, responder);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::fixtures::make_adnl_node;
+
+    struct TypedAnswer;
+
+    #[async_trait::async_trait]
+    impl QuerySubscriber for TypedAnswer {
+        async fn try_consume_query<'a>(
+            &self,
+            _: SubscriberContext<'a>,
+            _: u32,
+            _: Cow<'a, [u8]>,
+        ) -> Result<QueryConsumingResult<'a>> {
+            Ok(QueryConsumingResult::answer(proto::adnl::Pong {
+                value: 123,
+            }))
+        }
+    }
+
+    struct RawAnswer(Vec<u8>);
+
+    #[async_trait::async_trait]
+    impl QuerySubscriber for RawAnswer {
+        async fn try_consume_query<'a>(
+            &self,
+            _: SubscriberContext<'a>,
+            _: u32,
+            _: Cow<'a, [u8]>,
+        ) -> Result<QueryConsumingResult<'a>> {
+            Ok(Some(self.0.clone()).into())
+        }
+    }
+
+    pub(crate) async fn query_subscribers(
+        adnl: &Arc<adnl::Node>,
+        subscribers: &QuerySubscribers,
+        query: &[u8],
+    ) -> Result<QueryProcessingResult<Vec<u8>>> {
+        query_subscribers_with_timeout(adnl, subscribers, query, Duration::from_secs(5)).await
+    }
+
+    pub(crate) async fn query_subscribers_with_timeout(
+        adnl: &Arc<adnl::Node>,
+        subscribers: &QuerySubscribers,
+        query: &[u8],
+        timeout: Duration,
+    ) -> Result<QueryProcessingResult<Vec<u8>>> {
+        let local_id = *adnl.key_by_tag(0)?.id();
+        let ctx = SubscriberContext {
+            adnl,
+            local_id: &local_id,
+            peer_id: &local_id,
+        };
+        let received_at = adnl.clock().instant();
+        let query_ctx = QueryContext {
+            transport: QueryTransport::Adnl,
+            query_id: Default::default(),
+            trace_id: Default::default(),
+            query_len: query.len(),
+            received_at,
+            deadline: received_at + timeout,
+            local_id,
+            max_answer_size: None,
+        };
+        process_query(ctx, query_ctx, subscribers, Cow::Borrowed(query)).await
+    }
+
+    #[tokio::test]
+    async fn raw_and_typed_answers_are_identical() {
+        let adnl = make_adnl_node(Default::default());
+        let query = tl_proto::serialize(proto::rpc::AdnlPing { value: 123 });
+
+        let raw = tl_proto::serialize(proto::adnl::Pong { value: 123 });
+        let typed: Arc<dyn QuerySubscriber> = Arc::new(TypedAnswer);
+        let raw: Arc<dyn QuerySubscriber> = Arc::new(RawAnswer(raw));
+
+        let mut answers = Vec::new();
+        for subscriber in [typed, raw] {
+            let subscribers = QuerySubscribers::new(vec![subscriber]);
+            match query_subscribers(&adnl, &subscribers, &query).await {
+                Ok(QueryProcessingResult::Processed(Some(answer))) => answers.push(answer),
+                _ => panic!("query not processed"),
+            }
+        }
+        assert_eq!(answers[0], answers[1]);
+    }
+
+    struct Tagged {
+        tag: u32,
+        constructors: Vec<u32>,
+    }
+
+    #[async_trait::async_trait]
+    impl QuerySubscriber for Tagged {
+        async fn try_consume_query<'a>(
+            &self,
+            _: SubscriberContext<'a>,
+            _: u32,
+            _: Cow<'a, [u8]>,
+        ) -> Result<QueryConsumingResult<'a>> {
+            Ok(QueryConsumingResult::answer(proto::adnl::Pong {
+                value: self.tag as u64,
+            }))
+        }
+
+        fn interested_constructors(&self) -> &[u32] {
+            &self.constructors
+        }
+    }
+
+    async fn answered_tag(
+        adnl: &Arc<adnl::Node>,
+        subscribers: &QuerySubscribers,
+        query: &[u8],
+    ) -> u64 {
+        match query_subscribers(adnl, subscribers, query).await {
+            Ok(QueryProcessingResult::Processed(Some(answer))) => {
+                tl_proto::deserialize::<proto::adnl::Pong>(&answer)
+                    .unwrap()
+                    .value
+            }
+            _ => panic!("query not processed"),
+        }
+    }
+
+    #[tokio::test]
+    async fn dispatch_by_constructor() {
+        let adnl = make_adnl_node(Default::default());
+
+        let subscribers = |items: &[(u32, &[u32])]| {
+            QuerySubscribers::new(
+                items
+                    .iter()
+                    .map(|(tag, constructors)| {
+                        Arc::new(Tagged {
+                            tag: *tag,
+                            constructors: constructors.to_vec(),
+                        }) as Arc<dyn QuerySubscriber>
+                    })
+                    .collect(),
+            )
+        };
+
+        let ping = tl_proto::serialize(proto::rpc::AdnlPing { value: 0 });
+        let other = tl_proto::serialize(proto::rpc::DhtPing { random_id: 0 });
+
+        // Interested subscriber is preferred to the previously registered one
+        let ping_id = proto::rpc::AdnlPing::TL_ID;
+        let subscribers_one = subscribers(&[(1, &[]), (2, &[ping_id])]);
+        assert_eq!(answered_tag(&adnl, &subscribers_one, &ping).await, 2);
+
+        // Sequential scan for unknown constructors
+        assert_eq!(answered_tag(&adnl, &subscribers_one, &other).await, 1);
+
+        // Overlapping declarations are resolved by registration order
+        let subscribers_two = subscribers(&[(1, &[]), (2, &[ping_id]), (3, &[ping_id])]);
+        assert_eq!(answered_tag(&adnl, &subscribers_two, &ping).await, 2);
+    }
+
+    struct SlowSubscriber;
+
+    #[async_trait::async_trait]
+    impl QuerySubscriber for SlowSubscriber {
+        async fn try_consume_query_ext<'a>(
+            &self,
+            ctx: SubscriberContext<'a>,
+            query_ctx: QueryContext,
+            _: u32,
+            _: Cow<'a, [u8]>,
+        ) -> Result<QueryConsumingResult<'a>> {
+            let clock = ctx.adnl.clock().as_ref();
+            clock
+                .sleep(query_ctx.remaining_time(clock) + Duration::from_millis(10))
+                .await;
+            assert!(query_ctx.is_expired(clock));
+            Ok(QueryConsumingResult::answer(proto::adnl::Pong { value: 0 }))
+        }
+    }
+
+    #[tokio::test]
+    async fn expired_answers_are_not_sent() {
+        let adnl = make_adnl_node(Default::default());
+        let subscribers = QuerySubscribers::new(vec![Arc::new(SlowSubscriber)]);
+        let query = tl_proto::serialize(proto::rpc::AdnlPing { value: 0 });
+
+        let result =
+            query_subscribers_with_timeout(&adnl, &subscribers, &query, Duration::from_millis(20))
+                .await
+                .unwrap();
+
+        assert!(matches!(result, QueryProcessingResult::Processed(None)));
+        assert_eq!(adnl.metrics().answers_expired, 1);
+    }
+
+    #[derive(Default)]
+    struct SizedAnswer {
+        failed: parking_lot::Mutex<Vec<AnswerError>>,
+    }
+
+    #[async_trait::async_trait]
+    impl QuerySubscriber for SizedAnswer {
+        async fn try_consume_query<'a>(
+            &self,
+            _: SubscriberContext<'a>,
+            _: u32,
+            query: Cow<'a, [u8]>,
+        ) -> Result<QueryConsumingResult<'a>> {
+            let proto::rpc::AdnlPing { value } = tl_proto::deserialize(&query)?;
+            Ok(Some(vec![0; value as usize]).into())
+        }
+
+        fn on_answer_failed(
+            &self,
+            _: SubscriberContext<'_>,
+            _: &QueryContext,
+            _: u32,
+            error: AnswerError,
+        ) {
+            self.failed.lock().push(error);
+        }
+    }
+
+    #[tokio::test]
+    async fn answer_size_limits() {
+        let subscriber = Arc::new(SizedAnswer::default());
+        let subscribers = QuerySubscribers::new(vec![subscriber.clone()]);
+
+        let answer_len = |adnl, len: u64| {
+            let query = tl_proto::serialize(proto::rpc::AdnlPing { value: len });
+            let subscribers = &subscribers;
+            async move {
+                match query_subscribers(adnl, subscribers, &query).await.unwrap() {
+                    QueryProcessingResult::Processed(answer) => answer.map(|answer| answer.len()),
+                    _ => panic!("query rejected"),
+                }
+            }
+        };
+
+        let adnl = make_adnl_node(Default::default());
+        assert_eq!(answer_len(&adnl, 8192).await, Some(8192));
+        assert_eq!(answer_len(&adnl, 8193).await, None);
+        assert_eq!(
+            subscriber.failed.lock().as_slice(),
+            [AnswerError::AnswerTooLargeForTransport {
+                transport: QueryTransport::Adnl,
+                len: 8193,
+                max_len: 8192,
+            }]
+        );
+        assert_eq!(adnl.answers_too_large(), [(proto::rpc::AdnlPing::TL_ID, 1)]);
+        assert_eq!(adnl.metrics().answers_too_large, 1);
+
+        // Only single message answers without splitting
+        let adnl = make_adnl_node(adnl::NodeOptions {
+            max_split_adnl_answer_len: 0,
+            send_query_rejections: true,
+            ..Default::default()
+        });
+        assert_eq!(answer_len(&adnl, 980).await, Some(980));
+        match query_subscribers(
+            &adnl,
+            &subscribers,
+            &tl_proto::serialize(proto::rpc::AdnlPing { value: 981 }),
+        )
+        .await
+        .unwrap()
+        {
+            QueryProcessingResult::Processed(Some(answer)) => {
+                let answer = tl_proto::deserialize::<proto::adnl::QueryRejected>(&answer).unwrap();
+                assert_eq!(answer.reason, RejectReason::AnswerTooLarge.code());
+            }
+            _ => panic!("rejection answer expected"),
+        }
+        assert_eq!(subscriber.failed.lock().len(), 2);
+    }
+
+    struct Unauthorized;
+
+    #[async_trait::async_trait]
+    impl QuerySubscriber for Unauthorized {
+        async fn try_consume_query<'a>(
+            &self,
+            _: SubscriberContext<'a>,
+            _: u32,
+            query: Cow<'a, [u8]>,
+        ) -> Result<QueryConsumingResult<'a>> {
+            Ok(QueryConsumingResult::reject_with(
+                query,
+                RejectReason::Unauthorized,
+            ))
+        }
+    }
+
+    #[tokio::test]
+    async fn explicit_rejection_stops_dispatch() {
+        let subscribers = QuerySubscribers::new(vec![
+            Arc::new(Unauthorized),
+            Arc::new(Tagged {
+                tag: 1,
+                constructors: Vec::new(),
+            }),
+        ]);
+        let query = tl_proto::serialize(proto::rpc::AdnlPing { value: 0 });
+
+        let adnl = make_adnl_node(Default::default());
+        let result = query_subscribers(&adnl, &subscribers, &query)
+            .await
+            .unwrap();
+        assert!(matches!(result, QueryProcessingResult::Processed(None)));
+
+        let adnl = make_adnl_node(adnl::NodeOptions {
+            send_query_rejections: true,
+            ..Default::default()
+        });
+        match query_subscribers(&adnl, &subscribers, &query).await {
+            Ok(QueryProcessingResult::Processed(Some(answer))) => {
+                let answer = tl_proto::deserialize::<proto::adnl::QueryRejected>(&answer).unwrap();
+                assert_eq!(answer.reason, RejectReason::Unauthorized.code());
+            }
+            _ => panic!("rejection answer expected"),
+        }
+    }
+}
@@ -1,28 +1,188 @@
 use std::net::{Ipv4Addr, SocketAddrV4};
 
+use smallvec::SmallVec;
+
 use super::now;
 use crate::proto;
 
-/// Validates address list and extracts socket address from it
+/// Builder for the [`proto::adnl::AddressList`]
+///
+/// Addresses are stored in the order of preference. Only the first
+/// [`MAX_ADDRESS_LIST_LEN`] addresses are used
+///
+/// [`MAX_ADDRESS_LIST_LEN`]: proto::adnl::MAX_ADDRESS_LIST_LEN
+#[derive(Debug, Clone, Default)]
+pub struct AddressListBuilder {
+    addresses: SmallVec<[proto::adnl::Address; 2]>,
+    version: Option<u32>,
+    reinit_date: Option<u32>,
+    priority: u32,
+    expire_at: Option<u32>,
+}
+
+impl AddressListBuilder {
+    /// Creates an empty address list builder
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends address to the list if it is not full
+    pub fn with_address(self, address: SocketAddrV4) -> Self {
+        self.with_addresses([address])
+    }
+
+    /// Appends all addresses to the list until it is full
+    pub fn with_addresses<I>(mut self, addresses: I) -> Self
+    where
+        I: IntoIterator<Item = SocketAddrV4>,
+    {
+        let remaining = proto::adnl::MAX_ADDRESS_LIST_LEN.saturating_sub(self.addresses.len());
+        self.addresses.extend(
+            addresses
+                .into_iter()
+                .take(remaining)
+                .map(|address| proto::adnl::Address::from(&address)),
+        );
+        self
+    }
+
+    /// Sets the fixed version of the list.
+    ///
+    /// Default: current timestamp at the moment of building
+    pub fn with_version(mut self, version: u32) -> Self {
+        self.version = Some(version);
+        self
+    }
+
+    /// Sets reinit date of the list.
+    ///
+    /// Default: ADNL node start time (or `0` for [`AddressListBuilder::build`])
+    pub fn with_reinit_date(mut self, reinit_date: u32) -> Self {
+        self.reinit_date = Some(reinit_date);
+        self
+    }
+
+    /// Sets priority of the list.
+    ///
+    /// Default: `0`
+    pub fn with_priority(mut self, priority: u32) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Sets the timestamp after which the list will be treated as expired.
+    /// `0` means that list never expires.
+    ///
+    /// Default: `0` (or `now + address_list_timeout_sec` for ADNL packets)
+    pub fn with_expire_at(mut self, expire_at: u32) -> Self {
+        self.expire_at = Some(expire_at);
+        self
+    }
+
+    /// Returns `true` if there are no addresses in the list
+    pub fn is_empty(&self) -> bool {
+        self.addresses.is_empty()
+    }
+
+    /// Builds the TL structure, using the system clock for the default version
+    pub fn build(&self) -> proto::adnl::AddressList {
+        self.build_with_defaults(now(), 0, 0)
+    }
+
+    /// Builds and serializes the TL structure (bare)
+    pub fn build_serialized(&self) -> Vec<u8> {
+        tl_proto::serialize(self.build())
+    }
+
+    /// Builds the TL structure, using the specified values for unset fields
+    pub(crate) fn build_with_defaults(
+        &self,
+        version: u32,
+        reinit_date: u32,
+        expire_at: u32,
+    ) -> proto::adnl::AddressList {
+        proto::adnl::AddressList {
+            addresses: self.addresses.clone(),
+            version: self.version.unwrap_or(version),
+            reinit_date: self.reinit_date.unwrap_or(reinit_date),
+            priority: self.priority,
+            expire_at: self.expire_at.unwrap_or(expire_at),
+        }
+    }
+}
+
+/// Validates address list according to the system clock and extracts
+/// all socket addresses from it (in the order of preference)
 pub fn parse_address_list(
     list: &proto::adnl::AddressList,
     clock_tolerance: u32,
-) -> Result<SocketAddrV4, AdnlAddressListError> {
-    let address = list.address.ok_or(AdnlAddressListError::ListIsEmpty)?;
+) -> Result<Vec<SocketAddrV4>, AdnlAddressListError> {
+    parse_address_list_at(list, now(), clock_tolerance)
+}
+
+/// Same as [`parse_address_list`], but uses the specified timestamp as the current time
+pub(crate) fn parse_address_list_at(
+    list: &proto::adnl::AddressList,
+    now: u32,
+    clock_tolerance: u32,
+) -> Result<Vec<SocketAddrV4>, AdnlAddressListError> {
+    if list.addresses.is_empty() {
+        return Err(AdnlAddressListError::ListIsEmpty);
+    }
 
-    let version = now();
-    if list.reinit_date > version + clock_tolerance {
+    if list.reinit_date > now + clock_tolerance {
         return Err(AdnlAddressListError::TooNewVersion);
     }
 
-    if list.expire_at != 0 && list.expire_at < version {
+    if list.expire_at != 0 && list.expire_at < now {
         return Err(AdnlAddressListError::Expired);
     }
 
-    Ok(SocketAddrV4::new(
-        Ipv4Addr::from(address.ip),
-        address.port as u16,
-    ))
+    Ok(list
+        .addresses
+        .iter()
+        .map(|&address| SocketAddrV4::from(address))
+        .collect())
+}
+
+/// Validates address list and extracts the most preferred socket address from it
+pub(crate) fn parse_first_address(
+    list: &proto::adnl::AddressList,
+    now: u32,
+    clock_tolerance: u32,
+) -> Result<SocketAddrV4, AdnlAddressListError> {
+    let mut addresses = parse_address_list_at(list, now, clock_tolerance)?;
+    Ok(addresses.swap_remove(0))
+}
+
+/// Whether the address must not be advertised to the public network:
+/// private (RFC 1918), loopback, link-local, multicast, "this network" (`0.0.0.0/8`),
+/// reserved (`240.0.0.0/4`) or broadcast
+pub(crate) fn is_reserved_address(ip: &Ipv4Addr) -> bool {
+    let first_octet = ip.octets()[0];
+    ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_multicast()
+        // NOTE: packets to `0.0.0.0` are delivered to the local host on Linux
+        || first_octet == 0
+        // Including the broadcast address
+        || first_octet >= 240
+}
+
+/// Resolves `host:port` string into the list of IPv4 addresses.
+///
+/// IPv6 addresses are skipped since they are not supported yet
+#[cfg(feature = "dns")]
+pub async fn resolve_address(host: &str) -> anyhow::Result<Vec<proto::adnl::Address>> {
+    let addresses = tokio::net::lookup_host(host)
+        .await?
+        .filter_map(|addr| proto::adnl::Address::try_from(addr).ok())
+        .collect::<Vec<_>>();
+    if addresses.is_empty() {
+        anyhow::bail!("No IPv4 addresses found for {host}");
+    }
+    Ok(addresses)
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -37,7 +197,23 @@ pub enum AdnlAddressListError {
 
 #[cfg(test)]
 mod tests {
-    use std::net::SocketAddrV4;
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    use tl_proto::TlRead;
+
+    use super::*;
+
+    #[cfg(feature = "dns")]
+    #[tokio::test]
+    async fn resolve_addresses() {
+        let addresses = resolve_address("localhost:30303").await.unwrap();
+        assert!(addresses
+            .iter()
+            .any(|addr| addr.to_string() == "127.0.0.1:30303"));
+
+        assert!(resolve_address("1.2.3.4:port").await.is_err());
+        assert!(resolve_address("localhost").await.is_err());
+    }
 
     #[test]
     fn correct_port_update() {
@@ -47,4 +223,114 @@ mod tests {
         ip.set_port(4560);
         assert_eq!(ip.port(), 4560);
     }
+
+    #[test]
+    fn reserved_addresses() {
+        for ip in [
+            // RFC 1918
+            [10, 0, 0, 1],
+            [10, 255, 255, 255],
+            [172, 16, 0, 1],
+            [172, 31, 255, 255],
+            [192, 168, 0, 1],
+            [192, 168, 255, 255],
+            // Loopback
+            [127, 0, 0, 1],
+            [127, 255, 255, 254],
+            // Link-local
+            [169, 254, 0, 1],
+            [169, 254, 255, 255],
+            // Multicast
+            [224, 0, 0, 1],
+            [239, 255, 255, 255],
+            // Unspecified
+            [0, 0, 0, 0],
+            // This network
+            [0, 0, 0, 1],
+            [0, 255, 255, 255],
+            // Broadcast
+            [255, 255, 255, 255],
+            // Reserved
+            [240, 0, 0, 1],
+            [255, 255, 255, 254],
+        ] {
+            assert!(is_reserved_address(&Ipv4Addr::from(ip)), "{ip:?}");
+        }
+
+        for ip in [
+            [1, 2, 3, 4],
+            [9, 255, 255, 255],
+            [11, 0, 0, 1],
+            [172, 15, 255, 255],
+            [172, 32, 0, 1],
+            [192, 167, 255, 255],
+            [192, 169, 0, 1],
+            [128, 0, 0, 1],
+            [169, 253, 255, 255],
+            [1, 0, 0, 0],
+            [223, 255, 255, 255],
+        ] {
+            assert!(!is_reserved_address(&Ipv4Addr::from(ip)), "{ip:?}");
+        }
+    }
+
+    #[test]
+    fn multiple_addresses() {
+        let first = SocketAddrV4::new(Ipv4Addr::new(1, 2, 3, 4), 30303);
+        let second = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 30310);
+
+        let builder = AddressListBuilder::new()
+            .with_address(first)
+            .with_addresses([second])
+            .with_version(10)
+            .with_reinit_date(5)
+            .with_priority(1)
+            .with_expire_at(now() + 100);
+
+        let data = builder.build_serialized();
+        let list = proto::adnl::AddressList::read_from(&data, &mut 0).unwrap();
+        assert_eq!(list.version, 10);
+        assert_eq!(list.reinit_date, 5);
+        assert_eq!(list.priority, 1);
+        assert_eq!(parse_address_list(&list, 0).unwrap(), [first, second]);
+        assert_eq!(parse_first_address(&list, now(), 0).unwrap(), first);
+
+        let expired = builder.with_expire_at(1).build();
+        assert!(matches!(
+            parse_address_list(&expired, 0),
+            Err(AdnlAddressListError::Expired)
+        ));
+
+        // Only the first addresses are used
+        let many = (0..20).map(|i| SocketAddrV4::new(Ipv4Addr::new(1, 2, 3, i), 30303));
+        let builder = AddressListBuilder::new().with_addresses(many.clone());
+        assert_eq!(
+            builder.build().addresses.len(),
+            proto::adnl::MAX_ADDRESS_LIST_LEN
+        );
+
+        let mut list = builder.with_version(10).with_priority(1).build();
+        list.addresses = many
+            .map(|address| proto::adnl::Address::from(&address))
+            .collect();
+        let data = tl_proto::serialize(&list);
+        let mut offset = 0;
+        let parsed = proto::adnl::AddressList::read_from(&data, &mut offset).unwrap();
+        assert_eq!(offset, data.len());
+        assert_eq!(parsed.version, 10);
+        assert_eq!(parsed.priority, 1);
+        assert_eq!(
+            parse_address_list(&parsed, 0).unwrap(),
+            list.addresses[..proto::adnl::MAX_ADDRESS_LIST_LEN]
+                .iter()
+                .map(|&address| SocketAddrV4::from(address))
+                .collect::<Vec<_>>()
+        );
+
+        let empty = AddressListBuilder::new().build();
+        assert!(matches!(
+            parse_address_list(&empty, 0),
+            Err(AdnlAddressListError::ListIsEmpty)
+        ));
+    }
 }
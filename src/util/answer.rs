@@ -0,0 +1,95 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use tl_proto::{Boxed, TlError, TlRead};
+
+use crate::adnl::NodeIdShort;
+
+/// Deserializes boxed query answer.
+///
+/// Answer with a different constructor is mapped into [`UnexpectedAnswerError`].
+/// Unknown constructors of the nested fields are reported as is
+pub fn deserialize_answer<'a, A>(answer: &'a [u8]) -> Result<A>
+where
+    A: TlRead<'a, Repr = Boxed>,
+{
+    let mut offset = 0;
+    match A::read_from(answer, &mut offset) {
+        Ok(answer) => Ok(answer),
+        // NOTE: only the answer constructor itself was read
+        Err(TlError::UnknownConstructor) if offset == 4 => Err(UnexpectedAnswerError {
+            expected: std::any::type_name::<A>(),
+            got: u32::read_from(answer, &mut 0).unwrap_or_default(),
+        }
+        .into()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Peer answered with a different constructor
+#[derive(thiserror::Error, Debug, Copy, Clone, Eq, PartialEq)]
+#[error("Unexpected answer: expected {expected}, got constructor 0x{got:08x}")]
+pub struct UnexpectedAnswerError {
+    /// Expected answer type name
+    pub expected: &'static str,
+    /// Received constructor id
+    pub got: u32,
+}
+
+/// Callback which checks the raw answer bytes before the query resolves
+/// (e.g. compares the hash of the received block with the requested one)
+pub type AnswerVerifier = Arc<dyn Fn(&[u8]) -> bool + Send + Sync>;
+
+/// Answer was rejected by the [`AnswerVerifier`]. The peer is penalized
+/// with [`ReputationEvent::MalformedAnswer`]
+///
+/// [`ReputationEvent::MalformedAnswer`]: crate::adnl::ReputationEvent::MalformedAnswer
+#[derive(thiserror::Error, Debug, Copy, Clone, Eq, PartialEq)]
+#[error("Answer from {peer_id} failed verification")]
+pub struct AnswerVerificationFailed {
+    pub peer_id: NodeIdShort,
+}
+
+#[cfg(test)]
+mod tests {
+    use tl_proto::{BoxedConstructor, BoxedWrapper};
+
+    use super::*;
+    use crate::proto;
+
+    #[test]
+    fn unexpected_answers() {
+        let pong = tl_proto::serialize(proto::adnl::Pong { value: 123 });
+        let rejected = tl_proto::serialize(proto::adnl::QueryRejected { reason: 1 });
+
+        let answer = deserialize_answer::<proto::adnl::Pong>(&pong).unwrap();
+        assert_eq!(answer.value, 123);
+
+        let err = deserialize_answer::<proto::adnl::Pong>(&rejected).unwrap_err();
+        assert_eq!(
+            err.downcast::<UnexpectedAnswerError>().unwrap().got,
+            u32::from_le_bytes(rejected[..4].try_into().unwrap())
+        );
+
+        let err = match deserialize_answer::<BoxedWrapper<proto::overlay::Nodes>>(&pong) {
+            Ok(_) => panic!("constructor mismatch must not be ignored"),
+            Err(e) => e.downcast::<UnexpectedAnswerError>().unwrap(),
+        };
+        assert_eq!(err.got, u32::from_le_bytes(pong[..4].try_into().unwrap()));
+        assert_ne!(err.got, proto::overlay::Nodes::TL_ID);
+
+        // Unknown constructor of the nested field is not an answer mismatch
+        let mut nested = proto::adnl::HolePunch::TL_ID.to_le_bytes().to_vec();
+        nested.extend_from_slice(&0xdeadbeefu32.to_le_bytes());
+        let err = deserialize_answer::<proto::adnl::HolePunch>(&nested).unwrap_err();
+        assert!(err.downcast_ref::<UnexpectedAnswerError>().is_none());
+        assert!(matches!(
+            err.downcast_ref::<TlError>(),
+            Some(TlError::UnknownConstructor)
+        ));
+
+        // Truncated answer is not a constructor mismatch
+        let err = deserialize_answer::<proto::adnl::Pong>(&pong[..8]).unwrap_err();
+        assert!(err.downcast_ref::<UnexpectedAnswerError>().is_none());
+    }
+}
@@ -0,0 +1,154 @@
+use std::collections::VecDeque;
+use std::time::Instant;
+
+use super::FastHashMap;
+
+/// Prepared query answers with expiration, bounded by the total answers size
+#[derive(Default)]
+pub struct AnswerCache {
+    entries: FastHashMap<AnswerCacheKey, CachedAnswer>,
+    /// Keys in insertion order (with the entry seqno to skip replaced entries)
+    order: VecDeque<(AnswerCacheKey, u64)>,
+    total_size: usize,
+    next_seqno: u64,
+}
+
+impl AnswerCache {
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn total_size(&self) -> usize {
+        self.total_size
+    }
+
+    /// Returns a copy of the answer if it is not expired yet
+    pub fn get(&mut self, key: &AnswerCacheKey, now: Instant) -> Option<Vec<u8>> {
+        match self.entries.get(key) {
+            Some(entry) if entry.expires_at > now => Some(entry.answer.clone()),
+            Some(_) => {
+                self.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Stores the answer, evicting the oldest entries to fit into `max_size`
+    pub fn insert(
+        &mut self,
+        key: AnswerCacheKey,
+        answer: Vec<u8>,
+        expires_at: Instant,
+        max_size: usize,
+    ) {
+        if answer.len() > max_size {
+            return;
+        }
+
+        self.remove(&key);
+        while self.total_size + answer.len() > max_size {
+            if !self.evict_oldest() {
+                break;
+            }
+        }
+
+        let seqno = self.next_seqno;
+        self.next_seqno += 1;
+
+        self.total_size += answer.len();
+        self.order.push_back((key, seqno));
+        self.entries.insert(
+            key,
+            CachedAnswer {
+                answer,
+                expires_at,
+                seqno,
+            },
+        );
+    }
+
+    /// Removes expired entries from the beginning of the queue
+    /// and shrinks the cache to `max_size`
+    pub fn shrink(&mut self, now: Instant, max_size: usize) {
+        while let Some((key, seqno)) = self.order.front() {
+            match self.entries.get(key) {
+                Some(entry) if entry.seqno == *seqno => {
+                    if entry.expires_at > now && self.total_size <= max_size {
+                        break;
+                    }
+                    self.evict_oldest();
+                }
+                // Entry was replaced or removed
+                _ => {
+                    self.order.pop_front();
+                }
+            }
+        }
+    }
+
+    fn evict_oldest(&mut self) -> bool {
+        while let Some((key, seqno)) = self.order.pop_front() {
+            if matches!(self.entries.get(&key), Some(entry) if entry.seqno == seqno) {
+                self.remove(&key);
+                return true;
+            }
+        }
+        false
+    }
+
+    fn remove(&mut self, key: &AnswerCacheKey) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.total_size -= entry.answer.len();
+        }
+        if self.entries.is_empty() {
+            self.order.clear();
+        }
+    }
+}
+
+/// Hash of the query bytes with some additional context
+pub type AnswerCacheKey = [u8; 32];
+
+struct CachedAnswer {
+    answer: Vec<u8>,
+    expires_at: Instant,
+    seqno: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn expiration_and_size_bounds() {
+        let now = Instant::now();
+        let ttl = Duration::from_secs(1);
+        let key = |i: u8| [i; 32];
+
+        let mut cache = AnswerCache::default();
+        cache.insert(key(0), vec![0; 10], now + ttl, 25);
+        cache.insert(key(1), vec![1; 10], now + ttl * 2, 25);
+        assert_eq!(cache.get(&key(0), now).unwrap(), vec![0; 10]);
+
+        // The oldest entry is evicted
+        cache.insert(key(2), vec![2; 10], now + ttl, 25);
+        assert!(cache.get(&key(0), now).is_none());
+        assert_eq!((cache.len(), cache.total_size()), (2, 20));
+
+        // Too big answers are ignored
+        cache.insert(key(3), vec![3; 30], now + ttl, 25);
+        assert!(cache.get(&key(3), now).is_none());
+
+        // Replaced entry keeps only the new size
+        cache.insert(key(1), vec![1; 5], now + ttl * 2, 25);
+        assert_eq!((cache.len(), cache.total_size()), (2, 15));
+
+        // Expired entries are removed
+        assert!(cache.get(&key(2), now + ttl).is_none());
+        cache.shrink(now + ttl * 2, 25);
+        assert_eq!((cache.len(), cache.total_size()), (0, 0));
+    }
+}
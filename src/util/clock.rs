@@ -0,0 +1,156 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures_util::future::BoxFuture;
+use tokio::sync::watch;
+
+/// Source of the current time.
+///
+/// Wall time is used for protocol fields (reinit dates, versions, expiration),
+/// monotonic time is used for internal durations (timeouts, intervals).
+pub trait Clock: Send + Sync + 'static {
+    /// Current unix timestamp in seconds
+    fn now(&self) -> u32;
+
+    /// Current unix timestamp in milliseconds
+    fn now_ms(&self) -> u64 {
+        self.now() as u64 * 1000
+    }
+
+    /// Current monotonic time
+    fn instant(&self) -> Instant;
+
+    /// Waits until the monotonic time reaches the deadline
+    fn sleep_until(&self, deadline: Instant) -> BoxFuture<'static, ()>;
+
+    /// Waits for the specified duration of the monotonic time
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        self.sleep_until(self.instant() + duration)
+    }
+}
+
+/// Default clock which uses system time and tokio timers
+#[derive(Debug, Default, Copy, Clone)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    #[inline(always)]
+    fn now(&self) -> u32 {
+        super::now()
+    }
+
+    #[inline(always)]
+    fn now_ms(&self) -> u64 {
+        super::now_ms()
+    }
+
+    #[inline(always)]
+    fn instant(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> BoxFuture<'static, ()> {
+        Box::pin(tokio::time::sleep_until(deadline.into()))
+    }
+}
+
+/// Clock which only advances manually. Useful for tests
+#[derive(Clone)]
+pub struct ManualClock {
+    inner: Arc<ManualClockInner>,
+}
+
+struct ManualClockInner {
+    wall_start: u32,
+    monotonic_start: Instant,
+    elapsed: watch::Sender<Duration>,
+}
+
+impl ManualClock {
+    /// Creates a new clock, starting at the specified unix timestamp
+    pub fn new(now: u32) -> Self {
+        let (elapsed, _) = watch::channel(Duration::ZERO);
+        Self {
+            inner: Arc::new(ManualClockInner {
+                wall_start: now,
+                monotonic_start: Instant::now(),
+                elapsed,
+            }),
+        }
+    }
+
+    /// Advances both wall and monotonic time, waking up expired sleeps
+    pub fn advance(&self, duration: Duration) {
+        self.inner
+            .elapsed
+            .send_modify(|elapsed| *elapsed += duration);
+    }
+
+    fn elapsed(&self) -> Duration {
+        *self.inner.elapsed.borrow()
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new(super::now())
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> u32 {
+        self.inner.wall_start + self.elapsed().as_secs() as u32
+    }
+
+    fn now_ms(&self) -> u64 {
+        self.inner.wall_start as u64 * 1000 + self.elapsed().as_millis() as u64
+    }
+
+    fn instant(&self) -> Instant {
+        self.inner.monotonic_start + self.elapsed()
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> BoxFuture<'static, ()> {
+        let monotonic_start = self.inner.monotonic_start;
+        let mut elapsed = self.inner.elapsed.subscribe();
+        Box::pin(async move {
+            while monotonic_start + *elapsed.borrow_and_update() < deadline {
+                if elapsed.changed().await.is_err() {
+                    // Clock was dropped, time will never advance
+                    futures_util::future::pending::<()>().await;
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn manual_clock_sleep() {
+        let clock = ManualClock::new(1000);
+        let started_at = clock.instant();
+
+        let sleep = tokio::spawn(clock.sleep(Duration::from_secs(10)));
+        tokio::task::yield_now().await;
+
+        clock.advance(Duration::from_secs(5));
+        tokio::task::yield_now().await;
+        assert!(!sleep.is_finished());
+        assert_eq!(clock.now(), 1005);
+
+        clock.advance(Duration::from_secs(5));
+        tokio::time::timeout(Duration::from_secs(1), sleep)
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(clock.now(), 1010);
+        assert_eq!(clock.instant() - started_at, Duration::from_secs(10));
+
+        // Already expired
+        clock.sleep(Duration::ZERO).await;
+    }
+}
@@ -0,0 +1,61 @@
+//! Shared unit tests fixtures
+
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::Arc;
+
+use crate::adnl;
+#[cfg(feature = "overlay")]
+use crate::overlay;
+use crate::util::{Clock, SystemClock};
+
+/// Keystore with a single random key with tag `0`
+pub(crate) fn make_keystore() -> adnl::Keystore {
+    adnl::Keystore::builder()
+        .with_tagged_key(rand::random(), 0)
+        .unwrap()
+        .build()
+}
+
+/// ADNL node on a random localhost port
+pub(crate) fn make_adnl_node(options: adnl::NodeOptions) -> Arc<adnl::Node> {
+    make_adnl_node_with_clock(options, Arc::new(SystemClock))
+}
+
+/// ADNL node on a random localhost port with the specified clock
+pub(crate) fn make_adnl_node_with_clock(
+    options: adnl::NodeOptions,
+    clock: Arc<dyn Clock>,
+) -> Arc<adnl::Node> {
+    adnl::Node::with_clock(
+        SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0),
+        make_keystore(),
+        options,
+        None,
+        clock,
+    )
+    .unwrap()
+}
+
+/// ADNL node with a single random key in the virtual network
+pub(crate) fn add_virtual_node(
+    network: &adnl::VirtualNetwork,
+    options: adnl::NodeOptions,
+) -> Arc<adnl::Node> {
+    network.add_node(make_keystore(), options, None)
+}
+
+/// Not started ADNL node in the virtual network, joined to the public overlay
+#[cfg(feature = "overlay")]
+pub(crate) fn add_overlay_node(
+    network: &adnl::VirtualNetwork,
+    overlay_id: &overlay::IdShort,
+    adnl_options: adnl::NodeOptions,
+    overlay_options: overlay::OverlayOptions,
+) -> (Arc<adnl::Node>, Arc<overlay::Node>, Arc<overlay::Overlay>) {
+    let adnl = add_virtual_node(network, adnl_options);
+    let node = overlay::Node::new(adnl.clone(), 0).unwrap();
+    let (overlay, _) = node
+        .add_public_overlay(overlay_id, overlay_options)
+        .unwrap();
+    (adnl, node, overlay)
+}
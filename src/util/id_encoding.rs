@@ -0,0 +1,129 @@
+use base64::Engine as _;
+
+/// Parses 32-byte id from base64 (with padding) or hex string
+pub(crate) fn parse_id(s: &str) -> Result<[u8; 32], ParseIdError> {
+    let bytes = match s.len() {
+        64 => hex::decode(s).map_err(|_| ParseIdError::InvalidHex)?,
+        44 => base64::engine::general_purpose::STANDARD
+            .decode(s)
+            .map_err(|_| ParseIdError::InvalidBase64)?,
+        len => return Err(ParseIdError::InvalidLength(len)),
+    };
+    bytes
+        .try_into()
+        .map_err(|bytes: Vec<u8>| ParseIdError::InvalidLength(bytes.len()))
+}
+
+/// Encodes 32-byte id as base64 with padding
+pub(crate) fn id_to_base64(id: &[u8; 32]) -> String {
+    base64::engine::general_purpose::STANDARD.encode(id)
+}
+
+pub(crate) fn serialize_id<S>(id: &[u8; 32], serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    if serializer.is_human_readable() {
+        serializer.serialize_str(&id_to_base64(id))
+    } else {
+        serializer.serialize_bytes(id)
+    }
+}
+
+pub(crate) fn deserialize_id<'de, D>(deserializer: D) -> Result<[u8; 32], D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    use serde::de::{Error, Visitor};
+
+    struct IdVisitor;
+
+    impl<'de> Visitor<'de> for IdVisitor {
+        type Value = [u8; 32];
+
+        fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            f.write_str("base64 or hex encoded 32-byte id")
+        }
+
+        fn visit_str<E: Error>(self, v: &str) -> Result<Self::Value, E> {
+            parse_id(v).map_err(E::custom)
+        }
+
+        fn visit_bytes<E: Error>(self, v: &[u8]) -> Result<Self::Value, E> {
+            v.try_into()
+                .map_err(|_| E::custom(ParseIdError::InvalidLength(v.len())))
+        }
+    }
+
+    if deserializer.is_human_readable() {
+        deserializer.deserialize_str(IdVisitor)
+    } else {
+        deserializer.deserialize_bytes(IdVisitor)
+    }
+}
+
+/// Error while parsing id from string
+#[derive(thiserror::Error, Debug, Clone, Eq, PartialEq)]
+pub enum ParseIdError {
+    #[error("Invalid id length: {0}")]
+    InvalidLength(usize),
+    #[error("Invalid hex id")]
+    InvalidHex,
+    #[error("Invalid base64 id")]
+    InvalidBase64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Mainnet zerostate file hash as printed in the global config
+    const BASE64: &str = "0nC4eylStbp9qnCq8KjDYb789NjS25L5ZA1UQwcIOOQ=";
+    const HEX: &str = "d270b87b2952b5ba7daa70aaf0a8c361befcf4d8d2db92f9640d5443070838e4";
+
+    #[test]
+    fn parse_fixtures() {
+        let from_base64 = parse_id(BASE64).unwrap();
+        let from_hex = parse_id(HEX).unwrap();
+        assert_eq!(from_base64, from_hex);
+        assert_eq!(hex::encode(from_base64), HEX);
+        assert_eq!(id_to_base64(&from_hex), BASE64);
+
+        // Uppercase hex is also accepted
+        assert_eq!(parse_id(&HEX.to_uppercase()).unwrap(), from_hex);
+    }
+
+    #[test]
+    fn parse_invalid() {
+        assert_eq!(parse_id(""), Err(ParseIdError::InvalidLength(0)));
+        assert_eq!(
+            parse_id(&BASE64[..43]),
+            Err(ParseIdError::InvalidLength(43))
+        );
+        assert_eq!(
+            parse_id("0nC4eylStbp9qnCq8KjDYb789NjS25L5ZA1UQwcIOO=="),
+            Err(ParseIdError::InvalidBase64)
+        );
+        assert_eq!(parse_id(&"z".repeat(64)), Err(ParseIdError::InvalidHex));
+    }
+
+    #[test]
+    fn ids_roundtrip() {
+        let node_id: crate::adnl::NodeIdShort = BASE64.parse().unwrap();
+        assert_eq!(node_id, HEX.parse::<crate::adnl::NodeIdShort>().unwrap());
+        assert_eq!(node_id.to_string(), HEX);
+        assert_eq!(node_id.to_base64(), BASE64);
+        assert!(crate::adnl::NodeIdShort::from_base64(HEX).is_err());
+
+        let json = serde_json::to_string(&node_id).unwrap();
+        assert_eq!(json, format!("\"{BASE64}\""));
+        let parsed: crate::adnl::NodeIdShort = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, node_id);
+
+        let overlay_id: crate::overlay::IdShort = HEX.parse().unwrap();
+        assert_eq!(overlay_id.as_slice(), node_id.as_slice());
+        assert_eq!(overlay_id.to_base64(), BASE64);
+        let parsed: crate::overlay::IdShort = serde_json::from_str(&format!("\"{HEX}\"")).unwrap();
+        assert_eq!(parsed, overlay_id);
+    }
+}
@@ -0,0 +1,128 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use parking_lot::Mutex;
+
+use super::FastHashMap;
+
+/// Limits the number of repeated log messages with the same key
+#[derive(Default)]
+pub struct LogSampler {
+    keys: Mutex<FastHashMap<&'static str, SampledKey>>,
+    suppressed: AtomicU64,
+}
+
+impl LogSampler {
+    /// Total number of suppressed messages
+    pub fn suppressed(&self) -> u64 {
+        self.suppressed.load(Ordering::Relaxed)
+    }
+
+    /// Whether the message with this key must be logged. Logs a summary of the messages
+    /// which were suppressed during the previous interval.
+    ///
+    /// `limit` of zero disables sampling
+    pub fn should_log(&self, key: &'static str, now_ms: u64, limit: u32, interval_ms: u64) -> bool {
+        match self.sample(key, now_ms, limit, interval_ms) {
+            Some(0) => true,
+            Some(suppressed) => {
+                log_suppressed(key, suppressed);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Takes the numbers of messages suppressed since the last logged ones
+    fn take_suppressed(&self) -> Vec<(&'static str, u64)> {
+        self.keys
+            .lock()
+            .iter_mut()
+            .filter(|(_, sampled)| sampled.suppressed > 0)
+            .map(|(key, sampled)| (*key, std::mem::take(&mut sampled.suppressed)))
+            .collect()
+    }
+
+    /// Returns the number of messages suppressed since the last logged one,
+    /// or `None` if this message must be suppressed too
+    fn sample(&self, key: &'static str, now_ms: u64, limit: u32, interval_ms: u64) -> Option<u64> {
+        let mut keys = self.keys.lock();
+        if limit == 0 {
+            return Some(
+                keys.remove(key)
+                    .map(|key| key.suppressed)
+                    .unwrap_or_default(),
+            );
+        }
+
+        let sampled = keys.entry(key).or_default();
+        if now_ms.saturating_sub(sampled.interval_start_ms) >= interval_ms {
+            sampled.interval_start_ms = now_ms;
+            sampled.logged = 0;
+        }
+
+        if sampled.logged < limit {
+            sampled.logged += 1;
+            Some(std::mem::take(&mut sampled.suppressed))
+        } else {
+            sampled.suppressed += 1;
+            self.suppressed.fetch_add(1, Ordering::Relaxed);
+            None
+        }
+    }
+}
+
+impl Drop for LogSampler {
+    fn drop(&mut self) {
+        // Summary of the last interval is not carried by any later message
+        for (key, suppressed) in self.take_suppressed() {
+            log_suppressed(key, suppressed);
+        }
+    }
+}
+
+fn log_suppressed(key: &'static str, suppressed: u64) {
+    tracing::info!(key, "suppressed {suppressed} similar messages");
+}
+
+#[derive(Default)]
+struct SampledKey {
+    interval_start_ms: u64,
+    logged: u32,
+    /// Messages suppressed since the last logged one
+    suppressed: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flood_is_summarized() {
+        let sampler = LogSampler::default();
+
+        // Synthetic flood of 1000 identical messages during one interval
+        let now_ms = 1_000_000;
+        let logged = (0..1000)
+            .filter_map(|i| sampler.sample("flood", now_ms + i / 10, 10, 1000))
+            .collect::<Vec<_>>();
+        assert_eq!(logged, [0; 10]);
+        assert_eq!(sampler.suppressed(), 990);
+
+        // Other keys are not affected
+        assert_eq!(sampler.sample("other", now_ms, 10, 1000), Some(0));
+
+        // The first message of the next interval carries the summary
+        assert_eq!(sampler.sample("flood", now_ms + 1000, 10, 1000), Some(990));
+        assert_eq!(sampler.sample("flood", now_ms + 1001, 10, 1000), Some(0));
+        assert!(sampler.should_log("flood", now_ms + 1002, 10, 1000));
+
+        // Summary of the last interval is flushed on drop
+        assert_eq!(sampler.sample("flood", now_ms + 1003, 1, 1000), None);
+        assert_eq!(sampler.take_suppressed(), [("flood", 1)]);
+        assert!(sampler.take_suppressed().is_empty());
+
+        // Disabled sampling
+        assert!((0..100).all(|_| sampler.should_log("flood", now_ms + 1003, 0, 1000)));
+        assert_eq!(sampler.suppressed(), 991);
+    }
+}
@@ -0,0 +1,700 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::adnl;
+#[cfg(feature = "dht")]
+use crate::dht;
+#[cfg(feature = "overlay")]
+use crate::overlay;
+#[cfg(feature = "rldp")]
+use crate::rldp;
+
+/// Records metrics of the network nodes into the [`metrics`] facade.
+///
+/// All metric names are prefixed with `everscale_network_`.
+/// Overlay metrics are labeled with `overlay_id` (short id as hex),
+/// peer counts are labeled with `transport` (`adnl` or `rldp`),
+/// rolling rates are labeled with `window` (`1m` or `5m`).
+#[derive(Default, Clone)]
+pub struct MetricsExporter {
+    adnl: Option<Arc<adnl::Node>>,
+    #[cfg(feature = "rldp")]
+    rldp: Option<Arc<rldp::Node>>,
+    #[cfg(feature = "dht")]
+    dht: Option<Arc<dht::Node>>,
+    #[cfg(feature = "overlay")]
+    overlay: Option<Arc<overlay::Node>>,
+}
+
+impl MetricsExporter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Exports ADNL node metrics
+    pub fn with_adnl(mut self, adnl: Arc<adnl::Node>) -> Self {
+        self.adnl = Some(adnl);
+        self
+    }
+
+    /// Exports RLDP node metrics
+    #[cfg(feature = "rldp")]
+    pub fn with_rldp(mut self, rldp: Arc<rldp::Node>) -> Self {
+        self.rldp = Some(rldp);
+        self
+    }
+
+    /// Exports DHT node metrics
+    #[cfg(feature = "dht")]
+    pub fn with_dht(mut self, dht: Arc<dht::Node>) -> Self {
+        self.dht = Some(dht);
+        self
+    }
+
+    /// Exports metrics of all overlays of the node
+    #[cfg(feature = "overlay")]
+    pub fn with_overlay(mut self, overlay: Arc<overlay::Node>) -> Self {
+        self.overlay = Some(overlay);
+        self
+    }
+
+    /// Records current metrics values
+    pub fn record(&self) {
+        if let Some(adnl) = &self.adnl {
+            record_adnl_metrics(&adnl.metrics());
+            record_adnl_latencies(&adnl.latency_report());
+            for (constructor, count) in adnl.answers_too_large() {
+                let constructor = format!("0x{constructor:08x}");
+                metrics::absolute_counter!(
+                    "everscale_network_adnl_answers_too_large_total",
+                    count,
+                    "constructor" => constructor
+                );
+            }
+            if let Some(rates) = adnl.rates() {
+                record_rates("1m", &rates.last_1m);
+                record_rates("5m", &rates.last_5m);
+            }
+        }
+
+        #[cfg(feature = "rldp")]
+        if let Some(rldp) = &self.rldp {
+            let metrics = rldp.metrics();
+            metrics::gauge!(
+                "everscale_network_peers",
+                metrics.peer_count as f64,
+                "transport" => "rldp"
+            );
+            metrics::gauge!(
+                "everscale_network_rldp_transfers",
+                metrics.transfers_cache_len as f64
+            );
+            metrics::absolute_counter!(
+                "everscale_network_rldp_answers_too_large_total",
+                metrics.answers_too_large
+            );
+            metrics::absolute_counter!(
+                "everscale_network_rldp_pacing_ticks_total",
+                metrics.pacing_ticks
+            );
+            metrics::absolute_counter!(
+                "everscale_network_rldp_symbols_sent_total",
+                metrics.symbols_sent
+            );
+        }
+
+        #[cfg(feature = "dht")]
+        if let Some(dht) = &self.dht {
+            let metrics = dht.metrics();
+            metrics::gauge!(
+                "everscale_network_dht_known_peers",
+                metrics.known_peers_len as f64
+            );
+            metrics::gauge!(
+                "everscale_network_dht_bucket_peers",
+                metrics.bucket_peer_count as f64
+            );
+            metrics::gauge!(
+                "everscale_network_dht_storage_values",
+                metrics.storage_len as f64
+            );
+            metrics::gauge!(
+                "everscale_network_dht_storage_bytes",
+                metrics.storage_total_size as f64
+            );
+        }
+
+        #[cfg(feature = "overlay")]
+        if let Some(overlay) = &self.overlay {
+            metrics::absolute_counter!(
+                "everscale_network_overlay_unknown_overlay_messages_total",
+                overlay.unknown_overlay_messages()
+            );
+            for (overlay_id, metrics) in overlay.metrics() {
+                record_overlay_metrics(overlay_id.to_string(), &metrics);
+            }
+        }
+    }
+
+    /// Spawns a task on the current runtime which records metrics with the specified interval
+    pub fn spawn(self, interval: Duration) -> tokio::task::JoinHandle<()> {
+        super::spawn_named(
+            &tokio::runtime::Handle::current(),
+            "metrics_exporter",
+            async move {
+                let mut interval = tokio::time::interval(interval);
+                loop {
+                    interval.tick().await;
+                    self.record();
+                }
+            },
+        )
+    }
+}
+
+fn record_adnl_metrics(metrics: &adnl::NodeMetrics) {
+    metrics::gauge!(
+        "everscale_network_peers",
+        metrics.peer_count as f64,
+        "transport" => "adnl"
+    );
+    metrics::gauge!(
+        "everscale_network_adnl_channels",
+        metrics.channels_by_peers_len as f64
+    );
+    metrics::gauge!(
+        "everscale_network_adnl_channels_by_id",
+        metrics.channels_by_id_len as f64
+    );
+    metrics::gauge!(
+        "everscale_network_adnl_incoming_transfers",
+        metrics.incoming_transfers_len as f64
+    );
+    metrics::gauge!("everscale_network_adnl_queries", metrics.query_count as f64);
+    metrics::gauge!(
+        "everscale_network_adnl_large_answers",
+        metrics.large_answers_len as f64
+    );
+    metrics::gauge!(
+        "everscale_network_adnl_large_answers_bytes",
+        metrics.large_answers_size as f64
+    );
+    metrics::absolute_counter!(
+        "everscale_network_adnl_answers_expired_total",
+        metrics.answers_expired
+    );
+    metrics::gauge!(
+        "everscale_network_adnl_deferred_answers",
+        metrics.deferred_answers_len as f64
+    );
+    metrics::absolute_counter!(
+        "everscale_network_adnl_deferred_answers_expired_total",
+        metrics.deferred_answers_expired
+    );
+    metrics::absolute_counter!(
+        "everscale_network_adnl_answers_spoofed_total",
+        metrics.answers_spoofed
+    );
+    metrics::absolute_counter!(
+        "everscale_network_adnl_answers_unverified_total",
+        metrics.answers_unverified
+    );
+    metrics::absolute_counter!(
+        "everscale_network_adnl_peers_rejected_total",
+        metrics.peers_rejected
+    );
+    metrics::absolute_counter!(
+        "everscale_network_adnl_reserved_addresses_rejected_total",
+        metrics.reserved_addresses_rejected
+    );
+    metrics::absolute_counter!(
+        "everscale_network_adnl_peers_evicted_total",
+        metrics.peers_evicted
+    );
+    metrics::absolute_counter!(
+        "everscale_network_adnl_answers_dropped_total",
+        metrics.answers_dropped
+    );
+    metrics::absolute_counter!(
+        "everscale_network_adnl_packets_send_dropped_total",
+        metrics.packets_send_dropped
+    );
+    metrics::absolute_counter!(
+        "everscale_network_adnl_handshake_packets_sent_total",
+        metrics.handshake_packets_sent
+    );
+    metrics::absolute_counter!(
+        "everscale_network_adnl_query_retransmits_total",
+        metrics.query_retransmits
+    );
+    metrics::absolute_counter!(
+        "everscale_network_adnl_queries_deduplicated_total",
+        metrics.queries_deduplicated
+    );
+    metrics::absolute_counter!(
+        "everscale_network_adnl_channel_setup_retransmits_total",
+        metrics.channel_setup_retransmits
+    );
+    metrics::absolute_counter!(
+        "everscale_network_adnl_channel_setup_timeouts_total",
+        metrics.channel_setup_timeouts
+    );
+    metrics::absolute_counter!(
+        "everscale_network_adnl_events_dropped_total",
+        metrics.events_dropped
+    );
+    metrics::absolute_counter!(
+        "everscale_network_adnl_log_messages_suppressed_total",
+        metrics.log_messages_suppressed
+    );
+
+    let peers = &metrics.peers_by_context;
+    for (context, value) in [
+        ("adnl_packet", peers.adnl_packet),
+        ("dht", peers.dht),
+        ("public_overlay", peers.public_overlay),
+    ] {
+        metrics::gauge!(
+            "everscale_network_adnl_peers_by_context",
+            value as f64,
+            "context" => context
+        );
+    }
+
+    let traffic = &metrics.traffic;
+    for (direction, packets, bytes) in [
+        ("in", traffic.packets_received, traffic.bytes_received),
+        ("out", traffic.packets_sent, traffic.bytes_sent),
+    ] {
+        metrics::absolute_counter!(
+            "everscale_network_adnl_packets_total",
+            packets,
+            "direction" => direction
+        );
+        metrics::absolute_counter!(
+            "everscale_network_adnl_bytes_total",
+            bytes,
+            "direction" => direction
+        );
+    }
+    metrics::absolute_counter!(
+        "everscale_network_adnl_handshake_packets_received_total",
+        traffic.handshake_packets_received
+    );
+    metrics::absolute_counter!(
+        "everscale_network_adnl_packets_to_unknown_local_id_total",
+        traffic.packets_to_unknown_local_id
+    );
+    metrics::absolute_counter!(
+        "everscale_network_adnl_queries_sent_total",
+        traffic.queries_sent
+    );
+    for (result, value) in [
+        ("answered", traffic.queries_answered),
+        ("timed_out", traffic.queries_timed_out),
+    ] {
+        metrics::absolute_counter!(
+            "everscale_network_adnl_queries_finished_total",
+            value,
+            "result" => result
+        );
+    }
+    for (result, value) in [
+        ("consumed", traffic.queries_consumed),
+        ("unhandled", traffic.queries_unhandled),
+    ] {
+        metrics::absolute_counter!(
+            "everscale_network_adnl_queries_received_total",
+            value,
+            "result" => result
+        );
+    }
+    for (result, value) in [
+        ("consumed", traffic.messages_consumed),
+        ("unhandled", traffic.messages_unhandled),
+    ] {
+        metrics::absolute_counter!(
+            "everscale_network_adnl_messages_received_total",
+            value,
+            "result" => result
+        );
+    }
+
+    let drops = &metrics.packets_dropped;
+    for (reason, value) in [
+        ("bad_length", drops.bad_length),
+        ("unknown_channel", drops.unknown_channel),
+        ("checksum_mismatch", drops.checksum_mismatch),
+        ("decrypt_error", drops.decrypt_error),
+        ("parse_error", drops.parse_error),
+        ("unsupported_version", drops.unsupported_version),
+        ("invalid_signature", drops.invalid_signature),
+        ("denied_address", drops.denied_address),
+        ("denied_peer", drops.denied_peer),
+        ("replayed", drops.replayed),
+        ("seqno_too_old", drops.seqno_too_old),
+    ] {
+        metrics::absolute_counter!(
+            "everscale_network_adnl_packets_dropped_total",
+            value,
+            "reason" => reason
+        );
+    }
+}
+
+/// Exports query latency histograms in the Prometheus layout (cumulative `le` buckets),
+/// labeled with `constructor` (TL id as hex or `other`)
+fn record_adnl_latencies(report: &adnl::LatencyReport) {
+    let constructors = report
+        .by_constructor
+        .iter()
+        .map(|(constructor, histogram)| (format!("0x{constructor:08x}"), histogram))
+        .chain(std::iter::once((
+            "other".to_owned(),
+            &report.other_constructors,
+        )));
+
+    for (constructor, histogram) in constructors {
+        let mut total = 0;
+        for (bucket, count) in histogram.buckets.iter().enumerate() {
+            total += count;
+            let le = match adnl::LATENCY_BUCKETS_MS.get(bucket) {
+                Some(bound) => bound.to_string(),
+                None => "+Inf".to_owned(),
+            };
+            metrics::absolute_counter!(
+                "everscale_network_adnl_query_latency_ms_bucket",
+                total,
+                "constructor" => constructor.clone(),
+                "le" => le
+            );
+        }
+        metrics::absolute_counter!(
+            "everscale_network_adnl_query_latency_ms_count",
+            histogram.count,
+            "constructor" => constructor.clone()
+        );
+        metrics::absolute_counter!(
+            "everscale_network_adnl_query_latency_ms_sum",
+            histogram.sum_ms,
+            "constructor" => constructor
+        );
+    }
+}
+
+fn record_rates(window: &'static str, rates: &adnl::Rates) {
+    for (name, value) in [
+        ("packets_received", rates.packets_received),
+        ("packets_sent", rates.packets_sent),
+        ("bytes_received", rates.bytes_received),
+        ("bytes_sent", rates.bytes_sent),
+        ("adnl_queries", rates.queries),
+        ("rldp_queries", rates.rldp_queries),
+        ("overlay_broadcasts", rates.broadcasts),
+    ] {
+        metrics::gauge!(
+            format!("everscale_network_{name}_per_second"),
+            value,
+            "window" => window
+        );
+    }
+}
+
+#[cfg(feature = "overlay")]
+fn record_overlay_metrics(overlay_id: String, metrics: &overlay::OverlayMetrics) {
+    let labels = [("overlay_id", overlay_id)];
+    metrics::gauge!(
+        "everscale_network_overlay_owned_broadcasts",
+        metrics.owned_broadcasts_len as f64,
+        &labels
+    );
+    metrics::gauge!(
+        "everscale_network_overlay_finished_broadcasts",
+        metrics.finished_broadcasts_len as f64,
+        &labels
+    );
+    metrics::gauge!(
+        "everscale_network_overlay_nodes",
+        metrics.node_count as f64,
+        &labels
+    );
+    metrics::gauge!(
+        "everscale_network_overlay_known_peers",
+        metrics.known_peers as f64,
+        &labels
+    );
+    metrics::gauge!(
+        "everscale_network_overlay_neighbours",
+        metrics.neighbours as f64,
+        &labels
+    );
+    metrics::gauge!(
+        "everscale_network_overlay_received_broadcasts_bytes",
+        metrics.received_broadcasts_data_len as f64,
+        &labels
+    );
+    metrics::gauge!(
+        "everscale_network_overlay_received_broadcasts_barriers",
+        metrics.received_broadcasts_barrier_count as f64,
+        &labels
+    );
+    metrics::absolute_counter!(
+        "everscale_network_overlay_received_broadcasts_dropped_total",
+        metrics.received_broadcasts_dropped,
+        &labels
+    );
+    metrics::gauge!(
+        "everscale_network_overlay_oldest_received_broadcast_age_ms",
+        metrics.oldest_received_broadcast_age_ms.unwrap_or_default() as f64,
+        &labels
+    );
+    metrics::gauge!(
+        "everscale_network_overlay_received_peers",
+        metrics.received_peers_len as f64,
+        &labels
+    );
+    metrics::gauge!(
+        "everscale_network_overlay_ignored_peers",
+        metrics.ignored_peers_len as f64,
+        &labels
+    );
+    metrics::gauge!(
+        "everscale_network_overlay_outgoing_broadcast_rate",
+        metrics.outgoing_broadcast_rate as f64,
+        &labels
+    );
+    metrics::absolute_counter!(
+        "everscale_network_overlay_outgoing_broadcast_packets_total",
+        metrics.outgoing_broadcast_packets,
+        &labels
+    );
+    metrics::gauge!(
+        "everscale_network_overlay_answer_cache_entries",
+        metrics.answer_cache_len as f64,
+        &labels
+    );
+    metrics::gauge!(
+        "everscale_network_overlay_answer_cache_bytes",
+        metrics.answer_cache_size as f64,
+        &labels
+    );
+    metrics::absolute_counter!(
+        "everscale_network_overlay_answer_cache_hits_total",
+        metrics.answer_cache_hits,
+        &labels
+    );
+    metrics::gauge!(
+        "everscale_network_overlay_broadcast_history_entries",
+        metrics.broadcast_history_len as f64,
+        &labels
+    );
+    metrics::gauge!(
+        "everscale_network_overlay_broadcast_history_bytes",
+        metrics.broadcast_history_size as f64,
+        &labels
+    );
+    metrics::gauge!(
+        "everscale_network_overlay_throttled_broadcast_sources",
+        metrics.throttled_broadcast_sources as f64,
+        &labels
+    );
+    metrics::absolute_counter!(
+        "everscale_network_overlay_suppressed_broadcast_forwards_total",
+        metrics.suppressed_broadcast_forwards,
+        &labels
+    );
+    metrics::absolute_counter!(
+        "everscale_network_overlay_broadcast_decode_errors_total",
+        metrics.broadcast_decode_errors,
+        &labels
+    );
+    metrics::absolute_counter!(
+        "everscale_network_overlay_broadcasts_corrupted_total",
+        metrics.broadcasts_corrupted,
+        &labels
+    );
+    metrics::absolute_counter!(
+        "everscale_network_overlay_broadcasts_duplicated_total",
+        metrics.broadcasts_duplicated,
+        &labels
+    );
+    metrics::absolute_counter!(
+        "everscale_network_overlay_own_broadcast_echoes_total",
+        metrics.own_broadcast_echoes,
+        &labels
+    );
+    metrics::gauge!(
+        "everscale_network_overlay_incoming_fec_transfers",
+        metrics.incoming_fec_transfers as f64,
+        &labels
+    );
+    metrics::absolute_counter!(
+        "everscale_network_overlay_fec_transfers_expired_total",
+        metrics.fec_transfers_expired,
+        &labels
+    );
+    metrics::gauge!(
+        "everscale_network_overlay_originated_queue_len",
+        metrics.originated_queue_len as f64,
+        &labels
+    );
+    metrics::gauge!(
+        "everscale_network_overlay_relayed_queue_len",
+        metrics.relayed_queue_len as f64,
+        &labels
+    );
+    metrics::absolute_counter!(
+        "everscale_network_overlay_originated_packets_dropped_total",
+        metrics.originated_packets_dropped,
+        &labels
+    );
+    metrics::absolute_counter!(
+        "everscale_network_overlay_relayed_packets_dropped_total",
+        metrics.relayed_packets_dropped,
+        &labels
+    );
+    metrics::absolute_counter!(
+        "everscale_network_overlay_unhandled_messages_total",
+        metrics.unhandled_messages,
+        &labels
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use metrics::{Counter, Gauge, Histogram, Key, KeyName, Recorder, SharedString, Unit};
+    use parking_lot::Mutex;
+
+    use super::*;
+    use crate::util::fixtures::make_adnl_node;
+
+    /// Collects registered metric names with their labels
+    #[derive(Default)]
+    struct CollectingRecorder {
+        keys: Mutex<BTreeSet<String>>,
+    }
+
+    impl CollectingRecorder {
+        fn collect(&self, key: &Key) {
+            let mut name = key.name().to_owned();
+            for label in key.labels() {
+                name.push_str(&format!(" {}={}", label.key(), label.value()));
+            }
+            self.keys.lock().insert(name);
+        }
+    }
+
+    impl Recorder for &'static CollectingRecorder {
+        fn describe_counter(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+        fn describe_gauge(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+        fn describe_histogram(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+        fn register_counter(&self, key: &Key) -> Counter {
+            self.collect(key);
+            Counter::noop()
+        }
+
+        fn register_gauge(&self, key: &Key) -> Gauge {
+            self.collect(key);
+            Gauge::noop()
+        }
+
+        fn register_histogram(&self, key: &Key) -> Histogram {
+            self.collect(key);
+            Histogram::noop()
+        }
+    }
+
+    fn make_adnl() -> Arc<adnl::Node> {
+        let adnl = make_adnl_node(Default::default());
+        adnl.add_echo_subscriber().unwrap();
+        adnl
+    }
+
+    #[tokio::test]
+    async fn exported_metric_families() {
+        let recorder: &'static CollectingRecorder = Box::leak(Box::default());
+        metrics::set_boxed_recorder(Box::new(recorder)).unwrap();
+
+        let left = make_adnl();
+        let right = make_adnl();
+        #[cfg(feature = "rldp")]
+        let rldp = rldp::Node::new(left.clone(), Vec::new(), Default::default()).unwrap();
+        #[cfg(feature = "overlay")]
+        let (overlay, overlay_id) = {
+            let overlay = overlay::Node::new(left.clone(), 0).unwrap();
+            let overlay_id = overlay::IdFull::for_workchain(0, &[1; 32]).compute_short_id();
+            overlay
+                .add_public_overlay(&overlay_id, Default::default())
+                .unwrap();
+            (overlay, overlay_id)
+        };
+
+        left.start().unwrap();
+        right.start().unwrap();
+
+        // Push some traffic between the nodes
+        let left_id = *left.key_by_tag(0).unwrap().id();
+        let right_key = right.key_by_tag(0).unwrap().clone();
+        left.add_peer(
+            adnl::NewPeerContext::AdnlPacket,
+            &left_id,
+            right_key.id(),
+            right.socket_addr(),
+            *right_key.full_id(),
+        )
+        .unwrap();
+        let stats = left
+            .ping_peer(&left_id, right_key.id(), 16, Some(1000))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(stats.intact);
+
+        let exporter = MetricsExporter::new().with_adnl(left);
+        #[cfg(feature = "rldp")]
+        let exporter = exporter.with_rldp(rldp);
+        #[cfg(feature = "overlay")]
+        let exporter = exporter.with_overlay(overlay);
+        exporter.record();
+
+        let expected = vec![
+            "everscale_network_peers transport=adnl".to_owned(),
+            "everscale_network_adnl_channels".to_owned(),
+            "everscale_network_adnl_answers_dropped_total".to_owned(),
+            "everscale_network_adnl_packets_dropped_total reason=parse_error".to_owned(),
+            "everscale_network_adnl_query_latency_ms_count constructor=other".to_owned(),
+        ];
+        #[cfg(feature = "rldp")]
+        let expected = [
+            expected,
+            vec![
+                "everscale_network_peers transport=rldp".to_owned(),
+                "everscale_network_rldp_transfers".to_owned(),
+            ],
+        ]
+        .concat();
+        #[cfg(feature = "overlay")]
+        let expected =
+            [
+                expected,
+                vec![
+            format!("everscale_network_overlay_neighbours overlay_id={overlay_id}"),
+            format!("everscale_network_overlay_unhandled_messages_total overlay_id={overlay_id}"),
+            ],
+            ]
+            .concat();
+
+        let keys = recorder.keys.lock();
+        for expected in expected {
+            assert!(keys.contains(&expected), "{expected} not found in {keys:?}");
+        }
+
+        // Ping query latencies
+        assert!(keys.iter().any(|key| key
+            .starts_with("everscale_network_adnl_query_latency_ms_bucket constructor=0x")
+            && key.ends_with("le=+Inf")));
+    }
+}
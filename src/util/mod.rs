@@ -2,19 +2,52 @@
 
 use std::collections::{HashMap, HashSet};
 
+#[cfg(feature = "dns")]
+pub use self::address_list::resolve_address;
+pub use self::address_list::{parse_address_list, AddressListBuilder, AdnlAddressListError};
+pub use self::answer::{
+    deserialize_answer, AnswerVerificationFailed, AnswerVerifier, UnexpectedAnswerError,
+};
+pub use self::clock::{Clock, ManualClock, SystemClock};
+pub use self::id_encoding::ParseIdError;
+#[cfg(feature = "metrics")]
+pub use self::metrics_exporter::MetricsExporter;
 pub use self::network_builder::{
     DeferredInitialization, DeferredInitializationList, NetworkBuilder,
 };
+pub use self::public_key::{hash_public_key, PublicKeyVariant};
+pub use self::signatures::{
+    sign_dht_node, sign_overlay_node, verify_dht_node, verify_dht_value, verify_overlay_node,
+    verify_signed,
+};
 
 pub(crate) use self::address_list::*;
+pub(crate) use self::answer_cache::*;
 pub(crate) use self::fast_rand::*;
+pub(crate) use self::id_encoding::*;
+pub(crate) use self::log_sampler::*;
 pub(crate) use self::packets_history::*;
+pub(crate) use self::runtime::*;
+pub(crate) use self::serialize_buffer::*;
 pub(crate) use self::updated_at::*;
 
 mod address_list;
+mod answer;
+mod answer_cache;
+mod clock;
 mod fast_rand;
+#[cfg(test)]
+pub(crate) mod fixtures;
+mod id_encoding;
+mod log_sampler;
+#[cfg(feature = "metrics")]
+mod metrics_exporter;
 mod network_builder;
 mod packets_history;
+mod public_key;
+mod runtime;
+mod serialize_buffer;
+mod signatures;
 mod updated_at;
 
 pub(crate) type FastHashSet<K> = HashSet<K, FastHasherState>;
@@ -29,3 +62,10 @@ pub(crate) fn now() -> u32 {
         .unwrap_or_default()
         .as_secs() as u32
 }
+
+pub(crate) fn now_ms() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
@@ -1,192 +1,307 @@
 use std::sync::atomic::{AtomicU64, Ordering};
 
+use parking_lot::Mutex;
+
+/// Number of the latest seqnos which are tracked by default
+pub const DEFAULT_SEQNO_WINDOW: usize = 512;
+
 pub struct PacketsHistory {
-    mask: Option<HistoryBits>,
+    window: Option<Mutex<SeqnoWindow>>,
+    /// The highest sent or received seqno
     seqno: AtomicU64,
+    /// The highest seqno up to which all packets were received or left the window
+    contiguous_seqno: AtomicU64,
 }
 
 impl PacketsHistory {
     pub fn for_send() -> Self {
         Self {
-            mask: None,
+            window: None,
             seqno: Default::default(),
+            contiguous_seqno: Default::default(),
         }
     }
 
     pub fn for_recv() -> Self {
         Self {
-            mask: Some(Default::default()),
+            window: Some(Default::default()),
             seqno: Default::default(),
+            contiguous_seqno: Default::default(),
         }
     }
 
     pub fn reset(&self) {
-        if let Some(mask) = &self.mask {
-            loop {
-                let index = mask.index.load(Ordering::Acquire);
-                if index == IN_TRANSIT {
-                    // TODO: yield
-                    continue;
-                }
-
-                if mask
-                    .index
-                    .compare_exchange(index, IN_TRANSIT, Ordering::Release, Ordering::Relaxed)
-                    .is_err()
-                {
-                    continue;
-                }
-                break;
-            }
-
-            for i in 0..HISTORY_SIZE {
-                let value = u64::from(i == HISTORY_SIZE / 2);
-                mask.bits[i].store(value, Ordering::Release);
-            }
-        }
+        // NOTE: the lock is held to not interleave with the delivery
+        let _window = self.window.as_ref().map(|window| {
+            let mut window = window.lock();
+            *window = Default::default();
+            window
+        });
 
         self.seqno.store(0, Ordering::Release);
-        if let Some(mask) = &self.mask {
-            let _ =
-                mask.index
-                    .compare_exchange(IN_TRANSIT, 0, Ordering::Release, Ordering::Relaxed);
-        }
+        self.contiguous_seqno.store(0, Ordering::Release);
     }
 
     pub fn seqno(&self) -> u64 {
         self.seqno.load(Ordering::Acquire)
     }
 
+    /// The highest seqno up to which all packets were either received
+    /// or will never be accepted (left the window)
+    pub fn contiguous_seqno(&self) -> u64 {
+        match &self.window {
+            Some(_) => self.contiguous_seqno.load(Ordering::Acquire),
+            None => self.seqno(),
+        }
+    }
+
     pub fn bump_seqno(&self) -> u64 {
         self.seqno.fetch_add(1, Ordering::AcqRel) + 1
     }
 
+    /// Returns `true` if the packet with this seqno was not received before
+    /// (within the default window)
+    #[cfg(any(test, feature = "overlay"))]
     pub fn deliver_packet(&self, seqno: u64) -> bool {
-        let mask = match &self.mask {
-            Some(mask) => mask,
-            None => loop {
-                let last_seqno = self.seqno.load(Ordering::Acquire);
-                if last_seqno < seqno
-                    && self
-                        .seqno
-                        .compare_exchange(last_seqno, seqno, Ordering::Release, Ordering::Relaxed)
-                        .is_err()
-                {
-                    continue;
-                }
-                return true;
-            },
+        self.check_seqno(seqno, DEFAULT_SEQNO_WINDOW) == SeqnoCheck::New
+    }
+
+    /// Remembers the seqno if it is new and is within the window of
+    /// `window_size` latest seqnos (rounded up to a multiple of 64)
+    pub fn check_seqno(&self, seqno: u64, window_size: usize) -> SeqnoCheck {
+        let window = match &self.window {
+            Some(window) => window,
+            None => {
+                self.seqno.fetch_max(seqno, Ordering::AcqRel);
+                return SeqnoCheck::New;
+            }
         };
 
-        let seqno_masked = seqno & INDEX_MASK;
-        let seqno_normalized = seqno & !INDEX_MASK;
+        let mut window = window.lock();
+        let result = window.deliver(seqno, window_size);
+        if result == SeqnoCheck::New {
+            self.seqno.store(window.max_seqno, Ordering::Release);
+            self.contiguous_seqno
+                .store(window.contiguous_seqno, Ordering::Release);
+        }
+        result
+    }
 
-        loop {
-            let index = mask.index.load(Ordering::Acquire);
-            if index == IN_TRANSIT {
-                // TODO: yield
-                continue;
-            }
+    /// Received packets statistics since the last reset
+    pub fn stats(&self) -> SeqnoStats {
+        match &self.window {
+            Some(window) => window.lock().stats(),
+            None => Default::default(),
+        }
+    }
+}
 
-            let index_masked = index & INDEX_MASK;
-            let index_normalized = index & !INDEX_MASK;
+/// Result of [`PacketsHistory::check_seqno`]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum SeqnoCheck {
+    /// Seqno was not received before
+    New,
+    /// Seqno was already received
+    Duplicate,
+    /// Seqno is older than the window
+    TooOld,
+}
 
-            if index_normalized > seqno_normalized + INDEX_MASK + 1 {
-                tracing::debug!(seqno, index_normalized, "peer packet is too old");
-                return false;
-            }
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct SeqnoStats {
+    /// Number of unique received seqnos
+    pub received: u64,
+    /// Number of missing seqnos between the lowest and the highest received ones
+    pub lost: u64,
+}
 
-            let mask_bit = 1 << (seqno_masked % 64);
-            let mask_offset = match index_normalized.cmp(&seqno_normalized) {
-                std::cmp::Ordering::Greater => Some(0),
-                std::cmp::Ordering::Equal => Some(HISTORY_SIZE / 2),
-                std::cmp::Ordering::Less => None,
-            };
-
-            let next_index = match mask_offset {
-                Some(mask_offset) => {
-                    let mask_offset = mask_offset + seqno_masked as usize / 64;
-                    let already_delivered =
-                        mask.bits[mask_offset].load(Ordering::Acquire) & mask_bit;
-                    if mask.index.load(Ordering::Acquire) != index {
-                        continue;
-                    }
-
-                    if already_delivered != 0 {
-                        tracing::trace!(seqno, "peer packet was already received");
-                        return false;
-                    }
-
-                    if mask
-                        .index
-                        .compare_exchange(index, IN_TRANSIT, Ordering::Release, Ordering::Relaxed)
-                        .is_err()
-                    {
-                        continue;
-                    }
-
-                    mask.bits[mask_offset].fetch_or(mask_bit, Ordering::Release);
-
-                    index
-                }
-                None => {
-                    if mask
-                        .index
-                        .compare_exchange(index, IN_TRANSIT, Ordering::Release, Ordering::Relaxed)
-                        .is_err()
-                    {
-                        continue;
-                    }
-
-                    if index_normalized + INDEX_MASK + 1 == seqno_normalized {
-                        for i in 0..HISTORY_SIZE / 2 {
-                            mask.bits[i].store(
-                                mask.bits[i + HISTORY_SIZE / 2].load(Ordering::Acquire),
-                                Ordering::Release,
-                            )
-                        }
-
-                        for bits in &mask.bits[HISTORY_SIZE / 2..HISTORY_SIZE] {
-                            bits.store(0, Ordering::Relaxed)
-                        }
-                    } else {
-                        for bits in &mask.bits {
-                            bits.store(0, Ordering::Release)
-                        }
-                    }
-
-                    index_normalized
-                }
-            };
+#[derive(Default)]
+struct SeqnoWindow {
+    /// Received seqnos bitmap, indexed by the seqno modulo window size
+    bits: Vec<u64>,
+    /// Whether at least one seqno was received
+    started: bool,
+    first_seqno: u64,
+    max_seqno: u64,
+    contiguous_seqno: u64,
+    received: u64,
+}
+
+impl SeqnoWindow {
+    fn deliver(&mut self, seqno: u64, window_size: usize) -> SeqnoCheck {
+        self.resize(window_size);
+        let size = self.size();
 
-            let last_seqno = self.seqno.load(Ordering::Acquire);
-            if last_seqno < seqno {
-                self.seqno.store(seqno, Ordering::Release);
+        if !self.started {
+            self.started = true;
+            self.first_seqno = seqno;
+            self.max_seqno = seqno;
+            self.contiguous_seqno = seqno;
+        } else if seqno > self.max_seqno {
+            // Forget seqnos which left the window
+            if seqno - self.max_seqno >= size {
+                self.bits.fill(0);
+            } else {
+                for seqno in self.max_seqno + 1..=seqno {
+                    let (index, bit) = slot(&self.bits, seqno);
+                    self.bits[index] &= !bit;
+                }
             }
+            self.max_seqno = seqno;
+        } else if self.max_seqno - seqno >= size {
+            return SeqnoCheck::TooOld;
+        } else if self.contains(seqno) {
+            return SeqnoCheck::Duplicate;
+        }
 
-            let index_masked = (index_masked + 1) & !INDEX_MASK;
-            let _ = mask.index.compare_exchange(
-                IN_TRANSIT,
-                next_index | index_masked,
-                Ordering::Release,
-                Ordering::Relaxed,
-            );
+        let (index, bit) = slot(&self.bits, seqno);
+        self.bits[index] |= bit;
+        self.received += 1;
+        self.first_seqno = self.first_seqno.min(seqno);
 
-            break;
+        // Missing seqnos which left the window will never be accepted
+        let floor = self.max_seqno.saturating_sub(size);
+        self.contiguous_seqno = self.contiguous_seqno.max(floor);
+        while self.contiguous_seqno < self.max_seqno && self.contains(self.contiguous_seqno + 1) {
+            self.contiguous_seqno += 1;
         }
 
-        true
+        SeqnoCheck::New
+    }
+
+    fn stats(&self) -> SeqnoStats {
+        if !self.started {
+            return Default::default();
+        }
+
+        let expected = self.max_seqno - self.first_seqno + 1;
+        SeqnoStats {
+            received: self.received,
+            lost: expected.saturating_sub(self.received),
+        }
+    }
+
+    fn size(&self) -> u64 {
+        self.bits.len() as u64 * 64
+    }
+
+    fn contains(&self, seqno: u64) -> bool {
+        let (index, bit) = slot(&self.bits, seqno);
+        self.bits[index] & bit != 0
+    }
+
+    /// Changes the window size keeping the latest seqnos which fit into it
+    fn resize(&mut self, window_size: usize) {
+        let words = window_size.max(1).div_ceil(64);
+        if words == self.bits.len() {
+            return;
+        }
+
+        let old = std::mem::replace(&mut self.bits, vec![0; words]);
+        if !self.started || old.is_empty() {
+            return;
+        }
+
+        let kept = self.size().min(old.len() as u64 * 64);
+        for seqno in self.max_seqno.saturating_sub(kept - 1)..=self.max_seqno {
+            let (old_index, old_bit) = slot(&old, seqno);
+            if old[old_index] & old_bit != 0 {
+                let (index, bit) = slot(&self.bits, seqno);
+                self.bits[index] |= bit;
+            }
+        }
+        self.contiguous_seqno = self
+            .contiguous_seqno
+            .max(self.max_seqno.saturating_sub(self.size()));
     }
 }
 
-#[derive(Default)]
-struct HistoryBits {
-    index: AtomicU64,
-    bits: [AtomicU64; HISTORY_SIZE],
+fn slot(bits: &[u64], seqno: u64) -> (usize, u64) {
+    let offset = seqno % (bits.len() as u64 * 64);
+    ((offset / 64) as usize, 1 << (offset % 64))
 }
 
-const INDEX_MASK: u64 = HISTORY_BITS as u64 / 2 - 1;
-const IN_TRANSIT: u64 = 0xFFFFFFFFFFFFFFFF;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reordered_duplicated_and_gapping_seqnos() {
+        const WINDOW: usize = 64;
+
+        let history = PacketsHistory::for_recv();
+        let check = |seqno| history.check_seqno(seqno, WINDOW);
+
+        // Reordered packets are accepted once
+        for seqno in [1, 3, 2, 5, 4] {
+            assert_eq!(check(seqno), SeqnoCheck::New, "{seqno}");
+        }
+        for seqno in [1, 3, 5] {
+            assert_eq!(check(seqno), SeqnoCheck::Duplicate, "{seqno}");
+        }
+        assert_eq!(history.seqno(), 5);
+        assert_eq!(history.contiguous_seqno(), 5);
+        assert_eq!(
+            history.stats(),
+            SeqnoStats {
+                received: 5,
+                lost: 0
+            }
+        );
+
+        // Gaps are counted as lost until the missing packets arrive
+        assert_eq!(check(10), SeqnoCheck::New);
+        assert_eq!(history.contiguous_seqno(), 5);
+        assert_eq!(
+            history.stats(),
+            SeqnoStats {
+                received: 6,
+                lost: 4
+            }
+        );
+        assert_eq!(check(7), SeqnoCheck::New);
+        assert_eq!(check(6), SeqnoCheck::New);
+        assert_eq!(history.contiguous_seqno(), 7);
+        assert_eq!(
+            history.stats(),
+            SeqnoStats {
+                received: 8,
+                lost: 2
+            }
+        );
+
+        // Seqnos which left the window are too old and no longer hold the confirmation
+        assert_eq!(check(100), SeqnoCheck::New);
+        assert_eq!(history.contiguous_seqno(), 36);
+        assert_eq!(check(36), SeqnoCheck::TooOld);
+        assert_eq!(check(8), SeqnoCheck::TooOld);
+        assert_eq!(check(37), SeqnoCheck::New);
+        assert_eq!(history.contiguous_seqno(), 37);
+        assert_eq!(check(37), SeqnoCheck::Duplicate);
+        assert_eq!(
+            history.stats(),
+            SeqnoStats {
+                received: 10,
+                lost: 90
+            }
+        );
 
-const HISTORY_BITS: usize = 512;
-const HISTORY_SIZE: usize = HISTORY_BITS / 64;
+        // Window can be changed at runtime
+        assert_eq!(history.check_seqno(101, 128), SeqnoCheck::New);
+        assert_eq!(history.check_seqno(100, 128), SeqnoCheck::Duplicate);
+        assert_eq!(history.check_seqno(36, 128), SeqnoCheck::New);
+        assert_eq!(history.check_seqno(37, 16), SeqnoCheck::TooOld);
+        assert_eq!(history.check_seqno(101, 16), SeqnoCheck::Duplicate);
+
+        history.reset();
+        assert_eq!(history.seqno(), 0);
+        assert_eq!(history.stats(), SeqnoStats::default());
+        assert_eq!(check(1), SeqnoCheck::New);
+
+        // Sent seqnos are not tracked
+        let history = PacketsHistory::for_send();
+        assert_eq!(history.bump_seqno(), 1);
+        assert!(history.deliver_packet(1) && history.deliver_packet(1));
+        assert_eq!(history.contiguous_seqno(), 1);
+    }
+}
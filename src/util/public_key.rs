@@ -0,0 +1,100 @@
+use everscale_crypto::tl;
+
+/// Public key of any kind which can be used to compute short ids
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PublicKeyVariant<'a> {
+    /// `pub.ed25519 key:int256`
+    Ed25519(&'a [u8; 32]),
+    /// `pub.aes key:int256`
+    Aes(&'a [u8; 32]),
+    /// `pub.overlay name:bytes`
+    Overlay(&'a [u8]),
+    /// `pub.unenc data:bytes`
+    Unenc(&'a [u8]),
+}
+
+impl<'a> PublicKeyVariant<'a> {
+    /// Represents public key as a TL structure
+    pub fn as_tl(&self) -> tl::PublicKey<'a> {
+        match *self {
+            Self::Ed25519(key) => tl::PublicKey::Ed25519 { key },
+            Self::Aes(key) => tl::PublicKey::Aes { key },
+            Self::Overlay(name) => tl::PublicKey::Overlay { name },
+            Self::Unenc(data) => tl::PublicKey::Unencoded { data },
+        }
+    }
+}
+
+impl<'a> From<tl::PublicKey<'a>> for PublicKeyVariant<'a> {
+    fn from(key: tl::PublicKey<'a>) -> Self {
+        match key {
+            tl::PublicKey::Ed25519 { key } => Self::Ed25519(key),
+            tl::PublicKey::Aes { key } => Self::Aes(key),
+            tl::PublicKey::Overlay { name } => Self::Overlay(name),
+            tl::PublicKey::Unencoded { data } => Self::Unenc(data),
+        }
+    }
+}
+
+/// Computes short id of the public key (hash of its boxed TL representation)
+pub fn hash_public_key(key: PublicKeyVariant<'_>) -> [u8; 32] {
+    tl_proto::hash(key.as_tl())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::adnl::ComputeNodeIds;
+
+    // Expected values are sha256 of the boxed `pub.*` serialization
+    fn check(key: PublicKeyVariant<'_>, expected: &str) {
+        assert_eq!(hex::encode(hash_public_key(key)), expected);
+    }
+
+    #[test]
+    fn known_answers() {
+        let key: [u8; 32] = std::array::from_fn(|i| i as u8);
+
+        check(
+            PublicKeyVariant::Ed25519(&key),
+            "dac97a9aa4db0219421e1583c57ab0ea3316892969b19f3a75e64382c3e558f7",
+        );
+        check(
+            PublicKeyVariant::Aes(&key),
+            "518e3975b66a513a77f0d70be4de05bbd4097764f502bf1d456172d50007d9bf",
+        );
+        check(
+            PublicKeyVariant::Overlay(&key),
+            "e1d36115478b0ec32f5aafc4ce748df227a2578d3a727b6542296474974aa64f",
+        );
+        check(
+            PublicKeyVariant::Overlay(&[0xab; 300]),
+            "9a077579ec6b27b5f0fc883f3c1078dca8795c45a4fd993733bff927d9e203ca",
+        );
+        check(
+            PublicKeyVariant::Unenc(b"hello"),
+            "548e82b331e3081988eb8c181922d8c659ef69f2de5e56936f2c811b6c075261",
+        );
+    }
+
+    #[test]
+    fn same_as_ids() {
+        let overlay_id = crate::overlay::IdFull::for_workchain_overlay(0, &[1; 32]);
+        assert_eq!(
+            crate::overlay::IdShort::from(PublicKeyVariant::Overlay(overlay_id.as_slice())),
+            overlay_id.compute_short_id()
+        );
+
+        let secret = everscale_crypto::ed25519::SecretKey::from_bytes([1; 32]);
+        let public = everscale_crypto::ed25519::PublicKey::from(&secret);
+        let (_, short_id) = public.compute_node_ids();
+        assert_eq!(
+            crate::adnl::NodeIdShort::from(PublicKeyVariant::Ed25519(public.as_bytes())),
+            short_id
+        );
+        assert_eq!(
+            PublicKeyVariant::from(public.as_tl()),
+            PublicKeyVariant::Ed25519(public.as_bytes())
+        );
+    }
+}
@@ -0,0 +1,93 @@
+use std::future::Future;
+
+use tokio::runtime::Handle;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+
+/// Spawns a named background task on the specified runtime.
+///
+/// Names are visible in `tokio-console` when built with `--cfg tokio_unstable`
+pub(crate) fn spawn_named<F>(
+    runtime: &Handle,
+    name: &'static str,
+    future: F,
+) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    #[cfg(tokio_unstable)]
+    {
+        tokio::task::Builder::new()
+            .name(name)
+            .spawn_on(future, runtime)
+            .expect("failed to spawn task")
+    }
+
+    #[cfg(not(tokio_unstable))]
+    {
+        let _ = name;
+        runtime.spawn(future)
+    }
+}
+
+/// Background tasks which are cancelled and awaited together
+#[derive(Default)]
+pub(crate) struct TaskGroup {
+    token: CancellationToken,
+    tracker: TaskTracker,
+}
+
+impl TaskGroup {
+    /// Creates a group which is also cancelled with this one
+    #[cfg(feature = "rldp")]
+    pub fn child(&self) -> Self {
+        Self {
+            token: self.token.child_token(),
+            tracker: TaskTracker::new(),
+        }
+    }
+
+    pub fn token(&self) -> &CancellationToken {
+        &self.token
+    }
+
+    /// Spawns a task which is dropped as soon as the group is cancelled
+    pub fn spawn<F>(&self, runtime: &Handle, name: &'static str, future: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let token = self.token.clone();
+        spawn_named(
+            runtime,
+            name,
+            self.tracker.track_future(async move {
+                tokio::select! {
+                    _ = token.cancelled() => {}
+                    _ = future => {}
+                }
+            }),
+        );
+    }
+
+    /// Cancels all running tasks and the ones which will be spawned later
+    pub fn cancel(&self) {
+        self.tracker.close();
+        self.token.cancel();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.token.is_cancelled()
+    }
+
+    /// Number of tasks which are still running
+    pub fn len(&self) -> usize {
+        self.tracker.len()
+    }
+
+    /// Waits until all tasks are finished after the group was cancelled
+    pub async fn wait(&self) {
+        self.tracker.wait().await
+    }
+}
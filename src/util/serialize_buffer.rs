@@ -0,0 +1,118 @@
+use std::cell::RefCell;
+
+use parking_lot::Mutex;
+
+/// Max number of cached buffers (per thread or per pool)
+const MAX_CACHED_BUFFERS: usize = 16;
+/// Buffers with greater capacity are not returned to the cache
+const MAX_CACHED_CAPACITY: usize = 4096;
+
+thread_local! {
+    static SERIALIZE_BUFFERS: RefCell<Vec<Vec<u8>>> =
+        RefCell::new(Vec::with_capacity(MAX_CACHED_BUFFERS));
+}
+
+/// Runs the closure with an empty thread-local buffer.
+///
+/// Calls can be nested, each level gets its own buffer.
+pub(crate) fn with_serialize_buffer<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut Vec<u8>) -> R,
+{
+    let mut buffer = SERIALIZE_BUFFERS
+        .with(|buffers| buffers.borrow_mut().pop())
+        .unwrap_or_default();
+
+    let result = f(&mut buffer);
+
+    if buffer.capacity() <= MAX_CACHED_CAPACITY {
+        buffer.clear();
+        SERIALIZE_BUFFERS.with(|buffers| {
+            let mut buffers = buffers.borrow_mut();
+            if buffers.len() < MAX_CACHED_BUFFERS {
+                buffers.push(buffer);
+            }
+        });
+    }
+
+    result
+}
+
+/// Shared pool of buffers which are moved between threads
+/// (e.g. outgoing packets which are sent from the separate task)
+pub(crate) struct BufferPool {
+    buffers: Mutex<Vec<Vec<u8>>>,
+}
+
+impl BufferPool {
+    /// Returns an empty buffer with at least the specified capacity
+    pub fn get(&self, capacity: usize) -> Vec<u8> {
+        let mut buffer = self.buffers.lock().pop().unwrap_or_default();
+        buffer.reserve(capacity);
+        buffer
+    }
+
+    /// Returns buffer to the pool
+    pub fn put(&self, mut buffer: Vec<u8>) {
+        if buffer.capacity() > MAX_CACHED_CAPACITY {
+            return;
+        }
+        buffer.clear();
+
+        let mut buffers = self.buffers.lock();
+        if buffers.len() < MAX_CACHED_BUFFERS {
+            buffers.push(buffer);
+        }
+    }
+}
+
+impl Default for BufferPool {
+    fn default() -> Self {
+        Self {
+            buffers: Mutex::new(Vec::with_capacity(MAX_CACHED_BUFFERS)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nested_buffers() {
+        with_serialize_buffer(|outer| {
+            outer.extend_from_slice(&[1; 100]);
+            let inner_ptr = with_serialize_buffer(|inner| {
+                assert!(inner.is_empty());
+                inner.extend_from_slice(&[2; 100]);
+                inner.as_ptr()
+            });
+            assert_ne!(outer.as_ptr(), inner_ptr);
+            assert_eq!(outer.as_slice(), &[1; 100]);
+        });
+
+        // Buffer is reused and cleared
+        with_serialize_buffer(|buffer| {
+            assert!(buffer.is_empty());
+            assert!(buffer.capacity() >= 100);
+        });
+    }
+
+    #[test]
+    fn pool_reuses_buffers() {
+        let pool = BufferPool::default();
+
+        let mut buffer = pool.get(100);
+        buffer.extend_from_slice(&[1; 100]);
+        let ptr = buffer.as_ptr();
+        pool.put(buffer);
+
+        let buffer = pool.get(50);
+        assert!(buffer.is_empty());
+        assert_eq!(buffer.as_ptr(), ptr);
+
+        // Too big buffers are not cached
+        pool.put(Vec::with_capacity(MAX_CACHED_CAPACITY + 1));
+        assert!(pool.get(0).capacity() <= MAX_CACHED_CAPACITY);
+    }
+}
@@ -0,0 +1,173 @@
+use anyhow::Result;
+use everscale_crypto::ed25519;
+use tl_proto::{Boxed, BoxedConstructor, TlWrite};
+
+use crate::adnl;
+use crate::overlay;
+use crate::proto;
+
+/// Verifies the signature of the boxed TL representation of the data.
+///
+/// NOTE: signature itself is usually a part of the signed object, so it must be
+/// excluded before verification. See specialized helpers below for the common cases.
+pub fn verify_signed<T>(data: T, signature: &[u8], key: &ed25519::PublicKey) -> Result<()>
+where
+    T: TlWrite<Repr = Boxed>,
+{
+    adnl::NodeIdFull::new(*key).verify(data, signature)?;
+    Ok(())
+}
+
+/// Creates signed overlay node. `overlay.node.toSign` is signed
+pub fn sign_overlay_node(
+    key: &adnl::Key,
+    overlay_id: &overlay::IdShort,
+    version: u32,
+) -> proto::overlay::NodeOwned {
+    let signature = key.sign(proto::overlay::NodeToSign {
+        id: key.id().as_slice(),
+        overlay: overlay_id.as_slice(),
+        version,
+    });
+
+    proto::overlay::NodeOwned {
+        id: key.full_id().as_tl().as_equivalent_owned(),
+        overlay: *overlay_id.as_slice(),
+        version,
+        signature: signature.to_vec().into(),
+    }
+}
+
+/// Verifies the signature of the overlay node (without checking the overlay id)
+pub fn verify_overlay_node(node: &proto::overlay::Node<'_>) -> Result<()> {
+    let peer_id_full = adnl::NodeIdFull::try_from(node.id)?;
+    let peer_id = peer_id_full.compute_short_id();
+
+    peer_id_full.verify(
+        proto::overlay::NodeToSign {
+            id: peer_id.as_slice(),
+            overlay: node.overlay,
+            version: node.version,
+        },
+        node.signature,
+    )?;
+    Ok(())
+}
+
+/// Creates signed DHT node with the specified address list.
+/// `dht.node` with an empty signature is signed
+pub fn sign_dht_node(
+    key: &adnl::Key,
+    addr_list: proto::adnl::AddressList,
+) -> proto::dht::NodeOwned {
+    let mut node = proto::dht::NodeOwned {
+        id: key.full_id().as_tl().as_equivalent_owned(),
+        version: addr_list.version,
+        addr_list,
+        signature: Default::default(),
+    };
+    node.signature = key.sign(node.as_boxed()).to_vec().into();
+    node
+}
+
+/// Verifies the signature of the DHT node (and therefore of its address list)
+pub fn verify_dht_node(node: &proto::dht::Node<'_>) -> Result<()> {
+    let peer_id_full = adnl::NodeIdFull::try_from(node.id)?;
+
+    let node_to_sign = proto::dht::Node {
+        signature: &[],
+        ..node.clone()
+    };
+    peer_id_full.verify(node_to_sign.as_boxed(), node.signature)?;
+    Ok(())
+}
+
+/// Verifies both signatures of the DHT value with [`UpdateRule::Signature`].
+/// Key description is signed with an empty signature, value is signed with
+/// an empty signature but with the signed key description.
+///
+/// [`UpdateRule::Signature`]: crate::proto::dht::UpdateRule::Signature
+pub fn verify_dht_value(value: &proto::dht::Value<'_>) -> Result<()> {
+    let full_id = adnl::NodeIdFull::try_from(value.key.id)?;
+
+    let key_to_sign = proto::dht::KeyDescription {
+        signature: &[],
+        ..value.key
+    };
+    full_id.verify(key_to_sign.as_boxed(), value.key.signature)?;
+
+    let value_to_sign = proto::dht::Value {
+        signature: &[],
+        ..*value
+    };
+    full_id.verify(value_to_sign.as_boxed(), value.signature)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::AddressListBuilder;
+
+    fn make_key() -> adnl::Key {
+        adnl::Key::from_bytes(rand::random())
+    }
+
+    #[test]
+    fn overlay_node_signature() {
+        let key = make_key();
+        let overlay_id = overlay::IdFull::for_workchain_overlay(0, &[1; 32]).compute_short_id();
+
+        let node = sign_overlay_node(&key, &overlay_id, 123);
+        verify_overlay_node(&node.as_equivalent_ref()).unwrap();
+        overlay_id
+            .verify_overlay_node(&node.as_equivalent_ref())
+            .unwrap();
+
+        let mut tampered = node.as_equivalent_ref();
+        tampered.version += 1;
+        assert!(verify_overlay_node(&tampered).is_err());
+    }
+
+    #[test]
+    fn dht_node_signature() {
+        let key = make_key();
+        let addr_list = AddressListBuilder::new()
+            .with_address("127.0.0.1:30303".parse().unwrap())
+            .with_version(100)
+            .build();
+
+        let node = sign_dht_node(&key, addr_list);
+        let node_ref = node.as_equivalent_ref();
+        verify_dht_node(&node_ref).unwrap();
+
+        let mut tampered = node_ref;
+        tampered.addr_list.addresses[0] =
+            proto::adnl::Address::from(&"127.0.0.1:30304".parse().unwrap());
+        assert!(verify_dht_node(&tampered).is_err());
+    }
+
+    #[cfg(feature = "dht")]
+    #[tokio::test]
+    async fn dht_value_signature() {
+        let adnl = crate::util::fixtures::make_adnl_node(Default::default());
+        let dht = crate::dht::Node::new(adnl.clone(), 0, Default::default()).unwrap();
+        let key = adnl.key_by_tag(0).unwrap();
+
+        // Signed by the entry builder
+        let value = dht
+            .entry(key.id(), "addr")
+            .with_data(proto::adnl::Pong { value: 1 })
+            .sign(key);
+        let value_ref = value.as_equivalent_ref();
+        verify_dht_value(&value_ref).unwrap();
+
+        let mut tampered = value_ref;
+        tampered.value = &[1, 2, 3];
+        assert!(verify_dht_value(&tampered).is_err());
+
+        let mut tampered = value_ref;
+        tampered.key.key.idx = 1;
+        assert!(verify_dht_value(&tampered).is_err());
+    }
+}
@@ -0,0 +1,118 @@
+//! Allocation budget of the hot send path.
+//!
+//! Lives in a separate test binary, because it replaces the global allocator.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::Arc;
+use std::time::Duration;
+
+use everscale_network::{adnl, proto};
+use futures_util::FutureExt;
+
+thread_local! {
+    static COUNTER: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+/// Counts allocations on the current thread while enabled
+struct CountingAllocator;
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        COUNTER.with(|counter| {
+            if let Some(count) = counter.get() {
+                counter.set(Some(count + 1));
+            }
+        });
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+fn count_allocations<F: FnOnce()>(f: F) -> usize {
+    COUNTER.with(|counter| counter.set(Some(0)));
+    f();
+    COUNTER.with(|counter| counter.take()).unwrap_or_default()
+}
+
+fn make_node() -> Arc<adnl::Node> {
+    let keystore = adnl::Keystore::builder()
+        .with_tagged_key(rand::random(), 0)
+        .unwrap()
+        .build();
+    adnl::Node::new(
+        SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0),
+        keystore,
+        Default::default(),
+        None,
+    )
+    .unwrap()
+}
+
+#[tokio::test]
+async fn query_over_channel_allocations() {
+    let left = make_node();
+    let right = make_node();
+    right.add_echo_subscriber().unwrap();
+
+    left.start().unwrap();
+    right.start().unwrap();
+
+    let left_id = *left.key_by_tag(0).unwrap().id();
+    let right_key = right.key_by_tag(0).unwrap().clone();
+    let right_id = *right_key.id();
+
+    left.add_peer(
+        adnl::NewPeerContext::AdnlPacket,
+        &left_id,
+        &right_id,
+        right.socket_addr(),
+        *right_key.full_id(),
+    )
+    .unwrap();
+    left.ensure_channel(&left_id, &right_id, Some(1000))
+        .await
+        .unwrap();
+
+    let query = tl_proto::serialize(proto::rpc::NetworkEcho { data: vec![1; 100] });
+    let send_query = || {
+        // The first poll sends the query and registers the pending query
+        let _ = left
+            .query_raw(&left_id, &right_id, &query, Some(1000))
+            .now_or_never();
+    };
+
+    // Warm up buffers
+    for _ in 0..4 {
+        send_query();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    // Packet is built, encrypted and queued in the reused buffers
+    let message = [1; 100];
+    let allocations = count_allocations(|| {
+        left.send_custom_message(&left_id, &right_id, &message)
+            .unwrap();
+    });
+    assert!(
+        allocations <= MESSAGE_ALLOCATION_BUDGET,
+        "too many message allocations: {allocations}"
+    );
+
+    let allocations = count_allocations(send_query);
+    assert!(
+        allocations <= QUERY_ALLOCATION_BUDGET,
+        "too many query allocations: {allocations}"
+    );
+}
+
+const MESSAGE_ALLOCATION_BUDGET: usize = 1;
+/// Message allocations and the state of the pending query
+const QUERY_ALLOCATION_BUDGET: usize = MESSAGE_ALLOCATION_BUDGET + 4;